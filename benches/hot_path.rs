@@ -0,0 +1,169 @@
+//! Benchmarks of the packet processing hot path with the recorded workloads in `benches/workloads`.
+//!
+//! Run with `cargo bench --features bench`. With `--features bench,flamegraph` the stages are kept
+//! out of line, so a profiler attributes the time to the individual parsers instead of the benchmark loop.
+use std::fs::read_to_string;
+use std::hint::black_box;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use bluefang::a2dp::concealment::{ConcealmentMode, SbcConcealer, SbcFrame};
+use bluefang::a2dp::sbc::{AllocationMethods, BlockLengths, ChannelModes, SamplingFrequencies, SbcMediaCodecInformation, Subbands};
+use bluefang::avdtp::capabilities::Capability;
+use bluefang::avdtp::rtp::MediaPacketValidator;
+use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, MediaType, StreamEndpointType, StreamHandler, StreamHandlerFactory};
+use bluefang::bench::{AvdtpSignalDispatcher, AvrcpCommandParser, L2capDemux};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use sbc_rs::BufferedDecoder;
+
+/// Loads `benches/workloads/<name>.hex`, one packet of hex bytes per line.
+fn workload(name: &str) -> Vec<Bytes> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("benches/workloads")
+        .join(format!("{}.hex", name));
+    let content = read_to_string(&path).unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16).unwrap_or_else(|_| panic!("invalid hex byte {:?} in {}", byte, name)))
+                .collect::<Vec<u8>>()
+                .into()
+        })
+        .collect()
+}
+
+fn throughput(packets: &[Bytes]) -> Throughput {
+    Throughput::Bytes(packets.iter().map(Bytes::len).sum::<usize>() as u64)
+}
+
+fn sbc_codec() -> SbcMediaCodecInformation {
+    SbcMediaCodecInformation {
+        sampling_frequencies: SamplingFrequencies::FREQ_44100,
+        channel_modes: ChannelModes::JOINT_STEREO,
+        block_lengths: BlockLengths::SIXTEEN,
+        subbands: Subbands::EIGHT,
+        allocation_methods: AllocationMethods::LOUDNESS,
+        minimum_bitpool: 2,
+        maximum_bitpool: 53
+    }
+}
+
+struct NullHandler;
+
+impl StreamHandler for NullHandler {
+    fn on_play(&mut self) {}
+    fn on_stop(&mut self) {}
+    fn on_data(&mut self, _data: Bytes) {}
+}
+
+fn avrcp(c: &mut Criterion) {
+    let packets = workload("avrcp");
+    let mut parser = AvrcpCommandParser::default();
+    assert!(packets.iter().all(|packet| parser.process(packet.clone()).is_some()), "Workload contains rejected commands");
+
+    let mut group = c.benchmark_group("avrcp");
+    group.throughput(throughput(&packets));
+    group.bench_function("parse_commands", |b| {
+        b.iter(|| {
+            for packet in &packets {
+                black_box(parser.process(packet.clone()));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn avdtp(c: &mut Criterion) {
+    let packets = workload("avdtp");
+    let avdtp = AvdtpBuilder::default()
+        .with_endpoint(LocalEndpoint {
+            media_type: MediaType::Audio,
+            seid: 1,
+            in_use: Arc::new(AtomicBool::new(false)),
+            tsep: StreamEndpointType::Sink,
+            capabilities: vec![Capability::MediaTransport, Capability::MediaCodec(sbc_codec().into())],
+            factory: StreamHandlerFactory::new(|_| NullHandler)
+        })
+        .build();
+    let mut dispatcher = AvdtpSignalDispatcher::new(&avdtp);
+    assert!(packets.iter().all(|packet| dispatcher.process(packet.clone()).is_some()), "Workload contains incomplete signals");
+
+    let mut group = c.benchmark_group("avdtp");
+    group.throughput(throughput(&packets));
+    group.bench_function("dispatch_signals", |b| {
+        b.iter(|| {
+            for packet in &packets {
+                black_box(dispatcher.process(packet.clone()));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn sbc(c: &mut Criterion) {
+    let packets = workload("media");
+    let capabilities = [Capability::MediaTransport, Capability::MediaCodec(sbc_codec().into())];
+
+    let mut group = c.benchmark_group("sbc");
+    group.throughput(throughput(&packets));
+    group.bench_function("validate_and_split", |b| {
+        let mut validator = MediaPacketValidator::new(&capabilities);
+        let mut concealer = SbcConcealer::new(ConcealmentMode::RepeatWithFade);
+        b.iter(|| {
+            for packet in &packets {
+                let payload = validator.validate(packet.clone()).expect("Invalid media packet");
+                black_box(concealer.split_frames(&payload).len());
+            }
+        })
+    });
+    group.bench_function("decode", |b| {
+        let mut validator = MediaPacketValidator::new(&capabilities);
+        let mut concealer = SbcConcealer::new(ConcealmentMode::RepeatWithFade);
+        let mut decoder = BufferedDecoder::default();
+        b.iter(|| {
+            for packet in &packets {
+                let payload = validator.validate(packet.clone()).expect("Invalid media packet");
+                for frame in concealer.split_frames(&payload) {
+                    let SbcFrame::Valid(data) = frame else { panic!("Damaged frame in workload") };
+                    decoder.refill_buffer(data);
+                    while let Some(samples) = decoder.next_frame_lr() {
+                        black_box(samples);
+                    }
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn l2cap(c: &mut Criterion) {
+    let packets = workload("l2cap");
+
+    let mut group = c.benchmark_group("l2cap");
+    group.throughput(throughput(&packets));
+    group.bench_function("demux", |b| {
+        let mut demux = L2capDemux::default();
+        let mut signaling = demux.add_channel(0x000B, 0x0040);
+        let mut media = demux.add_channel(0x000B, 0x0041);
+        b.iter(|| {
+            for packet in &packets {
+                black_box(demux.process(packet.clone()));
+            }
+            while let Ok(event) = signaling.try_recv() {
+                black_box(event);
+            }
+            while let Ok(event) = media.try_recv() {
+                black_box(event);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, avrcp, avdtp, sbc, l2cap);
+criterion_main!(benches);
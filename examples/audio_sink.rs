@@ -1,19 +1,18 @@
-use std::array::from_fn;
 use std::cmp::PartialEq;
-use std::iter::zip;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
+use bluefang::a2dp::routing::AudioRouter;
 use bluefang::a2dp::sbc::SbcMediaCodecInformation;
 use bluefang::a2dp::sdp::A2dpSinkServiceRecord;
-use bluefang::avdtp::capabilities::{Capability, MediaCodecCapability};
-use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, StreamHandler, StreamHandlerFactory, MediaType, StreamEndpointType};
+use bluefang::avdtp::capabilities::Capability;
+use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, StreamHandlerFactory, MediaType, StreamEndpointType};
 use bluefang::avrcp::notifications::CurrentTrack;
 use bluefang::avrcp::sdp::{AvrcpControllerServiceRecord, AvrcpTargetServiceRecord};
-use bluefang::avrcp::{Avrcp, AvrcpSession, Event, MediaAttributeId, Notification};
+use bluefang::avrcp::{Avrcp, AvrcpSession, Event, Notification};
 use bluefang::firmware::{FolderFileProvider, RealTekFirmwareLoader};
 use bluefang::hci::connection::ConnectionManagerBuilder;
 use bluefang::hci::consts::{AudioVideoClass, ClassOfDevice, DeviceClass, MajorServiceClasses};
@@ -22,28 +21,23 @@ use bluefang::host::usb::UsbController;
 use bluefang::l2cap::L2capServerBuilder;
 use bluefang::sdp::SdpBuilder;
 use bluefang::utils::{select2, Either2};
-use bytes::Bytes;
 use console::{Key, Term};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{default_host, SampleFormat, Stream, StreamConfig};
 use enum_iterator::{all, Sequence};
 use portable_atomic::AtomicF32;
-use ringbuf::consumer::Consumer;
-use ringbuf::producer::Producer;
-use ringbuf::traits::Split;
-use ringbuf::{HeapProd, HeapRb};
-use rubato::{FastFixedIn, PolynomialDegree, Resampler};
-use sbc_rs::BufferedDecoder;
 use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
 use tokio::time::sleep;
-use tracing::{error, info, trace, warn};
+use tracing::{info, warn};
 use tracing_subscriber::fmt::layer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 use bluefang::avc::PassThroughOp;
 
+use crate::common::{retrieve_current_track_info, CpalSink, SbcStreamHandler};
+
+mod common;
+
 macro_rules! cloned {
     ([$($vars:ident),+] $e:expr) => {
         {
@@ -61,7 +55,7 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     Hci::register_firmware_loaders([
-        RealTekFirmwareLoader::new(FolderFileProvider::new("./firmware")).boxed()
+        RealTekFirmwareLoader::new(FolderFileProvider::from_env("./firmware")).boxed()
     ]);
 
     let usb = UsbController::list(|info| info.vendor_id() == 0x2B89 || info.vendor_id() == 0x10D7)?
@@ -79,10 +73,11 @@ async fn main() -> anyhow::Result<()> {
     info!("Local BD_ADDR: {}", host.read_bd_addr().await?);
     {
         let _conn_manager = ConnectionManagerBuilder::default()
-            .with_link_key_store("link-keys.dat")
             .spawn(host.clone())
             .await?;
         let volume = Arc::new(AtomicF32::new(1.0));
+        let audio = AudioRouter::new();
+        audio.add_sink("speaker", 256, cloned!([volume] move || CpalSink::new(volume)));
         let _l2cap_server = L2capServerBuilder::default()
             .with_protocol(
                 SdpBuilder::default()
@@ -106,7 +101,7 @@ async fn main() -> anyhow::Result<()> {
                             Capability::MediaCodec(SbcMediaCodecInformation::default().into()),
                         ],
                         //stream_handler_factory: Box::new(|cap| Box::new(FileDumpHandler::new())),
-                        factory: StreamHandlerFactory::new(cloned!([volume] move |cap| SbcStreamHandler::new(volume.clone(), cap)))
+                        factory: StreamHandlerFactory::new(cloned!([audio] move |cap| SbcStreamHandler::new(audio.clone(), cap)))
                     })
                     .build()
             )
@@ -170,7 +165,7 @@ fn avrcp_session_handler(volume: Arc<AtomicF32>, mut session: AvrcpSession) {
                             .await
                             .unwrap_or_else(|err| warn!("Failed to retrieve current track info: {}", err));
                     }
-                    Event::VolumeChanged(vol) => {
+                    Event::VolumeChanged(vol, _) => {
                         volume.store(vol, SeqCst);
                         println!("Volume: {}%", (volume.load(SeqCst) * 100.0).round());
                     },
@@ -182,191 +177,6 @@ fn avrcp_session_handler(volume: Arc<AtomicF32>, mut session: AvrcpSession) {
     });
 }
 
-async fn retrieve_current_track_info(session: &AvrcpSession) -> anyhow::Result<()> {
-    let current_track: CurrentTrack = session.register_notification(None).await?;
-    match current_track {
-        CurrentTrack::NotSelected => println!("No track selected"),
-        CurrentTrack::Selected => {
-            let attributes = session
-                .get_current_media_attributes(Some(&[MediaAttributeId::Title, MediaAttributeId::ArtistName]))
-                .await?;
-            println!(
-                "Current Track: {} - {}",
-                attributes
-                    .get(&MediaAttributeId::ArtistName)
-                    .map_or("", String::as_str),
-                attributes
-                    .get(&MediaAttributeId::Title)
-                    .map_or("", String::as_str)
-            );
-        }
-        CurrentTrack::Id(id) => println!("Track ID: {:?}", id)
-    }
-    Ok(())
-}
-
-struct SbcStreamHandler {
-    audio_session: AudioSession,
-    resampler: FastFixedIn<f32>,
-    decoder: BufferedDecoder,
-    volume: Arc<AtomicF32>,
-    input_buffers: [Vec<f32>; 2],
-    output_buffers: [Vec<f32>; 2],
-    interleave_buffer: Vec<i16>
-}
-
-impl SbcStreamHandler {
-    pub fn new(volume: Arc<AtomicF32>, capabilities: &[Capability]) -> Self {
-        let (source_frequency, input_size) = Self::parse_capabilities(capabilities)
-            .context("Invalid capabilities")
-            .unwrap();
-
-        let audio_session = AudioSession::new().unwrap();
-
-        let resampler = FastFixedIn::<f32>::new(
-            audio_session.config().sample_rate.0 as f64 / source_frequency as f64,
-            1.0,
-            PolynomialDegree::Septic,
-            input_size as usize,
-            2
-        )
-        .unwrap();
-
-        Self {
-            decoder: BufferedDecoder::default(),
-            volume,
-            input_buffers: from_fn(|_| vec![0f32; resampler.input_frames_max()]),
-            output_buffers: from_fn(|_| vec![0f32; resampler.output_frames_max()]),
-            interleave_buffer: Vec::with_capacity(2 * resampler.output_frames_max()),
-            audio_session,
-            resampler
-        }
-    }
-
-    fn parse_capabilities(capabilities: &[Capability]) -> Option<(u32, u32)> {
-        let sbc_info = capabilities.iter().find_map(|cap| match cap {
-            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Some(info),
-            _ => None
-        })?;
-        let frequency = sbc_info.sampling_frequencies.as_value()?;
-
-        let subbands = sbc_info.subbands.as_value()?;
-
-        let block_length = sbc_info.block_lengths.as_value()?;
-
-        Some((frequency, subbands * block_length))
-    }
-
-    fn process_frames(&mut self, data: &[u8]) {
-        //println!("buffer: {}", self.audio_session.writer().occupied_len());
-        self.decoder.refill_buffer(data);
-        while let Some(sample) = self.decoder.next_frame_lr() {
-            for (sample, buffer) in zip(sample.into_iter(), self.input_buffers.iter_mut()) {
-                buffer.clear();
-                buffer.extend(sample.iter().map(|s| *s as f32));
-            }
-            let (_, len) = self
-                .resampler
-                .process_into_buffer(&mut self.input_buffers, &mut self.output_buffers, None)
-                .unwrap();
-
-            self.interleave_buffer.clear();
-            let volume = self.volume.load(SeqCst).powi(2);
-            for (&l, &r) in zip(&self.output_buffers[0], &self.output_buffers[1]).take(len) {
-                self.interleave_buffer.push((l * volume) as i16);
-                self.interleave_buffer.push((r * volume) as i16);
-            }
-            self.audio_session
-                .writer()
-                .push_slice(&self.interleave_buffer);
-        }
-    }
-}
-
-impl StreamHandler for SbcStreamHandler {
-    fn on_play(&mut self) {
-        self.audio_session.play();
-    }
-
-    fn on_stop(&mut self) {
-        self.audio_session.stop();
-    }
-
-    fn on_data(&mut self, data: Bytes) {
-        //TODO actually parse the header to make sure the packets are not fragmented
-        self.process_frames(&data.as_ref()[1..]);
-    }
-}
-
-pub struct AudioSession {
-    stream: Stream,
-    config: StreamConfig,
-    buffer: HeapProd<i16>,
-    max_buffer_size: usize
-}
-
-impl AudioSession {
-    pub fn new() -> anyhow::Result<Self> {
-        let host = default_host();
-        let device = host
-            .default_output_device()
-            .context("failed to find output device")?;
-
-        let config = device
-            .supported_output_configs()?
-            .inspect(|config| trace!("supported output config: {:?}", config))
-            .find(|config| config.sample_format() == SampleFormat::I16 && config.channels() == 2)
-            .context("failed to find output config")?
-            .with_max_sample_rate()
-            .config();
-        trace!("selected output config: {:?}", config);
-
-        let max_buffer_size = (config.sample_rate.0 * config.channels as u32) as usize;
-        let buffer: Arc<HeapRb<i16>> = Arc::new(HeapRb::new(max_buffer_size));
-        let (buffer, mut consumer) = buffer.split();
-
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [i16], _info| {
-                let len = consumer.pop_slice(data);
-                //data[..len].iter_mut().for_each(|d| *d *=  8);
-                data[len..].fill(0);
-            },
-            move |err| {
-                error!("an error occurred on the output stream: {}", err);
-            },
-            None
-        )?;
-
-        Ok(Self {
-            stream,
-            config,
-            buffer,
-            max_buffer_size
-        })
-    }
-
-    pub fn play(&self) {
-        self.stream.play().unwrap();
-    }
-
-    pub fn stop(&self) {
-        self.stream.pause().unwrap();
-    }
-
-    pub fn writer(&mut self) -> &mut HeapProd<i16> {
-        &mut self.buffer
-    }
-
-    pub fn config(&self) -> &StreamConfig {
-        &self.config
-    }
-
-    pub fn max_buffer_size(&self) -> usize {
-        self.max_buffer_size
-    }
-}
-
 #[derive(Debug, Copy, Clone, PartialEq, Sequence)]
 enum PlayerCommand {
     Play,
@@ -0,0 +1,264 @@
+//! A small SBC encoder for the source example ([A2DP] Section 12): 8 subbands, loudness allocation, mono or stereo.
+
+use std::array::from_fn;
+use std::f32::consts::PI;
+
+use bluefang::a2dp::sbc::{ChannelModes, SbcFrameHeader};
+
+const SUBBANDS: usize = 8;
+const WINDOW: usize = 10 * SUBBANDS;
+
+/// The first half of the symmetric prototype filter of the analysis for 8 subbands ([A2DP] Table 12.24),
+/// with the sign changes of the table moved back into the cosine modulation.
+const PROTO_8: [f32; WINDOW / 2 + 1] = [
+    0.00000000E+00, 1.56575398E-04, 3.43256425E-04, 5.54620202E-04, 8.23919506E-04, 1.13992507E-03, 1.47640169E-03,
+    1.78371725E-03, 2.01182542E-03, 2.10371989E-03, 1.99454554E-03, 1.61656283E-03, 9.02154502E-04, -1.78805361E-04,
+    -1.64973098E-03, -3.49717454E-03, -5.65949473E-03, -8.02941163E-03, -1.04584443E-02, -1.27472335E-02,
+    -1.46525263E-02, -1.59045603E-02, -1.62208471E-02, -1.53184106E-02, -1.29371806E-02, -8.85757540E-03,
+    -2.92408442E-03, 4.91578024E-03, 1.46404076E-02, 2.61098752E-02, 3.90751381E-02, 5.31873032E-02, 6.79989431E-02,
+    8.29847578E-02, 9.75753918E-02, 1.11196689E-01, 1.23264548E-01, 1.33264415E-01, 1.40753505E-01, 1.45389847E-01,
+    1.46955068E-01
+];
+
+/// Encodes interleaved 16 bit PCM into SBC frames.
+pub struct SbcEncoder {
+    sampling_frequency: u32,
+    channel_mode: ChannelModes,
+    blocks: usize,
+    bitpool: u8,
+    /// The prototype filter multiplied with the cosine modulation of every subband.
+    coefficients: [[f32; WINDOW]; SUBBANDS],
+    /// The last input samples of every channel, the newest first.
+    history: Vec<[f32; WINDOW]>
+}
+
+impl SbcEncoder {
+    /// Only [ChannelModes::MONO] and [ChannelModes::STEREO] are supported.
+    pub fn new(sampling_frequency: u32, channel_mode: ChannelModes, blocks: usize, bitpool: u8) -> Self {
+        assert!(matches!(channel_mode, ChannelModes::MONO | ChannelModes::STEREO), "unsupported channel mode");
+        assert!(matches!(blocks, 4 | 8 | 12 | 16), "invalid block count");
+        let channels = if channel_mode == ChannelModes::MONO { 1 } else { 2 };
+        Self {
+            sampling_frequency,
+            channel_mode,
+            blocks,
+            bitpool,
+            coefficients: from_fn(|sb| {
+                from_fn(|i| {
+                    let proto = PROTO_8[i.min(WINDOW - i)];
+                    proto * ((sb as f32 + 0.5) * (i as f32 - 4.0) * PI / SUBBANDS as f32).cos()
+                })
+            }),
+            history: vec![[0.0; WINDOW]; channels]
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.history.len()
+    }
+
+    /// The number of samples per channel in each frame.
+    pub fn samples_per_frame(&self) -> usize {
+        self.blocks * SUBBANDS
+    }
+
+    /// Encodes one frame of [Self::samples_per_frame] interleaved samples per channel.
+    pub fn encode(&mut self, pcm: &[i16]) -> Vec<u8> {
+        let channels = self.channels();
+        assert_eq!(pcm.len(), self.samples_per_frame() * channels, "incomplete frame");
+
+        // Analysis filter bank ([A2DP] Section 12.5.1)
+        let mut sb_samples = vec![[[0.0f32; SUBBANDS]; 2]; self.blocks];
+        for (blk, block) in pcm.chunks_exact(SUBBANDS * channels).enumerate() {
+            for ch in 0..channels {
+                let history = &mut self.history[ch];
+                history.copy_within(0..WINDOW - SUBBANDS, SUBBANDS);
+                for (i, sample) in block.iter().skip(ch).step_by(channels).enumerate() {
+                    history[SUBBANDS - 1 - i] = *sample as f32;
+                }
+                for (sb, coefficients) in self.coefficients.iter().enumerate() {
+                    sb_samples[blk][ch][sb] = coefficients.iter().zip(history.iter()).map(|(c, x)| c * x).sum();
+                }
+            }
+        }
+
+        // The smallest power of two above the largest sample of each subband
+        let mut scale_factors = [[0u8; SUBBANDS]; 2];
+        for ch in 0..channels {
+            for sb in 0..SUBBANDS {
+                let max = sb_samples.iter().map(|block| block[ch][sb].abs()).fold(0.0, f32::max);
+                let mut scale_factor = 0;
+                while scale_factor < 15 && (2u32 << scale_factor) as f32 <= max {
+                    scale_factor += 1;
+                }
+                scale_factors[ch][sb] = scale_factor;
+            }
+        }
+
+        let bits = self.allocate_bits(&scale_factors);
+
+        let mut writer = BitWriter::default();
+        writer.write(SbcFrameHeader::SYNCWORD as u32, 8);
+        let frequency = match self.sampling_frequency {
+            16000 => 0,
+            32000 => 1,
+            44100 => 2,
+            _ => 3
+        };
+        let mode = if self.channel_mode == ChannelModes::MONO { 0 } else { 2 };
+        // Loudness allocation and 8 subbands
+        writer.write(frequency << 6 | (self.blocks as u32 / 4 - 1) << 4 | mode << 2 | 0b01, 8);
+        writer.write(self.bitpool as u32, 8);
+        writer.write(0, 8);
+        for scale_factors in &scale_factors[..channels] {
+            for &scale_factor in scale_factors {
+                writer.write(scale_factor as u32, 4);
+            }
+        }
+        // Quantization ([A2DP] Section 12.6.4)
+        for block in &sb_samples {
+            for ch in 0..channels {
+                for sb in 0..SUBBANDS {
+                    let bits = bits[ch][sb];
+                    if bits == 0 {
+                        continue;
+                    }
+                    let levels = ((1u32 << bits) - 1) as f32;
+                    let scale = (2u32 << scale_factors[ch][sb]) as f32;
+                    let sample = ((block[ch][sb] / scale + 1.0) * levels / 2.0).floor().clamp(0.0, levels - 1.0);
+                    writer.write(sample as u32, bits);
+                }
+            }
+        }
+        let mut frame = writer.finish();
+        frame[3] = crc8(&frame, 4 * SUBBANDS * channels);
+        debug_assert_eq!(SbcFrameHeader::parse(&frame).map(|header| header.frame_length()), Some(frame.len()));
+        frame
+    }
+
+    /// Distributes the bitpool over the subbands by their loudness ([A2DP] Section 12.6.3).
+    fn allocate_bits(&self, scale_factors: &[[u8; SUBBANDS]; 2]) -> [[u8; SUBBANDS]; 2] {
+        let offsets: [i32; SUBBANDS] = match self.sampling_frequency {
+            16000 => [-2, 0, 0, 0, 0, 0, 0, 1],
+            32000 => [-3, 0, 0, 0, 0, 0, 1, 2],
+            _ => [-4, 0, 0, 0, 0, 0, 1, 2]
+        };
+        let bitneed: [[i32; SUBBANDS]; 2] = from_fn(|ch| {
+            from_fn(|sb| match scale_factors[ch][sb] as i32 {
+                0 => -5,
+                scale_factor => match scale_factor - offsets[sb] {
+                    loudness if loudness > 0 => loudness / 2,
+                    loudness => loudness
+                }
+            })
+        });
+        // Stereo frames share the bitpool between both channels
+        let channels = 0..self.channels();
+        let bitpool = self.bitpool as i32;
+        let needs = || channels.clone().flat_map(|ch| bitneed[ch]);
+
+        let mut bitcount = 0;
+        let mut slicecount = 0;
+        let mut bitslice = needs().max().unwrap_or(0) + 1;
+        loop {
+            bitslice -= 1;
+            bitcount += slicecount;
+            slicecount = 0;
+            for need in needs() {
+                if need > bitslice + 1 && need < bitslice + 16 {
+                    slicecount += 1;
+                } else if need == bitslice + 1 {
+                    slicecount += 2;
+                }
+            }
+            if bitcount + slicecount >= bitpool {
+                break;
+            }
+        }
+        if bitcount + slicecount == bitpool {
+            bitcount += slicecount;
+            bitslice -= 1;
+        }
+
+        let mut bits = [[0u8; SUBBANDS]; 2];
+        for ch in channels.clone() {
+            for sb in 0..SUBBANDS {
+                if bitneed[ch][sb] >= bitslice + 2 {
+                    bits[ch][sb] = (bitneed[ch][sb] - bitslice).min(16) as u8;
+                }
+            }
+        }
+        // Hand out the remaining bits, alternating between the channels
+        for sb in 0..SUBBANDS {
+            for ch in channels.clone() {
+                if bitcount >= bitpool {
+                    return bits;
+                }
+                if (2..16).contains(&bits[ch][sb]) {
+                    bits[ch][sb] += 1;
+                    bitcount += 1;
+                } else if bitneed[ch][sb] == bitslice + 1 && bitpool > bitcount + 1 {
+                    bits[ch][sb] = 2;
+                    bitcount += 2;
+                }
+            }
+        }
+        for sb in 0..SUBBANDS {
+            for ch in channels.clone() {
+                if bitcount >= bitpool {
+                    return bits;
+                }
+                if bits[ch][sb] < 16 {
+                    bits[ch][sb] += 1;
+                    bitcount += 1;
+                }
+            }
+        }
+        bits
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    current: u32,
+    bits: u8
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u8) {
+        for i in (0..bits).rev() {
+            self.current = self.current << 1 | (value >> i) & 1;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.data.push(self.current as u8);
+                self.current = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with zeros.
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.data.push((self.current << (8 - self.bits)) as u8);
+        }
+        self.data
+    }
+}
+
+/// CRC-8 with the polynomial x^8 + x^4 + x^3 + x^2 + 1 over the second and third header byte
+/// followed by the scale factors ([A2DP] Section 12.6.3).
+fn crc8(frame: &[u8], bits: usize) -> u8 {
+    let update = |crc: u8, byte: u8| {
+        (0..8).fold(crc, |crc, i| {
+            let bit = ((byte << i) ^ crc) & 0x80 != 0;
+            (crc << 1) ^ if bit { 0x1D } else { 0x00 }
+        })
+    };
+    // The scale factors of 8 subbands always fill whole bytes
+    frame[1..3]
+        .iter()
+        .chain(&frame[4..4 + bits / 8])
+        .fold(0x0F, |crc, &byte| update(crc, byte))
+}
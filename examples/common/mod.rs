@@ -0,0 +1,368 @@
+//! Audio output and AVRCP helpers shared by the examples.
+#![allow(dead_code)]
+
+pub mod encoder;
+
+use std::array::from_fn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::iter::zip;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use anyhow::Context;
+use bluefang::a2dp::concealment::{ConcealmentMode, SbcConcealer, SbcFrame};
+use bluefang::a2dp::routing::{AudioRouter, AudioSink, PcmFormat};
+use bluefang::avdtp::capabilities::{Capability, MediaCodecCapability};
+use bluefang::avdtp::StreamHandler;
+use bluefang::avrcp::notifications::CurrentTrack;
+use bluefang::avrcp::{AvrcpSession, MediaAttributeId};
+use bytes::Bytes;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{default_host, SampleFormat, Stream, StreamConfig};
+use portable_atomic::AtomicF32;
+use ringbuf::consumer::Consumer;
+use ringbuf::producer::Producer;
+use ringbuf::traits::Split;
+use ringbuf::{HeapProd, HeapRb};
+use rubato::{FastFixedIn, PolynomialDegree, Resampler};
+use sbc_rs::BufferedDecoder;
+use tracing::{debug, error, trace};
+
+/// Decodes an SBC stream and passes the PCM samples to the sinks of an [AudioRouter].
+/// Damaged frames are concealed instead of being skipped.
+pub struct SbcStreamHandler {
+    router: AudioRouter,
+    format: PcmFormat,
+    decoder: BufferedDecoder,
+    concealer: SbcConcealer,
+    playing: bool,
+    interleave_buffer: Vec<i16>
+}
+
+impl SbcStreamHandler {
+    pub fn new(router: AudioRouter, capabilities: &[Capability]) -> Self {
+        let sample_rate = Self::parse_capabilities(capabilities)
+            .context("Invalid capabilities")
+            .unwrap();
+        Self {
+            router,
+            format: PcmFormat { sample_rate, channels: 2 },
+            decoder: BufferedDecoder::default(),
+            concealer: SbcConcealer::new(ConcealmentMode::RepeatWithFade),
+            playing: false,
+            interleave_buffer: Vec::new()
+        }
+    }
+
+    fn parse_capabilities(capabilities: &[Capability]) -> Option<u32> {
+        let sbc_info = capabilities.iter().find_map(|cap| match cap {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Some(info),
+            _ => None
+        })?;
+        sbc_info.sampling_frequencies.as_value()
+    }
+
+    fn process_frame(&mut self, frame: SbcFrame) {
+        match frame {
+            SbcFrame::Valid(data) => {
+                self.decoder.refill_buffer(data);
+                while let Some(sample) = self.decoder.next_frame_lr() {
+                    let mut channels = sample.into_iter();
+                    let (Some(left), Some(right)) = (channels.next(), channels.next()) else {
+                        continue;
+                    };
+                    self.interleave_buffer.clear();
+                    for (&l, &r) in zip(left.iter(), right.iter()) {
+                        self.interleave_buffer.push(l);
+                        self.interleave_buffer.push(r);
+                    }
+                    self.concealer.decoded(&self.interleave_buffer);
+                    self.router.push(&self.interleave_buffer);
+                }
+            }
+            SbcFrame::Damaged => {
+                let samples = self.concealer.conceal();
+                if !samples.is_empty() {
+                    self.router.push(&samples);
+                }
+            }
+        }
+    }
+}
+
+impl StreamHandler for SbcStreamHandler {
+    fn on_play(&mut self) {
+        self.playing = true;
+        self.router.start(self.format);
+    }
+
+    fn on_stop(&mut self) {
+        self.playing = false;
+        self.router.stop();
+        debug!("SBC concealment: {:?}", self.concealer.stats());
+    }
+
+    fn on_idle(&mut self) {
+        debug!("Stream went idle, audio device parked");
+    }
+
+    fn on_reconfigure(&mut self, capabilities: &[Capability]) -> bool {
+        let Some(sample_rate) = Self::parse_capabilities(capabilities) else {
+            return false;
+        };
+        debug!("Switching to {} Hz, SBC concealment: {:?}", sample_rate, self.concealer.stats());
+        // Frames of the old configuration must not be decoded with the new parameters
+        self.decoder = BufferedDecoder::default();
+        self.concealer = SbcConcealer::new(ConcealmentMode::RepeatWithFade);
+        self.format = PcmFormat { sample_rate, channels: 2 };
+        // The sinks drain the samples of the old format before they switch
+        if self.playing {
+            self.router.start(self.format);
+        }
+        true
+    }
+
+    fn on_data(&mut self, data: Bytes) {
+        //TODO actually parse the header to make sure the packets are not fragmented
+        for frame in self.concealer.split_frames(&data) {
+            self.process_frame(frame);
+        }
+    }
+}
+
+/// Plays the samples on the default output device, resampled to its sample rate.
+/// A format change only replaces the resampler, the output device keeps running.
+pub struct CpalSink {
+    volume: Arc<AtomicF32>,
+    audio_session: Option<AudioSession>,
+    resampler: Option<(PcmFormat, FastFixedIn<f32>)>,
+    input_buffers: [Vec<f32>; 2],
+    output_buffers: [Vec<f32>; 2],
+    interleave_buffer: Vec<i16>
+}
+
+impl CpalSink {
+    /// The resampler needs a fixed number of input frames, SBC frames contain at most 128.
+    const CHUNK_SIZE: usize = 128;
+
+    pub fn new(volume: Arc<AtomicF32>) -> Self {
+        Self {
+            volume,
+            audio_session: None,
+            resampler: None,
+            input_buffers: from_fn(|_| Vec::with_capacity(Self::CHUNK_SIZE)),
+            output_buffers: from_fn(|_| Vec::new()),
+            interleave_buffer: Vec::new()
+        }
+    }
+
+    fn configure(&mut self, format: PcmFormat) -> anyhow::Result<()> {
+        if self.audio_session.is_none() {
+            self.audio_session = Some(AudioSession::new()?);
+        }
+        if matches!(&self.resampler, Some((current, _)) if *current == format) {
+            return Ok(());
+        }
+        // The buffered samples still belong to the previous format
+        self.resample(true);
+        let output_rate = self.audio_session.as_ref().map_or(0, |session| session.config().sample_rate.0);
+        let resampler = FastFixedIn::<f32>::new(
+            output_rate as f64 / format.sample_rate as f64,
+            1.0,
+            PolynomialDegree::Septic,
+            Self::CHUNK_SIZE,
+            2
+        )?;
+        self.output_buffers = from_fn(|_| vec![0f32; resampler.output_frames_max()]);
+        self.interleave_buffer = Vec::with_capacity(2 * resampler.output_frames_max());
+        self.resampler = Some((format, resampler));
+        Ok(())
+    }
+
+    /// Resamples the buffered input, which has to be a full chunk unless `partial` is set.
+    fn resample(&mut self, partial: bool) {
+        let (Some(audio_session), Some((_, resampler))) = (&mut self.audio_session, &mut self.resampler) else {
+            return;
+        };
+        if self.input_buffers[0].is_empty() {
+            return;
+        }
+        let result = match partial {
+            true => resampler.process_partial_into_buffer(Some(&self.input_buffers[..]), &mut self.output_buffers, None),
+            false => resampler.process_into_buffer(&self.input_buffers, &mut self.output_buffers, None)
+        };
+        self.input_buffers.iter_mut().for_each(Vec::clear);
+        let (_, len) = result.unwrap();
+
+        self.interleave_buffer.clear();
+        let volume = self.volume.load(SeqCst).powi(2);
+        for (&l, &r) in zip(&self.output_buffers[0], &self.output_buffers[1]).take(len) {
+            self.interleave_buffer.push((l * volume) as i16);
+            self.interleave_buffer.push((r * volume) as i16);
+        }
+        audio_session.writer().push_slice(&self.interleave_buffer);
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn on_start(&mut self, format: PcmFormat) {
+        if let Err(err) = self.configure(format) {
+            self.resampler = None;
+            return error!("Failed to open audio output: {:?}", err);
+        }
+        if let Some(audio_session) = &self.audio_session {
+            audio_session.play();
+        }
+    }
+
+    fn on_samples(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(2) {
+            self.input_buffers[0].push(frame[0] as f32);
+            self.input_buffers[1].push(frame[1] as f32);
+            if self.input_buffers[0].len() == Self::CHUNK_SIZE {
+                self.resample(false);
+            }
+        }
+    }
+
+    fn on_stop(&mut self) {
+        self.resample(true);
+        if let Some(audio_session) = &self.audio_session {
+            audio_session.stop();
+        }
+    }
+}
+
+/// Appends the raw interleaved 16 bit samples to a file.
+pub struct PcmFileSink {
+    path: String,
+    file: Option<BufWriter<File>>
+}
+
+impl PcmFileSink {
+    pub fn new(path: String) -> Self {
+        Self { path, file: None }
+    }
+}
+
+impl AudioSink for PcmFileSink {
+    fn on_start(&mut self, format: PcmFormat) {
+        trace!("Writing {:?} to {}", format, self.path);
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map(BufWriter::new)
+            .inspect_err(|err| error!("Failed to open {}: {}", self.path, err))
+            .ok();
+    }
+
+    fn on_samples(&mut self, samples: &[i16]) {
+        let Some(file) = &mut self.file else { return };
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        if let Err(err) = file.write_all(&bytes) {
+            error!("Failed to write to {}: {}", self.path, err);
+            self.file = None;
+        }
+    }
+
+    fn on_stop(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            file.flush()
+                .unwrap_or_else(|err| error!("Failed to write to {}: {}", self.path, err));
+        }
+    }
+}
+
+pub struct AudioSession {
+    stream: Stream,
+    config: StreamConfig,
+    buffer: HeapProd<i16>,
+    max_buffer_size: usize
+}
+
+impl AudioSession {
+    pub fn new() -> anyhow::Result<Self> {
+        let host = default_host();
+        let device = host
+            .default_output_device()
+            .context("failed to find output device")?;
+
+        let config = device
+            .supported_output_configs()?
+            .inspect(|config| trace!("supported output config: {:?}", config))
+            .find(|config| config.sample_format() == SampleFormat::I16 && config.channels() == 2)
+            .context("failed to find output config")?
+            .with_max_sample_rate()
+            .config();
+        trace!("selected output config: {:?}", config);
+
+        let max_buffer_size = (config.sample_rate.0 * config.channels as u32) as usize;
+        let buffer: Arc<HeapRb<i16>> = Arc::new(HeapRb::new(max_buffer_size));
+        let (buffer, mut consumer) = buffer.split();
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [i16], _info| {
+                let len = consumer.pop_slice(data);
+                //data[..len].iter_mut().for_each(|d| *d *=  8);
+                data[len..].fill(0);
+            },
+            move |err| {
+                error!("an error occurred on the output stream: {}", err);
+            },
+            None
+        )?;
+
+        Ok(Self {
+            stream,
+            config,
+            buffer,
+            max_buffer_size
+        })
+    }
+
+    pub fn play(&self) {
+        self.stream.play().unwrap();
+    }
+
+    pub fn stop(&self) {
+        self.stream.pause().unwrap();
+    }
+
+    pub fn writer(&mut self) -> &mut HeapProd<i16> {
+        &mut self.buffer
+    }
+
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+}
+
+pub async fn retrieve_current_track_info(session: &AvrcpSession) -> anyhow::Result<()> {
+    let current_track: CurrentTrack = session.register_notification(None).await?;
+    match current_track {
+        CurrentTrack::NotSelected => println!("No track selected"),
+        CurrentTrack::Selected => {
+            let attributes = session
+                .get_current_media_attributes(Some(&[MediaAttributeId::Title, MediaAttributeId::ArtistName]))
+                .await?;
+            println!(
+                "Current Track: {} - {}",
+                attributes
+                    .get(&MediaAttributeId::ArtistName)
+                    .map_or("", String::as_str),
+                attributes
+                    .get(&MediaAttributeId::Title)
+                    .map_or("", String::as_str)
+            );
+        }
+        CurrentTrack::Id(id) => println!("Track ID: {:?}", id)
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+//! A line based control protocol for the speaker, served on a Unix socket so it can be scripted,
+//! e.g. with `socat - UNIX-CONNECT:speaker.sock`.
+//!
+//! Every command is a single line. The reply consists of zero or more result lines followed by `OK` or `ERR <reason>`.
+//!
+//! | Command                | Result                                               |
+//! |------------------------|------------------------------------------------------|
+//! | `devices`              | `<address> <bonded\|-> <connected\|->` per device    |
+//! | `pair <on\|off>`       | Enters or leaves pairing mode (discoverable)         |
+//! | `connect <address>`    | Connects to the device                               |
+//! | `disconnect <address>` | Disconnects the device                               |
+//! | `volume [0-100]`       | Prints or changes the volume                         |
+//! | `metadata`             | `<attribute>: <value>` for the current track         |
+//! | `pending`              | `<address> <psm>` per connection awaiting approval   |
+//! | `trust <address>`      | Accepts the pending connections and trusts the device |
+//! | `reject <address>`     | Rejects the pending connections of the device        |
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use bluefang::avrcp::AvrcpController;
+use bluefang::hci::connection::{ConnectionEvent, ConnectionEventReceiver};
+use bluefang::hci::consts::{BdAddr, Status};
+use bluefang::hci::remote_info::RemoteInfoCache;
+use bluefang::hci::Hci;
+use bluefang::l2cap::authorization::{AuthorizationRequest, AuthorizationRequests};
+use parking_lot::Mutex;
+use portable_atomic::AtomicF32;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::spawn;
+use tracing::{debug, warn};
+
+#[derive(Clone)]
+pub struct Control {
+    host: Arc<Hci>,
+    remote_info: RemoteInfoCache,
+    volume: Arc<AtomicF32>,
+    connections: Arc<Mutex<BTreeMap<u16, BdAddr>>>,
+    avrcp: Arc<Mutex<Option<AvrcpController>>>,
+    pending: Arc<Mutex<Vec<AuthorizationRequest>>>
+}
+
+impl Control {
+    pub fn new(host: Arc<Hci>, remote_info: RemoteInfoCache, volume: Arc<AtomicF32>) -> Self {
+        Self {
+            host,
+            remote_info,
+            volume,
+            connections: Default::default(),
+            avrcp: Default::default(),
+            pending: Default::default()
+        }
+    }
+
+    /// Makes `controller` the target of the `volume` and `metadata` commands.
+    pub fn set_avrcp(&self, controller: AvrcpController) {
+        *self.avrcp.lock() = Some(controller);
+    }
+
+    /// Keeps the connection requests of untrusted devices until they are answered with `trust` or `reject`.
+    pub fn handle_authorizations(&self, mut requests: AuthorizationRequests) {
+        let pending = self.pending.clone();
+        spawn(async move {
+            while let Some(request) = requests.recv().await {
+                warn!("{} wants to connect to PSM {:#06X}, answer with `trust` or `reject`", request.addr, request.psm);
+                let mut pending = pending.lock();
+                // Requests that timed out in the meantime are answered already
+                pending.retain(|request| !request.is_answered());
+                pending.push(request);
+            }
+        });
+    }
+
+    pub async fn serve<P: AsRef<Path>>(self, path: P) -> anyhow::Result<()> {
+        // A stale socket of a previous run would make binding fail
+        let _ = std::fs::remove_file(path.as_ref());
+        let listener = UnixListener::bind(path.as_ref())?;
+        let mut events = ConnectionEventReceiver::new(&self.host)?;
+        loop {
+            tokio::select! {
+                client = listener.accept() => {
+                    let (stream, _) = client?;
+                    spawn(self.clone().handle_client(stream));
+                }
+                Some(event) = events.recv() => self.track_connection(event)
+            }
+        }
+    }
+
+    fn track_connection(&self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::ConnectionComplete { status, handle, addr, .. } if status.is_ok() => {
+                self.connections.lock().insert(handle, addr);
+            }
+            ConnectionEvent::DisconnectionComplete { status, handle, .. } if status.is_ok() => {
+                self.connections.lock().remove(&handle);
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_client(self, stream: UnixStream) {
+        debug!("Control client connected");
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply = match self.execute(line.trim()).await {
+                Ok(result) => result + "OK\n",
+                Err(err) => format!("ERR {:#}\n", err)
+            };
+            if let Err(err) = writer.write_all(reply.as_bytes()).await {
+                warn!("Failed to reply to control client: {:?}", err);
+                break;
+            }
+        }
+        debug!("Control client disconnected");
+    }
+
+    async fn execute(&self, line: &str) -> anyhow::Result<String> {
+        let mut args = line.split_whitespace();
+        let command = args.next().unwrap_or_default();
+        let argument = args.next();
+        let mut result = String::new();
+        match (command, argument) {
+            ("devices", None) => {
+                let bonded = self.remote_info.bonded();
+                let connected: BTreeSet<BdAddr> = self.connections.lock().values().copied().collect();
+                let devices: BTreeSet<BdAddr> = bonded.iter().chain(connected.iter()).copied().collect();
+                for addr in devices {
+                    let bonded = if bonded.contains(&addr) { "bonded" } else { "-" };
+                    let connected = if connected.contains(&addr) { "connected" } else { "-" };
+                    result += &format!("{} {} {}\n", addr, bonded, connected);
+                }
+            }
+            ("pair", Some(mode @ ("on" | "off"))) => self.host.set_scan_enabled(true, mode == "on").await?,
+            ("connect", Some(addr)) => self.host.create_connection(parse_addr(addr)?, true).await?,
+            ("disconnect", Some(addr)) => {
+                let addr = parse_addr(addr)?;
+                let handle = self
+                    .connections
+                    .lock()
+                    .iter()
+                    .find_map(|(handle, connected)| (*connected == addr).then_some(*handle))
+                    .context("device is not connected")?;
+                self.host
+                    .disconnect(handle, Status::RemoteUserTerminatedConnection)
+                    .await?;
+            }
+            ("volume", None) => result += &format!("{}\n", (self.volume.load(SeqCst) * 100.0).round()),
+            ("volume", Some(percent)) => {
+                let percent: u8 = percent.parse().ok().filter(|p| *p <= 100).context("volume must be between 0 and 100")?;
+                let volume = percent as f32 / 100.0;
+                self.volume.store(volume, SeqCst);
+                if let Some(avrcp) = self.avrcp() {
+                    avrcp
+                        .notify_local_volume_change(volume)
+                        .await
+                        .map_err(|err| anyhow!("{}", err))?;
+                }
+            }
+            ("metadata", None) => {
+                let avrcp = self.avrcp().context("no device is connected")?;
+                let attributes = avrcp
+                    .get_current_media_attributes(None)
+                    .await
+                    .map_err(|err| anyhow!("{}", err))?;
+                for (id, value) in attributes {
+                    result += &format!("{:?}: {}\n", id, value);
+                }
+            }
+            ("pending", None) => {
+                for request in self.pending.lock().iter().filter(|request| !request.is_answered()) {
+                    result += &format!("{} {:#06X}\n", request.addr, request.psm);
+                }
+            }
+            ("trust", Some(addr)) => {
+                let addr = parse_addr(addr)?;
+                self.remote_info.update_settings(addr, |settings| settings.trusted = true);
+                self.take_pending(addr).into_iter().for_each(AuthorizationRequest::trust);
+            }
+            ("reject", Some(addr)) => {
+                let requests = self.take_pending(parse_addr(addr)?);
+                if requests.is_empty() {
+                    bail!("no pending connection");
+                }
+                requests.into_iter().for_each(AuthorizationRequest::reject);
+            }
+            _ => bail!("unknown command: {}", line)
+        }
+        Ok(result)
+    }
+
+    fn take_pending(&self, addr: BdAddr) -> Vec<AuthorizationRequest> {
+        let mut pending = self.pending.lock();
+        let (matching, rest): (Vec<_>, Vec<_>) = pending.drain(..).partition(|request| request.addr == addr);
+        *pending = rest;
+        matching
+    }
+
+    fn avrcp(&self) -> Option<AvrcpController> {
+        self.avrcp
+            .lock()
+            .as_ref()
+            .filter(|avrcp| !avrcp.is_closed())
+            .cloned()
+    }
+}
+
+fn parse_addr(addr: &str) -> anyhow::Result<BdAddr> {
+    addr.parse().ok().context("invalid address")
+}
@@ -0,0 +1,392 @@
+//! An A2DP source streaming to a Bluetooth speaker.
+//!
+//! Connects to the speaker given as the first argument, or to the first audio sink found by an inquiry, pairs with it,
+//! sets up an SBC stream and an AVRCP session and streams the WAV file given as the second argument or stdin.
+//! Input without a WAV header is read as 16 bit stereo PCM at 44.1 kHz,
+//! e.g. `ffmpeg -i song.mp3 -f s16le -ar 44100 -ac 2 - | cargo run --example bluefang-source`.
+//! The PCM is encoded to SBC on the fly and scaled by the volume of the speaker, which also controls playback.
+use std::fs::File;
+use std::io::{stdin, Cursor, ErrorKind, Read};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context};
+use bluefang::a2dp::routing::PcmFormat;
+use bluefang::a2dp::sbc::{AllocationMethods, BlockLengths, ChannelModes, SamplingFrequencies, SbcMediaCodecInformation, Subbands};
+use bluefang::a2dp::sdp::A2dpSourceServiceRecord;
+use bluefang::a2dp::source::{MediaPacer, Pacing};
+use bluefang::avc::{PassThroughOp, PassThroughState};
+use bluefang::avdtp::capabilities::{Capability, MediaCodecCapability};
+use bluefang::avdtp::{AvdtpClient, MediaSender, MediaType, StreamEndpointType};
+use bluefang::avrcp::notifications::Volume;
+use bluefang::avrcp::{Avrcp, AvrcpSession, Event, Notification};
+use bluefang::firmware::{FolderFileProvider, RealTekFirmwareLoader};
+use bluefang::hci::connection::ConnectionManagerBuilder;
+use bluefang::hci::consts::{BdAddr, DeviceClass, Lap, MajorServiceClasses};
+use bluefang::hci::establish::ProfileRequest;
+use bluefang::hci::{FirmwareLoader, Hci};
+use bluefang::host::usb::UsbController;
+use bluefang::profile::ProfileRegistry;
+use bytes::Bytes;
+use portable_atomic::AtomicF32;
+use tokio::spawn;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::watch;
+use tokio::time::sleep_until;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::common::encoder::SbcEncoder;
+
+mod common;
+
+const INQUIRY_LENGTH: u8 = 8;
+/// How far ahead of real time the audio is sent to give the speaker some buffer.
+const LEAD_TIME: Duration = Duration::from_millis(150);
+const LOCAL_SEID: u8 = 1;
+/// The bitpools recommended for high quality ([A2DP] Table 4.7), if the speaker supports them.
+const STEREO_BITPOOL: u8 = 53;
+const MONO_BITPOOL: u8 = 31;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(layer().without_time())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    let target = std::env::args().nth(1).filter(|arg| arg != "-");
+    let input: Box<dyn Read + Send> = match std::env::args().nth(2).filter(|arg| arg != "-") {
+        Some(path) => Box::new(File::open(&path).with_context(|| format!("failed to open {}", path))?),
+        None => Box::new(stdin())
+    };
+    let (format, input) = read_pcm_format(input)?;
+    info!("Input format: {} Hz, {} channels", format.sample_rate, format.channels);
+
+    Hci::register_firmware_loaders([
+        RealTekFirmwareLoader::new(FolderFileProvider::from_env("./firmware")).boxed()
+    ]);
+
+    let usb = UsbController::list(|info| info.vendor_id() == 0x2B89 || info.vendor_id() == 0x10D7)?
+        .next()
+        .context("failed to find device")?
+        .claim()?;
+
+    let host = Arc::new(Hci::new(usb).await?);
+    info!("Local BD_ADDR: {}", host.read_bd_addr().await?);
+
+    let conn_manager = ConnectionManagerBuilder::default()
+        .spawn(host.clone())
+        .await?;
+
+    let (paused_tx, mut paused) = watch::channel(false);
+    let volume = Arc::new(AtomicF32::new(1.0));
+    let avrcp = Avrcp::new({
+        let volume = volume.clone();
+        move |session| avrcp_session_handler(paused_tx.clone(), volume.clone(), session)
+    });
+    let mut registry = ProfileRegistry::default().with_profile(avrcp.clone());
+    let handle = registry.allocate_record_handle();
+    let registry = registry.with_record(A2dpSourceServiceRecord::new(handle));
+    let stack = registry.start(&host)?;
+    let opener = stack.channel_opener();
+
+    let addr = match target {
+        Some(addr) => addr.parse().map_err(|_| anyhow::anyhow!("invalid address: {}", addr))?,
+        None => find_speaker(&host).await?
+    };
+    let connection = conn_manager
+        .establish(&opener, addr, &[ProfileRequest::A2dpSink])
+        .await
+        .with_context(|| format!("failed to connect to {}", addr))?;
+    let handle = connection.handle;
+    info!("Connected to {} (handle: 0x{:04X})", addr, handle);
+    let mut client = connection.avdtp.context("missing AVDTP signaling channel")?;
+    let (remote_seid, configuration) = select_endpoint(&mut client, format).await?;
+    client
+        .set_configuration(remote_seid, LOCAL_SEID, &[
+            Capability::MediaTransport,
+            Capability::MediaCodec(configuration.into())
+        ])
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to configure stream: {:?}", err))?;
+    let mut sender = client
+        .open(remote_seid)
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to open stream: {:?}", err))?;
+    avrcp
+        .connect(&opener, handle)
+        .await
+        .unwrap_or_else(|err| warn!("Failed to connect AVRCP: {:?}", err));
+
+    client
+        .start(remote_seid)
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to start stream: {:?}", err))?;
+    info!("Streaming started");
+    let encoder = SbcEncoder::new(format.sample_rate, configuration.channel_modes, 16, configuration.maximum_bitpool);
+    let mut frames = spawn_encoder(input, encoder, volume);
+    let mut pacer = MediaPacer::new(format.sample_rate, Pacing::Timer { lead: LEAD_TIME });
+    let mut pending = None;
+    loop {
+        if *paused.borrow_and_update() {
+            client
+                .suspend(remote_seid)
+                .await
+                .unwrap_or_else(|err| warn!("Failed to suspend stream: {:?}", err));
+            info!("Paused");
+            if paused.wait_for(|paused| !*paused).await.is_err() {
+                break;
+            }
+            client
+                .start(remote_seid)
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to restart stream: {:?}", err))?;
+            pacer.resume();
+            info!("Resumed");
+        }
+        let frame = match pending.take() {
+            Some(frame) => frame,
+            None => match frames.recv().await {
+                Some(frame) => frame,
+                None => break
+            }
+        };
+        pending = send_packet(&mut sender, &mut pacer, frame, &mut frames).await?;
+    }
+    info!("Reached the end of the input");
+
+    client
+        .close(remote_seid)
+        .await
+        .unwrap_or_else(|err| warn!("Failed to close stream: {:?}", err));
+    stack.stop();
+    host.shutdown().await?;
+    Ok(())
+}
+
+/// Returns the first device that advertises itself as an audio sink.
+async fn find_speaker(host: &Hci) -> anyhow::Result<BdAddr> {
+    info!("Searching for speakers...");
+    let results = host.inquiry(Lap::General, INQUIRY_LENGTH, 0).await?;
+    for result in &results {
+        info!("Found {} ({:?})", result.addr, result.class_of_device);
+    }
+    results
+        .iter()
+        .find(|result| {
+            result
+                .class_of_device
+                .service_classes
+                .contains(MajorServiceClasses::Rendering)
+                && matches!(result.class_of_device.device_class, DeviceClass::AudioVideo(_))
+        })
+        .map(|result| result.addr)
+        .context("failed to find a speaker")
+}
+
+/// Finds an SBC sink endpoint that supports the format of the input.
+async fn select_endpoint(client: &mut AvdtpClient, format: PcmFormat) -> anyhow::Result<(u8, SbcMediaCodecInformation)> {
+    let endpoints = client
+        .discover()
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to discover endpoints: {:?}", err))?;
+    for ep in endpoints {
+        if ep.in_use || ep.media_type != MediaType::Audio || ep.tsep != StreamEndpointType::Sink {
+            continue;
+        }
+        let capabilities = match client.get_capabilities(ep.seid).await {
+            Ok(capabilities) => capabilities,
+            Err(err) => {
+                warn!("Failed to get capabilities of 0x{:02x}: {:?}", ep.seid, err);
+                continue;
+            }
+        };
+        let supported = capabilities.iter().find_map(|cap| match cap {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Some(*info),
+            _ => None
+        });
+        if let Some(configuration) = supported.and_then(|supported| sbc_configuration(format, &supported)) {
+            return Ok((ep.seid, configuration));
+        }
+        info!("Endpoint 0x{:02x} does not support the input format", ep.seid);
+    }
+    bail!("the speaker does not support {:?}", format)
+}
+
+/// The stream configuration the encoder uses for `format` if it's within the `supported` capabilities.
+fn sbc_configuration(format: PcmFormat, supported: &SbcMediaCodecInformation) -> Option<SbcMediaCodecInformation> {
+    let sampling_frequency = match format.sample_rate {
+        16000 => SamplingFrequencies::FREQ_16000,
+        32000 => SamplingFrequencies::FREQ_32000,
+        44100 => SamplingFrequencies::FREQ_44100,
+        48000 => SamplingFrequencies::FREQ_48000,
+        _ => return None
+    };
+    let (channel_mode, bitpool) = match format.channels {
+        1 => (ChannelModes::MONO, MONO_BITPOOL),
+        _ => (ChannelModes::STEREO, STEREO_BITPOOL)
+    };
+    let bitpool = bitpool.min(supported.maximum_bitpool);
+    let supported = supported.sampling_frequencies.contains(sampling_frequency)
+        && supported.channel_modes.contains(channel_mode)
+        && supported.block_lengths.contains(BlockLengths::SIXTEEN)
+        && supported.subbands.contains(Subbands::EIGHT)
+        && supported.allocation_methods.contains(AllocationMethods::LOUDNESS)
+        && bitpool >= supported.minimum_bitpool;
+    supported.then_some(SbcMediaCodecInformation {
+        sampling_frequencies: sampling_frequency,
+        channel_modes: channel_mode,
+        block_lengths: BlockLengths::SIXTEEN,
+        subbands: Subbands::EIGHT,
+        allocation_methods: AllocationMethods::LOUDNESS,
+        minimum_bitpool: supported.minimum_bitpool,
+        maximum_bitpool: bitpool
+    })
+}
+
+/// Fills a media packet with as many frames as possible ([A2DP] Section 4.3.4).
+/// Returns the first frame that didn't fit anymore.
+async fn send_packet(
+    sender: &mut MediaSender, pacer: &mut MediaPacer, first: SbcFrame, frames: &mut Receiver<SbcFrame>
+) -> anyhow::Result<Option<SbcFrame>> {
+    const MAX_FRAMES: usize = 15;
+    let max_size = sender.max_payload_size();
+    let mut payload = vec![0u8];
+    let mut samples = 0;
+    let mut count = 0;
+    let mut next = Some(first);
+    while let Some(frame) = next.take() {
+        if count > 0 && (count == MAX_FRAMES || payload.len() + frame.data.len() > max_size) {
+            next = Some(frame);
+            break;
+        }
+        payload.extend_from_slice(&frame.data);
+        samples += frame.samples;
+        count += 1;
+        next = frames.try_recv().ok();
+    }
+    payload[0] = count as u8;
+    sleep_until(pacer.deadline(None)).await;
+    let timestamp = pacer.advance(samples);
+    sender
+        .send(timestamp, &payload)
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to send media packet: {:?}", err))?;
+    Ok(next)
+}
+
+fn avrcp_session_handler(paused: watch::Sender<bool>, volume: Arc<AtomicF32>, mut session: AvrcpSession) {
+    let set_volume = move |level: f32| {
+        info!("Speaker volume: {}%", (level * 100.0).round());
+        volume.store(level, Relaxed);
+    };
+    spawn(async move {
+        let supported_events = session.get_supported_events().await.unwrap_or_default();
+        if supported_events.contains(&Volume::EVENT_ID) {
+            match session.subscribe::<Volume>(None).await {
+                Ok(Volume(level)) => set_volume(level),
+                Err(err) => warn!("Failed to register for volume changes: {}", err)
+            }
+        }
+        while let Some(event) = session.next_event().await {
+            match event {
+                Event::VolumeChanged(level, _) => set_volume(level),
+                Event::PassThrough(op, PassThroughState::Pressed) => match op {
+                    PassThroughOp::Play => {
+                        paused.send_replace(false);
+                    }
+                    PassThroughOp::Pause | PassThroughOp::Stop => {
+                        paused.send_replace(true);
+                    }
+                    _ => info!("Ignoring {:?}", op)
+                },
+                _ => {}
+            }
+        }
+    });
+}
+
+/// An encoded SBC frame ([A2DP] Section 12.6).
+struct SbcFrame {
+    data: Bytes,
+    /// The number of samples per channel.
+    samples: u32
+}
+
+/// Reads the header of a WAV input, input without one is returned unchanged as 16 bit stereo PCM at 44.1 kHz.
+fn read_pcm_format(mut input: Box<dyn Read + Send>) -> anyhow::Result<(PcmFormat, Box<dyn Read + Send>)> {
+    let mut riff = [0u8; 12];
+    input.read_exact(&mut riff).context("input is empty")?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        let format = PcmFormat {
+            sample_rate: 44100,
+            channels: 2
+        };
+        return Ok((format, Box::new(Cursor::new(riff).chain(input))));
+    }
+    let mut format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        input.read_exact(&mut chunk).context("WAV file has no data")?;
+        let length = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut fmt = vec![0u8; length + length % 2];
+                input.read_exact(&mut fmt)?;
+                ensure!(fmt.len() >= 16, "invalid WAV format");
+                let tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+                let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                // PCM or WAVE_FORMAT_EXTENSIBLE
+                ensure!((tag == 0x0001 || tag == 0xFFFE) && bits == 16, "only 16 bit PCM is supported");
+                ensure!(channels == 1 || channels == 2, "only mono and stereo are supported");
+                format = Some(PcmFormat {
+                    sample_rate,
+                    channels: channels as u8
+                });
+            }
+            b"data" => break,
+            _ => {
+                std::io::copy(&mut (&mut input).take((length + length % 2) as u64), &mut std::io::sink())?;
+            }
+        }
+    }
+    Ok((format.context("WAV file has no format")?, input))
+}
+
+/// Encodes the PCM input on a separate thread, as stdin can't be read asynchronously.
+/// The samples are scaled by the current `volume`.
+fn spawn_encoder(mut input: Box<dyn Read + Send>, mut encoder: SbcEncoder, volume: Arc<AtomicF32>) -> Receiver<SbcFrame> {
+    let (tx, rx) = channel(64);
+    std::thread::spawn(move || {
+        let samples = encoder.samples_per_frame();
+        let mut buffer = vec![0u8; samples * encoder.channels() * 2];
+        let mut pcm = vec![0i16; samples * encoder.channels()];
+        loop {
+            match input.read_exact(&mut buffer) {
+                Ok(()) => {}
+                // A partial frame at the end is dropped
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => break warn!("Failed to read input: {}", err)
+            }
+            let volume = volume.load(Relaxed);
+            for (sample, bytes) in pcm.iter_mut().zip(buffer.chunks_exact(2)) {
+                *sample = (i16::from_le_bytes([bytes[0], bytes[1]]) as f32 * volume) as i16;
+            }
+            let frame = SbcFrame {
+                data: Bytes::from(encoder.encode(&pcm)),
+                samples: samples as u32
+            };
+            if tx.blocking_send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
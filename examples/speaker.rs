@@ -0,0 +1,302 @@
+//! A headless Bluetooth speaker.
+//!
+//! Makes the controller discoverable under the name given as the first argument, accepts pairings without user
+//! interaction, plays A2DP audio through the default output device, syncs the volume over AVRCP and reconnects
+//! to the last connected device on startup and after the link was lost.
+//! On Unix, the speaker can be scripted through the commands accepted on `speaker.sock`, see the `control` module.
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use bluefang::a2dp::routing::AudioRouter;
+use bluefang::a2dp::sbc::SbcMediaCodecInformation;
+use bluefang::avc::{PassThroughOp, PassThroughState};
+use bluefang::avdtp::capabilities::Capability;
+use bluefang::avdtp::{AvdtpBuilder, LocalEndpoint, MediaType, StreamEndpointType, StreamHandlerFactory};
+use bluefang::avrcp::notifications::CurrentTrack;
+use bluefang::avrcp::{Avrcp, AvrcpSession, Event, Notification, VolumeOrigin};
+use bluefang::firmware::{FolderFileProvider, RealTekFirmwareLoader, VendorCommands};
+use bluefang::hci::connection::{ConnectionEvent, ConnectionEventReceiver, ConnectionManagerBuilder};
+use bluefang::hci::consts::{AudioVideoClass, BdAddr, DeviceClass, Status};
+use bluefang::hci::remote_info::RemoteInfoCache;
+use bluefang::hci::{DeviceIdentity, ExtendedAdvertisingParameters, FirmwareLoader, Hci};
+use bluefang::host::usb::UsbController;
+use bluefang::l2cap::authorization::ConnectionAuthorizer;
+use bluefang::profile::{ProfileRegistry, ProfileStack};
+use bluefang::storage::{FileStorage, Storage};
+use portable_atomic::AtomicF32;
+use tokio::time::timeout;
+use tokio::spawn;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::common::{retrieve_current_track_info, CpalSink, PcmFileSink, SbcStreamHandler};
+
+mod common;
+#[cfg(unix)]
+mod control;
+
+const STORAGE_DIR: &str = "speaker-state";
+const SPEAKER_NAMESPACE: &str = "speaker";
+const LAST_DEVICE_KEY: &str = "last-device";
+#[cfg(unix)]
+const CONTROL_SOCKET: &str = "speaker.sock";
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+const RECONNECT_ATTEMPTS: u32 = 6;
+const VOLUME_STEP: f32 = 1.0 / 16.0;
+const SUSPEND_GRACE_PERIOD: Duration = Duration::from_secs(2);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const AUDIO_BUFFER_PACKETS: usize = 256;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(layer().without_time())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    let name = std::env::args().nth(1).unwrap_or_else(|| String::from("bluefang"));
+
+    let bd_addr: Option<BdAddr> = match std::env::var("BD_ADDR") {
+        Ok(addr) => Some(addr.parse().ok().context("invalid BD_ADDR")?),
+        Err(_) => None
+    };
+    // Realtek controllers take the address with the firmware, the others through a vendor command
+    let mut realtek = RealTekFirmwareLoader::new(FolderFileProvider::from_env("./firmware"));
+    if let Some(addr) = bd_addr {
+        realtek = realtek.with_bd_addr(addr);
+    }
+    Hci::register_firmware_loaders([realtek.boxed(), VendorCommands.boxed()]);
+
+    let usb = UsbController::list(|info| info.vendor_id() == 0x2B89 || info.vendor_id() == 0x10D7)?
+        .next()
+        .context("failed to find device")?
+        .claim()?;
+
+    let host = Arc::new(Hci::new(usb).await?);
+    if let Some(addr) = bd_addr {
+        host.set_bd_addr(addr).await.context("failed to change the address of the controller")?;
+    }
+    info!("Local BD_ADDR: {}", host.read_bd_addr().await?);
+
+    let storage = Storage::new(FileStorage::new(STORAGE_DIR));
+    // Earlier versions kept the cache in a single file, the link keys are moved by the connection manager
+    RemoteInfoCache::migrate_file(&storage, "remote-info.dat").await?;
+    let remote_info = RemoteInfoCache::load(storage.clone()).await?;
+    let _conn_manager = ConnectionManagerBuilder::default()
+        .with_storage(storage.clone())
+        .with_remote_info_cache(remote_info.clone())
+        .spawn(host.clone())
+        .await?;
+
+    let volume = Arc::new(AtomicF32::new(1.0));
+    let audio = AudioRouter::new();
+    audio.add_sink("speaker", AUDIO_BUFFER_PACKETS, {
+        let volume = volume.clone();
+        move || CpalSink::new(volume)
+    });
+    // Tees the decoded audio into a file, e.g. a named pipe read by a network streamer
+    if let Ok(path) = std::env::var("PCM_DUMP") {
+        audio.add_sink("dump", AUDIO_BUFFER_PACKETS, move || PcmFileSink::new(path));
+    }
+    let (authorizer, authorizations) = ConnectionAuthorizer::new(remote_info.clone());
+    #[cfg(unix)]
+    let control = control::Control::new(host.clone(), remote_info.clone(), volume.clone());
+    #[cfg(unix)]
+    control.handle_authorizations(authorizations);
+    // Without the control socket there is nobody to ask
+    #[cfg(not(unix))]
+    spawn(async move {
+        let mut authorizations = authorizations;
+        while let Some(request) = authorizations.recv().await {
+            request.trust();
+        }
+    });
+    let registry = ProfileRegistry::default()
+        .with_authorizer(authorizer)
+        .with_profile(
+            Avrcp::new({
+                let volume = volume.clone();
+                let remote_info = remote_info.clone();
+                #[cfg(unix)]
+                let control = control.clone();
+                move |session| {
+                    #[cfg(unix)]
+                    control.set_avrcp(session.controller());
+                    avrcp_session_handler(volume.clone(), remote_info.clone(), session)
+                }
+            })
+            .with_feature_discovery()
+            .with_remote_info_cache(remote_info)
+        )
+        .with_profile(
+            AvdtpBuilder::default()
+                .with_endpoint(LocalEndpoint {
+                    media_type: MediaType::Audio,
+                    seid: 1,
+                    in_use: Arc::new(AtomicBool::new(false)),
+                    tsep: StreamEndpointType::Sink,
+                    capabilities: vec![
+                        Capability::MediaTransport,
+                        Capability::MediaCodec(SbcMediaCodecInformation::default().into()),
+                    ],
+                    factory: StreamHandlerFactory::new({
+                        let audio = audio.clone();
+                        move |cap| SbcStreamHandler::new(audio.clone(), cap)
+                    })
+                })
+                .with_suspend_grace_period(SUSPEND_GRACE_PERIOD)
+                .with_idle_timeout(IDLE_TIMEOUT)
+                .build()
+        );
+    for profile in registry.manifest() {
+        info!("Registered {}", profile);
+    }
+    let cod = registry.class_of_device(DeviceClass::AudioVideo(AudioVideoClass::Loudspeaker));
+    let stack = registry.start(&host)?;
+
+    host.set_identity(DeviceIdentity::new(name.as_str(), cod)).await?;
+    host.set_scan_enabled(true, true).await?;
+    // Lets phones find the speaker in an LE scan as well, older controllers lack extended advertising
+    host.advertise_identity(ExtendedAdvertisingParameters::default())
+        .await
+        .unwrap_or_else(|err| warn!("LE advertising unavailable: {:#}", err));
+
+    let reconnect = spawn(auto_reconnect(host.clone(), storage));
+    #[cfg(unix)]
+    spawn(async move {
+        control
+            .serve(CONTROL_SOCKET)
+            .await
+            .unwrap_or_else(|err| warn!("Control socket failed: {:#}", err))
+    });
+
+    println!("{} is ready, press Ctrl-C to exit", name);
+    wait_for_exit(&stack).await?;
+
+    reconnect.abort();
+    stack.stop();
+    host.shutdown().await?;
+    Ok(())
+}
+
+/// Waits for Ctrl-C and dumps the state of the stack whenever `SIGUSR1` is received.
+#[cfg(unix)]
+async fn wait_for_exit(stack: &ProfileStack) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut dump = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => return Ok(res?),
+            _ = dump.recv() => info!("{:#?}", stack.debug_snapshot().await)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_exit(_stack: &ProfileStack) -> anyhow::Result<()> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+/// Restores the volume last used with the device and remembers every change.
+fn avrcp_session_handler(volume: Arc<AtomicF32>, remote_info: RemoteInfoCache, mut session: AvrcpSession) {
+    let addr = session.controller().remote_addr();
+    spawn(async move {
+        if let Some(vol) = remote_info.settings(addr).volume() {
+            info!("Restoring volume of {}: {}%", addr, (vol * 100.0).round());
+            volume.store(vol, SeqCst);
+        }
+        session
+            .notify_local_volume_change(volume.load(SeqCst))
+            .await
+            .unwrap_or_else(|err| warn!("Failed to notify volume change: {}", err));
+        let supported_events = session.get_supported_events().await.unwrap_or_default();
+        if supported_events.contains(&CurrentTrack::EVENT_ID) {
+            retrieve_current_track_info(&session)
+                .await
+                .unwrap_or_else(|err| warn!("Failed to retrieve current track info: {}", err));
+        }
+        while let Some(event) = session.next_event().await {
+            match event {
+                Event::TrackChanged(_) => {
+                    retrieve_current_track_info(&session)
+                        .await
+                        .unwrap_or_else(|err| warn!("Failed to retrieve current track info: {}", err));
+                }
+                // Local changes were already applied when the button was pressed
+                Event::VolumeChanged(vol, VolumeOrigin::Remote) => {
+                    volume.store(vol, SeqCst);
+                    remote_info.update_settings(addr, |settings| settings.set_volume(vol));
+                    info!("Volume: {}%", (vol * 100.0).round());
+                }
+                Event::PassThrough(op @ (PassThroughOp::VolumeUp | PassThroughOp::VolumeDown), PassThroughState::Pressed) => {
+                    let step = if op == PassThroughOp::VolumeUp { VOLUME_STEP } else { -VOLUME_STEP };
+                    let vol = (volume.load(SeqCst) + step).clamp(0.0, 1.0);
+                    volume.store(vol, SeqCst);
+                    remote_info.update_settings(addr, |settings| settings.set_volume(vol));
+                    info!("Volume: {}%", (vol * 100.0).round());
+                    session
+                        .notify_local_volume_change(vol)
+                        .await
+                        .unwrap_or_else(|err| warn!("Failed to notify volume change: {}", err));
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Reconnects to the last connected device on startup and whenever its link times out.
+async fn auto_reconnect(host: Arc<Hci>, storage: Storage) {
+    let mut events = match ConnectionEventReceiver::new(&host) {
+        Ok(events) => events,
+        Err(err) => return warn!("Failed to listen for connection events: {:?}", err)
+    };
+    let mut last_device: Option<BdAddr> = storage
+        .read(SPEAKER_NAMESPACE, LAST_DEVICE_KEY)
+        .ok()
+        .flatten()
+        .and_then(|addr| String::from_utf8(addr).ok()?.parse().ok());
+    let mut connected: Option<u16> = None;
+    let mut attempts = 0;
+    loop {
+        if let (None, Some(addr)) = (connected, last_device) {
+            if attempts < RECONNECT_ATTEMPTS {
+                attempts += 1;
+                info!("Reconnecting to {} (attempt {}/{})", addr, attempts, RECONNECT_ATTEMPTS);
+                host.create_connection(addr, true)
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to create connection: {:?}", err));
+            }
+        }
+        let event = match timeout(RECONNECT_INTERVAL, events.recv()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(_) => continue
+        };
+        match event {
+            ConnectionEvent::ConnectionComplete { status, handle, addr, .. } if status.is_ok() => {
+                connected = Some(handle);
+                attempts = RECONNECT_ATTEMPTS;
+                if last_device != Some(addr) {
+                    last_device = Some(addr);
+                    storage
+                        .write(SPEAKER_NAMESPACE, LAST_DEVICE_KEY, addr.to_string().as_bytes())
+                        .unwrap_or_else(|err| warn!("Failed to save last device: {:?}", err));
+                }
+            }
+            ConnectionEvent::DisconnectionComplete { handle, reason, .. } if connected == Some(handle) => {
+                connected = None;
+                if reason == Status::ConnectionTimeout {
+                    attempts = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+}
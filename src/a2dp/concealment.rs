@@ -0,0 +1,135 @@
+//! Hides damaged SBC frames, so a corrupted or truncated media packet causes a short fade instead of a click.
+use crate::a2dp::sbc::SbcFrameHeader;
+
+/// What replaces a frame that can't be decoded.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum ConcealmentMode {
+    Silence,
+    /// Repeats the last good frame, halving its volume for every consecutive concealed frame.
+    #[default]
+    RepeatWithFade
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConcealmentStats {
+    /// All frames, including the concealed ones.
+    pub frames: u64,
+    pub concealed: u64,
+    pub crc_errors: u64,
+    /// Frames that were cut off or missing from a media packet.
+    pub truncated: u64
+}
+
+/// A frame of an SBC media packet ([A2DP] Section 4.3.4).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SbcFrame<'a> {
+    Valid(&'a [u8]),
+    /// The frame has to be replaced by [SbcConcealer::conceal].
+    Damaged
+}
+
+/// Validates the frames of an SBC stream and creates the replacements for damaged ones.
+#[derive(Debug, Default)]
+pub struct SbcConcealer {
+    mode: ConcealmentMode,
+    last_frame: Vec<i16>,
+    gain: f32,
+    stats: ConcealmentStats
+}
+
+impl SbcConcealer {
+    pub fn new(mode: ConcealmentMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> ConcealmentStats {
+        self.stats
+    }
+
+    /// Splits the payload of a media packet, including the one byte payload header, into its frames.
+    #[cfg_attr(feature = "flamegraph", inline(never))]
+    pub fn split_frames<'a>(&mut self, payload: &'a [u8]) -> Vec<SbcFrame<'a>> {
+        let Some((&header, mut data)) = payload.split_first() else {
+            return Vec::new();
+        };
+        let expected = (header & 0x0F) as usize;
+        let mut frames = Vec::with_capacity(expected);
+        while !data.is_empty() && frames.len() < expected {
+            let Some(header) = SbcFrameHeader::parse(data) else {
+                // Without a valid header the start of the next frame is unknown
+                self.stats.crc_errors += 1;
+                frames.push(SbcFrame::Damaged);
+                break;
+            };
+            let length = header.frame_length();
+            if length > data.len() {
+                break;
+            }
+            let (frame, rest) = data.split_at(length);
+            data = rest;
+            if header.check_crc(frame) {
+                frames.push(SbcFrame::Valid(frame));
+            } else {
+                self.stats.crc_errors += 1;
+                frames.push(SbcFrame::Damaged);
+            }
+        }
+        let missing = expected.saturating_sub(frames.len());
+        self.stats.truncated += missing as u64;
+        frames.extend((0..missing).map(|_| SbcFrame::Damaged));
+        self.stats.frames += frames.len() as u64;
+        frames
+    }
+
+    /// Remembers the decoded samples of a valid frame as the base of the next concealment frame.
+    pub fn decoded(&mut self, samples: &[i16]) {
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(samples);
+        self.gain = 1.0;
+    }
+
+    /// Creates the samples that replace a damaged frame. Empty until the first frame was decoded.
+    pub fn conceal(&mut self) -> Vec<i16> {
+        self.stats.concealed += 1;
+        match self.mode {
+            ConcealmentMode::Silence => vec![0; self.last_frame.len()],
+            ConcealmentMode::RepeatWithFade => {
+                self.gain *= 0.5;
+                self.last_frame
+                    .iter()
+                    .map(|sample| (*sample as f32 * self.gain) as i16)
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::a2dp::concealment::{ConcealmentMode, SbcConcealer, SbcFrame};
+
+    #[test]
+    fn truncated_packet() {
+        let mut concealer = SbcConcealer::new(ConcealmentMode::RepeatWithFade);
+        // Two frames announced, but the second one is cut off
+        let mut payload = vec![0x02];
+        for _ in 0..2 {
+            payload.extend_from_slice(&[0x9C, 0x11, 0x02, 0x00]);
+            payload.extend_from_slice(&[0x00; 6]);
+        }
+        payload.truncate(16);
+        let frames = concealer.split_frames(&payload);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1], SbcFrame::Damaged);
+        assert_eq!(concealer.stats().truncated, 1);
+
+        concealer.decoded(&[1000, -1000]);
+        assert_eq!(concealer.conceal(), vec![500, -500]);
+        assert_eq!(concealer.conceal(), vec![250, -250]);
+        assert_eq!(concealer.stats().concealed, 2);
+    }
+}
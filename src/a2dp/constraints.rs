@@ -0,0 +1,110 @@
+//! Limits the codec configurations that can be negotiated, independent of the capabilities of the endpoints,
+//! e.g. to keep the sampling rate within what the audio device supports.
+use crate::a2dp::sbc::{ChannelModes, SamplingFrequencies, SbcMediaCodecInformation};
+use crate::avdtp::capabilities::{AudioCodec, Capability, MediaCodec, MediaCodecCapability};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CodecConstraints {
+    /// The highest sampling frequency in Hz.
+    pub max_sampling_frequency: Option<u32>,
+    pub channel_modes: ChannelModes,
+    pub min_bitpool: Option<u8>,
+    pub max_bitpool: Option<u8>,
+    /// Endpoints of these codecs are preferred in the given order over the other endpoints,
+    /// which keep the priority they were registered with.
+    pub codec_priority: Vec<AudioCodec>
+}
+
+impl Default for CodecConstraints {
+    fn default() -> Self {
+        Self {
+            max_sampling_frequency: None,
+            channel_modes: ChannelModes::all(),
+            min_bitpool: None,
+            max_bitpool: None,
+            codec_priority: Vec::new()
+        }
+    }
+}
+
+impl CodecConstraints {
+    /// Narrows a capability of an endpoint down to the allowed values, other capabilities are returned unchanged.
+    pub fn restrict(&self, capability: &Capability) -> Capability {
+        match capability {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Capability::MediaCodec(MediaCodecCapability::Sbc(self.restrict_sbc(info))),
+            other => other.clone()
+        }
+    }
+
+    /// Whether a configuration selected by the peer stays within the constraints.
+    pub fn allows(&self, configuration: &[Capability]) -> bool {
+        configuration.iter().all(|capability| match capability {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => {
+                let allowed = self.restrict_sbc(info);
+                allowed == *info && allowed.minimum_bitpool <= allowed.maximum_bitpool
+            }
+            _ => true
+        })
+    }
+
+    /// The rank of an endpoint with the given capabilities among the endpoints of the same media type,
+    /// lower ranks are preferred. `None` if the codec has no priority.
+    pub fn codec_rank(&self, capabilities: &[Capability]) -> Option<usize> {
+        let codec = capabilities.iter().find_map(|capability| match capability {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(_)) => Some(AudioCodec::Sbc),
+            Capability::MediaCodec(MediaCodecCapability::Generic(MediaCodec::Audio(codec), _)) => Some(*codec),
+            _ => None
+        })?;
+        self.codec_priority.iter().position(|c| *c == codec)
+    }
+
+    fn restrict_sbc(&self, info: &SbcMediaCodecInformation) -> SbcMediaCodecInformation {
+        SbcMediaCodecInformation {
+            sampling_frequencies: self
+                .max_sampling_frequency
+                .map_or(info.sampling_frequencies, |max| info.sampling_frequencies & SamplingFrequencies::up_to(max)),
+            channel_modes: info.channel_modes & self.channel_modes,
+            minimum_bitpool: self.min_bitpool.map_or(info.minimum_bitpool, |min| min.max(info.minimum_bitpool)),
+            maximum_bitpool: self.max_bitpool.map_or(info.maximum_bitpool, |max| max.min(info.maximum_bitpool)),
+            ..*info
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::a2dp::constraints::CodecConstraints;
+    use crate::a2dp::sbc::{ChannelModes, SamplingFrequencies, SbcMediaCodecInformation};
+    use crate::avdtp::capabilities::{Capability, MediaCodecCapability};
+
+    #[test]
+    fn sbc_constraints() {
+        let constraints = CodecConstraints {
+            max_sampling_frequency: Some(44100),
+            channel_modes: ChannelModes::STEREO | ChannelModes::JOINT_STEREO,
+            max_bitpool: Some(35),
+            ..Default::default()
+        };
+        let Capability::MediaCodec(MediaCodecCapability::Sbc(restricted)) = constraints.restrict(&Capability::MediaCodec(SbcMediaCodecInformation::default().into())) else {
+            panic!("Unexpected capability");
+        };
+        assert_eq!(restricted.sampling_frequencies, SamplingFrequencies::up_to(44100));
+        assert_eq!(restricted.channel_modes, ChannelModes::STEREO | ChannelModes::JOINT_STEREO);
+        assert_eq!((restricted.minimum_bitpool, restricted.maximum_bitpool), (2, 35));
+
+        let configuration = |sampling_frequencies| {
+            Capability::MediaCodec(
+                SbcMediaCodecInformation {
+                    sampling_frequencies,
+                    channel_modes: ChannelModes::JOINT_STEREO,
+                    maximum_bitpool: 35,
+                    ..Default::default()
+                }
+                .into()
+            )
+        };
+        assert!(constraints.allows(&[Capability::MediaTransport, configuration(SamplingFrequencies::FREQ_44100)]));
+        assert!(!constraints.allows(&[Capability::MediaTransport, configuration(SamplingFrequencies::FREQ_48000)]));
+    }
+}
@@ -1,5 +1,9 @@
+pub mod concealment;
+pub mod constraints;
+pub mod routing;
 pub mod sbc;
 pub mod sdp;
+pub mod source;
 
 use instructor::utils::u24;
 use instructor::{Exstruct, Instruct};
@@ -0,0 +1,204 @@
+//! Fans the decoded audio of a stream out to multiple sinks, e.g. a local DAC and a network streamer.
+//! Every sink runs on its own thread behind its own queue, so a slow sink loses audio instead of stalling the others.
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tracing::{trace, warn};
+
+/// The format of the interleaved PCM samples passed to an [AudioSink].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u8
+}
+
+/// Receives the decoded audio of a stream on a dedicated thread.
+/// The sink is created on that thread, so it may own resources that can't be sent between threads (e.g. audio devices).
+pub trait AudioSink: 'static {
+    /// Called before the first samples of a stream and whenever the format changes.
+    fn on_start(&mut self, format: PcmFormat);
+    fn on_samples(&mut self, samples: &[i16]);
+    fn on_stop(&mut self);
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SinkId(u64);
+
+enum SinkMessage {
+    Start(PcmFormat),
+    Samples(Arc<[i16]>),
+    Stop
+}
+
+struct RegisteredSink {
+    id: SinkId,
+    queue: SyncSender<SinkMessage>,
+    dropped: Arc<AtomicU64>
+}
+
+#[derive(Default)]
+struct RouterState {
+    next_id: u64,
+    format: Option<PcmFormat>,
+    sinks: Vec<RegisteredSink>
+}
+
+/// Distributes the samples of one stream to all registered [AudioSink]s.
+/// Sinks can be added and removed while the stream is playing.
+#[derive(Clone, Default)]
+pub struct AudioRouter(Arc<Mutex<RouterState>>);
+
+impl AudioRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the sink created by `factory` with a queue of up to `buffered_packets` sample packets.
+    /// Packets are dropped for this sink alone while its queue is full.
+    pub fn add_sink<F, S>(&self, name: &str, buffered_packets: usize, factory: F) -> SinkId
+    where
+        F: FnOnce() -> S + Send + 'static,
+        S: AudioSink
+    {
+        let (queue, rx) = sync_channel(buffered_packets.max(1));
+        let mut state = self.0.lock();
+        let id = SinkId(state.next_id);
+        state.next_id += 1;
+        if let Some(format) = state.format {
+            // Can't fail, the queue is still empty
+            let _ = queue.try_send(SinkMessage::Start(format));
+        }
+        std::thread::Builder::new()
+            .name(format!("audio-sink-{}", name))
+            .spawn(move || run_sink(factory(), rx))
+            .expect("Failed to spawn audio sink thread");
+        state.sinks.push(RegisteredSink {
+            id,
+            queue,
+            dropped: Arc::new(AtomicU64::new(0))
+        });
+        id
+    }
+
+    /// Unregisters the sink, its thread exits after processing the queued packets.
+    pub fn remove_sink(&self, id: SinkId) -> bool {
+        let mut state = self.0.lock();
+        let len = state.sinks.len();
+        state.sinks.retain(|sink| sink.id != id);
+        state.sinks.len() != len
+    }
+
+    /// How many packets were dropped because the queue of the sink was full.
+    pub fn dropped_packets(&self, id: SinkId) -> Option<u64> {
+        self.0
+            .lock()
+            .sinks
+            .iter()
+            .find(|sink| sink.id == id)
+            .map(|sink| sink.dropped.load(Relaxed))
+    }
+
+    pub fn start(&self, format: PcmFormat) {
+        let mut state = self.0.lock();
+        state.format = Some(format);
+        state.broadcast(|| SinkMessage::Start(format));
+    }
+
+    /// Passes interleaved samples in the format of the last [AudioRouter::start] to all sinks.
+    pub fn push(&self, samples: &[i16]) {
+        let mut state = self.0.lock();
+        if state.sinks.is_empty() {
+            return;
+        }
+        let samples: Arc<[i16]> = Arc::from(samples);
+        state.broadcast(|| SinkMessage::Samples(samples.clone()));
+    }
+
+    pub fn stop(&self) {
+        let mut state = self.0.lock();
+        state.format = None;
+        state.broadcast(|| SinkMessage::Stop);
+    }
+}
+
+impl RouterState {
+    fn broadcast(&mut self, mut msg: impl FnMut() -> SinkMessage) {
+        self.sinks.retain(|sink| match sink.queue.try_send(msg()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if sink.dropped.fetch_add(1, Relaxed) == 0 {
+                    warn!("Audio sink {:?} can't keep up, dropping packets", sink.id);
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("Audio sink {:?} stopped unexpectedly", sink.id);
+                false
+            }
+        });
+    }
+}
+
+fn run_sink<S: AudioSink>(mut sink: S, queue: Receiver<SinkMessage>) {
+    let mut playing = false;
+    while let Ok(msg) = queue.recv() {
+        match msg {
+            SinkMessage::Start(format) => {
+                playing = true;
+                sink.on_start(format);
+            }
+            SinkMessage::Samples(samples) if playing => sink.on_samples(&samples),
+            SinkMessage::Samples(_) => {}
+            SinkMessage::Stop => {
+                playing = false;
+                sink.on_stop();
+            }
+        }
+    }
+    if playing {
+        sink.on_stop();
+    }
+    trace!("Audio sink thread finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    use crate::a2dp::routing::{AudioRouter, AudioSink, PcmFormat};
+
+    struct ChannelSink(Sender<Vec<i16>>);
+
+    impl AudioSink for ChannelSink {
+        fn on_start(&mut self, _format: PcmFormat) {}
+
+        fn on_samples(&mut self, samples: &[i16]) {
+            self.0.send(samples.to_vec()).unwrap();
+        }
+
+        fn on_stop(&mut self) {}
+    }
+
+    #[test]
+    fn fan_out() {
+        let router = AudioRouter::new();
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        router.add_sink("first", 4, move || ChannelSink(tx1));
+        router.start(PcmFormat { sample_rate: 48000, channels: 2 });
+        let second = router.add_sink("second", 4, move || ChannelSink(tx2));
+        router.push(&[1, 2]);
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(rx1.recv_timeout(timeout).unwrap(), vec![1, 2]);
+        assert_eq!(rx2.recv_timeout(timeout).unwrap(), vec![1, 2]);
+        assert!(router.remove_sink(second));
+        router.push(&[3, 4]);
+        assert_eq!(rx1.recv_timeout(timeout).unwrap(), vec![3, 4]);
+        assert!(rx2.recv_timeout(timeout).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use instructor::{ByteSize, Exstruct, Instruct};
 
 // ([A2DP] Section 4.3.2).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[instructor(endian = "big")]
 pub struct SbcMediaCodecInformation {
     #[instructor(bitfield(u8))]
@@ -38,6 +39,7 @@ impl Default for SbcMediaCodecInformation {
 // ([A2DP] Section 4.3.2.1).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct SamplingFrequencies: u8 {
         const FREQ_16000 = 0b1000;
@@ -48,6 +50,14 @@ bitflags! {
 }
 
 impl SamplingFrequencies {
+    /// All frequencies up to `max` Hz.
+    pub fn up_to(max: u32) -> Self {
+        Self::all()
+            .iter()
+            .filter(|frequency| frequency.as_value().is_some_and(|value| value <= max))
+            .collect()
+    }
+
     pub fn as_value(self) -> Option<u32> {
         match self {
             SamplingFrequencies::FREQ_16000 => Some(16000),
@@ -62,6 +72,7 @@ impl SamplingFrequencies {
 // ([A2DP] Section 4.3.2.2).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct ChannelModes: u8 {
         const MONO = 0b1000;
@@ -74,6 +85,7 @@ bitflags! {
 // ([A2DP] Section 4.3.2.3).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct BlockLengths: u8 {
         const FOUR = 0b1000;
@@ -98,6 +110,7 @@ impl BlockLengths {
 // ([A2DP] Section 4.3.2.4).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct Subbands: u8 {
         const FOUR = 0b10;
@@ -118,6 +131,7 @@ impl Subbands {
 // ([A2DP] Section 4.3.2.5).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct AllocationMethods: u8 {
         const SNR = 0b10;
@@ -132,12 +146,102 @@ impl ByteSize for SbcMediaCodecInformation {
     }
 }
 
+/// The header of an SBC frame ([A2DP] Section 12.6.2).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SbcFrameHeader {
+    pub sampling_frequency: u32,
+    pub blocks: u8,
+    pub channel_mode: ChannelModes,
+    pub allocation_method: AllocationMethods,
+    pub subbands: u8,
+    pub bitpool: u8,
+    pub crc: u8
+}
+
+impl SbcFrameHeader {
+    pub const SYNCWORD: u8 = 0x9C;
+
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let &[sync, config, bitpool, crc, ..] = frame else {
+            return None;
+        };
+        if sync != Self::SYNCWORD {
+            return None;
+        }
+        Some(Self {
+            sampling_frequency: [16000, 32000, 44100, 48000][(config >> 6) as usize],
+            blocks: 4 * (((config >> 4) & 0b11) + 1),
+            channel_mode: [ChannelModes::MONO, ChannelModes::DUAL_CHANNEL, ChannelModes::STEREO, ChannelModes::JOINT_STEREO]
+                [((config >> 2) & 0b11) as usize],
+            allocation_method: match (config >> 1) & 1 {
+                0 => AllocationMethods::LOUDNESS,
+                _ => AllocationMethods::SNR
+            },
+            subbands: if config & 1 == 0 { 4 } else { 8 },
+            bitpool,
+            crc
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        if self.channel_mode == ChannelModes::MONO { 1 } else { 2 }
+    }
+
+    /// The number of samples per channel encoded in the frame.
+    pub fn samples(&self) -> usize {
+        self.blocks as usize * self.subbands as usize
+    }
+
+    /// The length of the whole frame in bytes ([A2DP] Section 12.9).
+    pub fn frame_length(&self) -> usize {
+        let (blocks, subbands, bitpool) = (self.blocks as usize, self.subbands as usize, self.bitpool as usize);
+        let channels = self.channels();
+        let audio_bits = match self.channel_mode {
+            ChannelModes::MONO | ChannelModes::DUAL_CHANNEL => blocks * channels * bitpool,
+            ChannelModes::STEREO => blocks * bitpool,
+            _ => subbands + blocks * bitpool
+        };
+        4 + (4 * subbands * channels) / 8 + audio_bits.div_ceil(8)
+    }
+
+    /// The number of bits after the header that are protected by the CRC: the join flags and the scale factors.
+    fn protected_bits(&self) -> usize {
+        let join = if self.channel_mode == ChannelModes::JOINT_STEREO { self.subbands as usize } else { 0 };
+        join + 4 * self.subbands as usize * self.channels()
+    }
+
+    /// Checks the CRC of a complete frame ([A2DP] Section 12.6.3).
+    pub fn check_crc(&self, frame: &[u8]) -> bool {
+        let bits = self.protected_bits();
+        frame.len() >= 4 + bits.div_ceil(8) && sbc_crc8(frame, bits) == self.crc
+    }
+}
+
+/// CRC-8 with the polynomial x^8 + x^4 + x^3 + x^2 + 1 over the second and third header byte
+/// followed by `bits` bits starting after the CRC field.
+fn sbc_crc8(frame: &[u8], bits: usize) -> u8 {
+    fn update(crc: u8, byte: u8, bits: usize) -> u8 {
+        (0..bits).fold(crc, |crc, i| {
+            let bit = ((byte << i) ^ crc) & 0x80 != 0;
+            (crc << 1) ^ if bit { 0x1D } else { 0x00 }
+        })
+    }
+    let crc = update(0x0F, frame[1], 8);
+    let crc = update(crc, frame[2], 8);
+    let data = &frame[4..];
+    let crc = data[..bits / 8].iter().fold(crc, |crc, &byte| update(crc, byte, 8));
+    match bits % 8 {
+        0 => crc,
+        rem => update(crc, data[bits / 8], rem)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use bytes::Bytes;
     use instructor::Buffer;
 
-    use crate::a2dp::sbc::SbcMediaCodecInformation;
+    use crate::a2dp::sbc::{sbc_crc8, SbcFrameHeader, SbcMediaCodecInformation};
 
     #[test]
     fn test_sbc_codec_information() {
@@ -146,4 +250,26 @@ mod test {
         let codec: SbcMediaCodecInformation = data.read().unwrap();
         println!("{:#?}", codec);
     }
+
+    #[test]
+    fn test_sbc_frame_header() {
+        // 44.1kHz, 16 blocks, joint stereo, loudness, 8 subbands, bitpool 53
+        let mut frame = vec![0u8; 119];
+        frame[..4].copy_from_slice(&[0x9C, 0xBD, 0x35, 0x00]);
+        frame[4..13].copy_from_slice(&[0x5A, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        let header = SbcFrameHeader::parse(&frame).unwrap();
+        assert_eq!(header.sampling_frequency, 44100);
+        assert_eq!(header.samples(), 128);
+        assert_eq!(header.frame_length(), 119);
+
+        frame[3] = sbc_crc8(&frame, header.protected_bits());
+        let header = SbcFrameHeader::parse(&frame).unwrap();
+        assert!(header.check_crc(&frame));
+        frame[6] ^= 0x01;
+        assert!(!header.check_crc(&frame));
+        // The audio samples are not protected
+        frame[6] ^= 0x01;
+        frame[100] ^= 0x01;
+        assert!(header.check_crc(&frame));
+    }
 }
@@ -0,0 +1,183 @@
+//! The capture side of the source role: reads encoded audio from an [AudioSource] and paces the media packets.
+//! When the source reports the hardware timestamps of its capture device (e.g. the `htstamp` of ALSA),
+//! packets are sent relative to them, so the transmission follows the clock of the capture device instead of
+//! drifting away from it over long sessions.
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::Instant;
+use tracing::trace;
+
+use crate::avdtp::MediaSender;
+use crate::l2cap::channel::Error as L2capError;
+use crate::utils::clock::{now, sleep_until};
+
+/// The payload of one media packet and when its first sample was captured.
+#[derive(Debug, Clone)]
+pub struct CapturedAudio {
+    /// The complete media payload including the codec header, e.g. the SBC frame count ([A2DP] Section 12.5).
+    pub payload: Bytes,
+    /// The number of samples per channel in the payload.
+    pub samples: u32,
+    /// The hardware timestamp of the first sample, converted to the [Clock](crate::utils::clock::Clock) of the stack.
+    /// `None` if the device doesn't provide one, the packet is then paced from the previous timestamp.
+    pub captured: Option<Instant>
+}
+
+/// Produces the encoded audio of a stream on a dedicated thread.
+/// The source is created on that thread, so it may own resources that can't be sent between threads (e.g. audio devices).
+pub trait AudioSource: 'static {
+    /// Blocks until the next packet is available, `None` ends the stream.
+    fn read(&mut self) -> Option<CapturedAudio>;
+}
+
+/// Runs the source created by `factory` on its own thread, with a queue of up to `buffered_packets` packets.
+/// The thread exits when the source ends or the receiver is dropped.
+pub fn spawn_source<F, S>(name: &str, buffered_packets: usize, factory: F) -> Receiver<CapturedAudio>
+where
+    F: FnOnce() -> S + Send + 'static,
+    S: AudioSource
+{
+    let (tx, rx) = channel(buffered_packets.max(1));
+    std::thread::Builder::new()
+        .name(format!("audio-source-{}", name))
+        .spawn(move || {
+            let mut source = factory();
+            while let Some(audio) = source.read() {
+                if tx.blocking_send(audio).is_err() {
+                    break;
+                }
+            }
+            trace!("Audio source finished");
+        })
+        .expect("Failed to spawn audio source thread");
+    rx
+}
+
+/// How a [MediaPacer] decides when to send a packet.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pacing {
+    /// Sends the audio in real time according to the nominal sampling frequency, `lead` ahead of the media time.
+    Timer { lead: Duration },
+    /// Sends every packet `delay` after its first sample was captured according to [CapturedAudio::captured].
+    /// Packets without a timestamp are extrapolated from the last one.
+    HardwareTimestamps { delay: Duration }
+}
+
+/// Assigns the RTP timestamps of the media packets and the time they have to be sent.
+#[derive(Debug, Clone)]
+pub struct MediaPacer {
+    pacing: Pacing,
+    sampling_frequency: u32,
+    timestamp: u32,
+    samples_since_start: u64,
+    start: Instant,
+    /// The last hardware timestamp and the sample count it belongs to.
+    anchor: Option<(Instant, u64)>
+}
+
+impl MediaPacer {
+    pub fn new(sampling_frequency: u32, pacing: Pacing) -> Self {
+        Self {
+            pacing,
+            sampling_frequency,
+            timestamp: 0,
+            samples_since_start: 0,
+            start: now(),
+            anchor: None
+        }
+    }
+
+    /// The RTP timestamp of the next packet.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// When the next packet has to be sent, `captured` is the hardware timestamp of its first sample.
+    pub fn deadline(&mut self, captured: Option<Instant>) -> Instant {
+        match self.pacing {
+            Pacing::Timer { lead } => {
+                (self.start + self.media_time(self.samples_since_start))
+                    .checked_sub(lead)
+                    .unwrap_or(self.start)
+            }
+            Pacing::HardwareTimestamps { delay } => {
+                if let Some(captured) = captured {
+                    self.anchor = Some((captured, self.samples_since_start));
+                }
+                let (anchor, samples) = self.anchor.unwrap_or((self.start, 0));
+                anchor + self.media_time(self.samples_since_start - samples) + delay
+            }
+        }
+    }
+
+    /// Accounts for a packet with `samples` samples per channel and returns its RTP timestamp.
+    pub fn advance(&mut self, samples: u32) -> u32 {
+        let timestamp = self.timestamp;
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        self.samples_since_start += samples as u64;
+        timestamp
+    }
+
+    /// Restarts the pacing after the stream was suspended, the RTP timestamps continue.
+    pub fn resume(&mut self) {
+        self.samples_since_start = 0;
+        self.start = now();
+        self.anchor = None;
+    }
+
+    fn media_time(&self, samples: u64) -> Duration {
+        Duration::from_secs_f64(samples as f64 / self.sampling_frequency as f64)
+    }
+}
+
+/// Sends the packets of `audio` paced by `pacer` until the source ends.
+pub async fn stream_captured(
+    sender: &mut MediaSender, pacer: &mut MediaPacer, audio: &mut Receiver<CapturedAudio>
+) -> Result<(), L2capError> {
+    while let Some(packet) = audio.recv().await {
+        sleep_until(pacer.deadline(packet.captured)).await;
+        let timestamp = pacer.advance(packet.samples);
+        sender.send(timestamp, &packet.payload).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::a2dp::source::{MediaPacer, Pacing};
+    use crate::utils::clock::{now, set_thread_clock, SimulatedClock};
+
+    #[test]
+    fn hardware_timestamp_pacing() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let start = now();
+        let ms = Duration::from_millis;
+
+        let mut timer = MediaPacer::new(48000, Pacing::Timer { lead: ms(10) });
+        assert_eq!(timer.deadline(None), start - ms(10));
+        assert_eq!(timer.advance(480), 0);
+        assert_eq!(timer.advance(480), 480);
+        assert_eq!(timer.deadline(None), start + ms(10));
+
+        // The capture device runs slightly slow, 480 samples take 10.01ms
+        let delay = ms(20);
+        let mut pacer = MediaPacer::new(48000, Pacing::HardwareTimestamps { delay });
+        for i in 0..1000u32 {
+            let captured = start + Duration::from_micros(10010) * i;
+            // Only every other packet carries a timestamp
+            let deadline = pacer.deadline((i % 2 == 0).then_some(captured));
+            let expected = match i % 2 {
+                0 => captured + delay,
+                _ => captured - Duration::from_micros(10) + delay
+            };
+            assert_eq!(deadline, expected);
+            assert_eq!(pacer.advance(480), 480 * i);
+        }
+    }
+}
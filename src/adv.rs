@@ -0,0 +1,358 @@
+//! The length-type-value structures of extended inquiry responses and advertising data
+//! ([Vol 3] Part C, Section 8 and 11). The data types are defined in [Core Specification Supplement] Part A, Section 1.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use instructor::{Buffer, BufferMut};
+
+use crate::ensure;
+use crate::hci::consts::{ClassOfDevice, CompanyId};
+use crate::hci::{DeviceId, VendorIdSource};
+use crate::sdp::{Uuid, Uuid128, Uuid16, Uuid32};
+
+const FLAGS: u8 = 0x01;
+const INCOMPLETE_UUIDS16: u8 = 0x02;
+const COMPLETE_UUIDS16: u8 = 0x03;
+const INCOMPLETE_UUIDS32: u8 = 0x04;
+const COMPLETE_UUIDS32: u8 = 0x05;
+const INCOMPLETE_UUIDS128: u8 = 0x06;
+const COMPLETE_UUIDS128: u8 = 0x07;
+const SHORTENED_LOCAL_NAME: u8 = 0x08;
+const COMPLETE_LOCAL_NAME: u8 = 0x09;
+const TX_POWER_LEVEL: u8 = 0x0A;
+const CLASS_OF_DEVICE: u8 = 0x0D;
+const DEVICE_ID: u8 = 0x10;
+const TRANSPORT_DISCOVERY_DATA: u8 = 0x26;
+const MANUFACTURER_DATA: u8 = 0xFF;
+
+/// The largest payload of a single structure, as the length field also counts the type.
+pub const MAX_PAYLOAD_LENGTH: usize = 254;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdStructure {
+    /// ([Core Specification Supplement] Part A, Section 1.3).
+    Flags(u8),
+    /// `complete` is false if the device supports more services than listed
+    /// ([Core Specification Supplement] Part A, Section 1.1).
+    Uuids16 { complete: bool, uuids: Vec<Uuid16> },
+    Uuids32 { complete: bool, uuids: Vec<Uuid32> },
+    Uuids128 { complete: bool, uuids: Vec<Uuid128> },
+    /// ([Core Specification Supplement] Part A, Section 1.2).
+    LocalName { complete: bool, name: String },
+    /// The transmit power in dBm ([Core Specification Supplement] Part A, Section 1.5).
+    TxPowerLevel(i8),
+    /// ([Core Specification Supplement] Part A, Section 1.6).
+    DeviceId(DeviceId),
+    /// ([Core Specification Supplement] Part A, Section 1.6).
+    ClassOfDevice(ClassOfDevice),
+    /// Points scanners to the transports the device offers ([Core Specification Supplement] Part A, Section 1.10).
+    TransportDiscovery(Vec<TransportBlock>),
+    /// ([Core Specification Supplement] Part A, Section 1.4).
+    ManufacturerData { company_id: CompanyId, data: Vec<u8> },
+    /// Any other data type, kept as is.
+    Other { kind: u8, data: Vec<u8> }
+}
+
+impl AdStructure {
+    /// The data type of the structure ([Assigned Numbers] Section 2.3).
+    pub fn kind(&self) -> u8 {
+        match self {
+            AdStructure::Flags(_) => FLAGS,
+            AdStructure::Uuids16 { complete, .. } => if *complete { COMPLETE_UUIDS16 } else { INCOMPLETE_UUIDS16 },
+            AdStructure::Uuids32 { complete, .. } => if *complete { COMPLETE_UUIDS32 } else { INCOMPLETE_UUIDS32 },
+            AdStructure::Uuids128 { complete, .. } => if *complete { COMPLETE_UUIDS128 } else { INCOMPLETE_UUIDS128 },
+            AdStructure::LocalName { complete, .. } => if *complete { COMPLETE_LOCAL_NAME } else { SHORTENED_LOCAL_NAME },
+            AdStructure::TxPowerLevel(_) => TX_POWER_LEVEL,
+            AdStructure::DeviceId(_) => DEVICE_ID,
+            AdStructure::ClassOfDevice(_) => CLASS_OF_DEVICE,
+            AdStructure::TransportDiscovery(_) => TRANSPORT_DISCOVERY_DATA,
+            AdStructure::ManufacturerData { .. } => MANUFACTURER_DATA,
+            AdStructure::Other { kind, .. } => *kind
+        }
+    }
+
+    /// The size of the structure including its length and type fields.
+    pub fn encoded_len(&self) -> usize {
+        let payload = match self {
+            AdStructure::Flags(_) | AdStructure::TxPowerLevel(_) => 1,
+            AdStructure::Uuids16 { uuids, .. } => 2 * uuids.len(),
+            AdStructure::Uuids32 { uuids, .. } => 4 * uuids.len(),
+            AdStructure::Uuids128 { uuids, .. } => 16 * uuids.len(),
+            AdStructure::LocalName { name, .. } => name.len(),
+            AdStructure::DeviceId(_) => 8,
+            AdStructure::ClassOfDevice(_) => 3,
+            AdStructure::TransportDiscovery(blocks) => blocks.iter().map(|block| 3 + block.data.len()).sum(),
+            AdStructure::ManufacturerData { data, .. } => 2 + data.len(),
+            AdStructure::Other { data, .. } => data.len()
+        };
+        2 + payload
+    }
+
+    /// Payloads longer than [MAX_PAYLOAD_LENGTH] are rejected.
+    pub fn encode(&self, buffer: &mut BytesMut) -> Result<(), instructor::Error> {
+        ensure!(self.encoded_len() - 2 <= MAX_PAYLOAD_LENGTH, instructor::Error::TooLong);
+        buffer.put_u8((self.encoded_len() - 1) as u8);
+        buffer.put_u8(self.kind());
+        match self {
+            AdStructure::Flags(flags) => buffer.put_u8(*flags),
+            AdStructure::Uuids16 { uuids, .. } => uuids.iter().for_each(|uuid| buffer.put_u16_le(uuid.0)),
+            AdStructure::Uuids32 { uuids, .. } => uuids.iter().for_each(|uuid| buffer.put_u32_le(uuid.0)),
+            AdStructure::Uuids128 { uuids, .. } => uuids.iter().for_each(|uuid| buffer.put_u128_le(uuid.0)),
+            AdStructure::LocalName { name, .. } => buffer.put_slice(name.as_bytes()),
+            AdStructure::TxPowerLevel(power) => buffer.put_i8(*power),
+            AdStructure::DeviceId(device_id) => {
+                buffer.write_le(device_id.vendor_id_source as u16);
+                buffer.write_le(device_id.vendor_id);
+                buffer.write_le(device_id.product_id);
+                buffer.write_le(device_id.version);
+            }
+            AdStructure::ClassOfDevice(cod) => buffer.write_le(*cod),
+            AdStructure::TransportDiscovery(blocks) => {
+                for block in blocks {
+                    ensure!(block.data.len() <= u8::MAX as usize, instructor::Error::TooLong);
+                    buffer.put_u8(block.organization_id);
+                    buffer.put_u8(block.flags);
+                    buffer.put_u8(block.data.len() as u8);
+                    buffer.put_slice(&block.data);
+                }
+            }
+            AdStructure::ManufacturerData { company_id, data } => {
+                buffer.write_le(*company_id);
+                buffer.put_slice(data);
+            }
+            AdStructure::Other { data, .. } => buffer.put_slice(data)
+        }
+        Ok(())
+    }
+
+    /// Parses the payload of a structure of type `kind`.
+    pub fn decode(kind: u8, mut data: Bytes) -> Result<Self, instructor::Error> {
+        let result = match kind {
+            FLAGS => AdStructure::Flags(data.read_le()?),
+            INCOMPLETE_UUIDS16 | COMPLETE_UUIDS16 => AdStructure::Uuids16 {
+                complete: kind == COMPLETE_UUIDS16,
+                uuids: read_list(&mut data, 2, |data| Uuid16(data.get_u16_le()))?
+            },
+            INCOMPLETE_UUIDS32 | COMPLETE_UUIDS32 => AdStructure::Uuids32 {
+                complete: kind == COMPLETE_UUIDS32,
+                uuids: read_list(&mut data, 4, |data| Uuid32(data.get_u32_le()))?
+            },
+            INCOMPLETE_UUIDS128 | COMPLETE_UUIDS128 => AdStructure::Uuids128 {
+                complete: kind == COMPLETE_UUIDS128,
+                uuids: read_list(&mut data, 16, |data| Uuid128(data.get_u128_le()))?
+            },
+            SHORTENED_LOCAL_NAME | COMPLETE_LOCAL_NAME => AdStructure::LocalName {
+                complete: kind == COMPLETE_LOCAL_NAME,
+                // Some devices cut the name in the middle of a character
+                name: String::from_utf8_lossy(&data.split_off(0)).into_owned()
+            },
+            TX_POWER_LEVEL => AdStructure::TxPowerLevel(data.read_le()?),
+            DEVICE_ID => {
+                let vendor_id_source = match data.read_le::<u16>()? {
+                    0x0001 => VendorIdSource::BluetoothSig,
+                    0x0002 => VendorIdSource::UsbImplementersForum,
+                    _ => return Err(instructor::Error::InvalidValue)
+                };
+                AdStructure::DeviceId(DeviceId {
+                    vendor_id_source,
+                    vendor_id: data.read_le()?,
+                    product_id: data.read_le()?,
+                    version: data.read_le()?
+                })
+            }
+            CLASS_OF_DEVICE => AdStructure::ClassOfDevice(data.read_le()?),
+            TRANSPORT_DISCOVERY_DATA => {
+                let mut blocks = Vec::new();
+                while !data.is_empty() {
+                    let organization_id = data.read_le()?;
+                    let flags = data.read_le()?;
+                    let length: u8 = data.read_le()?;
+                    ensure!(data.len() >= length as usize, instructor::Error::TooShort);
+                    blocks.push(TransportBlock {
+                        organization_id,
+                        flags,
+                        data: data.split_to(length as usize).to_vec()
+                    });
+                }
+                AdStructure::TransportDiscovery(blocks)
+            }
+            MANUFACTURER_DATA => AdStructure::ManufacturerData {
+                company_id: data.read_le()?,
+                data: data.split_off(0).to_vec()
+            },
+            _ => AdStructure::Other {
+                kind,
+                data: data.split_off(0).to_vec()
+            }
+        };
+        data.finish()?;
+        Ok(result)
+    }
+}
+
+/// A transport of the device in the transport discovery data ([TDS] Section 4.2.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransportBlock {
+    /// The organization that defines the transport, [TransportBlock::BLUETOOTH_SIG] for BR/EDR.
+    pub organization_id: u8,
+    /// The role of the device in bits 0-1, whether `data` is incomplete in bit 2 and the transport state in bits 3-4.
+    pub flags: u8,
+    pub data: Vec<u8>
+}
+
+impl TransportBlock {
+    pub const BLUETOOTH_SIG: u8 = 0x01;
+    pub const ROLE_PROVIDER: u8 = 0b10;
+    pub const STATE_ON: u8 = 0b01 << 3;
+
+    /// Announces that the BR/EDR transport of the device is available.
+    pub fn br_edr() -> Self {
+        Self {
+            organization_id: Self::BLUETOOTH_SIG,
+            flags: Self::ROLE_PROVIDER | Self::STATE_ON,
+            data: Vec::new()
+        }
+    }
+}
+
+fn read_list<T>(data: &mut Bytes, width: usize, read: impl Fn(&mut Bytes) -> T) -> Result<Vec<T>, instructor::Error> {
+    ensure!(data.len() % width == 0, instructor::Error::InvalidValue);
+    Ok((0..data.len() / width).map(|_| read(data)).collect())
+}
+
+/// A sequence of structures, e.g. the extended inquiry response of a device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdvertisingData {
+    pub structures: Vec<AdStructure>
+}
+
+impl AdvertisingData {
+    /// Parses the significant part of `data`, the zero padding after it is ignored ([Vol 3] Part C, Section 8).
+    pub fn parse(mut data: Bytes) -> Result<Self, instructor::Error> {
+        let mut structures = Vec::new();
+        while !data.is_empty() {
+            let length: u8 = data.read_le()?;
+            if length == 0 {
+                break;
+            }
+            ensure!(data.len() >= length as usize, instructor::Error::TooShort);
+            let mut payload = data.split_to(length as usize);
+            let kind: u8 = payload.read_le()?;
+            structures.push(AdStructure::decode(kind, payload)?);
+        }
+        Ok(Self { structures })
+    }
+
+    pub fn with(mut self, structure: AdStructure) -> Self {
+        self.push(structure);
+        self
+    }
+
+    pub fn push(&mut self, structure: AdStructure) {
+        self.structures.push(structure);
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.structures.iter().map(AdStructure::encoded_len).sum()
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes, instructor::Error> {
+        let mut buffer = BytesMut::with_capacity(self.encoded_len());
+        for structure in &self.structures {
+            structure.encode(&mut buffer)?;
+        }
+        Ok(buffer.freeze())
+    }
+
+    /// All listed service class UUIDs, regardless of their size.
+    pub fn uuids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.structures
+            .iter()
+            .flat_map(|structure| -> Box<dyn Iterator<Item = Uuid> + '_> {
+                match structure {
+                    AdStructure::Uuids16 { uuids, .. } => Box::new(uuids.iter().copied().map(Uuid::from)),
+                    AdStructure::Uuids32 { uuids, .. } => Box::new(uuids.iter().copied().map(Uuid::from)),
+                    AdStructure::Uuids128 { uuids, .. } => Box::new(uuids.iter().copied().map(Uuid::from)),
+                    _ => Box::new(std::iter::empty())
+                }
+            })
+    }
+
+    /// The complete name if present, the shortened one otherwise.
+    pub fn local_name(&self) -> Option<&str> {
+        let names = || {
+            self.structures.iter().filter_map(|structure| match structure {
+                AdStructure::LocalName { complete, name } => Some((*complete, name.as_str())),
+                _ => None
+            })
+        };
+        names()
+            .find(|(complete, _)| *complete)
+            .or_else(|| names().next())
+            .map(|(_, name)| name)
+    }
+
+    pub fn tx_power_level(&self) -> Option<i8> {
+        self.structures.iter().find_map(|structure| match structure {
+            AdStructure::TxPowerLevel(power) => Some(*power),
+            _ => None
+        })
+    }
+
+    pub fn device_id(&self) -> Option<DeviceId> {
+        self.structures.iter().find_map(|structure| match structure {
+            AdStructure::DeviceId(device_id) => Some(*device_id),
+            _ => None
+        })
+    }
+
+    pub fn manufacturer_data(&self) -> impl Iterator<Item = (CompanyId, &[u8])> {
+        self.structures.iter().filter_map(|structure| match structure {
+            AdStructure::ManufacturerData { company_id, data } => Some((*company_id, data.as_slice())),
+            _ => None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::adv::{AdStructure, AdvertisingData};
+    use crate::sdp::{Uuid, Uuid16};
+
+    #[test]
+    fn roundtrip() {
+        let data = AdvertisingData::default()
+            .with(AdStructure::Flags(0x06))
+            .with(AdStructure::Uuids16 {
+                complete: false,
+                uuids: vec![Uuid16(0x110B), Uuid16(0x110E)]
+            })
+            .with(AdStructure::LocalName {
+                complete: true,
+                name: String::from("bluefang")
+            })
+            .with(AdStructure::TxPowerLevel(-4));
+        let bytes = data.to_bytes().unwrap();
+        assert_eq!(bytes.len(), data.encoded_len());
+        assert_eq!(&bytes[..11], b"\x02\x01\x06\x05\x02\x0B\x11\x0E\x11\x09\x09");
+
+        let mut padded = bytes.to_vec();
+        padded.resize(240, 0);
+        let parsed = AdvertisingData::parse(Bytes::from(padded)).unwrap();
+        assert_eq!(parsed, data);
+        assert_eq!(parsed.local_name(), Some("bluefang"));
+        assert_eq!(parsed.tx_power_level(), Some(-4));
+        assert_eq!(parsed.uuids().collect::<Vec<_>>(), vec![Uuid::from_u16(0x110B), Uuid::from_u16(0x110E)]);
+    }
+
+    #[test]
+    fn malformed() {
+        // Length exceeds the data
+        assert!(AdvertisingData::parse(Bytes::from_static(b"\x05\x09abc")).is_err());
+        // Odd number of bytes in a 16 bit UUID list
+        assert!(AdvertisingData::parse(Bytes::from_static(b"\x04\x03\x0B\x11\x0E")).is_err());
+    }
+}
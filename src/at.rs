@@ -0,0 +1,632 @@
+//! An engine for the AT commands of the hands-free and headset profiles ([HFP] Section 4.34, [V.250] Section 5).
+//! It parses and serializes command lines, result codes and unsolicited result codes. The profiles match on the
+//! command names and convert the arguments with the [Argument] and [Arguments] traits, so a command is declared
+//! by its name and the types of its arguments.
+use std::fmt::{Display, Formatter};
+
+use thiserror::Error;
+
+use crate::ensure;
+
+/// Lines longer than this are discarded, the longest lines of the profiles (phonebook entries) stay well below.
+pub const MAX_LINE_LENGTH: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum Error {
+    #[error("The command line doesn't start with AT.")]
+    MissingPrefix,
+    #[error("Malformed AT line at offset {0}.")]
+    Syntax(usize),
+    #[error("Argument {0} is missing or has the wrong type.")]
+    InvalidArgument(usize),
+    #[error("Unexpected argument {0}.")]
+    UnexpectedArgument(usize),
+    #[error("The line exceeds the length limit.")]
+    TooLong
+}
+
+/// A single argument of a command or result code ([V.250] Section 5.4.2).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Arg {
+    /// An omitted argument, e.g. the first of `AT+BIA=,1`.
+    Empty,
+    Int(u32),
+    /// A quoted string.
+    Str(String),
+    /// An unquoted value that is no number, e.g. the `1x` of `+CHLD: (0,1x)` or a dial string.
+    Token(String),
+    /// A range of supported values, e.g. `0-5`.
+    Range(u32, u32),
+    /// A parenthesized list, e.g. the supported values reported for a test command.
+    List(Vec<Arg>)
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arg::Empty => Ok(()),
+            Arg::Int(value) => write!(f, "{}", value),
+            Arg::Str(value) => write!(f, "\"{}\"", value),
+            Arg::Token(value) => f.write_str(value),
+            Arg::Range(start, end) => write!(f, "{}-{}", start, end),
+            Arg::List(items) => write!(f, "({})", DisplayArgs(items))
+        }
+    }
+}
+
+struct DisplayArgs<'a>(&'a [Arg]);
+
+impl Display for DisplayArgs<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, arg) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a command asks for ([V.250] Section 5.4.2).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommandKind {
+    /// `AT+CMD`
+    Action,
+    /// `AT+CMD?`, the current value.
+    Read,
+    /// `AT+CMD=?`, the supported values.
+    Test,
+    /// `AT+CMD=<args>`
+    Set(Vec<Arg>)
+}
+
+/// A single command of a command line. Names are upper case and include the `+` of extended commands,
+/// basic commands are named by their letter, e.g. `D` for dialing with the dial string as the only argument.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub kind: CommandKind
+}
+
+impl Command {
+    pub fn action(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: CommandKind::Action
+        }
+    }
+
+    pub fn read(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: CommandKind::Read
+        }
+    }
+
+    pub fn test(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: CommandKind::Test
+        }
+    }
+
+    pub fn set<A: Arguments>(name: &str, args: A) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: CommandKind::Set(args.into_args())
+        }
+    }
+
+    /// The arguments of a set command, converted to `A`. Other kinds of commands have no arguments.
+    pub fn args<A: Arguments>(&self) -> Result<A, Error> {
+        match &self.kind {
+            CommandKind::Set(args) => A::from_args(args),
+            _ => A::from_args(&[])
+        }
+    }
+
+    /// Parses a command line, which may chain several commands ([V.250] Section 5.2.1).
+    /// A bare `AT` results in no commands.
+    pub fn parse_line(line: &str) -> Result<Vec<Command>, Error> {
+        let line = line.trim();
+        ensure!(line.get(..2).map_or(false, |prefix| prefix.eq_ignore_ascii_case("AT")), Error::MissingPrefix);
+        let mut cursor = Cursor { line, pos: 2 };
+        let mut commands = Vec::new();
+        while !cursor.is_empty() {
+            commands.push(Self::parse_next(&mut cursor)?);
+            cursor.eat(';');
+        }
+        Ok(commands)
+    }
+
+    fn parse_next(cursor: &mut Cursor) -> Result<Command, Error> {
+        let (name, kind) = match cursor.bump().map(|c| c.to_ascii_uppercase()) {
+            Some('+') => {
+                let name = cursor.take_while(|c| c.is_ascii_alphanumeric() || "!%-./:_".contains(c));
+                ensure!(!name.is_empty(), cursor.error());
+                let kind = match (cursor.eat('='), cursor.eat('?')) {
+                    (true, true) => CommandKind::Test,
+                    (true, false) => CommandKind::Set(parse_args(cursor, &[';'])?),
+                    (false, true) => CommandKind::Read,
+                    (false, false) => CommandKind::Action
+                };
+                (format!("+{}", name.to_ascii_uppercase()), kind)
+            }
+            // The dial string extends to the end of the line, including the `;` of voice calls
+            Some('D') => ("D".to_string(), CommandKind::Set(vec![Arg::Token(cursor.rest().to_string())])),
+            // S-parameters ([V.250] Section 5.3.2)
+            Some('S') => {
+                let name = format!("S{}", cursor.take_while(|c| c.is_ascii_digit()));
+                let kind = match (cursor.eat('='), cursor.eat('?')) {
+                    (true, _) => CommandKind::Set(vec![parse_arg(cursor, &[';'])?]),
+                    (false, true) => CommandKind::Read,
+                    (false, false) => return Err(cursor.error())
+                };
+                (name, kind)
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '&' => {
+                let name = match c {
+                    '&' => format!("&{}", cursor.bump().filter(char::is_ascii_alphabetic).ok_or(cursor.error())?.to_ascii_uppercase()),
+                    _ => c.to_string()
+                };
+                let kind = match cursor.take_while(|c| c.is_ascii_digit()) {
+                    "" => CommandKind::Action,
+                    value => CommandKind::Set(vec![parse_token(value)])
+                };
+                (name, kind)
+            }
+            _ => return Err(cursor.error())
+        };
+        Ok(Command { name, kind })
+    }
+
+    /// The command line of this command alone.
+    pub fn to_line(&self) -> String {
+        format!("AT{}\r", self)
+    }
+
+    /// The command line of several chained commands.
+    pub fn chain_to_line(commands: &[Command]) -> String {
+        let mut line = String::from("AT");
+        for (i, command) in commands.iter().enumerate() {
+            if i > 0 && commands[i - 1].name.starts_with('+') {
+                line.push(';');
+            }
+            line.push_str(&command.to_string());
+        }
+        line.push('\r');
+        line
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        match &self.kind {
+            CommandKind::Action => Ok(()),
+            CommandKind::Read => f.write_str("?"),
+            CommandKind::Test => f.write_str("=?"),
+            CommandKind::Set(args) => match self.name.starts_with('+') || self.name.starts_with('S') {
+                true => write!(f, "={}", DisplayArgs(args)),
+                false => write!(f, "{}", DisplayArgs(args))
+            }
+        }
+    }
+}
+
+/// A line sent by the audio gateway ([V.250] Section 5.7).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Response {
+    Ok,
+    Error,
+    /// An extended error, reported after `AT+CMEE=1` ([HFP] Section 4.34.2).
+    CmeError(u32),
+    /// An information response or an unsolicited result code, e.g. `+CIEV: 2,1`.
+    /// Which one it is depends on whether a command is pending and its name.
+    Result { name: String, args: Vec<Arg> },
+    /// Any other result code, e.g. `RING`, `NO CARRIER` or `BUSY`.
+    Code(String)
+}
+
+/// The result codes besides `OK` and `ERROR` that complete a command ([HFP] Section 4.34.2).
+const FINAL_CODES: [&str; 6] = ["NO CARRIER", "BUSY", "NO ANSWER", "DELAYED", "BLACKLISTED", "NO DIALTONE"];
+
+impl Response {
+    pub fn result<A: Arguments>(name: &str, args: A) -> Self {
+        Self::Result {
+            name: name.to_string(),
+            args: args.into_args()
+        }
+    }
+
+    /// The arguments of a result, converted to `A`. Other responses have no arguments.
+    pub fn args<A: Arguments>(&self) -> Result<A, Error> {
+        match self {
+            Response::Result { args, .. } => A::from_args(args),
+            _ => A::from_args(&[])
+        }
+    }
+
+    /// Whether the response completes the pending command.
+    pub fn is_final(&self) -> bool {
+        match self {
+            Response::Ok | Response::Error | Response::CmeError(_) => true,
+            Response::Result { .. } => false,
+            Response::Code(code) => FINAL_CODES.contains(&code.as_str())
+        }
+    }
+
+    pub fn parse_line(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        Ok(match line {
+            "OK" => Response::Ok,
+            "ERROR" => Response::Error,
+            _ if line.starts_with("+CME ERROR:") => {
+                let code = line["+CME ERROR:".len()..].trim();
+                Response::CmeError(code.parse().map_err(|_| Error::Syntax("+CME ERROR:".len()))?)
+            }
+            _ if line.starts_with('+') => {
+                let (name, args) = match line.split_once(':') {
+                    Some((name, _)) => {
+                        let mut cursor = Cursor { line, pos: name.len() + 1 };
+                        let args = parse_args(&mut cursor, &[])?;
+                        ensure!(cursor.is_empty(), cursor.error());
+                        (name, args)
+                    }
+                    None => (line, Vec::new())
+                };
+                Response::Result {
+                    name: name.trim().to_string(),
+                    args
+                }
+            }
+            _ => Response::Code(line.to_string())
+        })
+    }
+
+    /// The response framed by carriage returns and line feeds.
+    pub fn to_line(&self) -> String {
+        format!("\r\n{}\r\n", self)
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Ok => f.write_str("OK"),
+            Response::Error => f.write_str("ERROR"),
+            Response::CmeError(code) => write!(f, "+CME ERROR: {}", code),
+            Response::Result { name, args } if args.is_empty() => f.write_str(name),
+            Response::Result { name, args } => write!(f, "{}: {}", name, DisplayArgs(args)),
+            Response::Code(code) => f.write_str(code)
+        }
+    }
+}
+
+/// Splits the byte stream of the channel into lines. Commands end with a carriage return and responses are framed by
+/// carriage returns and line feeds, so both are line ends and empty lines are skipped.
+#[derive(Debug, Default)]
+pub struct LineReader {
+    buffer: Vec<u8>,
+    overflow: bool
+}
+
+impl LineReader {
+    /// Adds `data` and returns the lines it completed.
+    /// A line longer than [MAX_LINE_LENGTH] is dropped and reported as [Error::TooLong] once it ends.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<String, Error>> {
+        let mut lines = Vec::new();
+        for &byte in data {
+            match byte {
+                b'\r' | b'\n' => {
+                    if self.overflow {
+                        lines.push(Err(Error::TooLong));
+                    } else if !self.buffer.is_empty() {
+                        lines.push(Ok(String::from_utf8_lossy(&self.buffer).into_owned()));
+                    }
+                    self.buffer.clear();
+                    self.overflow = false;
+                }
+                _ if self.buffer.len() >= MAX_LINE_LENGTH => {
+                    self.buffer.clear();
+                    self.overflow = true;
+                }
+                _ if self.overflow => {}
+                _ => self.buffer.push(byte)
+            }
+        }
+        lines
+    }
+}
+
+struct Cursor<'a> {
+    line: &'a str,
+    pos: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.line[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        match self.peek() == Some(expected) {
+            true => {
+                self.pos += expected.len_utf8();
+                true
+            }
+            false => false
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, predicate: F) -> &'a str {
+        let rest = &self.line[self.pos..];
+        let len = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn rest(&mut self) -> &'a str {
+        self.take_while(|_| true)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.line.len()
+    }
+
+    fn error(&self) -> Error {
+        Error::Syntax(self.pos)
+    }
+}
+
+/// Parses comma separated arguments up to the end of the line or one of the `terminators`.
+fn parse_args(cursor: &mut Cursor, terminators: &[char]) -> Result<Vec<Arg>, Error> {
+    let mut args = vec![parse_arg(cursor, terminators)?];
+    while cursor.eat(',') {
+        args.push(parse_arg(cursor, terminators)?);
+    }
+    Ok(args)
+}
+
+fn parse_arg(cursor: &mut Cursor, terminators: &[char]) -> Result<Arg, Error> {
+    cursor.take_while(|c| c == ' ');
+    let arg = match cursor.peek() {
+        Some('"') => {
+            cursor.bump();
+            let value = cursor.take_while(|c| c != '"');
+            ensure!(cursor.eat('"'), cursor.error());
+            Arg::Str(value.to_string())
+        }
+        Some('(') => {
+            cursor.bump();
+            let items = parse_args(cursor, &[')'])?;
+            ensure!(cursor.eat(')'), cursor.error());
+            match items.as_slice() {
+                [Arg::Empty] => Arg::List(Vec::new()),
+                _ => Arg::List(items)
+            }
+        }
+        _ => parse_token(cursor.take_while(|c| c != ',' && c != '"' && c != '(' && !terminators.contains(&c)).trim())
+    };
+    cursor.take_while(|c| c == ' ');
+    Ok(arg)
+}
+
+fn parse_token(token: &str) -> Arg {
+    let number = |value: &str| -> Option<u32> { value.bytes().all(|b| b.is_ascii_digit()).then(|| value.parse().ok()).flatten() };
+    if token.is_empty() {
+        return Arg::Empty;
+    }
+    if let Some(value) = number(token) {
+        return Arg::Int(value);
+    }
+    match token.split_once('-').and_then(|(start, end)| Some((number(start)?, number(end)?))) {
+        Some((start, end)) => Arg::Range(start, end),
+        None => Arg::Token(token.to_string())
+    }
+}
+
+/// The conversion of a single argument.
+pub trait Argument: Sized {
+    fn from_arg(arg: &Arg) -> Option<Self>;
+    fn into_arg(self) -> Arg;
+}
+
+macro_rules! impl_int_argument {
+    ($($ty:ty),*) => {$(
+        impl Argument for $ty {
+            fn from_arg(arg: &Arg) -> Option<Self> {
+                match arg {
+                    Arg::Int(value) => Self::try_from(*value).ok(),
+                    _ => None
+                }
+            }
+
+            fn into_arg(self) -> Arg {
+                Arg::Int(self.into())
+            }
+        }
+    )*};
+}
+
+impl_int_argument!(u8, u16, u32);
+
+impl Argument for bool {
+    fn from_arg(arg: &Arg) -> Option<Self> {
+        match arg {
+            Arg::Int(0) => Some(false),
+            Arg::Int(1) => Some(true),
+            _ => None
+        }
+    }
+
+    fn into_arg(self) -> Arg {
+        Arg::Int(self as u32)
+    }
+}
+
+impl Argument for String {
+    fn from_arg(arg: &Arg) -> Option<Self> {
+        match arg {
+            Arg::Str(value) | Arg::Token(value) => Some(value.clone()),
+            _ => None
+        }
+    }
+
+    fn into_arg(self) -> Arg {
+        Arg::Str(self)
+    }
+}
+
+impl Argument for Arg {
+    fn from_arg(arg: &Arg) -> Option<Self> {
+        Some(arg.clone())
+    }
+
+    fn into_arg(self) -> Arg {
+        self
+    }
+}
+
+/// An argument that may be omitted.
+impl<T: Argument> Argument for Option<T> {
+    fn from_arg(arg: &Arg) -> Option<Self> {
+        match arg {
+            Arg::Empty => Some(None),
+            arg => T::from_arg(arg).map(Some)
+        }
+    }
+
+    fn into_arg(self) -> Arg {
+        self.map_or(Arg::Empty, T::into_arg)
+    }
+}
+
+impl<T: Argument> Argument for Vec<T> {
+    fn from_arg(arg: &Arg) -> Option<Self> {
+        match arg {
+            Arg::List(items) => items.iter().map(T::from_arg).collect(),
+            _ => None
+        }
+    }
+
+    fn into_arg(self) -> Arg {
+        Arg::List(self.into_iter().map(T::into_arg).collect())
+    }
+}
+
+/// The conversion of a whole argument list, implemented for tuples of [Argument]s.
+/// Missing trailing arguments are treated as omitted ones.
+pub trait Arguments: Sized {
+    fn from_args(args: &[Arg]) -> Result<Self, Error>;
+    fn into_args(self) -> Vec<Arg>;
+}
+
+impl Arguments for () {
+    fn from_args(args: &[Arg]) -> Result<Self, Error> {
+        ensure!(args.iter().all(|arg| *arg == Arg::Empty), Error::UnexpectedArgument(0));
+        Ok(())
+    }
+
+    fn into_args(self) -> Vec<Arg> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_arguments {
+    ($($name:ident $index:tt),*) => {
+        impl<$($name: Argument),*> Arguments for ($($name,)*) {
+            fn from_args(args: &[Arg]) -> Result<Self, Error> {
+                let count = [$(stringify!($name)),*].len();
+                if let Some(extra) = args.iter().skip(count).position(|arg| *arg != Arg::Empty) {
+                    return Err(Error::UnexpectedArgument(count + extra));
+                }
+                Ok(($(<$name as Argument>::from_arg(args.get($index).unwrap_or(&Arg::Empty)).ok_or(Error::InvalidArgument($index))?,)*))
+            }
+
+            fn into_args(self) -> Vec<Arg> {
+                let mut args = vec![$(self.$index.into_arg()),*];
+                while args.last() == Some(&Arg::Empty) {
+                    args.pop();
+                }
+                args
+            }
+        }
+    };
+}
+
+impl_arguments!(A 0);
+impl_arguments!(A 0, B 1);
+impl_arguments!(A 0, B 1, C 2);
+impl_arguments!(A 0, B 1, C 2, D 3);
+impl_arguments!(A 0, B 1, C 2, D 3, E 4);
+impl_arguments!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+#[cfg(test)]
+mod tests {
+    use crate::at::{Arg, Command, CommandKind, Error, LineReader, Response, MAX_LINE_LENGTH};
+
+    #[test]
+    fn commands() {
+        let commands = Command::parse_line("AT+BRSF=959\r").unwrap();
+        assert_eq!(commands, vec![Command::set("+BRSF", (959u32,))]);
+        assert_eq!(commands[0].args::<(u32,)>(), Ok((959,)));
+        assert_eq!(commands[0].to_line(), "AT+BRSF=959\r");
+
+        let commands = Command::parse_line("at+cmee=1;+cind=?;+CIND?").unwrap();
+        assert_eq!(commands, vec![Command::set("+CMEE", (true,)), Command::test("+CIND"), Command::read("+CIND")]);
+        assert_eq!(Command::chain_to_line(&commands), "AT+CMEE=1;+CIND=?;+CIND?\r");
+
+        let command = &Command::parse_line("AT+BIA=,1,,0").unwrap()[0];
+        assert_eq!(command.args::<(Option<bool>, Option<bool>, Option<bool>, Option<bool>)>(), Ok((None, Some(true), None, Some(false))));
+        assert_eq!(command.args::<(bool, bool, Option<bool>, bool)>(), Err(Error::InvalidArgument(0)));
+        assert_eq!(command.args::<(Option<bool>, bool)>(), Err(Error::UnexpectedArgument(3)));
+
+        let commands = Command::parse_line("ATD>1;").unwrap();
+        assert_eq!(commands[0].kind, CommandKind::Set(vec![Arg::Token(">1;".to_string())]));
+        assert_eq!(commands[0].to_line(), "ATD>1;\r");
+        assert_eq!(Command::parse_line("ATE0V1").unwrap(), vec![Command::set("E", (0u8,)), Command::set("V", (1u8,))]);
+        assert_eq!(Command::parse_line("AT").unwrap(), vec![]);
+
+        assert_eq!(Command::parse_line("+BRSF=1"), Err(Error::MissingPrefix));
+        assert_eq!(Command::parse_line("AT+BRSF=\"1"), Err(Error::Syntax(10)));
+    }
+
+    #[test]
+    fn responses() {
+        let response = Response::parse_line("+CIND: (\"service\",(0,1)),(\"callsetup\",(0-3))").unwrap();
+        assert_eq!(
+            response.args::<(Vec<Arg>, Vec<Arg>)>().unwrap().1,
+            vec![Arg::Str("callsetup".to_string()), Arg::List(vec![Arg::Range(0, 3)])]
+        );
+        assert_eq!(response.to_line(), "\r\n+CIND: (\"service\",(0,1)),(\"callsetup\",(0-3))\r\n");
+        assert!(!response.is_final());
+
+        let response = Response::parse_line("+CHLD: (0,1,1x,2)").unwrap();
+        let values = vec![Arg::Int(0), Arg::Int(1), Arg::Token("1x".to_string()), Arg::Int(2)];
+        assert_eq!(response.args::<(Vec<Arg>,)>(), Ok((values,)));
+
+        assert_eq!(Response::parse_line("+CIEV: 2,1").unwrap(), Response::result("+CIEV", (2u8, 1u8)));
+        assert_eq!(Response::parse_line("+CME ERROR: 30").unwrap(), Response::CmeError(30));
+        assert_eq!(Response::parse_line("OK").unwrap(), Response::Ok);
+        assert!(Response::parse_line("NO CARRIER").unwrap().is_final());
+        assert!(!Response::parse_line("RING").unwrap().is_final());
+    }
+
+    #[test]
+    fn line_reader() {
+        let mut reader = LineReader::default();
+        assert_eq!(reader.push(b"\r\nOK\r\n\r\n+CI"), vec![Ok("OK".to_string())]);
+        assert_eq!(reader.push(b"EV: 1,0\r\n"), vec![Ok("+CIEV: 1,0".to_string())]);
+        let long = vec![b'A'; MAX_LINE_LENGTH + 1];
+        assert_eq!(reader.push(&long), vec![]);
+        assert_eq!(reader.push(b"\rAT\r"), vec![Err(Error::TooLong), Ok("AT".to_string())]);
+    }
+}
@@ -95,6 +95,7 @@ pub enum Opcode {
 
 // ([AVC Panel] Table 9.21)
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PassThroughOp {
     Select = 0x00,
@@ -158,6 +159,7 @@ pub enum PassThroughOp {
 
 // ([AVC Panel] Section 9.4)
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PassThroughState {
     Pressed = 0x00,
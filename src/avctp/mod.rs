@@ -1,20 +1,24 @@
-mod packets;
+pub(crate) mod packets;
 
 use std::collections::BTreeSet;
 
 use bytes::Bytes;
-pub use packets::{Message, MessageType};
+
+pub use packets::{AssemblyError, Message, MessageType, ReassemblyLimits};
 use tracing::{debug, warn};
 
-use crate::avctp::packets::{ControlChannelExt, MessageAssembler};
+use crate::avctp::packets::{encode_message, ControlChannelExt, MessageAssembler};
 use crate::l2cap::channel::{Channel, Error as L2capError};
 use crate::sdp::Uuid;
+use crate::utils::interceptor::{Direction, Interceptors};
 use crate::utils::IgnoreableResult;
 
 pub struct Avctp {
     channel: Channel,
     assembler: MessageAssembler,
-    profile_ids: BTreeSet<Uuid>
+    profile_ids: BTreeSet<Uuid>,
+    browsing: bool,
+    interceptors: Interceptors<Message>
 }
 
 impl Avctp {
@@ -22,32 +26,78 @@ impl Avctp {
         Self {
             channel,
             assembler: MessageAssembler::default(),
-            profile_ids: profiles.into_iter().collect()
+            profile_ids: profiles.into_iter().collect(),
+            browsing: false,
+            interceptors: Interceptors::default()
         }
     }
 
+    /// Wraps a browsing channel. Messages are never fragmented on it, so every message has to fit into the MTU of the peer
+    /// ([AVCTP] Section 6.1.2).
+    pub fn browsing<I: IntoIterator<Item = Uuid>>(channel: Channel, profiles: I) -> Self {
+        Self {
+            channel,
+            assembler: MessageAssembler::single_packet(),
+            profile_ids: profiles.into_iter().collect(),
+            browsing: true,
+            interceptors: Interceptors::default()
+        }
+    }
+
+    /// Replaces the default [ReassemblyLimits] of fragmented messages.
+    pub fn with_reassembly_limits(mut self, limits: ReassemblyLimits) -> Self {
+        self.assembler = std::mem::take(&mut self.assembler).with_limits(limits);
+        self
+    }
+
+    /// The number of fragmented messages of the peer that were dropped for exceeding the [ReassemblyLimits].
+    pub fn dropped_messages(&self) -> u64 {
+        self.assembler.dropped()
+    }
+
+    /// Passes every message through `interceptors`, vetoed messages are dropped silently.
+    pub fn with_interceptors(mut self, interceptors: Interceptors<Message>) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    pub fn is_browsing(&self) -> bool {
+        self.browsing
+    }
+
+    /// The largest payload of a message that can be sent on a browsing channel.
+    pub fn max_payload_size(&self) -> usize {
+        (self.channel.remote_mtu() as usize).saturating_sub(3)
+    }
+
     pub async fn read(&mut self) -> Option<Message> {
         while let Some(packet) = self.channel.read().await {
             match self.assembler.process_msg(packet) {
                 Ok(Some(msg)) => {
                     if self.profile_ids.contains(&msg.profile_id) {
+                        if !self.interceptors.check(Direction::Incoming, &msg) {
+                            debug!("Dropping intercepted message (label: {})", msg.transaction_label);
+                            continue;
+                        }
                         return Some(msg);
                     }
                     debug!("Received message with unexpected profile id: {:?}", msg.profile_id);
                     if msg.message_type == MessageType::Command {
                         self.channel
-                            .send_msg(Message {
-                                transaction_label: msg.transaction_label,
-                                message_type: MessageType::ResponseInvalidProfile,
-                                profile_id: msg.profile_id,
-                                data: Bytes::new()
-                            })
+                            .send_msg(msg.invalid_profile_response())
                             .await
                             .ignore()
                     }
                 }
                 Ok(None) => continue,
+                Err(AssemblyError::Malformed(err)) => {
+                    warn!("Error processing message: {:?}", err);
+                    continue;
+                }
                 Err(err) => {
+                    warn!("Dropping message: {}", err);
+                    continue;
+                }
                     warn!("Error processing message: {:?}", err);
                     continue;
                 }
@@ -57,7 +107,34 @@ impl Avctp {
     }
 
     pub async fn send_msg(&mut self, message: Message) -> Result<(), L2capError> {
+        if !self.interceptors.check(Direction::Outgoing, &message) {
+            debug!("Dropping intercepted message (label: {})", message.transaction_label);
+            return Ok(());
+        }
+        if self.browsing {
+            self.channel.wait_until_open().await?;
+            if message.encoded_len() > self.channel.remote_mtu() as usize {
+                return Err(L2capError::InvalidData(instructor::Error::TooLong));
+            }
+        }
         //TODO Fragment messages larger than mtu
         self.channel.send_msg(message).await
     }
+
+    /// Sends several messages of the control channel at once, e.g. the fragments of an AVRCP PDU.
+    pub async fn send_msgs(&mut self, messages: Vec<Message>) -> Result<(), L2capError> {
+        debug_assert!(!self.browsing, "Browsing messages are sent one at a time");
+        let packets: Vec<Bytes> = messages
+            .into_iter()
+            .filter(|message| {
+                let pass = self.interceptors.check(Direction::Outgoing, message);
+                if !pass {
+                    debug!("Dropping intercepted message (label: {})", message.transaction_label);
+                }
+                pass
+            })
+            .map(encode_message)
+            .collect();
+        self.channel.write_batch(packets).await
+    }
 }
@@ -1,8 +1,12 @@
+use std::time::Duration;
+
 use bytes::{BufMut, Bytes, BytesMut};
 use instructor::{Buffer, BufferMut, Error, Exstruct, Instruct};
+use tokio::time::Instant;
 
 use crate::l2cap::channel::{Channel, Error as L2capError};
 use crate::sdp::Uuid;
+use crate::utils::clock::now;
 use crate::{ensure, log_assert};
 
 // ([AVCTP] Section 6.1)
@@ -45,17 +49,92 @@ pub struct Message {
     pub data: Bytes
 }
 
+impl Message {
+    /// The reply to a command for a profile that is not registered on this channel.
+    /// Always a single packet without payload, even if the command itself was fragmented ([AVCTP] Section 6.1.1).
+    pub fn invalid_profile_response(&self) -> Message {
+        Message {
+            transaction_label: self.transaction_label,
+            profile_id: self.profile_id,
+            message_type: MessageType::ResponseInvalidProfile,
+            data: Bytes::new()
+        }
+    }
+
+    /// The size of the message when sent as a single packet.
+    pub(crate) fn encoded_len(&self) -> usize {
+        3 + self.data.len()
+    }
+}
+
+/// Bounds the reassembly of fragmented messages, so a peer can't make the assembler buffer without limit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReassemblyLimits {
+    /// The largest payload of a reassembled message.
+    pub max_message_size: usize,
+    /// The most packets a message may be split into, as announced in its start packet.
+    pub max_fragments: u8,
+    /// How long the remaining packets of a message may take after its start packet.
+    pub timeout: Duration
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 64 * 1024,
+            max_fragments: u8::MAX,
+            timeout: Duration::from_secs(1)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum AssemblyError {
+    #[error("Malformed packet: {0}")]
+    Malformed(#[from] Error),
+    #[error("Message of {size} bytes exceeds the limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+    #[error("Message of {fragments} packets exceeds the limit of {limit} packets")]
+    TooManyFragments { fragments: u8, limit: u8 },
+    #[error("Message with label {0} was not completed in time")]
+    TimedOut(u8)
+}
+
 #[derive(Default)]
 pub struct MessageAssembler {
+    /// Fragmented messages are only allowed on the control channel.
+    single_packet: bool,
+    limits: ReassemblyLimits,
     data: BytesMut,
     transaction_label: u8,
     message_type: Option<MessageType>,
     profile_id: u16,
     num_packets: u8,
-    packets_received: u8
+    packets_received: u8,
+    started: Option<Instant>,
+    /// The number of messages dropped for exceeding the limits.
+    dropped: u64
 }
 
 impl MessageAssembler {
+    /// An assembler for the browsing channel, which doesn't use fragmentation ([AVCTP] Section 6.1.2).
+    pub fn single_packet() -> Self {
+        Self {
+            single_packet: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_limits(mut self, limits: ReassemblyLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The number of fragmented messages that were dropped for exceeding the [ReassemblyLimits].
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
     fn reset(&mut self) {
         self.data.clear();
         self.message_type = None;
@@ -63,9 +142,10 @@ impl MessageAssembler {
         self.num_packets = 0;
         self.packets_received = 0;
         self.profile_id = 0;
+        self.started = None;
     }
 
-    fn process_msg_internal(&mut self, mut data: Bytes) -> Result<Option<Message>, Error> {
+    fn process_msg_internal(&mut self, mut data: Bytes) -> Result<Option<Message>, AssemblyError> {
         self.packets_received += 1;
 
         let PacketHeader {
@@ -87,10 +167,26 @@ impl MessageAssembler {
                 }))
             }
             PacketType::Start => {
+                ensure!(!self.single_packet, Error::InvalidValue, "Fragmented message on the browsing channel");
                 log_assert!(self.message_type.is_none());
                 self.reset();
                 self.num_packets = data.read_be()?;
+                ensure!(
+                    self.num_packets <= self.limits.max_fragments,
+                    AssemblyError::TooManyFragments {
+                        fragments: self.num_packets,
+                        limit: self.limits.max_fragments
+                    }
+                );
                 self.profile_id = data.read_be()?;
+                ensure!(
+                    data.len() <= self.limits.max_message_size,
+                    AssemblyError::MessageTooLarge {
+                        size: data.len(),
+                        limit: self.limits.max_message_size
+                    }
+                );
+                self.started = Some(now());
                 self.packets_received = 1;
                 self.message_type = Some(message_type);
                 self.transaction_label = transaction_label;
@@ -103,6 +199,19 @@ impl MessageAssembler {
                 ensure!(self.transaction_label == transaction_label, Error::InvalidValue);
                 ensure!(self.profile_id == profile_id, Error::InvalidValue);
                 ensure!(self.packets_received <= self.num_packets, Error::InvalidValue);
+                ensure!(
+                    self.started
+                        .is_some_and(|started| now() <= started + self.limits.timeout),
+                    AssemblyError::TimedOut(transaction_label)
+                );
+                let size = self.data.len() + data.len();
+                ensure!(
+                    size <= self.limits.max_message_size,
+                    AssemblyError::MessageTooLarge {
+                        size,
+                        limit: self.limits.max_message_size
+                    }
+                );
                 self.data.put(data);
                 match packet_type {
                     PacketType::End => {
@@ -122,15 +231,34 @@ impl MessageAssembler {
         }
     }
 
-    pub fn process_msg(&mut self, data: Bytes) -> Result<Option<Message>, Error> {
+    #[cfg_attr(feature = "flamegraph", inline(never))]
+    pub fn process_msg(&mut self, data: Bytes) -> Result<Option<Message>, AssemblyError> {
         let result = self.process_msg_internal(data);
-        if result.is_err() {
-            self.reset();
+        match &result {
+            Err(AssemblyError::Malformed(_)) => self.reset(),
+            Err(_) => {
+                self.dropped += 1;
+                self.reset();
+            }
+            Ok(_) => {}
         }
         result
     }
 }
 
+// ([AVCTP] Section 6.1.1)
+pub(super) fn encode_message(message: Message) -> Bytes {
+    let mut buffer = BytesMut::new();
+    buffer.write(PacketHeader {
+        transaction_label: message.transaction_label,
+        packet_type: PacketType::Single,
+        message_type: message.message_type
+    });
+    buffer.write_be(message.profile_id.as_u16().expect("Invalid profile id"));
+    buffer.put(message.data);
+    buffer.freeze()
+}
+
 pub trait ControlChannelExt {
     async fn send_msg(&mut self, message: Message) -> Result<(), L2capError>;
 }
@@ -138,15 +266,7 @@ pub trait ControlChannelExt {
 impl ControlChannelExt for Channel {
     async fn send_msg(&mut self, message: Message) -> Result<(), L2capError> {
         //TODO fragment message if necessary
-        let mut buffer = BytesMut::new();
-        buffer.write(PacketHeader {
-            transaction_label: message.transaction_label,
-            packet_type: PacketType::Single,
-            message_type: message.message_type
-        });
-        buffer.write_be(message.profile_id.as_u16().expect("Invalid profile id"));
-        buffer.put(message.data);
-        self.write(buffer.freeze()).await?;
+        self.write(encode_message(message)).await?;
         Ok(())
     }
 }
@@ -155,8 +275,12 @@ impl ControlChannelExt for Channel {
 mod test {
     use bytes::Bytes;
 
-    use crate::avctp::packets::{Message, MessageAssembler, MessageType};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::avctp::packets::{encode_message, AssemblyError, Message, MessageAssembler, MessageType, ReassemblyLimits};
     use crate::sdp::Uuid;
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
 
     #[test]
     fn test_parse_packet() {
@@ -175,4 +299,79 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn encode_messages() {
+        let command = Bytes::from_static(&[
+            0x00, 0x11, 0x0E, 0x03, 0x48, 0x00, 0x00, 0x19, 0x58, 0x31, 0x00, 0x00, 0x05, 0x0D, 0x00, 0x00, 0x00, 0x00
+        ]);
+        let message = Message {
+            transaction_label: 0,
+            profile_id: Uuid::from_u16(0x110E),
+            message_type: MessageType::Command,
+            data: command.slice(3..)
+        };
+        assert_eq!(encode_message(message), command);
+
+        let message = Message {
+            transaction_label: 5,
+            profile_id: Uuid::from_u16(0x110F),
+            message_type: MessageType::ResponseInvalidProfile,
+            data: Bytes::new()
+        };
+        assert_eq!(encode_message(message).as_ref(), &[0x53, 0x11, 0x0F]);
+    }
+
+    #[test]
+    fn fragmented_browsing_message() {
+        let mut assembler = MessageAssembler::single_packet();
+        let start = Bytes::from_static(&[0x54, 0x02, 0x11, 0x0E, 0x01, 0x02]);
+        let end = Bytes::from_static(&[0x5C, 0x11, 0x0E, 0x03]);
+        assert!(assembler.process_msg(start).is_err());
+        assert!(assembler.process_msg(end).is_err());
+        let single = Bytes::from_static(&[0x50, 0x11, 0x0E, 0x71]);
+        assert!(assembler.process_msg(single).unwrap().is_some());
+    }
+
+    #[test]
+    fn invalid_profile_response_to_fragmented_command() {
+        let mut assembler = MessageAssembler::default();
+        let start = Bytes::from_static(&[0x54, 0x02, 0x11, 0x0F, 0x01, 0x02]);
+        let end = Bytes::from_static(&[0x5C, 0x11, 0x0F, 0x03]);
+        assert_eq!(assembler.process_msg(start).unwrap(), None);
+        let command = assembler.process_msg(end).unwrap().unwrap();
+        assert_eq!(command.message_type, MessageType::Command);
+        assert_eq!(command.data.as_ref(), &[0x01, 0x02, 0x03]);
+        assert_eq!(encode_message(command.invalid_profile_response()).as_ref(), &[0x53, 0x11, 0x0F]);
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let mut assembler = MessageAssembler::default().with_limits(ReassemblyLimits {
+            max_message_size: 4,
+            max_fragments: 3,
+            timeout: Duration::from_millis(500)
+        });
+        let start = |fragments: u8| Bytes::from(vec![0x54, fragments, 0x11, 0x0E, 0x01, 0x02]);
+        let cont = Bytes::from_static(&[0x58, 0x11, 0x0E, 0x03]);
+        let end = Bytes::from_static(&[0x5C, 0x11, 0x0E, 0x04, 0x05]);
+
+        assert_eq!(
+            assembler.process_msg(start(4)),
+            Err(AssemblyError::TooManyFragments { fragments: 4, limit: 3 })
+        );
+        assert_eq!(assembler.process_msg(start(3)), Ok(None));
+        assert_eq!(assembler.process_msg(cont.clone()), Ok(None));
+        assert_eq!(
+            assembler.process_msg(end.clone()),
+            Err(AssemblyError::MessageTooLarge { size: 5, limit: 4 })
+        );
+
+        assert_eq!(assembler.process_msg(start(2)), Ok(None));
+        clock.advance(Duration::from_millis(501));
+        assert_eq!(assembler.process_msg(cont), Err(AssemblyError::TimedOut(5)));
+        assert_eq!(assembler.dropped(), 3);
+    }
 }
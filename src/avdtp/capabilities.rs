@@ -7,6 +7,7 @@ use crate::avdtp::MediaType;
 pub use super::packets::{AudioCodec, VideoCodec, ServiceCategory};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Capability {
     MediaTransport,
     MediaCodec(MediaCodecCapability),
@@ -18,9 +19,18 @@ impl Capability {
         // ([AVDTP] Section 8.21.1).
         !matches!(self, Capability::Generic(ServiceCategory::DelayReporting, _))
     }
+
+    pub fn category(&self) -> ServiceCategory {
+        match self {
+            Capability::MediaTransport => ServiceCategory::MediaTransport,
+            Capability::MediaCodec(_) => ServiceCategory::MediaCodec,
+            Capability::Generic(category, _) => *category
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MediaCodec {
     Audio(AudioCodec),
     Video(VideoCodec),
@@ -28,6 +38,7 @@ pub enum MediaCodec {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MediaCodecCapability {
     Sbc(SbcMediaCodecInformation),
     Generic(MediaCodec, Vec<u8>)
@@ -62,11 +73,12 @@ impl Exstruct<BigEndian> for Capability {
 impl Instruct<BigEndian> for Capability {
     #[inline]
     fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
-        let (cat, size) = match self {
-            Capability::MediaTransport => (ServiceCategory::MediaTransport, 0),
-            Capability::MediaCodec(codec) => (ServiceCategory::MediaCodec, codec.byte_size()),
-            Capability::Generic(cat, info) => (*cat, info.byte_size())
+        let size = match self {
+            Capability::MediaTransport => 0,
+            Capability::MediaCodec(codec) => codec.byte_size(),
+            Capability::Generic(_, info) => info.byte_size()
         };
+        let cat = self.category();
         buffer.write_be(cat);
         buffer.write_be(u8::try_from(size).expect("byte size is too large"));
         match self {
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use instructor::{Buffer, BufferMut};
+use tracing::{trace, warn};
+
+use crate::avdtp::capabilities::Capability;
+use crate::avdtp::error::Error;
+use crate::avdtp::packets::{MessageType, SignalChannelExt, SignalIdentifier, SignalMessage, SignalMessageAssembler, StreamEndpoint};
+use crate::avdtp::SignalMessageResponse;
+use crate::hci::AclPriority;
+use crate::l2cap::channel::{Channel, Error as L2capError};
+use crate::l2cap::{ChannelOpener, AVDTP_PSM};
+use crate::utils::clock::timeout;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Channel(L2capError),
+    /// The peer rejected the command with the given reason.
+    Rejected(Error),
+    /// The peer does not understand the command.
+    GeneralReject,
+    Timeout,
+    InvalidResponse
+}
+
+impl From<L2capError> for ClientError {
+    fn from(value: L2capError) -> Self {
+        Self::Channel(value)
+    }
+}
+
+impl From<instructor::Error> for ClientError {
+    fn from(_: instructor::Error) -> Self {
+        Self::InvalidResponse
+    }
+}
+
+/// Initiator side of an AVDTP signaling channel, used to set up streams on a remote stream endpoint.
+/// Commands sent by the peer on the same channel are rejected.
+pub struct AvdtpClient {
+    channel: Channel,
+    assembler: SignalMessageAssembler,
+    transaction_label: u8
+}
+
+impl AvdtpClient {
+    // ([AVDTP] Section 8.4.4, TGAVDP100 is at most 3 seconds).
+    const SIGNAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            assembler: SignalMessageAssembler::default(),
+            transaction_label: 0
+        }
+    }
+
+    pub async fn connect(opener: &ChannelOpener, handle: u16) -> Result<Self, ClientError> {
+        let channel = opener.open(handle, AVDTP_PSM as u64).await?;
+        Ok(Self::new(channel))
+    }
+
+    // ([AVDTP] Section 8.6).
+    pub async fn discover(&mut self) -> Result<Vec<StreamEndpoint>, ClientError> {
+        let mut data = self.transact(SignalIdentifier::Discover, Bytes::new()).await?;
+        let mut endpoints = Vec::new();
+        while !data.is_empty() {
+            endpoints.push(data.read_be()?);
+        }
+        Ok(endpoints)
+    }
+
+    /// Retrieves all capabilities of `acp_seid`.
+    /// Falls back to the basic capabilities if the peer only supports AVDTP 1.2 or earlier.
+    // ([AVDTP] Section 8.7 and 8.8).
+    pub async fn get_capabilities(&mut self, acp_seid: u8) -> Result<Vec<Capability>, ClientError> {
+        let mut data = match self
+            .transact(SignalIdentifier::GetAllCapabilities, seid_parameters(&[acp_seid]))
+            .await
+        {
+            Err(ClientError::GeneralReject | ClientError::Rejected(Error::NotSupportedCommand)) => {
+                self.transact(SignalIdentifier::GetCapabilities, seid_parameters(&[acp_seid]))
+                    .await?
+            }
+            other => other?
+        };
+        let capabilities: Vec<Capability> = data.read_be()?;
+        data.finish()?;
+        Ok(capabilities)
+    }
+
+    // ([AVDTP] Section 8.9).
+    pub async fn set_configuration(&mut self, acp_seid: u8, int_seid: u8, capabilities: &[Capability]) -> Result<(), ClientError> {
+        let mut parameters = BytesMut::new();
+        parameters.write_be(acp_seid << 2);
+        parameters.write_be(int_seid << 2);
+        for capability in capabilities {
+            parameters.write_be_ref(capability);
+        }
+        self.transact(SignalIdentifier::SetConfiguration, parameters.freeze())
+            .await?;
+        Ok(())
+    }
+
+    /// Opens the stream and connects its transport channel ([AVDTP] Section 8.12).
+    pub async fn open(&mut self, acp_seid: u8) -> Result<MediaSender, ClientError> {
+        self.transact(SignalIdentifier::Open, seid_parameters(&[acp_seid]))
+            .await?;
+        let mut channel = self
+            .channel
+            .channel_opener()
+            .open(self.channel.connection_handle(), AVDTP_PSM as u64)
+            .await?;
+        // Media packets are time-critical, so they should not wait behind bulk or signaling traffic
+        channel.set_priority(AclPriority::High);
+        Ok(MediaSender::new(channel))
+    }
+
+    // ([AVDTP] Section 8.13).
+    pub async fn start(&mut self, acp_seid: u8) -> Result<(), ClientError> {
+        self.transact(SignalIdentifier::Start, seid_parameters(&[acp_seid]))
+            .await?;
+        Ok(())
+    }
+
+    // ([AVDTP] Section 8.15).
+    pub async fn suspend(&mut self, acp_seid: u8) -> Result<(), ClientError> {
+        self.transact(SignalIdentifier::Suspend, seid_parameters(&[acp_seid]))
+            .await?;
+        Ok(())
+    }
+
+    // ([AVDTP] Section 8.14).
+    pub async fn close(&mut self, acp_seid: u8) -> Result<(), ClientError> {
+        self.transact(SignalIdentifier::Close, seid_parameters(&[acp_seid]))
+            .await?;
+        Ok(())
+    }
+
+    // ([AVDTP] Section 8.16).
+    pub async fn abort(&mut self, acp_seid: u8) -> Result<(), ClientError> {
+        self.transact(SignalIdentifier::Abort, seid_parameters(&[acp_seid]))
+            .await?;
+        Ok(())
+    }
+
+    async fn transact(&mut self, signal_identifier: SignalIdentifier, data: Bytes) -> Result<Bytes, ClientError> {
+        let transaction_label = self.transaction_label;
+        self.transaction_label = (self.transaction_label + 1) % 16;
+        trace!("Sending {:?} command (label: {})", signal_identifier, transaction_label);
+        self.channel
+            .send_signal(SignalMessage {
+                transaction_label,
+                message_type: MessageType::Command,
+                signal_identifier,
+                data
+            })
+            .await?;
+        timeout(Self::SIGNAL_TIMEOUT, self.wait_for_response(transaction_label, signal_identifier))
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    async fn wait_for_response(&mut self, transaction_label: u8, signal_identifier: SignalIdentifier) -> Result<Bytes, ClientError> {
+        loop {
+            let packet = self.channel.read().await.ok_or(L2capError::Disconnected)?;
+            let msg = match self.assembler.process_msg(packet) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Error processing signaling message: {:?}", err);
+                    continue;
+                }
+            };
+            if msg.message_type == MessageType::Command {
+                let reply = SignalMessageResponse::for_msg(&msg).unsupported();
+                self.channel.send_signal(reply).await?;
+                continue;
+            }
+            if msg.transaction_label != transaction_label || msg.signal_identifier != signal_identifier {
+                warn!("Ignoring unexpected {:?} response (label: {})", msg.signal_identifier, msg.transaction_label);
+                continue;
+            }
+            return match msg.message_type {
+                MessageType::ResponseAccept => Ok(msg.data),
+                // ([AVDTP] Section 8.20.6.2), the error code is always the last byte
+                MessageType::ResponseReject => {
+                    let mut code = msg.data.slice(msg.data.len().saturating_sub(1)..);
+                    Err(ClientError::Rejected(code.read_be()?))
+                }
+                MessageType::GeneralReject => Err(ClientError::GeneralReject),
+                MessageType::Command => unreachable!()
+            };
+        }
+    }
+}
+
+fn seid_parameters(seids: &[u8]) -> Bytes {
+    seids.iter().map(|seid| seid << 2).collect()
+}
+
+/// Sends RTP media packets over the transport channel of an open stream ([AVDTP] Section 7.2).
+pub struct MediaSender {
+    channel: Channel,
+    sequence_number: u16,
+    ssrc: u32
+}
+
+impl MediaSender {
+    const RTP_HEADER_SIZE: usize = 12;
+    // Dynamic payload type ([A2DP] Section 4.3.4).
+    const PAYLOAD_TYPE: u8 = 96;
+
+    fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            sequence_number: 0,
+            ssrc: 1
+        }
+    }
+
+    /// The largest payload that fits into a single media packet.
+    pub fn max_payload_size(&self) -> usize {
+        (self.channel.remote_mtu() as usize).saturating_sub(Self::RTP_HEADER_SIZE)
+    }
+
+    /// Sends `payload` in a single media packet. `timestamp` is in units of the media clock,
+    /// which is the sampling frequency for audio.
+    pub async fn send(&mut self, timestamp: u32, payload: &[u8]) -> Result<(), L2capError> {
+        let mut packet = BytesMut::with_capacity(Self::RTP_HEADER_SIZE + payload.len());
+        // Version 2 without padding, extension and contributing sources ([RFC3550] Section 5.1)
+        packet.write_be(0x80u8);
+        packet.write_be(Self::PAYLOAD_TYPE);
+        packet.write_be(self.sequence_number);
+        packet.write_be(timestamp);
+        packet.write_be(self.ssrc);
+        packet.extend_from_slice(payload);
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.channel.write(packet.freeze()).await
+    }
+
+    pub async fn disconnect(mut self) -> Result<(), L2capError> {
+        self.channel.disconnect().await
+    }
+}
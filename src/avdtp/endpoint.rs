@@ -1,17 +1,23 @@
-use std::future::{poll_fn, Future};
+use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::fmt::Debug;
 
 use bytes::Bytes;
-use tracing::warn;
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
 
 use crate::avdtp::capabilities::Capability;
 use crate::avdtp::error::Error;
 use crate::avdtp::packets::{MediaType, StreamEndpoint, StreamEndpointType};
+use crate::avdtp::rtp::{ClockMapping, MediaPacketStats, MediaPacketValidator, RtpClockEstimator};
 use crate::ensure;
+use crate::hci::AclPriority;
 use crate::l2cap::channel::Channel;
+use crate::l2cap::LinkEvent;
+use crate::utils::clock::{now, sleep, sleep_until, Sleep};
 
 
 pub struct StreamHandlerFactory(Box<dyn Fn(&[Capability]) -> Box<dyn StreamHandler> + Send + Sync>);
@@ -59,73 +65,197 @@ impl LocalEndpoint {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum StreamState {
-    Configured,
     Opening,
     Open,
     Streaming,
     Closing //Aborting,
 }
 
+/// The state of a stream for debugging.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StreamSnapshot {
+    pub local_seid: u8,
+    pub remote_seid: u8,
+    pub state: String,
+    /// Whether the transport channel is connected.
+    pub transport_channel: bool,
+    pub capabilities: Vec<String>,
+    pub media_packets: MediaPacketStats,
+    /// Whether the stream is streaming, but the peer stopped sending media packets.
+    pub idle: bool
+}
+
+/// A stream that was configured by the peer but not opened yet ([AVDTP] Section 6.6 and 6.7).
+/// Reserves the endpoint, but the stream handler is only created once the peer opens the stream.
+/// The endpoint is released again if the peer never opens the stream.
+pub struct PendingStream {
+    pub local_endpoint: u8,
+    pub remote_endpoint: u8,
+    capabilities: Vec<Capability>,
+    endpoint_usage_lock: Option<Arc<AtomicBool>>,
+    deadline: Sleep
+}
+
+impl PendingStream {
+    pub fn new(local_endpoint: &LocalEndpoint, remote_endpoint: u8, capabilities: Vec<Capability>, timeout: Duration) -> Result<Self, Error> {
+        ensure!(!local_endpoint.in_use.swap(true, Ordering::SeqCst), Error::SepInUse);
+        Ok(Self {
+            local_endpoint: local_endpoint.seid,
+            remote_endpoint,
+            capabilities,
+            endpoint_usage_lock: Some(local_endpoint.in_use.clone()),
+            deadline: sleep(timeout)
+        })
+    }
+
+    pub fn capabilities(&self) -> &Vec<Capability> {
+        &self.capabilities
+    }
+
+    /// Resolves once the peer took too long to open the stream.
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.deadline.as_mut().poll(cx)
+    }
+
+    pub fn snapshot(&self) -> StreamSnapshot {
+        StreamSnapshot {
+            local_seid: self.local_endpoint,
+            remote_seid: self.remote_endpoint,
+            state: String::from("Configured"),
+            transport_channel: false,
+            capabilities: self
+                .capabilities
+                .iter()
+                .map(|capability| format!("{:?}", capability))
+                .collect(),
+            media_packets: MediaPacketStats::default(),
+            idle: false
+        }
+    }
+}
+
+impl Drop for PendingStream {
+    fn drop(&mut self) {
+        if let Some(lock) = self.endpoint_usage_lock.take() {
+            lock.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
 pub struct Stream {
     state: StreamState,
     endpoint_usage_lock: Arc<AtomicBool>,
     pub local_endpoint: u8,
-    #[allow(dead_code)]
     pub remote_endpoint: u8,
     capabilities: Vec<Capability>,
     channel: Option<Channel>,
-    handler: Box<dyn StreamHandler>
+    handler: Box<dyn StreamHandler>,
+    validator: MediaPacketValidator,
+    clock: Option<RtpClockEstimator>,
+    /// Whether the handler was told to play and not told to stop yet.
+    handler_playing: bool,
+    suspend_grace_period: Duration,
+    pending_stop: Option<Sleep>,
+    idle_timeout: Option<Duration>,
+    /// Fires at the earliest time the stream can become idle, checked against the last media packet.
+    idle_deadline: Option<Sleep>,
+    last_media_packet: Instant,
+    idle: bool
 }
 
 impl Stream {
-    pub fn new(local_endpoint: &LocalEndpoint, remote_endpoint: u8, capabilities: Vec<Capability>) -> Result<Self, Error> {
-        ensure!(!local_endpoint.in_use.swap(true, Ordering::SeqCst), Error::SepInUse);
+    /// Commits the resources of a stream the peer is opening, the stream waits for its transport channel afterward.
+    pub fn open(
+        local_endpoint: &LocalEndpoint, mut pending: PendingStream, suspend_grace_period: Duration, idle_timeout: Option<Duration>
+    ) -> Result<Self, Error> {
+        ensure!(pending.local_endpoint == local_endpoint.seid, Error::BadAcpSeid);
+        let endpoint_usage_lock = pending
+            .endpoint_usage_lock
+            .take()
+            .ok_or(Error::BadState)?;
+        let capabilities = std::mem::take(&mut pending.capabilities);
         let handler = local_endpoint.factory.make_stream_handler(&capabilities);
+        let validator = MediaPacketValidator::new(&capabilities);
+        let clock = RtpClockEstimator::for_capabilities(&capabilities);
         Ok(Self {
             local_endpoint: local_endpoint.seid,
-            remote_endpoint,
-            state: StreamState::Configured,
+            remote_endpoint: pending.remote_endpoint,
+            state: StreamState::Opening,
             capabilities,
             channel: None,
             handler,
-            endpoint_usage_lock: local_endpoint.in_use.clone()
+            validator,
+            clock,
+            handler_playing: false,
+            suspend_grace_period,
+            pending_stop: None,
+            idle_timeout,
+            idle_deadline: None,
+            last_media_packet: now(),
+            idle: false,
+            endpoint_usage_lock
         })
     }
 
+    /// Applies the application service capabilities of a RECONFIGURE ([AVDTP] Section 8.11),
+    /// the other capabilities of the stream are kept.
     pub fn reconfigure(&mut self, capabilities: Vec<Capability>, ep: &LocalEndpoint) -> Result<(), Error> {
         assert_eq!(self.local_endpoint, ep.seid);
         ensure!(matches!(self.state, StreamState::Open), Error::BadState);
-        self.handler = ep.factory.make_stream_handler(&capabilities);
-        self.capabilities = capabilities;
-        Ok(())
-    }
-
-    pub fn set_to_opening(&mut self) -> Result<(), Error> {
-        ensure!(matches!(self.state, StreamState::Configured), Error::BadState);
-        ensure!(self.channel.is_none(), Error::BadState);
-        self.state = StreamState::Opening;
+        for capability in capabilities {
+            match self.capabilities.iter_mut().find(|c| c.category() == capability.category()) {
+                Some(existing) => *existing = capability,
+                None => self.capabilities.push(capability)
+            }
+        }
+        self.validator = MediaPacketValidator::new(&self.capabilities);
+        self.clock = RtpClockEstimator::for_capabilities(&self.capabilities);
+        if self.handler.on_reconfigure(&self.capabilities) {
+            debug!("Stream {} handler adopted the new configuration", self.local_endpoint);
+        } else {
+            self.stop_handler();
+            self.handler = ep.factory.make_stream_handler(&self.capabilities);
+        }
         Ok(())
     }
 
     pub fn start(&mut self) -> Result<(), Error> {
         ensure!(matches!(self.state, StreamState::Open), Error::BadState);
-        self.handler.on_play();
+        if self.pending_stop.take().is_some() {
+            debug!("Stream {} restarted within the grace period, keeping the handler playing", self.local_endpoint);
+        } else if !self.handler_playing {
+            self.handler.on_play();
+            self.handler_playing = true;
+        }
         self.state = StreamState::Streaming;
+        // The RTP timestamps usually continue where they stopped, so the gap would skew the mapping
+        if let Some(clock) = self.clock.as_mut() {
+            clock.reset();
+        }
+        self.last_media_packet = now();
+        self.idle_deadline = self.idle_timeout.map(sleep);
+        self.idle = false;
         Ok(())
     }
 
+    /// Suspends the stream. The handler is only stopped if the stream is not restarted within the suspend grace period.
     pub fn stop(&mut self) -> Result<(), Error> {
         ensure!(matches!(self.state, StreamState::Streaming), Error::BadState);
-        self.handler.on_stop();
+        if self.suspend_grace_period.is_zero() {
+            self.stop_handler();
+        } else {
+            self.pending_stop = Some(sleep(self.suspend_grace_period));
+        }
         self.state = StreamState::Open;
+        self.idle_deadline = None;
+        self.idle = false;
         Ok(())
     }
 
     pub fn close(&mut self) -> Result<(), Error> {
         ensure!(matches!(self.state, StreamState::Streaming | StreamState::Open), Error::BadState);
-        if self.state == StreamState::Streaming {
-            self.handler.on_stop();
-        }
+        self.stop_handler();
         self.state = StreamState::Closing;
         self.channel = None;
         Ok(())
@@ -135,9 +265,11 @@ impl Stream {
         matches!(self.state, StreamState::Opening)
     }
 
-    pub fn set_channel(&mut self, channel: Channel) {
+    pub fn set_channel(&mut self, mut channel: Channel) {
         assert!(matches!(self.state, StreamState::Opening));
         assert!(self.channel.is_none());
+        // Media packets are time-critical, so they should not wait behind bulk or signaling traffic
+        channel.set_priority(AclPriority::High);
         self.channel = Some(channel);
         self.state = StreamState::Open;
     }
@@ -147,29 +279,103 @@ impl Stream {
         Ok(&self.capabilities)
     }
 
-    pub fn process(&mut self) -> impl Future<Output = ()> + '_ {
-        poll_fn(move |cx| self.poll(cx))
+    pub fn snapshot(&self) -> StreamSnapshot {
+        StreamSnapshot {
+            local_seid: self.local_endpoint,
+            remote_seid: self.remote_endpoint,
+            state: format!("{:?}", self.state),
+            transport_channel: self.channel.is_some(),
+            capabilities: self
+                .capabilities
+                .iter()
+                .map(|capability| format!("{:?}", capability))
+                .collect(),
+            media_packets: self.validator.stats(),
+            idle: self.idle
+        }
+    }
+
+    /// Stops the handler of a stream that stopped receiving media packets without being suspended.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) {
+        let Some(timeout) = self.idle_timeout else { return };
+        while let Some(deadline) = self.idle_deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_pending() {
+                return;
+            }
+            let expiry = self.last_media_packet + timeout;
+            if now() < expiry {
+                self.idle_deadline = Some(sleep_until(expiry));
+                continue;
+            }
+            debug!("No media packets for stream {} in {:?}, treating it as idle", self.local_endpoint, timeout);
+            self.idle_deadline = None;
+            self.idle = true;
+            self.stop_handler();
+            self.handler.on_idle();
+        }
+    }
+
+    fn media_packet_received(&mut self) {
+        self.last_media_packet = now();
+        if self.idle {
+            debug!("Media packets for stream {} returned, resuming", self.local_endpoint);
+            if let Some(clock) = self.clock.as_mut() {
+                clock.reset();
+            }
+            self.idle = false;
+            self.idle_deadline = self.idle_timeout.map(sleep);
+            self.handler.on_play();
+            self.handler_playing = true;
+        }
+    }
+
+    fn stop_handler(&mut self) {
+        self.pending_stop = None;
+        if self.handler_playing {
+            self.handler.on_stop();
+            self.handler_playing = false;
+        }
     }
 
-    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+    /// Handles the media packets and link events of the transport channel, resolves once the stream is closed.
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(pending_stop) = self.pending_stop.as_mut() {
+            if pending_stop.as_mut().poll(cx).is_ready() {
+                trace!("Suspend grace period of stream {} elapsed", self.local_endpoint);
+                self.stop_handler();
+            }
+        }
         loop {
             match self.channel.as_mut() {
                 Some(channel) => {
+                    while let Poll::Ready(Some(event)) = channel.poll_link_event(cx) {
+                        self.handler.on_link_event(event);
+                    }
                     match channel.poll_data(cx) {
                         Poll::Ready(Some(data)) => {
+                            let received = channel.last_received().unwrap_or_else(now);
                             if self.state == StreamState::Streaming {
-                                //TODO Parse the realtime media header and do something useful with it
-                                self.handler.on_data(data.slice(12..));
+                                if let Some((header, payload)) = self.validator.validate_with_header(data) {
+                                    self.media_packet_received();
+                                    if let Some(clock) = self.clock.as_mut() {
+                                        self.handler.on_clock_mapping(clock.update(header.timestamp, received));
+                                    }
+                                    self.handler.on_timestamped_data(payload, received);
+                                }
                             } else {
                                 warn!("Data received while not streaming");
                             }
                         }
                         Poll::Ready(None) => {
+                            self.stop_handler();
                             self.state = StreamState::Closing;
                             self.channel = None;
                             return Poll::Ready(());
                         }
-                        Poll::Pending => return Poll::Pending
+                        Poll::Pending => {
+                            self.poll_idle(cx);
+                            return Poll::Pending;
+                        }
                     }
                 }
                 None => {
@@ -194,4 +400,139 @@ pub trait StreamHandler: 'static {
     fn on_stop(&mut self);
 
     fn on_data(&mut self, data: Bytes);
+
+    /// Like [StreamHandler::on_data] with the time the packet was received from the controller,
+    /// which excludes the time it spent queued in the stack. Forwards to [StreamHandler::on_data] by default.
+    fn on_timestamped_data(&mut self, data: Bytes, _received: Instant) {
+        self.on_data(data);
+    }
+
+    /// Called before each media packet with the mapping of its RTP timestamp to the host clock,
+    /// e.g. to align the audio with video. Only available for codecs with a known RTP clock rate (SBC).
+    fn on_clock_mapping(&mut self, _mapping: ClockMapping) {}
+
+    /// Called when the peer reconfigures the stream, e.g. to a different sampling frequency.
+    /// Returns whether the handler adopted the new configuration. If not, it is stopped and
+    /// replaced by a new handler from the [StreamHandlerFactory].
+    fn on_reconfigure(&mut self, _capabilities: &[Capability]) -> bool {
+        false
+    }
+
+    /// Called after the handler was stopped because the peer stopped sending media packets without suspending
+    /// the stream, see [AvdtpBuilder::with_idle_timeout](crate::avdtp::AvdtpBuilder::with_idle_timeout).
+    /// [StreamHandler::on_play] is called again once media packets return.
+    fn on_idle(&mut self) {}
+
+    /// Called when the ACL link carrying the stream changes, e.g. when it enters sniff mode or is re-keyed.
+    fn on_link_event(&mut self, _event: LinkEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+
+    use crate::avdtp::endpoint::{LocalEndpoint, PendingStream, Stream, StreamHandler, StreamHandlerFactory, StreamState};
+    use crate::avdtp::error::Error;
+    use crate::avdtp::packets::{MediaType, StreamEndpointType};
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    type Calls = Arc<Mutex<Vec<&'static str>>>;
+
+    struct Recorder(Calls);
+
+    impl StreamHandler for Recorder {
+        fn on_play(&mut self) {
+            self.0.lock().push("play");
+        }
+
+        fn on_stop(&mut self) {
+            self.0.lock().push("stop");
+        }
+
+        fn on_data(&mut self, _data: Bytes) {}
+    }
+
+    fn endpoint(seid: u8, calls: &Calls) -> LocalEndpoint {
+        let calls = calls.clone();
+        LocalEndpoint {
+            media_type: MediaType::Audio,
+            seid,
+            in_use: Arc::new(AtomicBool::new(false)),
+            tsep: StreamEndpointType::Sink,
+            capabilities: Vec::new(),
+            factory: StreamHandlerFactory::new(move |_| {
+                calls.lock().push("create");
+                Recorder(calls.clone())
+            })
+        }
+    }
+
+    #[test]
+    fn pending_streams_reserve_the_endpoint() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let calls = Calls::default();
+        let ep = endpoint(1, &calls);
+
+        let mut pending = PendingStream::new(&ep, 2, Vec::new(), Duration::from_secs(10)).unwrap();
+        assert!(ep.in_use.load(Ordering::SeqCst));
+        assert!(matches!(PendingStream::new(&ep, 3, Vec::new(), Duration::from_secs(10)), Err(Error::SepInUse)));
+        // The handler is only created once the stream is opened
+        assert!(calls.lock().is_empty());
+
+        // A peer that never opens the stream doesn't keep the endpoint
+        assert_eq!(now_or_never(std::future::poll_fn(|cx| pending.poll_expired(cx))), None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(now_or_never(std::future::poll_fn(|cx| pending.poll_expired(cx))), Some(()));
+        drop(pending);
+        assert!(!ep.in_use.load(Ordering::SeqCst));
+
+        let pending = PendingStream::new(&ep, 2, Vec::new(), Duration::from_secs(10)).unwrap();
+        assert!(matches!(Stream::open(&endpoint(4, &calls), pending, Duration::ZERO, None), Err(Error::BadAcpSeid)));
+        assert!(!ep.in_use.load(Ordering::SeqCst));
+
+        let pending = PendingStream::new(&ep, 2, Vec::new(), Duration::from_secs(10)).unwrap();
+        let stream = Stream::open(&ep, pending, Duration::ZERO, None).unwrap();
+        assert_eq!(*calls.lock(), ["create"]);
+        assert!(stream.is_opening());
+        assert!(ep.in_use.load(Ordering::SeqCst));
+        drop(stream);
+        assert!(!ep.in_use.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn suspend_grace_period() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let calls = Calls::default();
+        let ep = endpoint(1, &calls);
+        let pending = PendingStream::new(&ep, 2, Vec::new(), Duration::from_secs(10)).unwrap();
+        let mut stream = Stream::open(&ep, pending, Duration::from_secs(2), None).unwrap();
+        stream.state = StreamState::Open;
+
+        stream.start().unwrap();
+        stream.stop().unwrap();
+        // Restarting within the grace period keeps the handler playing
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(now_or_never(std::future::poll_fn(|cx| stream.poll(cx))), None);
+        stream.start().unwrap();
+        assert_eq!(*calls.lock(), ["create", "play"]);
+
+        stream.stop().unwrap();
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(now_or_never(std::future::poll_fn(|cx| stream.poll(cx))), None);
+        assert_eq!(*calls.lock(), ["create", "play", "stop"]);
+
+        // Without a grace period the handler stops right away
+        stream.suspend_grace_period = Duration::ZERO;
+        stream.start().unwrap();
+        stream.stop().unwrap();
+        assert_eq!(*calls.lock(), ["create", "play", "stop", "play", "stop"]);
+    }
 }
@@ -1,64 +1,218 @@
 pub mod capabilities;
+mod client;
 mod endpoint;
 mod error;
-mod packets;
+pub(crate) mod packets;
+pub mod rtp;
 pub mod utils;
 
 use std::collections::BTreeMap;
+use std::future::{poll_fn, Future};
+use std::task::Poll;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use instructor::{BigEndian, Buffer, BufferMut, Instruct};
 use parking_lot::Mutex;
 use tokio::runtime::Handle;
 use tokio::sync::oneshot::{Receiver, Sender};
-use tokio::{select, spawn};
-use tracing::{debug, trace, warn, error};
+use tokio::select;
+use tracing::{debug, trace, warn};
 
 use crate::avdtp::capabilities::Capability;
-use crate::avdtp::endpoint::Stream;
-use crate::avdtp::packets::{MessageType, ServiceCategory, SignalChannelExt, SignalIdentifier, SignalMessage, SignalMessageAssembler};
+use crate::avdtp::endpoint::{PendingStream, Stream};
+use crate::avdtp::packets::{ServiceCategory, SignalChannelExt, SignalMessageAssembler};
 use crate::ensure;
 use crate::l2cap::channel::{Channel, Error as L2capError};
+use crate::l2cap::configuration::ConfigurationPolicy;
+use crate::a2dp::constraints::CodecConstraints;
+use crate::a2dp::sdp::{A2dpSinkServiceRecord, A2dpSourceServiceRecord};
+use crate::hci::consts::MajorServiceClasses;
+use crate::hci::devices::DeviceRegistry;
 use crate::l2cap::{ProtocolHandler, AVDTP_PSM, L2capServer};
-use crate::utils::{select_all, MutexCell, OptionFuture, LoggableResult, IgnoreableResult};
+use crate::profile::{Profile, ProfileRoles, ProfileSnapshot, RecordHandles};
+#[cfg(feature = "pts")]
+use crate::pts::PtsHooks;
+use crate::sdp::ids::service_classes::ADVANCED_AUDIO_DISTRIBUTION;
+use crate::sdp::ServiceRecord;
+use crate::utils::interceptor::{Direction, Interceptors, Verdict};
+use crate::utils::{spawn_supervised, supervise, MutexCell, OptionFuture, PollSet, LoggableResult, IgnoreableResult};
 
-pub use endpoint::{LocalEndpoint, StreamHandler, StreamHandlerFactory};
-pub use packets::{MediaType, StreamEndpointType};
+pub use endpoint::{LocalEndpoint, StreamHandler, StreamHandlerFactory, StreamSnapshot};
+pub use client::{AvdtpClient, ClientError, MediaSender};
+pub use packets::{MediaType, MessageType, SignalIdentifier, SignalMessage, StreamEndpoint, StreamEndpointType};
 use crate::avdtp::error::Error;
 
+/// A configuration of the peer for one of the local endpoints, see [AvdtpBuilder::with_endpoint_selector].
+#[derive(Debug, Clone)]
+pub struct RemoteEndpoint {
+    /// The endpoint of the peer.
+    pub seid: u8,
+    /// The local endpoint the peer configured.
+    pub local_seid: u8,
+    pub capabilities: Vec<Capability>
+}
+
+/// Picks the configuration to keep among those of the same media type, see [AvdtpBuilder::with_endpoint_selector].
+pub type EndpointSelector = Arc<dyn Fn(&[RemoteEndpoint]) -> Option<usize> + Send + Sync>;
+
 #[derive(Default)]
 pub struct AvdtpBuilder {
-    endpoints: Vec<LocalEndpoint>
+    endpoints: Vec<(u8, LocalEndpoint)>,
+    selector: Option<EndpointSelector>,
+    suspend_grace_period: Duration,
+    idle_timeout: Option<Duration>,
+    transport_policy: Option<ConfigurationPolicy>,
+    codec_constraints: CodecConstraints,
+    devices: Option<DeviceRegistry>,
+    interceptors: Interceptors<SignalMessage>,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks
 }
 
 impl AvdtpBuilder {
-    pub fn with_endpoint(mut self, endpoint: LocalEndpoint) -> Self {
-        self.endpoints.push(endpoint);
+    pub fn with_endpoint(self, endpoint: LocalEndpoint) -> Self {
+        self.with_prioritized_endpoint(endpoint, 0)
+    }
+
+    /// Endpoints with a higher `priority` are listed first in DISCOVER responses.
+    /// A peer that already configured a stream can't configure an additional one on a lower priority endpoint
+    /// of the same media type, so it sticks with the preferred codec (e.g. AAC over SBC), see
+    /// [AvdtpBuilder::with_endpoint_selector] to decide otherwise.
+    pub fn with_prioritized_endpoint(mut self, endpoint: LocalEndpoint, priority: u8) -> Self {
+        self.endpoints.push((priority, endpoint));
+        self
+    }
+
+    /// Decides which configuration to keep when the peer configures more than one local endpoint of the same media
+    /// type, e.g. SBC next to AAC. The selector gets the existing configurations followed by the new one and returns
+    /// the index of the preferred one. The new configuration is rejected unless it is picked, `None` accepts it next to
+    /// the existing ones, which are never affected. Without a selector the endpoint that ranks higher is kept.
+    pub fn with_endpoint_selector<F>(mut self, selector: F) -> Self
+    where
+        F: Fn(&[RemoteEndpoint]) -> Option<usize> + Send + Sync + 'static
+    {
+        self.selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Keeps the stream handlers playing for `grace_period` after a stream was suspended.
+    /// Some phones suspend and restart streams in quick succession, which would otherwise
+    /// close and reopen the audio device every time. Disabled by default.
+    pub fn with_suspend_grace_period(mut self, grace_period: Duration) -> Self {
+        self.suspend_grace_period = grace_period;
+        self
+    }
+
+    /// Treats a stream as idle when the peer sends no media packets for `timeout` without suspending it,
+    /// which some phones do when playback is paused. The stream handler is stopped and notified with
+    /// [StreamHandler::on_idle], and started again once media packets return. Disabled by default.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Negotiates L2CAP streaming mode for the media channels of peers that support it,
+    /// so late media packets are dropped after `flush_timeout` milliseconds instead of delaying the stream.
+    /// See [ConfigurationPolicy::streaming].
+    pub fn with_streaming_mode(mut self, flush_timeout: u16) -> Self {
+        self.transport_policy = Some(ConfigurationPolicy::streaming(flush_timeout));
+        self
+    }
+
+    /// Limits the codec configurations of all endpoints, see [Avdtp::set_codec_constraints].
+    pub fn with_codec_constraints(mut self, constraints: CodecConstraints) -> Self {
+        self.codec_constraints = constraints;
         self
     }
 
-    pub fn build(self) -> Avdtp {
+    /// Reports the AVDTP sessions as connected A2DP profiles of the devices.
+    pub fn with_device_registry(mut self, registry: DeviceRegistry) -> Self {
+        self.devices = Some(registry);
+        self
+    }
+
+    /// Shows every signaling message to `interceptor` before it is processed or sent.
+    /// Vetoed commands are not answered, vetoed responses are never sent.
+    pub fn with_interceptor<F>(mut self, interceptor: F) -> Self
+    where
+        F: Fn(Direction, &SignalMessage) -> Verdict + Send + Sync + 'static
+    {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Lets the qualification tester force rejects of signals, see [PtsHooks].
+    #[cfg(feature = "pts")]
+    pub fn with_pts_hooks(mut self, hooks: PtsHooks) -> Self {
+        self.pts = hooks;
+        self
+    }
+
+    pub fn build(mut self) -> Avdtp {
+        // Stable sort, so the registration order decides between endpoints of the same priority
+        self.endpoints.sort_by(|(pa, _), (pb, _)| pb.cmp(pa));
         Avdtp {
             pending_streams: Arc::new(Mutex::new(BTreeMap::new())),
-            local_endpoints: self.endpoints.into()
+            sessions: Arc::new(Mutex::new(BTreeMap::new())),
+            local_endpoints: self.endpoints.into_iter().map(|(_, ep)| ep).collect(),
+            selector: self.selector,
+            suspend_grace_period: self.suspend_grace_period,
+            idle_timeout: self.idle_timeout,
+            transport_policy: self.transport_policy,
+            codec_constraints: Arc::new(Mutex::new(self.codec_constraints)),
+            devices: self.devices,
+            interceptors: self.interceptors,
+            #[cfg(feature = "pts")]
+            pts: self.pts
         }
     }
 }
 
 type ChannelSender = MutexCell<Option<Sender<Channel>>>;
 
+/// The streams of an AVDTP session for debugging.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AvdtpSessionSnapshot {
+    pub handle: u16,
+    pub streams: Vec<StreamSnapshot>
+}
+
 #[derive(Clone)]
 pub struct Avdtp {
     pending_streams: Arc<Mutex<BTreeMap<u16, Arc<ChannelSender>>>>,
-    local_endpoints: Arc<[LocalEndpoint]>
+    sessions: Arc<Mutex<BTreeMap<u16, AvdtpSessionSnapshot>>>,
+    local_endpoints: Arc<[LocalEndpoint]>,
+    selector: Option<EndpointSelector>,
+    suspend_grace_period: Duration,
+    idle_timeout: Option<Duration>,
+    transport_policy: Option<ConfigurationPolicy>,
+    codec_constraints: Arc<Mutex<CodecConstraints>>,
+    devices: Option<DeviceRegistry>,
+    interceptors: Interceptors<SignalMessage>,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks
 }
 
 impl Avdtp {
+    /// Replaces the limits of the codec configurations. The capabilities reported to peers and the
+    /// configurations accepted from then on follow the new constraints, running streams are not affected.
+    pub fn set_codec_constraints(&self, constraints: CodecConstraints) {
+        *self.codec_constraints.lock() = constraints;
+    }
+
+    pub fn codec_constraints(&self) -> CodecConstraints {
+        self.codec_constraints.lock().clone()
+    }
 
     pub fn connect(self: Arc<Self>, l2cap: &mut L2capServer, handle: u16) {
-        let mut channel = l2cap.new_channel(handle).expect("Failed to create channel");
-        spawn(async move {
+        let Some(mut channel) = l2cap.new_channel(handle) else {
+            warn!("Failed to create AVDTP channel for 0x{:04x}", handle);
+            return;
+        };
+        spawn_supervised("avdtp-connect", format!("handle 0x{:04x}", handle), async move {
             channel.connect(self.psm()).await.ignore();
             self.handle(channel);
         });
@@ -66,6 +220,54 @@ impl Avdtp {
 
 }
 
+impl Profile for Avdtp {
+    fn name(&self) -> &'static str {
+        "A2DP"
+    }
+
+    fn service_records(&self, handles: &mut RecordHandles) -> Vec<Box<dyn ServiceRecord>> {
+        let has_audio_endpoint = |tsep| {
+            self.local_endpoints
+                .iter()
+                .any(|ep| ep.media_type == MediaType::Audio && ep.tsep == tsep)
+        };
+        let mut records: Vec<Box<dyn ServiceRecord>> = Vec::new();
+        if has_audio_endpoint(StreamEndpointType::Sink) {
+            records.push(Box::new(A2dpSinkServiceRecord::new(handles.allocate())));
+        }
+        if has_audio_endpoint(StreamEndpointType::Source) {
+            records.push(Box::new(A2dpSourceServiceRecord::new(handles.allocate())));
+        }
+        records
+    }
+
+    fn service_classes(&self) -> MajorServiceClasses {
+        let mut classes = MajorServiceClasses::empty();
+        for ep in self.local_endpoints.iter().filter(|ep| ep.media_type == MediaType::Audio) {
+            classes |= match ep.tsep {
+                StreamEndpointType::Sink => MajorServiceClasses::Audio | MajorServiceClasses::Rendering,
+                StreamEndpointType::Source => MajorServiceClasses::Audio | MajorServiceClasses::Capturing
+            };
+        }
+        classes
+    }
+
+    fn roles(&self) -> ProfileRoles {
+        let mut roles = ProfileRoles::empty();
+        for ep in self.local_endpoints.iter().filter(|ep| ep.media_type == MediaType::Audio) {
+            roles |= match ep.tsep {
+                StreamEndpointType::Sink => ProfileRoles::A2DP_SINK,
+                StreamEndpointType::Source => ProfileRoles::A2DP_SOURCE
+            };
+        }
+        roles
+    }
+
+    fn debug_snapshot(&self) -> Option<ProfileSnapshot> {
+        Some(ProfileSnapshot::Avdtp(self.sessions.lock().values().cloned().collect()))
+    }
+}
+
 impl ProtocolHandler for Avdtp {
     fn psm(&self) -> u64 {
         AVDTP_PSM as u64
@@ -78,12 +280,21 @@ impl ProtocolHandler for Avdtp {
             None => {
                 trace!("New AVDTP session (signaling channel)");
                 let pending_streams = self.pending_streams.clone();
+                let sessions = self.sessions.clone();
                 let pending_stream = Arc::new(ChannelSender::default());
                 pending_streams
                     .lock()
                     .insert(handle, pending_stream.clone());
 
                 let local_endpoints = self.local_endpoints.clone();
+                let codec_constraints = self.codec_constraints.clone();
+                let suspend_grace_period = self.suspend_grace_period;
+                let idle_timeout = self.idle_timeout;
+                let devices = self.devices.clone();
+                let interceptors = self.interceptors.clone();
+                #[cfg(feature = "pts")]
+                let pts = self.pts.clone();
+                let addr = channel.remote_addr();
 
                 if channel.is_response_pending() && channel.accept_connection().log_err().is_err() {
                     return;
@@ -94,22 +305,42 @@ impl ProtocolHandler for Avdtp {
                     runtime.block_on(async move {
                         if let Err(err) = channel.configure().await {
                             warn!("Error configuring channel: {:?}", err);
+                            if let Some(devices) = &devices {
+                                devices.set_profile_failed(addr, ADVANCED_AUDIO_DISTRIBUTION, err.into());
+                            }
                             return;
                         }
                         let mut session = AvdtpSession {
+                            handle,
+                            snapshots: sessions.clone(),
                             channel_sender: pending_stream,
                             channel_receiver: OptionFuture::never(),
                             local_endpoints,
-                            streams: Vec::new()
+                            selector,
+                            codec_constraints,
+                            suspend_grace_period,
+                            idle_timeout,
+                            interceptors,
+                            #[cfg(feature = "pts")]
+                            pts,
+                            pending_streams: Vec::new(),
+                            streams: PollSet::default(),
+                            published_snapshot: None
                         };
-                        session
-                            .handle_control_channel(channel)
-                            .await
-                            .unwrap_or_else(|err| {
-                                warn!("Error handling control channel: {:?}", err);
-                            });
+                        if let Some(devices) = &devices {
+                            devices.set_profile_connected(addr, ADVANCED_AUDIO_DISTRIBUTION, true);
+                        }
+                        let context = format!("handle 0x{:04x}", handle);
+                        match supervise("avdtp-session", context, session.handle_control_channel(channel)).await {
+                            Some(Err(err)) => warn!("Error handling control channel: {:?}", err),
+                            Some(Ok(())) | None => {}
+                        }
+                        if let Some(devices) = &devices {
+                            devices.set_profile_connected(addr, ADVANCED_AUDIO_DISTRIBUTION, false);
+                        }
                         trace!("AVDTP signaling session ended for 0x{:04x}", handle);
                         pending_streams.lock().remove(&handle);
+                        sessions.lock().remove(&handle);
                     })
                 });
             }
@@ -119,14 +350,18 @@ impl ProtocolHandler for Avdtp {
                     if channel.accept_connection().log_err().is_err() {
                         return;
                     }
-                    spawn(async move {
+                    if let Some(policy) = &self.transport_policy {
+                        channel.set_configuration_policy(policy.clone());
+                    }
+                    spawn_supervised("avdtp-transport", format!("handle 0x{:04x}", handle), async move {
                         if let Err(err) = channel.configure().await {
                             warn!("Error configuring channel: {:?}", err);
                             return;
                         }
-                        sender
-                            .send(channel)
-                            .unwrap_or_else(|_| error!("Failed to send channel to session"));
+                        // The session ended in the meantime, dropping the channel disconnects it
+                        if sender.send(channel).is_err() {
+                            debug!("AVDTP session closed before its transport channel was established");
+                        }
                     });
                 }
                 None => {
@@ -140,27 +375,86 @@ impl ProtocolHandler for Avdtp {
     }
 }
 
-struct AvdtpSession {
+pub(crate) struct AvdtpSession {
+    handle: u16,
+    snapshots: Arc<Mutex<BTreeMap<u16, AvdtpSessionSnapshot>>>,
     channel_sender: Arc<ChannelSender>,
     channel_receiver: OptionFuture<Receiver<Channel>>,
     local_endpoints: Arc<[LocalEndpoint]>,
-    streams: Vec<Stream>
+    selector: Option<EndpointSelector>,
+    codec_constraints: Arc<Mutex<CodecConstraints>>,
+    suspend_grace_period: Duration,
+    idle_timeout: Option<Duration>,
+    interceptors: Interceptors<SignalMessage>,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks,
+    pending_streams: Vec<PendingStream>,
+    /// The streams by their local SEID.
+    streams: PollSet<u8, Stream>,
+    /// The snapshot that was published last, a new one is only published if it differs.
+    published_snapshot: Option<AvdtpSessionSnapshot>
+}
+
+fn expired_stream(pending_streams: &mut [PendingStream]) -> impl Future<Output = usize> + '_ {
+    poll_fn(move |cx| {
+        pending_streams
+            .iter_mut()
+            .position(|stream| stream.poll_expired(cx).is_ready())
+            .map_or(Poll::Pending, Poll::Ready)
+    })
 }
 
 impl AvdtpSession {
+    /// A session without a signaling channel, for the tests and benchmarks.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn detached(avdtp: &Avdtp, handle: u16) -> Self {
+        Self {
+            handle,
+            snapshots: avdtp.sessions.clone(),
+            channel_sender: Arc::new(ChannelSender::default()),
+            channel_receiver: OptionFuture::never(),
+            local_endpoints: avdtp.local_endpoints.clone(),
+            selector: avdtp.selector.clone(),
+            codec_constraints: avdtp.codec_constraints.clone(),
+            suspend_grace_period: avdtp.suspend_grace_period,
+            idle_timeout: avdtp.idle_timeout,
+            interceptors: avdtp.interceptors.clone(),
+            #[cfg(feature = "pts")]
+            pts: avdtp.pts.clone(),
+            pending_streams: Vec::new(),
+            streams: PollSet::default(),
+            published_snapshot: None
+        }
+    }
+
+    /// How long a configured stream may wait for the peer to open it before the endpoint is released again.
+    const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
     async fn handle_control_channel(&mut self, mut channel: Channel) -> Result<(), L2capError> {
         let mut assembler = SignalMessageAssembler::default();
         loop {
+            self.publish_snapshot();
             select! {
-                (i, _) = select_all(self.streams.iter_mut().map(Stream::process)) => {
-                    debug!("Stream {} ended", i);
-                    self.streams.swap_remove(i);
+                (seid, _) = self.streams.next(Stream::poll) => {
+                    debug!("Stream {} ended", seid);
+                    self.streams.remove(&seid);
+                },
+                i = expired_stream(&mut self.pending_streams) => {
+                    let stream = self.pending_streams.swap_remove(i);
+                    warn!("Stream {} was not opened in time, releasing the endpoint", stream.local_endpoint);
                 },
                 signal = channel.read() => match signal {
                     Some(packet) => match assembler.process_msg(packet) {
                         Ok(Some(header)) => {
+                            if !self.interceptors.check(Direction::Incoming, &header) {
+                                debug!("Dropping intercepted {:?} signal", header.signal_identifier);
+                                continue;
+                            }
                             let reply = self.handle_signal_message(header);
-                            channel.send_signal(reply).await?;
+                            match self.interceptors.check(Direction::Outgoing, &reply) {
+                                true => channel.send_signal(reply).await?,
+                                false => debug!("Dropping intercepted {:?} reply", reply.signal_identifier)
+                            }
                         }
                         Ok(None) => continue,
                         Err(err) => {
@@ -170,19 +464,86 @@ impl AvdtpSession {
                     },
                     None => break,
                 },
-                res = &mut self.channel_receiver => {
-                    let channel = res.expect("Channel receiver closed");
-                    self.streams
-                        .iter_mut()
+                res = &mut self.channel_receiver => match res {
+                    Ok(channel) => self.streams
+                        .values_mut()
                         .find(|stream| stream.is_opening())
                         .map(|stream| stream.set_channel(channel))
-                        .unwrap_or_else(|| warn!("No stream waiting for channel"));
+                        .unwrap_or_else(|| warn!("No stream waiting for channel")),
+                    // The transport channel failed before it was handed over, the stream times out
+                    Err(_) => warn!("Transport channel was not established")
                 }
             }
         }
         Ok(())
     }
 
+    fn publish_snapshot(&mut self) {
+        let snapshot = AvdtpSessionSnapshot {
+            handle: self.handle,
+            streams: self
+                .pending_streams
+                .iter()
+                .map(PendingStream::snapshot)
+                .chain(self.streams.values().map(Stream::snapshot))
+                .collect()
+        };
+        if self.published_snapshot.as_ref() != Some(&snapshot) {
+            self.snapshots.lock().insert(self.handle, snapshot.clone());
+            self.published_snapshot = Some(snapshot);
+        }
+    }
+
+    /// The local endpoints in the order of preference, the codec priority of the constraints
+    /// takes precedence over the priority the endpoints were registered with.
+    fn ranked_endpoints(&self) -> Vec<&LocalEndpoint> {
+        let constraints = self.codec_constraints.lock();
+        let mut endpoints: Vec<&LocalEndpoint> = self.local_endpoints.iter().collect();
+        endpoints.sort_by_key(|ep| constraints.codec_rank(&ep.capabilities).unwrap_or(usize::MAX));
+        endpoints
+    }
+
+    /// Whether the configuration `candidate` of `ep` is preferred over the configured streams of the same media type,
+    /// or the selector accepts it next to them.
+    fn selects_configuration(&self, ep: &LocalEndpoint, candidate: RemoteEndpoint) -> bool {
+        let same_type = |seid: u8| {
+            self.get_endpoint(seid)
+                .is_ok_and(|other| other.media_type == ep.media_type && other.tsep == ep.tsep)
+        };
+        let mut configured: Vec<RemoteEndpoint> = self
+            .pending_streams
+            .iter()
+            .map(|stream| (stream.remote_endpoint, stream.local_endpoint, stream.capabilities()))
+            .chain(self.streams.values().filter_map(|stream| {
+                let capabilities = stream.get_capabilities().ok()?;
+                Some((stream.remote_endpoint, stream.local_endpoint, capabilities))
+            }))
+            .filter(|(_, local_seid, _)| same_type(*local_seid))
+            .map(|(seid, local_seid, capabilities)| RemoteEndpoint {
+                seid,
+                local_seid,
+                capabilities: capabilities.clone()
+            })
+            .collect();
+        if configured.is_empty() {
+            return true;
+        }
+        configured.push(candidate);
+        let selected = match &self.selector {
+            Some(selector) => selector(&configured),
+            None => {
+                let ranked = self.ranked_endpoints();
+                let rank = |seid: u8| ranked.iter().position(|ep| ep.seid == seid);
+                configured
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, endpoint)| rank(endpoint.local_seid))
+                    .map(|(index, _)| index)
+            }
+        };
+        selected.map_or(true, |index| index == configured.len() - 1)
+    }
+
     fn get_endpoint(&self, seid: u8) -> Result<&LocalEndpoint, Error> {
         self.local_endpoints
             .iter()
@@ -193,8 +554,7 @@ impl AvdtpSession {
     fn get_stream(&mut self, seid: u8) -> Result<&mut Stream, Error> {
         #[allow(clippy::obfuscated_if_else)]
         self.streams
-            .iter_mut()
-            .find(|stream| stream.local_endpoint == seid)
+            .get_mut(&seid)
             .ok_or_else(|| {
                 self.local_endpoints
                     .iter()
@@ -204,16 +564,21 @@ impl AvdtpSession {
             })
     }
 
-    fn handle_signal_message(&mut self, msg: SignalMessage) -> SignalMessage {
+    #[cfg_attr(feature = "flamegraph", inline(never))]
+    pub(crate) fn handle_signal_message(&mut self, msg: SignalMessage) -> SignalMessage {
         assert_eq!(msg.message_type, MessageType::Command);
         let resp = SignalMessageResponse::for_msg(&msg);
+        #[cfg(feature = "pts")]
+        if let Some(error) = self.pts.avdtp_reject(msg.signal_identifier) {
+            return resp.forced_reject(&msg.data, error);
+        }
         let mut data = msg.data;
         match msg.signal_identifier {
             // ([AVDTP] Section 8.6).
             SignalIdentifier::Discover => resp.try_accept((), |buf, _| {
                 data.finish()?;
                 trace!("Got DISCOVER request");
-                for endpoint in self.local_endpoints.iter() {
+                for endpoint in self.ranked_endpoints() {
                     buf.write(endpoint.as_stream_endpoint());
                 }
                 Ok(())
@@ -224,10 +589,11 @@ impl AvdtpSession {
                 data.finish()?;
                 trace!("Got GET_CAPABILITIES request for 0x{:02x}", seid);
                 let ep = self.get_endpoint(seid)?;
+                let constraints = self.codec_constraints.lock();
                 ep.capabilities
                     .iter()
                     .filter(|cap| cap.is_basic())
-                    .for_each(|cap| buf.write_ref(cap));
+                    .for_each(|cap| buf.write(constraints.restrict(cap)));
                 Ok(())
             }),
             // ([AVDTP] Section 8.8).
@@ -236,24 +602,39 @@ impl AvdtpSession {
                 data.finish()?;
                 trace!("Got GET_ALL_CAPABILITIES request for 0x{:02x}", seid);
                 let ep = self.get_endpoint(seid)?;
-                buf.write_ref(&ep.capabilities);
+                let constraints = self.codec_constraints.lock();
+                ep.capabilities
+                    .iter()
+                    .for_each(|cap| buf.write(constraints.restrict(cap)));
                 Ok(())
             }),
             // ([AVDTP] Section 8.9).
-            SignalIdentifier::SetConfiguration => resp.try_accept(ServiceCategory::Unknown, |_, _| {
+            SignalIdentifier::SetConfiguration => resp.try_accept(ServiceCategory::Unknown, |_, ctx| {
                 let acp_seid = data.read_be::<u8>()? >> 2;
                 let int_seid = data.read_be::<u8>()? >> 2;
                 let capabilities: Vec<Capability> = data.read_be()?;
                 data.finish()?;
                 trace!("Got SET_CONFIGURATION request for 0x{:02x} -> 0x{:02x}", acp_seid, int_seid);
                 let ep = self.get_endpoint(acp_seid)?;
+                ensure!(!self.streams.contains_key(&acp_seid), Error::BadState);
                 ensure!(
-                    self.streams
+                    self.pending_streams
                         .iter()
                         .all(|stream| stream.local_endpoint != acp_seid),
                     Error::BadState
                 );
-                self.streams.push(Stream::new(ep, int_seid, capabilities)?);
+                if !self.codec_constraints.lock().allows(&capabilities) {
+                    *ctx = ServiceCategory::MediaCodec;
+                    return Err(Error::UnsupportedConfiguration);
+                }
+                let candidate = RemoteEndpoint {
+                    seid: int_seid,
+                    local_seid: acp_seid,
+                    capabilities: capabilities.clone()
+                };
+                ensure!(self.selects_configuration(ep, candidate), Error::SepInUse);
+                self.pending_streams
+                    .push(PendingStream::new(ep, int_seid, capabilities, Self::OPEN_TIMEOUT)?);
                 Ok(())
             }),
             // ([AVDTP] Section 8.10).
@@ -261,16 +642,22 @@ impl AvdtpSession {
                 let seid = data.read_be::<u8>()? >> 2;
                 data.finish()?;
                 trace!("Got GET_CONFIGURATION request for 0x{:02x}", seid);
-                let stream = self.get_stream(seid)?;
-                buf.write_ref(stream.get_capabilities()?);
+                match self.pending_streams.iter().find(|stream| stream.local_endpoint == seid) {
+                    Some(pending) => buf.write_ref(pending.capabilities()),
+                    None => buf.write_ref(self.get_stream(seid)?.get_capabilities()?)
+                }
                 Ok(())
             }),
             // ([AVDTP] Section 8.11).
-            SignalIdentifier::Reconfigure => resp.try_accept(ServiceCategory::Unknown, |_, _| {
+            SignalIdentifier::Reconfigure => resp.try_accept(ServiceCategory::Unknown, |_, ctx| {
                 let acp_seid = data.read_be::<u8>()? >> 2;
                 let capabilities: Vec<Capability> = data.read_be()?;
                 data.finish()?;
                 trace!("Got RECONFIGURE request for 0x{:02x}", acp_seid);
+                if !self.codec_constraints.lock().allows(&capabilities) {
+                    *ctx = ServiceCategory::MediaCodec;
+                    return Err(Error::UnsupportedConfiguration);
+                }
                 let ep = self
                     .local_endpoints
                     .iter()
@@ -278,8 +665,7 @@ impl AvdtpSession {
                     .ok_or(Error::BadAcpSeid)?;
                 let stream = self
                     .streams
-                    .iter_mut()
-                    .find(|stream| stream.local_endpoint == acp_seid)
+                    .get_mut(&acp_seid)
                     .ok_or(Error::BadState)?;
                 stream.reconfigure(capabilities, ep)?;
                 Ok(())
@@ -289,8 +675,19 @@ impl AvdtpSession {
                 let seid = data.read_be::<u8>()? >> 2;
                 data.finish()?;
                 trace!("Got OPEN request for 0x{:02x}", seid);
-                let stream = self.get_stream(seid)?;
-                stream.set_to_opening()?;
+                let local_endpoints = self.local_endpoints.clone();
+                let ep = local_endpoints
+                    .iter()
+                    .find(|ep| ep.seid == seid)
+                    .ok_or(Error::BadAcpSeid)?;
+                let pending = self
+                    .pending_streams
+                    .iter()
+                    .position(|stream| stream.local_endpoint == seid)
+                    .ok_or(Error::BadState)?;
+                let pending = self.pending_streams.swap_remove(pending);
+                self.streams
+                    .insert(seid, Stream::open(ep, pending, self.suspend_grace_period, self.idle_timeout)?);
                 let (tx, rx) = tokio::sync::oneshot::channel();
                 self.channel_sender.set(Some(tx));
                 self.channel_receiver.set(rx);
@@ -336,13 +733,9 @@ impl AvdtpSession {
                 let seid = data.read_be::<u8>()? >> 2;
                 data.finish()?;
                 trace!("Got ABORT request for 0x{:02x}", seid);
-                if let Some(id) = self
-                    .streams
-                    .iter_mut()
-                    .position(|stream| stream.local_endpoint == seid)
-                {
-                    self.streams.swap_remove(id);
-                }
+                self.streams.remove(&seid);
+                self.pending_streams
+                    .retain(|stream| stream.local_endpoint != seid);
                 Ok(())
             }),
             // ([AVDTP] Section 8.17).
@@ -378,6 +771,28 @@ impl SignalMessageResponse {
         }
     }
 
+    /// Rejects the signal with `error` regardless of its content, with the context the signal's reject carries
+    /// ([AVDTP] Section 8.20.6).
+    #[cfg(feature = "pts")]
+    pub fn forced_reject(&self, data: &[u8], error: u8) -> SignalMessage {
+        let mut buf = BytesMut::new();
+        match self.signal_identifier {
+            // The first service category of the configuration
+            SignalIdentifier::SetConfiguration => buf.write_be(data.get(2).copied().unwrap_or_default()),
+            SignalIdentifier::Reconfigure => buf.write_be(data.get(1).copied().unwrap_or_default()),
+            // The first stream endpoint
+            SignalIdentifier::Start | SignalIdentifier::Suspend => buf.write_be(data.first().map_or(0, |seid| seid >> 2)),
+            _ => {}
+        }
+        buf.write_be(error);
+        SignalMessage {
+            transaction_label: self.transaction_label,
+            message_type: MessageType::ResponseReject,
+            signal_identifier: self.signal_identifier,
+            data: buf.freeze()
+        }
+    }
+
     pub fn unsupported(&self) -> SignalMessage {
         self.try_accept((), |_, _| Err(Error::NotSupportedCommand))
     }
@@ -411,3 +826,108 @@ impl SignalMessageResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use crate::avdtp::endpoint::{LocalEndpoint, StreamHandler, StreamHandlerFactory};
+    use crate::avdtp::error::Error;
+    use crate::avdtp::packets::{MediaType, MessageType, SignalIdentifier, SignalMessage, StreamEndpointType};
+    use crate::avdtp::{AvdtpBuilder, AvdtpSession, RemoteEndpoint};
+
+    struct Silent;
+
+    impl StreamHandler for Silent {
+        fn on_play(&mut self) {}
+
+        fn on_stop(&mut self) {}
+
+        fn on_data(&mut self, _data: Bytes) {}
+    }
+
+    /// Handles a command and returns the error code of the reject, `None` if it was accepted.
+    fn reject(session: &mut AvdtpSession, signal_identifier: SignalIdentifier, data: &'static [u8]) -> Option<u8> {
+        let reply = session.handle_signal_message(SignalMessage {
+            transaction_label: 0,
+            message_type: MessageType::Command,
+            signal_identifier,
+            data: Bytes::from_static(data)
+        });
+        match reply.message_type {
+            MessageType::ResponseAccept => None,
+            _ => reply.data.last().copied()
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_order_signals_are_rejected() {
+        let avdtp = AvdtpBuilder::default()
+            .with_endpoint(LocalEndpoint {
+                media_type: MediaType::Audio,
+                seid: 1,
+                in_use: Arc::new(AtomicBool::new(false)),
+                tsep: StreamEndpointType::Sink,
+                capabilities: Vec::new(),
+                factory: StreamHandlerFactory::new(|_| Silent)
+            })
+            .build();
+        let mut session = AvdtpSession::detached(&avdtp, 0x0001);
+
+        assert_eq!(reject(&mut session, SignalIdentifier::Open, &[2 << 2]), Some(Error::BadAcpSeid as u8));
+        assert_eq!(reject(&mut session, SignalIdentifier::Open, &[1 << 2]), Some(Error::BadState as u8));
+        assert_eq!(reject(&mut session, SignalIdentifier::Start, &[1 << 2]), Some(Error::BadState as u8));
+
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[1 << 2, 5 << 2]), None);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[1 << 2, 6 << 2]), Some(Error::BadState as u8));
+        assert_eq!(reject(&mut session, SignalIdentifier::Open, &[1 << 2]), None);
+        // The stream is already open and waits for its transport channel
+        assert_eq!(reject(&mut session, SignalIdentifier::Open, &[1 << 2]), Some(Error::BadState as u8));
+        assert_eq!(reject(&mut session, SignalIdentifier::Start, &[1 << 2]), Some(Error::BadState as u8));
+    }
+
+    fn sink(seid: u8) -> LocalEndpoint {
+        LocalEndpoint {
+            media_type: MediaType::Audio,
+            seid,
+            in_use: Arc::new(AtomicBool::new(false)),
+            tsep: StreamEndpointType::Sink,
+            capabilities: Vec::new(),
+            factory: StreamHandlerFactory::new(|_| Silent)
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_selection() {
+        // The higher priority endpoint is kept by default
+        let avdtp = AvdtpBuilder::default()
+            .with_prioritized_endpoint(sink(1), 0)
+            .with_prioritized_endpoint(sink(2), 1)
+            .build();
+        let mut session = AvdtpSession::detached(&avdtp, 0x0001);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[2 << 2, 5 << 2]), None);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[1 << 2, 6 << 2]), Some(Error::SepInUse as u8));
+
+        // Without a preference of the selector the new configuration is accepted next to the others
+        let avdtp = AvdtpBuilder::default()
+            .with_prioritized_endpoint(sink(1), 0)
+            .with_prioritized_endpoint(sink(2), 1)
+            .with_prioritized_endpoint(sink(3), 0)
+            .with_prioritized_endpoint(sink(4), 0)
+            .with_endpoint_selector(|configured: &[RemoteEndpoint]| {
+                configured
+                    .iter()
+                    .position(|endpoint| endpoint.local_seid == 3)
+            })
+            .build();
+        let mut session = AvdtpSession::detached(&avdtp, 0x0001);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[2 << 2, 5 << 2]), None);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[1 << 2, 6 << 2]), None);
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[3 << 2, 7 << 2]), None);
+        // Once it prefers an existing configuration, new ones are rejected
+        assert_eq!(reject(&mut session, SignalIdentifier::SetConfiguration, &[4 << 2, 8 << 2]), Some(Error::SepInUse as u8));
+    }
+}
@@ -1,6 +1,4 @@
-use std::cmp::Ordering;
 use std::future::Future;
-use std::iter::repeat;
 
 use bytes::{Bytes, BytesMut};
 use instructor::{Buffer, BufferMut, Error, Exstruct, Instruct};
@@ -11,6 +9,7 @@ use crate::l2cap::channel::{Channel, Error as L2capError};
 
 // ([AVDTP] Section 8.6.2).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[instructor(endian = "big")]
 pub struct StreamEndpoint {
     #[instructor(bitfield(u8))]
@@ -27,6 +26,7 @@ pub struct StreamEndpoint {
 
 // [AVDTP] Section 8.21.1.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ServiceCategory {
     #[instructor(default)]
@@ -43,6 +43,7 @@ pub enum ServiceCategory {
 
 // ([Assigned Numbers] Section 6.3.1).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum MediaType {
     Audio = 0x00,
@@ -52,6 +53,7 @@ pub enum MediaType {
 
 // ([Assigned Numbers] Section 6.5.1).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum AudioCodec {
     Sbc = 0x00,
@@ -64,6 +66,7 @@ pub enum AudioCodec {
 
 // ([Assigned Numbers] Section 6.6.1).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum VideoCodec {
     H263Baseline = 0x00,
@@ -75,6 +78,7 @@ pub enum VideoCodec {
 
 // ([Assigned Numbers] Section 6.3.1).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum StreamEndpointType {
     Source = 0x00,
@@ -171,9 +175,8 @@ impl SignalMessageAssembler {
         self.packet_count = 0;
     }
 
+    #[cfg_attr(feature = "flamegraph", inline(never))]
     pub fn process_msg(&mut self, mut data: Bytes) -> Result<Option<SignalMessage>, Error> {
-        self.packet_count += 1;
-
         let SignalHeader {
             transaction_label,
             packet_type,
@@ -181,7 +184,7 @@ impl SignalMessageAssembler {
         } = data.read_be()?;
 
         match packet_type {
-            PacketType::Single | PacketType::Start if !self.message.is_empty() => {
+            PacketType::Single | PacketType::Start if self.packet_count > 0 => {
                 warn!("Clearing incomplete message");
                 self.reset();
             }
@@ -202,6 +205,7 @@ impl SignalMessageAssembler {
                 }))
             }
             PacketType::Start => {
+                self.packet_count = 1;
                 self.transaction_label = transaction_label;
                 self.message_type = message_type;
                 self.number_of_signaling_packets = data.read_be()?;
@@ -209,39 +213,49 @@ impl SignalMessageAssembler {
                 self.message.extend_from_slice(&data);
                 Ok(None)
             }
-            PacketType::Continue => match self.packet_count < self.number_of_signaling_packets {
-                true => {
-                    self.message.extend_from_slice(&data);
-                    Ok(None)
-                }
-                false => {
-                    warn!(
-                        "Exceeded number of signaling packets (got: {}, expected: {})",
-                        self.packet_count, self.number_of_signaling_packets
-                    );
-                    self.reset();
-                    Err(Error::InvalidValue)
-                }
-            },
-            PacketType::End => match self.packet_count == self.number_of_signaling_packets {
-                true => {
-                    self.message.extend_from_slice(&data);
-                    let message = SignalMessage {
-                        transaction_label: self.transaction_label,
-                        message_type: self.message_type,
-                        signal_identifier: self.signal_identifier,
-                        data: self.message.split().freeze()
-                    };
-                    self.reset();
-                    Ok(Some(message))
+            PacketType::Continue | PacketType::End if self.packet_count == 0 => {
+                warn!("Received {:?} packet without start packet", packet_type);
+                Err(Error::InvalidValue)
+            }
+            PacketType::Continue => {
+                self.packet_count += 1;
+                match self.packet_count < self.number_of_signaling_packets {
+                    true => {
+                        self.message.extend_from_slice(&data);
+                        Ok(None)
+                    }
+                    false => {
+                        warn!(
+                            "Exceeded number of signaling packets (got: {}, expected: {})",
+                            self.packet_count, self.number_of_signaling_packets
+                        );
+                        self.reset();
+                        Err(Error::InvalidValue)
+                    }
                 }
-                false => {
-                    warn!(
-                        "Insufficient number of signaling packets (got: {}, expected: {})",
-                        self.packet_count, self.number_of_signaling_packets
-                    );
-                    self.reset();
-                    Err(Error::InvalidValue)
+            }
+            PacketType::End => {
+                self.packet_count += 1;
+                match self.packet_count == self.number_of_signaling_packets {
+                    true => {
+                        self.message.extend_from_slice(&data);
+                        let message = SignalMessage {
+                            transaction_label: self.transaction_label,
+                            message_type: self.message_type,
+                            signal_identifier: self.signal_identifier,
+                            data: self.message.split().freeze()
+                        };
+                        self.reset();
+                        Ok(Some(message))
+                    }
+                    false => {
+                        warn!(
+                            "Insufficient number of signaling packets (got: {}, expected: {})",
+                            self.packet_count, self.number_of_signaling_packets
+                        );
+                        self.reset();
+                        Err(Error::InvalidValue)
+                    }
                 }
             }
         }
@@ -253,51 +267,64 @@ pub trait SignalChannelExt {
 }
 
 impl SignalChannelExt for Channel {
-    async fn send_signal(
-        &mut self,
-        SignalMessage {
+    async fn send_signal(&mut self, message: SignalMessage) -> Result<(), L2capError> {
+        let packets = fragment_signal(message, self.remote_mtu()).map_err(L2capError::InvalidData)?;
+        self.write_batch(packets).await
+    }
+}
+
+// ([AVDTP] Section 8.4).
+fn fragment_signal(
+    SignalMessage {
+        transaction_label,
+        message_type,
+        signal_identifier,
+        data
+    }: SignalMessage,
+    mtu: u16
+) -> Result<Vec<Bytes>, Error> {
+    let mtu = mtu as usize;
+    let mut buffer = BytesMut::new();
+    if data.len() + 2 <= mtu {
+        buffer.write_be(SignalHeader {
             transaction_label,
-            message_type,
-            signal_identifier,
-            data
-        }: SignalMessage
-    ) -> Result<(), L2capError> {
-        let mut buffer = BytesMut::new();
-        let (mut packet_type, chunk_size) = match data.len() + 2 <= self.remote_mtu() as usize {
-            true => (PacketType::Single, usize::MAX),
-            false => (PacketType::Start, (self.remote_mtu() - 2) as usize)
-        };
-        let number_of_signaling_packets: u8 = data
-            .len()
-            .div_ceil(chunk_size)
-            .try_into()
-            .expect("payload too large");
-        for (i, chunk) in data
-            .chunks(chunk_size)
-            .chain(repeat([].as_slice()))
-            .enumerate()
-        {
-            buffer.write_be(SignalHeader {
-                transaction_label,
-                packet_type,
-                message_type
-            });
-            if matches!(packet_type, PacketType::Start) {
-                buffer.write_be(number_of_signaling_packets);
-            }
-            if matches!(packet_type, PacketType::Single | PacketType::Start) {
-                buffer.write_be(SignalIdentifierField { signal_identifier });
-            }
-            buffer.extend_from_slice(chunk);
-            self.write(buffer.split().freeze()).await?;
-            match (i + 2).cmp(&(number_of_signaling_packets as usize)) {
-                Ordering::Less => packet_type = PacketType::Continue,
-                Ordering::Equal => packet_type = PacketType::End,
-                Ordering::Greater => break
-            }
-        }
-        Ok(())
+            packet_type: PacketType::Single,
+            message_type
+        });
+        buffer.write_be(SignalIdentifierField { signal_identifier });
+        buffer.extend_from_slice(&data);
+        return Ok(vec![buffer.freeze()]);
     }
+    // The start packet also carries the number of packets and the signal identifier, the others only the header
+    ensure!(mtu > 3, Error::TooLong);
+    let (first, mut rest) = data.split_at(mtu - 3);
+    let number_of_signaling_packets: u8 = (1 + rest.len().div_ceil(mtu - 1))
+        .try_into()
+        .map_err(|_| Error::TooLong)?;
+    buffer.write_be(SignalHeader {
+        transaction_label,
+        packet_type: PacketType::Start,
+        message_type
+    });
+    buffer.write_be(number_of_signaling_packets);
+    buffer.write_be(SignalIdentifierField { signal_identifier });
+    buffer.extend_from_slice(first);
+    let mut packets = vec![buffer.split().freeze()];
+    while !rest.is_empty() {
+        let (chunk, remaining) = rest.split_at(rest.len().min(mtu - 1));
+        buffer.write_be(SignalHeader {
+            transaction_label,
+            packet_type: match remaining.is_empty() {
+                true => PacketType::End,
+                false => PacketType::Continue
+            },
+            message_type
+        });
+        buffer.extend_from_slice(chunk);
+        packets.push(buffer.split().freeze());
+        rest = remaining;
+    }
+    Ok(packets)
 }
 
 #[cfg(test)]
@@ -305,7 +332,10 @@ mod tests {
     use bytes::{Buf, Bytes, BytesMut};
     use instructor::{Buffer, BufferMut};
 
-    use crate::avdtp::packets::{MediaType, ServiceCategory, SignalMessageAssembler, StreamEndpoint, StreamEndpointType};
+    use crate::avdtp::packets::{
+        fragment_signal, MediaType, MessageType, ServiceCategory, SignalIdentifier, SignalMessage, SignalMessageAssembler, StreamEndpoint,
+        StreamEndpointType
+    };
 
     #[test]
     fn test_packets() {
@@ -343,4 +373,67 @@ mod tests {
         buffer.write(ep);
         assert_eq!(buffer.chunk(), data);
     }
+
+    #[test]
+    fn encode_signals() {
+        let discover = SignalMessage {
+            transaction_label: 1,
+            message_type: MessageType::Command,
+            signal_identifier: SignalIdentifier::Discover,
+            data: Bytes::new()
+        };
+        assert_eq!(fragment_signal(discover, 672).unwrap().concat(), [0x10, 0x01]);
+
+        let mut endpoints = BytesMut::new();
+        endpoints.write(StreamEndpoint {
+            seid: 0x01,
+            in_use: false,
+            media_type: MediaType::Audio,
+            tsep: StreamEndpointType::Sink
+        });
+        let discover_response = SignalMessage {
+            transaction_label: 1,
+            message_type: MessageType::ResponseAccept,
+            signal_identifier: SignalIdentifier::Discover,
+            data: endpoints.freeze()
+        };
+        assert_eq!(fragment_signal(discover_response, 672).unwrap().concat(), [0x12, 0x01, 0x04, 0x08]);
+
+        let response = SignalMessage {
+            transaction_label: 1,
+            message_type: MessageType::ResponseAccept,
+            signal_identifier: SignalIdentifier::GetAllCapabilities,
+            data: Bytes::from_static(&[0x01, 0x00, 0x07, 0x06, 0x00, 0x00, 0xFF, 0xFF, 0x02, 0x35])
+        };
+        assert_eq!(
+            fragment_signal(response, 672).unwrap().concat(),
+            [0x12, 0x0C, 0x01, 0x00, 0x07, 0x06, 0x00, 0x00, 0xFF, 0xFF, 0x02, 0x35]
+        );
+    }
+
+    #[test]
+    fn fragmentation() {
+        let data: Bytes = (0..200u8).collect();
+        let message = SignalMessage {
+            transaction_label: 3,
+            message_type: MessageType::ResponseAccept,
+            signal_identifier: SignalIdentifier::GetAllCapabilities,
+            data
+        };
+        let packets = fragment_signal(message.clone(), 48).unwrap();
+        // 45 bytes in the start packet, 47 in the others
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets[0][1], 5);
+        assert!(packets.iter().all(|packet| packet.len() <= 48));
+
+        let mut assembler = SignalMessageAssembler::default();
+        let single = fragment_signal(SignalMessage { data: Bytes::new(), ..message.clone() }, 48).unwrap();
+        assert!(assembler.process_msg(single[0].clone()).unwrap().is_some());
+        let results: Vec<_> = packets
+            .into_iter()
+            .map(|packet| assembler.process_msg(packet).unwrap())
+            .collect();
+        assert!(results[..4].iter().all(Option::is_none));
+        assert_eq!(results[4], Some(message));
+    }
 }
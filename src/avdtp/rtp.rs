@@ -0,0 +1,385 @@
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use instructor::{Buffer, Exstruct};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::a2dp::sbc::{SbcFrameHeader, SbcMediaCodecInformation};
+use crate::avdtp::capabilities::{Capability, MediaCodecCapability};
+use crate::utils::clock::now;
+
+/// The fixed part of an RTP header ([RFC3550] Section 5.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct)]
+#[instructor(endian = "big")]
+pub struct RtpHeader {
+    #[instructor(bitfield(u8))]
+    #[instructor(bits(6..8))]
+    pub version: u8,
+    #[instructor(bits(5..6))]
+    pub padding: bool,
+    #[instructor(bits(4..5))]
+    pub extension: bool,
+    #[instructor(bits(0..4))]
+    pub csrc_count: u8,
+    #[instructor(bitfield(u8))]
+    #[instructor(bits(7..8))]
+    pub marker: bool,
+    #[instructor(bits(0..7))]
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32
+}
+
+impl RtpHeader {
+    /// Splits a media packet into its header and payload, skipping contributing sources, extensions and padding.
+    pub fn parse(mut packet: Bytes) -> Result<(Self, Bytes), instructor::Error> {
+        let header: Self = packet.read()?;
+        let csrc_length = 4 * header.csrc_count as usize;
+        if packet.len() < csrc_length {
+            return Err(instructor::Error::TooShort);
+        }
+        packet.advance(csrc_length);
+        if header.extension {
+            // ([RFC3550] Section 5.3.1).
+            let _profile: u16 = packet.read_be()?;
+            let length: u16 = packet.read_be()?;
+            let length = 4 * length as usize;
+            if packet.len() < length {
+                return Err(instructor::Error::TooShort);
+            }
+            packet.advance(length);
+        }
+        if header.padding {
+            let padding = packet.last().copied().unwrap_or_default() as usize;
+            if padding == 0 || padding > packet.len() {
+                return Err(instructor::Error::InvalidValue);
+            }
+            packet.truncate(packet.len() - padding);
+        }
+        Ok((header, packet))
+    }
+}
+
+/// Why a media packet was dropped.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MediaPacketError {
+    Malformed,
+    UnsupportedVersion(u8),
+    /// The payload type changed while the stream was running.
+    UnexpectedPayloadType(u8),
+    /// The payload does not match the configured codec, e.g. a packet from before a reconfiguration.
+    CodecMismatch
+}
+
+/// How many media packets were accepted and dropped.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MediaPacketStats {
+    pub accepted: u64,
+    pub malformed: u64,
+    pub unexpected_payload_type: u64,
+    pub codec_mismatch: u64
+}
+
+/// Checks incoming media packets against the configuration of the stream.
+/// The payload type of the first valid packet is expected for the rest of the configuration.
+#[derive(Debug, Default)]
+pub struct MediaPacketValidator {
+    codec: Option<SbcMediaCodecInformation>,
+    payload_type: Option<u8>,
+    stats: MediaPacketStats,
+    last_error: Option<MediaPacketError>
+}
+
+impl MediaPacketValidator {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        let codec = capabilities.iter().find_map(|capability| match capability {
+            Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => Some(*info),
+            _ => None
+        });
+        Self {
+            codec,
+            ..Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> MediaPacketStats {
+        self.stats
+    }
+
+    /// Returns the payload of the packet or `None` if it should be dropped.
+    #[cfg_attr(feature = "flamegraph", inline(never))]
+    pub fn validate(&mut self, packet: Bytes) -> Option<Bytes> {
+        self.validate_with_header(packet).map(|(_, payload)| payload)
+    }
+
+    /// Like [MediaPacketValidator::validate], but also returns the RTP header of the packet.
+    pub fn validate_with_header(&mut self, packet: Bytes) -> Option<(RtpHeader, Bytes)> {
+        let result = self.check(packet);
+        match &result {
+            Ok(_) => {
+                self.stats.accepted += 1;
+                self.last_error = None;
+            }
+            Err(err) => {
+                match err {
+                    MediaPacketError::Malformed | MediaPacketError::UnsupportedVersion(_) => self.stats.malformed += 1,
+                    MediaPacketError::UnexpectedPayloadType(_) => self.stats.unexpected_payload_type += 1,
+                    MediaPacketError::CodecMismatch => self.stats.codec_mismatch += 1
+                }
+                // Only log the first of a series of bad packets
+                if self.last_error != Some(*err) {
+                    warn!("Dropping media packet: {:?}", err);
+                    self.last_error = Some(*err);
+                }
+            }
+        }
+        result.ok()
+    }
+
+    fn check(&mut self, packet: Bytes) -> Result<(RtpHeader, Bytes), MediaPacketError> {
+        let (header, payload) = RtpHeader::parse(packet).map_err(|_| MediaPacketError::Malformed)?;
+        if header.version != 2 {
+            return Err(MediaPacketError::UnsupportedVersion(header.version));
+        }
+        if let Some(codec) = &self.codec {
+            if !sbc_payload_matches(codec, &payload) {
+                return Err(MediaPacketError::CodecMismatch);
+            }
+        }
+        // ([A2DP] Section 4.3.4) only requires a dynamic payload type, so the first one is taken as reference
+        match self.payload_type {
+            Some(expected) if expected != header.payload_type => {
+                return Err(MediaPacketError::UnexpectedPayloadType(header.payload_type));
+            }
+            Some(_) => {}
+            None => self.payload_type = Some(header.payload_type)
+        }
+        Ok((header, payload))
+    }
+}
+
+/// Relates the RTP timestamps of a stream to the host clock, see [RtpClockEstimator].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClockMapping {
+    pub rtp_timestamp: u32,
+    /// The estimated arrival time of the media with `rtp_timestamp`, without the jitter of the link.
+    pub host_time: Instant,
+    /// The RTP clock rate in Hz, the sampling frequency for A2DP ([A2DP] Section 4.3.4).
+    pub clock_rate: u32
+}
+
+impl ClockMapping {
+    /// The host time of another RTP timestamp of the stream, timestamps more than half the RTP range away wrap around.
+    pub fn to_host_time(&self, rtp_timestamp: u32) -> Instant {
+        let delta = rtp_timestamp.wrapping_sub(self.rtp_timestamp) as i32;
+        let offset = Duration::from_secs_f64(delta.unsigned_abs() as f64 / self.clock_rate as f64);
+        match delta >= 0 {
+            true => self.host_time + offset,
+            false => self.host_time - offset
+        }
+    }
+
+    /// The RTP timestamp of the media that arrives at `time`.
+    pub fn to_rtp_timestamp(&self, time: Instant) -> u32 {
+        let ticks = |duration: Duration| (duration.as_secs_f64() * self.clock_rate as f64).round() as u64 as u32;
+        match time >= self.host_time {
+            true => self.rtp_timestamp.wrapping_add(ticks(time - self.host_time)),
+            false => self.rtp_timestamp.wrapping_sub(ticks(self.host_time - time))
+        }
+    }
+}
+
+/// Estimates a [ClockMapping] from the RTP timestamps and arrival times of the media packets of a stream.
+///
+/// The packets with the least delay approximate the transmission best, so the offset between the media clock
+/// and the host clock follows the smallest offset of the recent packets. Earlier packets move the offset down at once,
+/// while a rising minimum (the source clock runs slower than the host clock) is followed gradually.
+#[derive(Debug, Clone)]
+pub struct RtpClockEstimator {
+    clock_rate: u32,
+    /// The arrival time of the first packet, everything else is measured relative to it.
+    origin: Option<Instant>,
+    last_timestamp: u32,
+    /// The RTP timestamp of the last packet relative to the origin, without wrap-arounds.
+    extended: i64,
+    /// The filtered arrival time minus media time in seconds.
+    offset: f64,
+    window_minimum: f64,
+    window_end: Instant
+}
+
+impl RtpClockEstimator {
+    /// How often the offset follows the minimum of the last window.
+    const WINDOW: Duration = Duration::from_secs(2);
+    /// How much of the difference to the window minimum is applied per window.
+    const SMOOTHING: f64 = 0.25;
+    /// Timestamp jumps of more than this many seconds restart the estimation, e.g. when the source restarts its clock.
+    const MAX_JUMP: f64 = 10.0;
+
+    pub fn new(clock_rate: u32) -> Self {
+        assert_ne!(clock_rate, 0, "RTP clock rate must not be zero");
+        Self {
+            clock_rate,
+            origin: None,
+            last_timestamp: 0,
+            extended: 0,
+            offset: 0.0,
+            window_minimum: f64::INFINITY,
+            window_end: now()
+        }
+    }
+
+    /// The estimator of an SBC stream, other codecs don't have a known clock rate.
+    pub fn for_capabilities(capabilities: &[Capability]) -> Option<Self> {
+        capabilities
+            .iter()
+            .find_map(|capability| match capability {
+                Capability::MediaCodec(MediaCodecCapability::Sbc(info)) => info.sampling_frequencies.as_value(),
+                _ => None
+            })
+            .map(Self::new)
+    }
+
+    /// Forgets the previous packets, e.g. after the stream was suspended.
+    pub fn reset(&mut self) {
+        self.origin = None;
+    }
+
+    /// Adds a packet and returns the updated mapping for its timestamp.
+    pub fn update(&mut self, rtp_timestamp: u32, received: Instant) -> ClockMapping {
+        let Some(origin) = self.origin else {
+            return self.restart(rtp_timestamp, received);
+        };
+        let delta = rtp_timestamp.wrapping_sub(self.last_timestamp) as i32;
+        if (delta.unsigned_abs() as f64 / self.clock_rate as f64) > Self::MAX_JUMP {
+            return self.restart(rtp_timestamp, received);
+        }
+        self.last_timestamp = rtp_timestamp;
+        self.extended += delta as i64;
+
+        let media_time = self.extended as f64 / self.clock_rate as f64;
+        let offset = received.saturating_duration_since(origin).as_secs_f64() - media_time;
+        self.window_minimum = self.window_minimum.min(offset);
+        if offset < self.offset {
+            self.offset = offset;
+        }
+        if received >= self.window_end {
+            self.offset += Self::SMOOTHING * (self.window_minimum - self.offset);
+            self.window_minimum = f64::INFINITY;
+            self.window_end = received + Self::WINDOW;
+        }
+        ClockMapping {
+            rtp_timestamp,
+            host_time: offset_instant(origin, media_time + self.offset),
+            clock_rate: self.clock_rate
+        }
+    }
+
+    fn restart(&mut self, rtp_timestamp: u32, received: Instant) -> ClockMapping {
+        self.origin = Some(received);
+        self.last_timestamp = rtp_timestamp;
+        self.extended = 0;
+        self.offset = 0.0;
+        self.window_minimum = f64::INFINITY;
+        self.window_end = received + Self::WINDOW;
+        ClockMapping {
+            rtp_timestamp,
+            host_time: received,
+            clock_rate: self.clock_rate
+        }
+    }
+}
+
+fn offset_instant(base: Instant, seconds: f64) -> Instant {
+    match seconds >= 0.0 {
+        true => base + Duration::from_secs_f64(seconds),
+        false => base
+            .checked_sub(Duration::from_secs_f64(-seconds))
+            .unwrap_or(base)
+    }
+}
+
+/// Checks the first frame of an SBC media payload against the configured parameters ([A2DP] Section 4.3.4).
+fn sbc_payload_matches(codec: &SbcMediaCodecInformation, payload: &[u8]) -> bool {
+    let Some(header) = payload.get(1..).and_then(SbcFrameHeader::parse) else {
+        return false;
+    };
+    codec.sampling_frequencies.as_value() == Some(header.sampling_frequency)
+        && codec.channel_modes == header.channel_mode
+        && codec.subbands.as_value() == Some(header.subbands as u32)
+        && codec.block_lengths.as_value() == Some(header.blocks as u32)
+        && codec.allocation_methods == header.allocation_method
+        && (codec.minimum_bitpool..=codec.maximum_bitpool).contains(&header.bitpool)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::time::Instant;
+
+    use crate::a2dp::sbc::{AllocationMethods, BlockLengths, ChannelModes, SamplingFrequencies, SbcMediaCodecInformation, Subbands};
+    use crate::avdtp::capabilities::Capability;
+    use crate::avdtp::rtp::{MediaPacketValidator, RtpClockEstimator, RtpHeader};
+
+    fn packet(payload_type: u8, config: u8) -> Bytes {
+        let mut packet = vec![0x80, payload_type, 0x00, 0x01, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x01];
+        packet.extend_from_slice(&[0x01, 0x9C, config, 0x35, 0x00]);
+        Bytes::from(packet)
+    }
+
+    #[test]
+    fn header() {
+        let (header, payload) = RtpHeader::parse(packet(96, 0xBD)).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.payload_type, 96);
+        assert_eq!(header.sequence_number, 1);
+        assert_eq!(header.timestamp, 128);
+        assert_eq!(payload.len(), 5);
+    }
+
+    #[test]
+    fn validation() {
+        let configured = SbcMediaCodecInformation {
+            sampling_frequencies: SamplingFrequencies::FREQ_44100,
+            channel_modes: ChannelModes::JOINT_STEREO,
+            block_lengths: BlockLengths::SIXTEEN,
+            subbands: Subbands::EIGHT,
+            allocation_methods: AllocationMethods::LOUDNESS,
+            minimum_bitpool: 2,
+            maximum_bitpool: 53
+        };
+        let mut validator = MediaPacketValidator::new(&[Capability::MediaTransport, Capability::MediaCodec(configured.into())]);
+        assert!(validator.validate(packet(96, 0xBD)).is_some());
+        // 48kHz frame of a previous configuration
+        assert!(validator.validate(packet(96, 0xFD)).is_none());
+        assert!(validator.validate(packet(97, 0xBD)).is_none());
+        let stats = validator.stats();
+        assert_eq!((stats.accepted, stats.codec_mismatch, stats.unexpected_payload_type), (1, 1, 1));
+    }
+
+    #[test]
+    fn clock_mapping() {
+        let start = Instant::now();
+        let mut estimator = RtpClockEstimator::new(44100);
+        // 128 samples per packet, every third packet is delayed by 20ms and the timestamps wrap around
+        let mut mapping = None;
+        for i in 0..1000u32 {
+            let timestamp = (u32::MAX - 50_000).wrapping_add(i * 128);
+            let jitter = Duration::from_millis(if i % 3 == 0 { 20 } else { 0 });
+            let arrival = start + Duration::from_secs_f64(i as f64 * 128.0 / 44100.0) + jitter;
+            mapping = Some(estimator.update(timestamp, arrival));
+        }
+        let mapping = mapping.unwrap();
+        let expected = start + Duration::from_secs_f64(999.0 * 128.0 / 44100.0);
+        assert!(mapping.host_time.max(expected) - mapping.host_time.min(expected) < Duration::from_millis(1));
+
+        let later = mapping.rtp_timestamp.wrapping_add(44100);
+        assert_eq!(mapping.to_host_time(later) - mapping.host_time, Duration::from_secs(1));
+        assert_eq!(mapping.to_rtp_timestamp(mapping.host_time + Duration::from_secs(1)), later);
+    }
+}
@@ -0,0 +1,391 @@
+//! The cover art responder of the target role, which serves the artwork of the local track over the BIP OBEX service
+//! ([AVRCP] Section 5.14). The handle of the artwork is reported as the default cover art attribute while the peer is
+//! connected to the service, new artwork gets a new handle so the peer notices the change.
+use std::sync::Arc;
+
+use bytes::Bytes;
+use instructor::Buffer;
+use parking_lot::Mutex;
+use tracing::{debug, trace, warn};
+
+use crate::avrcp::cover_art::{COVER_ART_UUID, IMG_DESCRIPTION, IMG_HANDLE};
+use crate::l2cap::channel::Channel;
+use crate::obex::{
+    header_ids, ConnectParameters, Header, HeaderValue, Opcode, Packet, ResponseCode, MAX_PACKET_LENGTH, MIN_PACKET_LENGTH, VERSION
+};
+
+/// The only OBEX connection of a channel.
+const CONNECTION_ID: u32 = 1;
+
+/// The artwork of the local track, see [AvrcpController::set_local_cover_art](crate::avrcp::AvrcpController::set_local_cover_art).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Artwork {
+    /// The image in its native encoding.
+    pub image: Bytes,
+    /// The BIP name of the encoding, e.g. `JPEG` or `PNG` ([BIP] Section 4.4.1).
+    pub encoding: String,
+    /// The width and height of the image in pixels.
+    pub pixels: (u32, u32),
+    /// The 200x200 pixel JPEG version of the image, which every target has to provide ([AVRCP] Section 5.14.2.2.3).
+    pub thumbnail: Bytes
+}
+
+#[derive(Debug, Default)]
+struct State {
+    artwork: Option<(u32, Arc<Artwork>)>,
+    last_handle: u32,
+    /// The OBEX connections of the peer to the cover art service.
+    connections: usize
+}
+
+/// The artwork of a session, shared by the session, its controllers and the OBEX connections of the peer.
+#[derive(Debug, Default)]
+pub(super) struct LocalArtwork(Mutex<State>);
+
+impl LocalArtwork {
+    pub fn set(&self, artwork: Option<Artwork>) {
+        let state = &mut *self.0.lock();
+        state.artwork = match artwork {
+            Some(artwork) => {
+                // Handles are seven decimal digits ([BIP] Section 4.3)
+                state.last_handle = state.last_handle % 9_999_999 + 1;
+                Some((state.last_handle, Arc::new(artwork)))
+            }
+            None => None
+        };
+    }
+
+    /// The handle of the artwork, `None` without artwork or while the peer can't fetch it.
+    pub fn handle(&self) -> Option<String> {
+        let state = self.0.lock();
+        match state.connections > 0 {
+            true => state.artwork.as_ref().map(|(handle, _)| format_handle(*handle)),
+            false => None
+        }
+    }
+
+    fn get(&self, handle: &str) -> Option<Arc<Artwork>> {
+        self.0
+            .lock()
+            .artwork
+            .as_ref()
+            .filter(|(current, _)| format_handle(*current) == handle)
+            .map(|(_, artwork)| artwork.clone())
+    }
+
+    fn set_connected(&self, connected: bool) {
+        let mut state = self.0.lock();
+        match connected {
+            true => state.connections += 1,
+            false => state.connections -= 1
+        }
+    }
+}
+
+fn format_handle(handle: u32) -> String {
+    format!("{:07}", handle)
+}
+
+/// Serves `artwork` on an OBEX channel of the peer until the channel is closed.
+pub(super) async fn serve(mut channel: Channel, artwork: Arc<LocalArtwork>) {
+    let mut session = Session::new(artwork, channel.remote_mtu());
+    while let Some(data) = channel.read().await {
+        let connect = data.first() == Some(&(Opcode::Connect as u8));
+        let response = match Packet::decode(data, connect) {
+            Ok(request) => session.process(request),
+            Err(err) => {
+                warn!("Invalid OBEX request: {:?}", err);
+                Packet::response(ResponseCode::BadRequest)
+            }
+        };
+        let sent = match response.encode() {
+            Ok(data) => channel.write(data).await.is_ok(),
+            Err(err) => {
+                warn!("Failed to encode OBEX response: {:?}", err);
+                false
+            }
+        };
+        if !sent {
+            break;
+        }
+    }
+    debug!("Cover art channel closed");
+}
+
+struct Session {
+    artwork: Arc<LocalArtwork>,
+    connected: bool,
+    /// The largest packet the peer accepts.
+    max_packet_length: u16,
+    mtu: u16,
+    /// The headers of a request that spans several packets.
+    request: Vec<Header>,
+    /// The rest of the object of the current GET operation.
+    remaining: Option<Bytes>
+}
+
+impl Session {
+    fn new(artwork: Arc<LocalArtwork>, mtu: u16) -> Self {
+        Self {
+            artwork,
+            connected: false,
+            max_packet_length: MIN_PACKET_LENGTH,
+            mtu,
+            request: Vec::new(),
+            remaining: None
+        }
+    }
+
+    fn process(&mut self, request: Packet) -> Packet {
+        let opcode = [request.code].as_slice().read_be::<Opcode>();
+        trace!("OBEX request: {:?}", opcode);
+        match opcode {
+            Ok(Opcode::Connect) => self.connect(&request),
+            Ok(Opcode::Disconnect) => {
+                self.set_connected(false);
+                Packet::response(ResponseCode::Success)
+            }
+            Ok(Opcode::Abort) => {
+                self.request.clear();
+                self.remaining = None;
+                Packet::response(ResponseCode::Success)
+            }
+            Ok(opcode @ (Opcode::Get | Opcode::GetFinal)) if self.connected => self.get(request, opcode == Opcode::GetFinal),
+            Ok(Opcode::Get | Opcode::GetFinal) => Packet::response(ResponseCode::Forbidden),
+            _ => Packet::response(ResponseCode::NotImplemented)
+        }
+    }
+
+    // ([OBEX] Section 3.4.1).
+    fn connect(&mut self, request: &Packet) -> Packet {
+        let parameters = ConnectParameters {
+            version: VERSION,
+            flags: 0,
+            max_packet_length: MAX_PACKET_LENGTH
+        };
+        let target = request
+            .header(header_ids::TARGET)
+            .and_then(HeaderValue::as_bytes);
+        if target.map_or(true, |target| target[..] != COVER_ART_UUID) {
+            return Packet::response(ResponseCode::ServiceUnavailable).with_connect(parameters);
+        }
+        self.max_packet_length = request
+            .connect
+            .map_or(MIN_PACKET_LENGTH, |connect| connect.max_packet_length.max(MIN_PACKET_LENGTH));
+        self.set_connected(true);
+        Packet::response(ResponseCode::Success)
+            .with_connect(parameters)
+            .with_header(Header::u32(header_ids::CONNECTION_ID, CONNECTION_ID))
+            .with_header(Header::bytes(header_ids::WHO, Bytes::from_static(&COVER_ART_UUID)))
+    }
+
+    // ([OBEX] Section 3.4.5).
+    fn get(&mut self, request: Packet, final_packet: bool) -> Packet {
+        self.request.extend(
+            request
+                .headers
+                .into_iter()
+                .filter(|header| header.id != header_ids::CONNECTION_ID)
+        );
+        if !final_packet {
+            return Packet::response(ResponseCode::Continue);
+        }
+        let headers = std::mem::take(&mut self.request);
+        if self.remaining.is_none() {
+            match self.find_object(&headers) {
+                Ok(object) => self.remaining = Some(object),
+                Err(code) => return Packet::response(code)
+            }
+        }
+        // The response and the body header take six bytes
+        let size = (self.max_packet_length.min(self.mtu) as usize).saturating_sub(6).max(1);
+        match self.remaining.take() {
+            Some(mut remaining) if remaining.len() > size => {
+                let part = remaining.split_to(size);
+                self.remaining = Some(remaining);
+                Packet::response(ResponseCode::Continue).with_header(Header::bytes(header_ids::BODY, part))
+            }
+            remaining => {
+                let part = remaining.unwrap_or_default();
+                Packet::response(ResponseCode::Success).with_header(Header::bytes(header_ids::END_OF_BODY, part))
+            }
+        }
+    }
+
+    /// The object of a GetImageProperties, GetImage or GetLinkedThumbnail request ([AVRCP] Section 5.14.2.2).
+    fn find_object(&self, headers: &[Header]) -> Result<Bytes, ResponseCode> {
+        let find = |id| {
+            headers
+                .iter()
+                .find(|header| header.id == id)
+                .map(|header| &header.value)
+        };
+        let handle = find(IMG_HANDLE)
+            .and_then(HeaderValue::as_text)
+            .ok_or(ResponseCode::BadRequest)?;
+        let mime_type: &[u8] = find(header_ids::TYPE)
+            .and_then(HeaderValue::as_bytes)
+            .ok_or(ResponseCode::BadRequest)?;
+        let artwork = self.artwork.get(handle).ok_or(ResponseCode::NotFound)?;
+        match mime_type.strip_suffix(&[0]).unwrap_or(mime_type) {
+            b"x-bt/img-thm" => Ok(artwork.thumbnail.clone()),
+            b"x-bt/img-properties" => Ok(image_properties(handle, &artwork).into()),
+            b"x-bt/img-img" => {
+                let descriptor = find(IMG_DESCRIPTION)
+                    .and_then(HeaderValue::as_bytes)
+                    .map(|descriptor| String::from_utf8_lossy(descriptor).into_owned())
+                    .unwrap_or_default();
+                select_image(&descriptor, &artwork).ok_or(ResponseCode::NotAcceptable)
+            }
+            _ => Err(ResponseCode::BadRequest)
+        }
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        if self.connected != connected {
+            self.connected = connected;
+            self.artwork.set_connected(connected);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.set_connected(false);
+    }
+}
+
+// ([BIP] Section 4.4.2).
+fn image_properties(handle: &str, artwork: &Artwork) -> String {
+    format!(
+        concat!(
+            "<image-properties version=\"1.0\" handle=\"{}\">\n",
+            "<native encoding=\"{}\" pixel=\"{}*{}\" size=\"{}\"/>\n",
+            "<variant encoding=\"JPEG\" pixel=\"200*200\"/>\n",
+            "</image-properties>\n"
+        ),
+        handle,
+        artwork.encoding,
+        artwork.pixels.0,
+        artwork.pixels.1,
+        artwork.image.len()
+    )
+}
+
+/// The version of the artwork that matches the image descriptor of a GetImage request ([BIP] Section 4.4.1),
+/// the native image for an empty descriptor.
+fn select_image(descriptor: &str, artwork: &Artwork) -> Option<Bytes> {
+    // Only the attributes of the image element matter, the XML declaration has an encoding as well
+    let image = descriptor.find("<image ").map_or("", |start| &descriptor[start..]);
+    let encoding = xml_attribute(image, "encoding").filter(|encoding| !encoding.is_empty());
+    let pixel = xml_attribute(image, "pixel").filter(|pixel| !pixel.is_empty());
+    let matches = |candidate: &str, pixels: (u32, u32)| {
+        encoding.map_or(true, |encoding| encoding.eq_ignore_ascii_case(candidate))
+            && pixel.map_or(true, |pixel| pixel_matches(pixel, pixels))
+    };
+    if matches(&artwork.encoding, artwork.pixels) {
+        Some(artwork.image.clone())
+    } else if matches("JPEG", (200, 200)) {
+        Some(artwork.thumbnail.clone())
+    } else {
+        None
+    }
+}
+
+/// The value of the first attribute `name`, enough for the flat documents of BIP.
+fn xml_attribute<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let length = xml[start..].find('"')?;
+    Some(&xml[start..start + length])
+}
+
+/// Whether a pixel size like `640*480` or a range like `160*120-640*480` includes `pixels` ([BIP] Section 4.4.1).
+fn pixel_matches(pixel: &str, (width, height): (u32, u32)) -> bool {
+    let parse = |size: &str| -> Option<(u32, u32)> {
+        let (width, height) = size.split_once('*')?;
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+    };
+    match pixel.split_once('-') {
+        Some((min, max)) => match (parse(min), parse(max)) {
+            (Some(min), Some(max)) => (min.0..=max.0).contains(&width) && (min.1..=max.1).contains(&height),
+            _ => false
+        },
+        None => parse(pixel) == Some((width, height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use crate::avrcp::artwork::{select_image, Artwork, LocalArtwork, Session};
+    use crate::avrcp::cover_art::{image_request, CoverArtFormat, COVER_ART_UUID};
+    use crate::obex::{header_ids, ConnectParameters, Header, HeaderValue, Opcode, Packet, ResponseCode, VERSION};
+
+    fn artwork() -> Artwork {
+        Artwork {
+            image: Bytes::from_static(&[1; 600]),
+            encoding: String::from("PNG"),
+            pixels: (640, 480),
+            thumbnail: Bytes::from_static(&[2; 300])
+        }
+    }
+
+    #[test]
+    fn serve_thumbnail() {
+        let artwork = Arc::new(LocalArtwork::default());
+        artwork.set(Some(self::artwork()));
+        assert_eq!(artwork.handle(), None);
+
+        let mut session = Session::new(artwork.clone(), 1000);
+        let connect = Packet::request(Opcode::Connect)
+            .with_connect(ConnectParameters {
+                version: VERSION,
+                flags: 0,
+                max_packet_length: 255
+            })
+            .with_header(Header::bytes(header_ids::TARGET, Bytes::from_static(&COVER_ART_UUID)));
+        let response = session.process(connect);
+        assert_eq!(response.response_code(), ResponseCode::Success);
+        assert_eq!(response.header(header_ids::CONNECTION_ID).and_then(HeaderValue::as_u32), Some(1));
+        assert_eq!(artwork.handle().as_deref(), Some("0000001"));
+
+        let mut request = Packet::request(Opcode::GetFinal);
+        request.headers = image_request("0000001", CoverArtFormat::Thumbnail);
+        let mut thumbnail = Vec::new();
+        loop {
+            let response = session.process(request);
+            assert!(response.encode().unwrap().len() <= 255);
+            for header in &response.headers {
+                thumbnail.extend_from_slice(header.value.as_bytes().unwrap());
+            }
+            match response.response_code() {
+                ResponseCode::Continue => request = Packet::request(Opcode::GetFinal),
+                code => {
+                    assert_eq!(code, ResponseCode::Success);
+                    break;
+                }
+            }
+        }
+        assert_eq!(thumbnail, [2; 300]);
+
+        let mut request = Packet::request(Opcode::GetFinal);
+        request.headers = image_request("0000002", CoverArtFormat::Thumbnail);
+        assert_eq!(session.process(request).response_code(), ResponseCode::NotFound);
+        drop(session);
+        assert_eq!(artwork.handle(), None);
+    }
+
+    #[test]
+    fn image_descriptors() {
+        let artwork = artwork();
+        let descriptor = |image: &str| format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><image-descriptor version=\"1.0\">{}</image-descriptor>", image);
+        assert_eq!(select_image("", &artwork), Some(artwork.image.clone()));
+        assert_eq!(select_image(&descriptor("<image encoding=\"PNG\" pixel=\"\"/>"), &artwork), Some(artwork.image.clone()));
+        assert_eq!(select_image(&descriptor("<image encoding=\"JPEG\" pixel=\"200*200\"/>"), &artwork), Some(artwork.thumbnail.clone()));
+        assert_eq!(select_image(&descriptor("<image encoding=\"PNG\" pixel=\"320*240-1280*960\"/>"), &artwork), Some(artwork.image.clone()));
+        assert_eq!(select_image(&descriptor("<image encoding=\"GIF\" pixel=\"640*480\"/>"), &artwork), None);
+    }
+}
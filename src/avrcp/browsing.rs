@@ -0,0 +1,545 @@
+//! Browsing the media library of the peer over the browsing channel ([AVRCP] Section 6.10).
+//! The other direction, the peer browsing our media library, is implemented in [library](super::library).
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+use bytes::{Buf, Bytes, BytesMut};
+use instructor::{Buffer, BufferMut, Exstruct, Instruct};
+use tracing::{debug, warn};
+
+use crate::avc::CommandCode;
+use crate::avrcp::charset::{self, UTF8};
+use crate::avrcp::error::{Error, ErrorCode};
+use crate::avrcp::notifications::{PlaybackStatus, UidCounter};
+use crate::avrcp::packets::{MediaAttributeId, Pdu};
+use crate::avrcp::session::{AvrcpCommand, AvrcpController};
+use crate::ensure;
+use crate::utils::MutexCell;
+
+
+// ([AVRCP] Section 6.10.1)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum Scope {
+    MediaPlayerList = 0x00,
+    VirtualFilesystem = 0x01,
+    Search = 0x02,
+    NowPlaying = 0x03
+}
+
+// ([AVRCP] Section 6.10.2.2)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum FolderType {
+    Mixed = 0x00,
+    Titles = 0x01,
+    Albums = 0x02,
+    Artists = 0x03,
+    Genres = 0x04,
+    Playlists = 0x05,
+    Years = 0x06,
+    #[instructor(default)]
+    Unknown = 0xFF
+}
+
+// ([AVRCP] Section 6.10.2.3)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum MediaType {
+    Audio = 0x00,
+    Video = 0x01,
+    #[instructor(default)]
+    Unknown = 0xFF
+}
+
+/// ([AVRCP] Section 6.10.2.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaPlayerItem {
+    pub player_id: u16,
+    pub major_type: u8,
+    pub sub_type: u32,
+    pub play_status: PlaybackStatus,
+    /// The feature bit mask, octet 0 first.
+    pub features: [u8; 16],
+    pub name: String
+}
+
+/// ([AVRCP] Section 6.10.2.2)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FolderItem {
+    pub uid: u64,
+    pub folder_type: FolderType,
+    pub playable: bool,
+    pub name: String
+}
+
+/// ([AVRCP] Section 6.10.2.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaItem {
+    pub uid: u64,
+    pub media_type: MediaType,
+    pub name: String,
+    pub attributes: BTreeMap<MediaAttributeId, String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BrowsingItem {
+    MediaPlayer(MediaPlayerItem),
+    Folder(FolderItem),
+    Media(MediaItem)
+}
+
+/// One page of a folder listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderItems {
+    pub uid_counter: u16,
+    pub items: Vec<BrowsingItem>
+}
+
+/// The state of the browsed player after [AvrcpController::set_browsed_player].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowsedPlayer {
+    pub uid_counter: u16,
+    pub number_of_items: u32,
+    /// The folder names from the root to the current folder.
+    pub path: Vec<String>
+}
+
+// ([AVRCP] Section 6.10.4.1)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct)]
+#[repr(u8)]
+pub enum Direction {
+    Up = 0x00,
+    Down = 0x01
+}
+
+/// The UID counter of the peer, shared by the session and all of its controllers ([AVRCP] Section 6.10.3).
+/// Database unaware players always report a counter of zero, so a player is considered database aware
+/// once it reported any other value.
+#[derive(Default)]
+pub(super) struct UidTracker {
+    counter: MutexCell<Option<u16>>,
+    database_aware: AtomicBool,
+    subscribed: AtomicBool
+}
+
+impl UidTracker {
+    pub fn get(&self) -> Option<u16> {
+        self.counter.get()
+    }
+
+    pub fn is_database_aware(&self) -> Option<bool> {
+        self.counter
+            .get()
+            .map(|_| self.database_aware.load(Relaxed))
+    }
+
+    pub fn update(&self, counter: u16) {
+        if counter != 0 {
+            self.database_aware.store(true, Relaxed);
+        }
+        let previous = self.counter.replace(Some(counter));
+        if previous.is_some_and(|previous| previous != counter) {
+            debug!("UID counter changed: {:?} -> {}", previous, counter);
+        }
+    }
+}
+
+fn read_string(data: &mut Bytes, charset: u16) -> Result<String, instructor::Error> {
+    let length: u16 = data.read_be()?;
+    ensure!(data.len() >= length as usize, instructor::Error::TooShort);
+    let value = data.split_to(length as usize);
+    Ok(charset::decode(&value, charset))
+}
+
+impl BrowsingItem {
+    /// Parses a single item, `None` for item types this implementation doesn't know.
+    pub(super) fn read(data: &mut Bytes) -> Result<Option<Self>, instructor::Error> {
+        let item_type: u8 = data.read_be()?;
+        let length: u16 = data.read_be()?;
+        ensure!(data.len() >= length as usize, instructor::Error::TooShort);
+        let mut item = data.split_to(length as usize);
+        let item = match item_type {
+            0x01 => {
+                let player_id = item.read_be()?;
+                let major_type = item.read_be()?;
+                let sub_type = item.read_be()?;
+                let play_status = item.read_be()?;
+                ensure!(item.len() >= 16, instructor::Error::TooShort);
+                let mut features = [0u8; 16];
+                item.copy_to_slice(&mut features);
+                let charset: u16 = item.read_be()?;
+                let name = read_string(&mut item, charset)?;
+                Self::MediaPlayer(MediaPlayerItem {
+                    player_id,
+                    major_type,
+                    sub_type,
+                    play_status,
+                    features,
+                    name
+                })
+            }
+            0x02 => {
+                let uid = item.read_be()?;
+                let folder_type = item.read_be()?;
+                let playable = item.read_be::<u8>()? == 0x01;
+                let charset: u16 = item.read_be()?;
+                let name = read_string(&mut item, charset)?;
+                Self::Folder(FolderItem {
+                    uid,
+                    folder_type,
+                    playable,
+                    name
+                })
+            }
+            0x03 => {
+                let uid = item.read_be()?;
+                let media_type = item.read_be()?;
+                let charset: u16 = item.read_be()?;
+                let name = read_string(&mut item, charset)?;
+                let count: u8 = item.read_be()?;
+                let mut attributes = BTreeMap::new();
+                for _ in 0..count {
+                    // Unknown attributes are skipped instead of failing the whole item
+                    let id = item.clone().read_be::<MediaAttributeId>().ok();
+                    let _: u32 = item.read_be()?;
+                    let charset: u16 = item.read_be()?;
+                    let value = read_string(&mut item, charset)?;
+                    if let Some(id) = id {
+                        attributes.insert(id, value);
+                    }
+                }
+                Self::Media(MediaItem {
+                    uid,
+                    media_type,
+                    name,
+                    attributes
+                })
+            }
+            _ => {
+                warn!("Skipping unknown item type: {:#04x}", item_type);
+                return Ok(None);
+            }
+        };
+        Ok(Some(item))
+    }
+}
+
+impl AvrcpController {
+    /// Sends a command over the browsing channel, which is opened on first use.
+    /// Returns the parameters of the response after the status.
+    pub(super) async fn send_browsing_cmd(&self, pdu: Pdu, parameters: Bytes) -> Result<Bytes, Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.commands
+            .send(AvrcpCommand::Browsing(pdu, parameters, tx))
+            .await
+            .map_err(|_| Error::SessionClosed)?;
+        let mut result = rx.await.map_err(|_| Error::SessionClosed)??;
+        let status: ErrorCode = result.read_be()?;
+        ensure!(status == ErrorCode::NoError, Error::Rejected(status));
+        self.track_uid_changes().await;
+        Ok(result)
+    }
+
+    /// Subscribes to UID changes once, so the tracked counter follows changes of the media database of the peer.
+    async fn track_uid_changes(&self) {
+        if self.uids.subscribed.swap(true, Relaxed) {
+            return;
+        }
+        match self.subscribe::<UidCounter>(None).await {
+            Ok(UidCounter(counter)) => self.uids.update(counter),
+            Err(err) => debug!("Peer does not report UID changes: {}", err)
+        }
+    }
+
+    /// The last UID counter reported by the peer, `None` before the first browsing command.
+    pub fn uid_counter(&self) -> Option<u16> {
+        self.uids.get()
+    }
+
+    /// Whether the UIDs of the peer stay valid until the UID counter changes ([AVRCP] Section 6.10.3.2),
+    /// `None` before the first browsing command.
+    pub fn is_database_aware(&self) -> Option<bool> {
+        self.uids.is_database_aware()
+    }
+
+    /// Asks the peer for its current UID counter by listing the first item of the browsed folder.
+    /// Keeps the last known counter if the folder is empty.
+    async fn refresh_uid_counter(&self) -> u16 {
+        if let Err(err) = self
+            .get_folder_items(Scope::VirtualFilesystem, 0, 0, Some(&[MediaAttributeId::Title]))
+            .await
+        {
+            debug!("Failed to refresh the UID counter: {}", err);
+        }
+        self.uids.get().unwrap_or(0)
+    }
+
+    /// Runs `command` with the tracked UID counter.
+    /// If the peer rejects the counter as outdated, the command is repeated once with the refreshed counter,
+    /// a second rejection means that the UID itself is no longer valid.
+    async fn with_uid_counter<T, F, Fut>(&self, command: F) -> Result<T, Error>
+    where
+        F: Fn(u16) -> Fut,
+        Fut: Future<Output = Result<T, Error>>
+    {
+        // Database unaware players always use a counter of zero
+        let counter = self.uids.get().unwrap_or(0);
+        match command(counter).await {
+            Err(Error::Rejected(ErrorCode::UidChanged)) => {
+                let refreshed = self.refresh_uid_counter().await;
+                debug!("Peer rejected UID counter {}, retrying with {}", counter, refreshed);
+                match command(refreshed).await {
+                    Err(Error::Rejected(ErrorCode::UidChanged | ErrorCode::DoesNotExist)) => Err(Error::StaleUid),
+                    result => result
+                }
+            }
+            result => result
+        }
+    }
+
+    /// Lists the items `start..=end` of `scope` ([AVRCP] Section 6.10.4.2).
+    /// The peer may return fewer items than requested, e.g. to fit the response into a single packet.
+    /// `attributes` selects the attributes of media items, `None` requests all of them.
+    pub async fn get_folder_items(
+        &self, scope: Scope, start: u32, end: u32, attributes: Option<&[MediaAttributeId]>
+    ) -> Result<FolderItems, Error> {
+        let mut buffer = BytesMut::new();
+        buffer.write_be((scope, start, end));
+        match attributes {
+            None => buffer.write_be(0u8),
+            Some(attributes) => {
+                buffer.write_be(attributes.len() as u8);
+                for &id in attributes {
+                    buffer.write_be(id);
+                }
+            }
+        }
+        let mut result = self
+            .send_browsing_cmd(Pdu::GetFolderItems, buffer.freeze())
+            .await?;
+        let uid_counter: u16 = result.read_be()?;
+        let count: u16 = result.read_be()?;
+        let mut items = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            items.extend(BrowsingItem::read(&mut result)?);
+        }
+        self.uids.update(uid_counter);
+        Ok(FolderItems { uid_counter, items })
+    }
+
+    /// Returns an iterator over all items of `scope`, which fetches the items page by page.
+    pub fn browse_folder(&self, scope: Scope) -> FolderBrowser {
+        FolderBrowser {
+            controller: self.clone(),
+            scope,
+            attributes: None,
+            page_size: FolderBrowser::DEFAULT_PAGE_SIZE,
+            next_start: 0,
+            buffer: VecDeque::new(),
+            uid_counter: None,
+            finished: false
+        }
+    }
+
+    /// Makes `player_id` the target of all browsing commands of the virtual filesystem scope ([AVRCP] Section 6.9.3).
+    pub async fn set_browsed_player(&self, player_id: u16) -> Result<BrowsedPlayer, Error> {
+        let mut result = self
+            .send_browsing_cmd(Pdu::SetBrowsedPlayer, Bytes::copy_from_slice(&player_id.to_be_bytes()))
+            .await?;
+        let uid_counter: u16 = result.read_be()?;
+        let number_of_items: u32 = result.read_be()?;
+        let charset: u16 = result.read_be()?;
+        let depth: u8 = result.read_be()?;
+        let path = (0..depth)
+            .map(|_| read_string(&mut result, charset))
+            .collect::<Result<_, _>>()?;
+        self.uids.update(uid_counter);
+        Ok(BrowsedPlayer {
+            uid_counter,
+            number_of_items,
+            path
+        })
+    }
+
+    /// Moves up or into the folder `folder_uid` and returns the number of items in the new folder ([AVRCP] Section 6.10.4.1).
+    pub async fn change_path(&self, direction: Direction, folder_uid: u64) -> Result<u32, Error> {
+        self.with_uid_counter(|uid_counter| async move {
+            let mut buffer = BytesMut::new();
+            buffer.write_be((uid_counter, direction, folder_uid));
+            let mut result = self
+                .send_browsing_cmd(Pdu::ChangePath, buffer.freeze())
+                .await?;
+            let number_of_items: u32 = result.read_be()?;
+            Ok(number_of_items)
+        })
+        .await
+    }
+
+    /// Searches the browsed player for `query` and returns the number of results ([AVRCP] Section 6.11.1).
+    /// The results can be listed with [AvrcpController::browse_folder] in the [Scope::Search] scope.
+    pub async fn search(&self, query: &str) -> Result<u32, Error> {
+        let mut buffer = BytesMut::new();
+        buffer.write_be((UTF8, query.len() as u16));
+        buffer.extend_from_slice(query.as_bytes());
+        let mut result = self
+            .send_browsing_cmd(Pdu::Search, buffer.freeze())
+            .await?;
+        let uid_counter: u16 = result.read_be()?;
+        let number_of_items: u32 = result.read_be()?;
+        self.uids.update(uid_counter);
+        Ok(number_of_items)
+    }
+
+    /// Starts playing the item `uid` of `scope` ([AVRCP] Section 6.12.1).
+    pub async fn play_item(&self, scope: Scope, uid: u64) -> Result<(), Error> {
+        self.send_item_cmd(Pdu::PlayItem, scope, uid).await
+    }
+
+    /// Appends the item `uid` of `scope` to the now playing list ([AVRCP] Section 6.12.2).
+    pub async fn add_to_now_playing(&self, scope: Scope, uid: u64) -> Result<(), Error> {
+        self.send_item_cmd(Pdu::AddToNowPlaying, scope, uid).await
+    }
+
+    /// Sends a command of the control channel that refers to an item by its UID and the tracked UID counter.
+    async fn send_item_cmd(&self, pdu: Pdu, scope: Scope, uid: u64) -> Result<(), Error> {
+        self.with_uid_counter(|uid_counter| async move {
+            let mut buffer = BytesMut::new();
+            buffer.write_be((scope, uid, uid_counter));
+            let mut result = self
+                .send_vendor_cmd(CommandCode::Control, pdu, buffer.freeze())
+                .await?;
+            let status: ErrorCode = result.read_be()?;
+            ensure!(status == ErrorCode::NoError, Error::Rejected(status));
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Pages through a folder listing, see [AvrcpController::browse_folder].
+pub struct FolderBrowser {
+    controller: AvrcpController,
+    scope: Scope,
+    attributes: Option<Vec<MediaAttributeId>>,
+    page_size: u32,
+    next_start: u32,
+    buffer: VecDeque<BrowsingItem>,
+    uid_counter: Option<u16>,
+    finished: bool
+}
+
+impl FolderBrowser {
+    pub const DEFAULT_PAGE_SIZE: u32 = 32;
+
+    /// How many items are requested at once. The peer may still return fewer.
+    pub fn with_page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Only requests `attributes` for media items instead of all of them.
+    pub fn with_attributes(mut self, attributes: &[MediaAttributeId]) -> Self {
+        self.attributes = Some(attributes.to_vec());
+        self
+    }
+
+    /// The UID counter reported with the last page.
+    pub fn uid_counter(&self) -> Option<u16> {
+        self.uid_counter
+    }
+
+    /// Returns the next item, requesting the next page once the current one is exhausted.
+    /// Ends after the last item or the first error.
+    /// The listing restarts from the first item when the UID counter changes, so items may be returned again.
+    pub async fn next_item(&mut self) -> Option<Result<BrowsingItem, Error>> {
+        if self.buffer.is_empty() && !self.finished {
+            if self.uid_counter.is_some() && self.uid_counter != self.controller.uid_counter() {
+                debug!("Media database changed while browsing {:?}, restarting", self.scope);
+                self.next_start = 0;
+            }
+            let mut page = self.get_page().await;
+            if matches!(page, Err(Error::Rejected(ErrorCode::UidChanged))) {
+                // Reported before the changed counter reached us
+                debug!("Media database changed while browsing {:?}, restarting", self.scope);
+                self.controller.refresh_uid_counter().await;
+                self.next_start = 0;
+                page = self.get_page().await;
+            }
+            match page {
+                Ok(page) if page.items.is_empty() => self.finished = true,
+                Ok(page) => {
+                    self.uid_counter = Some(page.uid_counter);
+                    self.next_start = self.next_start.saturating_add(page.items.len() as u32);
+                    self.buffer.extend(page.items);
+                }
+                // ([AVRCP] Section 6.10.4.2) the start item is beyond the end of the folder
+                Err(Error::Rejected(ErrorCode::RangeOutOfBounds)) => self.finished = true,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+
+    async fn get_page(&self) -> Result<FolderItems, Error> {
+        let end = self.next_start.saturating_add(self.page_size - 1);
+        self.controller
+            .get_folder_items(self.scope, self.next_start, end, self.attributes.as_deref())
+            .await
+    }
+
+    /// Collects all remaining items.
+    pub async fn collect(mut self) -> Result<Vec<BrowsingItem>, Error> {
+        let mut items = Vec::new();
+        while let Some(item) = self.next_item().await {
+            items.push(item?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::avrcp::browsing::{BrowsingItem, FolderType, MediaType};
+    use crate::avrcp::MediaAttributeId;
+
+    #[test]
+    fn parse_items() {
+        let mut data = Bytes::from_static(&[
+            // Folder "A"
+            0x02, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x6A, 0x00, 0x01, b'A',
+            // Song "B" with title "C" and an unknown attribute
+            0x03, 0x00, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x6A, 0x00, 0x01, b'B', 0x02,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x6A, 0x00, 0x01, b'C',
+            0x00, 0x00, 0x00, 0x7F, 0x00, 0x6A, 0x00, 0x01, b'D',
+            // Unknown item type
+            0x7E, 0x00, 0x01, 0x00
+        ]);
+        let Some(BrowsingItem::Folder(folder)) = BrowsingItem::read(&mut data).unwrap() else {
+            panic!("expected folder")
+        };
+        assert_eq!((folder.uid, folder.folder_type, folder.playable, folder.name.as_str()), (1, FolderType::Albums, false, "A"));
+        let Some(BrowsingItem::Media(media)) = BrowsingItem::read(&mut data).unwrap() else {
+            panic!("expected media item")
+        };
+        assert_eq!((media.uid, media.media_type, media.name.as_str()), (2, MediaType::Audio, "B"));
+        assert_eq!(media.attributes.len(), 1);
+        assert_eq!(media.attributes[&MediaAttributeId::Title], "C");
+        assert_eq!(BrowsingItem::read(&mut data).unwrap(), None);
+        assert!(data.is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+//! Turns the pass-through commands of headsets and car kits into application actions.
+//! Recognizes single, double and triple presses as well as long presses per button and device.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use futures_lite::FutureExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+use tracing::trace;
+
+use crate::avc::{PassThroughOp, PassThroughState};
+use crate::avrcp::Event;
+use crate::hci::consts::BdAddr;
+use crate::utils::clock::{now, sleep_until};
+use crate::utils::redact::redacted;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Gesture {
+    Press,
+    DoublePress,
+    TriplePress,
+    /// The button was held down for the long press duration, fires while the button is still held.
+    LongPress
+}
+
+impl Gesture {
+    fn from_presses(presses: u8) -> Self {
+        match presses {
+            0 | 1 => Gesture::Press,
+            2 => Gesture::DoublePress,
+            _ => Gesture::TriplePress
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ButtonState {
+    op: PassThroughOp,
+    presses: u8,
+    held: bool,
+    deadline: Option<Instant>
+}
+
+/// Maps the gestures of the pass-through buttons of all connected controllers to actions of type `A`.
+///
+/// The events of every [AvrcpSession](crate::avrcp::AvrcpSession) are forwarded to an [ButtonInput],
+/// the recognized actions of all devices are returned by [ButtonMapper::next] or passed to the callback of [ButtonMapper::run].
+/// Presses only wait for a possible follow-up press if a multi-press gesture of the button is bound.
+pub struct ButtonMapper<A> {
+    bindings: Vec<(PassThroughOp, Gesture, A)>,
+    multi_press_interval: Duration,
+    long_press_duration: Duration,
+    sender: UnboundedSender<(BdAddr, PassThroughOp, PassThroughState)>,
+    events: UnboundedReceiver<(BdAddr, PassThroughOp, PassThroughState)>,
+    buttons: BTreeMap<(BdAddr, u8), ButtonState>
+}
+
+/// Feeds the pass-through events of a session into a [ButtonMapper].
+#[derive(Clone)]
+pub struct ButtonInput(UnboundedSender<(BdAddr, PassThroughOp, PassThroughState)>);
+
+impl ButtonInput {
+    /// Ignores everything but [Event::PassThrough].
+    pub fn handle_event(&self, addr: BdAddr, event: &Event) {
+        if let Event::PassThrough(op, state) = event {
+            self.send(addr, *op, *state);
+        }
+    }
+
+    pub fn send(&self, addr: BdAddr, op: PassThroughOp, state: PassThroughState) {
+        let _ = self.0.send((addr, op, state));
+    }
+}
+
+impl<A> Default for ButtonMapper<A> {
+    fn default() -> Self {
+        let (sender, events) = unbounded_channel();
+        Self {
+            bindings: Vec::new(),
+            multi_press_interval: Duration::from_millis(400),
+            long_press_duration: Duration::from_millis(800),
+            sender,
+            events,
+            buttons: BTreeMap::new()
+        }
+    }
+}
+
+impl<A: Clone> ButtonMapper<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_binding(mut self, op: PassThroughOp, gesture: Gesture, action: A) -> Self {
+        assert!(
+            !self.bindings.iter().any(|(o, g, _)| *o == op && *g == gesture),
+            "Duplicate binding for {:?} {:?}",
+            op,
+            gesture
+        );
+        self.bindings.push((op, gesture, action));
+        self
+    }
+
+    /// The longest time between releasing a button and pressing it again for a double or triple press.
+    pub fn with_multi_press_interval(mut self, interval: Duration) -> Self {
+        self.multi_press_interval = interval;
+        self
+    }
+
+    pub fn with_long_press_duration(mut self, duration: Duration) -> Self {
+        self.long_press_duration = duration;
+        self
+    }
+
+    pub fn input(&self) -> ButtonInput {
+        ButtonInput(self.sender.clone())
+    }
+
+    /// Waits for the next action of any device.
+    pub async fn next(&mut self) -> (BdAddr, A) {
+        loop {
+            let deadline = self.buttons.values().filter_map(|button| button.deadline).min();
+            let event = async { Some(self.events.recv().await) }
+                .or(async {
+                    match deadline {
+                        Some(deadline) => sleep_until(deadline).await,
+                        None => std::future::pending::<()>().await
+                    }
+                    None
+                })
+                .await;
+            let action = match event {
+                Some(Some((addr, op, state))) => self.process(addr, op, state),
+                Some(None) => unreachable!("The mapper holds a sender"),
+                None => self.expire()
+            };
+            if let Some(action) = action {
+                return action;
+            }
+        }
+    }
+
+    /// Passes the actions of all devices to `callback`, never returns.
+    pub async fn run<F: FnMut(BdAddr, A)>(mut self, mut callback: F) {
+        loop {
+            let (addr, action) = self.next().await;
+            callback(addr, action);
+        }
+    }
+
+    fn binding(&self, op: PassThroughOp, gesture: Gesture) -> Option<A> {
+        self.bindings
+            .iter()
+            .find(|(o, g, _)| *o == op && *g == gesture)
+            .map(|(_, _, action)| action.clone())
+    }
+
+    /// The highest number of presses with a binding for `op`.
+    fn max_presses(&self, op: PassThroughOp) -> u8 {
+        self.bindings
+            .iter()
+            .filter(|(o, _, _)| *o == op)
+            .map(|(_, gesture, _)| match gesture {
+                Gesture::DoublePress => 2,
+                Gesture::TriplePress => 3,
+                Gesture::Press | Gesture::LongPress => 1
+            })
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn process(&mut self, addr: BdAddr, op: PassThroughOp, state: PassThroughState) -> Option<(BdAddr, A)> {
+        let time = now();
+        let max_presses = self.max_presses(op);
+        let has_long_press = self.binding(op, Gesture::LongPress).is_some();
+        let button = self.buttons.entry((addr, op as u8)).or_insert(ButtonState {
+            op,
+            presses: 0,
+            held: false,
+            deadline: None
+        });
+        match state {
+            // Controllers repeat the pressed state while a button is held ([AVRCP] Section 4.6.1)
+            PassThroughState::Pressed if button.held => None,
+            PassThroughState::Pressed => {
+                button.held = true;
+                button.presses += 1;
+                button.deadline = has_long_press.then(|| time + self.long_press_duration);
+                None
+            }
+            PassThroughState::Released => {
+                let presses = match button.held {
+                    true => button.presses,
+                    // Some controllers only send the release
+                    false => button.presses + 1
+                };
+                button.held = false;
+                if presses == 0 {
+                    // The long press already fired
+                    self.buttons.remove(&(addr, op as u8));
+                    return None;
+                }
+                if presses >= max_presses {
+                    self.buttons.remove(&(addr, op as u8));
+                    return self.gesture(addr, op, Gesture::from_presses(presses));
+                }
+                button.presses = presses;
+                button.deadline = Some(time + self.multi_press_interval);
+                None
+            }
+        }
+    }
+
+    /// Handles the buttons whose deadline passed, returns the first resulting action.
+    fn expire(&mut self) -> Option<(BdAddr, A)> {
+        let time = now();
+        let expired: Vec<(BdAddr, u8)> = self
+            .buttons
+            .iter()
+            .filter(|(_, button)| button.deadline.is_some_and(|deadline| deadline <= time))
+            .map(|(key, _)| *key)
+            .collect();
+        let mut result = None;
+        for key in expired {
+            let button = self.buttons.get_mut(&key)?;
+            let (addr, op) = (key.0, button.op);
+            let gesture = match button.held {
+                true => {
+                    // Keep tracking the button until it is released, but don't count this press
+                    button.presses = 0;
+                    button.deadline = None;
+                    Gesture::LongPress
+                }
+                false => {
+                    let presses = button.presses;
+                    self.buttons.remove(&key);
+                    Gesture::from_presses(presses)
+                }
+            };
+            let action = self.gesture(addr, op, gesture);
+            result = result.or(action);
+        }
+        result
+    }
+
+    fn gesture(&self, addr: BdAddr, op: PassThroughOp, gesture: Gesture) -> Option<(BdAddr, A)> {
+        trace!("{:?} {:?} from {}", op, gesture, redacted(&addr));
+        self.binding(op, gesture).map(|action| (addr, action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::avc::PassThroughOp::{Play, VolumeUp};
+    use crate::avc::PassThroughState::{Pressed, Released};
+    use crate::avrcp::buttons::{ButtonMapper, Gesture};
+    use crate::hci::consts::BdAddr;
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    #[test]
+    fn gestures() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let phone = BdAddr::new([1, 2, 3, 4, 5, 6]);
+        let mut mapper = ButtonMapper::new()
+            .with_binding(Play, Gesture::Press, "play")
+            .with_binding(Play, Gesture::DoublePress, "next")
+            .with_binding(Play, Gesture::LongPress, "assistant")
+            .with_binding(VolumeUp, Gesture::Press, "louder");
+        let input = mapper.input();
+
+        // Without multi-press bindings the action fires on release
+        input.send(phone, VolumeUp, Pressed);
+        input.send(phone, VolumeUp, Released);
+        assert_eq!(now_or_never(mapper.next()), Some((phone, "louder")));
+
+        // A single press waits for a possible second press
+        input.send(phone, Play, Pressed);
+        input.send(phone, Play, Released);
+        assert_eq!(now_or_never(mapper.next()), None);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(now_or_never(mapper.next()), Some((phone, "play")));
+
+        input.send(phone, Play, Pressed);
+        input.send(phone, Play, Released);
+        input.send(phone, Play, Pressed);
+        input.send(phone, Play, Released);
+        assert_eq!(now_or_never(mapper.next()), Some((phone, "next")));
+
+        // Repeated pressed states while holding the button
+        input.send(phone, Play, Pressed);
+        input.send(phone, Play, Pressed);
+        assert_eq!(now_or_never(mapper.next()), None);
+        clock.advance(Duration::from_millis(900));
+        assert_eq!(now_or_never(mapper.next()), Some((phone, "assistant")));
+        input.send(phone, Play, Released);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(now_or_never(mapper.next()), None);
+    }
+}
@@ -0,0 +1,113 @@
+//! The character sets of AVRCP strings, identified by their IANA MIBenum ([AVRCP] Section 6.5.7).
+use tracing::trace;
+
+pub const UTF8: u16 = 106;
+pub const US_ASCII: u16 = 3;
+pub const ISO_8859_1: u16 = 4;
+pub const UCS2: u16 = 1000;
+pub const UTF16BE: u16 = 1013;
+pub const UTF16LE: u16 = 1014;
+pub const UTF16: u16 = 1015;
+
+/// The fallbacks in order of preference if the controller can't display UTF-8.
+const FALLBACKS: [u16; 6] = [UCS2, UTF16BE, UTF16, UTF16LE, ISO_8859_1, US_ASCII];
+
+/// Picks the character set for strings sent to a controller that announced `displayable` character sets.
+/// UTF-8 is the default and has to be supported by every controller, but some head units only render UCS-2.
+pub fn negotiate(displayable: &[u16]) -> u16 {
+    if displayable.is_empty() || displayable.contains(&UTF8) {
+        return UTF8;
+    }
+    FALLBACKS
+        .into_iter()
+        .find(|charset| displayable.contains(charset))
+        .unwrap_or(UTF8)
+}
+
+/// Characters that don't exist in the character set are replaced.
+pub fn encode(value: &str, charset: u16) -> Vec<u8> {
+    let utf16 = |big_endian: bool| -> Vec<u8> {
+        value
+            .encode_utf16()
+            .flat_map(|unit| match big_endian {
+                true => unit.to_be_bytes(),
+                false => unit.to_le_bytes()
+            })
+            .collect()
+    };
+    let narrow = |max: char| -> Vec<u8> {
+        value
+            .chars()
+            .map(|c| if c <= max { c as u8 } else { b'?' })
+            .collect()
+    };
+    match charset {
+        // UCS-2 has no surrogate pairs, so characters outside the basic multilingual plane are replaced
+        UCS2 => value
+            .chars()
+            .map(|c| u16::try_from(c as u32).unwrap_or(0xFFFD))
+            .flat_map(u16::to_be_bytes)
+            .collect(),
+        // Without a byte order mark UTF-16 is big endian (RFC 2781, Section 4.3)
+        UTF16BE | UTF16 => utf16(true),
+        UTF16LE => utf16(false),
+        ISO_8859_1 => narrow('\u{FF}'),
+        US_ASCII => narrow('\u{7F}'),
+        _ => value.as_bytes().to_vec()
+    }
+}
+
+/// Invalid sequences are replaced and unknown character sets are decoded as UTF-8.
+pub fn decode(value: &[u8], charset: u16) -> String {
+    let utf16 = |big_endian: bool| {
+        let units: Vec<u16> = value
+            .chunks_exact(2)
+            .map(|pair| match big_endian {
+                true => u16::from_be_bytes([pair[0], pair[1]]),
+                false => u16::from_le_bytes([pair[0], pair[1]])
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    match charset {
+        UCS2 | UTF16BE => utf16(true),
+        UTF16LE => utf16(false),
+        UTF16 => match value {
+            [0xFF, 0xFE, rest @ ..] => decode(rest, UTF16LE),
+            [0xFE, 0xFF, rest @ ..] => decode(rest, UTF16BE),
+            _ => utf16(true)
+        },
+        ISO_8859_1 | US_ASCII => value.iter().map(|b| char::from(*b)).collect(),
+        _ => {
+            if charset != UTF8 {
+                trace!("Unsupported character set {}, decoding as UTF-8", charset);
+            }
+            String::from_utf8_lossy(value).into_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avrcp::charset::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(&[]), UTF8);
+        assert_eq!(negotiate(&[UCS2, UTF8]), UTF8);
+        assert_eq!(negotiate(&[ISO_8859_1, UCS2]), UCS2);
+        assert_eq!(negotiate(&[0x1234]), UTF8);
+    }
+
+    #[test]
+    fn transcoding() {
+        assert_eq!(encode("Ä€", UCS2), [0x00, 0xC4, 0x20, 0xAC]);
+        assert_eq!(encode("🎵", UCS2), [0xFF, 0xFD]);
+        assert_eq!(encode("🎵", UTF16BE), [0xD8, 0x3C, 0xDF, 0xB5]);
+        assert_eq!(encode("Ä€", ISO_8859_1), [0xC4, b'?']);
+        for charset in [UTF8, UCS2, UTF16BE, UTF16LE, UTF16] {
+            assert_eq!(decode(&encode("Motörhead – Ace", charset), charset), "Motörhead – Ace");
+        }
+        assert_eq!(decode(&[0xFF, 0xFE, b'a', 0x00], UTF16), "a");
+    }
+}
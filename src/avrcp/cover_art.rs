@@ -0,0 +1,152 @@
+//! The cover art client of the controller role, which fetches images from the target over a BIP OBEX connection
+//! ([AVRCP] Section 5.14). The target only includes image handles in the metadata while the OBEX connection exists,
+//! so the connection is kept open for the rest of the session once established.
+//!
+//! GOEP requires the enhanced retransmission mode for OBEX over L2CAP, which the L2CAP layer doesn't implement yet.
+//! The channel is configured in basic mode, targets that insist on the enhanced retransmission mode refuse it.
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{debug, warn};
+
+use crate::avrcp::error::Error;
+use crate::l2cap::ChannelOpener;
+use crate::obex::{Error as ObexError, Header, ObexClient};
+use crate::utils::clock::timeout;
+
+/// The target header of the cover art service, 7163DD54-4A7E-11E2-B47C-0050C2490048 ([AVRCP] Section 5.14.2.1).
+pub const COVER_ART_UUID: [u8; 16] = [
+    0x71, 0x63, 0xDD, 0x54, 0x4A, 0x7E, 0x11, 0xE2, 0xB4, 0x7C, 0x00, 0x50, 0xC2, 0x49, 0x00, 0x48
+];
+
+// ([BIP] Section 6.2).
+pub(super) const IMG_HANDLE: u8 = 0x30;
+pub(super) const IMG_DESCRIPTION: u8 = 0x71;
+
+/// How long a connection or a complete image transfer may take.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Images above this size are aborted, no reasonable cover art gets close.
+const MAX_IMAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Which version of a cover art image to fetch.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CoverArtFormat {
+    /// The 200x200 pixel JPEG thumbnail every target provides (GetLinkedThumbnail).
+    Thumbnail,
+    /// The image in its native encoding and resolution (GetImage).
+    Image
+}
+
+/// The headers of the request for the image `handle` ([AVRCP] Section 5.14.2.2).
+pub(super) fn image_request(handle: &str, format: CoverArtFormat) -> Vec<Header> {
+    let mut headers = vec![Header::text(IMG_HANDLE, handle)];
+    match format {
+        CoverArtFormat::Thumbnail => headers.push(Header::object_type("x-bt/img-thm")),
+        CoverArtFormat::Image => {
+            headers.push(Header::object_type("x-bt/img-img"));
+            // Without a descriptor the native image is requested
+            headers.push(Header::bytes(IMG_DESCRIPTION, Bytes::new()));
+        }
+    }
+    headers
+}
+
+/// The OBEX connection of a session, shared by all clones of its [AvrcpController](crate::avrcp::AvrcpController).
+pub(super) struct CoverArt {
+    opener: ChannelOpener,
+    handle: u16,
+    /// The PSM of the cover art service of the peer, `None` if it doesn't advertise one or it is not known yet.
+    psm: Mutex<Option<u16>>,
+    client: AsyncMutex<Option<ObexClient>>
+}
+
+impl CoverArt {
+    pub fn new(opener: ChannelOpener, handle: u16, psm: Option<u16>) -> Self {
+        Self {
+            opener,
+            handle,
+            psm: Mutex::new(psm),
+            client: AsyncMutex::new(None)
+        }
+    }
+
+    /// Sets the PSM once the features of the peer were discovered.
+    pub fn set_psm(&self, psm: Option<u16>) {
+        *self.psm.lock() = psm;
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.psm.lock().is_some()
+    }
+
+    /// Establishes the OBEX connection if there is none yet.
+    pub async fn connect(&self) -> Result<(), Error> {
+        let mut client = self.client.lock().await;
+        if client.is_none() {
+            *client = Some(self.open_client().await?);
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, image_handle: &str, format: CoverArtFormat) -> Result<Bytes, Error> {
+        let mut guard = self.client.lock().await;
+        let mut client = match guard.take() {
+            Some(client) => client,
+            None => self.open_client().await?
+        };
+        let Ok(result) = timeout(TRANSFER_TIMEOUT, client.get(image_request(image_handle, format), MAX_IMAGE_SIZE)).await else {
+            // The connection is dropped, it would be out of sync with the unanswered request
+            return Err(Error::Timeout);
+        };
+        match result {
+            Err(err @ (ObexError::Channel(_) | ObexError::InvalidPacket(_))) => {
+                warn!("Cover art connection failed: {:?}", err);
+                Err(Error::CoverArtUnavailable)
+            }
+            result => {
+                *guard = Some(client);
+                result.map_err(|err| match err {
+                    ObexError::Response(code) => Error::CoverArtRejected(code),
+                    _ => Error::InvalidReturnData
+                })
+            }
+        }
+    }
+
+    async fn open_client(&self) -> Result<ObexClient, Error> {
+        let psm = self.psm.lock().ok_or(Error::CoverArtUnavailable)?;
+        let connect = async {
+            let channel = self.opener.open(self.handle, psm as u64).await?;
+            ObexClient::connect(channel, Some(Bytes::from_static(&COVER_ART_UUID))).await
+        };
+        match timeout(TRANSFER_TIMEOUT, connect).await {
+            Ok(Ok(client)) => {
+                debug!("Cover art connection established");
+                Ok(client)
+            }
+            Ok(Err(err)) => {
+                warn!("Failed to connect to the cover art service: {:?}", err);
+                Err(Error::CoverArtUnavailable)
+            }
+            Err(_) => Err(Error::Timeout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avrcp::cover_art::{image_request, CoverArtFormat};
+    use crate::obex::{header_ids, Header, Opcode, Packet};
+
+    #[test]
+    fn linked_thumbnail_request() {
+        let mut request = Packet::request(Opcode::GetFinal).with_header(Header::u32(header_ids::CONNECTION_ID, 1));
+        request.headers.extend(image_request("1000001", CoverArtFormat::Thumbnail));
+        assert_eq!(
+            request.encode().unwrap().as_ref(),
+            b"\x83\x00\x2b\xcb\x00\x00\x00\x01\x30\x00\x13\x001\x000\x000\x000\x000\x000\x001\x00\x00\x42\x00\x10x-bt/img-thm\x00"
+        );
+    }
+}
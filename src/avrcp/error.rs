@@ -2,6 +2,8 @@ use instructor::{Exstruct, Instruct};
 use thiserror::Error;
 use tracing::error;
 
+use crate::obex::ResponseCode;
+
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
 #[repr(u8)]
@@ -48,10 +50,26 @@ pub enum Error {
     NotImplemented,
     #[error("The receiver rejected the command (reason: {0:?}).")]
     Rejected(ErrorCode),
+    #[error("The receiver does not advertise the feature the command belongs to.")]
+    NotSupported,
+    #[error("The receiver did not respond in time.")]
+    Timeout,
     #[error("The receiver is currently unable to perform this action due to being in a transient state.")]
     Busy,
     #[error("The returned data has an invalid format.")]
-    InvalidReturnData
+    InvalidReturnData,
+    #[error("The receiver does not support the AVRCP profile on this channel.")]
+    InvalidProfile,
+    #[error("The receiver does not support browsing or the browsing channel could not be opened.")]
+    BrowsingUnavailable,
+    #[error("The item no longer exists after a change of the media database, the folder has to be listed again.")]
+    StaleUid,
+    #[error("The receiver does not provide cover art or the OBEX connection could not be established.")]
+    CoverArtUnavailable,
+    #[error("The receiver rejected the cover art request ({0:?}).")]
+    CoverArtRejected(ResponseCode),
+    #[error("The Bluetooth SIG company id is reserved for the commands of the specification.")]
+    ReservedCompanyId
 }
 
 
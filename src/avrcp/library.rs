@@ -0,0 +1,379 @@
+//! Lets the peer browse a media library of the application while we are the target ([AVRCP] Section 6.10).
+//! The library is a single, always addressed media player whose virtual filesystem is provided by a
+//! [MediaLibraryProvider]. The commands are answered on the session task, so the provider should answer from memory.
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use instructor::{Buffer, BufferMut};
+use tracing::{debug, warn};
+
+use crate::avrcp::browsing::{BrowsingItem, Direction, FolderItem, MediaItem, Scope};
+use crate::avrcp::charset::UTF8;
+use crate::avrcp::error::ErrorCode;
+use crate::avrcp::packets::{MediaAttributeId, Pdu};
+use crate::ensure;
+
+/// The UID of the root folder of the virtual filesystem.
+pub const ROOT_FOLDER: u64 = 0;
+/// The id of the only player in the media player list.
+pub const PLAYER_ID: u16 = 0x0001;
+
+/// The feature bits of the player: advanced control player and browsing ([AVRCP] Section 6.10.2.1).
+const PLAYER_FEATURES: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0x06, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Major player type audio ([AVRCP] Section 6.10.2.1).
+const AUDIO_PLAYER: u8 = 0x01;
+/// The attribute count of a media item that requests no attributes at all ([AVRCP] Section 6.10.4.2).
+const NO_ATTRIBUTES: u8 = 0xFF;
+
+/// The folder and media item tree the peer browses, see [Avrcp::with_media_library](crate::avrcp::Avrcp::with_media_library).
+pub trait MediaLibraryProvider: Send + Sync {
+    /// The folders and media items in the folder `folder`, [ROOT_FOLDER] for the root.
+    /// `None` if there is no such folder. Media player items are ignored.
+    fn folder_items(&self, folder: u64) -> Option<Vec<BrowsingItem>>;
+
+    /// The media item `uid` anywhere in the library, `None` if there is no such item.
+    fn media_item(&self, uid: u64) -> Option<MediaItem>;
+
+    /// Changes whenever UIDs of the library become invalid ([AVRCP] Section 6.10.3).
+    /// The default of zero announces a database unaware player, whose UIDs are only valid in the current folder.
+    fn uid_counter(&self) -> u16 {
+        0
+    }
+
+    /// The name of the player in the media player list.
+    fn player_name(&self) -> String {
+        String::from("bluefang")
+    }
+}
+
+/// The browsing state of a single session.
+pub(super) struct LibraryBrowser {
+    provider: Arc<dyn MediaLibraryProvider>,
+    /// The folders from the root to the current folder.
+    path: Vec<FolderItem>
+}
+
+impl LibraryBrowser {
+    pub fn new(provider: Arc<dyn MediaLibraryProvider>) -> Self {
+        Self {
+            provider,
+            path: Vec::new()
+        }
+    }
+
+    /// Handles a browsing command and returns the parameters of the response including the status.
+    /// `max_size` bounds the parameters, folder listings are cut short to fit.
+    pub fn process(&mut self, pdu: Pdu, mut parameters: Bytes, max_size: usize) -> Result<Bytes, ErrorCode> {
+        let mut response = BytesMut::new();
+        response.write_be(ErrorCode::NoError);
+        match pdu {
+            // ([AVRCP] Section 6.9.3)
+            Pdu::SetBrowsedPlayer => {
+                let player_id: u16 = parameters.read_be()?;
+                parameters.finish()?;
+                ensure!(player_id == PLAYER_ID, ErrorCode::InvalidPlayerId);
+                self.path.clear();
+                let items = self.current_items()?;
+                // The current folder is the root, so the path is empty
+                response.write_be((self.provider.uid_counter(), items.len() as u32, UTF8, 0u8));
+            }
+            // ([AVRCP] Section 6.10.4.2)
+            Pdu::GetFolderItems => {
+                let scope: Scope = parameters.read_be()?;
+                let start: u32 = parameters.read_be()?;
+                let end: u32 = parameters.read_be()?;
+                let attributes = match parameters.read_be::<u8>()? {
+                    NO_ATTRIBUTES => Some(Vec::new()),
+                    0 => None,
+                    count => Some(
+                        (0..count)
+                            .map(|_| parameters.read_be::<u32>())
+                            .collect::<Result<Vec<_>, _>>()?
+                    )
+                };
+                parameters.finish()?;
+                ensure!(start <= end, ErrorCode::RangeOutOfBounds);
+                let items = match scope {
+                    Scope::MediaPlayerList => vec![self.player_item()],
+                    Scope::VirtualFilesystem => self.current_items()?,
+                    scope => {
+                        debug!("Unsupported browsing scope: {:?}", scope);
+                        return Err(ErrorCode::InvalidScope);
+                    }
+                };
+                ensure!((start as usize) < items.len(), ErrorCode::RangeOutOfBounds);
+                let end = (end as usize).min(items.len() - 1);
+                let mut encoded = BytesMut::new();
+                let mut count = 0u16;
+                for item in &items[start as usize..=end] {
+                    let before = encoded.len();
+                    write_item(&mut encoded, item, attributes.as_deref());
+                    // Header of the response: status, UID counter and number of items
+                    if 5 + encoded.len() > max_size {
+                        encoded.truncate(before);
+                        break;
+                    }
+                    count += 1;
+                }
+                ensure!(count > 0, ErrorCode::InternalError, "The first item does not fit into a browsing response");
+                response.write_be((self.provider.uid_counter(), count));
+                response.put(encoded);
+            }
+            // ([AVRCP] Section 6.10.4.1)
+            Pdu::ChangePath => {
+                let uid_counter: u16 = parameters.read_be()?;
+                let direction: u8 = parameters.read_be()?;
+                let folder: u64 = parameters.read_be()?;
+                parameters.finish()?;
+                self.check_uid_counter(uid_counter)?;
+                match direction {
+                    d if d == Direction::Up as u8 => {
+                        ensure!(self.path.pop().is_some(), ErrorCode::InvalidDirection);
+                    }
+                    d if d == Direction::Down as u8 => {
+                        let item = self
+                            .current_items()?
+                            .into_iter()
+                            .find(|item| item_uid(item) == Some(folder))
+                            .ok_or(ErrorCode::DoesNotExist)?;
+                        let BrowsingItem::Folder(item) = item else {
+                            return Err(ErrorCode::NotADirectory);
+                        };
+                        self.path.push(item);
+                    }
+                    _ => return Err(ErrorCode::InvalidDirection)
+                }
+                let items = self.current_items();
+                if items.is_err() && direction == Direction::Down as u8 {
+                    // The folder vanished, stay where we were
+                    self.path.pop();
+                }
+                response.write_be(items?.len() as u32);
+            }
+            // ([AVRCP] Section 6.10.4.3)
+            Pdu::GetItemAttributes => {
+                let scope: Scope = parameters.read_be()?;
+                let uid: u64 = parameters.read_be()?;
+                let uid_counter: u16 = parameters.read_be()?;
+                let count: u8 = parameters.read_be()?;
+                let requested = (0..count)
+                    .map(|_| parameters.read_be::<u32>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                parameters.finish()?;
+                ensure!(scope == Scope::VirtualFilesystem, ErrorCode::InvalidScope);
+                self.check_uid_counter(uid_counter)?;
+                let item = self
+                    .provider
+                    .media_item(uid)
+                    .ok_or(ErrorCode::DoesNotExist)?;
+                let attributes = selected_attributes(&item, (!requested.is_empty()).then_some(&requested));
+                response.write_be(attributes.len() as u8);
+                for (id, value) in attributes {
+                    write_attribute(&mut response, id, value);
+                }
+                ensure!(response.len() <= max_size, ErrorCode::InternalError, "Item attributes exceed the browsing MTU");
+            }
+            // ([AVRCP] Section 6.10.4.4)
+            Pdu::GetTotalNumberOfItems => {
+                let scope: Scope = parameters.read_be()?;
+                parameters.finish()?;
+                let count = match scope {
+                    Scope::MediaPlayerList => 1,
+                    Scope::VirtualFilesystem => self.current_items()?.len() as u32,
+                    _ => return Err(ErrorCode::InvalidScope)
+                };
+                response.write_be((self.provider.uid_counter(), count));
+            }
+            _ => {
+                warn!("Unsupported browsing pdu: {:?}", pdu);
+                return Err(ErrorCode::InvalidCommand);
+            }
+        }
+        Ok(response.freeze())
+    }
+
+    /// Folders and media items of the current folder.
+    fn current_items(&self) -> Result<Vec<BrowsingItem>, ErrorCode> {
+        let folder = self.path.last().map_or(ROOT_FOLDER, |folder| folder.uid);
+        let mut items = self
+            .provider
+            .folder_items(folder)
+            .ok_or(ErrorCode::DoesNotExist)?;
+        items.retain(|item| !matches!(item, BrowsingItem::MediaPlayer(_)));
+        Ok(items)
+    }
+
+    /// ([AVRCP] Section 6.10.3) outdated UIDs are rejected, database unaware players accept all of them.
+    fn check_uid_counter(&self, uid_counter: u16) -> Result<(), ErrorCode> {
+        let current = self.provider.uid_counter();
+        ensure!(current == 0 || uid_counter == current, ErrorCode::UidChanged);
+        Ok(())
+    }
+
+    fn player_item(&self) -> BrowsingItem {
+        BrowsingItem::MediaPlayer(crate::avrcp::browsing::MediaPlayerItem {
+            player_id: PLAYER_ID,
+            major_type: AUDIO_PLAYER,
+            sub_type: 0,
+            play_status: Default::default(),
+            features: PLAYER_FEATURES,
+            name: self.provider.player_name()
+        })
+    }
+}
+
+fn item_uid(item: &BrowsingItem) -> Option<u64> {
+    match item {
+        BrowsingItem::Folder(folder) => Some(folder.uid),
+        BrowsingItem::Media(media) => Some(media.uid),
+        BrowsingItem::MediaPlayer(_) => None
+    }
+}
+
+/// The attributes of `item` in `requested`, all of them if `requested` is `None`.
+fn selected_attributes<'a>(item: &'a MediaItem, requested: Option<&[u32]>) -> Vec<(MediaAttributeId, &'a str)> {
+    item.attributes
+        .iter()
+        .filter(|(id, _)| requested.map_or(true, |requested| requested.contains(&(**id as u32))))
+        .map(|(id, value)| (*id, value.as_str()))
+        .collect()
+}
+
+fn write_string(buffer: &mut BytesMut, value: &str) {
+    let value = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+    buffer.write_be(value.len() as u16);
+    buffer.put_slice(value);
+}
+
+fn write_attribute(buffer: &mut BytesMut, id: MediaAttributeId, value: &str) {
+    buffer.write_be((id, UTF8));
+    write_string(buffer, value);
+}
+
+/// Writes an item with its type and length, `attributes` selects the attributes of media items.
+fn write_item(buffer: &mut BytesMut, item: &BrowsingItem, attributes: Option<&[u32]>) {
+    let mut body = BytesMut::new();
+    let item_type: u8 = match item {
+        BrowsingItem::MediaPlayer(player) => {
+            body.write_be((player.player_id, player.major_type, player.sub_type, player.play_status as u8));
+            body.put_slice(&player.features);
+            body.write_be(UTF8);
+            write_string(&mut body, &player.name);
+            0x01
+        }
+        BrowsingItem::Folder(folder) => {
+            body.write_be((folder.uid, folder.folder_type as u8, folder.playable, UTF8));
+            write_string(&mut body, &folder.name);
+            0x02
+        }
+        BrowsingItem::Media(media) => {
+            body.write_be((media.uid, media.media_type as u8, UTF8));
+            write_string(&mut body, &media.name);
+            let attributes = selected_attributes(media, attributes);
+            body.write_be(attributes.len() as u8);
+            for (id, value) in attributes {
+                write_attribute(&mut body, id, value);
+            }
+            0x03
+        }
+    };
+    buffer.write_be((item_type, body.len() as u16));
+    buffer.put(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use bytes::{Buf, Bytes, BytesMut};
+    use instructor::{Buffer, BufferMut};
+
+    use crate::avrcp::browsing::{BrowsingItem, Direction, FolderItem, FolderType, MediaItem, MediaType, Scope};
+    use crate::avrcp::error::ErrorCode;
+    use crate::avrcp::library::{LibraryBrowser, MediaLibraryProvider, PLAYER_ID, ROOT_FOLDER};
+    use crate::avrcp::packets::{MediaAttributeId, Pdu};
+
+    struct Library;
+
+    fn song() -> MediaItem {
+        MediaItem {
+            uid: 2,
+            media_type: MediaType::Audio,
+            name: String::from("Song"),
+            attributes: BTreeMap::from([
+                (MediaAttributeId::Title, String::from("Song")),
+                (MediaAttributeId::ArtistName, String::from("Artist"))
+            ])
+        }
+    }
+
+    impl MediaLibraryProvider for Library {
+        fn folder_items(&self, folder: u64) -> Option<Vec<BrowsingItem>> {
+            match folder {
+                ROOT_FOLDER => Some(vec![BrowsingItem::Folder(FolderItem {
+                    uid: 1,
+                    folder_type: FolderType::Albums,
+                    playable: false,
+                    name: String::from("Album")
+                })]),
+                1 => Some(vec![BrowsingItem::Media(song())]),
+                _ => None
+            }
+        }
+
+        fn media_item(&self, uid: u64) -> Option<MediaItem> {
+            (uid == 2).then(song)
+        }
+    }
+
+    fn request(browser: &mut LibraryBrowser, pdu: Pdu, parameters: impl instructor::Instruct<instructor::BigEndian>) -> Result<Bytes, ErrorCode> {
+        let mut buffer = BytesMut::new();
+        buffer.write_be(parameters);
+        browser.process(pdu, buffer.freeze(), 512)
+    }
+
+    #[test]
+    fn browse_library() {
+        let mut browser = LibraryBrowser::new(Arc::new(Library));
+        assert_eq!(request(&mut browser, Pdu::SetBrowsedPlayer, 7u16), Err(ErrorCode::InvalidPlayerId));
+        let mut response = request(&mut browser, Pdu::SetBrowsedPlayer, PLAYER_ID).unwrap();
+        assert_eq!(response.read_be::<ErrorCode>().unwrap(), ErrorCode::NoError);
+        // UID counter, one item, UTF-8 and the root as path
+        assert_eq!(&response[..], &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x6A, 0x00]);
+
+        let mut response = request(&mut browser, Pdu::GetFolderItems, (Scope::VirtualFilesystem, 0u32, 10u32, 0u8)).unwrap();
+        response.advance(3);
+        assert_eq!(response.read_be::<u16>().unwrap(), 1);
+        let Some(BrowsingItem::Folder(folder)) = BrowsingItem::read(&mut response).unwrap() else {
+            panic!("expected folder")
+        };
+        assert_eq!(folder.name, "Album");
+
+        assert_eq!(request(&mut browser, Pdu::ChangePath, (0u16, Direction::Down, 5u64)), Err(ErrorCode::DoesNotExist));
+        let response = request(&mut browser, Pdu::ChangePath, (0u16, Direction::Down, 1u64)).unwrap();
+        assert_eq!(&response[..], &[0x04, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(request(&mut browser, Pdu::ChangePath, (0u16, Direction::Down, 2u64)), Err(ErrorCode::NotADirectory));
+
+        // Only the title of the song
+        let mut response = request(
+            &mut browser,
+            Pdu::GetFolderItems,
+            ((Scope::VirtualFilesystem, 0u32, 0u32), (1u8, MediaAttributeId::Title))
+        )
+        .unwrap();
+        response.advance(5);
+        let Some(BrowsingItem::Media(media)) = BrowsingItem::read(&mut response).unwrap() else {
+            panic!("expected media item")
+        };
+        assert_eq!(media.attributes.len(), 1);
+        assert_eq!(request(&mut browser, Pdu::GetFolderItems, (Scope::VirtualFilesystem, 1u32, 1u32, 0u8)), Err(ErrorCode::RangeOutOfBounds));
+
+        let response = request(&mut browser, Pdu::GetItemAttributes, (Scope::VirtualFilesystem, 2u64, 0u16, 0u8)).unwrap();
+        assert_eq!(&response[..2], &[0x04, 0x02]);
+
+        let response = request(&mut browser, Pdu::ChangePath, (0u16, Direction::Up, 0u64)).unwrap();
+        assert_eq!(&response[..], &[0x04, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(request(&mut browser, Pdu::ChangePath, (0u16, Direction::Up, 0u64)), Err(ErrorCode::InvalidDirection));
+    }
+}
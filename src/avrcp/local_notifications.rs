@@ -0,0 +1,154 @@
+//! The notifications the peer registered with us as target ([AVRCP] Section 6.7.2).
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::avrcp::packets::EventId;
+use crate::utils::clock::{now, sleep_until, Sleep};
+
+#[derive(Debug, Default)]
+pub(super) struct LocalNotifications {
+    /// The transaction label of every registered notification.
+    registered: BTreeMap<EventId, u8>,
+    /// Notifications that ended with a change and were not registered again yet,
+    /// `true` if the value changed again in the meantime.
+    unregistered_changes: BTreeMap<EventId, bool>
+}
+
+impl LocalNotifications {
+    pub fn is_registered(&self, event: EventId) -> bool {
+        self.registered.contains_key(&event)
+    }
+
+    /// Registers `event` under `transaction` after the interim response was sent.
+    /// Returns whether the value changed since the last changed response, which the peer may have missed
+    /// because some controllers ignore the value of the interim response.
+    pub fn register(&mut self, event: EventId, transaction: u8) -> bool {
+        self.registered.insert(event, transaction);
+        self.unregistered_changes.remove(&event) == Some(true)
+    }
+
+    /// Returns the transaction of `event` to complete with a changed response,
+    /// or remembers the change until the peer registers again.
+    pub fn changed(&mut self, event: EventId) -> Option<u8> {
+        match self.registered.remove(&event) {
+            Some(transaction) => {
+                self.unregistered_changes.insert(event, false);
+                Some(transaction)
+            }
+            None => {
+                if let Some(changed) = self.unregistered_changes.get_mut(&event) {
+                    *changed = true;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EventId, u8)> + '_ {
+        self.registered.iter().map(|(event, transaction)| (*event, *transaction))
+    }
+}
+
+/// Keeps the changed responses of a notification at least `interval` apart.
+pub(super) struct NotificationThrottle {
+    interval: Duration,
+    last_sent: Option<Instant>,
+    /// Fires once a change that was held back may be sent.
+    flush: Option<Sleep>
+}
+
+impl NotificationThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_sent: None,
+            flush: None
+        }
+    }
+
+    /// Whether a change may be sent now. Otherwise [NotificationThrottle::poll_flush] resolves once it may.
+    pub fn try_send(&mut self) -> bool {
+        if let Some(next) = self.last_sent.map(|last| last + self.interval) {
+            if now() < next {
+                if self.flush.is_none() {
+                    self.flush = Some(sleep_until(next));
+                }
+                return false;
+            }
+        }
+        self.flush = None;
+        true
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = Some(now());
+    }
+
+    /// Resolves once a change that was held back by [NotificationThrottle::try_send] may be sent.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(flush) = self.flush.as_mut() else {
+            return Poll::Pending;
+        };
+        ready!(flush.as_mut().poll(cx));
+        self.flush = None;
+        Poll::Ready(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::avrcp::local_notifications::{LocalNotifications, NotificationThrottle};
+    use crate::avrcp::packets::EventId;
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    #[test]
+    fn changes_while_unregistered() {
+        let mut notifications = LocalNotifications::default();
+        // Changes before the first registration are covered by the interim response
+        assert_eq!(notifications.changed(EventId::TrackChanged), None);
+        assert!(!notifications.register(EventId::TrackChanged, 3));
+        assert!(notifications.is_registered(EventId::TrackChanged));
+
+        assert_eq!(notifications.changed(EventId::TrackChanged), Some(3));
+        assert!(!notifications.is_registered(EventId::TrackChanged));
+        // No change until the peer registers again, so no second changed response
+        assert!(!notifications.register(EventId::TrackChanged, 4));
+
+        assert_eq!(notifications.changed(EventId::TrackChanged), Some(4));
+        assert_eq!(notifications.changed(EventId::PlaybackStatusChanged), None);
+        assert_eq!(notifications.changed(EventId::TrackChanged), None);
+        // The change the peer missed while unregistered is reported right after the interim response
+        assert!(notifications.register(EventId::TrackChanged, 5));
+        assert!(!notifications.register(EventId::PlaybackStatusChanged, 6));
+        assert_eq!(notifications.iter().collect::<Vec<_>>(), [(EventId::PlaybackStatusChanged, 6), (EventId::TrackChanged, 5)]);
+    }
+
+    #[test]
+    fn throttled_changes_are_flushed() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let mut throttle = NotificationThrottle::new(Duration::from_millis(100));
+        assert_eq!(now_or_never(poll_fn(|cx| throttle.poll_flush(cx))), None);
+        assert!(throttle.try_send());
+        throttle.sent();
+
+        // Changes within the interval are held back until it passed
+        clock.advance(Duration::from_millis(40));
+        assert!(!throttle.try_send());
+        assert!(!throttle.try_send());
+        assert_eq!(now_or_never(poll_fn(|cx| throttle.poll_flush(cx))), None);
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(now_or_never(poll_fn(|cx| throttle.poll_flush(cx))), Some(()));
+        assert_eq!(now_or_never(poll_fn(|cx| throttle.poll_flush(cx))), None);
+        assert!(throttle.try_send());
+    }
+}
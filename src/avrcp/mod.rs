@@ -1,46 +1,195 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::sync::Arc;
+use std::future::poll_fn;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use bytes::{Bytes, BytesMut};
+use bitflags::bitflags;
+use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::u24;
 use instructor::{BigEndian, Buffer, BufferMut, Instruct};
 use parking_lot::Mutex;
-use tokio::spawn;
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{error, trace, warn};
+use tokio::time::Instant;
+use tracing::{debug, error, trace, warn};
 
 use crate::avc::{CommandCode, Frame, Opcode, PassThroughFrame, Subunit, SubunitType};
-use crate::avctp::{Avctp, Message, MessageType};
+use crate::avctp::{Avctp, Message, MessageType, ReassemblyLimits};
+use crate::avrcp::artwork::LocalArtwork;
+use crate::avrcp::browsing::UidTracker;
+use crate::avrcp::cover_art::CoverArt;
 use crate::avrcp::error::NotImplemented;
+use crate::avrcp::library::{LibraryBrowser, MediaLibraryProvider};
+use crate::avrcp::local_notifications::{LocalNotifications, NotificationThrottle};
+#[cfg(feature = "fault-injection")]
+use crate::avrcp::packets::{fragment_command_with_size, MAX_PAYLOAD_SIZE};
 use crate::avrcp::packets::{
-    fragment_command, CommandAssembler, CommandStatus, Pdu, BLUETOOTH_SIG_COMPANY_ID, COMPANY_ID_CAPABILITY, EVENTS_SUPPORTED_CAPABILITY, PANEL
+    browsing_message, fragment_command, parse_browsing_message, reject_unknown_pdu, unknown_pdu_id, validate_command, CommandAssembler, CommandStatus,
+    Pdu, BLUETOOTH_SIG_COMPANY_ID, COMPANY_ID_CAPABILITY, EVENTS_SUPPORTED_CAPABILITY, PANEL
 };
+use crate::avrcp::sdp::{AvrcpControllerServiceRecord, AvrcpTargetServiceRecord, RemoteFeatures};
 use crate::avrcp::session::{AvrcpCommand, CommandResponseSender, EventParser};
-use crate::l2cap::channel::Channel;
-use crate::l2cap::{ProtocolDelegate, ProtocolHandler, ProtocolHandlerProvider, AVCTP_PSM};
-use crate::utils::{select2, Either2, LoggableResult, IgnoreableResult};
+use crate::avrcp::subscriptions::Subscribers;
+use crate::avrcp::transactions::{Rearm, TransactionState, Transactions};
+use crate::avrcp::vendor::{PendingCommands, Progress};
+use crate::hci::consts::BdAddr;
+use crate::hci::devices::DeviceRegistry;
+use crate::hci::remote_info::RemoteInfoCache;
+use crate::l2cap::channel::{Channel, Error as L2capError};
+use crate::l2cap::{ChannelOpener, ProtocolDelegate, ProtocolHandler, ProtocolHandlerProvider, AVCTP_BROWSING_PSM, AVCTP_PSM, AVRCP_COVER_ART_PSM};
+use crate::profile::{Profile, ProfileRoles, ProfileSnapshot, RecordHandles};
+#[cfg(feature = "pts")]
+use crate::pts::PtsHooks;
+use crate::sdp::ServiceRecord;
+#[cfg(feature = "fault-injection")]
+use crate::utils::clock::sleep;
+use crate::utils::clock::{now, timeout, Timestamped};
+use crate::utils::interceptor::{Direction, Interceptors, Verdict};
+use crate::utils::redact::redacted;
+use crate::utils::{select3, spawn_supervised, supervise, Either3, LoggableResult, IgnoreableResult};
 use crate::{ensure, hci};
 
+pub mod browsing;
+pub mod buttons;
+mod artwork;
+mod charset;
+mod cover_art;
 mod error;
-mod packets;
+pub mod library;
+mod local_notifications;
+pub(crate) mod packets;
 pub mod sdp;
 mod session;
+mod settings;
+#[cfg(feature = "fault-injection")]
+mod shaping;
+mod subscriptions;
+mod transactions;
+mod vendor;
 
+pub use artwork::Artwork;
+pub use cover_art::CoverArtFormat;
 pub use error::{Error, ErrorCode};
-pub use packets::{EventId, MediaAttributeId};
-pub use session::{notifications, AvrcpSession, Event, Notification};
+pub use packets::{BatteryStatus, EventId, MediaAttributeId};
+pub use session::{notifications, AvrcpController, AvrcpSession, Event, Notification, PlayStatus, VolumeOrigin};
+pub use settings::{PlaybackOrder, PlayerSetting, PlayerSettingAttribute, RepeatMode};
+#[cfg(feature = "fault-injection")]
+pub use shaping::{Pattern, ResponseFault, ResponseShaping};
+pub use subscriptions::{EventFilter, EventSubscription};
 use crate::sdp::ids::service_classes::AV_REMOTE_CONTROL;
+use crate::sdp::SdpClient;
+
+/// Handles vendor unique commands (`ctype`, operands after the company id) and returns the response code and operands.
+pub type VendorCommandHandler = Arc<dyn Fn(CommandCode, Bytes) -> (CommandCode, Bytes) + Send + Sync>;
+
+bitflags! {
+    /// The AVRCP roles advertised via SDP ([AVRCP] Section 2.2.1).
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct Roles: u8 {
+        const CONTROLLER = 1 << 0;
+        const TARGET = 1 << 1;
+    }
+}
+
+/// The state of an AVRCP session for debugging.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AvrcpSessionSnapshot {
+    pub handle: u16,
+    /// The absolute volume (`0..=0x7F`) last reported to the peer.
+    pub volume: u8,
+    /// The transaction labels that are in use and what they are waiting for.
+    pub transactions: Vec<(u8, String)>,
+    /// The notifications the peer registered for and the transaction label to answer them with.
+    pub registered_notifications: Vec<(String, u8)>,
+    /// The transaction label and PDU of a response whose remaining fragments were not requested yet.
+    pub continuing_response: Option<(u8, String)>,
+    /// The character sets the peer announced it can display.
+    pub displayable_character_sets: Vec<u16>,
+    /// The battery status the peer reported while controlling us.
+    pub controller_battery_status: Option<BatteryStatus>,
+    /// How many vendor commands of the peer were answered automatically because their handler was too slow.
+    pub overdue_vendor_responses: u32,
+    /// How many fragmented AVCTP messages of the peer were dropped for exceeding the [ReassemblyLimits].
+    pub dropped_messages: u64
+}
 
 #[derive(Clone)]
 pub struct Avrcp {
     existing_connections: Arc<Mutex<BTreeSet<u16>>>,
-    session_handler: Arc<Mutex<dyn FnMut(AvrcpSession) + Send>>
+    /// Hands browsing channels to the session of their connection.
+    browsing_channels: Arc<Mutex<BTreeMap<u16, BrowsingChannelSender>>>,
+    /// The local artwork of the sessions for their cover art channels, only used with [Avrcp::with_local_cover_art].
+    artwork: Arc<Mutex<BTreeMap<u16, Arc<LocalArtwork>>>>,
+    sessions: Arc<Mutex<BTreeMap<u16, AvrcpSessionSnapshot>>>,
+    session_handler: Arc<Mutex<dyn FnMut(AvrcpSession) + Send>>,
+    vendor_handlers: Arc<Vec<(u24, VendorCommandHandler)>>,
+    media_library: Option<Arc<dyn MediaLibraryProvider>>,
+    roles: Roles,
+    max_response_size: usize,
+    reassembly_limits: ReassemblyLimits,
+    volume_hysteresis: f32,
+    discover_features: bool,
+    cover_art: bool,
+    local_cover_art: bool,
+    remote_info: Option<RemoteInfoCache>,
+    devices: Option<DeviceRegistry>,
+    interceptors: Interceptors<Message>,
+    #[cfg(feature = "fault-injection")]
+    response_shaping: ResponseShaping,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks
 }
 
 impl ProtocolHandlerProvider for Avrcp {
     fn protocol_handlers(&self) -> Vec<Arc<dyn ProtocolHandler>> {
-        vec![ProtocolDelegate::boxed(AVCTP_PSM, self.clone(), Self::handle_control)]
+        let mut handlers = vec![
+            ProtocolDelegate::boxed(AVCTP_PSM, self.clone(), Self::handle_control),
+            ProtocolDelegate::boxed(AVCTP_BROWSING_PSM, self.clone(), Self::handle_browsing),
+        ];
+        if self.local_cover_art {
+            handlers.push(ProtocolDelegate::boxed(AVRCP_COVER_ART_PSM, self.clone(), Self::handle_cover_art));
+        }
+        handlers
+    }
+}
+
+impl Profile for Avrcp {
+    fn name(&self) -> &'static str {
+        "AVRCP"
+    }
+
+    fn service_records(&self, handles: &mut RecordHandles) -> Vec<Box<dyn ServiceRecord>> {
+        let mut records: Vec<Box<dyn ServiceRecord>> = Vec::new();
+        if self.roles.contains(Roles::CONTROLLER) {
+            let record = AvrcpControllerServiceRecord::new(handles.allocate());
+            records.push(match self.cover_art {
+                true => Box::new(record.with_cover_art()),
+                false => Box::new(record)
+            });
+        }
+        if self.roles.contains(Roles::TARGET) {
+            let mut record = AvrcpTargetServiceRecord::new(handles.allocate());
+            if self.media_library.is_some() {
+                record = record.with_browsing();
+            }
+            if self.local_cover_art {
+                record = record.with_cover_art(AVRCP_COVER_ART_PSM);
+            }
+            records.push(Box::new(record));
+        }
+        records
+    }
+
+    fn roles(&self) -> ProfileRoles {
+        let mut roles = ProfileRoles::empty();
+        roles.set(ProfileRoles::AVRCP_CONTROLLER, self.roles.contains(Roles::CONTROLLER));
+        roles.set(ProfileRoles::AVRCP_TARGET, self.roles.contains(Roles::TARGET));
+        roles
+    }
+
+    fn debug_snapshot(&self) -> Option<ProfileSnapshot> {
+        Some(ProfileSnapshot::Avrcp(self.sessions.lock().values().cloned().collect()))
     }
 }
 
@@ -48,7 +197,163 @@ impl Avrcp {
     pub fn new<F: FnMut(AvrcpSession) + Send + 'static>(handler: F) -> Self {
         Self {
             existing_connections: Arc::new(Mutex::new(BTreeSet::new())),
-            session_handler: Arc::new(Mutex::new(handler))
+            browsing_channels: Arc::new(Mutex::new(BTreeMap::new())),
+            artwork: Arc::new(Mutex::new(BTreeMap::new())),
+            sessions: Arc::new(Mutex::new(BTreeMap::new())),
+            session_handler: Arc::new(Mutex::new(handler)),
+            vendor_handlers: Arc::new(Vec::new()),
+            media_library: None,
+            roles: Roles::all(),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            reassembly_limits: ReassemblyLimits::default(),
+            volume_hysteresis: 0.0,
+            discover_features: false,
+            cover_art: false,
+            local_cover_art: false,
+            remote_info: None,
+            devices: None,
+            interceptors: Interceptors::default(),
+            #[cfg(feature = "fault-injection")]
+            response_shaping: ResponseShaping::default(),
+            #[cfg(feature = "pts")]
+            pts: PtsHooks::default()
+        }
+    }
+
+    /// Queries the AVRCP service records of the peer before the session is handed out, which takes at
+    /// most a few seconds. The result is available through [AvrcpController::remote_features], and commands
+    /// for features the peer doesn't advertise fail with [Error::NotSupported].
+    pub fn with_feature_discovery(mut self) -> Self {
+        self.discover_features = true;
+        self
+    }
+
+    /// Connects to the cover art service of targets that provide one as soon as the service is discovered, so the
+    /// metadata includes image handles for [AvrcpController::get_cover_art]. The controller service record then
+    /// advertises the cover art features. Enables the feature discovery, which finds the service.
+    pub fn with_cover_art(mut self) -> Self {
+        self.cover_art = true;
+        self.discover_features = true;
+        self
+    }
+
+    /// Serves the artwork of [AvrcpController::set_local_cover_art] to controllers over the cover art service, which
+    /// the target service record then advertises.
+    pub fn with_local_cover_art(mut self) -> Self {
+        self.local_cover_art = true;
+        self
+    }
+
+    /// Remembers the discovered features, so they don't have to be queried again when the device reconnects.
+    pub fn with_remote_info_cache(mut self, cache: RemoteInfoCache) -> Self {
+        self.remote_info = Some(cache);
+        self
+    }
+
+    /// Reports the AVRCP sessions as connected profiles of the devices.
+    pub fn with_device_registry(mut self, registry: DeviceRegistry) -> Self {
+        self.devices = Some(registry);
+        self
+    }
+
+    /// Restricts the advertised service records, e.g. to [Roles::CONTROLLER] for a pure remote control.
+    pub fn with_roles(mut self, roles: Roles) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Limits how many bytes of a fragmented response are buffered.
+    /// Longer responses (e.g. huge metadata values) are cut off and the remaining fragments are aborted.
+    ///
+    /// The responses are not streamed to the caller in chunks, as the metadata values are only usable once complete
+    /// and the peer only sends the next fragment when it is asked for it ([AVRCP] Section 6.8.1). Nothing is buffered
+    /// beyond the limit, it only decides how much of an oversized response is kept.
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// Limits the reassembly of fragmented AVCTP messages on the control channel, messages exceeding them are dropped.
+    pub fn with_reassembly_limits(mut self, limits: ReassemblyLimits) -> Self {
+        self.reassembly_limits = limits;
+        self
+    }
+
+    /// How many steps past the rounding boundary a volume of [AvrcpController::notify_local_volume_change] has to be
+    /// before the reported step changes. Helps with encoders whose resolution does not match the 128 volume steps,
+    /// applications that count steps themselves can use [AvrcpController::notify_local_volume_steps] instead.
+    pub fn with_volume_hysteresis(mut self, steps: f32) -> Self {
+        assert!(steps >= 0.0, "Volume hysteresis must not be negative");
+        self.volume_hysteresis = steps;
+        self
+    }
+
+    /// Handles vendor dependent commands with a manufacturer specific `company_id` instead of rejecting them.
+    /// The company id is also reported in the company id capability ([AVRCP] Section 6.4.1).
+    /// The handler runs on a blocking thread, if it takes longer than 100ms for a control command an interim response
+    /// is sent in the meantime, other commands are answered without it after 200ms.
+    /// The handler has to answer with a response code, anything else is replaced with [CommandCode::NotImplemented].
+    /// Fails with [Error::ReservedCompanyId] for the Bluetooth SIG company id, whose commands are handled internally.
+    pub fn with_vendor_handler<F>(mut self, company_id: u32, handler: F) -> Result<Self, Error>
+    where
+        F: Fn(CommandCode, Bytes) -> (CommandCode, Bytes) + Send + Sync + 'static
+    {
+        let company_id = u24::new(company_id);
+        ensure!(company_id != BLUETOOTH_SIG_COMPANY_ID, Error::ReservedCompanyId);
+        let handlers = Arc::make_mut(&mut self.vendor_handlers);
+        handlers.retain(|(id, _)| *id != company_id);
+        handlers.push((company_id, Arc::new(handler)));
+        Ok(self)
+    }
+
+    /// Lets the peer browse `library` over the browsing channel, e.g. car head units that list the music of a phone.
+    /// The target service record then advertises the browsing feature and a player with the library is listed as the
+    /// only media player.
+    pub fn with_media_library<L: MediaLibraryProvider + 'static>(mut self, library: L) -> Self {
+        self.media_library = Some(Arc::new(library));
+        self
+    }
+
+    /// Shows every AVCTP message of the control and browsing channels to `interceptor` before it is processed or sent.
+    /// Vetoed messages are dropped. Fragmented AVRCP PDUs are seen one fragment at a time.
+    pub fn with_interceptor<F>(mut self, interceptor: F) -> Self
+    where
+        F: Fn(Direction, &Message) -> Verdict + Send + Sync + 'static
+    {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Delays or fragments the responses of the target as described by `shaping`, to reproduce the behavior
+    /// a specific controller has trouble with.
+    #[cfg(feature = "fault-injection")]
+    pub fn with_response_shaping(mut self, shaping: ResponseShaping) -> Self {
+        self.response_shaping = shaping;
+        self
+    }
+
+    /// Lets the qualification tester force rejects of commands, see [PtsHooks].
+    #[cfg(feature = "pts")]
+    pub fn with_pts_hooks(mut self, hooks: PtsHooks) -> Self {
+        self.pts = hooks;
+        self
+    }
+
+    /// Opens the AVCTP channel to a device that is already connected, e.g. after setting up an audio stream to it.
+    /// Does nothing if there already is a session with the device.
+    pub async fn connect(&self, opener: &ChannelOpener, handle: u16) -> Result<(), L2capError> {
+        if !self.existing_connections.lock().insert(handle) {
+            return Ok(());
+        }
+        match opener.open(handle, AVCTP_PSM as u64).await {
+            Ok(channel) => {
+                spawn_supervised("avrcp-connection", format!("handle 0x{:04x}", handle), self.clone().run_session(channel));
+                Ok(())
+            }
+            Err(err) => {
+                self.existing_connections.lock().remove(&handle);
+                Err(err)
+            }
         }
     }
 
@@ -59,114 +364,415 @@ impl Avrcp {
             if channel.accept_connection().log_err().is_err() {
                 return;
             }
-            let existing_connections = self.existing_connections.clone();
-            let session_handler = self.session_handler.clone();
-            spawn(async move {
+            let avrcp = self.clone();
+            spawn_supervised("avrcp-connection", format!("handle 0x{:04x}", handle), async move {
                 if let Err(err) = channel.configure().await {
                     warn!("Error configuring channel: {:?}", err);
+                    if let Some(devices) = &avrcp.devices {
+                        devices.set_profile_failed(channel.remote_addr(), AV_REMOTE_CONTROL, err.into());
+                    }
                     return;
                 }
-                let (cmd_tx, cmd_rx) = tokio::sync::mpsc::channel(16);
-                let (evt_tx, evt_rx) = tokio::sync::mpsc::channel(16);
-                let mut state = State {
-                    avctp: Avctp::new(channel, [AV_REMOTE_CONTROL]),
-                    command_assembler: Default::default(),
-                    response_assembler: Default::default(),
-                    volume: MAX_VOLUME,
-                    commands: cmd_rx,
-                    events: evt_tx,
-                    outstanding_transactions: Default::default(),
-                    registered_notifications: Default::default()
-                };
-                session_handler.lock()(AvrcpSession {
-                    commands: cmd_tx,
-                    events: evt_rx
-                });
-                state.run().await.unwrap_or_else(|err| {
-                    warn!("Error running avctp: {:?}", err);
-                });
-                trace!("AVCTP connection closed");
-                existing_connections.lock().remove(&handle);
+                avrcp.run_session(channel).await;
             });
         } else {
             channel.reject_connection().ignore();
         }
     }
+
+    /// Browsing channels are only accepted once the control channel of the connection is established ([AVRCP] Section 6.1.2).
+    fn handle_browsing(&self, mut channel: Channel) {
+        let handle = channel.connection_handle();
+        let Some(session) = self.browsing_channels.lock().get(&handle).cloned() else {
+            warn!("Rejecting browsing channel without control channel");
+            channel.reject_connection().ignore();
+            return;
+        };
+        if channel.accept_connection().log_err().is_err() {
+            return;
+        }
+        spawn_supervised("avrcp-browsing", format!("handle 0x{:04x}", handle), async move {
+            if let Err(err) = channel.configure().await {
+                warn!("Error configuring browsing channel: {:?}", err);
+                return;
+            }
+            if session.send(Ok(channel)).await.is_err() {
+                debug!("Session ended before the browsing channel was established");
+            }
+        });
+    }
+
+    /// Cover art channels serve the artwork of the session of their connection.
+    fn handle_cover_art(&self, mut channel: Channel) {
+        let handle = channel.connection_handle();
+        let Some(artwork) = self.artwork.lock().get(&handle).cloned() else {
+            warn!("Rejecting cover art channel without control channel");
+            channel.reject_connection().ignore();
+            return;
+        };
+        if channel.accept_connection().log_err().is_err() {
+            return;
+        }
+        spawn_supervised("avrcp-cover-art", format!("handle 0x{:04x}", handle), async move {
+            if let Err(err) = channel.configure().await {
+                warn!("Error configuring cover art channel: {:?}", err);
+                return;
+            }
+            artwork::serve(channel, artwork).await;
+        });
+    }
+
+    /// The features of the peer from the cache or its service records. The session only starts afterwards, so
+    /// commands for features the peer doesn't support fail right away. Without an answer all features are assumed.
+    async fn discover_remote_features(&self, opener: ChannelOpener, handle: u16, addr: BdAddr) -> Option<RemoteFeatures> {
+        let cache = self.remote_info.as_ref();
+        if let Some(features) = cache.and_then(|cache| RemoteFeatures::from_cache(&cache.get(addr)?)) {
+            debug!("Using cached remote AVRCP features: {:?}", features);
+            return Some(features);
+        }
+        let features = query_remote_features(opener, handle).await?;
+        if let Some(cache) = cache {
+            cache.update(addr, |info| features.store(info));
+        }
+        Some(features)
+    }
+
+    async fn run_session(self, channel: Channel) {
+        let handle = channel.connection_handle();
+        let addr = channel.remote_addr();
+        let remote_features = match self.discover_features {
+            true => self.discover_remote_features(channel.channel_opener(), handle, addr).await,
+            false => None
+        };
+        let (cmd_tx, cmd_rx) = tokio::sync::mpsc::channel(16);
+        let (evt_tx, evt_rx) = tokio::sync::mpsc::channel(16);
+        let (browsing_tx, browsing_rx) = tokio::sync::mpsc::channel(1);
+        self.browsing_channels.lock().insert(handle, browsing_tx.clone());
+        let uids = Arc::new(UidTracker::default());
+        let cover_art = Arc::new(CoverArt::new(channel.channel_opener(), handle, None));
+        let artwork = Arc::new(LocalArtwork::default());
+        let subscribers = Subscribers::default();
+        if self.local_cover_art {
+            self.artwork.lock().insert(handle, artwork.clone());
+        }
+        let mut state = State {
+            handle,
+            uids: uids.clone(),
+            snapshots: self.sessions.clone(),
+            opener: channel.channel_opener(),
+            avctp: Avctp::new(channel, [AV_REMOTE_CONTROL])
+                .with_reassembly_limits(self.reassembly_limits)
+                .with_interceptors(self.interceptors.clone()),
+            interceptors: self.interceptors.clone(),
+            browsing: None,
+            browsing_psm: browsing_psm(None),
+            browsing_opener: browsing_tx,
+            browsing_channels: browsing_rx,
+            browsing_opening: false,
+            queued_browsing: Vec::new(),
+            browsing_transactions: Default::default(),
+            command_assembler: Default::default(),
+            response_assembler: CommandAssembler::new(self.max_response_size),
+            volume: MAX_VOLUME,
+            local_volume: None,
+            volume_hysteresis: self.volume_hysteresis,
+            reported_volume: MAX_VOLUME,
+            volume_throttle: NotificationThrottle::new(VOLUME_NOTIFICATION_INTERVAL),
+            vendor_handlers: self.vendor_handlers.clone(),
+            vendor_commands: PendingCommands::default(),
+            library: self.media_library.clone().map(LibraryBrowser::new),
+            artwork: artwork.clone(),
+            #[cfg(feature = "fault-injection")]
+            response_shaping: self.response_shaping.clone(),
+            #[cfg(feature = "pts")]
+            pts: self.pts.clone(),
+            commands: cmd_rx,
+            events: evt_tx,
+            subscribers: subscribers.clone(),
+            outstanding_transactions: Default::default(),
+            continuing_response: None,
+            notifications: Default::default(),
+            displayable_character_sets: Vec::new(),
+            controller_battery_status: None,
+            media_attributes: BTreeMap::new(),
+            play_status: Timestamped::now(PlayStatus::default()),
+            remote_features: Default::default(),
+            cover_art: cover_art.clone(),
+            connect_cover_art: self.cover_art,
+            published_snapshot: None
+        };
+        if let Some(features) = remote_features {
+            state.apply_remote_features(features);
+        }
+        self.session_handler.lock()(AvrcpSession {
+            controller: AvrcpController {
+                commands: cmd_tx,
+                addr,
+                uids: uids.clone(),
+                cover_art: cover_art.clone(),
+                artwork,
+                subscribers,
+                remote_features: state.remote_features.clone()
+            },
+            events: evt_rx,
+            interpolator: None
+        });
+        if let Some(devices) = &self.devices {
+            devices.set_profile_connected(addr, AV_REMOTE_CONTROL, true);
+        }
+        // A panic only ends this session, the cleanup below still runs
+        match supervise("avrcp-session", format!("handle 0x{:04x}", handle), state.run()).await {
+            Some(Err(err)) => warn!("Error running avctp: {:?}", err),
+            Some(Ok(())) | None => {}
+        }
+        if let Some(devices) = &self.devices {
+            devices.set_profile_connected(addr, AV_REMOTE_CONTROL, false);
+        }
+        trace!("AVCTP connection closed");
+        self.sessions.lock().remove(&handle);
+        state.subscribers.close();
+        self.browsing_channels.lock().remove(&handle);
+        self.artwork.lock().remove(&handle);
+        self.existing_connections.lock().remove(&handle);
+    }
 }
 
-#[derive(Default, Debug)]
-enum TransactionState {
-    #[default]
-    Empty,
-    PendingPassThrough(CommandResponseSender),
-    PendingVendorDependent(CommandCode, CommandResponseSender),
-    PendingNotificationRegistration(EventParser, CommandResponseSender),
-    WaitingForChange(EventParser)
+/// The PSM of the browsing channel of a peer with `features`.
+fn browsing_psm(features: Option<&RemoteFeatures>) -> Option<u16> {
+    match features {
+        Some(features) if features.supports_browsing() => features.browsing_psm,
+        Some(_) => None,
+        // Worth a try, the peer rejects the channel if it doesn't support browsing
+        None => Some(AVCTP_BROWSING_PSM)
+    }
 }
 
-impl TransactionState {
-    pub fn is_free(&self) -> bool {
-        matches!(self, TransactionState::Empty)
+async fn query_remote_features(opener: ChannelOpener, handle: u16) -> Option<RemoteFeatures> {
+    let query = async {
+        let mut client = SdpClient::connect(&opener, handle).await?;
+        client
+            .service_search_attribute(&[AV_REMOTE_CONTROL], &[0x0000..=0xFFFF])
+            .await
+    };
+    match timeout(FEATURE_DISCOVERY_TIMEOUT, query).await {
+        Ok(Ok(records)) => {
+            let features = RemoteFeatures::from_records(&records);
+            debug!("Remote AVRCP features: {:?}", features);
+            Some(features)
+        }
+        Ok(Err(err)) => {
+            warn!("Failed to query remote AVRCP features: {:?}", err);
+            None
+        }
+        Err(_) => {
+            warn!("Timed out while querying remote AVRCP features");
+            None
+        }
     }
+}
 
-    pub fn take_sender(&mut self) -> CommandResponseSender {
-        let prev = std::mem::take(self);
-        match prev {
-            TransactionState::PendingPassThrough(sender) => sender,
-            TransactionState::PendingVendorDependent(_, sender) => sender,
-            TransactionState::PendingNotificationRegistration(parser, sender) => {
-                *self = TransactionState::WaitingForChange(parser);
-                sender
-            }
-            _ => unreachable!()
+fn parse_event(parser: EventParser, parameters: &mut Bytes) -> Option<Event> {
+    parameters
+        .read_be::<EventId>()
+        .and_then(|_| parser(parameters))
+        .map_err(|err| error!("Error parsing event: {:?}", err))
+        .ok()
+}
+
+/// Passes browsing channels opened by the peer or by the session itself to the session.
+type BrowsingChannelSender = Sender<Result<Channel, L2capError>>;
+
+/// Something that happened on the control or the browsing channel of a session.
+enum Incoming {
+    Control(Message),
+    Browsing(Message),
+    BrowsingOpened(Channel),
+    BrowsingFailed(L2capError),
+    BrowsingClosed
+}
+
+/// Waits for the next message of either channel or a change of the browsing channel.
+async fn next_message(
+    control: &mut Avctp, browsing: Option<&mut Avctp>, browsing_channels: &mut Receiver<Result<Channel, L2capError>>
+) -> Option<Incoming> {
+    let browsing_message = async {
+        match browsing {
+            Some(browsing) => browsing.read().await,
+            None => std::future::pending().await
         }
+    };
+    match select3(control.read(), browsing_message, browsing_channels.recv()).await {
+        Either3::A(message) => message.map(Incoming::Control),
+        Either3::B(Some(message)) => Some(Incoming::Browsing(message)),
+        Either3::B(None) => Some(Incoming::BrowsingClosed),
+        Either3::C(Some(Ok(channel))) => Some(Incoming::BrowsingOpened(channel)),
+        Either3::C(Some(Err(err))) => Some(Incoming::BrowsingFailed(err)),
+        Either3::C(None) => unreachable!("The session holds a sender itself")
     }
 }
 
+async fn open_browsing_channel(opener: ChannelOpener, handle: u16, psm: u16, session: BrowsingChannelSender) {
+    let result = opener.open(handle, psm as u64).await;
+    let _ = session.send(result).await;
+}
+
 struct State {
+    handle: u16,
+    uids: Arc<UidTracker>,
+    snapshots: Arc<Mutex<BTreeMap<u16, AvrcpSessionSnapshot>>>,
+    opener: ChannelOpener,
     avctp: Avctp,
+    interceptors: Interceptors<Message>,
+    browsing: Option<Avctp>,
+    /// `None` if the peer is known to not support browsing.
+    browsing_psm: Option<u16>,
+    browsing_opener: BrowsingChannelSender,
+    browsing_channels: Receiver<Result<Channel, L2capError>>,
+    browsing_opening: bool,
+    /// Commands waiting for the browsing channel to open.
+    queued_browsing: Vec<(Pdu, Bytes, CommandResponseSender)>,
+    /// Browsing uses its own transaction labels.
+    browsing_transactions: [Option<(Pdu, CommandResponseSender)>; 16],
     command_assembler: CommandAssembler,
     response_assembler: CommandAssembler,
 
     volume: u8,
+    /// The last volume set by the application and when, the peer echoing it back is reported as a local change.
+    local_volume: Option<(u8, Instant)>,
+    volume_hysteresis: f32,
+    /// The volume of the last interim or changed response to the volume notification.
+    reported_volume: u8,
+    /// Holds back volume notifications that are less than [VOLUME_NOTIFICATION_INTERVAL] apart.
+    volume_throttle: NotificationThrottle,
+    vendor_handlers: Arc<Vec<(u24, VendorCommandHandler)>>,
+    /// Vendor commands of the peer whose handler is still running.
+    vendor_commands: PendingCommands,
+    /// Answers the browsing commands of the peer, `None` without a media library.
+    library: Option<LibraryBrowser>,
+    /// Provides the handle of the default cover art attribute.
+    artwork: Arc<LocalArtwork>,
+    #[cfg(feature = "fault-injection")]
+    response_shaping: ResponseShaping,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks,
 
     commands: Receiver<AvrcpCommand>,
-    events: Sender<Event>,
-    outstanding_transactions: [TransactionState; 16],
-    registered_notifications: BTreeMap<EventId, u8>
+    events: Sender<Timestamped<Event>>,
+    subscribers: Subscribers,
+    outstanding_transactions: Transactions,
+    continuing_response: Option<(u8, Pdu)>,
+    /// The notifications the peer registered with us.
+    notifications: LocalNotifications,
+    displayable_character_sets: Vec<u16>,
+    controller_battery_status: Option<BatteryStatus>,
+    /// The metadata of the local track, reported to the controller in its preferred character set.
+    media_attributes: BTreeMap<MediaAttributeId, String>,
+    play_status: Timestamped<PlayStatus>,
+    /// Shared with the [AvrcpSession], set once the features of the peer are known.
+    remote_features: Arc<OnceLock<RemoteFeatures>>,
+    cover_art: Arc<CoverArt>,
+    /// Connects the cover art channel as soon as the peer is known to support it.
+    connect_cover_art: bool,
+    /// The snapshot that was published last, a new one is only published if it differs.
+    published_snapshot: Option<AvrcpSessionSnapshot>
 }
 
 impl State {
+    fn apply_remote_features(&mut self, features: RemoteFeatures) {
+        self.browsing_psm = browsing_psm(Some(&features));
+        self.cover_art.set_psm(features.cover_art_psm.filter(|_| features.supports_cover_art()));
+        if self.connect_cover_art && self.cover_art.is_available() {
+            let cover_art = self.cover_art.clone();
+            spawn_supervised("avrcp-cover-art", format!("handle 0x{:04x}", self.handle), async move {
+                if let Err(err) = cover_art.connect().await {
+                    debug!("Cover art is not available: {:?}", err);
+                }
+            });
+        }
+        let _ = self.remote_features.set(features);
+    }
+
     async fn run(&mut self) -> Result<(), hci::Error> {
         loop {
-            match select2(self.avctp.read(), self.commands.recv()).await {
-                Either2::A(Some(mut packet)) => {
+            // Published before waiting, so a stuck session still shows what it is waiting for
+            self.publish_snapshot();
+            let message = next_message(&mut self.avctp, self.browsing.as_mut(), &mut self.browsing_channels);
+            let volume_flush = poll_fn(|cx| self.volume_throttle.poll_flush(cx));
+            let timers = select3(self.outstanding_transactions.cancelled(), volume_flush, self.vendor_commands.next());
+            match select3(message, self.commands.recv(), timers).await {
+                Either3::A(Some(Incoming::Browsing(message))) => self.process_browsing_message(message).await,
+                Either3::A(Some(Incoming::BrowsingOpened(channel))) => {
+                    debug!("Browsing channel established");
+                    self.browsing = Some(Avctp::browsing(channel, [AV_REMOTE_CONTROL]).with_interceptors(self.interceptors.clone()));
+                    self.browsing_opening = false;
+                    for (pdu, parameters, sender) in std::mem::take(&mut self.queued_browsing) {
+                        self.browsing_command(pdu, parameters, sender).await;
+                    }
+                }
+                Either3::A(Some(Incoming::BrowsingFailed(err))) => {
+                    warn!("Failed to open browsing channel: {:?}", err);
+                    self.browsing_opening = false;
+                    for (_, _, sender) in self.queued_browsing.drain(..) {
+                        let _ = sender.send(Err(Error::BrowsingUnavailable));
+                    }
+                }
+                Either3::A(Some(Incoming::BrowsingClosed)) => {
+                    debug!("Browsing channel closed");
+                    self.browsing = None;
+                    for (_, sender) in self.browsing_transactions.iter_mut().filter_map(Option::take) {
+                        let _ = sender.send(Err(Error::BrowsingUnavailable));
+                    }
+                }
+                Either3::A(Some(Incoming::Control(packet))) if packet.message_type == MessageType::ResponseInvalidProfile => {
+                    self.invalid_profile(packet.transaction_label);
+                }
+                Either3::A(Some(Incoming::Control(mut packet))) => {
                     let transaction_label = packet.transaction_label;
-                    if let Ok(frame) = packet.data.read_be::<Frame>() {
+                    let Ok(frame) = packet.data.read_be::<Frame>() else { continue };
+                    // Commands and responses use independent transaction labels, so the direction decides
+                    // whether the label refers to one of our transactions or to one of the peer.
+                    if frame.ctype.is_response() != (packet.message_type == MessageType::Response) {
+                        warn!("AV/C frame {:?} does not match the AVCTP message type {:?}", frame, packet.message_type);
+                        continue;
+                    }
+                    if packet.message_type == MessageType::Response {
+                        if let Err(NotImplemented) = self.process_response(frame, packet).await {
+                            warn!("Failed to handle response: {:?}", frame);
+                        }
+                    } else {
                         let payload = packet.data.clone();
-                        if let Err(NotImplemented) = self.process_message(frame, packet).await {
-                            if !frame.ctype.is_response() {
-                                self.send_avc(
-                                    transaction_label,
-                                    Frame {
-                                        ctype: CommandCode::NotImplemented,
-                                        ..frame
-                                    },
-                                    payload
-                                )
-                                .await;
-                            } else {
-                                warn!("Failed to handle response: {:?}", frame);
-                            }
+                        if let Err(NotImplemented) = self.process_command_message(frame, packet).await {
+                            self.send_avc(
+                                transaction_label,
+                                Frame {
+                                    ctype: CommandCode::NotImplemented,
+                                    ..frame
+                                },
+                                payload
+                            )
+                            .await;
                         }
                     }
                 }
-                Either2::B(Some(cmd)) => {
-                    let Some(transaction) = self
-                        .outstanding_transactions
-                        .iter()
-                        .position(|x| x.is_free())
-                    else {
+                Either3::B(Some(AvrcpCommand::Browsing(pdu, parameters, sender))) => {
+                    self.browsing_command(pdu, parameters, sender).await;
+                }
+                Either3::B(Some(AvrcpCommand::UpdatedMediaAttributes(attributes))) => {
+                    if attributes != self.media_attributes {
+                        debug!("Local track changed: {}", redacted(&attributes));
+                        self.media_attributes = attributes;
+                        self.notify_changed(EventId::TrackChanged, self.track_identifier()).await;
+                    }
+                }
+                Either3::B(Some(AvrcpCommand::UpdatedPlayStatus(status))) => {
+                    let changed = status.value.status != self.play_status.value.status;
+                    self.play_status = status;
+                    if changed {
+                        self.notify_changed(EventId::PlaybackStatusChanged, status.value.status as u8).await;
+                    }
+                }
+                Either3::B(Some(cmd)) => {
+                    let Some(transaction) = self.outstanding_transactions.allocate() else {
                         if let Some(sender) = cmd.into_response_sender() {
                             let _ = sender.send(Err(Error::NoTransactionIdAvailable));
                         }
@@ -175,7 +781,7 @@ impl State {
                     match cmd {
                         AvrcpCommand::PassThrough(op, state, sender) => {
                             self.send_avc(
-                                transaction as u8,
+                                transaction,
                                 Frame {
                                     ctype: CommandCode::Control,
                                     subunit: PANEL,
@@ -184,145 +790,400 @@ impl State {
                                 PassThroughFrame { op, state, data_len: 0 }
                             )
                             .await
-                            .then(|| self.outstanding_transactions[transaction] = TransactionState::PendingPassThrough(sender));
+                            .then(|| self.outstanding_transactions.start(transaction, TransactionState::PendingPassThrough(sender)));
                         }
                         AvrcpCommand::VendorSpecific(cmd, pdu, params, sender) => {
                             // These should be registered using register notification
                             debug_assert!(cmd != CommandCode::Notify);
-                            self.send_avrcp(transaction as u8, cmd, pdu, params)
+                            if pdu == Pdu::SetAbsoluteVolume {
+                                // The target reports the new volume through its volume notification as well
+                                self.local_volume = params.first().map(|volume| (volume & MAX_VOLUME, now()));
+                            }
+                            self.send_avrcp(transaction, cmd, pdu, params)
                                 .await
-                                .then(|| self.outstanding_transactions[transaction] = TransactionState::PendingVendorDependent(cmd, sender));
+                                .then(|| self.outstanding_transactions.start(transaction, TransactionState::PendingVendorDependent(cmd, sender)));
                         }
-                        AvrcpCommand::RegisterNotification(event, interval, parser, sender) => {
-                            self.send_avrcp(transaction as u8, CommandCode::Notify, Pdu::RegisterNotification, (event, interval))
+                        AvrcpCommand::RegisterNotification(event, interval, parser, rearm, sender) => {
+                            let rearm = rearm.then_some(Rearm { event, interval });
+                            self.send_avrcp(transaction, CommandCode::Notify, Pdu::RegisterNotification, (event, interval))
                                 .await
                                 .then(|| {
-                                    self.outstanding_transactions[transaction] = TransactionState::PendingNotificationRegistration(parser, sender)
+                                    self.outstanding_transactions
+                                        .start(transaction, TransactionState::PendingNotificationRegistration(parser, rearm, sender))
                                 });
                         }
+                        AvrcpCommand::Browsing(..)
+                        | AvrcpCommand::UpdatedMediaAttributes(_)
+                        | AvrcpCommand::UpdatedPlayStatus(_) => unreachable!(),
                         AvrcpCommand::UpdatedVolume(volume) => {
-                            let new_volume = (volume.min(1.0).max(0.0) * MAX_VOLUME as f32).round() as u8;
+                            let new_volume = self.volume_step(volume);
+                            self.local_volume = Some((new_volume, now()));
                             if new_volume != self.volume {
                                 self.volume = new_volume;
-                                if let Some(transaction) = self
-                                    .registered_notifications
-                                    .remove(&EventId::VolumeChanged)
-                                {
-                                    self.send_avrcp(
-                                        transaction,
-                                        CommandCode::Changed,
-                                        Pdu::RegisterNotification,
-                                        (EventId::VolumeChanged, self.volume)
-                                    )
-                                    .await;
-                                }
+                                self.volume_changed().await;
+                            }
+                        }
+                        AvrcpCommand::UpdatedVolumeSteps(steps) => {
+                            let new_volume = steps.min(MAX_VOLUME);
+                            self.local_volume = Some((new_volume, now()));
+                            if new_volume != self.volume {
+                                self.volume = new_volume;
+                                self.volume_changed().await;
                             }
                         }
                     }
                 }
+                Either3::C(Either3::A(transaction)) => self.cancel_transaction(transaction).await,
+                Either3::C(Either3::B(())) => self.volume_changed().await,
+                Either3::C(Either3::C(progress)) => self.vendor_command_progress(progress).await,
                 _ => break
             }
         }
         Ok(())
     }
 
-    async fn process_message(&mut self, frame: Frame, mut message: Message) -> Result<(), NotImplemented> {
+    async fn vendor_command_progress(&mut self, progress: Progress) {
+        match progress {
+            Progress::Finished {
+                transaction,
+                frame,
+                company_id,
+                response,
+                elapsed
+            } => {
+                let (mut ctype, mut operands) = response.unwrap_or_else(|| {
+                    error!("Vendor handler for company id {:#06x} panicked", company_id);
+                    (CommandCode::Rejected, Bytes::new())
+                });
+                if !ctype.is_response() {
+                    warn!("Vendor handler for company id {:#06x} answered with {:?}, which is no response", company_id, ctype);
+                    ctype = CommandCode::NotImplemented;
+                    operands = Bytes::new();
+                }
+                trace!("Vendor command {:?} took {:?}", frame.ctype, elapsed);
+                self.send_avc(transaction, Frame { ctype, ..frame }, (company_id, operands))
+                    .await;
+            }
+            Progress::Overdue {
+                transaction,
+                frame,
+                company_id,
+                ctype
+            } => {
+                warn!("Vendor handler for company id {:#06x} is too slow, answering {:?} with {:?}", company_id, frame.ctype, ctype);
+                self.send_avc(transaction, Frame { ctype, ..frame }, company_id)
+                    .await;
+            }
+        }
+    }
+
+    fn publish_snapshot(&mut self) {
+        let snapshot = AvrcpSessionSnapshot {
+            handle: self.handle,
+            volume: self.volume,
+            transactions: self.outstanding_transactions.describe(),
+            registered_notifications: self
+                .notifications
+                .iter()
+                .map(|(event, label)| (format!("{:?}", event), label))
+                .collect(),
+            continuing_response: self
+                .continuing_response
+                .as_ref()
+                .map(|(label, pdu)| (*label, format!("{:?}", pdu))),
+            displayable_character_sets: self.displayable_character_sets.clone(),
+            controller_battery_status: self.controller_battery_status,
+            overdue_vendor_responses: self.vendor_commands.overdue(),
+            dropped_messages: self.avctp.dropped_messages()
+        };
+        if self.published_snapshot.as_ref() != Some(&snapshot) {
+            self.snapshots.lock().insert(self.handle, snapshot.clone());
+            self.published_snapshot = Some(snapshot);
+        }
+    }
+
+    // ([AVCTP] Section 6.1.1)
+    fn invalid_profile(&mut self, transaction: u8) {
+        let state = &mut self.outstanding_transactions[transaction as usize];
+        if let TransactionState::Rearming(_, rearm, _) = state {
+            warn!("Failed to register {:?} again: invalid profile", rearm.event);
+            *state = TransactionState::Empty;
+            return;
+        }
+        if matches!(state, TransactionState::Abandoned) {
+            *state = TransactionState::Empty;
+            return;
+        }
+        if !state.is_pending() {
+            warn!("Received invalid profile response for transaction {} without pending command", transaction);
+            return;
+        }
+        if self.continuing_response.is_some_and(|(label, _)| label == transaction) {
+            self.continuing_response = None;
+            self.response_assembler.reset();
+        }
+        let _ = state.take_sender().send(Err(Error::InvalidProfile));
+        *state = TransactionState::Empty;
+    }
+
+    async fn cancel_transaction(&mut self, transaction: u8) {
+        let state = std::mem::replace(&mut self.outstanding_transactions[transaction as usize], TransactionState::Abandoned);
+        trace!("Transaction {} was cancelled locally: {:?}", transaction, state);
+        if let Some((label, pdu)) = self.continuing_response {
+            if label == transaction && matches!(state, TransactionState::PendingVendorDependent(_, _)) {
+                self.continuing_response = None;
+                self.response_assembler.reset();
+                self.abort_continuing_response(transaction, pdu).await;
+            }
+        }
+    }
+
+    /// Asks the peer to stop sending the fragments of `pdu` and keeps `transaction` reserved until it acknowledged that.
+    // ([AVRCP] Section 6.8.2)
+    async fn abort_continuing_response(&mut self, transaction: u8, pdu: Pdu) {
+        if self
+            .send_avrcp(transaction, CommandCode::Control, Pdu::AbortContinuingResponse, pdu)
+            .await
+        {
+            self.reserve_until_aborted(transaction);
+        }
+    }
+
+    /// Registered notifications keep their label, the abort acknowledgement is recognized by its PDU either way.
+    fn reserve_until_aborted(&mut self, transaction: u8) {
+        let state = &mut self.outstanding_transactions[transaction as usize];
+        if matches!(state, TransactionState::Empty | TransactionState::Abandoned) {
+            *state = TransactionState::Aborting;
+        }
+    }
+
+    fn check_vendor_dependent(frame: Frame, message: &mut Message) -> Result<(), NotImplemented> {
+        let company_id = Self::read_company_id(frame, message)?;
+        ensure!(
+            company_id == BLUETOOTH_SIG_COMPANY_ID,
+            NotImplemented,
+            "Unsupported company id: {:#06x}",
+            company_id
+        );
+        Ok(())
+    }
+
+    fn read_company_id(frame: Frame, message: &mut Message) -> Result<u24, NotImplemented> {
+        ensure!(
+            frame.subunit == PANEL,
+            NotImplemented,
+            "Unsupported subunit: {:?}",
+            frame.subunit
+        );
+        Ok(message.data.read_be::<u24>()?)
+    }
+
+    /// Handles a response to one of our own commands. The transaction label refers to `outstanding_transactions`.
+    async fn process_response(&mut self, frame: Frame, mut message: Message) -> Result<(), NotImplemented> {
+        self.outstanding_transactions.touch(message.transaction_label);
         match frame.opcode {
             Opcode::VendorDependent => {
-                ensure!(
-                    frame.subunit == PANEL,
-                    NotImplemented,
-                    "Unsupported subunit: {:?}",
-                    frame.subunit
-                );
-                let company_id: u24 = message.data.read_be::<u24>()?;
-                ensure!(
-                    company_id == BLUETOOTH_SIG_COMPANY_ID,
-                    NotImplemented,
-                    "Unsupported company id: {:#06x}",
-                    company_id
-                );
-                if frame.ctype.is_response() {
-                    match self.response_assembler.process_msg(message.data)? {
-                        CommandStatus::Complete(pdu, mut parameters) => {
-                            let transaction = &mut self.outstanding_transactions[message.transaction_label as usize];
-                            match transaction {
-                                TransactionState::PendingVendorDependent(CommandCode::Control, _) => {
-                                    let reply = match frame.ctype {
-                                        CommandCode::NotImplemented => Err(Error::NotImplemented),
-                                        CommandCode::Accepted => Ok(parameters),
-                                        CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
-                                        CommandCode::Interim => return Ok(()),
-                                        _ => Err(Error::InvalidReturnData)
-                                    };
-                                    let _ = transaction.take_sender().send(reply);
-                                }
-                                TransactionState::PendingVendorDependent(CommandCode::Status, _) => {
-                                    let reply = match frame.ctype {
-                                        CommandCode::NotImplemented => Err(Error::NotImplemented),
-                                        CommandCode::Implemented => Ok(parameters),
-                                        CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
-                                        CommandCode::InTransition => Err(Error::Busy),
-                                        _ => Err(Error::InvalidReturnData)
-                                    };
-                                    let _ = transaction.take_sender().send(reply);
-                                }
-                                TransactionState::PendingVendorDependent(code, _) => {
-                                    error!("Received response for invalid command code: {:?}", code);
-                                    *transaction = TransactionState::Empty;
-                                }
-                                TransactionState::PendingNotificationRegistration(_, _) => {
-                                    let reply = match frame.ctype {
-                                        CommandCode::NotImplemented => Err(Error::NotImplemented),
-                                        CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
-                                        CommandCode::Interim => Ok(parameters),
-                                        CommandCode::Changed => {
-                                            warn!("Received changed response without interims response");
-                                            Err(Error::InvalidReturnData)
+                Self::check_vendor_dependent(frame, &mut message)?;
+                let status = self.response_assembler.process_msg(message.data)?;
+                let mut aborted = false;
+                if let CommandStatus::Truncated(pdu, _, true) = &status {
+                    // ([AVRCP] Section 6.8.2)
+                    aborted = self
+                        .send_avrcp(message.transaction_label, CommandCode::Control, Pdu::AbortContinuingResponse, *pdu)
+                        .await;
+                }
+                match status {
+                    CommandStatus::Complete(pdu, mut parameters) | CommandStatus::Truncated(pdu, mut parameters, _) => {
+                        self.continuing_response = None;
+                        let transaction = &mut self.outstanding_transactions[message.transaction_label as usize];
+                        match transaction {
+                            TransactionState::PendingVendorDependent(CommandCode::Control, _) => {
+                                let reply = match frame.ctype {
+                                    CommandCode::NotImplemented => Err(Error::NotImplemented),
+                                    CommandCode::Accepted => Ok(parameters),
+                                    CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
+                                    CommandCode::Interim => return Ok(()),
+                                    _ => Err(Error::InvalidReturnData)
+                                };
+                                let _ = transaction.take_sender().send(reply);
+                            }
+                            TransactionState::PendingVendorDependent(CommandCode::Status, _) => {
+                                let reply = match frame.ctype {
+                                    CommandCode::NotImplemented => Err(Error::NotImplemented),
+                                    CommandCode::Implemented => Ok(parameters),
+                                    CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
+                                    CommandCode::InTransition => Err(Error::Busy),
+                                    _ => Err(Error::InvalidReturnData)
+                                };
+                                let _ = transaction.take_sender().send(reply);
+                            }
+                            TransactionState::PendingVendorDependent(code, _) => {
+                                error!("Received response for invalid command code: {:?}", code);
+                                *transaction = TransactionState::Empty;
+                            }
+                            TransactionState::PendingNotificationRegistration(..) => {
+                                let reply = match frame.ctype {
+                                    CommandCode::NotImplemented => Err(Error::NotImplemented),
+                                    CommandCode::Rejected => Err(Error::Rejected(parameters.read_be().unwrap_or(ErrorCode::ParameterContentError))),
+                                    CommandCode::Interim => Ok(parameters),
+                                    CommandCode::Changed => {
+                                        warn!("Received changed response without interims response");
+                                        Err(Error::InvalidReturnData)
+                                    }
+                                    _ => Err(Error::InvalidReturnData)
+                                };
+                                let _ = transaction.take_sender().send(reply);
+                            }
+                            TransactionState::WaitingForChange(parser, rearm) => {
+                                let (parser, rearm) = (*parser, *rearm);
+                                *transaction = TransactionState::Empty;
+                                if frame.ctype == CommandCode::Changed {
+                                    if let Some(event) = parse_event(parser, &mut parameters) {
+                                        self.trigger_event(event.clone());
+                                        if let Some(rearm) = rearm {
+                                            // ([AVRCP] Section 6.7.1), a changed notification ends the registration.
+                                            // The label is reused, so nothing else can take it in the meantime.
+                                            let label = message.transaction_label;
+                                            let parameters = (rearm.event, rearm.interval);
+                                            self.send_avrcp(label, CommandCode::Notify, Pdu::RegisterNotification, parameters)
+                                                .await
+                                                .then(|| {
+                                                    self.outstanding_transactions
+                                                        .start(label, TransactionState::Rearming(parser, rearm, event))
+                                                });
                                         }
-                                        _ => Err(Error::InvalidReturnData)
-                                    };
-                                    let _ = transaction.take_sender().send(reply);
+                                    }
                                 }
-                                TransactionState::WaitingForChange(parser) => {
-                                    let parser = *parser;
-                                    *transaction = TransactionState::Empty;
-                                    if frame.ctype == CommandCode::Changed {
-                                        let event = parameters
-                                            .read_be::<EventId>()
-                                            .and_then(|_| parser(&mut parameters))
-                                            .map_err(|err| {
-                                                error!("Error parsing event: {:?}", err);
-                                            });
-                                        if let Ok(event) = event {
-                                            self.trigger_event(event);
+                            }
+                            TransactionState::Rearming(..) => {
+                                let TransactionState::Rearming(parser, rearm, last) = std::mem::take(transaction) else {
+                                    unreachable!()
+                                };
+                                match frame.ctype {
+                                    CommandCode::Interim => {
+                                        self.outstanding_transactions[message.transaction_label as usize] =
+                                            TransactionState::WaitingForChange(parser, Some(rearm));
+                                        // The value might have changed again before the registration was renewed
+                                        match parse_event(parser, &mut parameters) {
+                                            Some(event) if event != last => self.trigger_event(event),
+                                            _ => {}
                                         }
                                     }
+                                    code => warn!("Failed to register {:?} again: {:?}", rearm.event, code)
                                 }
-                                _ => {
-                                    warn!(
-                                        "Received vendor dependent response with no/wrong outstanding transaction: {:?} {:?} {:?}",
-                                        transaction, pdu, frame.ctype
-                                    );
-                                    return Ok(());
+                            }
+                            TransactionState::Abandoned => {
+                                // Interim responses are followed by the final one on the same label
+                                if frame.ctype != CommandCode::Interim {
+                                    trace!("Dropping response to abandoned transaction: {:?} {:?}", pdu, frame.ctype);
+                                    *transaction = TransactionState::Empty;
                                 }
                             }
+                            TransactionState::Aborting if pdu == Pdu::AbortContinuingResponse => {
+                                trace!("Peer acknowledged aborted continuing response");
+                                *transaction = TransactionState::Empty;
+                                return Ok(());
+                            }
+                            _ if pdu == Pdu::AbortContinuingResponse => {
+                                trace!("Peer acknowledged aborted continuing response");
+                                return Ok(());
+                            }
+                            _ => {
+                                warn!(
+                                    "Received vendor dependent response with no/wrong outstanding transaction: {:?} {:?} {:?}",
+                                    transaction, pdu, frame.ctype
+                                );
+                                return Ok(());
+                            }
                         }
-                        CommandStatus::Incomplete(pdu) => {
-                            self.send_avrcp(message.transaction_label, CommandCode::Control, Pdu::RequestContinuingResponse, pdu)
-                                .await;
+                    }
+                    CommandStatus::Incomplete(pdu) => {
+                        if !self.outstanding_transactions[message.transaction_label as usize].is_pending() {
+                            // The requester is gone, so there is no point in fetching the remaining fragments
+                            self.response_assembler.reset();
+                            self.abort_continuing_response(message.transaction_label, pdu).await;
+                            return Ok(());
                         }
+                        self.continuing_response = Some((message.transaction_label, pdu));
+                        self.send_avrcp(message.transaction_label, CommandCode::Control, Pdu::RequestContinuingResponse, pdu)
+                            .await;
                     }
-                } else if let CommandStatus::Complete(pdu, parameters) = self.command_assembler.process_msg(message.data)? {
-                    if let Err(err) = self
-                        .process_command(message.transaction_label, frame.ctype, pdu, parameters)
+                }
+                if aborted {
+                    self.reserve_until_aborted(message.transaction_label);
+                }
+                Ok(())
+            }
+            Opcode::PassThrough => {
+                ensure!(frame.subunit == PANEL, NotImplemented, "Unsupported subunit: {:?}", frame.subunit);
+                let transaction = &mut self.outstanding_transactions[message.transaction_label as usize];
+                if matches!(transaction, TransactionState::Abandoned) {
+                    if frame.ctype != CommandCode::Interim {
+                        trace!("Dropping pass-through response to abandoned transaction: {:?}", frame.ctype);
+                        *transaction = TransactionState::Empty;
+                    }
+                    return Ok(());
+                }
+                if !matches!(transaction, TransactionState::PendingPassThrough(_)) {
+                    warn!("Received pass-through response with no/wrong outstanding transaction: {:?} {:?}", message, transaction);
+                    return Ok(());
+                }
+                let _ = transaction.take_sender().send(match frame.ctype {
+                    CommandCode::Accepted => Ok(message.data),
+                    CommandCode::Rejected => Err(Error::Rejected(ErrorCode::NoError)),
+                    CommandCode::NotImplemented => Err(Error::NotImplemented),
+                    _ => Err(Error::InvalidReturnData)
+                });
+                Ok(())
+            }
+            code => {
+                warn!("Unexpected response for opcode: {:?}", code);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles a command of the peer. The transaction label belongs to the peer and is only used to address our response.
+    async fn process_command_message(&mut self, frame: Frame, mut message: Message) -> Result<(), NotImplemented> {
+        match frame.opcode {
+            Opcode::VendorDependent => {
+                let company_id = Self::read_company_id(frame, &mut message)?;
+                if company_id != BLUETOOTH_SIG_COMPANY_ID {
+                    let Some((_, handler)) = self.vendor_handlers.iter().find(|(id, _)| *id == company_id) else {
+                        warn!("Unsupported company id: {:#06x}", company_id);
+                        return Err(NotImplemented);
+                    };
+                    self.vendor_commands
+                        .start(message.transaction_label, frame, company_id, handler.clone(), message.data);
+                    return Ok(());
+                }
+                if let Some(pdu_id) = unknown_pdu_id(&message.data) {
+                    warn!("Unknown pdu id: {:#04x}", pdu_id);
+                    let _ = self
+                        .avctp
+                        .send_msg(Message {
+                            transaction_label: message.transaction_label,
+                            profile_id: AV_REMOTE_CONTROL,
+                            message_type: MessageType::Response,
+                            data: reject_unknown_pdu(pdu_id, ErrorCode::InvalidCommand)
+                        })
                         .await
-                    {
+                        .map_err(|err| warn!("Error sending command: {:?}", err));
+                    return Ok(());
+                }
+                if let CommandStatus::Complete(pdu, parameters) = self.command_assembler.process_msg(message.data)? {
+                    let result = match validate_command(frame.ctype, pdu, &parameters) {
+                        Ok(()) => {
+                            self.process_command(message.transaction_label, frame.ctype, pdu, parameters)
+                                .await
+                        }
+                        Err(err) => Err(err)
+                    };
+                    if let Err(err) = result {
                         self.send_avrcp(message.transaction_label, CommandCode::Rejected, pdu, err)
                             .await;
                     }
                 }
-
                 Ok(())
             }
             Opcode::UnitInfo => {
@@ -375,18 +1236,21 @@ impl State {
                 Ok(())
             }
             Opcode::PassThrough => {
-                ensure!(frame.subunit == PANEL,NotImplemented,"Unsupported subunit: {:?}",frame.subunit);
-                let transaction = &mut self.outstanding_transactions[message.transaction_label as usize];
-                if !matches!(transaction, TransactionState::PendingPassThrough(_)) {
-                    warn!("Received pass-through response with no/wrong outstanding transaction: {:?} {:?}", message, transaction);
-                    return Ok(());
-                }
-                let _ = transaction.take_sender().send(match frame.ctype {
-                    CommandCode::Accepted => Ok(message.data),
-                    CommandCode::Rejected => Err(Error::Rejected(ErrorCode::NoError)),
-                    CommandCode::NotImplemented => Err(Error::NotImplemented),
-                    _ => Err(Error::InvalidReturnData)
-                });
+                ensure!(frame.subunit == PANEL, NotImplemented, "Unsupported subunit: {:?}", frame.subunit);
+                ensure!(frame.ctype == CommandCode::Control, NotImplemented, "Unsupported command type: {:?}", frame.ctype);
+                let payload = message.data.clone();
+                let pass_through: PassThroughFrame = message.data.read_be()?;
+                // ([AVRCP] Section 4.6.1)
+                self.send_avc(
+                    message.transaction_label,
+                    Frame {
+                        ctype: CommandCode::Accepted,
+                        ..frame
+                    },
+                    payload
+                )
+                .await;
+                self.trigger_event(Event::PassThrough(pass_through.op, pass_through.state));
                 Ok(())
             }
             code => {
@@ -396,21 +1260,159 @@ impl State {
         }
     }
 
-    async fn send_avrcp<I: Instruct<BigEndian>>(&mut self, transaction_label: u8, cmd: CommandCode, pdu: Pdu, parameters: I) -> bool {
-        for packet in fragment_command(cmd, pdu, parameters) {
-            let err = self
-                .avctp
-                .send_msg(Message {
-                    transaction_label,
-                    profile_id: AV_REMOTE_CONTROL,
-                    message_type: match cmd.is_response() {
-                        true => MessageType::Response,
-                        false => MessageType::Command
-                    },
-                    data: packet
-                })
+    /// Sends a browsing command of the local controller, opening the browsing channel first if necessary.
+    async fn browsing_command(&mut self, pdu: Pdu, parameters: Bytes, sender: CommandResponseSender) {
+        if self.browsing.is_none() {
+            let Some(psm) = self.browsing_psm else {
+                let _ = sender.send(Err(Error::BrowsingUnavailable));
+                return;
+            };
+            self.queued_browsing.push((pdu, parameters, sender));
+            if !self.browsing_opening {
+                self.browsing_opening = true;
+                let opening = open_browsing_channel(self.opener.clone(), self.handle, psm, self.browsing_opener.clone());
+                spawn_supervised("avrcp-browsing", format!("handle 0x{:04x}", self.handle), opening);
+            }
+            return;
+        }
+        // Labels of requesters that gave up can be reused
+        let Some(label) = self
+            .browsing_transactions
+            .iter()
+            .position(|transaction| transaction.as_ref().map_or(true, |(_, sender)| sender.is_closed()))
+        else {
+            let _ = sender.send(Err(Error::NoTransactionIdAvailable));
+            return;
+        };
+        match self.send_browsing(label as u8, MessageType::Command, pdu, parameters).await {
+            true => self.browsing_transactions[label] = Some((pdu, sender)),
+            false => {
+                let _ = sender.send(Err(Error::BrowsingUnavailable));
+            }
+        }
+    }
+
+    /// Handles a message of the browsing channel.
+    /// Browsing is not supported as target, so every command of the peer is rejected.
+    async fn process_browsing_message(&mut self, message: Message) {
+        if message.message_type != MessageType::Command {
+            let Some((pdu, sender)) = self.browsing_transactions[message.transaction_label as usize].take() else {
+                warn!("Received browsing response for transaction {} without pending command", message.transaction_label);
+                return;
+            };
+            let reply = match (message.message_type, parse_browsing_message(message.data)) {
+                (MessageType::ResponseInvalidProfile, _) => Err(Error::InvalidProfile),
+                (_, Ok((Some(Pdu::GeneralReject), _))) => Err(Error::NotImplemented),
+                (_, Ok((Some(response), parameters))) if response == pdu => Ok(parameters),
+                _ => Err(Error::InvalidReturnData)
+            };
+            let _ = sender.send(reply);
+            return;
+        }
+        // ([AVRCP] Section 6.15.3) unknown or malformed PDUs are answered with a general reject
+        let (pdu, parameters) = match parse_browsing_message(message.data) {
+            Ok((Some(pdu), parameters)) if pdu != Pdu::GeneralReject => (pdu, parameters),
+            _ => {
+                self.send_browsing(message.transaction_label, MessageType::Response, Pdu::GeneralReject, ErrorCode::InvalidCommand)
+                    .await;
+                return;
+            }
+        };
+        // The response parameters follow the browsing header of the PDU
+        let max_size = self
+            .browsing
+            .as_ref()
+            .map_or(0, |browsing| browsing.max_payload_size().saturating_sub(3));
+        #[cfg(feature = "pts")]
+        if let Some(status) = self.pts.avrcp_reject(pdu as u8) {
+            self.send_browsing(message.transaction_label, MessageType::Response, pdu, status)
                 .await;
-            if let Err(err) = err {
+            return;
+        }
+        let response = match self.library.as_mut() {
+            Some(library) => library.process(pdu, parameters, max_size),
+            None => {
+                warn!("Unsupported browsing pdu: {:?}", pdu);
+                Err(ErrorCode::InvalidCommand)
+            }
+        };
+        match response {
+            Ok(response) => {
+                self.send_browsing(message.transaction_label, MessageType::Response, pdu, response)
+                    .await
+            }
+            Err(status) => {
+                self.send_browsing(message.transaction_label, MessageType::Response, pdu, status)
+                    .await
+            }
+        };
+    }
+
+    async fn send_browsing<I: Instruct<BigEndian>>(&mut self, transaction_label: u8, message_type: MessageType, pdu: Pdu, parameters: I) -> bool {
+        let Some(browsing) = self.browsing.as_mut() else {
+            warn!("No browsing channel");
+            return false;
+        };
+        let result = match browsing_message(pdu, parameters) {
+            Ok(data) => {
+                browsing
+                    .send_msg(Message {
+                        transaction_label,
+                        profile_id: AV_REMOTE_CONTROL,
+                        message_type,
+                        data
+                    })
+                    .await
+            }
+            Err(err) => Err(err.into())
+        };
+        result
+            .map_err(|err| warn!("Error sending browsing message: {:?}", err))
+            .is_ok()
+    }
+
+    async fn send_avrcp<I: Instruct<BigEndian>>(&mut self, transaction_label: u8, cmd: CommandCode, pdu: Pdu, parameters: I) -> bool {
+        #[cfg(feature = "fault-injection")]
+        if cmd.is_response() {
+            if let Some(fault) = self.response_shaping.next(pdu as u8) {
+                return self.send_faulted_response(transaction_label, cmd, pdu, parameters, fault).await;
+            }
+        }
+        let messages = fragment_command(cmd, pdu, parameters)
+            .map(|packet| Message {
+                transaction_label,
+                profile_id: AV_REMOTE_CONTROL,
+                message_type: match cmd.is_response() {
+                    true => MessageType::Response,
+                    false => MessageType::Command
+                },
+                data: packet
+            })
+            .collect();
+        self.avctp
+            .send_msgs(messages)
+            .await
+            .map_err(|err| warn!("Error sending command: {:?}", err))
+            .is_ok()
+    }
+
+    #[cfg(feature = "fault-injection")]
+    async fn send_faulted_response<I: Instruct<BigEndian>>(
+        &mut self, transaction_label: u8, cmd: CommandCode, pdu: Pdu, parameters: I, fault: ResponseFault
+    ) -> bool {
+        sleep(fault.delay).await;
+        let size = fault.fragment_size.unwrap_or(MAX_PAYLOAD_SIZE).clamp(1, MAX_PAYLOAD_SIZE);
+        for (i, packet) in fragment_command_with_size(cmd, pdu, parameters, size).enumerate() {
+            if i > 0 {
+                sleep(fault.fragment_gap).await;
+            }
+            let message = Message {
+                transaction_label,
+                profile_id: AV_REMOTE_CONTROL,
+                message_type: MessageType::Response,
+                data: packet
+            };
+            if let Err(err) = self.avctp.send_msg(message).await {
                 warn!("Error sending command: {:?}", err);
                 return false;
             }
@@ -437,13 +1439,91 @@ impl State {
             .is_ok()
     }
 
-    fn trigger_event(&self, event: Event) {
+    /// Completes the volume notification of the peer with the current volume ([AVRCP] Section 6.7.1).
+    /// Changes until the peer registers again are coalesced into one, so rapid changes (e.g. of a volume wheel)
+    /// don't flood the peer and the final value is never lost.
+    /// Notifications are at least [VOLUME_NOTIFICATION_INTERVAL] apart, later changes are sent once the interval passed.
+    async fn volume_changed(&mut self) {
+        let event = EventId::VolumeChanged;
+        if !self.volume_throttle.try_send() {
+            return;
+        }
+        // The volume went back to the reported value in the meantime
+        if self.notifications.is_registered(event) && self.volume == self.reported_volume {
+            return;
+        }
+        if let Some(transaction) = self.notifications.changed(event) {
+            self.send_avrcp(transaction, CommandCode::Changed, Pdu::RegisterNotification, (event, self.volume))
+                .await;
+            self.reported_volume = self.volume;
+            self.volume_throttle.sent();
+        }
+    }
+
+    /// Completes the notification `event` of the peer, if it registered one ([AVRCP] Section 6.7.2).
+    /// Otherwise the change is reported once the peer registers again.
+    async fn notify_changed<I: Instruct<BigEndian>>(&mut self, event: EventId, value: I) {
+        if let Some(transaction) = self.notifications.changed(event) {
+            self.send_avrcp(transaction, CommandCode::Changed, Pdu::RegisterNotification, (event, value))
+                .await;
+        }
+    }
+
+    /// The identifier of the local track, the playing element or none without metadata ([AVRCP] Section 6.7.2).
+    fn track_identifier(&self) -> u64 {
+        match self.media_attributes.is_empty() {
+            true => NO_TRACK,
+            false => PLAYING_ELEMENT
+        }
+    }
+
+    fn trigger_event(&mut self, event: Event) {
+        if let Event::UidsChanged(counter) = event {
+            self.uids.update(counter);
+        }
+        let event = match event {
+            Event::VolumeChanged(volume, VolumeOrigin::Remote) if self.echoes_local_volume(volume) => {
+                Event::VolumeChanged(volume, VolumeOrigin::Local)
+            }
+            event => event
+        };
+        let event = Timestamped::now(event);
+        if self.subscribers.publish(&event) {
+            return;
+        }
         if let Err(TrySendError::Full(event)) = self.events.try_send(event) {
-            warn!("Event queue full, dropping event: {:?}", event);
+            warn!("Event queue full, dropping {:?} event", EventFilter::kind(&event.value));
         }
     }
 
+    /// Converts a volume of `0.0..=1.0` to a step. The current step is kept until the volume is more than
+    /// the hysteresis past the rounding boundary, so values close to a boundary don't toggle between two steps.
+    fn volume_step(&self, volume: f32) -> u8 {
+        let exact = volume.clamp(0.0, 1.0) * MAX_VOLUME as f32;
+        match (exact - self.volume as f32).abs() <= 0.5 + self.volume_hysteresis {
+            true => self.volume,
+            false => exact.round() as u8
+        }
+    }
+
+    /// Whether `volume` is the peer confirming the volume the application set last, consumes the local volume.
+    fn echoes_local_volume(&mut self, volume: f32) -> bool {
+        let volume = (volume * MAX_VOLUME as f32).round() as u8;
+        match self.local_volume.take() {
+            Some((local, time)) if local == volume && now().saturating_duration_since(time) <= LOCAL_VOLUME_ECHO_WINDOW => true,
+            other => {
+                self.local_volume = other;
+                false
+            }
+        }
+    }
+
+    /// Handles a command that passed [validate_command], the returned error code is sent back as rejection.
     async fn process_command(&mut self, transaction: u8, _cmd: CommandCode, pdu: Pdu, mut parameters: Bytes) -> Result<(), ErrorCode> {
+        #[cfg(feature = "pts")]
+        if let Some(status) = self.pts.avrcp_reject(pdu as u8) {
+            return Err(status);
+        }
         match pdu {
             // ([AVRCP] Section 6.4.1)
             Pdu::GetCapabilities => {
@@ -451,24 +1531,26 @@ impl State {
                 parameters.finish()?;
                 match capability {
                     COMPANY_ID_CAPABILITY => {
-                        self.send_avrcp(
-                            transaction,
-                            CommandCode::Implemented,
-                            pdu,
-                            (COMPANY_ID_CAPABILITY, 1, BLUETOOTH_SIG_COMPANY_ID)
-                        )
-                        .await;
+                        let mut company_ids = BytesMut::new();
+                        company_ids.write_be(COMPANY_ID_CAPABILITY);
+                        company_ids.write_be(1 + self.vendor_handlers.len() as u8);
+                        company_ids.write_be(BLUETOOTH_SIG_COMPANY_ID);
+                        for (company_id, _) in self.vendor_handlers.iter() {
+                            company_ids.write_be(*company_id);
+                        }
+                        self.send_avrcp(transaction, CommandCode::Implemented, pdu, company_ids.freeze())
+                            .await;
                         Ok(())
                     }
                     EVENTS_SUPPORTED_CAPABILITY => {
-                        //TODO Support a second event type to conform to spec
-                        self.send_avrcp(
-                            transaction,
-                            CommandCode::Implemented,
-                            pdu,
-                            (EVENTS_SUPPORTED_CAPABILITY, 1u8, EventId::VolumeChanged)
-                        )
-                        .await;
+                        let mut events = BytesMut::new();
+                        events.write_be(EVENTS_SUPPORTED_CAPABILITY);
+                        events.write_be(SUPPORTED_EVENTS.len() as u8);
+                        for event in SUPPORTED_EVENTS {
+                            events.write_be(event);
+                        }
+                        self.send_avrcp(transaction, CommandCode::Implemented, pdu, events.freeze())
+                            .await;
                         Ok(())
                     }
                     _ => {
@@ -479,25 +1561,49 @@ impl State {
             }
             // ([AVRCP] Section 6.7.2)
             Pdu::RegisterNotification => {
-                // ensure!(cmd == CommandCode::Notify, ErrorCode::InvalidCommand);
                 let event: EventId = parameters.read_be()?;
                 let _: u32 = parameters.read_be()?;
                 parameters.finish()?;
                 ensure!(
-                    !self.registered_notifications.contains_key(&event),
+                    !self.notifications.is_registered(event),
                     ErrorCode::InternalError,
                     "Event id already has a notification registered"
                 );
-                ensure!(
-                    event == EventId::VolumeChanged,
-                    ErrorCode::InvalidParameter,
-                    "Attempted to register unsupported event: {:?}",
-                    event
-                );
-                // ([AVRCP] Section 6.13.3)
-                self.send_avrcp(transaction, CommandCode::Interim, pdu, (event, self.volume))
-                    .await;
-                self.registered_notifications.insert(event, transaction);
+                match event {
+                    // ([AVRCP] Section 6.13.3)
+                    EventId::VolumeChanged => {
+                        self.send_avrcp(transaction, CommandCode::Interim, pdu, (event, self.volume))
+                            .await;
+                        // Some controllers ignore the value of the interim response, so a change they missed
+                        // while the notification was not registered is compared to the last changed response
+                        // and reported right away
+                        match self.notifications.register(event, transaction) {
+                            true => self.volume_changed().await,
+                            false => self.reported_volume = self.volume
+                        }
+                    }
+                    // ([AVRCP] Section 6.7.2)
+                    EventId::PlaybackStatusChanged => {
+                        let status = self.play_status.value.status as u8;
+                        self.send_avrcp(transaction, CommandCode::Interim, pdu, (event, status))
+                            .await;
+                        if self.notifications.register(event, transaction) {
+                            self.notify_changed(event, status).await;
+                        }
+                    }
+                    EventId::TrackChanged => {
+                        let track = self.track_identifier();
+                        self.send_avrcp(transaction, CommandCode::Interim, pdu, (event, track))
+                            .await;
+                        if self.notifications.register(event, transaction) {
+                            self.notify_changed(event, track).await;
+                        }
+                    }
+                    _ => {
+                        warn!("Attempted to register unsupported event: {:?}", event);
+                        return Err(ErrorCode::InvalidParameter);
+                    }
+                }
                 Ok(())
             }
             // ([AVRCP] Section 6.8.1)
@@ -505,13 +1611,77 @@ impl State {
                 // Technically we have to delay parts of the response until these arrive but who cares
                 Ok(())
             }
+            // ([AVRCP] Section 6.5.7)
+            Pdu::InformDisplayableCharacterSet => {
+                let count: u8 = parameters.read_be()?;
+                let character_sets = (0..count)
+                    .map(|_| parameters.read_be::<u16>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                parameters.finish()?;
+                self.send_avrcp(transaction, CommandCode::Accepted, pdu, ()).await;
+                self.displayable_character_sets = character_sets.clone();
+                self.trigger_event(Event::DisplayableCharacterSets(character_sets));
+                Ok(())
+            }
+            // ([AVRCP] Section 6.6.1)
+            Pdu::GetElementAttributes => {
+                // Without browsing only the currently playing element can be addressed
+                let identifier: u64 = parameters.read_be()?;
+                ensure!(identifier == PLAYING_ELEMENT, ErrorCode::InvalidParameter);
+                let count: u8 = parameters.read_be()?;
+                let requested = (0..count)
+                    .map(|_| parameters.read_be::<u32>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                parameters.finish()?;
+                let charset = charset::negotiate(&self.displayable_character_sets);
+                // The handle of the served artwork replaces a cover art attribute of the application
+                let cover_art = self.artwork.handle();
+                let attributes: Vec<(MediaAttributeId, Vec<u8>)> = self
+                    .media_attributes
+                    .iter()
+                    .filter(|(id, _)| cover_art.is_none() || **id != MediaAttributeId::DefaultCoverArt)
+                    .map(|(id, value)| (*id, value.as_str()))
+                    .chain(cover_art.as_deref().map(|handle| (MediaAttributeId::DefaultCoverArt, handle)))
+                    .filter(|(id, _)| requested.is_empty() || requested.contains(&(*id as u32)))
+                    .map(|(id, value)| (id, charset::encode(value, charset)))
+                    .collect();
+                let mut response = BytesMut::new();
+                response.write_be(attributes.len() as u8);
+                for (id, value) in attributes {
+                    let value = &value[..value.len().min(u16::MAX as usize)];
+                    response.write_be((id, charset, value.len() as u16));
+                    response.put_slice(value);
+                }
+                self.send_avrcp(transaction, CommandCode::Implemented, pdu, response.freeze())
+                    .await;
+                Ok(())
+            }
+            // ([AVRCP] Section 6.7.1)
+            Pdu::GetPlayStatus => {
+                parameters.finish()?;
+                let status = self.play_status.value;
+                let position = status.position_after(self.play_status.age());
+                let millis = |value: Option<Duration>| value.map_or(u32::MAX, |value| value.as_millis().min(u32::MAX as u128 - 1) as u32);
+                self.send_avrcp(transaction, CommandCode::Implemented, pdu, (millis(status.length), millis(position), status.status as u8))
+                    .await;
+                Ok(())
+            }
+            // ([AVRCP] Section 6.5.8)
+            Pdu::InformBatteryStatusOfCt => {
+                let status: BatteryStatus = parameters.read_be()?;
+                parameters.finish()?;
+                self.send_avrcp(transaction, CommandCode::Accepted, pdu, ()).await;
+                self.controller_battery_status = Some(status);
+                self.trigger_event(Event::ControllerBatteryStatus(status));
+                Ok(())
+            }
             // ([AVRCP] Section 6.13.2)
             Pdu::SetAbsoluteVolume => {
                 self.volume = MAX_VOLUME.min(parameters.read_be()?);
                 parameters.finish()?;
                 self.send_avrcp(transaction, CommandCode::Accepted, pdu, self.volume)
                     .await;
-                self.trigger_event(Event::VolumeChanged(self.volume as f32 / MAX_VOLUME as f32));
+                self.trigger_event(Event::VolumeChanged(self.volume as f32 / MAX_VOLUME as f32, VolumeOrigin::Remote));
                 Ok(())
             }
             _ => {
@@ -522,4 +1692,18 @@ impl State {
     }
 }
 
-const MAX_VOLUME: u8 = 0x7f;
+/// The highest step of the absolute volume ([AVRCP] Section 6.13.1).
+pub const MAX_VOLUME: u8 = 0x7f;
+/// The identifier of the currently playing element ([AVRCP] Section 6.6.1).
+const PLAYING_ELEMENT: u64 = 0x00;
+/// The track identifier while no track is selected ([AVRCP] Section 6.7.2).
+const NO_TRACK: u64 = u64::MAX;
+/// The events the peer can register as controller.
+const SUPPORTED_EVENTS: [EventId; 3] = [EventId::VolumeChanged, EventId::PlaybackStatusChanged, EventId::TrackChanged];
+/// Fits all attributes of a track with common values, a title and URI of a few hundred bytes each.
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+const FEATURE_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// The shortest time between two volume notifications to the peer.
+const VOLUME_NOTIFICATION_INTERVAL: Duration = Duration::from_millis(100);
+/// How long after a local volume change the same volume from the peer is considered its echo.
+const LOCAL_VOLUME_ECHO_WINDOW: Duration = Duration::from_secs(2);
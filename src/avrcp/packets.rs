@@ -1,8 +1,10 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::u24;
 use instructor::{BigEndian, Buffer, BufferMut, Error, Exstruct, Instruct};
+use tracing::warn;
 
 use crate::avc::{CommandCode, Frame, Opcode, Subunit, SubunitType};
+use crate::avrcp::error::ErrorCode;
 use crate::{ensure, log_assert};
 
 pub const PANEL: Subunit = Subunit {
@@ -25,6 +27,14 @@ struct CommandHeader {
     parameter_length: u16
 }
 
+// ([AVRCP] Section 6.10.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Instruct, Exstruct)]
+#[instructor(endian = "big")]
+struct BrowsingHeader {
+    pdu: u8,
+    parameter_length: u16
+}
+
 // ([AVRCP] Section 6.3)
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct, Exstruct)]
 #[repr(u8)]
@@ -77,6 +87,7 @@ pub enum Pdu {
 
 // ([AVRCP] Section 26)
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum MediaAttributeId {
     Title = 0x01,
@@ -91,6 +102,7 @@ pub enum MediaAttributeId {
 
 // ([AVRCP] Section 28)
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum EventId {
     PlaybackStatusChanged = 0x01,
@@ -108,23 +120,150 @@ pub enum EventId {
     VolumeChanged = 0x0D
 }
 
+// ([AVRCP] Section 6.5.8)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum BatteryStatus {
+    Normal = 0x00,
+    Warning = 0x01,
+    Critical = 0x02,
+    External = 0x03,
+    FullCharge = 0x04
+}
+
+/// The expected length of the parameters of a command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParameterLength {
+    Exact(usize),
+    /// A count after a fixed size header, followed by that many items of the given size.
+    Counted { header: usize, item: usize }
+}
+
+impl ParameterLength {
+    fn matches(self, parameters: &[u8]) -> bool {
+        match self {
+            ParameterLength::Exact(length) => parameters.len() == length,
+            ParameterLength::Counted { header, item } => parameters
+                .get(header)
+                .is_some_and(|&count| parameters.len() == header + 1 + count as usize * item)
+        }
+    }
+}
+
+/// A PDU the target supports and the command type and parameters it has to arrive with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PduRule {
+    pub pdu: Pdu,
+    pub ctype: CommandCode,
+    pub parameters: ParameterLength
+}
+
+const fn rule(pdu: Pdu, ctype: CommandCode, parameters: ParameterLength) -> PduRule {
+    PduRule { pdu, ctype, parameters }
+}
+
+/// The PDUs handled by the target ([AVRCP] Section 6.15.2).
+pub const TARGET_PDUS: &[PduRule] = &[
+    // ([AVRCP] Section 6.4.1)
+    rule(Pdu::GetCapabilities, CommandCode::Status, ParameterLength::Exact(1)),
+    // ([AVRCP] Section 6.5.7)
+    rule(Pdu::InformDisplayableCharacterSet, CommandCode::Control, ParameterLength::Counted { header: 0, item: 2 }),
+    // ([AVRCP] Section 6.5.8)
+    rule(Pdu::InformBatteryStatusOfCt, CommandCode::Control, ParameterLength::Exact(1)),
+    // ([AVRCP] Section 6.6.1), the element identifier followed by the attribute ids
+    rule(Pdu::GetElementAttributes, CommandCode::Status, ParameterLength::Counted { header: 8, item: 4 }),
+    // ([AVRCP] Section 6.7.2)
+    rule(Pdu::RegisterNotification, CommandCode::Notify, ParameterLength::Exact(5)),
+    // ([AVRCP] Section 6.8)
+    rule(Pdu::RequestContinuingResponse, CommandCode::Control, ParameterLength::Exact(1)),
+    rule(Pdu::AbortContinuingResponse, CommandCode::Control, ParameterLength::Exact(1)),
+    // ([AVRCP] Section 6.13.2)
+    rule(Pdu::SetAbsoluteVolume, CommandCode::Control, ParameterLength::Exact(1))
+];
+
+/// Checks a command against [TARGET_PDUS]. Unsupported PDUs and command types are invalid commands,
+/// parameters of the wrong length are invalid parameters ([AVRCP] Section 6.15.2).
+pub fn validate_command(ctype: CommandCode, pdu: Pdu, parameters: &[u8]) -> Result<(), ErrorCode> {
+    let rule = TARGET_PDUS
+        .iter()
+        .find(|rule| rule.pdu == pdu)
+        .ok_or(ErrorCode::InvalidCommand)?;
+    ensure!(rule.ctype == ctype, ErrorCode::InvalidCommand, "Unexpected command type {:?} for {:?}", ctype, pdu);
+    ensure!(rule.parameters.matches(parameters), ErrorCode::InvalidParameter, "Invalid parameter length for {:?}", pdu);
+    Ok(())
+}
+
+/// The PDU id of a vendor dependent command if it isn't a known [Pdu].
+pub fn unknown_pdu_id(packet: &[u8]) -> Option<u8> {
+    let id = *packet.first()?;
+    Bytes::copy_from_slice(&[id]).read_be::<Pdu>().is_err().then_some(id)
+}
+
+/// A rejection of a PDU that can't be represented as [Pdu] ([AVRCP] Section 6.15.1).
+pub fn reject_unknown_pdu(pdu_id: u8, error: ErrorCode) -> Bytes {
+    let mut buffer = BytesMut::new();
+    buffer.write(Frame {
+        ctype: CommandCode::Rejected,
+        subunit: PANEL,
+        opcode: Opcode::VendorDependent
+    });
+    buffer.write_be(BLUETOOTH_SIG_COMPANY_ID);
+    buffer.write_be(pdu_id);
+    buffer.write_be(PacketType::Single as u8);
+    buffer.write_be(1u16);
+    buffer.write_be(error);
+    buffer.freeze()
+}
+
 pub enum CommandStatus {
     Complete(Pdu, Bytes),
-    Incomplete(Pdu)
+    Incomplete(Pdu),
+    /// The reassembled message exceeded the size limit and was cut off.
+    /// The flag is set if the sender still has fragments left, which have to be aborted ([AVRCP] Section 6.8.2).
+    Truncated(Pdu, Bytes, bool)
 }
 
-#[derive(Default)]
 pub struct CommandAssembler {
     pdu: Option<Pdu>,
-    data: BytesMut
+    data: BytesMut,
+    limit: usize
+}
+
+impl Default for CommandAssembler {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
 }
 
 impl CommandAssembler {
-    fn reset(&mut self) {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            pdu: None,
+            data: BytesMut::new(),
+            limit
+        }
+    }
+
+    fn append(&mut self, pdu: Pdu, mut packet: Bytes, last: bool) -> Option<CommandStatus> {
+        let remaining = self.limit.saturating_sub(self.data.len());
+        if packet.len() > remaining {
+            warn!("Message exceeds the size limit of {} bytes, truncating", self.limit);
+            self.data.put(packet.split_to(remaining));
+            let data = self.data.split().freeze();
+            self.reset();
+            return Some(CommandStatus::Truncated(pdu, data, !last));
+        }
+        self.data.put(packet);
+        None
+    }
+
+    pub fn reset(&mut self) {
         self.data.clear();
         self.pdu = None;
     }
 
+    #[cfg_attr(feature = "flamegraph", inline(never))]
     pub fn process_msg(&mut self, mut packet: Bytes) -> Result<CommandStatus, Error> {
         let CommandHeader {
             pdu,
@@ -143,17 +282,23 @@ impl CommandAssembler {
                 log_assert!(self.pdu.is_none());
                 self.reset();
                 self.pdu = Some(pdu);
-                self.data.put(packet);
+                if let Some(truncated) = self.append(pdu, packet, false) {
+                    return Ok(truncated);
+                }
                 Ok(CommandStatus::Incomplete(pdu))
             }
             PacketType::Continue => {
                 ensure!(self.pdu == Some(pdu), Error::InvalidValue);
-                self.data.put(packet);
+                if let Some(truncated) = self.append(pdu, packet, false) {
+                    return Ok(truncated);
+                }
                 Ok(CommandStatus::Incomplete(pdu))
             }
             PacketType::End => {
                 ensure!(self.pdu == Some(pdu), Error::InvalidValue);
-                self.data.put(packet);
+                if let Some(truncated) = self.append(pdu, packet, true) {
+                    return Ok(truncated);
+                }
                 let data = self.data.split().freeze();
                 self.reset();
                 Ok(CommandStatus::Complete(pdu, data))
@@ -180,7 +325,7 @@ where
             opcode: Opcode::VendorDependent
         });
         buffer.write_be(BLUETOOTH_SIG_COMPANY_ID);
-        let payload = parameters.split_to(MAX_PAYLOAD_SIZE.min(parameters.len()));
+        let payload = parameters.split_to(max_payload_size.min(parameters.len()));
         let packet_type = match (first, parameters.is_empty()) {
             (true, true) => PacketType::Single,
             (true, false) => PacketType::Start,
@@ -202,11 +347,43 @@ where
 
  */
 
+/// Encodes a message of the browsing channel, which always consists of a single packet ([AVRCP] Section 6.10.1).
+pub fn browsing_message<P: Instruct<BigEndian>>(pdu: Pdu, parameters: P) -> Result<Bytes, Error> {
+    let mut buffer = BytesMut::new();
+    buffer.write(parameters);
+    let parameters = buffer.split().freeze();
+    buffer.write(BrowsingHeader {
+        pdu: pdu as u8,
+        parameter_length: u16::try_from(parameters.len()).map_err(|_| Error::TooLong)?
+    });
+    buffer.put(parameters);
+    Ok(buffer.freeze())
+}
+
+/// Splits a message of the browsing channel into the PDU and the parameters.
+/// The PDU is `None` if it is unknown, which still has to be answered with a general reject.
+pub fn parse_browsing_message(mut packet: Bytes) -> Result<(Option<Pdu>, Bytes), Error> {
+    let BrowsingHeader { pdu, parameter_length } = packet.read()?;
+    ensure!(parameter_length as usize == packet.len(), Error::InvalidValue);
+    Ok(([pdu].as_slice().read_be().ok(), packet))
+}
+
+/// The most parameter bytes of a single AVRCP packet.
+pub const MAX_PAYLOAD_SIZE: usize = 512 - 3 - 3 - 3;
+
 pub fn fragment_command<P>(cmd: CommandCode, pdu: Pdu, parameters: P) -> impl Iterator<Item = Bytes>
 where
     P: Instruct<BigEndian>
 {
-    const MAX_PAYLOAD_SIZE: usize = 512 - 3 - 3 - 3;
+    fragment_command_with_size(cmd, pdu, parameters, MAX_PAYLOAD_SIZE)
+}
+
+/// Like [fragment_command] but with at most `max_payload_size` parameter bytes per fragment.
+pub fn fragment_command_with_size<P>(cmd: CommandCode, pdu: Pdu, parameters: P, max_payload_size: usize) -> impl Iterator<Item = Bytes>
+where
+    P: Instruct<BigEndian>
+{
+    debug_assert!(max_payload_size > 0);
     let mut buffer = BytesMut::new();
     buffer.write(parameters);
     let mut parameters = buffer.split().freeze();
@@ -219,7 +396,7 @@ where
             opcode: Opcode::VendorDependent
         });
         buffer.write_be(BLUETOOTH_SIG_COMPANY_ID);
-        let payload = parameters.split_to(MAX_PAYLOAD_SIZE.min(parameters.len()));
+        let payload = parameters.split_to(max_payload_size.min(parameters.len()));
         let packet_type = match (first, parameters.is_empty()) {
             (true, true) => PacketType::Single,
             (true, false) => PacketType::Start,
@@ -239,10 +416,14 @@ where
 
 #[cfg(test)]
 mod tests {
-    use bytes::Buf;
+    use bytes::{Buf, Bytes};
 
     use crate::avc::CommandCode;
-    use crate::avrcp::packets::{fragment_command, EventId, Pdu};
+    use crate::avrcp::error::ErrorCode;
+    use crate::avrcp::packets::{
+        browsing_message, fragment_command, fragment_command_with_size, parse_browsing_message, reject_unknown_pdu, unknown_pdu_id,
+        validate_command, CommandAssembler, CommandStatus, EventId, Pdu, EVENTS_SUPPORTED_CAPABILITY
+    };
 
     #[test]
     pub fn test_fragmentation() {
@@ -252,5 +433,67 @@ mod tests {
             packets.next().unwrap().chunk()
         );
         assert_eq!(None, packets.next());
+
+        let mut packets = fragment_command_with_size(CommandCode::Interim, Pdu::RegisterNotification, (EventId::VolumeChanged, 0u8), 1);
+        assert_eq!(&[0x0F, 0x48, 0x00, 0x00, 0x19, 0x58, 0x31, 0x01, 0x00, 0x01, 0x0D], packets.next().unwrap().chunk());
+        assert_eq!(&[0x0F, 0x48, 0x00, 0x00, 0x19, 0x58, 0x31, 0x03, 0x00, 0x01, 0x00], packets.next().unwrap().chunk());
+        assert_eq!(None, packets.next());
+    }
+
+    #[test]
+    pub fn test_truncation() {
+        let mut assembler = CommandAssembler::new(4);
+        let start = Bytes::from_static(&[0x20, 0x01, 0x00, 0x03, 0x01, 0x02, 0x03]);
+        let end = Bytes::from_static(&[0x20, 0x03, 0x00, 0x03, 0x04, 0x05, 0x06]);
+        assert!(matches!(assembler.process_msg(start), Ok(CommandStatus::Incomplete(Pdu::GetElementAttributes))));
+        match assembler.process_msg(end) {
+            Ok(CommandStatus::Truncated(Pdu::GetElementAttributes, data, false)) => assert_eq!(data.chunk(), &[0x01, 0x02, 0x03, 0x04]),
+            _ => panic!("expected truncated message")
+        }
+
+        // Cut off before the last fragment, the sender has to be told to stop
+        let start = Bytes::from_static(&[0x20, 0x01, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        match assembler.process_msg(start) {
+            Ok(CommandStatus::Truncated(Pdu::GetElementAttributes, data, true)) => assert_eq!(data.chunk(), &[0x01, 0x02, 0x03, 0x04]),
+            _ => panic!("expected truncated message")
+        }
+    }
+
+    #[test]
+    pub fn test_browsing_message() {
+        let message = browsing_message(Pdu::GeneralReject, ErrorCode::InvalidCommand).unwrap();
+        assert_eq!(message.chunk(), &[0xA0, 0x00, 0x01, 0x00]);
+        let (pdu, parameters) = parse_browsing_message(message).unwrap();
+        assert_eq!(pdu, Some(Pdu::GeneralReject));
+        assert_eq!(parameters.chunk(), &[0x00]);
+        assert!(parse_browsing_message(Bytes::from_static(&[0x71, 0x00, 0x02, 0x00])).is_err());
+        assert_eq!(parse_browsing_message(Bytes::from_static(&[0xFF, 0x00, 0x00])).unwrap().0, None);
+    }
+
+    #[test]
+    pub fn encode_commands() {
+        let packets: Vec<_> = fragment_command(CommandCode::Status, Pdu::GetCapabilities, EVENTS_SUPPORTED_CAPABILITY).collect();
+        assert_eq!(packets, [&[0x01, 0x48, 0x00, 0x00, 0x19, 0x58, 0x10, 0x00, 0x00, 0x01, 0x03][..]]);
+        let packets: Vec<_> = fragment_command(CommandCode::Control, Pdu::SetAbsoluteVolume, 0x40u8).collect();
+        assert_eq!(packets, [&[0x00, 0x48, 0x00, 0x00, 0x19, 0x58, 0x50, 0x00, 0x00, 0x01, 0x40][..]]);
+        assert_eq!(
+            reject_unknown_pdu(0x55, ErrorCode::InvalidCommand).as_ref(),
+            &[0x0A, 0x48, 0x00, 0x00, 0x19, 0x58, 0x55, 0x00, 0x00, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    pub fn command_validation() {
+        assert_eq!(unknown_pdu_id(&[0x55, 0x00, 0x00, 0x00]), Some(0x55));
+        assert_eq!(unknown_pdu_id(&[0x50, 0x00, 0x00, 0x01, 0x40]), None);
+        assert_eq!(validate_command(CommandCode::Control, Pdu::SetAbsoluteVolume, &[0x40]), Ok(()));
+        assert_eq!(validate_command(CommandCode::Status, Pdu::SetAbsoluteVolume, &[0x40]), Err(ErrorCode::InvalidCommand));
+        assert_eq!(validate_command(CommandCode::Control, Pdu::SetAbsoluteVolume, &[]), Err(ErrorCode::InvalidParameter));
+        assert_eq!(validate_command(CommandCode::Control, Pdu::PlayItem, &[]), Err(ErrorCode::InvalidCommand));
+        assert_eq!(validate_command(CommandCode::Control, Pdu::InformDisplayableCharacterSet, &[0x01, 0x00, 0x6A]), Ok(()));
+        assert_eq!(validate_command(CommandCode::Control, Pdu::InformDisplayableCharacterSet, &[0x02, 0x00, 0x6A]), Err(ErrorCode::InvalidParameter));
+        let get_title = [0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(validate_command(CommandCode::Status, Pdu::GetElementAttributes, &get_title), Ok(()));
+        assert_eq!(validate_command(CommandCode::Status, Pdu::GetElementAttributes, &get_title[..12]), Err(ErrorCode::InvalidParameter));
     }
 }
@@ -1,12 +1,14 @@
 use bitflags::bitflags;
 
-use crate::l2cap::AVCTP_PSM;
+use crate::hci::remote_info::{RemoteDeviceInfo, RemoteProfile};
+use crate::l2cap::{AVCTP_BROWSING_PSM, AVCTP_PSM};
 use crate::sdp::ids::attributes::{
-    BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID, BROWSE_GROUP_LIST_ID, PROTOCOL_DESCRIPTOR_LIST_ID, SERVICE_CLASS_ID_LIST_ID, SERVICE_RECORD_HANDLE_ID
+    ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID, BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID, BROWSE_GROUP_LIST_ID, PROTOCOL_DESCRIPTOR_LIST_ID,
+    SERVICE_CLASS_ID_LIST_ID, SERVICE_RECORD_HANDLE_ID
 };
 use crate::sdp::ids::browse_groups::PUBLIC_BROWSE_ROOT;
-use crate::sdp::ids::protocols::{AVCTP, L2CAP};
-use crate::sdp::{DataElement, ServiceAttribute, ServiceRecord};
+use crate::sdp::ids::protocols::{AVCTP, L2CAP, OBEX};
+use crate::sdp::{DataElement, ServiceAttribute, ServiceRecord, Uuid};
 use crate::sdp::ids::service_classes::{AV_REMOTE_CONTROL, AV_REMOTE_CONTROL_CONTROLLER, AV_REMOTE_CONTROL_TARGET};
 
 
@@ -15,12 +17,22 @@ const SUPPORTED_FEATURES_ID: u16 = 0x0311;
 
 #[derive(Debug)]
 pub struct AvrcpControllerServiceRecord {
-    handle: u32
+    handle: u32,
+    features: SupportedControllerFeatures
 }
 
 impl AvrcpControllerServiceRecord {
     pub fn new(handle: u32) -> Self {
-        Self { handle }
+        Self {
+            handle,
+            features: SupportedControllerFeatures::CATEGORY_1
+        }
+    }
+
+    /// Advertises that images and linked thumbnails are fetched over the cover art service of the target ([AVRCP] Section 8).
+    pub fn with_cover_art(mut self) -> Self {
+        self.features |= SupportedControllerFeatures::COVER_ART_IMAGE | SupportedControllerFeatures::COVER_ART_LINKED_THUNBNAIL;
+        self
     }
 }
 
@@ -49,7 +61,7 @@ impl ServiceRecord for AvrcpControllerServiceRecord {
                 BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID,
                 DataElement::from_iter([(AV_REMOTE_CONTROL, avcrp_version)])
             ),
-            ServiceAttribute::new(SUPPORTED_FEATURES_ID, SupportedControllerFeatures::CATEGORY_1),
+            ServiceAttribute::new(SUPPORTED_FEATURES_ID, self.features),
         ]
     }
 }
@@ -57,6 +69,7 @@ impl ServiceRecord for AvrcpControllerServiceRecord {
 // ([AVRCP] Section 8).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct SupportedControllerFeatures: u16 {
         const CATEGORY_1 = 1 << 0;
         const CATEGORY_2 = 1 << 1;
@@ -77,12 +90,31 @@ impl From<SupportedControllerFeatures> for DataElement {
 
 #[derive(Debug)]
 pub struct AvrcpTargetServiceRecord {
-    handle: u32
+    handle: u32,
+    features: SupportedTargetFeatures,
+    cover_art_psm: Option<u16>
 }
 
 impl AvrcpTargetServiceRecord {
     pub fn new(handle: u32) -> Self {
-        Self { handle }
+        Self {
+            handle,
+            features: SupportedTargetFeatures::CATEGORY_2,
+            cover_art_psm: None
+        }
+    }
+
+    /// Advertises a browsable player and the browsing channel ([AVRCP] Section 8).
+    pub fn with_browsing(mut self) -> Self {
+        self.features |= SupportedTargetFeatures::CATEGORY_1 | SupportedTargetFeatures::BROWSING;
+        self
+    }
+
+    /// Advertises the cover art service and the PSM of its OBEX channel ([AVRCP] Section 8).
+    pub fn with_cover_art(mut self, psm: u16) -> Self {
+        self.features |= SupportedTargetFeatures::COVER_ART;
+        self.cover_art_psm = Some(psm);
+        self
     }
 }
 
@@ -96,7 +128,7 @@ impl ServiceRecord for AvrcpTargetServiceRecord {
         let avctp_version = 1u16 << 8 | 4u16;
         let avcrp_version = 1u16 << 8 | 6u16;
 
-        vec![
+        let mut attributes = vec![
             ServiceAttribute::new(SERVICE_RECORD_HANDLE_ID, self.handle),
             ServiceAttribute::new(BROWSE_GROUP_LIST_ID, DataElement::from_iter([PUBLIC_BROWSE_ROOT])),
             ServiceAttribute::new(SERVICE_CLASS_ID_LIST_ID, DataElement::from_iter([AV_REMOTE_CONTROL_TARGET])),
@@ -108,14 +140,26 @@ impl ServiceRecord for AvrcpTargetServiceRecord {
                 BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID,
                 DataElement::from_iter([(AV_REMOTE_CONTROL, avcrp_version)])
             ),
-            ServiceAttribute::new(SUPPORTED_FEATURES_ID, SupportedTargetFeatures::CATEGORY_2),
-        ]
+            ServiceAttribute::new(SUPPORTED_FEATURES_ID, self.features),
+        ];
+        let mut additional_protocols = Vec::new();
+        if self.features.contains(SupportedTargetFeatures::BROWSING) {
+            additional_protocols.push(DataElement::from_iter([(L2CAP, AVCTP_BROWSING_PSM), (AVCTP, avctp_version)]));
+        }
+        if let Some(psm) = self.cover_art_psm {
+            additional_protocols.push(DataElement::from_iter([DataElement::from((L2CAP, psm)), DataElement::from_iter([OBEX])]));
+        }
+        if !additional_protocols.is_empty() {
+            attributes.push(ServiceAttribute::new(ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID, additional_protocols));
+        }
+        attributes
     }
 }
 
 // ([AVRCP] Section 8).
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct SupportedTargetFeatures: u16 {
         const CATEGORY_1 = 1 << 0;
         const CATEGORY_2 = 1 << 1;
@@ -134,3 +178,160 @@ impl From<SupportedTargetFeatures> for DataElement {
         DataElement::from(features.bits())
     }
 }
+
+/// The AVRCP capabilities a remote device advertises in its service records.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteFeatures {
+    /// The highest advertised AVRCP version (major << 8 | minor).
+    pub version: Option<u16>,
+    pub controller: Option<SupportedControllerFeatures>,
+    pub target: Option<SupportedTargetFeatures>,
+    pub browsing_psm: Option<u16>,
+    /// The PSM of the OBEX channel of the cover art service ([AVRCP] Section 5.14).
+    pub cover_art_psm: Option<u16>
+}
+
+impl RemoteFeatures {
+    pub fn from_records(records: &[Vec<ServiceAttribute>]) -> Self {
+        let mut features = Self::default();
+        for record in records {
+            let get = |id: u16| record.iter().find(|attribute| attribute.id == id);
+            let Some(classes) = get(SERVICE_CLASS_ID_LIST_ID) else { continue };
+            let supported_features = get(SUPPORTED_FEATURES_ID).and_then(|attribute| attribute.value.as_u16().ok());
+            if classes.contains(AV_REMOTE_CONTROL_TARGET) {
+                features.target = Some(SupportedTargetFeatures::from_bits_truncate(supported_features.unwrap_or_default()));
+            }
+            if classes.contains(AV_REMOTE_CONTROL) || classes.contains(AV_REMOTE_CONTROL_CONTROLLER) {
+                features.controller = Some(SupportedControllerFeatures::from_bits_truncate(supported_features.unwrap_or_default()));
+            }
+            // ([AVRCP] Section 8).
+            if let Some(version) = get(BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID).and_then(|attribute| find_parameter(&attribute.value, AV_REMOTE_CONTROL)) {
+                features.version = features.version.max(Some(version));
+            }
+            // The browsing channel and the cover art service each have their own protocol list
+            let additional = get(ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID).and_then(|attribute| attribute.value.as_sequence().ok());
+            for protocols in additional.unwrap_or_default() {
+                let Some(psm) = find_parameter(protocols, L2CAP) else { continue };
+                match contains_protocol(protocols, OBEX) {
+                    true => features.cover_art_psm = Some(psm),
+                    false => features.browsing_psm = Some(psm)
+                }
+            }
+        }
+        features
+    }
+
+    /// Restores features previously saved with [RemoteFeatures::store].
+    pub fn from_cache(info: &RemoteDeviceInfo) -> Option<Self> {
+        let profile = info.profiles.get(&AV_REMOTE_CONTROL)?;
+        let features = |uuid| info.profiles.get(&uuid).map(|profile| profile.supported_features.unwrap_or_default());
+        Some(Self {
+            version: profile.version,
+            controller: features(AV_REMOTE_CONTROL_CONTROLLER).map(SupportedControllerFeatures::from_bits_truncate),
+            target: features(AV_REMOTE_CONTROL_TARGET).map(SupportedTargetFeatures::from_bits_truncate),
+            browsing_psm: profile.additional_psm,
+            cover_art_psm: info
+                .profiles
+                .get(&AV_REMOTE_CONTROL_TARGET)
+                .and_then(|profile| profile.additional_psm)
+        })
+    }
+
+    pub fn store(&self, info: &mut RemoteDeviceInfo) {
+        info.profiles.insert(AV_REMOTE_CONTROL, RemoteProfile {
+            version: self.version,
+            supported_features: None,
+            additional_psm: self.browsing_psm
+        });
+        let mut role = |uuid, features: Option<u16>, additional_psm| match features {
+            Some(features) => info.profiles.insert(uuid, RemoteProfile {
+                version: self.version,
+                supported_features: Some(features),
+                additional_psm
+            }),
+            None => info.profiles.remove(&uuid)
+        };
+        role(AV_REMOTE_CONTROL_CONTROLLER, self.controller.map(|features| features.bits()), None);
+        // The cover art service belongs to the target
+        role(AV_REMOTE_CONTROL_TARGET, self.target.map(|features| features.bits()), self.cover_art_psm);
+    }
+
+    pub fn has_target(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Whether the peer has a target that advertises all of `features`.
+    pub fn target_supports(&self, features: SupportedTargetFeatures) -> bool {
+        self.target.is_some_and(|target| target.contains(features))
+    }
+
+    pub fn supports_browsing(&self) -> bool {
+        self.browsing_psm.is_some()
+            && self
+                .target
+                .is_some_and(|target| target.contains(SupportedTargetFeatures::BROWSING))
+    }
+
+    /// Whether the target advertises the cover art feature and the PSM of its OBEX channel.
+    pub fn supports_cover_art(&self) -> bool {
+        self.cover_art_psm.is_some()
+            && self
+                .target
+                .is_some_and(|target| target.contains(SupportedTargetFeatures::COVER_ART))
+    }
+}
+
+/// Whether a (nested) protocol descriptor list contains `uuid`.
+fn contains_protocol(element: &DataElement, uuid: Uuid) -> bool {
+    match element.as_sequence() {
+        Ok([DataElement::Uuid(id), ..]) if *id == uuid => true,
+        Ok(sequence) => sequence
+            .iter()
+            .any(|element| contains_protocol(element, uuid)),
+        Err(_) => false
+    }
+}
+
+/// Finds the first `u16` parameter following `uuid` in a (nested) sequence like `[[L2CAP, psm], [AVCTP, version]]`.
+fn find_parameter(element: &DataElement, uuid: Uuid) -> Option<u16> {
+    let sequence = element.as_sequence().ok()?;
+    match sequence {
+        [DataElement::Uuid(id), DataElement::U16(value), ..] if *id == uuid => Some(*value),
+        _ => sequence
+            .iter()
+            .find_map(|element| find_parameter(element, uuid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avrcp::sdp::{RemoteFeatures, SupportedTargetFeatures, SUPPORTED_FEATURES_ID};
+    use crate::sdp::ids::attributes::{ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID, SERVICE_CLASS_ID_LIST_ID};
+    use crate::sdp::ids::protocols::{AVCTP, L2CAP, OBEX};
+    use crate::sdp::ids::service_classes::AV_REMOTE_CONTROL_TARGET;
+    use crate::sdp::{DataElement, ServiceAttribute};
+
+    #[test]
+    fn additional_protocols() {
+        let record = vec![
+            ServiceAttribute::new(SERVICE_CLASS_ID_LIST_ID, DataElement::from_iter([AV_REMOTE_CONTROL_TARGET])),
+            ServiceAttribute::new(SUPPORTED_FEATURES_ID, SupportedTargetFeatures::BROWSING | SupportedTargetFeatures::COVER_ART),
+            ServiceAttribute::new(
+                ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID,
+                DataElement::from_iter([
+                    DataElement::from_iter([(L2CAP, 0x001Bu16), (AVCTP, 0x0104u16)]),
+                    DataElement::from_iter([
+                        DataElement::from((L2CAP, 0x1005u16)),
+                        DataElement::from_iter([OBEX])
+                    ])
+                ])
+            ),
+        ];
+        let features = RemoteFeatures::from_records(&[record]);
+        assert_eq!(features.browsing_psm, Some(0x001B));
+        assert_eq!(features.cover_art_psm, Some(0x1005));
+        assert!(features.supports_browsing() && features.supports_cover_art());
+        assert!(!features.target_supports(SupportedTargetFeatures::SETTINGS));
+    }
+}
@@ -1,17 +1,33 @@
 use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::future::Future;
+use std::future::pending;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use instructor::{BigEndian, Buffer, BufferMut, Exstruct};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::select;
 use tokio::sync::oneshot::Sender as OneshotSender;
+use tokio::time::Instant;
+use tracing::warn;
 
 use crate::avc::{CommandCode, PassThroughFrame, PassThroughOp, PassThroughState};
+use crate::avrcp::browsing::UidTracker;
+use crate::avrcp::artwork::{Artwork, LocalArtwork};
+use crate::avrcp::charset;
+use crate::avrcp::cover_art::{CoverArt, CoverArtFormat};
 use crate::avrcp::error::Error;
-use crate::avrcp::packets::{EventId, MediaAttributeId, Pdu, EVENTS_SUPPORTED_CAPABILITY};
+use crate::avrcp::notifications::{PlaybackPosition, PlaybackStatus};
+use crate::avrcp::MAX_VOLUME;
+use crate::avrcp::sdp::{RemoteFeatures, SupportedTargetFeatures};
+use crate::avrcp::settings::{PlayerSetting, PlayerSettingAttribute};
+use crate::avrcp::subscriptions::{EventFilter, EventSubscription, Subscribers};
+use crate::avrcp::packets::{BatteryStatus, EventId, MediaAttributeId, Pdu, EVENTS_SUPPORTED_CAPABILITY};
 use crate::ensure;
+use crate::hci::consts::BdAddr;
+use crate::utils::clock::{now, sleep_until, Timestamped};
 use crate::utils::FromStruct;
 
 pub type CommandResponseSender = OneshotSender<Result<Bytes, Error>>;
@@ -19,8 +35,14 @@ pub type CommandResponseSender = OneshotSender<Result<Bytes, Error>>;
 pub enum AvrcpCommand {
     PassThrough(PassThroughOp, PassThroughState, CommandResponseSender),
     VendorSpecific(CommandCode, Pdu, Bytes, CommandResponseSender),
-    RegisterNotification(EventId, u32, EventParser, CommandResponseSender),
-    UpdatedVolume(f32)
+    /// The flag registers the notification again after every change.
+    RegisterNotification(EventId, u32, EventParser, bool, CommandResponseSender),
+    UpdatedVolume(f32),
+    UpdatedVolumeSteps(u8),
+    UpdatedMediaAttributes(BTreeMap<MediaAttributeId, String>),
+    UpdatedPlayStatus(Timestamped<PlayStatus>),
+    /// A command of the browsing channel, answered with the parameters of the response.
+    Browsing(Pdu, Bytes, CommandResponseSender)
 }
 
 impl AvrcpCommand {
@@ -28,29 +50,112 @@ impl AvrcpCommand {
         match self {
             AvrcpCommand::PassThrough(_, _, tx) => Some(tx),
             AvrcpCommand::VendorSpecific(_, _, _, tx) => Some(tx),
-            AvrcpCommand::RegisterNotification(_, _, _, tx) => Some(tx),
+            AvrcpCommand::RegisterNotification(_, _, _, _, tx) => Some(tx),
+            AvrcpCommand::Browsing(_, _, tx) => Some(tx),
             _ => None
         }
     }
 }
 
 pub struct AvrcpSession {
-    pub(super) commands: Sender<AvrcpCommand>,
-    pub(super) events: Receiver<Event>
+    pub(super) controller: AvrcpController,
+    pub(super) events: Receiver<Timestamped<Event>>,
+    pub(super) interpolator: Option<PositionInterpolator>
 }
 
 impl Debug for AvrcpSession {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("AvrcpSession").finish()
+        f.debug_struct("AvrcpSession")
+            .field("addr", &self.controller.addr)
+            .finish()
     }
 }
 
 impl AvrcpSession {
-    pub fn next_event(&mut self) -> impl Future<Output = Option<Event>> + '_ {
-        self.events.recv()
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.next_timestamped_event().await.map(|event| event.value)
+    }
+
+    /// Like [AvrcpSession::next_event] with the time the event was received from the peer.
+    /// Interpolated positions are stamped with the time of the estimate.
+    pub async fn next_timestamped_event(&mut self) -> Option<Timestamped<Event>> {
+        let Some(interpolator) = self.interpolator.as_mut() else {
+            return self.events.recv().await;
+        };
+        let event = select! {
+            event = self.events.recv() => event?,
+            position = interpolator.next_estimate() => return Some(Timestamped::now(Event::PlaybackPositionChanged(position)))
+        };
+        interpolator.update(&event.value);
+        Some(event)
+    }
+
+    /// Synthesizes position events every `interval` while playing and the peer does not report the position itself.
+    /// Requires subscriptions to [PlaybackStatus] and [PlaybackPosition](notifications::PlaybackPosition) changes,
+    /// as the estimate is based on the last reported position and playback status.
+    pub fn enable_position_interpolation(&mut self, interval: Duration) {
+        self.interpolator = Some(PositionInterpolator::new(interval));
+    }
+
+    /// A handle that can send commands to the peer independently of this session, e.g. from an IPC server.
+    pub fn controller(&self) -> AvrcpController {
+        self.controller.clone()
+    }
+}
+
+impl Deref for AvrcpSession {
+    type Target = AvrcpController;
+
+    fn deref(&self) -> &Self::Target {
+        &self.controller
+    }
+}
+
+/// Sends commands to the peer of an [AvrcpSession]. All clones stop working once the session is closed.
+#[derive(Clone)]
+pub struct AvrcpController {
+    pub(super) commands: Sender<AvrcpCommand>,
+    pub(super) addr: BdAddr,
+    pub(super) uids: Arc<UidTracker>,
+    pub(super) cover_art: Arc<CoverArt>,
+    pub(super) artwork: Arc<LocalArtwork>,
+    pub(super) subscribers: Subscribers,
+    /// Set before the session is handed out if feature discovery was enabled and succeeded.
+    pub(super) remote_features: Arc<OnceLock<RemoteFeatures>>
+}
+
+impl Debug for AvrcpController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AvrcpController")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl AvrcpController {
+    pub fn remote_addr(&self) -> BdAddr {
+        self.addr
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// The features advertised by the peer, if feature discovery was enabled and succeeded.
+    pub fn remote_features(&self) -> Option<&RemoteFeatures> {
+        self.remote_features.get()
     }
 
-    async fn send_vendor_cmd(&self, code: CommandCode, pdu: Pdu, parameters: Bytes) -> Result<Bytes, Error> {
+    /// Fails with [Error::NotSupported] if the peer is known to lack the target `feature`.
+    /// Without discovered features, the peer is assumed to support everything.
+    fn require(&self, feature: SupportedTargetFeatures) -> Result<(), Error> {
+        if let Some(features) = self.remote_features() {
+            ensure!(features.target_supports(feature), Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    pub(super) async fn send_vendor_cmd(&self, code: CommandCode, pdu: Pdu, parameters: Bytes) -> Result<Bytes, Error> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.commands
             .send(AvrcpCommand::VendorSpecific(code, pdu, parameters, tx))
@@ -59,6 +164,22 @@ impl AvrcpSession {
         rx.await.map_err(|_| Error::SessionClosed)?
     }
 
+    /// Sends the vendor dependent command `pdu_id` with raw `parameters` and returns the parameters of the response,
+    /// e.g. to send a PDU the qualification tester asks for. Fails with [Error::NotImplemented] for unknown PDUs.
+    #[cfg(feature = "pts")]
+    pub async fn send_raw_command(&self, code: CommandCode, pdu_id: u8, parameters: Bytes) -> Result<Bytes, Error> {
+        let pdu: Pdu = [pdu_id].as_slice().read_be().map_err(|_| Error::NotImplemented)?;
+        self.send_vendor_cmd(code, pdu, parameters).await
+    }
+
+    /// Fetches the image `handle` from the cover art service of the target ([AVRCP] Section 5.14), e.g. the
+    /// [MediaAttributeId::DefaultCoverArt] of the current track. Opens the OBEX connection if it isn't open yet, which
+    /// requires the feature discovery to find the service. Targets only report image handles while it is open,
+    /// see [Avrcp::with_cover_art](crate::avrcp::Avrcp::with_cover_art).
+    pub async fn get_cover_art(&self, handle: &str, format: CoverArtFormat) -> Result<Bytes, Error> {
+        self.cover_art.get(handle, format).await
+    }
+
     async fn send_action(&self, op: PassThroughOp, state: PassThroughState) -> Result<(), Error> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.commands
@@ -78,6 +199,68 @@ impl AvrcpSession {
             .map_err(|_| Error::SessionClosed)
     }
 
+    /// Like [AvrcpController::notify_local_volume_change] with the raw step (`0..=`[MAX_VOLUME]).
+    pub async fn notify_local_volume_steps(&self, steps: u8) -> Result<(), Error> {
+        self.commands
+            .send(AvrcpCommand::UpdatedVolumeSteps(steps))
+            .await
+            .map_err(|_| Error::SessionClosed)
+    }
+
+    /// Replaces the metadata of the local track that the peer reads when it controls us ([AVRCP] Section 6.6.1).
+    /// Strings are converted to a character set the peer announced it can display, e.g. UCS-2 for older head units.
+    /// Different attributes count as a new track, which completes the track notification of the peer.
+    pub async fn set_local_media_attributes(&self, attributes: BTreeMap<MediaAttributeId, String>) -> Result<(), Error> {
+        self.commands
+            .send(AvrcpCommand::UpdatedMediaAttributes(attributes))
+            .await
+            .map_err(|_| Error::SessionClosed)
+    }
+
+    /// Replaces the playback status of the local player that the peer reads with GetPlayStatus ([AVRCP] Section 6.7.1).
+    /// The position advances from the time of the call while playing, so it only has to be set on seeks and state changes.
+    /// A different [PlaybackStatus] completes the playback status notification of the peer.
+    pub async fn set_local_play_status(&self, status: PlayStatus) -> Result<(), Error> {
+        self.commands
+            .send(AvrcpCommand::UpdatedPlayStatus(Timestamped::now(status)))
+            .await
+            .map_err(|_| Error::SessionClosed)
+    }
+
+    /// Receives the events of the session that pass `filter` in a separate queue of `capacity` events. These events
+    /// are no longer reported by [AvrcpSession::next_event], so a session that is only read through subscriptions
+    /// doesn't fill up. Events that don't fit into the queue are dropped for this subscription only.
+    /// Interpolated positions are only reported by the session itself. The subscription ends with the session.
+    pub fn subscribe_events(&self, filter: EventFilter, capacity: usize) -> EventSubscription {
+        self.subscribers.subscribe(filter, capacity)
+    }
+
+    /// Replaces the artwork of the local track that the peer fetches over the cover art service, see
+    /// [Avrcp::with_local_cover_art](crate::avrcp::Avrcp::with_local_cover_art). Each artwork gets a new image handle,
+    /// which the peer reads with the metadata as [MediaAttributeId::DefaultCoverArt].
+    pub fn set_local_cover_art(&self, artwork: Option<Artwork>) {
+        self.artwork.set(artwork);
+    }
+
+    /// Asks the peer to change its volume and returns the volume it actually applied ([AVRCP] Section 6.13.2).
+    pub async fn set_absolute_volume(&self, volume: f32) -> Result<f32, Error> {
+        let steps = notifications::Volume(volume).steps();
+        let steps = self.set_absolute_volume_steps(steps).await?;
+        Ok(notifications::Volume::from_steps(steps).0)
+    }
+
+    /// Like [AvrcpController::set_absolute_volume] with the raw step (`0..=`[MAX_VOLUME]).
+    pub async fn set_absolute_volume_steps(&self, steps: u8) -> Result<u8, Error> {
+        // Absolute volume is part of category 2 ([AVRCP] Section 6.13)
+        self.require(SupportedTargetFeatures::CATEGORY_2)?;
+        let mut result = self
+            .send_vendor_cmd(CommandCode::Control, Pdu::SetAbsoluteVolume, Bytes::from_struct_be(steps.min(MAX_VOLUME)))
+            .await?;
+        let steps: u8 = result.read_be()?;
+        result.finish()?;
+        Ok(steps & MAX_VOLUME)
+    }
+
     pub async fn action(&self, op: PassThroughOp) -> Result<(), Error> {
         self.send_action(op, PassThroughState::Pressed)
             .await?;
@@ -103,12 +286,90 @@ impl AvrcpSession {
         Ok(events)
     }
 
+    /// The player application settings the peer supports ([AVRCP] Section 6.5.1).
+    pub async fn list_player_setting_attributes(&self) -> Result<Vec<PlayerSettingAttribute>, Error> {
+        self.require(SupportedTargetFeatures::SETTINGS)?;
+        let mut result = self
+            .send_vendor_cmd(CommandCode::Status, Pdu::ListPlayerApplicationSettingAttributes, Bytes::new())
+            .await?;
+        let count: u8 = result.read_be()?;
+        let attributes = (0..count)
+            .map(|_| result.read_be::<u8>().map(PlayerSettingAttribute::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        result.finish()?;
+        Ok(attributes)
+    }
+
+    /// The values the peer supports for the setting `attribute` ([AVRCP] Section 6.5.2).
+    pub async fn list_player_setting_values(&self, attribute: PlayerSettingAttribute) -> Result<Vec<PlayerSetting>, Error> {
+        self.require(SupportedTargetFeatures::SETTINGS)?;
+        let mut result = self
+            .send_vendor_cmd(CommandCode::Status, Pdu::ListPlayerApplicationSettingValues, Bytes::from_struct_be(attribute.id()))
+            .await?;
+        let count: u8 = result.read_be()?;
+        let values = (0..count)
+            .map(|_| result.read_be::<u8>().map(|value| PlayerSetting::from_ids(attribute.id(), value)))
+            .collect::<Result<Vec<_>, _>>()?;
+        result.finish()?;
+        Ok(values)
+    }
+
+    /// The current values of the settings `attributes` of the peer ([AVRCP] Section 6.5.3).
+    pub async fn get_player_settings(&self, attributes: &[PlayerSettingAttribute]) -> Result<Vec<PlayerSetting>, Error> {
+        self.require(SupportedTargetFeatures::SETTINGS)?;
+        let mut buffer = BytesMut::new();
+        buffer.write_be(attributes.len() as u8);
+        for attribute in attributes {
+            buffer.write_be(attribute.id());
+        }
+        let mut result = self
+            .send_vendor_cmd(CommandCode::Status, Pdu::GetCurrentPlayerApplicationSettingValue, buffer.freeze())
+            .await?;
+        let settings: notifications::PlayerApplicationSettings = result.read_be()?;
+        result.finish()?;
+        Ok(settings.0)
+    }
+
+    /// Changes the settings of the peer, e.g. turns shuffle on with [PlayerSetting::Shuffle] ([AVRCP] Section 6.5.4).
+    pub async fn set_player_settings(&self, settings: &[PlayerSetting]) -> Result<(), Error> {
+        self.require(SupportedTargetFeatures::SETTINGS)?;
+        let mut buffer = BytesMut::new();
+        buffer.write_be(settings.len() as u8);
+        for setting in settings {
+            buffer.write_be(setting.ids());
+        }
+        let mut result = self
+            .send_vendor_cmd(CommandCode::Control, Pdu::SetPlayerApplicationSettingValue, buffer.freeze())
+            .await?;
+        result.finish()?;
+        Ok(())
+    }
+
+    /// Registers for a single change of `N`, which is delivered through [AvrcpSession::next_event].
+    /// Returns the current value.
     pub async fn register_notification<N: Notification>(&self, playback_interval: Option<Duration>) -> Result<N, Error> {
+        self.register::<N>(playback_interval, false).await
+    }
+
+    /// Subscribes to position changes, which the peer should report every `interval` while playing.
+    /// The interval has a resolution of one second ([AVRCP] Section 6.7.2), shorter intervals are rounded up.
+    pub async fn subscribe_position(&self, interval: Duration) -> Result<notifications::PlaybackPosition, Error> {
+        let interval = Duration::from_secs(interval.as_secs_f64().ceil().max(1.0) as u64);
+        self.subscribe(Some(interval)).await
+    }
+
+    /// Like [AvrcpController::register_notification], but registers the notification again after every change,
+    /// so that all changes are delivered until the session is closed.
+    pub async fn subscribe<N: Notification>(&self, playback_interval: Option<Duration>) -> Result<N, Error> {
+        self.register::<N>(playback_interval, true).await
+    }
+
+    async fn register<N: Notification>(&self, playback_interval: Option<Duration>, rearm: bool) -> Result<N, Error> {
         assert!(N::EVENT_ID != EventId::PlaybackPosChanged || playback_interval.is_some(), "PlaybackPosChanged requires an interval");
         let (tx, rx) = tokio::sync::oneshot::channel();
         let int = playback_interval.map_or(0, |interval| interval.as_secs() as u32);
         self.commands
-            .send(AvrcpCommand::RegisterNotification(N::EVENT_ID, int, N::read, tx))
+            .send(AvrcpCommand::RegisterNotification(N::EVENT_ID, int, N::read, rearm, tx))
             .await
             .map_err(|_| Error::SessionClosed)?;
         let mut result = rx.await.map_err(|_| Error::SessionClosed)??;
@@ -123,7 +384,7 @@ impl AvrcpSession {
         &self, filter: Option<&[MediaAttributeId]>
     ) -> Result<BTreeMap<MediaAttributeId, String>, Error> {
         const PLAYING: u64 = 0x00;
-        const UTF8: u16 = 106;
+        const ATTRIBUTE_HEADER_SIZE: usize = 8;
         debug_assert!(filter.map_or(true, |filter| !filter.is_empty()), "Filter should not be empty");
         let mut buffer = BytesMut::new();
         buffer.write_be(PLAYING);
@@ -142,16 +403,90 @@ impl AvrcpSession {
         let number_of_attributes: u8 = result.read_be()?;
         let mut results = BTreeMap::new();
         for _ in 0..number_of_attributes {
+            // The response might have been cut off because of the configured size limit
+            if result.len() < ATTRIBUTE_HEADER_SIZE {
+                warn!("Media attributes response is truncated, returning partial results");
+                break;
+            }
             let id: MediaAttributeId = result.read_be()?;
-            ensure!(result.read_be::<u16>()? == UTF8, Error::InvalidReturnData);
+            let charset: u16 = result.read_be()?;
             let length: u16 = result.read_be()?;
-            let value = result.split_to(length as usize);
-            results.insert(id, String::from_utf8_lossy(&value).to_string());
+            let value = result.split_to(result.len().min(length as usize));
+            results.insert(id, charset::decode(&value, charset));
         }
         Ok(results)
     }
 }
 
+/// Estimates the playback position between the reports of the peer.
+pub(super) struct PositionInterpolator {
+    interval: Duration,
+    playing: bool,
+    /// The last known position and when it was valid.
+    anchor: Option<(Duration, Instant)>,
+    last_report: Option<Instant>,
+    warned: bool
+}
+
+impl PositionInterpolator {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            playing: false,
+            anchor: None,
+            last_report: None,
+            warned: false
+        }
+    }
+
+    fn estimate(&self, now: Instant) -> Option<Duration> {
+        let (position, time) = self.anchor?;
+        Some(match self.playing {
+            true => position + now.saturating_duration_since(time),
+            false => position
+        })
+    }
+
+    fn update(&mut self, event: &Event) {
+        let now = now();
+        match event {
+            Event::PlaybackStatusChanged(status) => {
+                self.anchor = self.estimate(now).map(|position| (position, now));
+                self.playing = *status == PlaybackStatus::Playing;
+            }
+            Event::PlaybackPositionChanged(PlaybackPosition::Position(position)) => {
+                if let Some(last) = self.last_report {
+                    // Some peers ignore the requested interval and only report on seeks or status changes
+                    if self.playing && !self.warned && now - last > self.interval * 2 {
+                        warn!("Peer reports the playback position less often than every {:?}, interpolating", self.interval);
+                        self.warned = true;
+                    }
+                }
+                self.anchor = Some((*position, now));
+                self.last_report = Some(now);
+            }
+            Event::PlaybackPositionChanged(PlaybackPosition::NotSelected) | Event::TrackChanged(_) => {
+                self.anchor = None;
+                self.last_report = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Resolves with the estimated position once the peer missed a report.
+    async fn next_estimate(&mut self) -> PlaybackPosition {
+        let Some((_, time)) = self.anchor.filter(|_| self.playing) else {
+            return pending().await;
+        };
+        // Allow for some latency before stepping in for the peer
+        let deadline = time + self.interval + self.interval / 4;
+        sleep_until(deadline).await;
+        let position = self.estimate(deadline).unwrap_or_default();
+        self.anchor = Some((position, deadline));
+        PlaybackPosition::Position(position)
+    }
+}
+
 pub type EventParser = fn(&mut Bytes) -> Result<Event, instructor::Error>;
 pub trait Notification: Exstruct<BigEndian> + Into<Event> {
     const EVENT_ID: EventId;
@@ -162,12 +497,61 @@ pub trait Notification: Exstruct<BigEndian> + Into<Event> {
     }
 }
 
+/// The playback status of the local player, see [AvrcpController::set_local_play_status].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PlayStatus {
+    pub status: PlaybackStatus,
+    /// The length of the track, `None` if it is unknown.
+    pub length: Option<Duration>,
+    /// The position when the status was set, `None` if it is unknown.
+    pub position: Option<Duration>
+}
+
+impl PlayStatus {
+    /// The position `elapsed` after the status was set, never beyond the end of the track.
+    pub(super) fn position_after(&self, elapsed: Duration) -> Option<Duration> {
+        let position = match self.status {
+            PlaybackStatus::Playing => self.position? + elapsed,
+            _ => self.position?
+        };
+        Some(self.length.map_or(position, |length| position.min(length)))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Event {
     TrackChanged(notifications::CurrentTrack),
     PlaybackStatusChanged(notifications::PlaybackStatus),
     PlaybackPositionChanged(notifications::PlaybackPosition),
-    VolumeChanged(f32)
+    VolumeChanged(f32, VolumeOrigin),
+    /// The peer pressed or released a button while controlling us ([AVRCP] Section 4.6.1).
+    PassThrough(PassThroughOp, PassThroughState),
+    /// The media database of the peer changed, UIDs of earlier listings are no longer valid ([AVRCP] Section 6.10.3.3).
+    UidsChanged(u16),
+    /// The character sets (IANA MIBenum) the peer can display ([AVRCP] Section 6.5.7).
+    DisplayableCharacterSets(Vec<u16>),
+    /// The battery status of the peer while it controls us ([AVRCP] Section 6.5.8).
+    ControllerBatteryStatus(BatteryStatus),
+    /// The changed player application settings of the peer ([AVRCP] Section 6.5).
+    PlayerApplicationSettingsChanged(Vec<PlayerSetting>),
+    /// The content of the now playing list of the peer changed ([AVRCP] Section 6.9.4).
+    NowPlayingContentChanged,
+    /// A player of the peer was added or removed ([AVRCP] Section 6.9.4).
+    AvailablePlayersChanged,
+    /// The peer addresses commands to another player ([AVRCP] Section 6.9.2).
+    AddressedPlayerChanged(notifications::AddressedPlayer)
+}
+
+/// Who changed the volume of an [Event::VolumeChanged].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VolumeOrigin {
+    /// The user changed the volume on the peer.
+    Remote,
+    /// The peer confirms a volume the application set shortly before with
+    /// [AvrcpController::notify_local_volume_change] or [AvrcpController::set_absolute_volume].
+    Local
 }
 
 pub mod notifications {
@@ -176,9 +560,11 @@ pub mod notifications {
 
     use crate::avrcp::packets::EventId;
     use crate::avrcp::session::Notification;
-    use crate::avrcp::Event;
+    use crate::avrcp::settings::PlayerSetting;
+    use crate::avrcp::{Event, VolumeOrigin, MAX_VOLUME};
 
     #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum CurrentTrack {
         #[default]
         NotSelected,
@@ -208,6 +594,7 @@ pub mod notifications {
     }
 
     #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[repr(u8)]
     pub enum PlaybackStatus {
         #[default]
@@ -231,6 +618,7 @@ pub mod notifications {
     }
 
     #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum PlaybackPosition {
         #[default]
         NotSelected,
@@ -266,4 +654,137 @@ pub mod notifications {
         const EVENT_ID: EventId = EventId::PlaybackPosChanged;
     }
 
+    /// The absolute volume of the peer ([AVRCP] Section 6.13.3).
+    #[derive(Default, Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Volume(pub f32);
+
+    impl Volume {
+        pub fn from_steps(steps: u8) -> Self {
+            Self(steps.min(MAX_VOLUME) as f32 / MAX_VOLUME as f32)
+        }
+
+        /// The nearest of the 128 absolute volume steps.
+        pub fn steps(self) -> u8 {
+            (self.0.clamp(0.0, 1.0) * MAX_VOLUME as f32).round() as u8
+        }
+    }
+
+    impl Exstruct<BigEndian> for Volume {
+        fn read_from_buffer<B: Buffer>(buffer: &mut B) -> Result<Self, Error> {
+            let volume: u8 = buffer.read_be()?;
+            Ok(Self::from_steps(volume & MAX_VOLUME))
+        }
+    }
+
+    impl From<Volume> for Event {
+        fn from(event: Volume) -> Self {
+            Self::VolumeChanged(event.0, VolumeOrigin::Remote)
+        }
+    }
+
+    impl Notification for Volume {
+        const EVENT_ID: EventId = EventId::VolumeChanged;
+    }
+
+    /// The UID counter of the browsed player ([AVRCP] Section 6.10.3.3).
+    #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[instructor(endian = "big")]
+    pub struct UidCounter(pub u16);
+
+    impl From<UidCounter> for Event {
+        fn from(event: UidCounter) -> Self {
+            Self::UidsChanged(event.0)
+        }
+    }
+
+    impl Notification for UidCounter {
+        const EVENT_ID: EventId = EventId::UidsChanged;
+    }
+
+    /// The changed player application settings ([AVRCP] Section 6.7.2).
+    #[derive(Default, Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct PlayerApplicationSettings(pub Vec<PlayerSetting>);
+
+    impl Exstruct<BigEndian> for PlayerApplicationSettings {
+        fn read_from_buffer<B: Buffer>(buffer: &mut B) -> Result<Self, Error> {
+            let count: u8 = buffer.read_be()?;
+            let settings = (0..count)
+                .map(|_| Ok(PlayerSetting::from_ids(buffer.read_be()?, buffer.read_be()?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(Self(settings))
+        }
+    }
+
+    impl From<PlayerApplicationSettings> for Event {
+        fn from(event: PlayerApplicationSettings) -> Self {
+            Self::PlayerApplicationSettingsChanged(event.0)
+        }
+    }
+
+    impl Notification for PlayerApplicationSettings {
+        const EVENT_ID: EventId = EventId::PlayerApplicationSettingChanged;
+    }
+
+    /// The content of the now playing list changed, the notification has no value ([AVRCP] Section 6.9.4).
+    #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct NowPlayingContent;
+
+    impl Exstruct<BigEndian> for NowPlayingContent {
+        fn read_from_buffer<B: Buffer>(_buffer: &mut B) -> Result<Self, Error> {
+            Ok(Self)
+        }
+    }
+
+    impl From<NowPlayingContent> for Event {
+        fn from(_: NowPlayingContent) -> Self {
+            Self::NowPlayingContentChanged
+        }
+    }
+
+    impl Notification for NowPlayingContent {
+        const EVENT_ID: EventId = EventId::NowPlayingContentChanged;
+    }
+
+    /// A player was added or removed, the notification has no value ([AVRCP] Section 6.9.4).
+    #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct AvailablePlayers;
+
+    impl Exstruct<BigEndian> for AvailablePlayers {
+        fn read_from_buffer<B: Buffer>(_buffer: &mut B) -> Result<Self, Error> {
+            Ok(Self)
+        }
+    }
+
+    impl From<AvailablePlayers> for Event {
+        fn from(_: AvailablePlayers) -> Self {
+            Self::AvailablePlayersChanged
+        }
+    }
+
+    impl Notification for AvailablePlayers {
+        const EVENT_ID: EventId = EventId::AvailablePlayerChanged;
+    }
+
+    /// The player that receives the commands and the UID counter of its media database ([AVRCP] Section 6.9.2).
+    #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Exstruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[instructor(endian = "big")]
+    pub struct AddressedPlayer {
+        pub player_id: u16,
+        pub uid_counter: u16
+    }
+
+    impl From<AddressedPlayer> for Event {
+        fn from(event: AddressedPlayer) -> Self {
+            Self::AddressedPlayerChanged(event)
+        }
+    }
+
+    impl Notification for AddressedPlayer {
+        const EVENT_ID: EventId = EventId::AddressedPlayerChanged;
+    }
+
 }
@@ -0,0 +1,145 @@
+//! The player application settings of the peer, e.g. its shuffle and repeat modes ([AVRCP] Section 6.5, Appendix F).
+
+/// The attribute ids of a player application setting ([AVRCP] Appendix F).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerSettingAttribute {
+    Equalizer,
+    Repeat,
+    Shuffle,
+    Scan,
+    /// A player specific setting (`0x80..=0xFF`) or one of a later version.
+    Other(u8)
+}
+
+impl PlayerSettingAttribute {
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Equalizer => 0x01,
+            Self::Repeat => 0x02,
+            Self::Shuffle => 0x03,
+            Self::Scan => 0x04,
+            Self::Other(id) => id
+        }
+    }
+}
+
+impl From<u8> for PlayerSettingAttribute {
+    fn from(id: u8) -> Self {
+        match id {
+            0x01 => Self::Equalizer,
+            0x02 => Self::Repeat,
+            0x03 => Self::Shuffle,
+            0x04 => Self::Scan,
+            id => Self::Other(id)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum RepeatMode {
+    Off = 0x01,
+    SingleTrack = 0x02,
+    AllTracks = 0x03,
+    Group = 0x04
+}
+
+/// The modes of the shuffle and the scan setting.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum PlaybackOrder {
+    Off = 0x01,
+    AllTracks = 0x02,
+    Group = 0x03
+}
+
+impl PlaybackOrder {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(Self::Off),
+            0x02 => Some(Self::AllTracks),
+            0x03 => Some(Self::Group),
+            _ => None
+        }
+    }
+}
+
+/// The value of a player application setting.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerSetting {
+    Equalizer(bool),
+    Repeat(RepeatMode),
+    Shuffle(PlaybackOrder),
+    Scan(PlaybackOrder),
+    /// A player specific setting or a value this version doesn't know.
+    Other(u8, u8)
+}
+
+impl PlayerSetting {
+    pub fn from_ids(attribute: u8, value: u8) -> Self {
+        let setting = match PlayerSettingAttribute::from(attribute) {
+            PlayerSettingAttribute::Equalizer => match value {
+                0x01 => Some(Self::Equalizer(false)),
+                0x02 => Some(Self::Equalizer(true)),
+                _ => None
+            },
+            PlayerSettingAttribute::Repeat => match value {
+                0x01 => Some(Self::Repeat(RepeatMode::Off)),
+                0x02 => Some(Self::Repeat(RepeatMode::SingleTrack)),
+                0x03 => Some(Self::Repeat(RepeatMode::AllTracks)),
+                0x04 => Some(Self::Repeat(RepeatMode::Group)),
+                _ => None
+            },
+            PlayerSettingAttribute::Shuffle => PlaybackOrder::from_id(value).map(Self::Shuffle),
+            PlayerSettingAttribute::Scan => PlaybackOrder::from_id(value).map(Self::Scan),
+            PlayerSettingAttribute::Other(_) => None
+        };
+        setting.unwrap_or(Self::Other(attribute, value))
+    }
+
+    pub fn attribute(self) -> PlayerSettingAttribute {
+        match self {
+            Self::Equalizer(_) => PlayerSettingAttribute::Equalizer,
+            Self::Repeat(_) => PlayerSettingAttribute::Repeat,
+            Self::Shuffle(_) => PlayerSettingAttribute::Shuffle,
+            Self::Scan(_) => PlayerSettingAttribute::Scan,
+            Self::Other(attribute, _) => PlayerSettingAttribute::from(attribute)
+        }
+    }
+
+    /// The attribute id and the value id.
+    pub fn ids(self) -> (u8, u8) {
+        let value = match self {
+            Self::Equalizer(enabled) => 0x01 + enabled as u8,
+            Self::Repeat(mode) => mode as u8,
+            Self::Shuffle(order) | Self::Scan(order) => order as u8,
+            Self::Other(_, value) => value
+        };
+        (self.attribute().id(), value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avrcp::settings::{PlaybackOrder, PlayerSetting, RepeatMode};
+
+    #[test]
+    fn ids() {
+        for setting in [
+            PlayerSetting::Equalizer(true),
+            PlayerSetting::Repeat(RepeatMode::AllTracks),
+            PlayerSetting::Shuffle(PlaybackOrder::Off),
+            PlayerSetting::Scan(PlaybackOrder::Group),
+            PlayerSetting::Other(0x80, 0x01)
+        ] {
+            let (attribute, value) = setting.ids();
+            assert_eq!(PlayerSetting::from_ids(attribute, value), setting);
+        }
+        assert_eq!(PlayerSetting::Shuffle(PlaybackOrder::AllTracks).ids(), (0x03, 0x02));
+        assert_eq!(PlayerSetting::from_ids(0x02, 0x09), PlayerSetting::Other(0x02, 0x09));
+    }
+}
@@ -0,0 +1,112 @@
+//! A debug mode of the target that deliberately delays or fragments its responses, to reproduce interop issues
+//! reported against specific controllers without owning them. Only the responses to vendor dependent commands are
+//! affected, pass-through and browsing responses are sent as usual.
+use std::time::Duration;
+
+use tracing::debug;
+
+/// How a response is altered.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ResponseFault {
+    /// Holds the response back this long. The session processes nothing else in the meantime, like a busy target.
+    pub delay: Duration,
+    /// Splits the response into fragments of at most this many parameter bytes ([AVRCP] Section 6.3.1),
+    /// even if it would fit into a single packet.
+    pub fragment_size: Option<usize>,
+    /// The pause between two fragments of the response.
+    pub fragment_gap: Duration
+}
+
+/// Which of the matching responses are altered.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Pattern {
+    Always,
+    /// Only the first `n` responses of the session.
+    First(u32),
+    /// Every `n`-th response, starting with the `n`-th.
+    Every(u32)
+}
+
+impl Pattern {
+    fn matches(self, count: u32) -> bool {
+        match self {
+            Pattern::Always => true,
+            Pattern::First(n) => count <= n,
+            Pattern::Every(n) => n != 0 && count % n == 0
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pdu_id: Option<u8>,
+    pattern: Pattern,
+    fault: ResponseFault,
+    /// How many responses matched the PDU so far.
+    count: u32
+}
+
+/// The rules for altering the responses of the target. Every session starts counting from zero.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseShaping {
+    rules: Vec<Rule>
+}
+
+impl ResponseShaping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `fault` to the responses to the PDU `pdu_id` (any PDU if `None`) selected by `pattern`.
+    /// Rules are checked in the order they were added, the first rule that selects a response wins.
+    pub fn with_rule(mut self, pdu_id: Option<u8>, pattern: Pattern, fault: ResponseFault) -> Self {
+        self.rules.push(Rule {
+            pdu_id,
+            pattern,
+            fault,
+            count: 0
+        });
+        self
+    }
+
+    pub(super) fn next(&mut self, pdu_id: u8) -> Option<ResponseFault> {
+        let mut selected = None;
+        for rule in self.rules.iter_mut().filter(|rule| rule.pdu_id.map_or(true, |id| id == pdu_id)) {
+            rule.count += 1;
+            if selected.is_none() && rule.pattern.matches(rule.count) {
+                selected = Some(rule.fault);
+            }
+        }
+        if let Some(fault) = selected {
+            debug!("Altering the response to pdu {:#04x}: {:?}", pdu_id, fault);
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::avrcp::shaping::{Pattern, ResponseFault, ResponseShaping};
+
+    #[test]
+    fn patterns() {
+        let delay = ResponseFault {
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let fragment = ResponseFault {
+            fragment_size: Some(8),
+            ..Default::default()
+        };
+        let mut shaping = ResponseShaping::new()
+            .with_rule(Some(0x20), Pattern::Every(2), fragment)
+            .with_rule(None, Pattern::First(2), delay);
+        assert_eq!(shaping.next(0x20), Some(delay));
+        assert_eq!(shaping.next(0x20), Some(fragment));
+        assert_eq!(shaping.next(0x31), None);
+        assert_eq!(shaping.next(0x20), None);
+        assert_eq!(shaping.next(0x20), Some(fragment));
+    }
+}
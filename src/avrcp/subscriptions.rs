@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use bitflags::bitflags;
+use parking_lot::Mutex;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::trace;
+
+use crate::avrcp::session::Event;
+use crate::utils::clock::Timestamped;
+
+bitflags! {
+    /// The kinds of [Event]s an [EventSubscription] receives.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct EventFilter: u16 {
+        const TRACK = 1 << 0;
+        const PLAYBACK_STATUS = 1 << 1;
+        const PLAYBACK_POSITION = 1 << 2;
+        const VOLUME = 1 << 3;
+        const PASS_THROUGH = 1 << 4;
+        const UIDS = 1 << 5;
+        const CHARACTER_SETS = 1 << 6;
+        const BATTERY_STATUS = 1 << 7;
+        const PLAYER_SETTINGS = 1 << 8;
+        const NOW_PLAYING = 1 << 9;
+        const PLAYERS = 1 << 10;
+    }
+}
+
+impl EventFilter {
+    /// The kind of `event`, which is logged instead of the event itself as it may contain track metadata.
+    pub(super) fn kind(event: &Event) -> Self {
+        match event {
+            Event::TrackChanged(_) => Self::TRACK,
+            Event::PlaybackStatusChanged(_) => Self::PLAYBACK_STATUS,
+            Event::PlaybackPositionChanged(_) => Self::PLAYBACK_POSITION,
+            Event::VolumeChanged(..) => Self::VOLUME,
+            Event::PassThrough(..) => Self::PASS_THROUGH,
+            Event::UidsChanged(_) => Self::UIDS,
+            Event::DisplayableCharacterSets(_) => Self::CHARACTER_SETS,
+            Event::ControllerBatteryStatus(_) => Self::BATTERY_STATUS,
+            Event::PlayerApplicationSettingsChanged(_) => Self::PLAYER_SETTINGS,
+            Event::NowPlayingContentChanged => Self::NOW_PLAYING,
+            Event::AvailablePlayersChanged | Event::AddressedPlayerChanged(_) => Self::PLAYERS
+        }
+    }
+
+    pub fn matches(self, event: &Event) -> bool {
+        self.contains(Self::kind(event))
+    }
+}
+
+/// The events of a session that pass a filter, in a queue of their own.
+/// A subscriber that falls behind loses its own events without affecting the session or other subscribers.
+#[derive(Debug)]
+pub struct EventSubscription {
+    events: Receiver<Timestamped<Event>>
+}
+
+impl EventSubscription {
+    /// The next event, `None` once the session ended.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.next_timestamped_event().await.map(|event| event.value)
+    }
+
+    /// Like [EventSubscription::next_event] with the time the event was received from the peer.
+    pub async fn next_timestamped_event(&mut self) -> Option<Timestamped<Event>> {
+        self.events.recv().await
+    }
+}
+
+type Subscription = (EventFilter, Sender<Timestamped<Event>>);
+
+/// The subscriptions of a session, shared by the session task and the controllers.
+/// `None` once the session ended, so the subscriptions end with it even though controllers outlive the session.
+#[derive(Debug, Clone)]
+pub(super) struct Subscribers(Arc<Mutex<Option<Vec<Subscription>>>>);
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Some(Vec::new()))))
+    }
+}
+
+impl Subscribers {
+    pub fn subscribe(&self, filter: EventFilter, capacity: usize) -> EventSubscription {
+        let (tx, rx) = channel(capacity.max(1));
+        // The sender is dropped right away if the session already ended
+        if let Some(subscriptions) = self.0.lock().as_mut() {
+            subscriptions.push((filter, tx));
+        }
+        EventSubscription { events: rx }
+    }
+
+    /// Queues `event` for every matching subscriber and forgets dropped subscriptions.
+    /// Returns `false` if no subscriber wants the event.
+    pub fn publish(&self, event: &Timestamped<Event>) -> bool {
+        let mut delivered = false;
+        let mut subscriptions = self.0.lock();
+        let Some(subscriptions) = subscriptions.as_mut() else {
+            return false;
+        };
+        subscriptions.retain(|(filter, sender)| {
+            if !filter.matches(&event.value) {
+                return !sender.is_closed();
+            }
+            match sender.try_send(event.clone()) {
+                Ok(()) => {
+                    delivered = true;
+                    true
+                }
+                Err(TrySendError::Full(event)) => {
+                    trace!("Subscription queue full, dropping {:?} event", EventFilter::kind(&event.value));
+                    delivered = true;
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false
+            }
+        });
+        delivered
+    }
+
+    /// Ends all subscriptions, [EventSubscription::next_event] returns `None` once the queued events were received.
+    pub fn close(&self) {
+        self.0.lock().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avrcp::notifications::PlaybackPosition;
+    use crate::avrcp::subscriptions::{EventFilter, Subscribers};
+    use crate::avrcp::{Event, VolumeOrigin};
+    use crate::utils::clock::Timestamped;
+
+    #[tokio::test]
+    async fn independent_queues() {
+        let subscribers = Subscribers::default();
+        let mut volume = subscribers.subscribe(EventFilter::VOLUME, 1);
+        let mut position = subscribers.subscribe(EventFilter::PLAYBACK_POSITION, 1);
+        subscribers.publish(&Timestamped::now(Event::PlaybackPositionChanged(PlaybackPosition::NotSelected)));
+        // Overflows the queue of the position subscriber only
+        subscribers.publish(&Timestamped::now(Event::PlaybackPositionChanged(PlaybackPosition::NotSelected)));
+        subscribers.publish(&Timestamped::now(Event::VolumeChanged(0.5, VolumeOrigin::Remote)));
+        assert_eq!(volume.next_event().await, Some(Event::VolumeChanged(0.5, VolumeOrigin::Remote)));
+        assert_eq!(position.next_event().await, Some(Event::PlaybackPositionChanged(PlaybackPosition::NotSelected)));
+
+        drop(position);
+        subscribers.publish(&Timestamped::now(Event::VolumeChanged(0.5, VolumeOrigin::Remote)));
+        assert_eq!(subscribers.0.lock().as_ref().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn routing() {
+        let subscribers = Subscribers::default();
+        let _volume = subscribers.subscribe(EventFilter::VOLUME, 1);
+        assert!(subscribers.publish(&Timestamped::now(Event::VolumeChanged(0.5, VolumeOrigin::Remote))));
+        // Still taken by the subscription when its queue is full
+        assert!(subscribers.publish(&Timestamped::now(Event::VolumeChanged(0.5, VolumeOrigin::Remote))));
+        assert!(!subscribers.publish(&Timestamped::now(Event::NowPlayingContentChanged)));
+    }
+
+    #[tokio::test]
+    async fn closed_with_session() {
+        let subscribers = Subscribers::default();
+        let mut volume = subscribers.subscribe(EventFilter::VOLUME, 1);
+        subscribers.publish(&Timestamped::now(Event::VolumeChanged(0.5, VolumeOrigin::Remote)));
+        // A controller outliving the session keeps `subscribers` alive
+        let controller = subscribers.clone();
+        subscribers.close();
+        assert_eq!(volume.next_event().await, Some(Event::VolumeChanged(0.5, VolumeOrigin::Remote)));
+        assert_eq!(volume.next_event().await, None);
+        assert_eq!(controller.subscribe(EventFilter::all(), 1).next_event().await, None);
+    }
+}
@@ -0,0 +1,299 @@
+//! The transaction labels of the commands we sent to the peer ([AVCTP] Section 6.1.1).
+use std::future::{poll_fn, Future};
+use std::ops::{Index, IndexMut};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::avc::CommandCode;
+use crate::avrcp::error::Error;
+use crate::avrcp::packets::EventId;
+use crate::avrcp::session::{CommandResponseSender, EventParser};
+use crate::avrcp::Event;
+use crate::utils::clock::now;
+
+const LABELS: usize = 16;
+
+#[derive(Default, Debug)]
+pub(super) enum TransactionState {
+    #[default]
+    Empty,
+    PendingPassThrough(CommandResponseSender),
+    PendingVendorDependent(CommandCode, CommandResponseSender),
+    PendingNotificationRegistration(EventParser, Option<Rearm>, CommandResponseSender),
+    WaitingForChange(EventParser, Option<Rearm>),
+    /// The notification was registered again after a change, `Event` is the last delivered value.
+    Rearming(EventParser, Rearm, Event),
+    /// The local requester is gone, the label stays reserved until the final response arrived,
+    /// so a late response can't be mistaken for the response to a new command.
+    Abandoned,
+    /// The remaining fragments of a continuing response were aborted ([AVRCP] Section 6.8.2).
+    /// The label stays reserved until the peer acknowledges the abort, so late fragments can't end up in a new transaction.
+    Aborting
+}
+
+/// How to register a notification again after it changed.
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Rearm {
+    pub event: EventId,
+    pub interval: u32
+}
+
+impl TransactionState {
+    pub fn is_free(&self) -> bool {
+        matches!(self, TransactionState::Empty)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !matches!(
+            self,
+            TransactionState::Empty
+                | TransactionState::WaitingForChange(..)
+                | TransactionState::Rearming(..)
+                | TransactionState::Abandoned
+                | TransactionState::Aborting
+        )
+    }
+
+    pub fn take_sender(&mut self) -> CommandResponseSender {
+        let prev = std::mem::take(self);
+        match prev {
+            TransactionState::PendingPassThrough(sender) => sender,
+            TransactionState::PendingVendorDependent(_, sender) => sender,
+            TransactionState::PendingNotificationRegistration(parser, rearm, sender) => {
+                *self = TransactionState::WaitingForChange(parser, rearm);
+                sender
+            }
+            _ => unreachable!()
+        }
+    }
+
+    /// Resolves once the local requester of a pending transaction is no longer interested in the response.
+    pub fn poll_cancelled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self {
+            TransactionState::PendingPassThrough(sender)
+            | TransactionState::PendingVendorDependent(_, sender)
+            | TransactionState::PendingNotificationRegistration(_, _, sender) => sender.poll_closed(cx),
+            _ => Poll::Pending
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            TransactionState::Empty => "Empty",
+            TransactionState::PendingPassThrough(_) => "PendingPassThrough",
+            TransactionState::PendingVendorDependent(_, _) => "PendingVendorDependent",
+            TransactionState::PendingNotificationRegistration(..) => "PendingNotificationRegistration",
+            TransactionState::WaitingForChange(..) => "WaitingForChange",
+            TransactionState::Rearming(..) => "Rearming",
+            TransactionState::Abandoned => "Abandoned",
+            TransactionState::Aborting => "Aborting"
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    state: TransactionState,
+    /// Counts how often the label was handed out, to tell apart transactions in the logs.
+    generation: u32,
+    last_used: Instant
+}
+
+/// The 16 transaction labels of a session.
+/// Labels are handed out round-robin, so a late response to a transaction that was given up
+/// is unlikely to be mistaken for the response to a newer one.
+#[derive(Debug)]
+pub(super) struct Transactions {
+    slots: [Slot; LABELS],
+    next_label: u8
+}
+
+impl Default for Transactions {
+    fn default() -> Self {
+        let started = now();
+        Self {
+            slots: std::array::from_fn(|_| Slot {
+                state: TransactionState::Empty,
+                generation: 0,
+                last_used: started
+            }),
+            next_label: 0
+        }
+    }
+}
+
+impl Transactions {
+    /// Pending commands without any response for this long are given up when the labels run out.
+    /// The peer should have answered long before ([AVRCP] Section 6.2, T_MTP is 1 second).
+    pub const STALE_AFTER: Duration = Duration::from_secs(30);
+
+    /// Finds a free label, reclaiming stale transactions if there is none.
+    pub fn allocate(&mut self) -> Option<u8> {
+        self.free_label().or_else(|| {
+            self.reclaim_stale();
+            self.free_label()
+        })
+    }
+
+    fn free_label(&mut self) -> Option<u8> {
+        let label = (0..LABELS as u8)
+            .map(|offset| (self.next_label + offset) % LABELS as u8)
+            .find(|label| self[*label as usize].is_free())?;
+        self.next_label = (label + 1) % LABELS as u8;
+        Some(label)
+    }
+
+    /// Fails the pending transactions that have not seen a response in [Self::STALE_AFTER]
+    /// and frees the labels of abandoned transactions and aborts the peer never answered.
+    pub fn reclaim_stale(&mut self) {
+        let now = now();
+        for (label, slot) in self.slots.iter_mut().enumerate() {
+            let age = now.saturating_duration_since(slot.last_used);
+            if matches!(slot.state, TransactionState::Abandoned | TransactionState::Aborting) && age >= Self::STALE_AFTER {
+                slot.state = TransactionState::Empty;
+            }
+            if slot.state.is_pending() && age >= Self::STALE_AFTER {
+                warn!(
+                    "Label {} (generation {}) stuck in {} for {:?}, reclaiming it",
+                    label,
+                    slot.generation,
+                    slot.state.describe(),
+                    age
+                );
+                let _ = slot.state.take_sender().send(Err(Error::Timeout));
+                slot.state = TransactionState::Empty;
+            }
+        }
+    }
+
+    /// Starts a new transaction on `label`, which also counts as activity for the staleness check.
+    pub fn start(&mut self, label: u8, state: TransactionState) {
+        let slot = &mut self.slots[label as usize];
+        slot.state = state;
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.last_used = now();
+    }
+
+    /// Records that the peer answered on `label`.
+    pub fn touch(&mut self, label: u8) {
+        self.slots[label as usize].last_used = now();
+    }
+
+    /// The labels in use with a description of their state, e.g. `PendingVendorDependent #3 for 1.2s`.
+    pub fn describe(&self) -> Vec<(u8, String)> {
+        let now = now();
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| !slot.state.is_free())
+            .map(|(label, slot)| {
+                let age = now.saturating_duration_since(slot.last_used);
+                (label as u8, format!("{} #{} for {:.1?}", slot.state.describe(), slot.generation, age))
+            })
+            .collect()
+    }
+
+    /// Resolves with the label of a transaction whose local requester is gone.
+    pub fn cancelled(&mut self) -> impl Future<Output = u8> + '_ {
+        poll_fn(move |cx| {
+            self.slots
+                .iter_mut()
+                .position(|slot| slot.state.poll_cancelled(cx).is_ready())
+                .map_or(Poll::Pending, |label| Poll::Ready(label as u8))
+        })
+    }
+}
+
+impl Index<usize> for Transactions {
+    type Output = TransactionState;
+
+    fn index(&self, label: usize) -> &Self::Output {
+        &self.slots[label].state
+    }
+}
+
+impl IndexMut<usize> for Transactions {
+    fn index_mut(&mut self, label: usize) -> &mut Self::Output {
+        &mut self.slots[label].state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::oneshot::channel;
+
+    use crate::avc::CommandCode;
+    use crate::avrcp::error::Error;
+    use crate::avrcp::transactions::{TransactionState, Transactions};
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    #[test]
+    fn round_robin_and_reclamation() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let mut transactions = Transactions::default();
+
+        let mut receivers = Vec::new();
+        for expected in 0..16 {
+            let label = transactions.allocate().unwrap();
+            assert_eq!(label, expected);
+            let (sender, receiver) = channel();
+            transactions.start(label, TransactionState::PendingPassThrough(sender));
+            receivers.push(receiver);
+        }
+        transactions[3] = TransactionState::Empty;
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(transactions.allocate(), Some(3));
+        transactions.start(3, TransactionState::WaitingForChange(|_| unreachable!(), None));
+        assert_eq!(transactions.allocate(), None);
+
+        // Only pending commands are reclaimed, registered notifications stay
+        clock.advance(Transactions::STALE_AFTER);
+        transactions.touch(7);
+        assert_eq!(transactions.allocate(), Some(4));
+        assert_eq!(receivers[5].try_recv(), Ok(Err(Error::Timeout)));
+        assert!(transactions[3].describe() == "WaitingForChange");
+        assert!(transactions[7].is_pending());
+    }
+
+    #[test]
+    fn cancelled_requesters() {
+        let mut transactions = Transactions::default();
+        let (sender, receiver) = channel();
+        transactions.start(0, TransactionState::PendingVendorDependent(CommandCode::Status, sender));
+        transactions.start(1, TransactionState::WaitingForChange(|_| unreachable!(), None));
+        assert_eq!(now_or_never(transactions.cancelled()), None);
+
+        // Dropping the future that waits for the response reports the label
+        drop(receiver);
+        assert_eq!(now_or_never(transactions.cancelled()), Some(0));
+
+        // Which is kept until the response arrived
+        transactions[0] = TransactionState::Abandoned;
+        assert_eq!(now_or_never(transactions.cancelled()), None);
+        assert_eq!(transactions.allocate(), Some(2));
+    }
+
+    #[test]
+    fn aborted_labels_stay_reserved() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let mut transactions = Transactions::default();
+        for label in 0..16 {
+            transactions.start(label, TransactionState::Aborting);
+        }
+        assert!(!transactions[0].is_pending());
+        assert_eq!(transactions.allocate(), None);
+
+        // Unless the peer never acknowledges the abort
+        clock.advance(Transactions::STALE_AFTER);
+        assert_eq!(transactions.allocate(), Some(0));
+    }
+}
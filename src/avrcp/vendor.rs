@@ -0,0 +1,188 @@
+//! Vendor dependent commands of the peer that are answered by a [VendorCommandHandler] of the application.
+//! The handlers run on blocking tasks, so a slow handler neither stalls the session nor lets the peer run into its
+//! response timeout: a control command that takes longer than 100ms is answered with an interim response first,
+//! other commands that are not answered within 200ms are answered with in transition (or rejected for notifications)
+//! and the late result is dropped.
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::Bytes;
+use instructor::utils::u24;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::Instant;
+
+use crate::avc::{CommandCode, Frame};
+use crate::avrcp::VendorCommandHandler;
+use crate::utils::clock::{now, sleep, Sleep};
+
+/// How long a control command may take before the target has to send an interim response (T_RCP(100)).
+pub const CONTROL_RESPONSE_TIME: Duration = Duration::from_millis(100);
+/// How long any other command may take before it has to be answered (T_MTC(200)).
+pub const STATUS_RESPONSE_TIME: Duration = Duration::from_millis(200);
+
+struct PendingCommand {
+    transaction: u8,
+    frame: Frame,
+    company_id: u24,
+    started: Instant,
+    /// Expires when the command has to be answered, `None` once the interim response was sent.
+    timer: Option<Sleep>,
+    task: JoinHandle<(CommandCode, Bytes)>
+}
+
+#[derive(Debug)]
+pub enum Progress {
+    /// The handler of a command finished after `elapsed`, `response` is `None` if it panicked.
+    Finished {
+        transaction: u8,
+        frame: Frame,
+        company_id: u24,
+        response: Option<(CommandCode, Bytes)>,
+        elapsed: Duration
+    },
+    /// The handler is too slow, the command has to be answered with `ctype` and no operands right away.
+    Overdue {
+        transaction: u8,
+        frame: Frame,
+        company_id: u24,
+        ctype: CommandCode
+    }
+}
+
+#[derive(Default)]
+pub struct PendingCommands {
+    commands: Vec<PendingCommand>,
+    overdue: u32
+}
+
+impl PendingCommands {
+    /// Runs `handler` for the command with `frame` and the operands after the company id.
+    pub fn start(&mut self, transaction: u8, frame: Frame, company_id: u24, handler: VendorCommandHandler, operands: Bytes) {
+        let budget = match frame.ctype {
+            CommandCode::Control => CONTROL_RESPONSE_TIME,
+            _ => STATUS_RESPONSE_TIME
+        };
+        let ctype = frame.ctype;
+        self.commands.push(PendingCommand {
+            transaction,
+            frame,
+            company_id,
+            started: now(),
+            timer: Some(sleep(budget)),
+            task: spawn_blocking(move || handler(ctype, operands))
+        });
+    }
+
+    /// The number of commands whose handler missed the response time since the session started.
+    pub fn overdue(&self) -> u32 {
+        self.overdue
+    }
+
+    /// Waits for the next handler to finish or to miss its response time.
+    pub async fn next(&mut self) -> Progress {
+        poll_fn(|cx| {
+            for i in 0..self.commands.len() {
+                let command = &mut self.commands[i];
+                if let Poll::Ready(result) = Pin::new(&mut command.task).poll(cx) {
+                    let command = self.commands.swap_remove(i);
+                    return Poll::Ready(Progress::Finished {
+                        transaction: command.transaction,
+                        frame: command.frame,
+                        company_id: command.company_id,
+                        response: result.ok(),
+                        elapsed: now().saturating_duration_since(command.started)
+                    });
+                }
+                let expired = command
+                    .timer
+                    .as_mut()
+                    .is_some_and(|timer| timer.as_mut().poll(cx).is_ready());
+                if expired {
+                    self.overdue += 1;
+                    let (transaction, frame, company_id) = (command.transaction, command.frame, command.company_id);
+                    let ctype = match frame.ctype {
+                        CommandCode::Control => {
+                            // The final response follows once the handler finishes
+                            command.timer = None;
+                            CommandCode::Interim
+                        }
+                        ctype => {
+                            // The blocking task can't be cancelled, its result is dropped
+                            self.commands.swap_remove(i);
+                            match ctype {
+                                CommandCode::Notify => CommandCode::Rejected,
+                                _ => CommandCode::InTransition
+                            }
+                        }
+                    };
+                    return Poll::Ready(Progress::Overdue {
+                        transaction,
+                        frame,
+                        company_id,
+                        ctype
+                    });
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use instructor::utils::u24;
+    use parking_lot::Mutex;
+
+    use crate::avc::{CommandCode, Frame, Opcode};
+    use crate::avrcp::packets::PANEL;
+    use crate::avrcp::vendor::{PendingCommands, Progress, CONTROL_RESPONSE_TIME};
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    #[tokio::test]
+    async fn slow_control_command_gets_interim_response() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let (release, released) = channel::<()>();
+        let released = Mutex::new(released);
+        let frame = Frame {
+            ctype: CommandCode::Control,
+            subunit: PANEL,
+            opcode: Opcode::VendorDependent
+        };
+        let mut pending = PendingCommands::default();
+        pending.start(
+            3,
+            frame,
+            u24::new(0x123456),
+            Arc::new(move |_, operands| {
+                let _ = released.lock().recv();
+                (CommandCode::Accepted, operands)
+            }),
+            Bytes::from_static(&[1, 2])
+        );
+        assert!(now_or_never(pending.next()).is_none());
+
+        clock.advance(CONTROL_RESPONSE_TIME);
+        match pending.next().await {
+            Progress::Overdue { transaction, ctype, .. } => assert_eq!((transaction, ctype), (3, CommandCode::Interim)),
+            progress => panic!("Unexpected progress: {:?}", progress)
+        }
+        release.send(()).unwrap();
+        match pending.next().await {
+            Progress::Finished { transaction, response, .. } => {
+                assert_eq!(transaction, 3);
+                assert_eq!(response, Some((CommandCode::Accepted, Bytes::from_static(&[1, 2]))));
+            }
+            progress => panic!("Unexpected progress: {:?}", progress)
+        }
+        assert_eq!(pending.overdue(), 1);
+    }
+}
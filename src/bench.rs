@@ -0,0 +1,107 @@
+//! Entry points into the packet processing hot path for the benchmarks in `benches/`.
+//! They run the same parsers and handlers as the sessions, just without the channels and tasks around them.
+//! Not part of the stable API.
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use instructor::utils::u24;
+use instructor::Buffer;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::avc::{Frame, Opcode};
+use crate::avctp::packets::MessageAssembler;
+use crate::avctp::MessageType;
+use crate::avdtp::packets::SignalMessageAssembler;
+use crate::avdtp::{Avdtp, AvdtpSession, SignalMessage};
+use crate::avrcp::packets::{unknown_pdu_id, validate_command, CommandAssembler, CommandStatus, BLUETOOTH_SIG_COMPANY_ID, PANEL};
+use crate::hci::acl::{AclDataAssembler, AclHeader};
+use crate::l2cap::{ChannelEvent, L2capHeader};
+use crate::utils::clock::now;
+
+/// The command path of an AVRCP target: AVCTP reassembly, the AV/C frame and the AVRCP PDU.
+#[derive(Default)]
+pub struct AvrcpCommandParser {
+    avctp: MessageAssembler,
+    commands: CommandAssembler
+}
+
+impl AvrcpCommandParser {
+    /// Returns the parameters of a complete and valid command, pass-through commands have none.
+    pub fn process(&mut self, packet: Bytes) -> Option<Bytes> {
+        let mut message = self.avctp.process_msg(packet).ok()??;
+        if message.message_type != MessageType::Command {
+            return None;
+        }
+        let frame: Frame = message.data.read_be().ok()?;
+        match frame.opcode {
+            Opcode::PassThrough => Some(Bytes::new()),
+            Opcode::VendorDependent if frame.subunit == PANEL => {
+                let company_id: u24 = message.data.read_be().ok()?;
+                if company_id != BLUETOOTH_SIG_COMPANY_ID || unknown_pdu_id(&message.data).is_some() {
+                    return None;
+                }
+                match self.commands.process_msg(message.data).ok()? {
+                    CommandStatus::Complete(pdu, parameters) => validate_command(frame.ctype, pdu, &parameters)
+                        .ok()
+                        .map(|_| parameters),
+                    CommandStatus::Incomplete(_) | CommandStatus::Truncated(..) => None
+                }
+            }
+            _ => None
+        }
+    }
+}
+
+/// The signaling path of an AVDTP session with the endpoints of `avdtp`, from the raw packets to the reply.
+pub struct AvdtpSignalDispatcher {
+    assembler: SignalMessageAssembler,
+    session: AvdtpSession
+}
+
+impl AvdtpSignalDispatcher {
+    pub fn new(avdtp: &Avdtp) -> Self {
+        Self {
+            assembler: SignalMessageAssembler::default(),
+            session: AvdtpSession::detached(avdtp, 0x0001)
+        }
+    }
+
+    /// Returns the reply to a complete command.
+    pub fn process(&mut self, packet: Bytes) -> Option<SignalMessage> {
+        let msg = self.assembler.process_msg(packet).ok()??;
+        Some(self.session.handle_signal_message(msg))
+    }
+}
+
+/// The data path of the L2CAP server: ACL reassembly per connection and routing by channel id.
+#[derive(Default)]
+pub struct L2capDemux {
+    connections: BTreeMap<u16, AclDataAssembler>,
+    channels: BTreeMap<u16, UnboundedSender<ChannelEvent>>
+}
+
+impl L2capDemux {
+    /// Registers a connection with a channel and returns the receiving end of the channel.
+    pub fn add_channel(&mut self, handle: u16, cid: u16) -> UnboundedReceiver<ChannelEvent> {
+        let (tx, rx) = unbounded_channel();
+        self.connections.entry(handle).or_default();
+        self.channels.insert(cid, tx);
+        rx
+    }
+
+    /// Returns whether the packet completed an L2CAP PDU that was delivered to its channel.
+    pub fn process(&mut self, mut packet: Bytes) -> bool {
+        let Ok(header) = packet.read::<AclHeader>() else { return false };
+        let Some(mut pdu) = self
+            .connections
+            .get_mut(&header.handle)
+            .and_then(|assembler| assembler.push(header, packet))
+        else {
+            return false;
+        };
+        let Ok(L2capHeader { cid, .. }) = pdu.read() else { return false };
+        self.channels
+            .get(&cid)
+            .is_some_and(|channel| channel.send(ChannelEvent::DataReceived(pdu, now())).is_ok())
+    }
+}
@@ -1,31 +1,86 @@
+#[cfg(feature = "firmware-realtek")]
 mod realtek;
+mod vendor;
 
 use std::future::Future;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
-use tracing::error;
-pub use realtek::RealTekFirmwareLoader;
+use tracing::{debug, error};
+#[cfg(feature = "firmware-realtek")]
+pub use realtek::{FileNameMapper, RealTekFirmwareLoader};
+pub use vendor::VendorCommands;
+
+/// The environment variable with the folders [FolderFileProvider::from_env] searches,
+/// separated like `PATH` (`:` on Unix, `;` on Windows).
+pub const FIRMWARE_PATH_ENV: &str = "BLUEFANG_FIRMWARE_PATH";
 
 pub trait FileProvider {
     fn get_file(&self, name: &str) -> impl Future<Output=Option<Vec<u8>>> + Send;
 }
 
+/// Searches several folders in order and returns the first file with the requested name.
 #[derive(Debug, Clone)]
 pub struct FolderFileProvider {
-    folder: PathBuf
+    folders: Vec<PathBuf>
 }
 
 impl FolderFileProvider {
     pub fn new<P: AsRef<Path>>(folder: P) -> Self {
-        Self { folder: folder.as_ref().to_path_buf() }
+        Self { folders: vec![folder.as_ref().to_path_buf()] }
+    }
+
+    /// Uses the folders of [FIRMWARE_PATH_ENV] if it is set and `default` otherwise,
+    /// so images with a non-standard layout can point the loaders to their firmware without code changes.
+    pub fn from_env<P: AsRef<Path>>(default: P) -> Self {
+        match std::env::var_os(FIRMWARE_PATH_ENV) {
+            Some(paths) => Self {
+                folders: std::env::split_paths(&paths).collect()
+            },
+            None => Self::new(default)
+        }
+    }
+
+    /// Searches `folder` after the folders that were added before.
+    pub fn with_folder<P: AsRef<Path>>(mut self, folder: P) -> Self {
+        self.folders.push(folder.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn folders(&self) -> &[PathBuf] {
+        &self.folders
     }
 }
 
 impl FileProvider for FolderFileProvider {
     async fn get_file(&self, file_name: &str) -> Option<Vec<u8>> {
-        let path = self.folder.join(file_name);
-        tokio::fs::read(path)
-            .await
-            .map_err(|err| error!("Failed to read file {}: {:?}", file_name, err))
-            .ok()
+        for folder in &self.folders {
+            let path = folder.join(file_name);
+            match tokio::fs::read(&path).await {
+                Ok(data) => {
+                    debug!("Loaded {}", path.display());
+                    return Some(data);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    error!("Failed to read file {}: {:?}", path.display(), err);
+                    return None;
+                }
+            }
+        }
+        error!("Failed to find {} in {:?}", file_name, self.folders);
+        None
+    }
+}
+
+/// Reads the files through a callback, e.g. from a flash partition or blobs embedded with `include_bytes!`.
+#[derive(Clone)]
+pub struct FnFileProvider<F>(pub F);
+
+impl<F> FileProvider for FnFileProvider<F>
+where
+    F: Fn(&str) -> Option<Vec<u8>> + Send + Sync
+{
+    async fn get_file(&self, name: &str) -> Option<Vec<u8>> {
+        (self.0)(name)
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::future::Future;
+
 use bytes::BufMut;
 
 use crate::hci::{Error, Hci, Opcode, OpcodeGroup};
@@ -12,6 +14,8 @@ pub const RTL_CHIP_TYPE: [u8; 5] = [0x00, 0x94, 0xa0, 0x00, 0xb0];
 pub trait RtkHciExit {
     async fn read_rom_version(&self) -> Result<u8, Error>;
     async fn download(&self, index: u8, data: &[u8]) -> Result<u8, Error>;
+    /// Queues the download of a fragment without waiting for the controller to acknowledge it.
+    fn submit_download(&self, index: u8, data: &[u8]) -> Result<impl Future<Output = Result<u8, Error>>, Error>;
 
     async fn read_reg16(&self, cmd: [u8; 5]) -> Result<u16, Error>;
 
@@ -26,11 +30,14 @@ impl RtkHciExit for Hci {
     }
 
     async fn download(&self, index: u8, data: &[u8]) -> Result<u8, Error> {
-        self.call_with_args(Opcode::new(OpcodeGroup::Vendor, 0x0020), |p| {
+        self.submit_download(index, data)?.await
+    }
+
+    fn submit_download(&self, index: u8, data: &[u8]) -> Result<impl Future<Output = Result<u8, Error>>, Error> {
+        self.submit_with_args(Opcode::new(OpcodeGroup::Vendor, 0x0020), |p| {
             p.put_u8(index);
             p.put_slice(data);
         })
-        .await
     }
 
     async fn read_reg16(&self, cmd: [u8; 5]) -> Result<u16, Error> {
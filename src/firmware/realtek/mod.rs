@@ -1,29 +1,68 @@
 mod commands;
 mod info;
 
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use tracing::{debug, error};
+use tracing::{debug, error, trace};
 
 use crate::ensure;
 use crate::firmware::FileProvider;
 use crate::firmware::realtek::commands::{RtkHciExit, RTL_CHIP_REV, RTL_CHIP_SUBVER, RTL_CHIP_TYPE};
 use crate::firmware::realtek::info::*;
-use crate::hci::consts::CoreVersion;
+use crate::hci::consts::{BdAddr, CompanyId, CoreVersion};
 use crate::hci::consts::CoreVersion::*;
 use crate::hci::{Error, FirmwareLoader, Hci, LocalVersion};
 
+/// Maps the file names of the Linux firmware package (e.g. `rtl8761bu_fw.bin`) to the names of the [FileProvider].
+pub type FileNameMapper = Arc<dyn Fn(&str) -> String + Send + Sync>;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct RealTekFirmwareLoader<P> {
-    provider: P
+    provider: P,
+    file_names: Option<FileNameMapper>,
+    bd_addr: Option<BdAddr>
+}
+
+impl<P: Debug> Debug for RealTekFirmwareLoader<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RealTekFirmwareLoader")
+            .field("provider", &self.provider)
+            .field("bd_addr", &self.bd_addr)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<P: FileProvider + Send + Sync> RealTekFirmwareLoader<P> {
 
     pub fn new(provider: P) -> Self {
-        Self { provider }
+        Self { provider, file_names: None, bd_addr: None }
+    }
+
+    /// Patches `addr` into the config before it is downloaded, as Realtek controllers have no command to change
+    /// their address afterwards. [Hci::set_bd_addr] with the same address then only verifies it.
+    pub fn with_bd_addr(mut self, addr: BdAddr) -> Self {
+        self.bd_addr = Some(addr);
+        self
+    }
+
+    /// Renames the requested files, e.g. to `rtl_bt/rtl8761bu_fw.bin` for a provider rooted at `/lib/firmware`.
+    pub fn with_file_names<F>(mut self, mapper: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        self.file_names = Some(Arc::new(mapper));
+        self
+    }
+
+    async fn get_file(&self, name: &str) -> Option<Vec<u8>> {
+        match &self.file_names {
+            Some(mapper) => self.provider.get_file(&mapper(name)).await,
+            None => self.provider.get_file(name).await
+        }
     }
 
     async fn find_chip_info(&self, hci: &Hci) -> Result<(u16, u16, CoreVersion, u8), Error> {
@@ -74,13 +113,13 @@ impl<P: FileProvider + Send + Sync> RealTekFirmwareLoader<P> {
 
         debug!("found driver info: {:?}", info);
 
-        let firmware = self.provider
+        let firmware = self
             .get_file(info.firmware_name)
             .await
             .ok_or_else(|| Error::from("Failed to find load firmware"))?;
 
         let config = if !info.config_name.is_empty() {
-            let config = self.provider
+            let config = self
                 .get_file(info.config_name)
                 .await
                 .ok_or_else(|| Error::from("Failed to find load firmware config.bin"))?;
@@ -91,6 +130,10 @@ impl<P: FileProvider + Send + Sync> RealTekFirmwareLoader<P> {
         if config.is_none() && info.config_needed {
             return Err(Error::from("Config needed, but no config file available"));
         }
+        let config = match self.bd_addr {
+            Some(addr) => Some(patch_config(config, RTL_CONFIG_BD_ADDR, addr.as_ref())?),
+            None => config
+        };
 
         match lmp_subversion {
             RTL_ROM_LMP_8723A => download_for_rtl8723a(hci, firmware).await?,
@@ -100,17 +143,79 @@ impl<P: FileProvider + Send + Sync> RealTekFirmwareLoader<P> {
         }
         Ok(true)
     }
+
+    async fn set_bd_addr(&self, hci: &Hci, addr: BdAddr) -> Result<bool, Error> {
+        if hci.read_local_version().await?.company_id != CompanyId::REALTEK {
+            return Ok(false);
+        }
+        ensure!(
+            self.bd_addr == Some(addr),
+            Error::NotSupported("Realtek controllers take their address from the config, see RealTekFirmwareLoader::with_bd_addr")
+        );
+        Ok(true)
+    }
 }
 
 impl<T: Send + Sync + FileProvider> FirmwareLoader for RealTekFirmwareLoader<T> {
     fn try_load_firmware<'a>(&'a self, host: &'a Hci) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
         Box::pin(Self::try_load_firmware(self, host))
     }
+
+    fn set_bd_addr<'a>(&'a self, hci: &'a Hci, addr: BdAddr) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(Self::set_bd_addr(self, hci, addr))
+    }
+}
+
+const RTL_CONFIG_MAGIC: u32 = 0x8723AB55;
+const RTL_CONFIG_HEADER_SIZE: usize = 6;
+// The offset of the public address in the config
+const RTL_CONFIG_BD_ADDR: u16 = 0x0044;
+
+/// Replaces the value of the config entry `offset`, or appends it if the config doesn't have it yet.
+///
+/// The config starts with the magic and the length of the entries, each entry is its offset, length and value.
+fn patch_config(config: Option<Vec<u8>>, offset: u16, value: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut config = config.unwrap_or_else(|| {
+        let mut config = Vec::with_capacity(RTL_CONFIG_HEADER_SIZE);
+        config.extend_from_slice(&RTL_CONFIG_MAGIC.to_le_bytes());
+        config.extend_from_slice(&0u16.to_le_bytes());
+        config
+    });
+    ensure!(config.len() >= RTL_CONFIG_HEADER_SIZE, "Config too short");
+    ensure!(u32::from_le_bytes(read_bytes(&config, 0)) == RTL_CONFIG_MAGIC, "Config does not start with the magic");
+    let entries_end = RTL_CONFIG_HEADER_SIZE + u16::from_le_bytes(read_bytes(&config, 4)) as usize;
+    ensure!(entries_end <= config.len(), "Config too short");
+    let mut position = RTL_CONFIG_HEADER_SIZE;
+    while position + 3 <= entries_end {
+        let entry_offset = u16::from_le_bytes(read_bytes(&config, position));
+        let length = config[position + 2] as usize;
+        let data = position + 3;
+        ensure!(data + length <= entries_end, "Config entry exceeds the config");
+        if entry_offset == offset {
+            ensure!(length == value.len(), "Config entry has an unexpected length");
+            config[data..data + length].copy_from_slice(value);
+            return Ok(config);
+        }
+        position = data + length;
+    }
+    let mut entry = Vec::with_capacity(3 + value.len());
+    entry.extend_from_slice(&offset.to_le_bytes());
+    entry.push(value.len() as u8);
+    entry.extend_from_slice(value);
+    config.splice(entries_end..entries_end, entry);
+    let total_length = u16::try_from(config.len() - RTL_CONFIG_HEADER_SIZE).map_err(|_| Error::from("Config too long"))?;
+    config[4..RTL_CONFIG_HEADER_SIZE].copy_from_slice(&total_length.to_le_bytes());
+    Ok(config)
 }
 
+// The download command carries the index byte and the fragment, HCI limits command parameters to 255 bytes
 const RTK_FRAGMENT_LENGTH: usize = 252;
+// How many fragments may wait for their acknowledgement, the event loop still honors the controller's command credits
+const RTK_DOWNLOAD_WINDOW: usize = 8;
 async fn download_firmware(host: &Hci, firmware: Vec<u8>) -> Result<(), Error> {
-    // Download the payload, one fragment at a time.
+    let fragment_count = firmware.len().div_ceil(RTK_FRAGMENT_LENGTH);
+    let mut in_flight = VecDeque::with_capacity(RTK_DOWNLOAD_WINDOW);
+    // Download the payload, pipelining the fragments.
     for (fragment_index, fragment) in firmware.chunks(RTK_FRAGMENT_LENGTH).enumerate() {
         // NOTE: the Linux driver somehow adds 1 to the index after it wraps around.
         // That's odd, but we"ll do the same here.
@@ -118,11 +223,21 @@ async fn download_firmware(host: &Hci, firmware: Vec<u8>) -> Result<(), Error> {
         if download_index >= 0x80 {
             download_index += 1;
         }
-        if (fragment_index + 1) * RTK_FRAGMENT_LENGTH >= firmware.len() {
+        let last = fragment_index + 1 == fragment_count;
+        if last {
             download_index |= 0x80; // End marker
         }
-        debug!("downloading fragment {}", fragment_index);
-        host.download(download_index as u8, fragment).await?;
+        // The controller switches to the new firmware after the last fragment, so everything else has to be acknowledged first
+        while in_flight.len() >= RTK_DOWNLOAD_WINDOW || (last && !in_flight.is_empty()) {
+            if let Some(ack) = in_flight.pop_front() {
+                ack.await?;
+            }
+        }
+        trace!("downloading fragment {}", fragment_index);
+        in_flight.push_back(host.submit_download(download_index as u8, fragment)?);
+    }
+    for ack in in_flight {
+        ack.await?;
     }
     debug!("download complete");
     Ok(())
@@ -261,3 +376,27 @@ fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
     result.copy_from_slice(&data[offset..offset + N]);
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bd_addr_in_config() {
+        let addr = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let config = vec![0x55, 0xAB, 0x23, 0x87, 0x04, 0x00, 0x0C, 0x00, 0x01, 0x02];
+        let patched = patch_config(Some(config), RTL_CONFIG_BD_ADDR, &addr).unwrap();
+        assert_eq!(
+            patched,
+            [0x55, 0xAB, 0x23, 0x87, 0x0D, 0x00, 0x0C, 0x00, 0x01, 0x02, 0x44, 0x00, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]
+        );
+        let addr = [0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F];
+        let patched = patch_config(Some(patched), RTL_CONFIG_BD_ADDR, &addr).unwrap();
+        assert_eq!(&patched[4..6], &[0x0D, 0x00]);
+        assert_eq!(&patched[13..], &addr);
+
+        let created = patch_config(None, RTL_CONFIG_BD_ADDR, &addr).unwrap();
+        assert_eq!(created.len(), RTL_CONFIG_HEADER_SIZE + 9);
+        assert!(patch_config(Some(vec![0x00; 6]), RTL_CONFIG_BD_ADDR, &addr).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use instructor::BufferMut;
+use tracing::debug;
+
+use crate::hci::consts::{BdAddr, CompanyId};
+use crate::hci::{Error, FirmwareLoader, Hci, Opcode, OpcodeGroup, ScoRouting};
+
+/// The vendor specific commands of controllers that do not need any firmware to be loaded:
+/// the public address of Broadcom and Intel controllers and the SCO routing of Broadcom controllers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VendorCommands;
+
+impl VendorCommands {
+    async fn set_bd_addr(&self, hci: &Hci, addr: BdAddr) -> Result<bool, Error> {
+        let opcode = match hci.read_local_version().await?.company_id {
+            // Broadcom Write_BD_ADDR (0xFC01)
+            CompanyId::BROADCOM => Opcode::new(OpcodeGroup::Vendor, 0x0001),
+            // Intel Write_BD_ADDR (0xFC31)
+            CompanyId::INTEL => Opcode::new(OpcodeGroup::Vendor, 0x0031),
+            company_id => {
+                debug!("Unknown command to change the address of {:?} controllers", company_id);
+                return Ok(false);
+            }
+        };
+        hci.call_with_args(opcode, |p| p.write_le(addr)).await?;
+        Ok(true)
+    }
+
+    async fn set_sco_routing(&self, hci: &Hci, routing: ScoRouting) -> Result<bool, Error> {
+        match hci.read_local_version().await?.company_id {
+            CompanyId::BROADCOM => {}
+            // Realtek keeps the PCM settings in the config of the firmware, there is no command to change them
+            CompanyId::REALTEK if routing != ScoRouting::Hci => {
+                return Err(Error::NotSupported("Realtek controllers can only route SCO audio over HCI"));
+            }
+            company_id => {
+                debug!("Unknown command to change the SCO routing of {:?} controllers", company_id);
+                return Ok(false);
+            }
+        }
+        // Broadcom Write_SCO_PCM_Int_Param (0xFC1C): routing, clock rate, frame type, sync role and clock role
+        let (route, config) = match routing {
+            ScoRouting::Pcm(config) => (0x00u8, config),
+            ScoRouting::Hci => (0x01u8, Default::default()),
+            ScoRouting::I2s(config) => (0x03u8, config)
+        };
+        hci.call_with_args(Opcode::new(OpcodeGroup::Vendor, 0x001C), |p| {
+            p.write_le(route);
+            p.write_le(config.clock_rate as u8);
+            p.write_le(config.long_frame_sync as u8);
+            p.write_le(config.master as u8);
+            p.write_le(config.master as u8);
+        })
+        .await?;
+        if let ScoRouting::I2s(config) = routing {
+            // Broadcom Write_I2SPCM_Interface_Param (0xFC6D): enable, role, sample rate and clock rate
+            hci.call_with_args(Opcode::new(OpcodeGroup::Vendor, 0x006D), |p| {
+                p.write_le(1u8);
+                p.write_le(config.master as u8);
+                p.write_le(config.wideband as u8);
+                p.write_le(config.clock_rate as u8);
+            })
+            .await?;
+        }
+        Ok(true)
+    }
+}
+
+impl FirmwareLoader for VendorCommands {
+    fn try_load_firmware<'a>(&'a self, _hci: &'a Hci) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn set_bd_addr<'a>(&'a self, hci: &'a Hci, addr: BdAddr) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(Self::set_bd_addr(self, hci, addr))
+    }
+
+    fn set_sco_routing<'a>(&'a self, hci: &'a Hci, routing: ScoRouting) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(Self::set_sco_routing(self, hci, routing))
+    }
+}
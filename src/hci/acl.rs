@@ -1,4 +1,4 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::Bytes;
 use instructor::utils::Length;
 use instructor::{Exstruct, Instruct};
 use tracing::warn;
@@ -8,47 +8,74 @@ use crate::utils::SliceExt;
 
 #[derive(Default)]
 pub struct AclDataAssembler {
-    buffer: BytesMut,
+    /// The packets of the PDU in progress.
+    fragments: Vec<Bytes>,
+    received: usize,
     l2cap_pdu_length: usize,
     in_progress: bool
 }
 
 impl AclDataAssembler {
+    #[cfg_attr(feature = "flamegraph", inline(never))]
     pub fn push(&mut self, header: AclHeader, data: Bytes) -> Option<Bytes> {
         if header.pb.is_first() {
             debug_assert!(!self.in_progress);
             if let Some(l2cap_pdu_length) = data.get_chunk(0).copied().map(u16::from_le_bytes) {
-                self.buffer.clear();
-                self.buffer.put(data);
+                self.fragments.clear();
+                self.received = 0;
                 self.l2cap_pdu_length = l2cap_pdu_length as usize;
                 self.in_progress = true;
             } else {
                 warn!("A start packet should contain a valid L2CAP PDU length");
                 return None;
             }
-        } else if self.in_progress {
-            self.buffer.put(data);
-        } else {
+        } else if !self.in_progress {
             warn!("A continuation packet should not be the first packet");
             return None;
         }
+        self.received += data.len();
+        self.fragments.push(data);
 
         debug_assert!(self.in_progress);
-        match self.buffer.len().cmp(&(self.l2cap_pdu_length + 4)) {
+        match self.received.cmp(&(self.l2cap_pdu_length + 4)) {
             std::cmp::Ordering::Less => None,
             std::cmp::Ordering::Equal => {
                 self.in_progress = false;
-                Some(self.buffer.split().freeze())
+                Some(reassemble(std::mem::take(&mut self.fragments)))
             }
             std::cmp::Ordering::Greater => {
                 warn!("L2CAP PDU length exceeded");
                 self.in_progress = false;
+                self.fragments.clear();
                 None
             }
         }
     }
 }
 
+/// Joins the fragments of a PDU. The PDU keeps the received packets alive, so that they only count as processed
+/// once the PDU itself was consumed (see [Hci::enable_host_flow_control](crate::hci::Hci::enable_host_flow_control)).
+fn reassemble(mut fragments: Vec<Bytes>) -> Bytes {
+    if fragments.len() == 1 {
+        return fragments.swap_remove(0);
+    }
+    Bytes::from_owner(Reassembled {
+        data: fragments.concat(),
+        _fragments: fragments
+    })
+}
+
+struct Reassembled {
+    data: Vec<u8>,
+    _fragments: Vec<Bytes>
+}
+
+impl AsRef<[u8]> for Reassembled {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 // ([Vol 4] Part E, Section 5.4.2).
 #[derive(Debug, Copy, Clone, Exstruct, Instruct)]
 #[instructor(endian = "little")]
@@ -1,9 +1,12 @@
+use std::time::Duration;
+
 use bytes::BufMut;
 use instructor::BufferMut;
 
 use crate::hci::commands::{Opcode, OpcodeGroup};
 use crate::hci::consts::{ClassOfDevice, EventMask};
-use crate::hci::{Error, Hci};
+use crate::hci::identity::truncate_utf8;
+use crate::hci::{Error, Hci, MAX_LOCAL_NAME_LENGTH};
 
 /// Controller and baseband commands ([Vol 4] Part E, Section 7.3).
 impl Hci {
@@ -21,13 +24,13 @@ impl Hci {
             .await
     }
 
-    /// Sets the user-friendly name for the BR/EDR controller
+    /// Sets the user-friendly name for the BR/EDR controller, names longer than 248 bytes are truncated
     /// ([Vol 4] Part E, Section 7.3.11).
     pub async fn write_local_name(&self, name: &str) -> Result<(), Error> {
-        assert!(name.len() < 248);
+        let name = truncate_utf8(name, MAX_LOCAL_NAME_LENGTH);
         self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0013), |p| {
             p.put_slice(name.as_bytes());
-            p.put_bytes(0, 248 - name.len());
+            p.put_bytes(0, MAX_LOCAL_NAME_LENGTH - name.len());
         })
         .await
     }
@@ -41,6 +44,39 @@ impl Hci {
         .await
     }
 
+    /// ([Vol 4] Part E, Section 7.3.38).
+    pub async fn set_controller_to_host_flow_control(&self, acl: bool) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0031), |p| {
+            p.write_le(acl);
+        })
+        .await
+    }
+
+    /// Tells the controller how many ACL packets the host can buffer
+    /// ([Vol 4] Part E, Section 7.3.39).
+    pub async fn host_buffer_size(&self, acl_packet_length: u16, acl_packets: u16) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0033), |p| {
+            p.write_le(acl_packet_length);
+            // Synchronous data is not supported
+            p.write_le(0u8);
+            p.write_le(acl_packets);
+            p.write_le(0u16);
+        })
+        .await
+    }
+
+    /// Makes the controller discard automatically flushable packets of the connection that weren't transmitted
+    /// within `timeout`, `None` disables the flush timeout ([Vol 4] Part E, Section 7.3.30).
+    pub async fn write_automatic_flush_timeout(&self, handle: u16, timeout: Option<Duration>) -> Result<(), Error> {
+        // In baseband slots of 0.625ms, up to 1279ms
+        let slots = timeout.map_or(0, |timeout| (timeout.as_micros() / 625).clamp(1, 0x07FF) as u16);
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0028), |p| {
+            p.write_le(handle);
+            p.write_le(slots);
+        })
+        .await
+    }
+
     /// Sets the class of device
     /// ([Vol 4] Part E, Section 7.3.26).
     pub async fn write_class_of_device(&self, cod: ClassOfDevice) -> Result<(), Error> {
@@ -50,6 +86,16 @@ impl Hci {
         .await
     }
 
+    /// ([Vol 4] Part E, Section 7.3.56).
+    pub async fn write_extended_inquiry_response(&self, eir: &[u8; 240]) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0052), |p| {
+            // FEC_Required
+            p.write_le(true);
+            p.put_slice(eir);
+        })
+        .await
+    }
+
     /// ([Vol 4] Part E, Section 7.3.59).
     pub async fn set_simple_pairing_support(&self, enabled: bool) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x0056), |p| {
@@ -58,6 +104,17 @@ impl Hci {
         .await
     }
 
+    /// Announces that the host supports LE, which dual-mode controllers require before LE advertising
+    /// ([Vol 4] Part E, Section 7.3.79).
+    pub async fn write_le_host_support(&self, enabled: bool) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x006D), |p| {
+            p.write_le(enabled);
+            // Simultaneous_LE_Host, unused since 4.1
+            p.write_le(false);
+        })
+        .await
+    }
+
     /// ([Vol 4] Part E, Section 7.3.92).
     pub async fn set_secure_connections_support(&self, enabled: bool) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::HciControl, 0x007A), |p| {
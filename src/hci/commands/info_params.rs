@@ -1,7 +1,7 @@
 use instructor::Exstruct;
 
 use crate::hci::commands::{Opcode, OpcodeGroup};
-use crate::hci::consts::{CompanyId, CoreVersion, RemoteAddr};
+use crate::hci::consts::{BdAddr, CompanyId, CoreVersion};
 use crate::hci::{Error, Hci};
 
 /// Informational parameters commands ([Vol 4] Part E, Section 7.4).
@@ -28,7 +28,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.4.6).
-    pub async fn read_bd_addr(&self) -> Result<RemoteAddr, Error> {
+    pub async fn read_bd_addr(&self) -> Result<BdAddr, Error> {
         self.call(Opcode::new(OpcodeGroup::InfoParams, 0x0009))
             .await
     }
@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use bitflags::bitflags;
+use bytes::BufMut;
+use instructor::utils::u24;
+use instructor::{BufferMut, Instruct};
+
+use crate::ensure;
+use crate::hci::commands::{Opcode, OpcodeGroup};
+use crate::hci::consts::{BdAddr, LeEventMask};
+use crate::hci::{Error, Hci};
+
+/// The most advertising data a single `HCI_LE_Set_Extended_Advertising_Data` command can carry
+/// ([Vol 4] Part E, Section 7.8.54).
+pub const MAX_ADVERTISING_DATA_FRAGMENT: usize = 251;
+/// The most advertising data a legacy advertising PDU can carry ([Vol 6] Part B, Section 2.3.1).
+pub const MAX_LEGACY_ADVERTISING_DATA: usize = 31;
+
+/// LE controller commands ([Vol 4] Part E, Section 7.8).
+impl Hci {
+    /// Controls which LE meta events are reported to the host.
+    /// `EventCode::LeMeta` also has to be enabled in the regular event mask
+    /// ([Vol 4] Part E, Section 7.8.1).
+    pub async fn set_le_event_mask(&self, mask: LeEventMask) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x0001), |p| {
+            p.write_le(mask);
+        })
+        .await
+    }
+
+    /// Sets the random address an advertising set uses if it advertises with [OwnAddressType::Random]
+    /// ([Vol 4] Part E, Section 7.8.52).
+    pub async fn set_advertising_set_random_address(&self, handle: u8, addr: BdAddr) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x0035), |p| {
+            p.write_le(handle);
+            p.write_le(addr);
+        })
+        .await
+    }
+
+    /// Creates or reconfigures the advertising set `handle` and returns the transmit power selected by the
+    /// controller in dBm ([Vol 4] Part E, Section 7.8.53).
+    pub async fn set_extended_advertising_parameters(
+        &self, handle: u8, parameters: &ExtendedAdvertisingParameters
+    ) -> Result<i8, Error> {
+        // In units of 0.625ms, from 20ms up to the 24 bit maximum
+        let interval = |interval: Duration| (interval.as_micros() / 625).clamp(0x20, 0xFF_FFFF) as u32;
+        self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x0036), |p| {
+            p.write_le(handle);
+            p.write_le(parameters.properties);
+            p.write_le(u24::new(interval(parameters.min_interval)));
+            p.write_le(u24::new(interval(parameters.max_interval)));
+            // All three primary advertising channels
+            p.write_le(0x07u8);
+            p.write_le(parameters.own_address_type as u8);
+            // No peer address, as the advertising is undirected
+            p.write_le(0x00u8);
+            p.write_le(BdAddr::new([0; 6]));
+            // Process scan and connection requests from all devices
+            p.write_le(0x00u8);
+            // 0x7F lets the controller choose the transmit power
+            p.write_le(parameters.tx_power.unwrap_or(0x7F));
+            // LE 1M on the primary and the secondary channels
+            p.write_le(0x01u8);
+            p.write_le(0x00u8);
+            p.write_le(0x01u8);
+            p.write_le(parameters.sid);
+            p.write_le(false);
+        })
+        .await
+    }
+
+    /// Sets the advertising data of the advertising set `handle`, data that doesn't fit into a single command is
+    /// sent in fragments. Legacy advertising sets take at most [MAX_LEGACY_ADVERTISING_DATA] bytes
+    /// ([Vol 4] Part E, Section 7.8.54).
+    pub async fn set_extended_advertising_data(&self, handle: u8, data: &[u8]) -> Result<(), Error> {
+        let fragments = data.chunks(MAX_ADVERTISING_DATA_FRAGMENT).count().max(1);
+        for i in 0..fragments {
+            let start = (i * MAX_ADVERTISING_DATA_FRAGMENT).min(data.len());
+            let fragment = &data[start..(start + MAX_ADVERTISING_DATA_FRAGMENT).min(data.len())];
+            let operation: u8 = match (i == 0, i == fragments - 1) {
+                (true, true) => 0x03,
+                (true, false) => 0x01,
+                (false, true) => 0x02,
+                (false, false) => 0x00
+            };
+            self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x0037), |p| {
+                p.write_le(handle);
+                p.write_le(operation);
+                // The controller should not fragment the data any further
+                p.write_le(0x01u8);
+                p.write_le(fragment.len() as u8);
+                p.put_slice(fragment);
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Starts or stops the given advertising sets, an empty list together with `enable == false` stops all of them.
+    /// An advertising set without `duration` keeps advertising until it is disabled
+    /// ([Vol 4] Part E, Section 7.8.56).
+    pub async fn set_extended_advertising_enable(
+        &self, enable: bool, sets: &[(u8, Option<Duration>)]
+    ) -> Result<(), Error> {
+        ensure!(!enable || !sets.is_empty(), "At least one advertising set has to be enabled");
+        ensure!(sets.len() <= 0x3F, "Too many advertising sets");
+        self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x0039), |p| {
+            p.write_le(enable);
+            p.write_le(sets.len() as u8);
+            for (handle, duration) in sets {
+                // In units of 10ms, zero advertises until disabled
+                let duration = duration.map_or(0, |duration| (duration.as_millis() / 10).clamp(1, 0xFFFF) as u16);
+                p.write_le(*handle);
+                p.write_le(duration);
+                // No limit on the number of advertising events
+                p.write_le(0x00u8);
+            }
+        })
+        .await
+    }
+
+    /// Returns how much advertising data the controller supports in a single advertising set
+    /// ([Vol 4] Part E, Section 7.8.57).
+    pub async fn read_maximum_advertising_data_length(&self) -> Result<u16, Error> {
+        self.call(Opcode::new(OpcodeGroup::Le, 0x003A))
+            .await
+    }
+
+    /// Removes a disabled advertising set from the controller ([Vol 4] Part E, Section 7.8.59).
+    pub async fn remove_advertising_set(&self, handle: u8) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::Le, 0x003C), |p| {
+            p.write_le(handle);
+        })
+        .await
+    }
+}
+
+bitflags! {
+    /// `Advertising_Event_Properties` ([Vol 4] Part E, Section 7.8.53).
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct)]
+    #[instructor(bitflags)]
+    pub struct AdvertisingEventProperties: u16 {
+        const CONNECTABLE = 1 << 0;
+        const SCANNABLE = 1 << 1;
+        const DIRECTED = 1 << 2;
+        const HIGH_DUTY_CYCLE = 1 << 3;
+        /// Uses the advertising PDUs of Bluetooth 4.x, which every scanner understands.
+        const LEGACY = 1 << 4;
+        const ANONYMOUS = 1 << 5;
+        const INCLUDE_TX_POWER = 1 << 6;
+    }
+}
+
+/// The address an advertising set is sent from ([Vol 4] Part E, Section 7.8.53).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum OwnAddressType {
+    /// The BD_ADDR of the controller, which ties the advertisements to the BR/EDR identity of the device.
+    Public = 0x00,
+    Random = 0x01
+}
+
+/// The parameters of an advertising set ([Vol 4] Part E, Section 7.8.53).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtendedAdvertisingParameters {
+    pub properties: AdvertisingEventProperties,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    pub own_address_type: OwnAddressType,
+    /// The maximum transmit power in dBm, `None` lets the controller decide.
+    pub tx_power: Option<i8>,
+    /// The advertising set id that scanners use to tell the sets of a device apart.
+    pub sid: u8
+}
+
+impl Default for ExtendedAdvertisingParameters {
+    /// Non-connectable legacy advertising from the public address every 100 to 150ms.
+    fn default() -> Self {
+        Self {
+            properties: AdvertisingEventProperties::LEGACY,
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_millis(150),
+            own_address_type: OwnAddressType::Public,
+            tx_power: None,
+            sid: 0
+        }
+    }
+}
@@ -1,31 +1,58 @@
-use bytes::BufMut;
+use bytes::{Buf, BufMut, Bytes};
 use instructor::{Buffer, BufferMut, Exstruct, Instruct};
 use tokio::sync::mpsc::unbounded_channel;
+use tracing::warn;
 use crate::ensure;
 
-use crate::hci::consts::{AuthenticationRequirements, EncryptionMode, EventCode, IoCapability, Lap, LinkKey, OobDataPresence, RemoteAddr, Role, Status};
+use crate::adv::AdvertisingData;
+use crate::hci::consts::{AuthenticationRequirements, BdAddr, ClassOfDevice, EncryptionMode, EventCode, IoCapability, Lap, LinkKey, OobDataPresence, Role, Status};
+use crate::hci::remote_info::{LmpFeatures, RemoteVersion};
 use crate::hci::{Error, Hci, Opcode, OpcodeGroup};
 
 impl Hci {
     /// Start the inquiry process to discover other Bluetooth devices in the vicinity.
+    /// Returns the devices that responded once the inquiry is complete.
     /// ([Vol 4] Part E, Section 7.1.1).
     ///
     /// # Parameters
     /// - `time`: The duration of the inquiry process in 1.28s units. Range: 1-30.
     /// - `max_responses`: The maximum number of responses to receive. 0 means no limit.
-    pub async fn inquiry(&self, lap: Lap, time: u8, max_responses: u8) -> Result<(), Error> {
+    pub async fn inquiry(&self, lap: Lap, time: u8, max_responses: u8) -> Result<Vec<InquiryResult>, Error> {
+        const EVENTS: [EventCode; 4] = [
+            EventCode::InquiryComplete,
+            EventCode::InquiryResult,
+            EventCode::InquiryResultWithRssi,
+            EventCode::ExtendedInquiryResult
+        ];
+        let (tx, mut rx) = unbounded_channel();
+        self.register_event_handler(EVENTS, tx)?;
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0001), |p| {
             p.write_le(lap);
             p.write_le(time);
             p.write_le(max_responses);
         })
         .await?;
-        // TODO return channel for inquiry results
-        Ok(())
+        let mut results: Vec<InquiryResult> = Vec::new();
+        while let Some((code, mut packet)) = rx.recv().await {
+            if code == EventCode::InquiryComplete {
+                // ([Vol 4] Part E, Section 7.7.1).
+                let status: Status = packet.read_le()?;
+                packet.finish()?;
+                ensure!(status.is_ok(), Error::Controller(status));
+                return Ok(results);
+            }
+            for result in InquiryResult::parse(code, &mut packet)? {
+                match results.iter_mut().find(|r| r.addr == result.addr) {
+                    Some(existing) => *existing = result,
+                    None => results.push(result)
+                }
+            }
+        }
+        Err(Error::EventLoopClosed)
     }
 
     // ([Vol 4] Part E, Section 7.1.5).
-    pub async fn create_connection(&self, addr: RemoteAddr, allow_role_switch: bool) -> Result<(), Error> {
+    pub async fn create_connection(&self, addr: BdAddr, allow_role_switch: bool) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0005), |p| {
             p.write_le(addr);
             p.write_le(0xCC18u16);
@@ -37,9 +64,31 @@ impl Hci {
         Ok(())
     }
 
+    /// Stops paging a device, a `ConnectionComplete` event still reports the end of the page.
+    /// Fails if the connection was already established.
+    /// ([Vol 4] Part E, Section 7.1.7).
+    pub async fn create_connection_cancel(&self, addr: BdAddr) -> Result<(), Error> {
+        let _: BdAddr = self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0008), |p| {
+            p.write_le(addr);
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Terminates a connection, completion is reported by a `DisconnectionComplete` event.
+    /// ([Vol 4] Part E, Section 7.1.6).
+    pub async fn disconnect(&self, handle: u16, reason: Status) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0006), |p| {
+            p.write_le(handle);
+            p.write_le(reason);
+        })
+        .await?;
+        Ok(())
+    }
+
     /// Accept a connection request from a remote device.
     /// ([Vol 4] Part E, Section 7.1.8).
-    pub async fn accept_connection_request(&self, bd_addr: RemoteAddr, role: Role) -> Result<(), Error> {
+    pub async fn accept_connection_request(&self, bd_addr: BdAddr, role: Role) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0009), |p| {
             p.write_le(bd_addr);
             p.write_le(role);
@@ -50,7 +99,7 @@ impl Hci {
 
     /// Reject a connection request from a remote device.
     /// ([Vol 4] Part E, Section 7.1.9).
-    pub async fn reject_connection_request(&self, bd_addr: RemoteAddr, reason: Status) -> Result<(), Error> {
+    pub async fn reject_connection_request(&self, bd_addr: BdAddr, reason: Status) -> Result<(), Error> {
         assert!(matches!(
             reason,
             Status::ConnectionRejectedDueToLimitedResources
@@ -66,7 +115,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.1.10).
-    pub async fn link_key_present(&self, bd_addr: RemoteAddr, key: &LinkKey) -> Result<RemoteAddr, Error> {
+    pub async fn link_key_present(&self, bd_addr: BdAddr, key: &LinkKey) -> Result<BdAddr, Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x000B), |p| {
             p.write_le(bd_addr);
             p.write_le_ref(key);
@@ -75,7 +124,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.1.11).
-    pub async fn link_key_not_present(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+    pub async fn link_key_not_present(&self, bd_addr: BdAddr) -> Result<BdAddr, Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x000C), |p| {
             p.write_le(bd_addr);
         })
@@ -83,7 +132,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.1.12).
-    pub async fn pin_code_request_reply(&self, bd_addr: RemoteAddr, pin: &str) -> Result<RemoteAddr, Error> {
+    pub async fn pin_code_request_reply(&self, bd_addr: BdAddr, pin: &str) -> Result<BdAddr, Error> {
         assert!(pin.len() <= 16);
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x000D), |p| {
             p.write_le(bd_addr);
@@ -145,7 +194,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.1.19).
-    pub async fn request_remote_name(&self, bd_addr: RemoteAddr, mode: PageScanRepititionMode) -> Result<(), Error> {
+    pub async fn request_remote_name(&self, bd_addr: BdAddr, mode: PageScanRepititionMode) -> Result<(), Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x0019), |p| {
             p.write_le(bd_addr);
             p.write_le(mode);
@@ -155,10 +204,52 @@ impl Hci {
         }).await
     }
 
+    /// ([Vol 4] Part E, Section 7.1.21).
+    pub async fn read_remote_supported_features(&self, handle: u16) -> Result<LmpFeatures, Error> {
+        let (tx, mut rx) = unbounded_channel();
+        self.register_event_handler([EventCode::ReadRemoteSupportedFeaturesComplete], tx)?;
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x001B), |p| {
+            p.write_le(handle);
+        }).await?;
+        while let Some((code, mut packet)) = rx.recv().await {
+            assert_eq!(code, EventCode::ReadRemoteSupportedFeaturesComplete);
+            let status: Status = packet.read_le()?;
+            let event_handle: u16 = packet.read_le()?;
+            let features: LmpFeatures = packet.read_le()?;
+            packet.finish()?;
+            if event_handle == handle {
+                ensure!(status.is_ok(), Error::Controller(status));
+                return Ok(features);
+            }
+        }
+        Err(Error::EventLoopClosed)
+    }
+
+    /// ([Vol 4] Part E, Section 7.1.23).
+    pub async fn read_remote_version_information(&self, handle: u16) -> Result<RemoteVersion, Error> {
+        let (tx, mut rx) = unbounded_channel();
+        self.register_event_handler([EventCode::ReadRemoteVersionInformationComplete], tx)?;
+        self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x001D), |p| {
+            p.write_le(handle);
+        }).await?;
+        while let Some((code, mut packet)) = rx.recv().await {
+            assert_eq!(code, EventCode::ReadRemoteVersionInformationComplete);
+            let status: Status = packet.read_le()?;
+            let event_handle: u16 = packet.read_le()?;
+            let version: RemoteVersion = packet.read_le()?;
+            packet.finish()?;
+            if event_handle == handle {
+                ensure!(status.is_ok(), Error::Controller(status));
+                return Ok(version);
+            }
+        }
+        Err(Error::EventLoopClosed)
+    }
+
     /// ([Vol 4] Part E, Section 7.1.29).
     pub async fn io_capability_reply(
-        &self, bd_addr: RemoteAddr, io: IoCapability, oob: OobDataPresence, auth: AuthenticationRequirements
-    ) -> Result<RemoteAddr, Error> {
+        &self, bd_addr: BdAddr, io: IoCapability, oob: OobDataPresence, auth: AuthenticationRequirements
+    ) -> Result<BdAddr, Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x002B), |p| {
             p.write_le(bd_addr);
             p.write_le(io);
@@ -169,7 +260,7 @@ impl Hci {
     }
 
     /// ([Vol 4] Part E, Section 7.1.30).
-    pub async fn user_confirmation_request_accept(&self, bd_addr: RemoteAddr) -> Result<RemoteAddr, Error> {
+    pub async fn user_confirmation_request_accept(&self, bd_addr: BdAddr) -> Result<BdAddr, Error> {
         self.call_with_args(Opcode::new(OpcodeGroup::LinkControl, 0x002C), |p| {
             p.write_le(bd_addr);
         })
@@ -177,10 +268,97 @@ impl Hci {
     }
 }
 
+/// A device that responded to an inquiry.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InquiryResult {
+    pub addr: BdAddr,
+    pub page_scan_repetition_mode: PageScanRepititionMode,
+    pub class_of_device: ClassOfDevice,
+    pub clock_offset: u16,
+    /// Only reported in inquiry modes with RSSI.
+    pub rssi: Option<i8>,
+    /// Only reported in the extended inquiry mode, see [AdvertisingData::local_name] for the name.
+    pub extended_inquiry_response: Option<AdvertisingData>
+}
+
+impl InquiryResult {
+    fn parse(code: EventCode, packet: &mut Bytes) -> Result<Vec<Self>, instructor::Error> {
+        let count: u8 = packet.read_le()?;
+        let count = count as usize;
+        let addrs = (0..count).map(|_| packet.read_le()).collect::<Result<Vec<BdAddr>, _>>()?;
+        let modes = (0..count).map(|_| packet.read_le()).collect::<Result<Vec<PageScanRepititionMode>, _>>()?;
+        let reserved = match code {
+            // ([Vol 4] Part E, Section 7.7.2).
+            EventCode::InquiryResult => 2,
+            // ([Vol 4] Part E, Section 7.7.33 and 7.7.38).
+            _ => 1
+        };
+        ensure!(packet.remaining() >= count * reserved, instructor::Error::TooShort);
+        packet.advance(count * reserved);
+        let classes = (0..count).map(|_| packet.read_le()).collect::<Result<Vec<ClassOfDevice>, _>>()?;
+        let clock_offsets = (0..count).map(|_| packet.read_le()).collect::<Result<Vec<u16>, _>>()?;
+        let rssi = match code {
+            EventCode::InquiryResult => vec![None; count],
+            _ => (0..count)
+                .map(|_| packet.read_le().map(Some))
+                .collect::<Result<Vec<Option<i8>>, _>>()?
+        };
+        // ([Vol 4] Part E, Section 7.7.38), always reports a single device
+        let eir = match code {
+            EventCode::ExtendedInquiryResult => AdvertisingData::parse(packet.split_off(0))
+                .map_err(|err| warn!("Malformed extended inquiry response: {:?}", err))
+                .ok(),
+            _ => None
+        };
+        packet.finish()?;
+        Ok((0..count)
+            .map(|i| InquiryResult {
+                addr: addrs[i],
+                page_scan_repetition_mode: modes[i],
+                class_of_device: classes[i],
+                clock_offset: clock_offsets[i],
+                rssi: rssi[i],
+                extended_inquiry_response: eir.clone()
+            })
+            .collect())
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PageScanRepititionMode {
     R0 = 0x00,
     R1 = 0x01,
     R2 = 0x02
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::hci::commands::link_control::{InquiryResult, PageScanRepititionMode};
+    use crate::hci::consts::{BdAddr, EventCode};
+
+    #[test]
+    fn parse_inquiry_result() {
+        let mut packet = Bytes::from_static(&[
+            0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x00, 0x00, 0x04, 0x04, 0x24, 0x34, 0x12
+        ]);
+        let results = InquiryResult::parse(EventCode::InquiryResult, &mut packet).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].addr, BdAddr::new([1, 2, 3, 4, 5, 6]));
+        assert_eq!(results[0].page_scan_repetition_mode, PageScanRepititionMode::R1);
+        assert_eq!(results[0].clock_offset, 0x1234);
+        assert_eq!(results[0].rssi, None);
+
+        // Claims two devices but ends after their repetition modes
+        let mut packet = Bytes::from_static(&[
+            0x02, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x01
+        ]);
+        assert!(InquiryResult::parse(EventCode::InquiryResult, &mut packet).is_err());
+        let mut packet = Bytes::from_static(&[0xFF, 0x01, 0x02]);
+        assert!(InquiryResult::parse(EventCode::InquiryResultWithRssi, &mut packet).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use bitflags::bitflags;
 use instructor::{Buffer, BufferMut, Exstruct, Instruct};
 use tokio::sync::mpsc::unbounded_channel;
 use crate::ensure;
-use crate::hci::consts::{EventCode, RemoteAddr, Role, Status};
+use crate::hci::consts::{BdAddr, EventCode, Role, Status};
 use crate::hci::{Error, Hci, Opcode, OpcodeGroup};
 
 impl Hci {
@@ -16,7 +16,7 @@ impl Hci {
     }
 
     // ([Vol 4] Part E, Section 7.2.8).
-    pub async fn switch_role(&self, addr: RemoteAddr, role: Role) -> Result<Role, Error> {
+    pub async fn switch_role(&self, addr: BdAddr, role: Role) -> Result<Role, Error> {
         let (tx, mut rx) = unbounded_channel();
         self.register_event_handler([EventCode::RoleChange], tx)?;
         self.call_with_args(Opcode::new(OpcodeGroup::LinkPolicy, 0x000B), |p| {
@@ -26,7 +26,7 @@ impl Hci {
         while let Some((code, mut packet)) = rx.recv().await {
             assert_eq!(code, EventCode::RoleChange);
             let status: Status = packet.read_le()?;
-            let target_addr: RemoteAddr = packet.read_le()?;
+            let target_addr: BdAddr = packet.read_le()?;
             let new_role: Role = packet.read_le()?;
             packet.finish()?;
             if target_addr == addr {
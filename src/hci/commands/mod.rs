@@ -1,15 +1,20 @@
 mod hci_control;
 mod info_params;
+mod le;
 mod link_control;
 mod link_policy;
+mod status_params;
+mod testing;
 
 use std::fmt::{Debug, Formatter};
 use instructor::Exstruct;
 use num_enum::TryFromPrimitive;
 
 pub use info_params::*;
+pub use le::*;
 pub use link_control::*;
 pub use link_policy::*;
+pub use testing::LoopbackMode;
 
 //pub use hci_control::*;
 
@@ -31,11 +36,10 @@ pub enum OpcodeGroup {
 #[derive(Default, Copy, Clone, Eq, PartialEq, Exstruct)]
 pub struct Opcode(u16);
 
-#[allow(dead_code)]
 impl Opcode {
     /// Opcode 0x0000 is used to update `Num_HCI_Command_Packets`
     /// ([Vol 4] Part E, Section 7.7.14).
-    const NONE: Opcode = Opcode(0x0000);
+    pub(crate) const NONE: Opcode = Opcode(0x0000);
 }
 
 impl Opcode {
@@ -0,0 +1,19 @@
+use instructor::BufferMut;
+
+use crate::hci::commands::{Opcode, OpcodeGroup};
+use crate::hci::{Error, Hci};
+
+/// Status parameters commands ([Vol 4] Part E, Section 7.5).
+impl Hci {
+    /// Returns the RSSI of the connection in dB relative to the golden receive power range,
+    /// i.e. `0` means the signal is neither too weak nor too strong
+    /// ([Vol 4] Part E, Section 7.5.4).
+    pub async fn read_rssi(&self, handle: u16) -> Result<i8, Error> {
+        let (_, rssi): (u16, i8) = self
+            .call_with_args(Opcode::new(OpcodeGroup::StatusParams, 0x0005), |p| {
+                p.write_le(handle);
+            })
+            .await?;
+        Ok(rssi)
+    }
+}
@@ -0,0 +1,30 @@
+use instructor::{BufferMut, Exstruct, Instruct};
+
+use crate::hci::commands::{Opcode, OpcodeGroup};
+use crate::hci::{Error, Hci};
+
+/// Testing commands ([Vol 4] Part E, Section 7.6).
+impl Hci {
+    /// ([Vol 4] Part E, Section 7.6.1).
+    pub async fn read_loopback_mode(&self) -> Result<LoopbackMode, Error> {
+        self.call(Opcode::new(OpcodeGroup::Testing, 0x0001))
+            .await
+    }
+
+    /// In local loopback mode the controller reports a loopback connection and sends all data
+    /// and most commands straight back to the host ([Vol 4] Part E, Section 7.6.2).
+    pub async fn write_loopback_mode(&self, mode: LoopbackMode) -> Result<(), Error> {
+        self.call_with_args(Opcode::new(OpcodeGroup::Testing, 0x0002), |p| {
+            p.write_le(mode);
+        })
+        .await
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[repr(u8)]
+pub enum LoopbackMode {
+    Disabled = 0x00,
+    Local = 0x01,
+    Remote = 0x02
+}
@@ -1,42 +1,69 @@
 use std::collections::BTreeMap;
-use std::future::Future;
+use std::future::{poll_fn, Future};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use futures_lite::{Stream, StreamExt};
-use instructor::{Buffer, BufferMut};
+use instructor::Buffer;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
-use tokio::task::{spawn_blocking, JoinHandle};
-use tokio::{fs, spawn};
+use tokio::task::JoinHandle;
 use tracing::{debug, trace, warn};
 
 use crate::ensure;
 use crate::hci::consts::*;
-use crate::hci::{Error, Hci};
-use crate::utils::catch_error;
+use crate::hci::devices::{DevicePropertyChange, DeviceRegistry};
+use crate::hci::remote_info::RemoteInfoCache;
+use crate::hci::{Error, Hci, PageScanRepititionMode};
+use crate::storage::{device_key, migrate_file, parent_dir, parse_device_key, persist, run_blocking, FileStorage, Storage};
+use crate::utils::clock::{sleep, Timestamped};
+use crate::utils::redact::redacted;
+use crate::utils::{catch_error, spawn_supervised};
 
-#[derive(Debug, Clone)]
+/// The namespace of the link keys in the [Storage], one entry per bonded device.
+pub const LINK_KEY_NAMESPACE: &str = "link-keys";
+
+#[derive(Clone)]
 pub struct ConnectionManagerBuilder {
-    link_key_store: PathBuf,
-    simple_secure_pairing: bool
+    storage: Storage,
+    /// The file earlier versions kept all link keys in, moved into the storage on startup.
+    legacy_link_keys: PathBuf,
+    simple_secure_pairing: bool,
+    remote_info: Option<RemoteInfoCache>,
+    devices: Option<DeviceRegistry>,
+    rssi_interval: Option<Duration>
 }
 
 impl Default for ConnectionManagerBuilder {
     fn default() -> Self {
         Self {
-            link_key_store: PathBuf::from("link-keys.dat"),
-            simple_secure_pairing: true
+            storage: Storage::new(FileStorage::new(".")),
+            legacy_link_keys: PathBuf::from("link-keys.dat"),
+            simple_secure_pairing: true,
+            remote_info: None,
+            devices: None,
+            rssi_interval: None
         }
     }
 }
 
 impl ConnectionManagerBuilder {
+    /// Where the link keys of bonded devices are kept, a [FileStorage] in the working directory by default.
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Keeps the link keys in a [FileStorage] in the directory of `path`.
+    /// The keys of the file at `path`, which earlier versions used, are moved into it.
+    #[deprecated(note = "use with_storage")]
     pub fn with_link_key_store<P: AsRef<Path>>(mut self, path: P) -> Self {
-        self.link_key_store = PathBuf::from(path.as_ref());
+        let path = path.as_ref();
+        self.storage = Storage::new(FileStorage::new(parent_dir(path)));
+        self.legacy_link_keys = path.to_path_buf();
         self
     }
 
@@ -45,21 +72,49 @@ impl ConnectionManagerBuilder {
         self
     }
 
-    pub async fn spawn(self, hci: Arc<Hci>) -> Result<JoinHandle<()>, Error> {
-        let link_keys = match fs::read(&self.link_key_store).await {
-            Ok(data) => {
-                let mut data = data.as_slice();
-                let mut result = BTreeMap::new();
-                while !data.is_empty() {
-                    let addr: RemoteAddr = data.read_le()?;
-                    let key: LinkKey = data.read_le()?;
-                    result.insert(addr, key);
+    /// Queries the version and LMP features of connecting devices unless they are already cached.
+    pub fn with_remote_info_cache(mut self, cache: RemoteInfoCache) -> Self {
+        self.remote_info = Some(cache);
+        self
+    }
+
+    /// Reports the name, class of device, bonding and connection state of remote devices to `registry`.
+    /// The name of connecting devices is requested if it is not known yet.
+    pub fn with_device_registry(mut self, registry: DeviceRegistry) -> Self {
+        self.devices = Some(registry);
+        self
+    }
+
+    /// Reads the RSSI of every connection every `interval` and reports it to the device registry.
+    pub fn with_rssi_polling(mut self, interval: Duration) -> Self {
+        self.rssi_interval = Some(interval);
+        self
+    }
+
+    pub async fn spawn(self, hci: Arc<Hci>) -> Result<ConnectionManager, Error> {
+        migrate_file::<Error, _>(&self.storage, LINK_KEY_NAMESPACE, &self.legacy_link_keys, |mut data| {
+            let mut entries = Vec::new();
+            while !data.is_empty() {
+                let addr: BdAddr = data.read_le()?;
+                let key: LinkKey = data.read_le()?;
+                entries.push((addr, key.as_ref().to_vec()));
+            }
+            Ok(entries)
+        })
+        .await?;
+        let link_keys = run_blocking(&self.storage, |storage| {
+            let mut result = Vec::new();
+            for key in storage.keys(LINK_KEY_NAMESPACE)? {
+                if let (Some(addr), Some(data)) = (parse_device_key(&key), storage.read(LINK_KEY_NAMESPACE, &key)?) {
+                    result.push((addr, data));
                 }
-                result
             }
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
-            Err(err) => return Err(err.into())
-        };
+            Ok(result)
+        })
+        .await?
+        .into_iter()
+        .map(|(addr, data)| Ok((addr, data.as_slice().read_le::<LinkKey>()?)))
+        .collect::<Result<BTreeMap<_, _>, Error>>()?;
 
         let mut events = ConnectionEventReceiver::new(&hci)?;
 
@@ -67,13 +122,23 @@ impl ConnectionManagerBuilder {
             hci.set_simple_pairing_support(true).await?;
         }
 
+        if let Some(devices) = &self.devices {
+            for addr in link_keys.keys() {
+                devices.update(*addr, DevicePropertyChange::Bonded(true));
+            }
+        }
+
         let mut state = ConnectionManagerState {
-            hci,
-            link_key_store: self.link_key_store,
-            link_keys
+            hci: hci.clone(),
+            storage: self.storage,
+            link_keys,
+            remote_info: self.remote_info,
+            devices: self.devices,
+            rssi_interval: self.rssi_interval,
+            handles: BTreeMap::new()
         };
 
-        Ok(spawn(async move {
+        let task = spawn_supervised("connection-manager", "HCI", async move {
             while let Some(event) = events.recv().await {
                 // trace!("Connection event: {:?}", event);
                 state.handle_event(event).await.unwrap_or_else(|err| {
@@ -81,32 +146,92 @@ impl ConnectionManagerBuilder {
                 });
             }
             trace!("Connection event handler finished");
-        }))
+        });
+        Ok(ConnectionManager { hci, task })
+    }
+}
+
+/// The running connection manager, it answers the pairing and link key requests of all connections.
+/// Dropping it does not stop the manager, it runs until the event loop of the [Hci] closes.
+pub struct ConnectionManager {
+    pub(crate) hci: Arc<Hci>,
+    task: JoinHandle<Option<()>>
+}
+
+impl ConnectionManager {
+    /// Waits until the manager stopped.
+    pub async fn join(self) {
+        self.task.await.unwrap_or_else(|err| warn!("Connection manager panicked: {:?}", err));
     }
 }
 
 struct ConnectionManagerState {
     hci: Arc<Hci>,
-    link_key_store: PathBuf,
-    link_keys: BTreeMap<RemoteAddr, LinkKey>
+    storage: Storage,
+    link_keys: BTreeMap<BdAddr, LinkKey>,
+    remote_info: Option<RemoteInfoCache>,
+    devices: Option<DeviceRegistry>,
+    rssi_interval: Option<Duration>,
+    handles: BTreeMap<u16, BdAddr>
 }
 
 impl ConnectionManagerState {
     async fn handle_event(&mut self, event: ConnectionEvent) -> Result<(), Error> {
         match event {
-            ConnectionEvent::ConnectionRequest { addr, link_type, .. } => {
+            ConnectionEvent::ConnectionRequest { addr, class, link_type } => {
                 ensure!(link_type == LinkType::Acl, "Invalid link type");
-                debug!("Connection request: {}", addr);
+                debug!("Connection request: {}", redacted(&addr));
+                if let Some(devices) = &self.devices {
+                    devices.update(addr, DevicePropertyChange::ClassOfDevice(class));
+                }
                 self.hci
                     .accept_connection_request(addr, Role::Slave)
                     .await?;
             }
+            ConnectionEvent::ConnectionComplete { status, handle, addr, link_type, .. } if status.is_ok() && link_type == LinkType::Acl => {
+                if let Some(cache) = &self.remote_info {
+                    if self.link_keys.contains_key(&addr) {
+                        cache.set_bonded(addr);
+                    }
+                    if cache.get(addr).is_some_and(|info| info.has_controller_info()) {
+                        debug!("Using cached remote info for {}", redacted(&addr));
+                    } else {
+                        let query = query_remote_info(self.hci.clone(), cache.clone(), handle, addr);
+                        spawn_supervised("remote-info-query", format!("handle 0x{:04x}", handle), query);
+                    }
+                }
+                if let Some(devices) = &self.devices {
+                    self.handles.insert(handle, addr);
+                    devices.update(addr, DevicePropertyChange::Connected(true));
+                    if devices.get(addr).is_some_and(|device| device.name.is_none()) {
+                        self.hci
+                            .request_remote_name(addr, PageScanRepititionMode::R1)
+                            .await
+                            .unwrap_or_else(|err| warn!("Failed to request the name of {}: {:?}", redacted(&addr), err));
+                    }
+                    if let Some(interval) = self.rssi_interval {
+                        let polling = poll_rssi(self.hci.clone(), devices.clone(), handle, addr, interval);
+                        spawn_supervised("rssi-polling", format!("handle 0x{:04x}", handle), polling);
+                    }
+                }
+            }
+            ConnectionEvent::DisconnectionComplete { status, handle, .. } if status.is_ok() => {
+                if let (Some(devices), Some(addr)) = (&self.devices, self.handles.remove(&handle)) {
+                    devices.set_disconnected(addr);
+                }
+            }
+            ConnectionEvent::RemoteNameRequestComplete { status, addr, name } if status.is_ok() => {
+                debug!("Remote name of {}: {}", redacted(&addr), redacted(&name));
+                if let Some(devices) = &self.devices {
+                    devices.update(addr, DevicePropertyChange::NameResolved(name));
+                }
+            }
             ConnectionEvent::PinCodeRequest { addr } => {
-                debug!("Pin code request: {}", addr);
+                debug!("Pin code request: {}", redacted(&addr));
                 self.hci.pin_code_request_reply(addr, "0000").await?;
             }
             ConnectionEvent::LinkKeyRequest { addr } => {
-                debug!("Link key request: {}", addr);
+                debug!("Link key request: {}", redacted(&addr));
                 if let Some(key) = self.link_keys.get(&addr) {
                     debug!("   Link key present");
                     self.hci.link_key_present(addr, key).await?;
@@ -116,12 +241,18 @@ impl ConnectionManagerState {
                 }
             }
             ConnectionEvent::LinkKeyNotification { addr, key, key_type } => {
-                debug!("Link key notification: {} {:?} {:?}", addr, key, key_type);
+                debug!("Link key notification: {} {:?}", redacted(&addr), key_type);
                 self.link_keys.insert(addr, key);
-                self.save_link_keys();
+                persist(&self.storage, LINK_KEY_NAMESPACE, device_key(addr), Some(key.as_ref().to_vec()));
+                if let Some(cache) = &self.remote_info {
+                    cache.set_bonded(addr);
+                }
+                if let Some(devices) = &self.devices {
+                    devices.update(addr, DevicePropertyChange::Bonded(true));
+                }
             }
             ConnectionEvent::IoCapabilityRequest { addr} => {
-                debug!("Io capability request: {}", addr);
+                debug!("Io capability request: {}", redacted(&addr));
                 self.hci
                     .io_capability_reply(
                         addr,
@@ -132,28 +263,28 @@ impl ConnectionManagerState {
                     .await?;
             }
             ConnectionEvent::IoCapabilityResponse { addr, io, oob, auth } => {
-                debug!("Io capability response: {} {:?} {} {:?}", addr, io, oob, auth);
+                debug!("Io capability response: {} {:?} {} {:?}", redacted(&addr), io, oob, auth);
             }
             ConnectionEvent::UserConfirmationRequest { addr, passkey } => {
-                debug!("User confirmation request: {} {}", addr, passkey);
+                debug!("User confirmation request: {} {}", redacted(&addr), passkey);
                 self.hci.user_confirmation_request_accept(addr).await?;
             }
             ConnectionEvent::SimplePairingComplete { status, addr } => {
-                debug!("Simple pairing complete: {} {}", addr, status);
+                debug!("Simple pairing complete: {} {}", redacted(&addr), status);
             }
             ConnectionEvent::UserPasskeyNotification { addr, passkey } => {
-                debug!("User passkey notification: {} {}", addr, passkey);
+                debug!("User passkey notification: {} {}", redacted(&addr), passkey);
                 panic!("Passkeys not supported");
             }
             ConnectionEvent::UserPasskeyRequest { addr } => {
-                debug!("User passkey request: {}", addr);
+                debug!("User passkey request: {}", redacted(&addr));
                 panic!("Passkeys not supported");
             }
             ConnectionEvent::KeypressNotification { addr, ty } => {
-                debug!("Keypress notification: {} {:?}", addr, ty);
+                debug!("Keypress notification: {} {:?}", redacted(&addr), ty);
             }
             ConnectionEvent::RemoteOobDataRequest { addr } => {
-                debug!("Remote OOB data request: {}", addr);
+                debug!("Remote OOB data request: {}", redacted(&addr));
                 panic!("OOB data not supported");
             },
             _ => {}
@@ -161,15 +292,33 @@ impl ConnectionManagerState {
         Ok(())
     }
 
-    fn save_link_keys(&self) {
-        let mut data = BytesMut::new();
-        for (addr, key) in &self.link_keys {
-            data.write_le_ref(addr);
-            data.write_le_ref(key);
+}
+
+async fn query_remote_info(hci: Arc<Hci>, cache: RemoteInfoCache, handle: u16, addr: BdAddr) {
+    match hci.read_remote_version_information(handle).await {
+        Ok(version) => cache.update(addr, |info| info.version = Some(version)),
+        Err(err) => warn!("Failed to read remote version of {}: {:?}", redacted(&addr), err)
+    }
+    match hci.read_remote_supported_features(handle).await {
+        Ok(features) => cache.update(addr, |info| info.lmp_features = Some(features)),
+        Err(err) => warn!("Failed to read remote features of {}: {:?}", redacted(&addr), err)
+    }
+}
+
+/// Reads the RSSI until the connection is closed.
+async fn poll_rssi(hci: Arc<Hci>, devices: DeviceRegistry, handle: u16, addr: BdAddr, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        if !devices.get(addr).is_some_and(|device| device.connected) {
+            break;
+        }
+        match hci.read_rssi(handle).await {
+            Ok(rssi) => devices.update(addr, DevicePropertyChange::RssiUpdated(rssi)),
+            Err(err) => {
+                debug!("Stopped reading the RSSI of {}: {:?}", redacted(&addr), err);
+                break;
+            }
         }
-        let data = data.freeze();
-        let path = self.link_key_store.clone();
-        spawn_blocking(move || std::fs::write(path, &data).unwrap_or_else(|err| warn!("Failed to save link keys: {:?}", err)));
     }
 }
 
@@ -179,13 +328,13 @@ pub enum ConnectionEvent {
     ConnectionComplete {
         status: Status,
         handle: u16,
-        addr: RemoteAddr,
+        addr: BdAddr,
         link_type: LinkType,
         encryption_enabled: bool
     },
     // ([Vol 4] Part E, Section 7.7.4).
     ConnectionRequest {
-        addr: RemoteAddr,
+        addr: BdAddr,
         class: ClassOfDevice,
         link_type: LinkType
     },
@@ -198,7 +347,7 @@ pub enum ConnectionEvent {
     // ([Vol 4] Part E, Section 7.7.7).
     RemoteNameRequestComplete {
         status: Status,
-        addr: RemoteAddr,
+        addr: BdAddr,
         name: String
     },
     // ([Vol 4] Part E, Section 7.7.8).
@@ -210,32 +359,32 @@ pub enum ConnectionEvent {
     },
     // ([Vol 4] Part E, Section 7.7.22)
     PinCodeRequest {
-        addr: RemoteAddr
+        addr: BdAddr
     },
     // ([Vol 4] Part E, Section 7.7.23).
     LinkKeyRequest {
-        addr: RemoteAddr
+        addr: BdAddr
     },
     // ([Vol 4] Part E, Section 7.7.24).
     LinkKeyNotification {
-        addr: RemoteAddr,
+        addr: BdAddr,
         key: LinkKey,
         key_type: LinkKeyType
     },
     // ([Vol 4] Part E, Section 7.7.40).
     IoCapabilityRequest {
-        addr: RemoteAddr
+        addr: BdAddr
     },
     // ([Vol 4] Part E, Section 7.7.30).
     IoCapabilityResponse {
-        addr: RemoteAddr,
+        addr: BdAddr,
         io: IoCapability,
         oob: bool,
         auth: AuthenticationRequirements
     },
     // ([Vol 4] Part E, Section 7.7.31).
     UserConfirmationRequest {
-        addr: RemoteAddr,
+        addr: BdAddr,
         passkey: u32
     },
     // ([Vol 4] Part E, Section 7.7.46).
@@ -245,57 +394,58 @@ pub enum ConnectionEvent {
     },
     // ([Vol 4] Part E, Section 7.7.48).
     UserPasskeyNotification {
-        addr: RemoteAddr,
+        addr: BdAddr,
         passkey: u32
     },
     // ([Vol 4] Part E, Section 7.7.43).
     UserPasskeyRequest {
-        addr: RemoteAddr
+        addr: BdAddr
     },
     // ([Vol 4] Part E, Section 7.7.49).
     KeypressNotification {
-        addr: RemoteAddr,
+        addr: BdAddr,
         ty: KeypressNotificationType
     },
     // ([Vol 4] Part E, Section 7.7.44).
     RemoteOobDataRequest {
-        addr: RemoteAddr
+        addr: BdAddr
     },
     // ([Vol 4] Part E, Section 7.7.45).
     SimplePairingComplete {
         status: Status,
-        addr: RemoteAddr
+        addr: BdAddr
     }
 }
 
-pub struct ConnectionEventReceiver(UnboundedReceiver<(EventCode, Bytes)>);
+pub struct ConnectionEventReceiver(UnboundedReceiver<Timestamped<(EventCode, Bytes)>>);
 
 impl ConnectionEventReceiver {
+    pub const EVENTS: [EventCode; 17] = [
+        EventCode::ConnectionRequest,
+        EventCode::ConnectionComplete,
+        EventCode::DisconnectionComplete,
+        EventCode::RemoteNameRequestComplete,
+        EventCode::EncryptionChange,
+        EventCode::PinCodeRequest,
+        EventCode::LinkKeyNotification,
+        EventCode::LinkKeyRequest,
+        EventCode::IoCapabilityRequest,
+        EventCode::IoCapabilityResponse,
+        EventCode::UserConfirmationRequest,
+        EventCode::LinkSupervisionTimeoutChanged,
+        EventCode::UserPasskeyNotification,
+        EventCode::UserPasskeyRequest,
+        EventCode::KeypressNotification,
+        EventCode::RemoteOobDataRequest,
+        EventCode::SimplePairingComplete
+    ];
+
+    /// Registers the event listener.
+    /// The events stay enabled in the controller's event mask for as long as the listener lives.
     pub fn new(hci: &Hci) -> Result<Self, Error> {
         let events = {
             let (tx, rx) = unbounded_channel();
-            hci.register_event_handler(
-                [
-                    EventCode::ConnectionRequest,
-                    EventCode::ConnectionComplete,
-                    EventCode::DisconnectionComplete,
-                    EventCode::RemoteNameRequestComplete,
-                    EventCode::EncryptionChange,
-                    EventCode::PinCodeRequest,
-                    EventCode::LinkKeyNotification,
-                    EventCode::LinkKeyRequest,
-                    EventCode::IoCapabilityRequest,
-                    EventCode::IoCapabilityResponse,
-                    EventCode::UserConfirmationRequest,
-                    EventCode::LinkSupervisionTimeoutChanged,
-                    EventCode::UserPasskeyNotification,
-                    EventCode::UserPasskeyRequest,
-                    EventCode::KeypressNotification,
-                    EventCode::RemoteOobDataRequest,
-                    EventCode::SimplePairingComplete
-                ],
-                tx
-            )?;
+            hci.register_timestamped_event_handler(Self::EVENTS, tx)?;
             trace!("Registered new connection event listener");
             rx
         };
@@ -307,21 +457,21 @@ impl ConnectionEventReceiver {
         self.next()
     }
 
-}
-
-impl Stream for ConnectionEventReceiver {
-    type Item = ConnectionEvent;
+    /// Like [ConnectionEventReceiver::recv], with the time the event was read from the controller.
+    pub fn recv_timestamped(&mut self) -> impl Future<Output = Option<Timestamped<ConnectionEvent>>> + '_ {
+        poll_fn(move |cx| self.poll_recv_timestamped(cx))
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    pub fn poll_recv_timestamped(&mut self, cx: &mut Context<'_>) -> Poll<Option<Timestamped<ConnectionEvent>>> {
         while let Poll::Ready(event) = self.0.poll_recv(cx) {
-            let Some((code, mut data)) = event else {
+            let Some(Timestamped { value: (code, mut data), received }) = event else {
                 return Poll::Ready(None);
             };
             let event: Result<_, instructor::Error> = catch_error(|| match code {
                 EventCode::ConnectionComplete => {
                     let status: Status = data.read_le()?;
                     let handle: u16 = data.read_le()?;
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let link_type: LinkType = data.read_le()?;
                     let encryption_enabled: bool = data.read_le()?;
                     data.finish()?;
@@ -336,7 +486,7 @@ impl Stream for ConnectionEventReceiver {
                 },
                 EventCode::RemoteNameRequestComplete => {
                     let status: Status = data.read_le()?;
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let name: String = String::from_utf8_lossy(&data.split_to(248))
                         .trim_end_matches('\0')
                         .to_string();
@@ -357,36 +507,36 @@ impl Stream for ConnectionEventReceiver {
                     Ok(ConnectionEvent::EncryptionChanged { status, handle, mode, key_size})
                 }
                 EventCode::ConnectionRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let class: ClassOfDevice = data.read_le()?;
                     let link_type: LinkType = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::ConnectionRequest { addr, class, link_type })
                 }
                 EventCode::PinCodeRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::PinCodeRequest { addr })
                 }
                 EventCode::LinkKeyRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::LinkKeyRequest { addr })
                 }
                 EventCode::LinkKeyNotification => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let key: LinkKey = data.read_le()?;
                     let key_type: LinkKeyType = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::LinkKeyNotification { addr, key, key_type })
                 }
                 EventCode::IoCapabilityRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::IoCapabilityRequest { addr })
                 }
                 EventCode::IoCapabilityResponse => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let io: IoCapability = data.read_le()?;
                     let oob: bool = data.read_le()?;
                     let auth: AuthenticationRequirements = data.read_le()?;
@@ -394,7 +544,7 @@ impl Stream for ConnectionEventReceiver {
                     Ok(ConnectionEvent::IoCapabilityResponse { addr, io, oob, auth })
                 }
                 EventCode::UserConfirmationRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let passkey: u32 = data.read_le()?;
                     ensure!(passkey <= 999999, instructor::Error::InvalidValue);
                     data.finish()?;
@@ -402,7 +552,7 @@ impl Stream for ConnectionEventReceiver {
                 }
                 EventCode::SimplePairingComplete => {
                     let status: Status = data.read_le()?;
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::SimplePairingComplete { status, addr })
                 }
@@ -415,35 +565,44 @@ impl Stream for ConnectionEventReceiver {
                     Ok(ConnectionEvent::LinkSuperVisionTimeoutChanged { handle, timeout })
                 }
                 EventCode::UserPasskeyNotification => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let passkey: u32 = data.read_le()?;
                     ensure!(passkey <= 999999, instructor::Error::InvalidValue);
                     data.finish()?;
                     Ok(ConnectionEvent::UserPasskeyNotification { addr, passkey })
                 }
                 EventCode::UserPasskeyRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::UserPasskeyRequest { addr })
                 }
                 EventCode::KeypressNotification => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     let ty: KeypressNotificationType = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::KeypressNotification { addr, ty })
                 }
                 EventCode::RemoteOobDataRequest => {
-                    let addr: RemoteAddr = data.read_le()?;
+                    let addr: BdAddr = data.read_le()?;
                     data.finish()?;
                     Ok(ConnectionEvent::RemoteOobDataRequest { addr })
                 }
                 _ => unreachable!()
             });
             match event {
-                Ok(event) => return Poll::Ready(Some(event)),
+                Ok(value) => return Poll::Ready(Some(Timestamped { value, received })),
                 Err(err) => warn!("Error parsing connection event {:?}: {:?}", code, err)
             }
         }
         Poll::Pending
     }
+}
+
+impl Stream for ConnectionEventReceiver {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_recv_timestamped(cx)
+            .map(|event| event.map(|event| event.value))
+    }
 }
\ No newline at end of file
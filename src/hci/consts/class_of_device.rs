@@ -1,6 +1,8 @@
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
 
 use bitflags::bitflags;
+use bytes::{Buf, Bytes, BytesMut};
 use instructor::utils::u24;
 use instructor::{BitBuffer, Buffer, BufferMut, Error, Exstruct, Instruct, LittleEndian};
 
@@ -8,6 +10,7 @@ use crate::utils::DebugFn;
 
 /// Class of Device ([Assigned Numbers] Section 2.8).
 #[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClassOfDevice {
     pub service_classes: MajorServiceClasses,
     pub device_class: DeviceClass,
@@ -133,9 +136,47 @@ impl Debug for ClassOfDevice {
     }
 }
 
+impl From<ClassOfDevice> for u32 {
+    fn from(cod: ClassOfDevice) -> Self {
+        let mut buffer = BytesMut::with_capacity(3);
+        buffer.write_le(cod);
+        buffer.get_uint_le(3) as u32
+    }
+}
+
+impl TryFrom<u32> for ClassOfDevice {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        if value > 0xFFFFFF {
+            return Err(Error::InvalidValue);
+        }
+        Bytes::copy_from_slice(&value.to_le_bytes()[..3]).read_le()
+    }
+}
+
+/// Formats the class of device as the 24 bit hex value used by most tools, e.g. `0x240404`.
+impl Display for ClassOfDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:06X}", u32::from(*self))
+    }
+}
+
+impl FromStr for ClassOfDevice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+        u32::from_str_radix(hex, 16)
+            .map_err(|_| Error::InvalidValue)?
+            .try_into()
+    }
+}
+
 bitflags! {
     /// Major Service Classes ([Assigned Numbers] Section 2.8.1).
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct MajorServiceClasses: u16 {
         const LimitedDiscoverableMode = 0x0001;
@@ -154,6 +195,7 @@ bitflags! {
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DeviceClass {
     Miscellaneous,
     Computer(ComputerClass),
@@ -170,6 +212,7 @@ pub enum DeviceClass {
 
 // ([Assigned Numbers] Section 2.8.2.1).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ComputerClass {
     Uncategorized = 0b000,
@@ -184,6 +227,7 @@ pub enum ComputerClass {
 
 // ([Assigned Numbers] Section 2.8.2.2).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PhoneClass {
     Uncategorized = 0b000,
@@ -196,6 +240,7 @@ pub enum PhoneClass {
 
 // ([Assigned Numbers] Section 2.8.2.3).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum LanClass {
     FullyAvailable = 0b000000,
@@ -210,6 +255,7 @@ pub enum LanClass {
 
 // ([Assigned Numbers] Section 2.8.2.4).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum AudioVideoClass {
     Uncategorized = 0b00000,
@@ -233,6 +279,7 @@ pub enum AudioVideoClass {
 
 // ([Assigned Numbers] Section 2.8.2.5).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct  PeripheralClass {
     #[instructor(bitfield(u8))]
     #[instructor(bits(4..5))]
@@ -244,6 +291,7 @@ pub struct  PeripheralClass {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PeripheralDeviceType {
     Uncategorized = 0b0000,
@@ -261,6 +309,7 @@ pub enum PeripheralDeviceType {
 bitflags! {
     /// ([Assigned Numbers] Section 2.8.2.6).
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[instructor(bitflags)]
     pub struct ImagingClass: u8 {
         const Display = 0b000100;
@@ -272,6 +321,7 @@ bitflags! {
 
 // ([Assigned Numbers] Section 2.8.2.7).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum WearableClass {
     WristWatch = 0b001,
@@ -284,6 +334,7 @@ pub enum WearableClass {
 
 // ([Assigned Numbers] Section 2.8.2.8).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ToyClass {
     Robot = 0b001,
@@ -295,6 +346,7 @@ pub enum ToyClass {
 
 // ([Assigned Numbers] Section 2.8.2.9).
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum HealthClass {
     Undefined = 0b0000,
@@ -317,7 +369,6 @@ pub enum HealthClass {
 
 #[cfg(test)]
 mod tests {
-    use bytes::{Buf, Bytes, BytesMut};
     use super::*;
 
     #[test]
@@ -334,4 +385,17 @@ mod tests {
         buffer.write(expected);
         assert_eq!(buffer.chunk(), data);
     }
+
+    #[test]
+    fn test_cod_string() {
+        let cod = ClassOfDevice {
+            service_classes: MajorServiceClasses::Audio | MajorServiceClasses::Rendering,
+            device_class: DeviceClass::AudioVideo(AudioVideoClass::WearableHeadset)
+        };
+        assert_eq!(u32::from(cod), 0x240404);
+        assert_eq!(cod.to_string(), "0x240404");
+        assert_eq!("0x240404".parse::<ClassOfDevice>().unwrap(), cod);
+        assert_eq!("240404".parse::<ClassOfDevice>().unwrap(), cod);
+        assert!("0x1000000".parse::<ClassOfDevice>().is_err());
+    }
 }
\ No newline at end of file
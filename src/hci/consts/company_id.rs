@@ -1,8 +1,9 @@
 use std::fmt::Debug;
-use instructor::Exstruct;
+use instructor::{Exstruct, Instruct};
 
 /// Company identifier ([Assigned Numbers] Section 7.1).
-#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct)]
+#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(transparent)]
 pub struct CompanyId(u16);
 
@@ -17,6 +18,10 @@ impl Debug for CompanyId {
 }
 
 impl CompanyId {
+    pub const INTEL: Self = Self(0x0002);
+    pub const BROADCOM: Self = Self(0x000F);
+    pub const REALTEK: Self = Self(0x005D);
+
     pub fn name(self) -> Option<&'static str> {
         match self.0 {
             0x0dcb => Some("GEOPH, LLC"),
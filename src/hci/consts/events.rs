@@ -193,12 +193,137 @@ impl EventMask {
     }
 }
 
+impl EventMask {
+    /// The events the stack relies on regardless of which subsystems are active.
+    pub fn core() -> Self {
+        [
+            EventCode::ConnectionComplete,
+            EventCode::DisconnectionComplete,
+            EventCode::HardwareError,
+            EventCode::DataBufferOverflow,
+            EventCode::MaxSlotsChange,
+            EventCode::ModeChange,
+            EventCode::RoleChange,
+            EventCode::EncryptionChange,
+            EventCode::EncryptionKeyRefreshComplete
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[inline(always)]
+    pub fn contains(self, c: EventCode) -> bool {
+        let mask = c.to_mask_bits();
+        mask != 0 && self.0 & mask == mask
+    }
+
+    #[inline(always)]
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    #[inline(always)]
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
 impl Default for EventMask {
     fn default() -> Self {
         Self::all()
     }
 }
 
+impl FromIterator<EventCode> for EventMask {
+    fn from_iter<T: IntoIterator<Item = EventCode>>(iter: T) -> Self {
+        iter.into_iter().fold(EventMask::none(), |mask, e| mask.with(e, true))
+    }
+}
+
+/// LE meta event subevent codes ([Vol 4] Part E, Section 7.7.65).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Exstruct, Sequence)]
+#[repr(u8)]
+pub enum LeSubevent {
+    ConnectionComplete = 0x01,
+    AdvertisingReport = 0x02,
+    ConnectionUpdateComplete = 0x03,
+    ReadRemoteFeaturesComplete = 0x04,
+    LongTermKeyRequest = 0x05,
+    RemoteConnectionParameterRequest = 0x06,
+    DataLengthChange = 0x07,
+    ReadLocalP256PublicKeyComplete = 0x08,
+    GenerateDhKeyComplete = 0x09,
+    EnhancedConnectionComplete = 0x0A,
+    DirectedAdvertisingReport = 0x0B,
+    PhyUpdateComplete = 0x0C,
+    ExtendedAdvertisingReport = 0x0D,
+    PeriodicAdvertisingSyncEstablished = 0x0E,
+    PeriodicAdvertisingReport = 0x0F,
+    PeriodicAdvertisingSyncLost = 0x10,
+    ScanTimeout = 0x11,
+    AdvertisingSetTerminated = 0x12,
+    ScanRequestReceived = 0x13,
+    ChannelSelectionAlgorithm = 0x14
+}
+
+impl LeSubevent {
+    // ([Vol 4] Part E, Section 7.8.1)
+    #[inline(always)]
+    pub fn to_mask_bits(self) -> u64 {
+        1u64 << (self as u8 - 1)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct)]
+pub struct LeEventMask(u64);
+
+impl LeEventMask {
+    /// Returns an all-zero event mask that disables all LE meta events.
+    #[inline(always)]
+    pub const fn none() -> Self {
+        Self(0)
+    }
+
+    pub fn all() -> Self {
+        enum_iterator::all::<LeSubevent>().collect()
+    }
+
+    // Enables or disables the specified subevent.
+    #[inline(always)]
+    pub fn with(mut self, c: LeSubevent, enable: bool) -> Self {
+        let mask = c.to_mask_bits();
+        if enable {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+        self
+    }
+
+    #[inline(always)]
+    pub fn contains(self, c: LeSubevent) -> bool {
+        self.0 & c.to_mask_bits() != 0
+    }
+
+    #[inline(always)]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// The controller default after reset ([Vol 4] Part E, Section 7.8.1).
+impl Default for LeEventMask {
+    fn default() -> Self {
+        Self(0x1F)
+    }
+}
+
+impl FromIterator<LeSubevent> for LeEventMask {
+    fn from_iter<T: IntoIterator<Item = LeSubevent>>(iter: T) -> Self {
+        iter.into_iter().fold(LeEventMask::none(), |mask, e| mask.with(e, true))
+    }
+}
+
 impl EventCode {
     // ([Vol 4] Part E, Section 7.3.1)
     pub fn to_mask_bits(self) -> u64 {
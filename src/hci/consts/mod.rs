@@ -4,6 +4,7 @@ mod company_id;
 
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
+use std::sync::OnceLock;
 use std::time::Duration;
 use instructor::utils::u24;
 use instructor::{BufferMut, Endian, Exstruct, Instruct};
@@ -14,7 +15,8 @@ pub use company_id::CompanyId;
 
 
 /// Bluetooth Core Specification versions ([Assigned Numbers] Section 2.1).
-#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct)]
+#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 #[repr(u8)]
 pub enum CoreVersion {
@@ -90,10 +92,50 @@ pub enum Role {
     Slave = 0x01
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Exstruct, Instruct)]
-pub struct RemoteAddr([u8; 6]);
+/// A Bluetooth device address ([Vol 2] Part B, Section 1.2), stored little endian as it is sent over HCI.
+/// Parsed from and formatted as the usual `XX:XX:XX:XX:XX:XX` with the most significant byte first.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Exstruct, Instruct)]
+#[cfg_attr(not(feature = "redact-logs"), derive(Debug))]
+pub struct BdAddr([u8; 6]);
 
-impl Display for RemoteAddr {
+#[deprecated(note = "renamed to BdAddr")]
+pub type RemoteAddr = BdAddr;
+
+/// Resolves an organizationally unique identifier to the name of the vendor it is assigned to.
+pub type OuiResolver = fn(u32) -> Option<&'static str>;
+
+static OUI_RESOLVER: OnceLock<OuiResolver> = OnceLock::new();
+
+impl BdAddr {
+    pub const fn new(addr: [u8; 6]) -> Self {
+        Self(addr)
+    }
+
+    /// The bytes of the address with the most significant byte first.
+    pub fn to_be_bytes(self) -> [u8; 6] {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+
+    /// The organizationally unique identifier, i.e. the upper 24 bits of the address.
+    pub fn oui(self) -> u32 {
+        u32::from_le_bytes([self.0[3], self.0[4], self.0[5], 0])
+    }
+
+    /// Sets the resolver used by [BdAddr::vendor]. The stack does not ship the IEEE registry itself.
+    /// Returns `false` if a resolver has already been set.
+    pub fn set_oui_resolver(resolver: OuiResolver) -> bool {
+        OUI_RESOLVER.set(resolver).is_ok()
+    }
+
+    /// The vendor of the device according to the resolver set by [BdAddr::set_oui_resolver].
+    pub fn vendor(self) -> Option<&'static str> {
+        OUI_RESOLVER.get().and_then(|resolver| resolver(self.oui()))
+    }
+}
+
+impl Display for BdAddr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -103,7 +145,17 @@ impl Display for RemoteAddr {
     }
 }
 
-impl FromStr for RemoteAddr {
+/// Keeps addresses out of logged events and errors, see [redacted](crate::utils::redact::redacted).
+#[cfg(feature = "redact-logs")]
+impl Debug for BdAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BdAddr")
+            .field(&crate::utils::redact::redacted(self))
+            .finish()
+    }
+}
+
+impl FromStr for BdAddr {
     type Err = instructor::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -111,29 +163,34 @@ impl FromStr for RemoteAddr {
         let mut iter = s
             .split(':')
             .rev()
-            .map(|s| u8::from_str_radix(s, 16)
-                .map_err(|_| instructor::Error::InvalidValue));
+            .map(|s| match s.len() {
+                1 | 2 => u8::from_str_radix(s, 16).map_err(|_| instructor::Error::InvalidValue),
+                _ => Err(instructor::Error::InvalidValue)
+            });
         for i in addr.iter_mut() {
             *i = iter.next().ok_or(instructor::Error::TooShort)??;
         }
-        Ok(Self(addr))
+        match iter.next() {
+            Some(_) => Err(instructor::Error::InvalidValue),
+            None => Ok(Self(addr))
+        }
     }
 }
 
 #[cfg(feature = "serde")]
-impl serde::Serialize for RemoteAddr {
+impl serde::Serialize for BdAddr {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         serializer.collect_str(&self)
     }
 }
 
 #[cfg(feature = "serde")]
-impl<'de> serde::Deserialize<'de> for RemoteAddr {
+impl<'de> serde::Deserialize<'de> for BdAddr {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
-        struct RemoteAddrVisitor;
+        struct BdAddrVisitor;
 
-        impl serde::de::Visitor<'_> for RemoteAddrVisitor {
-            type Value = RemoteAddr;
+        impl serde::de::Visitor<'_> for BdAddrVisitor {
+            type Value = BdAddr;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
                 formatter.write_str("a string in the format XX:XX:XX:XX:XX:XX")
@@ -144,17 +201,23 @@ impl<'de> serde::Deserialize<'de> for RemoteAddr {
             }
         }
 
-        deserializer.deserialize_str(RemoteAddrVisitor)
+        deserializer.deserialize_str(BdAddrVisitor)
     }
 }
 
-impl From<[u8; 6]> for RemoteAddr {
+impl From<[u8; 6]> for BdAddr {
     fn from(addr: [u8; 6]) -> Self {
         Self(addr)
     }
 }
 
-impl AsRef<[u8]> for RemoteAddr {
+impl From<BdAddr> for [u8; 6] {
+    fn from(addr: BdAddr) -> Self {
+        addr.0
+    }
+}
+
+impl AsRef<[u8]> for BdAddr {
     fn as_ref(&self) -> &[u8] {
         &self.0
     }
@@ -169,6 +232,12 @@ impl Debug for LinkKey {
     }
 }
 
+impl AsRef<[u8]> for LinkKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl Display for LinkKey {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for i in &self.0 {
@@ -223,6 +292,7 @@ impl<'de> serde::Deserialize<'de> for LinkKey {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum LinkKeyType {
     Combination = 0x00,
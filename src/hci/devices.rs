@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_lite::Stream;
+use parking_lot::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::hci::consts::{BdAddr, ClassOfDevice};
+use crate::sdp::Uuid;
+
+/// The properties of a remote device that a user interface typically shows.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceProperties {
+    pub name: Option<String>,
+    pub class_of_device: Option<ClassOfDevice>,
+    /// The last measured RSSI of the connection in dBm.
+    pub rssi: Option<i8>,
+    pub bonded: bool,
+    pub connected: bool,
+    /// The service classes of the profiles that currently have a session with the device.
+    pub profiles: BTreeSet<Uuid>
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DevicePropertyChange {
+    NameResolved(String),
+    ClassOfDevice(ClassOfDevice),
+    RssiUpdated(i8),
+    Bonded(bool),
+    Connected(bool),
+    ProfileConnected(Uuid),
+    ProfileDisconnected(Uuid),
+    /// A profile session could not be established. Doesn't change any property, but is always reported.
+    ProfileFailed(Uuid, ProfileFailure)
+}
+
+/// Why a profile session with a device could not be established.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProfileFailure {
+    /// The device rejected the channel configuration, retrying won't help.
+    Rejected,
+    /// The device didn't answer in time, even after retrying.
+    Timeout,
+    /// The device or the local stack closed the connection.
+    Disconnected
+}
+
+impl DevicePropertyChange {
+    fn apply(&self, properties: &mut DeviceProperties) -> bool {
+        match self {
+            Self::NameResolved(name) => replace(&mut properties.name, Some(name.clone())),
+            Self::ClassOfDevice(cod) => replace(&mut properties.class_of_device, Some(*cod)),
+            Self::RssiUpdated(rssi) => replace(&mut properties.rssi, Some(*rssi)),
+            Self::Bonded(bonded) => replace(&mut properties.bonded, *bonded),
+            Self::Connected(connected) => replace(&mut properties.connected, *connected),
+            Self::ProfileConnected(uuid) => properties.profiles.insert(*uuid),
+            Self::ProfileDisconnected(uuid) => properties.profiles.remove(uuid),
+            Self::ProfileFailed(_, _) => true
+        }
+    }
+}
+
+fn replace<T: PartialEq>(target: &mut T, value: T) -> bool {
+    let changed = *target != value;
+    *target = value;
+    changed
+}
+
+type Watcher = (Option<BdAddr>, UnboundedSender<(BdAddr, DevicePropertyChange)>);
+
+#[derive(Default)]
+struct RegistryState {
+    devices: BTreeMap<BdAddr, DeviceProperties>,
+    watchers: Vec<Watcher>
+}
+
+/// Collects the properties of remote devices from the connection manager and the profiles
+/// and notifies watchers whenever one of them changes.
+#[derive(Clone, Default)]
+pub struct DeviceRegistry(Arc<Mutex<RegistryState>>);
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, addr: BdAddr) -> Option<DeviceProperties> {
+        self.0.lock().devices.get(&addr).cloned()
+    }
+
+    pub fn devices(&self) -> BTreeMap<BdAddr, DeviceProperties> {
+        self.0.lock().devices.clone()
+    }
+
+    /// Returns the current properties of `addr` and a stream of all subsequent changes.
+    pub fn watch(&self, addr: BdAddr) -> (DeviceProperties, DeviceWatch) {
+        let mut state = self.0.lock();
+        let (tx, rx) = unbounded_channel();
+        state.watchers.push((Some(addr), tx));
+        (state.devices.get(&addr).cloned().unwrap_or_default(), DeviceWatch(rx))
+    }
+
+    /// Returns the current properties of all known devices and a stream of all subsequent changes,
+    /// including those of devices that appear later.
+    pub fn watch_all(&self) -> (BTreeMap<BdAddr, DeviceProperties>, DeviceWatch) {
+        let mut state = self.0.lock();
+        let (tx, rx) = unbounded_channel();
+        state.watchers.push((None, tx));
+        (state.devices.clone(), DeviceWatch(rx))
+    }
+
+    /// Applies `change` and notifies the watchers if it actually changed anything.
+    pub fn update(&self, addr: BdAddr, change: DevicePropertyChange) {
+        let mut state = self.0.lock();
+        if !change.apply(state.devices.entry(addr).or_default()) {
+            return;
+        }
+        state.watchers.retain(|(filter, tx)| match filter {
+            Some(filter) if *filter != addr => !tx.is_closed(),
+            _ => tx.send((addr, change.clone())).is_ok()
+        });
+    }
+
+    /// Marks the device as disconnected, which also ends all of its profile sessions.
+    pub fn set_disconnected(&self, addr: BdAddr) {
+        let profiles = self.get(addr).map(|properties| properties.profiles).unwrap_or_default();
+        for uuid in profiles {
+            self.update(addr, DevicePropertyChange::ProfileDisconnected(uuid));
+        }
+        self.update(addr, DevicePropertyChange::Connected(false));
+    }
+
+    /// Marks the profile with the service class `uuid` as (dis)connected.
+    pub fn set_profile_connected(&self, addr: BdAddr, uuid: impl Into<Uuid>, connected: bool) {
+        let uuid = uuid.into();
+        self.update(addr, match connected {
+            true => DevicePropertyChange::ProfileConnected(uuid),
+            false => DevicePropertyChange::ProfileDisconnected(uuid)
+        });
+    }
+
+    pub fn set_profile_failed(&self, addr: BdAddr, uuid: impl Into<Uuid>, failure: ProfileFailure) {
+        self.update(addr, DevicePropertyChange::ProfileFailed(uuid.into(), failure));
+    }
+}
+
+/// The property changes of one or all devices, see [DeviceRegistry::watch].
+pub struct DeviceWatch(UnboundedReceiver<(BdAddr, DevicePropertyChange)>);
+
+impl DeviceWatch {
+    pub async fn recv(&mut self) -> Option<(BdAddr, DevicePropertyChange)> {
+        self.0.recv().await
+    }
+}
+
+impl Stream for DeviceWatch {
+    type Item = (BdAddr, DevicePropertyChange);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hci::consts::BdAddr;
+    use crate::hci::devices::{DevicePropertyChange, DeviceRegistry};
+    use crate::sdp::ids::service_classes::AUDIO_SINK;
+
+    #[test]
+    fn watch() {
+        let registry = DeviceRegistry::new();
+        let phone = BdAddr::new([1, 2, 3, 4, 5, 6]);
+        let other = BdAddr::new([6, 5, 4, 3, 2, 1]);
+        registry.update(phone, DevicePropertyChange::Bonded(true));
+
+        let (properties, mut watch) = registry.watch(phone);
+        assert!(properties.bonded);
+        registry.update(phone, DevicePropertyChange::Bonded(true));
+        registry.update(other, DevicePropertyChange::Connected(true));
+        registry.update(phone, DevicePropertyChange::Connected(true));
+        registry.set_profile_connected(phone, AUDIO_SINK, true);
+        registry.set_disconnected(phone);
+
+        let changes: Vec<_> = std::iter::from_fn(|| watch.0.try_recv().ok())
+            .map(|(_, change)| change)
+            .collect();
+        assert_eq!(changes, vec![
+            DevicePropertyChange::Connected(true),
+            DevicePropertyChange::ProfileConnected(AUDIO_SINK),
+            DevicePropertyChange::ProfileDisconnected(AUDIO_SINK),
+            DevicePropertyChange::Connected(false)
+        ]);
+        assert!(registry.get(phone).is_some_and(|properties| properties.profiles.is_empty()));
+    }
+}
@@ -0,0 +1,253 @@
+//! Connects to a device in one step: paging, pairing, encryption, service discovery and the profile channels.
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+#[cfg(feature = "avdtp")]
+use crate::avdtp::{AvdtpClient, ClientError as AvdtpClientError};
+#[cfg(feature = "avrcp")]
+use crate::avrcp::Avrcp;
+use crate::hci::connection::{ConnectionEvent, ConnectionEventReceiver, ConnectionManager};
+use crate::hci::consts::{BdAddr, EncryptionMode, Status};
+use crate::hci::Error;
+#[cfg(feature = "avrcp")]
+use crate::l2cap::channel::Error as L2capError;
+use crate::l2cap::ChannelOpener;
+use crate::sdp::ids::attributes::SERVICE_CLASS_ID_LIST_ID;
+#[cfg(feature = "avdtp")]
+use crate::sdp::ids::service_classes::{AUDIO_SINK, AUDIO_SOURCE};
+#[cfg(feature = "avrcp")]
+use crate::sdp::ids::service_classes::{AV_REMOTE_CONTROL, AV_REMOTE_CONTROL_TARGET};
+use crate::sdp::{ClientError as SdpClientError, SdpClient, Uuid};
+use crate::utils::clock::timeout;
+use crate::utils::redact::redacted;
+use crate::utils::spawn_supervised;
+
+const PAGE_TIMEOUT: Duration = Duration::from_secs(10);
+const SDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A profile that [ConnectionManager::establish] connects after the link is set up.
+#[derive(Clone)]
+pub enum ProfileRequest {
+    /// Opens the AVDTP signaling channel to an audio sink, see [EstablishedConnection::avdtp].
+    #[cfg(feature = "avdtp")]
+    A2dpSink,
+    /// Opens the AVDTP signaling channel to an audio source, see [EstablishedConnection::avdtp].
+    #[cfg(feature = "avdtp")]
+    A2dpSource,
+    /// Connects the control channel of the [Avrcp] profile to a device that is an AVRCP target or controller.
+    #[cfg(feature = "avrcp")]
+    Avrcp(Avrcp)
+}
+
+impl ProfileRequest {
+    /// The remote service classes of which at least one has to be present in the device's SDP records.
+    fn service_classes(&self) -> &'static [Uuid] {
+        match *self {
+            #[cfg(feature = "avdtp")]
+            ProfileRequest::A2dpSink => &[AUDIO_SINK],
+            #[cfg(feature = "avdtp")]
+            ProfileRequest::A2dpSource => &[AUDIO_SOURCE],
+            #[cfg(feature = "avrcp")]
+            ProfileRequest::Avrcp(_) => &[AV_REMOTE_CONTROL_TARGET, AV_REMOTE_CONTROL]
+        }
+    }
+}
+
+/// The stage of [ConnectionManager::establish] that failed.
+#[derive(Debug, thiserror::Error)]
+pub enum EstablishError {
+    #[error("Failed to page the device: {0}")]
+    Paging(Error),
+    #[error("The device did not answer the page")]
+    PageTimeout,
+    #[error("Failed to authenticate the link: {0}")]
+    Authentication(Error),
+    #[error("Failed to encrypt the link: {0}")]
+    Encryption(Error),
+    #[error("The device refused to encrypt the link")]
+    EncryptionOff,
+    #[error("Service discovery failed: {0:?}")]
+    ServiceDiscovery(SdpClientError),
+    #[error("Service discovery timed out")]
+    ServiceDiscoveryTimeout,
+    #[error("The device has none of the service classes {0:?}")]
+    ProfileNotSupported(&'static [Uuid]),
+    #[cfg(feature = "avdtp")]
+    #[error("Failed to connect A2DP: {0:?}")]
+    A2dp(AvdtpClientError),
+    #[cfg(feature = "avrcp")]
+    #[error("Failed to connect AVRCP: {0}")]
+    Avrcp(L2capError)
+}
+
+impl EstablishError {
+    /// Whether the link was already up when the error occurred, in that case it has been disconnected again.
+    pub fn after_paging(&self) -> bool {
+        !matches!(self, EstablishError::Paging(_) | EstablishError::PageTimeout)
+    }
+}
+
+/// The link and the profile connections set up by [ConnectionManager::establish].
+pub struct EstablishedConnection {
+    pub addr: BdAddr,
+    pub handle: u16,
+    pub encryption: EncryptionMode,
+    /// The signaling channel if A2DP was requested.
+    #[cfg(feature = "avdtp")]
+    pub avdtp: Option<AvdtpClient>,
+    /// Whether the control channel of the requested [Avrcp] was connected.
+    #[cfg(feature = "avrcp")]
+    pub avrcp: bool
+}
+
+impl ConnectionManager {
+    /// Connects to `addr` and all requested `profiles`.
+    ///
+    /// The link is authenticated (pairing if there is no link key yet) and encrypted before the service records
+    /// of the device are checked for the requested profiles. Once the link is up any failure disconnects it again.
+    pub async fn establish(
+        &self, opener: &ChannelOpener, addr: BdAddr, profiles: &[ProfileRequest]
+    ) -> Result<EstablishedConnection, EstablishError> {
+        let handle = self.page(addr).await?;
+        debug!("Connected to {} (0x{:04x})", redacted(&addr), handle);
+        match self.setup(opener, addr, handle, profiles).await {
+            Ok(connection) => Ok(connection),
+            Err(err) => {
+                warn!("Failed to establish connection to {}: {}", redacted(&addr), err);
+                self.hci
+                    .disconnect(handle, Status::RemoteUserTerminatedConnection)
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to disconnect {}: {:?}", redacted(&addr), err));
+                Err(err)
+            }
+        }
+    }
+
+    async fn page(&self, addr: BdAddr) -> Result<u16, EstablishError> {
+        let mut events = ConnectionEventReceiver::new(&self.hci).map_err(EstablishError::Paging)?;
+        self.hci
+            .create_connection(addr, true)
+            .await
+            .map_err(EstablishError::Paging)?;
+        match timeout(PAGE_TIMEOUT, connection_complete(&mut events, addr)).await {
+            Ok(Some((status, handle))) => match status.is_ok() {
+                true => Ok(handle),
+                false => Err(EstablishError::Paging(Error::Controller(status)))
+            },
+            Ok(None) => Err(EstablishError::Paging(Error::EventLoopClosed)),
+            Err(_) => {
+                self.cancel_page(addr, events);
+                Err(EstablishError::PageTimeout)
+            }
+        }
+    }
+
+    /// Stops the page in the background. A connection that completes anyway, because it raced the cancellation,
+    /// is disconnected again, as the caller already gave up on it.
+    fn cancel_page(&self, addr: BdAddr, mut events: ConnectionEventReceiver) {
+        let hci = self.hci.clone();
+        spawn_supervised("page-cancel", redacted(&addr).to_string(), async move {
+            if let Err(err) = hci.create_connection_cancel(addr).await {
+                debug!("Failed to cancel the page of {}: {:?}", redacted(&addr), err);
+            }
+            if let Ok(Some((status, handle))) = timeout(PAGE_TIMEOUT, connection_complete(&mut events, addr)).await {
+                if status.is_ok() {
+                    debug!("Disconnecting {} (0x{:04x}) after the page timed out", redacted(&addr), handle);
+                    hci.disconnect(handle, Status::RemoteUserTerminatedConnection)
+                        .await
+                        .unwrap_or_else(|err| warn!("Failed to disconnect {}: {:?}", redacted(&addr), err));
+                }
+            }
+        });
+    }
+
+    async fn setup(
+        &self, opener: &ChannelOpener, addr: BdAddr, handle: u16, profiles: &[ProfileRequest]
+    ) -> Result<EstablishedConnection, EstablishError> {
+        self.hci
+            .request_authentication(handle)
+            .await
+            .map_err(EstablishError::Authentication)?;
+        let (encryption, _) = self
+            .hci
+            .set_encryption(handle, true)
+            .await
+            .map_err(EstablishError::Encryption)?;
+        if encryption == EncryptionMode::Off {
+            return Err(EstablishError::EncryptionOff);
+        }
+
+        if !profiles.is_empty() {
+            timeout(SDP_TIMEOUT, check_services(opener, handle, profiles))
+                .await
+                .unwrap_or(Err(EstablishError::ServiceDiscoveryTimeout))?;
+        }
+
+        #[cfg_attr(not(any(feature = "avdtp", feature = "avrcp")), allow(unused_mut))]
+        let mut connection = EstablishedConnection {
+            addr,
+            handle,
+            encryption,
+            #[cfg(feature = "avdtp")]
+            avdtp: None,
+            #[cfg(feature = "avrcp")]
+            avrcp: false
+        };
+        for profile in profiles {
+            match *profile {
+                #[cfg(feature = "avdtp")]
+                ProfileRequest::A2dpSink | ProfileRequest::A2dpSource => {
+                    if connection.avdtp.is_none() {
+                        let client = AvdtpClient::connect(opener, handle)
+                            .await
+                            .map_err(EstablishError::A2dp)?;
+                        connection.avdtp = Some(client);
+                    }
+                }
+                #[cfg(feature = "avrcp")]
+                ProfileRequest::Avrcp(ref avrcp) => {
+                    avrcp.connect(opener, handle).await.map_err(EstablishError::Avrcp)?;
+                    connection.avrcp = true;
+                }
+            }
+        }
+        Ok(connection)
+    }
+}
+
+/// Waits for the status and handle of the next `ConnectionComplete` event of `addr`.
+async fn connection_complete(events: &mut ConnectionEventReceiver, addr: BdAddr) -> Option<(Status, u16)> {
+    while let Some(event) = events.recv().await {
+        if let ConnectionEvent::ConnectionComplete { status, handle, addr: event_addr, .. } = event {
+            if event_addr == addr {
+                return Some((status, handle));
+            }
+        }
+    }
+    None
+}
+
+/// Checks that the device has a service record for each of the requested profiles.
+async fn check_services(opener: &ChannelOpener, handle: u16, profiles: &[ProfileRequest]) -> Result<(), EstablishError> {
+    let mut client = SdpClient::connect(opener, handle)
+        .await
+        .map_err(EstablishError::ServiceDiscovery)?;
+    for profile in profiles {
+        let mut found = false;
+        for class in profile.service_classes() {
+            let records = client
+                .service_search_attribute(&[*class], &[SERVICE_CLASS_ID_LIST_ID..=SERVICE_CLASS_ID_LIST_ID])
+                .await
+                .map_err(EstablishError::ServiceDiscovery)?;
+            if !records.is_empty() {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            return Err(EstablishError::ProfileNotSupported(profile.service_classes()));
+        }
+    }
+    Ok(())
+}
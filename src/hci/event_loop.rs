@@ -1,23 +1,30 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::future::pending;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::future::{poll_fn, Future};
 use std::mem::size_of;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use instructor::utils::Length;
 use instructor::{Buffer, Exstruct};
-use nusb::transfer::{ControlOut, ControlType, Recipient, RequestBuffer, TransferError};
-use tokio::sync::mpsc::{UnboundedReceiver as MpscReceiver, UnboundedSender as MpscSender};
+use nusb::transfer::{ControlOut, ControlType, Queue, Recipient, RequestBuffer, TransferError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver as MpscReceiver, UnboundedSender as MpscSender};
 use tokio::sync::oneshot::Sender as OneshotSender;
+use tokio::time::Instant;
 use tracing::{debug, error, warn};
 
 use crate::hci::btsnoop::{LogWriter, PacketType};
 use crate::hci::consts::{EventCode, Status};
-use crate::hci::{Error, Opcode};
-use crate::host::usb::UsbHost;
+use crate::hci::{Error, Opcode, OpcodeGroup};
+use crate::host::usb::{TransferConfig, UsbHost};
+#[cfg(target_os = "linux")]
+use crate::host::user_channel::{UserChannel, HCI_ACLDATA_PKT, HCI_COMMAND_PKT, HCI_EVENT_PKT};
+use crate::host::Transport;
+use crate::utils::clock::{now, Timestamped};
+use crate::utils::redact::redacted;
 use crate::utils::DispatchExt;
-
-const TRANSFER_BUFFER_SIZE: usize = 4096;
-const TRANSFER_BUFFER_COUNT: usize = 4;
+#[cfg(feature = "fault-injection")]
+use crate::utils::fault::FaultInjector;
 
 pub enum EventLoopCommand {
     Shutdown,
@@ -25,98 +32,103 @@ pub enum EventLoopCommand {
         events: BTreeSet<EventCode>,
         handler: MpscSender<(EventCode, Bytes)>
     },
+    /// Like [EventLoopCommand::RegisterHciEventHandler], but the events carry the time they were read from the transport.
+    RegisterTimestampedHciEventHandler {
+        events: BTreeSet<EventCode>,
+        handler: MpscSender<Timestamped<(EventCode, Bytes)>>
+    },
     RegisterAclDataHandler {
         handler: MpscSender<Bytes>
     },
-    SetMaxInFlightAclPackets(u32)
+    SetMaxInFlightAclPackets(u32),
+    /// Starts reporting processed ACL packets to the controller in batches of the given size.
+    EnableHostFlowControl { batch_size: u16 },
+    /// Sends consecutive queued ACL packets in one transfer.
+    EnableAclPacking,
+    #[cfg(feature = "fault-injection")]
+    SetFaultInjector(Option<FaultInjector>)
 }
 
 pub type CmdResultSender = OneshotSender<Result<Bytes, TransferError>>;
 
+/// How long the response to a command whose caller stopped waiting is still expected.
+const ABANDONED_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command that was sent but not answered yet.
+enum OutstandingCommand {
+    Waiting(CmdResultSender),
+    /// The caller stopped waiting. The entry consumes the late response, so it isn't taken for a later command with the
+    /// same opcode.
+    Abandoned(Instant)
+}
+
+/// An outgoing ACL packet and an optional notifier for when the controller reports it as completed.
+pub type AclPacket = (Bytes, Option<OneshotSender<()>>);
+/// The fragments of a single L2CAP PDU.
+pub type AclPdu = Vec<AclPacket>;
+
 pub async fn event_loop(
-    transport: UsbHost, mut cmd_receiver: MpscReceiver<(Opcode, Bytes, CmdResultSender)>, mut acl_receiver: MpscReceiver<Bytes>,
+    transport: Transport, mut cmd_receiver: MpscReceiver<(Opcode, Bytes, CmdResultSender)>, mut acl_receiver: MpscReceiver<AclPdu>,
+    mut acl_high_priority_receiver: MpscReceiver<AclPdu>,
     mut ctl_receiver: MpscReceiver<EventLoopCommand>
 ) {
-    let mut events = transport
-        .interface
-        .interrupt_in_queue(transport.endpoints.event);
-    for _ in 0..TRANSFER_BUFFER_COUNT {
-        events.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
-    }
-
-    let mut acl_in = transport
-        .interface
-        .bulk_in_queue(transport.endpoints.acl_in);
-    for _ in 0..TRANSFER_BUFFER_COUNT {
-        acl_in.submit(RequestBuffer::new(TRANSFER_BUFFER_SIZE));
-    }
-    let mut acl_out = transport
-        .interface
-        .bulk_out_queue(transport.endpoints.acl_out);
-
-    let mut state = State::default();
+    let mut io = Io::new(transport);
+    let mut state = State {
+        // The host may send one command until the controller reports otherwise ([Vol 4] Part E, Section 4.4)
+        command_credits: 1,
+        ..Default::default()
+    };
+    let mut pending_fragments = VecDeque::new();
+    let mut high_priority_streak = 0;
+    let (processed_tx, mut processed_rx) = unbounded_channel();
     let log = LogWriter::new();
     let mut buffer = BytesMut::with_capacity(4096);
 
     loop {
         tokio::select! {
-            event = events.next_complete() => {
-                match event.status {
-                    Ok(_) => {
-                        buffer.put_slice(&event.data);
-                        let data = buffer.split().freeze();
-                        log.write(PacketType::Event, data.clone());
-                        match state.process_hci_event(data) {
-                            Ok(true) => (),
-                            Ok(false) => log.write(PacketType::SystemNode, Bytes::from_static("Unhandled HCI event".as_bytes())),
-                            Err(err) => error!("Error processing HCI event: {:?}", err),
-                        }
-                    },
-                    Err(err) => error!("Error reading HCI event: {:?}", err),
-                }
-                events.submit(RequestBuffer::reuse(event.data, TRANSFER_BUFFER_SIZE));
-            },
-            data = acl_in.next_complete() => {
-                match data.status {
-                    Ok(_) => {
-                        buffer.put_slice(&data.data);
-                        let data = buffer.split().freeze();
-                        log.write(PacketType::AclRx, data.clone());
-                        state.process_acl_data(data)
-                            .unwrap_or_else(|err| error!("Error processing ACL data: {:?}", err));
-                    },
-                    Err(err) => error!("Error reading HCI event: {:?}", err),
-                }
-                acl_in.submit(RequestBuffer::reuse(data.data, TRANSFER_BUFFER_SIZE));
-            },
-            completion = acl_out.next_complete(), if acl_out.pending() > 0 => {
-                completion
-                    .status
-                    .unwrap_or_else(|err| error!("Error writing ACL data: {:?}", err));
+            received = poll_fn(|cx| io.poll_next(cx, &mut buffer)) => match received {
+                Received::Event(Ok(data)) => {
+                    log.write(PacketType::Event, data.clone());
+                    match state.process_hci_event(data) {
+                        Ok(true) => (),
+                        Ok(false) => log.write(PacketType::SystemNode, Bytes::from_static("Unhandled HCI event".as_bytes())),
+                        Err(err) => error!("Error processing HCI event: {:?}", err),
+                    }
+                },
+                Received::Event(Err(err)) => error!("Error reading HCI event: {:?}", err),
+                Received::AclData(Ok(data)) => {
+                    log.write(PacketType::AclRx, data.clone());
+                    state.process_acl_data(data)
+                        .unwrap_or_else(|err| error!("Error processing ACL data: {:?}", err));
+                },
+                Received::AclData(Err(err)) => error!("Error reading ACL data: {:?}", err),
+                Received::AclSent(result) => result.unwrap_or_else(|err| error!("Error writing ACL data: {:?}", err))
             },
-            data = acl_receiver.recv(), if state.in_flight < state.max_in_flight => {
-                if let Some(data) = data {
-                    state.in_flight += 1;
-                    log.write(PacketType::AclTx, data.clone());
-                    let data = data.to_vec();
-                    acl_out.submit(data);
+            data = next_acl_fragment(&mut pending_fragments, &mut high_priority_streak, &mut acl_high_priority_receiver, &mut acl_receiver), if state.in_flight < state.max_in_flight => {
+                if let Some(packet) = data {
+                    let mut transfer = vec![packet];
+                    if state.pack_acl_packets {
+                        pack_fragments(&mut transfer, &mut pending_fragments, state.max_in_flight.saturating_sub(state.in_flight) as usize, io.max_transfer_size());
+                    }
+                    let mut packets = Vec::with_capacity(transfer.len());
+                    for (data, notifier) in transfer {
+                        state.packet_sent(&data, notifier);
+                        log.write(PacketType::AclTx, data.clone());
+                        packets.push(data);
+                    }
+                    io.send_acl_data(packets).await;
                 } else  {
                     break;
                 }
             },
-            cmd = cmd_receiver.recv(), if state.outstanding_command.is_none() => {
+            cmd = cmd_receiver.recv(), if state.command_credits > 0 => {
                 if let Some((opcode, req, tx)) = cmd {
                     log.write(PacketType::Command, req.clone());
-                    let cmd = transport.interface.control_out(ControlOut {
-                        control_type: ControlType::Class,
-                        recipient: Recipient::Interface,
-                        request: 0x00,
-                        value: 0x00,
-                        index: transport.endpoints.main_iface.into(),
-                        data: &req,
-                    }).await;
-                    match cmd.status {
-                        Ok(_) => state.outstanding_command = Some((opcode, tx)),
+                    match io.write_command(&req).await {
+                        Ok(_) => {
+                            state.command_credits -= 1;
+                            state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(tx)));
+                        }
                         Err(err) => {
                             let _ = tx.send(Err(err));
                         }
@@ -125,8 +137,15 @@ pub async fn event_loop(
                     break;
                 }
             },
-            _ = state.outstanding_command_dropped() => {
-                state.outstanding_command = None;
+            i = state.outstanding_command_dropped() => state.command_abandoned(i, now()),
+            Some(packet) = processed_rx.recv() => {
+                // Does not consume a command credit and is not answered ([Vol 4] Part E, Section 7.3.40)
+                if let Some(report) = state.acl_packet_processed(packet) {
+                    log.write(PacketType::Command, report.clone());
+                    io.write_command(&report)
+                        .await
+                        .unwrap_or_else(|err| error!("Error reporting completed packets: {:?}", err));
+                }
             },
             cmd = ctl_receiver.recv() => {
                 match cmd {
@@ -135,12 +154,27 @@ pub async fn event_loop(
                             state.hci_event_handlers.entry(event).or_default().push(handler.clone());
                         }
                     }
+                    Some(EventLoopCommand::RegisterTimestampedHciEventHandler { events, handler }) => {
+                        for event in events {
+                            state.timestamped_event_handlers.entry(event).or_default().push(handler.clone());
+                        }
+                    }
                     Some(EventLoopCommand::RegisterAclDataHandler { handler }) => {
                         state.acl_data_handlers.push(handler);
                     }
                     Some(EventLoopCommand::SetMaxInFlightAclPackets(n)) => {
                         state.max_in_flight = n;
                     }
+                    Some(EventLoopCommand::EnableHostFlowControl { batch_size }) => {
+                        state.host_flow_control = Some(HostFlowControl::new(batch_size, processed_tx.clone()));
+                    }
+                    Some(EventLoopCommand::EnableAclPacking) => {
+                        state.pack_acl_packets = true;
+                    }
+                    #[cfg(feature = "fault-injection")]
+                    Some(EventLoopCommand::SetFaultInjector(injector)) => {
+                        state.fault_injector = injector;
+                    }
                     Some(EventLoopCommand::Shutdown) | None => {
                         break;
                     }
@@ -152,21 +186,376 @@ pub async fn event_loop(
     debug!("Event loop closed");
 }
 
+/// How many high priority fragments are sent in a row before a waiting normal priority PDU gets its turn.
+const MAX_HIGH_PRIORITY_STREAK: usize = 8;
+
+/// Returns the next fragment to send.
+/// Queued high priority PDUs are preferred, but a PDU that was already started is always finished first.
+/// `high_priority_streak` counts the high priority fragments since the last normal priority PDU, so a steady
+/// flow of high priority PDUs doesn't starve the normal priority ones.
+fn next_acl_fragment<'a>(
+    pending: &'a mut VecDeque<AclPacket>, high_priority_streak: &'a mut usize, high_priority: &'a mut MpscReceiver<AclPdu>,
+    normal_priority: &'a mut MpscReceiver<AclPdu>
+) -> impl Future<Output = Option<AclPacket>> + 'a {
+    poll_fn(move |cx| loop {
+        if let Some(fragment) = pending.pop_front() {
+            return Poll::Ready(Some(fragment));
+        }
+        let (pdu, is_high_priority) = match *high_priority_streak >= MAX_HIGH_PRIORITY_STREAK {
+            false => match high_priority.poll_recv(cx) {
+                Poll::Ready(Some(pdu)) => (pdu, true),
+                _ => match normal_priority.poll_recv(cx) {
+                    Poll::Ready(Some(pdu)) => (pdu, false),
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending
+                }
+            },
+            true => match normal_priority.poll_recv(cx) {
+                Poll::Ready(Some(pdu)) => (pdu, false),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => match high_priority.poll_recv(cx) {
+                    Poll::Ready(Some(pdu)) => (pdu, true),
+                    _ => return Poll::Pending
+                }
+            }
+        };
+        *high_priority_streak = match is_high_priority {
+            true => *high_priority_streak + pdu.len(),
+            false => 0
+        };
+        pending.extend(pdu);
+    })
+}
+
+/// Adds the fragments queued behind the first packet of a transfer,
+/// as long as the controller has buffers for them and they fit into one transfer.
+fn pack_fragments(transfer: &mut Vec<AclPacket>, pending: &mut VecDeque<AclPacket>, free_buffers: usize, max_size: usize) {
+    let mut size: usize = transfer.iter().map(|(data, _)| data.len()).sum();
+    while transfer.len() < free_buffers {
+        match pending.front() {
+            Some((data, _)) if size + data.len() <= max_size => {
+                size += data.len();
+                transfer.extend(pending.pop_front());
+            }
+            _ => break
+        }
+    }
+}
+
+enum Received {
+    Event(Result<Bytes, TransferError>),
+    AclData(Result<Bytes, TransferError>),
+    AclSent(Result<(), TransferError>)
+}
+
+/// The packet queues of the transport.
+enum Io {
+    Usb {
+        host: UsbHost,
+        events: Queue<RequestBuffer>,
+        acl_in: Queue<RequestBuffer>,
+        acl_out: Queue<Vec<u8>>
+    },
+    #[cfg(target_os = "linux")]
+    UserChannel(UserChannel)
+}
+
+impl Io {
+    fn new(transport: Transport) -> Self {
+        match transport {
+            Transport::Usb(host) => {
+                let TransferConfig { buffer_size, queue_depth } = host.transfers;
+                let mut events = host
+                    .interface
+                    .interrupt_in_queue(host.endpoints.event);
+                for _ in 0..queue_depth {
+                    events.submit(RequestBuffer::new(buffer_size));
+                }
+
+                let mut acl_in = host
+                    .interface
+                    .bulk_in_queue(host.endpoints.acl_in);
+                for _ in 0..queue_depth {
+                    acl_in.submit(RequestBuffer::new(buffer_size));
+                }
+                let acl_out = host
+                    .interface
+                    .bulk_out_queue(host.endpoints.acl_out);
+                Io::Usb { host, events, acl_in, acl_out }
+            }
+            #[cfg(target_os = "linux")]
+            Transport::UserChannel(channel) => Io::UserChannel(channel)
+        }
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>, buffer: &mut BytesMut) -> Poll<Received> {
+        match self {
+            Io::Usb { host, events, acl_in, acl_out } => {
+                if let Poll::Ready(event) = events.poll_next(cx) {
+                    let result = event.status.map(|_| {
+                        buffer.put_slice(&event.data);
+                        buffer.split().freeze()
+                    });
+                    events.submit(RequestBuffer::reuse(event.data, host.transfers.buffer_size));
+                    return Poll::Ready(Received::Event(result));
+                }
+                if let Poll::Ready(data) = acl_in.poll_next(cx) {
+                    let result = data.status.map(|_| {
+                        buffer.put_slice(&data.data);
+                        buffer.split().freeze()
+                    });
+                    acl_in.submit(RequestBuffer::reuse(data.data, host.transfers.buffer_size));
+                    return Poll::Ready(Received::AclData(result));
+                }
+                if acl_out.pending() > 0 {
+                    if let Poll::Ready(completion) = acl_out.poll_next(cx) {
+                        return Poll::Ready(Received::AclSent(completion.status));
+                    }
+                }
+                Poll::Pending
+            }
+            #[cfg(target_os = "linux")]
+            Io::UserChannel(channel) => loop {
+                return match std::task::ready!(channel.poll_recv(cx)) {
+                    Ok((HCI_EVENT_PKT, data)) => Poll::Ready(Received::Event(Ok(data))),
+                    Ok((HCI_ACLDATA_PKT, data)) => Poll::Ready(Received::AclData(Ok(data))),
+                    Ok((indicator, _)) => {
+                        debug!("Ignoring packet of type 0x{:02X}", indicator);
+                        continue;
+                    }
+                    Err(err) => Poll::Ready(Received::Event(Err(socket_error(err))))
+                };
+            }
+        }
+    }
+
+    fn max_transfer_size(&self) -> usize {
+        match self {
+            Io::Usb { host, .. } => host.transfers.buffer_size,
+            #[cfg(target_os = "linux")]
+            Io::UserChannel(_) => usize::MAX
+        }
+    }
+
+    async fn send_acl_data(&mut self, packets: Vec<Bytes>) {
+        match self {
+            Io::Usb { acl_out, .. } => acl_out.submit(packets.concat()),
+            // Every write is a single packet on the socket
+            #[cfg(target_os = "linux")]
+            Io::UserChannel(channel) => {
+                for data in packets {
+                    channel
+                        .send(HCI_ACLDATA_PKT, &data)
+                        .await
+                        .unwrap_or_else(|err| error!("Error writing ACL data: {:?}", err));
+                }
+            }
+        }
+    }
+
+    async fn write_command(&self, data: &[u8]) -> Result<(), TransferError> {
+        match self {
+            Io::Usb { host, .. } => host
+                .interface
+                .control_out(ControlOut {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request: 0x00,
+                    value: 0x00,
+                    index: host.endpoints.main_iface.into(),
+                    data
+                })
+                .await
+                .status,
+            #[cfg(target_os = "linux")]
+            Io::UserChannel(channel) => channel
+                .send(HCI_COMMAND_PKT, data)
+                .await
+                .map_err(socket_error)
+        }
+    }
+}
+
+/// Reports socket errors like failed USB transfers, so callers of commands don't depend on the transport.
+#[cfg(target_os = "linux")]
+fn socket_error(err: std::io::Error) -> TransferError {
+    debug!("HCI socket error: {}", err);
+    // ENODEV: the controller was removed
+    match err.raw_os_error() {
+        Some(19) => TransferError::Disconnected,
+        _ => TransferError::Unknown
+    }
+}
+
+/// A received ACL packet, identified by its handle and the connection that used the handle at the time.
+type ProcessedPacket = (u16, u32);
+
+/// Controller to host flow control ([Vol 4] Part E, Section 4.2).
+/// Every received packet counts as processed once the last reference to its data is dropped, no matter whether
+/// a handler consumed it, discarded it or there was no handler at all.
+struct HostFlowControl {
+    /// The current connection of each handle, so that packets of a closed connection aren't reported for a later one.
+    connections: BTreeMap<u16, u32>,
+    next_connection: u32,
+    /// Packets processed by the host that were not reported to the controller yet.
+    processed: BTreeMap<u16, u16>,
+    batch_size: u16,
+    reports: MpscSender<ProcessedPacket>
+}
+
+impl HostFlowControl {
+    fn new(batch_size: u16, reports: MpscSender<ProcessedPacket>) -> Self {
+        Self {
+            connections: BTreeMap::new(),
+            next_connection: 0,
+            processed: BTreeMap::new(),
+            batch_size,
+            reports
+        }
+    }
+
+    /// Ties the data of a received packet to its credit.
+    fn track(&mut self, data: Bytes) -> Bytes {
+        let [a, b, ..] = data[..] else {
+            return data;
+        };
+        let handle = u16::from_le_bytes([a, b]) & 0x0FFF;
+        let connection = *self.connections.entry(handle).or_insert_with(|| {
+            self.next_connection = self.next_connection.wrapping_add(1);
+            self.next_connection
+        });
+        Bytes::from_owner(TrackedPacket {
+            data,
+            packet: (handle, connection),
+            reports: self.reports.clone()
+        })
+    }
+}
+
+/// The data of a received packet, reports the packet as processed when dropped.
+struct TrackedPacket {
+    data: Bytes,
+    packet: ProcessedPacket,
+    reports: MpscSender<ProcessedPacket>
+}
+
+impl AsRef<[u8]> for TrackedPacket {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for TrackedPacket {
+    fn drop(&mut self) {
+        let _ = self.reports.send(self.packet);
+    }
+}
+
 #[derive(Default)]
 struct State {
-    outstanding_command: Option<(Opcode, OneshotSender<Result<Bytes, TransferError>>)>,
+    /// How many commands the controller currently accepts (Num_HCI_Command_Packets).
+    command_credits: u8,
+    /// Commands that were sent but not answered yet, in the order they were sent.
+    outstanding_commands: Vec<(Opcode, OutstandingCommand)>,
     hci_event_handlers: BTreeMap<EventCode, Vec<MpscSender<(EventCode, Bytes)>>>,
+    timestamped_event_handlers: BTreeMap<EventCode, Vec<MpscSender<Timestamped<(EventCode, Bytes)>>>>,
     acl_data_handlers: Vec<MpscSender<Bytes>>,
     max_in_flight: u32,
-    in_flight: u32
+    in_flight: u32,
+    pending_completions: BTreeMap<u16, VecDeque<Option<OneshotSender<()>>>>,
+    host_flow_control: Option<HostFlowControl>,
+    pack_acl_packets: bool,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<FaultInjector>
 }
 
 impl State {
-    async fn outstanding_command_dropped(&mut self) {
-        match self.outstanding_command.as_mut() {
-            None => pending().await,
-            Some((_, tx)) => tx.closed().await
+    /// Resolves with the index of an outstanding command whose caller is no longer waiting for the response.
+    fn outstanding_command_dropped(&mut self) -> impl Future<Output = usize> + '_ {
+        poll_fn(move |cx| {
+            self.outstanding_commands
+                .iter_mut()
+                .position(|(_, command)| matches!(command, OutstandingCommand::Waiting(tx) if tx.poll_closed(cx).is_ready()))
+                .map_or(Poll::Pending, Poll::Ready)
+        })
+    }
+
+    fn command_abandoned(&mut self, i: usize, now: Instant) {
+        let (opcode, command) = &mut self.outstanding_commands[i];
+        debug!("Caller of {:?} stopped waiting for the response", opcode);
+        *command = OutstandingCommand::Abandoned(now);
+        // Fall back to lockstep in case the controller never answers the command
+        self.command_credits = self.command_credits.max(1);
+    }
+
+    /// Takes the oldest outstanding command with `opcode`, forgetting abandoned ones that were never answered.
+    fn take_outstanding_command(&mut self, opcode: Opcode, now: Instant) -> Option<OutstandingCommand> {
+        self.outstanding_commands.retain(|(_, command)| match command {
+            OutstandingCommand::Abandoned(since) => now.duration_since(*since) < ABANDONED_COMMAND_TIMEOUT,
+            OutstandingCommand::Waiting(_) => true
+        });
+        let i = self.outstanding_commands.iter().position(|(op, _)| *op == opcode)?;
+        Some(self.outstanding_commands.remove(i).1)
+    }
+
+    fn packet_sent(&mut self, data: &Bytes, notifier: Option<OneshotSender<()>>) {
+        self.in_flight += 1;
+        let handle = u16::from_le_bytes([data[0], data[1]]) & 0x0FFF;
+        self.pending_completions
+            .entry(handle)
+            .or_default()
+            .push_back(notifier);
+    }
+
+    fn packets_completed(&mut self, handle: u16, count: u16) {
+        self.in_flight = self.in_flight.saturating_sub(count as u32);
+        if let Some(pending) = self.pending_completions.get_mut(&handle) {
+            for notifier in pending.drain(..pending.len().min(count as usize)) {
+                if let Some(notifier) = notifier {
+                    let _ = notifier.send(());
+                }
+            }
+        }
+    }
+
+    /// Returns a `HCI_Host_Number_Of_Completed_Packets` command once enough packets were processed.
+    fn acl_packet_processed(&mut self, (handle, connection): ProcessedPacket) -> Option<Bytes> {
+        let flow = self.host_flow_control.as_mut()?;
+        if flow.connections.get(&handle) != Some(&connection) {
+            // The controller already released the buffers of the closed connection
+            return None;
+        }
+        *flow.processed.entry(handle).or_default() += 1;
+        let total: u32 = flow.processed.values().map(|count| *count as u32).sum();
+        if total < flow.batch_size as u32 {
+            return None;
+        }
+        let mut cmd = BytesMut::new();
+        cmd.put_u16_le(Opcode::new(OpcodeGroup::HciControl, 0x0035).into());
+        cmd.put_u8((1 + 4 * flow.processed.len()) as u8);
+        cmd.put_u8(flow.processed.len() as u8);
+        for (handle, count) in std::mem::take(&mut flow.processed) {
+            cmd.put_u16_le(handle);
+            cmd.put_u16_le(count);
+        }
+        Some(cmd.freeze())
+    }
+
+    // The controller flushes all pending packets of a connection when it is closed ([Vol 4] Part E, Section 4.3).
+    fn connection_closed(&mut self, mut data: Bytes) -> Result<(), Error> {
+        let status: Status = data.read_le()?;
+        let handle: u16 = data.read_le()?;
+        if status.is_ok() {
+            if let Some(pending) = self.pending_completions.remove(&handle) {
+                self.in_flight = self.in_flight.saturating_sub(pending.len() as u32);
+            }
+            // The controller also forgets the packets it sent on this connection
+            if let Some(flow) = self.host_flow_control.as_mut() {
+                flow.processed.remove(&handle);
+                flow.connections.remove(&handle);
+            }
         }
+        Ok(())
     }
 
     fn process_hci_event(&mut self, mut data: Bytes) -> Result<bool, Error> {
@@ -182,18 +571,21 @@ impl State {
                     tmp.rotate_left(size_of::<Status>());
                     data = tmp.freeze();
                 }
-                let _cmd_quota: u8 = data.read_le()?;
+                self.command_credits = data.read_le()?;
                 let opcode: Opcode = data.read_le()?;
                 // trace!("Received CommandComplete for {:?}", opcode);
-                match self.outstanding_command.take() {
-                    Some((op, tx)) if op == opcode => tx
+                // Only updates the number of allowed commands ([Vol 4] Part E, Section 7.7.14)
+                if opcode == Opcode::NONE {
+                    return Ok(true);
+                }
+                match self
+                    .take_outstanding_command(opcode, now())
+                    .ok_or(Error::UnexpectedCommandResponse(opcode))?
+                {
+                    OutstandingCommand::Waiting(tx) => tx
                         .send(Ok(data))
                         .unwrap_or_else(|_| debug!("CommandComplete receiver dropped")),
-                    Some((op, tx)) => {
-                        self.outstanding_command = Some((op, tx));
-                        return Err(Error::UnexpectedCommandResponse(opcode));
-                    }
-                    None => return Err(Error::UnexpectedCommandResponse(opcode))
+                    OutstandingCommand::Abandoned(_) => debug!("Discarding late response to {:?}", opcode)
                 }
                 Ok(true)
             }
@@ -203,20 +595,28 @@ impl State {
                 let mut handles = data.split_to(count * 2);
                 let mut counts = data.split_to(count * 2);
                 for _ in 0..count {
-                    let _handle: u16 = handles.read_le()?;
+                    let handle: u16 = handles.read_le()?;
                     let count: u16 = counts.read_le()?;
                     //trace!("Flushed {} packets for handle {}", count, handle);
-                    self.in_flight = self.in_flight.saturating_sub(count as u32);
+                    self.packets_completed(handle, count);
                 }
                 data.finish()?;
                 Ok(true)
             }
             _ => {
                 let code = header.code;
+                if code == EventCode::DisconnectionComplete {
+                    self.connection_closed(data.clone())?;
+                }
+                let timestamped = self
+                    .timestamped_event_handlers
+                    .get_mut(&code)
+                    .map_or(false, |handlers| handlers.dispatch(Timestamped::now((code, data.clone()))));
                 let handled = self
                     .hci_event_handlers
                     .get_mut(&code)
                     .map_or(false, |handlers| handlers.dispatch((code, data)));
+                let handled = handled || timestamped;
                 if !handled {
                     warn!("Unhandled HCI event: {:?}", code);
                 }
@@ -227,6 +627,18 @@ impl State {
 
     fn process_acl_data(&mut self, data: Bytes) -> Result<(), Error> {
         // let data = AclDataPacket::from_bytes(data).ok_or(Error::BadEventPacketSize)?;
+        let data = match self.host_flow_control.as_mut() {
+            Some(flow) => flow.track(data),
+            None => data
+        };
+        #[cfg(feature = "fault-injection")]
+        if let Some(injector) = &self.fault_injector {
+            let mut handlers = self.acl_data_handlers.clone();
+            injector.apply(data).deliver(move |data| {
+                handlers.dispatch(data);
+            });
+            return Ok(());
+        }
         self.acl_data_handlers.dispatch(data);
         Ok(())
     }
@@ -268,8 +680,8 @@ InquiryEvent::Complete => {
 InquiryEvent::Result => {
     // ([Vol 4] Part E, Section 7.7.2).
     let count = payload.u8()? as usize;
-    let addr: SmallVec<[RemoteAddr; 2]> = (0..count)
-        .map(|_| payload.bytes().map(RemoteAddr::from))
+    let addr: SmallVec<[BdAddr; 2]> = (0..count)
+        .map(|_| payload.bytes().map(BdAddr::from))
         .collect::<Result<_, _>>()?;
     payload.skip(count * 3); // repetition mode
     let classes: SmallVec<[ClassOfDevice; 2]> = (0..count)
@@ -281,7 +693,221 @@ InquiryEvent::Result => {
     payload.finish()?;
 
     for i in 0..count {
-        debug!("Inquiry result: {} {:?}", addr[i], classes[i]);
+        debug!("Inquiry result: {} {:?}", redacted(&addr[i]), classes[i]);
     }
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use bytes::Bytes;
+    use instructor::Buffer;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::sync::oneshot;
+    use tokio::time::Instant;
+
+    use crate::hci::acl::{AclDataAssembler, AclHeader};
+    use crate::hci::event_loop::{next_acl_fragment, pack_fragments, HostFlowControl, OutstandingCommand, State, ABANDONED_COMMAND_TIMEOUT};
+    use crate::hci::{Opcode, OpcodeGroup};
+
+    /// A Command Complete event for `opcode` with `credits` and `parameters`.
+    fn command_complete(credits: u8, opcode: Opcode, parameters: &[u8]) -> Bytes {
+        let mut data = vec![0x0E, 3 + parameters.len() as u8, credits];
+        data.extend_from_slice(&u16::from(opcode).to_le_bytes());
+        data.extend_from_slice(parameters);
+        Bytes::from(data)
+    }
+
+    #[tokio::test]
+    async fn high_priority_does_not_starve_normal_priority() {
+        let (high_tx, mut high) = unbounded_channel();
+        let (normal_tx, mut normal) = unbounded_channel();
+        let pdu = |id: u8, fragments: usize| (0..fragments).map(|_| (Bytes::from(vec![id]), None)).collect::<Vec<_>>();
+        for id in 1..=3 {
+            high_tx.send(pdu(id, 4)).unwrap();
+        }
+        normal_tx.send(pdu(0, 1)).unwrap();
+        let mut pending = VecDeque::new();
+        let mut streak = 0;
+        let mut order = Vec::new();
+        for _ in 0..13 {
+            let (data, _) = next_acl_fragment(&mut pending, &mut streak, &mut high, &mut normal).await.unwrap();
+            order.push(data[0]);
+        }
+        assert_eq!(order, [1, 1, 1, 1, 2, 2, 2, 2, 0, 3, 3, 3, 3]);
+    }
+
+    #[tokio::test]
+    async fn late_responses_of_abandoned_commands() {
+        let opcode = Opcode::new(OpcodeGroup::InfoParams, 0x0009);
+        let mut state = State::default();
+        let (first, first_rx) = oneshot::channel();
+        let (second, mut second_rx) = oneshot::channel();
+        state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(first)));
+        state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(second)));
+        drop(first_rx);
+        let i = state.outstanding_command_dropped().await;
+        assert_eq!(i, 0);
+        state.command_abandoned(i, Instant::now());
+        // The controller may have been out of credits, the host keeps sending in lockstep
+        assert_eq!(state.command_credits, 1);
+
+        assert!(state.process_hci_event(command_complete(1, opcode, &[0x00, 0x01])).unwrap());
+        assert!(second_rx.try_recv().is_err());
+        assert!(state.process_hci_event(command_complete(2, opcode, &[0x00, 0x02])).unwrap());
+        assert_eq!(second_rx.try_recv().unwrap().unwrap(), Bytes::from_static(&[0x00, 0x02]));
+        assert_eq!(state.command_credits, 2);
+        assert!(state.outstanding_commands.is_empty());
+    }
+
+    #[test]
+    fn unanswered_abandoned_commands_expire() {
+        let opcode = Opcode::new(OpcodeGroup::InfoParams, 0x0009);
+        let abandoned = Instant::now();
+        let mut state = State::default();
+        let (tx, _rx) = oneshot::channel();
+        state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(tx)));
+        state.command_abandoned(0, abandoned);
+        let (tx, _rx) = oneshot::channel();
+        state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(tx)));
+        let response = state.take_outstanding_command(opcode, abandoned + ABANDONED_COMMAND_TIMEOUT);
+        assert!(matches!(response, Some(OutstandingCommand::Waiting(_))));
+    }
+
+    #[test]
+    fn credit_updates() {
+        let mut state = State::default();
+        // Events with no opcode only update the number of allowed commands
+        assert!(state.process_hci_event(command_complete(5, Opcode::NONE, &[])).unwrap());
+        assert_eq!(state.command_credits, 5);
+        // Command Status carries the status before the credits
+        let opcode = Opcode::new(OpcodeGroup::LinkControl, 0x0001);
+        let (tx, mut rx) = oneshot::channel();
+        state.outstanding_commands.push((opcode, OutstandingCommand::Waiting(tx)));
+        let mut status = vec![0x0F, 0x04, 0x00, 0x03];
+        status.extend_from_slice(&u16::from(opcode).to_le_bytes());
+        assert!(state.process_hci_event(Bytes::from(status)).unwrap());
+        assert_eq!(state.command_credits, 3);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), Bytes::from_static(&[0x00]));
+    }
+
+    fn packet(handle: u16, first: bool, payload: &[u8]) -> Bytes {
+        let flags: u16 = if first { 0x2000 } else { 0x1000 };
+        let mut data = Vec::new();
+        data.extend_from_slice(&(handle | flags).to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        data.extend_from_slice(payload);
+        Bytes::from(data)
+    }
+
+    #[test]
+    fn credits_follow_data() {
+        let (reports, mut processed) = unbounded_channel();
+        let (handler, mut received) = unbounded_channel();
+        let mut state = State {
+            host_flow_control: Some(HostFlowControl::new(2, reports)),
+            acl_data_handlers: vec![handler],
+            ..Default::default()
+        };
+        state.process_acl_data(packet(0x0001, true, &[0x00, 0x00, 0x40, 0x00])).unwrap();
+        let data = received.try_recv().unwrap();
+        assert!(processed.try_recv().is_err());
+        drop(data);
+        let report = processed.try_recv().unwrap();
+        assert_eq!(state.acl_packet_processed(report), None);
+
+        // Packets nobody takes count as processed right away
+        state.acl_data_handlers.clear();
+        state.process_acl_data(packet(0x0002, true, &[0x00, 0x00, 0x40, 0x00])).unwrap();
+        let report = processed.try_recv().unwrap();
+        assert_eq!(
+            state.acl_packet_processed(report),
+            Some(Bytes::from_static(&[0x35, 0x0C, 0x09, 0x02, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00]))
+        );
+        assert!(state.host_flow_control.unwrap().processed.is_empty());
+    }
+
+    #[test]
+    fn reassembled_pdus_hold_their_packets() {
+        let (reports, mut processed) = unbounded_channel();
+        let mut flow = HostFlowControl::new(1, reports);
+        let mut assembler = AclDataAssembler::default();
+        let mut pdu = None;
+        for data in [packet(0x0001, true, &[0x04, 0x00, 0x40, 0x00, 0x01]), packet(0x0001, false, &[0x02, 0x03, 0x04])] {
+            let mut data = flow.track(data);
+            let header: AclHeader = data.read().unwrap();
+            pdu = assembler.push(header, data);
+        }
+        let pdu = pdu.unwrap();
+        assert_eq!(&pdu[..], &[0x04, 0x00, 0x40, 0x00, 0x01, 0x02, 0x03, 0x04]);
+        assert!(processed.try_recv().is_err());
+        drop(pdu);
+        assert_eq!(processed.try_recv(), Ok((0x0001, 1)));
+        assert_eq!(processed.try_recv(), Ok((0x0001, 1)));
+    }
+
+    #[test]
+    fn closed_connections_are_not_reported() {
+        let (reports, mut processed) = unbounded_channel();
+        let mut state = State {
+            host_flow_control: Some(HostFlowControl::new(1, reports)),
+            ..Default::default()
+        };
+        let flow = state.host_flow_control.as_mut().unwrap();
+        let stale = flow.track(packet(0x0001, true, &[0x00, 0x00, 0x40, 0x00]));
+        state.connection_closed(Bytes::from_static(&[0x00, 0x01, 0x00, 0x13])).unwrap();
+
+        // The handle is reused by the next connection
+        let flow = state.host_flow_control.as_mut().unwrap();
+        let current = flow.track(packet(0x0001, true, &[0x00, 0x00, 0x40, 0x00]));
+        drop(stale);
+        assert_eq!(state.acl_packet_processed(processed.try_recv().unwrap()), None);
+        drop(current);
+        assert!(state.acl_packet_processed(processed.try_recv().unwrap()).is_some());
+    }
+
+    #[test]
+    fn completed_packets_notify_senders() {
+        let mut state = State::default();
+        let packet = |handle: u16| Bytes::from(vec![handle as u8, (handle >> 8) as u8, 0x01, 0x00, 0xAA]);
+        let (first, mut first_rx) = oneshot::channel();
+        let (second, mut second_rx) = oneshot::channel();
+        state.packet_sent(&packet(0x0001), None);
+        state.packet_sent(&packet(0x0001), Some(first));
+        state.packet_sent(&packet(0x0002), Some(second));
+        assert_eq!(state.in_flight, 3);
+
+        // Packets of a connection complete in order
+        assert!(state.process_hci_event(Bytes::from_static(&[0x13, 0x05, 0x01, 0x01, 0x00, 0x01, 0x00])).unwrap());
+        assert!(first_rx.try_recv().is_err());
+        assert!(state.process_hci_event(Bytes::from_static(&[0x13, 0x05, 0x01, 0x01, 0x00, 0x01, 0x00])).unwrap());
+        assert_eq!(first_rx.try_recv(), Ok(()));
+        assert_eq!(state.in_flight, 1);
+
+        // The packets of a closed connection are flushed without completing
+        state.process_hci_event(Bytes::from_static(&[0x05, 0x04, 0x00, 0x02, 0x00, 0x13])).unwrap();
+        assert_eq!(second_rx.try_recv(), Err(oneshot::error::TryRecvError::Closed));
+        assert_eq!(state.in_flight, 0);
+    }
+
+    #[test]
+    fn packing_respects_buffers_and_transfer_size() {
+        let packet = |id: u8, len: usize| (Bytes::from(vec![id; len]), None);
+        let ids = |transfer: &Vec<_>| transfer.iter().map(|(data, _): &(Bytes, _)| data[0]).collect::<Vec<u8>>();
+
+        let mut pending = VecDeque::from([packet(2, 10), packet(3, 10), packet(4, 10)]);
+        let mut transfer = vec![packet(1, 10)];
+        pack_fragments(&mut transfer, &mut pending, 3, 1024);
+        assert_eq!(ids(&transfer), [1, 2, 3]);
+        assert_eq!(pending.len(), 1);
+
+        // Packets that don't fit into the transfer stay queued, even if later ones would fit
+        let mut pending = VecDeque::from([packet(2, 20), packet(3, 5)]);
+        let mut transfer = vec![packet(1, 10)];
+        pack_fragments(&mut transfer, &mut pending, 8, 25);
+        assert_eq!(ids(&transfer), [1]);
+        assert_eq!(pending.len(), 2);
+    }
+}
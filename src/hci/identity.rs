@@ -0,0 +1,287 @@
+use crate::adv::{AdStructure, AdvertisingData, TransportBlock};
+use crate::ensure;
+use crate::hci::consts::ClassOfDevice;
+use crate::hci::{
+    AdvertisingEventProperties, Error, ExtendedAdvertisingParameters, Hci, MAX_ADVERTISING_DATA_FRAGMENT,
+    MAX_LEGACY_ADVERTISING_DATA
+};
+use crate::sdp::{Uuid, Uuid16};
+
+/// The maximum length of the local name in bytes ([Vol 4] Part E, Section 7.3.11).
+pub const MAX_LOCAL_NAME_LENGTH: usize = 248;
+const EIR_LENGTH: usize = 240;
+/// The advertising set used by [Hci::advertise_identity].
+const IDENTITY_ADVERTISING_HANDLE: u8 = 0x00;
+/// LE General Discoverable Mode, simultaneous LE and BR/EDR in the controller and the host
+/// ([Core Specification Supplement] Part A, Section 1.3).
+const DUAL_MODE_FLAGS: u8 = 0x1A;
+
+/// How the local device presents itself to remote devices: in the remote name request, the class of device,
+/// the device id record and the extended inquiry response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceIdentity {
+    pub name: String,
+    /// Shown instead of the name if the name does not fit into the extended inquiry response.
+    pub short_name: Option<String>,
+    pub class_of_device: ClassOfDevice,
+    pub device_id: Option<DeviceId>,
+    /// The service classes advertised in the extended inquiry response.
+    pub service_classes: Vec<Uuid>
+}
+
+impl DeviceIdentity {
+    pub fn new(name: impl Into<String>, class_of_device: ClassOfDevice) -> Self {
+        Self {
+            name: name.into(),
+            short_name: None,
+            class_of_device,
+            device_id: None,
+            service_classes: Vec::new()
+        }
+    }
+
+    pub fn with_short_name(mut self, short_name: impl Into<String>) -> Self {
+        self.short_name = Some(short_name.into());
+        self
+    }
+
+    pub fn with_device_id(mut self, device_id: DeviceId) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    pub fn with_service_classes(mut self, service_classes: impl IntoIterator<Item = impl Into<Uuid>>) -> Self {
+        self.service_classes = service_classes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Builds the extended inquiry response data ([Vol 3] Part C, Section 8).
+    /// The name comes last, so that it can be shortened to the remaining space.
+    pub fn extended_inquiry_response(&self) -> [u8; EIR_LENGTH] {
+        let mut eir = AdvertisingData::default();
+        if let Some(device_id) = self.device_id {
+            eir.push(AdStructure::DeviceId(device_id));
+        }
+        let mut uuids: Vec<Uuid16> = self
+            .service_classes
+            .iter()
+            .filter_map(|uuid| Uuid16::try_from(*uuid).ok())
+            .collect();
+        uuids.truncate((EIR_LENGTH - eir.encoded_len() - 2) / 2);
+        if !uuids.is_empty() {
+            let complete = uuids.len() == self.service_classes.len();
+            eir.push(AdStructure::Uuids16 { complete, uuids });
+        }
+        let space = EIR_LENGTH.saturating_sub(eir.encoded_len() + 2);
+        let (complete, name) = match self.short_name.as_deref() {
+            _ if self.name.len() <= space => (true, self.name.as_str()),
+            Some(short_name) if short_name.len() <= space => (false, short_name),
+            _ => (false, truncate_utf8(&self.name, space))
+        };
+        if space > 0 && !name.is_empty() {
+            eir.push(AdStructure::LocalName {
+                complete,
+                name: name.to_string()
+            });
+        }
+        let eir = eir
+            .to_bytes()
+            .expect("Every structure is limited to the EIR length");
+        let mut result = [0; EIR_LENGTH];
+        result[..eir.len()].copy_from_slice(&eir);
+        result
+    }
+
+    /// Builds LE advertising data of at most `max_len` bytes that announces the BR/EDR side of the device:
+    /// the class of device and the transport discovery data let phones show the device from an LE scan and
+    /// page it directly instead of running an inquiry first.
+    pub fn le_advertising_data(&self, max_len: usize) -> AdvertisingData {
+        let mut data = AdvertisingData::default()
+            .with(AdStructure::Flags(DUAL_MODE_FLAGS))
+            .with(AdStructure::ClassOfDevice(self.class_of_device))
+            .with(AdStructure::TransportDiscovery(vec![TransportBlock::br_edr()]));
+        let space = max_len.saturating_sub(data.encoded_len() + 2);
+        let (complete, name) = match self.short_name.as_deref() {
+            _ if self.name.len() <= space => (true, self.name.as_str()),
+            Some(short_name) if short_name.len() <= space => (false, short_name),
+            _ => (false, truncate_utf8(&self.name, space))
+        };
+        if space > 0 && !name.is_empty() {
+            data.push(AdStructure::LocalName {
+                complete,
+                name: name.to_string()
+            });
+        }
+        data
+    }
+}
+
+fn advertising_data_limit(parameters: &ExtendedAdvertisingParameters) -> usize {
+    match parameters.properties.contains(AdvertisingEventProperties::LEGACY) {
+        true => MAX_LEGACY_ADVERTISING_DATA,
+        false => MAX_ADVERTISING_DATA_FRAGMENT
+    }
+}
+
+/// The device id as defined by the Device ID profile ([DID] Section 5).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId {
+    pub vendor_id_source: VendorIdSource,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u16)]
+pub enum VendorIdSource {
+    BluetoothSig = 0x0001,
+    UsbImplementersForum = 0x0002
+}
+
+/// Shortens `value` to at most `max_len` bytes without splitting a character.
+pub(crate) fn truncate_utf8(value: &str, max_len: usize) -> &str {
+    if value.len() <= max_len {
+        return value;
+    }
+    let end = (0..=max_len).rev().find(|&i| value.is_char_boundary(i)).unwrap_or(0);
+    &value[..end]
+}
+
+impl Hci {
+    /// Applies all parts of `identity` to the controller and remembers it for [Hci::update_identity].
+    pub async fn set_identity(&self, identity: DeviceIdentity) -> Result<(), Error> {
+        let mut current = self.identity.lock().await;
+        self.write_local_name(&identity.name).await?;
+        self.write_class_of_device(identity.class_of_device).await?;
+        self.write_extended_inquiry_response(&identity.extended_inquiry_response())
+            .await?;
+        *current = Some(identity);
+        Ok(())
+    }
+
+    /// Changes the identity set by [Hci::set_identity] at runtime.
+    /// Only the parts that actually changed are written to the controller.
+    pub async fn update_identity(&self, f: impl FnOnce(&mut DeviceIdentity)) -> Result<(), Error> {
+        let mut current = self.identity.lock().await;
+        let old = current.as_ref().ok_or(Error::Generic("No device identity set"))?;
+        let mut new = old.clone();
+        f(&mut new);
+        if new.name != old.name {
+            self.write_local_name(&new.name).await?;
+        }
+        if new.class_of_device != old.class_of_device {
+            self.write_class_of_device(new.class_of_device).await?;
+        }
+        let eir = new.extended_inquiry_response();
+        if eir != old.extended_inquiry_response() {
+            self.write_extended_inquiry_response(&eir).await?;
+        }
+        let advertising = *self.identity_advertising.lock();
+        if let Some(parameters) = advertising {
+            let limit = advertising_data_limit(&parameters);
+            let data = new.le_advertising_data(limit);
+            if data != old.le_advertising_data(limit) {
+                self.set_extended_advertising_data(IDENTITY_ADVERTISING_HANDLE, &data.to_bytes()?)
+                    .await?;
+            }
+        }
+        *current = Some(new);
+        Ok(())
+    }
+
+    /// Advertises the identity set by [Hci::set_identity] over LE until [Hci::stop_advertising_identity],
+    /// so that phones scanning for LE devices find the device and connect to it over BR/EDR.
+    /// Advertising from the public address lets the phone associate both transports with the same device.
+    pub async fn advertise_identity(&self, parameters: ExtendedAdvertisingParameters) -> Result<(), Error> {
+        let current = self.identity.lock().await;
+        let identity = current.as_ref().ok_or(Error::Generic("No device identity set"))?;
+        ensure!(
+            !parameters.properties.contains(AdvertisingEventProperties::CONNECTABLE),
+            "LE connections are not supported"
+        );
+        let data = identity.le_advertising_data(advertising_data_limit(&parameters));
+        self.write_le_host_support(true).await?;
+        if self.identity_advertising.lock().is_some() {
+            self.set_extended_advertising_enable(false, &[(IDENTITY_ADVERTISING_HANDLE, None)])
+                .await?;
+        }
+        self.set_extended_advertising_parameters(IDENTITY_ADVERTISING_HANDLE, &parameters)
+            .await?;
+        self.set_extended_advertising_data(IDENTITY_ADVERTISING_HANDLE, &data.to_bytes()?)
+            .await?;
+        self.set_extended_advertising_enable(true, &[(IDENTITY_ADVERTISING_HANDLE, None)])
+            .await?;
+        *self.identity_advertising.lock() = Some(parameters);
+        Ok(())
+    }
+
+    /// Stops the advertising started by [Hci::advertise_identity] and removes its advertising set.
+    pub async fn stop_advertising_identity(&self) -> Result<(), Error> {
+        let _current = self.identity.lock().await;
+        if self.identity_advertising.lock().take().is_some() {
+            self.set_extended_advertising_enable(false, &[(IDENTITY_ADVERTISING_HANDLE, None)])
+                .await?;
+            self.remove_advertising_set(IDENTITY_ADVERTISING_HANDLE)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the identity set by [Hci::set_identity].
+    pub async fn identity(&self) -> Option<DeviceIdentity> {
+        self.identity.lock().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::adv::AdvertisingData;
+    use crate::hci::consts::{ClassOfDevice, DeviceClass, MajorServiceClasses};
+    use crate::hci::identity::{truncate_utf8, DeviceIdentity};
+    use crate::hci::MAX_LEGACY_ADVERTISING_DATA;
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate_utf8("bluefang", 4), "blue");
+        assert_eq!(truncate_utf8("bluefang", 10), "bluefang");
+        // 'ä' takes two bytes
+        assert_eq!(truncate_utf8("bär", 2), "b");
+        assert_eq!(truncate_utf8("bär", 3), "bä");
+    }
+
+    #[test]
+    fn eir_name() {
+        let cod = ClassOfDevice {
+            service_classes: MajorServiceClasses::empty(),
+            device_class: DeviceClass::Uncategorized
+        };
+        let eir = DeviceIdentity::new("bluefang", cod).extended_inquiry_response();
+        assert_eq!(&eir[..10], b"\x09\x09bluefang");
+        assert!(eir[10..].iter().all(|b| *b == 0));
+
+        let long = "x".repeat(300);
+        let eir = DeviceIdentity::new(long.as_str(), cod)
+            .with_short_name("short")
+            .extended_inquiry_response();
+        assert_eq!(&eir[..7], b"\x06\x08short");
+    }
+
+    #[test]
+    fn le_advertising_data() {
+        let cod = ClassOfDevice {
+            service_classes: MajorServiceClasses::empty(),
+            device_class: DeviceClass::Uncategorized
+        };
+        let identity = DeviceIdentity::new("bluefang kitchen speaker", cod);
+        let data = identity.le_advertising_data(MAX_LEGACY_ADVERTISING_DATA);
+        let bytes = data.to_bytes().unwrap();
+        assert!(bytes.len() <= MAX_LEGACY_ADVERTISING_DATA);
+        // Flags, class of device and a single BR/EDR transport block
+        assert_eq!(&bytes[..13], b"\x02\x01\x1A\x04\x0D\x00\x1F\x00\x04\x26\x01\x0A\x00");
+        assert_eq!(AdvertisingData::parse(bytes).unwrap(), data);
+        assert_eq!(data.local_name(), Some("bluefang kitchen"));
+    }
+}
@@ -2,65 +2,100 @@ mod commands;
 pub mod consts;
 mod error;
 // pub mod connection;
+pub mod devices;
 pub mod acl;
 pub mod btsnoop;
 pub mod connection;
+pub mod establish;
 mod event_loop;
+mod identity;
+pub mod remote_info;
+mod sco;
+mod self_test;
 
-use std::collections::BTreeSet;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 pub use commands::*;
+pub use identity::{DeviceId, DeviceIdentity, VendorIdSource, MAX_LOCAL_NAME_LENGTH};
+pub use sco::{PcmClockRate, PcmConfig, ScoRouting};
+pub use self_test::{CommandLatency, SelfTestReport};
 use instructor::utils::Length;
 use instructor::{Buffer, BufferMut, Exstruct, LittleEndian};
 use nusb::transfer::TransferError;
 use parking_lot::Mutex;
-use tokio::spawn;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender as MpscSender};
+use tokio::sync::oneshot::{channel as oneshot_channel, Receiver as OneshotReceiver, Sender as OneshotSender};
+use tokio::sync::Mutex as AsyncMutex;
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
+use crate::ensure;
 use crate::hci::acl::{AclHeader, BoundaryFlag, BroadcastFlag};
-use crate::hci::consts::{EventCode, EventMask, Status};
-use crate::hci::event_loop::{CmdResultSender, EventLoopCommand};
-use crate::host::usb::UsbHost;
-use crate::utils::Loggable;
+use crate::hci::consts::{BdAddr, EventCode, EventMask, LeEventMask, LeSubevent, Status};
+use crate::hci::event_loop::{AclPdu, CmdResultSender, EventLoopCommand};
+use crate::host::Transport;
+use crate::utils::clock::{sleep, Timestamped};
+use crate::utils::redact::redacted;
+use crate::utils::{spawn_supervised, Loggable};
+#[cfg(feature = "fault-injection")]
+use crate::utils::fault::FaultInjector;
 
 //TODO make generic over transport
 pub struct Hci {
     //transport: UsbHost,
     //router: Arc<EventRouter>,
-    cmd_out: MpscSender<(Opcode, Bytes, CmdResultSender)>,
-    acl_out: MpscSender<Bytes>,
+    cmd_out: CommandSender,
+    acl_out: MpscSender<AclPdu>,
+    acl_high_priority_out: MpscSender<AclPdu>,
     ctl_out: MpscSender<EventLoopCommand>,
     acl_size: usize,
-    event_loop: Mutex<Option<JoinHandle<()>>>,
+    host_acl_size: u16,
+    event_loop: Mutex<Option<JoinHandle<Option<()>>>>,
+    event_mask: Arc<Mutex<EventMaskState>>,
+    le_event_mask: AsyncMutex<LeEventMask>,
+    identity: AsyncMutex<Option<DeviceIdentity>>,
+    /// The parameters the identity is advertised over LE with, `None` while it isn't.
+    identity_advertising: Mutex<Option<ExtendedAdvertisingParameters>>,
     version: LocalVersion
 }
 
 impl Hci {
-    pub async fn new(transport: UsbHost) -> Result<Self, Error> {
+    /// Takes over the controller behind `transport`, e.g. a claimed [UsbHost](crate::host::usb::UsbHost)
+    /// or a [UserChannel](crate::host::user_channel::UserChannel) on Linux.
+    pub async fn new(transport: impl Into<Transport>) -> Result<Self, Error> {
+        let transport = transport.into();
+        let name = transport.name();
+        let host_acl_size = transport.max_acl_packet_length();
         let (acl_out, acl_in) = unbounded_channel();
+        let (acl_high_priority_out, acl_high_priority_in) = unbounded_channel();
         let (cmd_out, cmd_in) = unbounded_channel();
         let (ctl_out, ctl_in) = unbounded_channel();
-        let event_loop = spawn(event_loop::event_loop(transport, cmd_in, acl_in, ctl_in));
+        let event_loop = spawn_supervised("hci-event-loop", name, event_loop::event_loop(transport, cmd_in, acl_in, acl_high_priority_in, ctl_in));
         let mut hci = Self {
             cmd_out,
             acl_out,
+            acl_high_priority_out,
             ctl_out,
             acl_size: 0,
+            host_acl_size,
             event_loop: Mutex::new(Some(event_loop)),
+            event_mask: Default::default(),
+            le_event_mask: AsyncMutex::new(LeEventMask::none()),
+            identity: AsyncMutex::new(None),
+            identity_advertising: Mutex::new(None),
             version: Default::default(),
         };
 
         // Reset after allowing the event loop to discard any unexpected events
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        sleep(Duration::from_millis(100)).await;
         debug!("HCI reset...");
         hci.reset().await?;
 
@@ -71,7 +106,11 @@ impl Hci {
 
         //debug!("{:?}", hci.read_local_supported_commands().await?);
 
-        hci.set_event_mask(EventMask::all()).await?;
+        // Subsystems enable the events they need on top of this through `enable_events`,
+        // handlers registered during the setup already did
+        let mask = hci.event_mask.lock().required();
+        hci.set_event_mask(mask).await?;
+        hci.event_mask.lock().current = mask;
 
         let buffer_size = hci.read_buffer_size().await?;
         hci.acl_size = buffer_size.acl_data_packet_length as usize;
@@ -87,9 +126,100 @@ impl Hci {
         debug_assert!(!events.is_empty());
         debug_assert!(!events.contains(&EventCode::CommandComplete));
         debug_assert!(!events.contains(&EventCode::CommandStatus));
+        let closed = handler.clone();
         self.ctl_out
-            .send(EventLoopCommand::RegisterHciEventHandler { events, handler })
-            .map_err(|_| Error::EventLoopClosed)
+            .send(EventLoopCommand::RegisterHciEventHandler { events: events.clone(), handler })
+            .map_err(|_| Error::EventLoopClosed)?;
+        self.enable_handled_events(events, async move { closed.closed().await })
+    }
+
+    /// Like [Hci::register_event_handler], but each event is stamped with the time the event loop read it from the controller.
+    pub fn register_timestamped_event_handler(
+        &self, events: impl Into<BTreeSet<EventCode>>, handler: MpscSender<Timestamped<(EventCode, Bytes)>>
+    ) -> Result<(), Error> {
+        let events = events.into();
+        debug_assert!(!events.is_empty());
+        debug_assert!(!events.contains(&EventCode::CommandComplete));
+        debug_assert!(!events.contains(&EventCode::CommandStatus));
+        let closed = handler.clone();
+        self.ctl_out
+            .send(EventLoopCommand::RegisterTimestampedHciEventHandler { events: events.clone(), handler })
+            .map_err(|_| Error::EventLoopClosed)?;
+        self.enable_handled_events(events, async move { closed.closed().await })
+    }
+
+    /// Enables the events of a new handler until it is dropped. The command is queued before anything the caller
+    /// sends afterward, so the events are enabled before the commands that trigger them.
+    fn enable_handled_events(&self, events: BTreeSet<EventCode>, closed: impl Future<Output = ()> + Send + 'static) -> Result<(), Error> {
+        let done = self.update_event_mask(|state| state.acquire(&events))?;
+        let state = self.event_mask.clone();
+        let cmd_out = self.cmd_out.clone();
+        spawn_supervised("hci-event-mask", "HCI", async move {
+            if let Some(done) = done {
+                done.await.unwrap_or_else(|err| warn!("Failed to enable the events of a handler: {:?}", err));
+            }
+            closed.await;
+            // Events that no other handler or subsystem needs are disabled again, e.g. the results after an inquiry
+            if let Ok(Some(done)) = update_event_mask(&state, &cmd_out, |state| state.release(&events)) {
+                done.await.unwrap_or_else(|err| warn!("Failed to disable the events of a handler: {:?}", err));
+            }
+        });
+        Ok(())
+    }
+
+    fn update_event_mask(
+        &self, update: impl FnOnce(&mut EventMaskState)
+    ) -> Result<Option<impl Future<Output = Result<(), Error>> + Send + 'static>, Error> {
+        update_event_mask(&self.event_mask, &self.cmd_out, update)
+    }
+
+    /// Adds the given events to the controller's event mask until they are disabled again.
+    /// Only sends a command to the controller if the mask actually changes.
+    pub async fn enable_events(&self, events: impl IntoIterator<Item = EventCode>) -> Result<(), Error> {
+        let events: EventMask = events.into_iter().collect();
+        if let Some(done) = self.update_event_mask(move |state| state.enabled = state.enabled.union(events))? {
+            done.await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the given events from the controller's event mask.
+    /// Events that are part of [`EventMask::core`] or needed by a registered handler stay enabled.
+    pub async fn disable_events(&self, events: impl IntoIterator<Item = EventCode>) -> Result<(), Error> {
+        let events: EventMask = events.into_iter().collect();
+        let update = move |state: &mut EventMaskState| state.enabled = state.enabled.difference(events).union(EventMask::core());
+        if let Some(done) = self.update_event_mask(update)? {
+            done.await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the given LE subevents to the controller's LE event mask and enables the LE meta event if necessary.
+    pub async fn enable_le_events(&self, events: impl IntoIterator<Item = LeSubevent>) -> Result<(), Error> {
+        let mut mask = self.le_event_mask.lock().await;
+        let updated = events.into_iter().fold(*mask, |mask, e| mask.with(e, true));
+        if updated != *mask {
+            self.set_le_event_mask(updated).await?;
+            *mask = updated;
+        }
+        if !mask.is_empty() {
+            self.enable_events([EventCode::LeMeta]).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the given LE subevents from the controller's LE event mask and disables the LE meta event once no subevents are left.
+    pub async fn disable_le_events(&self, events: impl IntoIterator<Item = LeSubevent>) -> Result<(), Error> {
+        let mut mask = self.le_event_mask.lock().await;
+        let updated = events.into_iter().fold(*mask, |mask, e| mask.with(e, false));
+        if updated != *mask {
+            self.set_le_event_mask(updated).await?;
+            *mask = updated;
+        }
+        if mask.is_empty() {
+            self.disable_events([EventCode::LeMeta]).await?;
+        }
+        Ok(())
     }
 
     pub fn register_data_handler(&self, handler: MpscSender<Bytes>) -> Result<(), Error> {
@@ -98,10 +228,50 @@ impl Hci {
             .map_err(|_| Error::EventLoopClosed)
     }
 
+    /// Enables controller to host flow control ([Vol 4] Part E, Section 4.2), so that the controller holds back
+    /// ACL data once `acl_packets` packets were not processed by the host yet. This bounds the memory used for
+    /// inbound data during bursts. Should be called before any connection is established.
+    ///
+    /// A packet counts as processed once every piece of its data was dropped, e.g. after the application took the
+    /// data of a channel out of its queue and dropped it. Data handlers and applications that keep received data
+    /// around for long should copy it, otherwise the controller eventually stops sending.
+    pub async fn enable_host_flow_control(&self, acl_packets: u16) -> Result<(), Error> {
+        ensure!(acl_packets > 0, "The host has to buffer at least one ACL packet");
+        self.host_buffer_size(self.host_acl_size, acl_packets)
+            .await?;
+        // Reporting in batches saves commands while leaving the controller enough room to keep sending
+        self.ctl_out
+            .send(EventLoopCommand::EnableHostFlowControl {
+                batch_size: (acl_packets / 4).max(1)
+            })
+            .map_err(|_| Error::EventLoopClosed)?;
+        self.set_controller_to_host_flow_control(true).await
+    }
+
+    /// Lets the event loop combine the queued packets of a PDU or a [batch](AclSender::send_batch) into a single USB transfer.
+    /// The controller separates the packets by their headers ([Vol 4] Part B, Section 2.1), but some controllers
+    /// only accept one packet per transfer, so this is disabled by default. Has no effect on other transports.
+    pub fn enable_acl_packing(&self) -> Result<(), Error> {
+        self.ctl_out
+            .send(EventLoopCommand::EnableAclPacking)
+            .map_err(|_| Error::EventLoopClosed)
+    }
+
+    /// Applies the faults of `injector` to all incoming ACL data. HCI commands and events are not affected.
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fault_injector(&self, injector: Option<FaultInjector>) -> Result<(), Error> {
+        self.ctl_out
+            .send(EventLoopCommand::SetFaultInjector(injector))
+            .map_err(|_| Error::EventLoopClosed)
+    }
+
     pub fn get_acl_sender(&self) -> AclSender {
         AclSender {
             sender: self.acl_out.clone(),
-            max_size: self.acl_size
+            high_priority_sender: self.acl_high_priority_out.clone(),
+            max_size: self.acl_size,
+            priority: AclPriority::Normal,
+            flushable: false
         }
     }
 
@@ -110,29 +280,16 @@ impl Hci {
     }
 
     pub async fn call_with_args<T: Exstruct<LittleEndian>>(&self, cmd: Opcode, packer: impl FnOnce(&mut BytesMut)) -> Result<T, Error> {
-        // TODO: check if the command is supported
-        let mut buf = BytesMut::with_capacity(255);
-        buf.write::<u16, LittleEndian>(cmd.into());
-        buf.write::<u8, LittleEndian>(0);
-        packer(&mut buf);
-        let payload_len = u8::try_from(buf.len() - 3).map_err(|_| Error::PayloadTooLarge)?;
-        buf[2] = payload_len;
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.cmd_out
-            .send((cmd, buf.freeze(), tx))
-            .map_err(|_| Error::EventLoopClosed)?;
-        //TODO: 1s timeout
-        let mut resp = rx.await.map_err(|_| Error::EventLoopClosed)??;
-        let status: Status = resp.read_le()?;
-        match status {
-            Status::Success => {
-                let result: T = resp.read_le()?;
-                resp.finish()?;
-                Ok(result)
-            }
-            _ => Err(Error::Controller(status))
-        }
+        self.submit_with_args(cmd, packer)?.await
+    }
+
+    /// Queues the command right away and returns a future that resolves with its result.
+    /// Submitting several commands before awaiting them lets the event loop send them as fast as the controller's
+    /// command credits allow, instead of waiting for each response.
+    pub fn submit_with_args<T: Exstruct<LittleEndian>>(
+        &self, cmd: Opcode, packer: impl FnOnce(&mut BytesMut)
+    ) -> Result<impl Future<Output = Result<T, Error>>, Error> {
+        submit_command(&self.cmd_out, cmd, packer)
     }
 
     pub async fn shutdown(&self) -> Result<(), Error> {
@@ -164,6 +321,8 @@ impl Debug for Hci {
 pub enum AclSendError {
     #[error("The underlying event loop has been closed")]
     EventLoopClosed,
+    #[error("The packet was discarded before the controller reported it as completed")]
+    Discarded,
     #[error("Failed to build packet: {0}")]
     InvalidData(#[from] instructor::Error)
 }
@@ -174,18 +333,81 @@ impl Loggable for AclSendError {
     }
 }
 
+/// Scheduling priority of outgoing ACL data.
+/// Whole PDUs of higher priority overtake queued lower priority PDUs, fragments are never interleaved.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum AclPriority {
+    #[default]
+    Normal,
+    High
+}
+
 #[derive(Clone)]
 pub struct AclSender {
-    sender: MpscSender<Bytes>,
-    max_size: usize
+    sender: MpscSender<AclPdu>,
+    high_priority_sender: MpscSender<AclPdu>,
+    max_size: usize,
+    priority: AclPriority,
+    flushable: bool
 }
 
 impl AclSender {
+    /// Returns a sender that queues its PDUs with the given priority.
+    pub fn with_priority(&self, priority: AclPriority) -> Self {
+        Self { priority, ..self.clone() }
+    }
+
+    pub fn priority(&self) -> AclPriority {
+        self.priority
+    }
+
+    /// Returns a sender whose PDUs the controller may discard after the flush timeout of the connection,
+    /// see [Hci::write_automatic_flush_timeout].
+    pub fn with_flushable(&self, flushable: bool) -> Self {
+        Self { flushable, ..self.clone() }
+    }
+
     pub fn send(&self, handle: u16, pdu: Bytes) -> Result<(), AclSendError> {
+        self.send_with_notifier(handle, pdu, None, self.priority)
+    }
+
+    /// Like [`AclSender::send`], but also returns a future that resolves once the controller
+    /// reported all fragments of the PDU as completed ([Vol 4] Part E, Section 7.7.19).
+    pub fn send_flushed(&self, handle: u16, pdu: Bytes) -> Result<Flushed, AclSendError> {
+        let (tx, rx) = oneshot_channel();
+        self.send_with_notifier(handle, pdu, Some(tx), self.priority)?;
+        Ok(Flushed(rx))
+    }
+
+    /// Queues several PDUs as one unit, so they are sent back to back and the event loop can pack
+    /// their packets into fewer transfers, see [Hci::enable_acl_packing].
+    pub fn send_batch<I: IntoIterator<Item = Bytes>>(&self, handle: u16, pdus: I) -> Result<(), AclSendError> {
+        let mut fragments = AclPdu::new();
+        for pdu in pdus {
+            self.fragment(handle, pdu, None, &mut fragments)?;
+        }
+        self.queue(fragments, self.priority)
+    }
+
+    pub(crate) fn send_with_priority(&self, handle: u16, pdu: Bytes, priority: AclPriority) -> Result<(), AclSendError> {
+        self.send_with_notifier(handle, pdu, None, priority)
+    }
+
+    fn send_with_notifier(&self, handle: u16, pdu: Bytes, notifier: Option<OneshotSender<()>>, priority: AclPriority) -> Result<(), AclSendError> {
+        let mut fragments = AclPdu::new();
+        self.fragment(handle, pdu, notifier, &mut fragments)?;
+        self.queue(fragments, priority)
+    }
+
+    fn fragment(&self, handle: u16, pdu: Bytes, mut notifier: Option<OneshotSender<()>>, fragments: &mut AclPdu) -> Result<(), AclSendError> {
         //trace!("Sending ACL data to handle 0x{:04X}", handle);
         let mut buffer = BytesMut::with_capacity(512);
-        let mut pb = BoundaryFlag::FirstNonAutomaticallyFlushable;
-        for chunk in pdu.chunks(self.max_size) {
+        let mut pb = match self.flushable {
+            true => BoundaryFlag::FirstAutomaticallyFlushable,
+            false => BoundaryFlag::FirstNonAutomaticallyFlushable
+        };
+        let mut chunks = pdu.chunks(self.max_size).peekable();
+        while let Some(chunk) = chunks.next() {
             buffer.write(AclHeader {
                 handle,
                 pb,
@@ -193,13 +415,51 @@ impl AclSender {
                 length: Length::new(chunk.len())?
             });
             buffer.put(chunk);
-            self.sender
-                .send(buffer.split().freeze())
-                .map_err(|_| AclSendError::EventLoopClosed)?;
+            // Packets of a connection complete in order, so only the last fragment needs to be tracked
+            let notifier = chunks.peek().is_none().then(|| notifier.take()).flatten();
+            fragments.push((buffer.split().freeze(), notifier));
             pb = BoundaryFlag::Continuing;
         }
         Ok(())
     }
+
+    fn queue(&self, fragments: AclPdu, priority: AclPriority) -> Result<(), AclSendError> {
+        let sender = match priority {
+            AclPriority::Normal => &self.sender,
+            AclPriority::High => &self.high_priority_sender
+        };
+        sender
+            .send(fragments)
+            .map_err(|_| AclSendError::EventLoopClosed)
+    }
+
+    /// A sender that queues the PDUs of both priorities in the returned receiver instead of an event loop.
+    #[cfg(test)]
+    pub(crate) fn detached(max_size: usize) -> (Self, tokio::sync::mpsc::UnboundedReceiver<AclPdu>) {
+        let (sender, receiver) = unbounded_channel();
+        let acl_sender = AclSender {
+            high_priority_sender: sender.clone(),
+            sender,
+            max_size,
+            priority: AclPriority::Normal,
+            flushable: false
+        };
+        (acl_sender, receiver)
+    }
+}
+
+/// Resolves once the controller has reported an ACL packet as completed.
+#[must_use = "futures do nothing unless polled"]
+pub struct Flushed(OneshotReceiver<()>);
+
+impl Future for Flushed {
+    type Output = Result<(), AclSendError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map_err(|_| AclSendError::Discarded)
+    }
 }
 
 //impl Drop for Hci {
@@ -232,7 +492,9 @@ pub enum Error {
     #[error(transparent)]
     Controller(#[from] Status),
     #[error("Unknown channel id: 0x{0:02X}")]
-    UnknownChannelId(u16)
+    UnknownChannelId(u16),
+    #[error("Not supported: {0}")]
+    NotSupported(&'static str)
 }
 
 impl Error {
@@ -250,9 +512,112 @@ impl From<&'static str> for Error {
     }
 }
 
+type CommandSender = MpscSender<(Opcode, Bytes, CmdResultSender)>;
+
+fn submit_command<T: Exstruct<LittleEndian>>(
+    cmd_out: &CommandSender, cmd: Opcode, packer: impl FnOnce(&mut BytesMut)
+) -> Result<impl Future<Output = Result<T, Error>> + Send + 'static, Error> {
+    // TODO: check if the command is supported
+    let mut buf = BytesMut::with_capacity(255);
+    buf.write::<u16, LittleEndian>(cmd.into());
+    buf.write::<u8, LittleEndian>(0);
+    packer(&mut buf);
+    let payload_len = u8::try_from(buf.len() - 3).map_err(|_| Error::PayloadTooLarge)?;
+    buf[2] = payload_len;
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    cmd_out
+        .send((cmd, buf.freeze(), tx))
+        .map_err(|_| Error::EventLoopClosed)?;
+    Ok(async move {
+        //TODO: 1s timeout
+        let mut resp = rx.await.map_err(|_| Error::EventLoopClosed)??;
+        let status: Status = resp.read_le()?;
+        match status {
+            Status::Success => {
+                let result: T = resp.read_le()?;
+                resp.finish()?;
+                Ok(result)
+            }
+            _ => Err(Error::Controller(status))
+        }
+    })
+}
+
+/// The events the controller has to report: the core events, the events enabled through [Hci::enable_events]
+/// and the events of the registered handlers, which are counted per registration.
+#[derive(Debug)]
+struct EventMaskState {
+    enabled: EventMask,
+    handled: BTreeMap<EventCode, usize>,
+    /// The mask that was sent to the controller last.
+    current: EventMask
+}
+
+impl Default for EventMaskState {
+    fn default() -> Self {
+        Self {
+            enabled: EventMask::core(),
+            handled: BTreeMap::new(),
+            current: EventMask::core()
+        }
+    }
+}
+
+impl EventMaskState {
+    fn required(&self) -> EventMask {
+        self.enabled.union(self.handled.keys().copied().collect())
+    }
+
+    fn acquire(&mut self, events: &BTreeSet<EventCode>) {
+        for &event in events {
+            *self.handled.entry(event).or_default() += 1;
+        }
+    }
+
+    fn release(&mut self, events: &BTreeSet<EventCode>) {
+        for &event in events {
+            if let Entry::Occupied(mut entry) = self.handled.entry(event) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+/// Queues a `HCI_Set_Event_Mask` command if `update` changes the required events. The lock is held while the
+/// command is queued, so the commands are queued in the order of the updates.
+fn update_event_mask(
+    state: &Mutex<EventMaskState>, cmd_out: &CommandSender, update: impl FnOnce(&mut EventMaskState)
+) -> Result<Option<impl Future<Output = Result<(), Error>> + Send + 'static>, Error> {
+    let mut state = state.lock();
+    update(&mut state);
+    let required = state.required();
+    if required == state.current {
+        return Ok(None);
+    }
+    let done = submit_command(cmd_out, Opcode::new(OpcodeGroup::HciControl, 0x0001), move |p| p.write_le(required))?;
+    state.current = required;
+    Ok(Some(done))
+}
+
 pub trait FirmwareLoader: Send + Sync {
     fn try_load_firmware<'a>(&'a self, hci: &'a Hci) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>>;
 
+    /// Changes the public address of the controller through a vendor specific command.
+    /// Returns `false` if this loader does not know how to change the address of the controller.
+    fn set_bd_addr<'a>(&'a self, _hci: &'a Hci, _addr: BdAddr) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    /// Routes the SCO audio of the controller through a vendor specific command.
+    /// Returns `false` if this loader does not know how to route the audio of the controller.
+    fn set_sco_routing<'a>(&'a self, _hci: &'a Hci, _routing: ScoRouting) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + 'a>> {
+        Box::pin(async { Ok(false) })
+    }
+
     fn boxed(self) -> Box<dyn FirmwareLoader> where Self: 'static + Sized {
         Box::new(self)
     }
@@ -265,6 +630,38 @@ impl Hci {
             .unwrap_or_else(|_| panic!("Firmware loaders already registered"));
     }
 
+    /// Overrides the public address of the controller, e.g. to assign each unit its address during manufacturing.
+    /// The address is not persistent, so this has to be done after every start before the controller is used.
+    pub async fn set_bd_addr(&self, addr: BdAddr) -> Result<(), Error> {
+        let loaders = FIRMWARE_LOADERS.get().map_or(&[][..], Vec::as_slice);
+        for loader in loaders {
+            if loader.set_bd_addr(self, addr).await? {
+                let current = self.read_bd_addr().await?;
+                ensure!(current == addr, "Controller did not accept the new address");
+                debug!("Changed BD_ADDR to {}", redacted(&addr));
+                return Ok(());
+            }
+        }
+        Err(Error::Generic("No firmware loader can change the address of this controller"))
+    }
+
+    /// Selects whether SCO audio is exchanged with the host or with a codec wired to the PCM or I2S interface of the
+    /// controller. This has to be done before the first synchronous connection is set up.
+    pub async fn set_sco_routing(&self, routing: ScoRouting) -> Result<(), Error> {
+        let loaders = FIRMWARE_LOADERS.get().map_or(&[][..], Vec::as_slice);
+        for loader in loaders {
+            if loader.set_sco_routing(self, routing).await? {
+                debug!("Changed SCO routing to {:?}", routing);
+                return Ok(());
+            }
+        }
+        match routing {
+            // Nothing to change for controllers without vendor routing
+            ScoRouting::Hci => Ok(()),
+            _ => Err(Error::Generic("No firmware loader can change the SCO routing of this controller"))
+        }
+    }
+
     async fn try_load_firmware(&self) {
         if let Some(loaders) = FIRMWARE_LOADERS.get() {
             for loader in loaders {
@@ -277,3 +674,79 @@ impl Hci {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use instructor::BufferMut;
+    use parking_lot::Mutex;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    use crate::hci::consts::{EventCode, EventMask, LeEventMask};
+    use crate::hci::event_loop::EventLoopCommand;
+    use crate::hci::{AclSender, Hci, Opcode, OpcodeGroup};
+
+    #[tokio::test]
+    async fn handlers_enable_their_events() {
+        let (cmd_out, mut commands) = unbounded_channel();
+        let (acl_out, _acl) = unbounded_channel();
+        let (acl_high_priority_out, _acl_high_priority) = unbounded_channel();
+        let (ctl_out, mut ctl) = unbounded_channel();
+        let hci = Hci {
+            cmd_out,
+            acl_out,
+            acl_high_priority_out,
+            ctl_out,
+            acl_size: 0,
+            host_acl_size: 0,
+            event_loop: Mutex::new(None),
+            event_mask: Default::default(),
+            le_event_mask: AsyncMutex::new(LeEventMask::none()),
+            identity: AsyncMutex::new(None),
+            identity_advertising: Mutex::new(None),
+            version: Default::default()
+        };
+        let (handler, events) = unbounded_channel();
+        hci.register_event_handler([EventCode::AuthenticationComplete], handler.clone()).unwrap();
+        assert!(matches!(ctl.try_recv(), Ok(EventLoopCommand::RegisterHciEventHandler { .. })));
+        let (opcode, command, response) = commands.try_recv().unwrap();
+        assert_eq!(opcode, Opcode::new(OpcodeGroup::HciControl, 0x0001));
+        let expected = EventMask::core().with(EventCode::AuthenticationComplete, true);
+        assert_eq!(command.slice(3..), mask_parameters(expected));
+        response.send(Ok(Bytes::from_static(&[0x00]))).unwrap();
+
+        // Events that are already enabled don't change the mask again
+        hci.register_event_handler([EventCode::AuthenticationComplete, EventCode::ConnectionComplete], handler).unwrap();
+        assert!(commands.try_recv().is_err());
+        assert_eq!(hci.event_mask.lock().current, expected);
+
+        // Once the last handler is gone, its events are disabled again
+        drop(events);
+        let (opcode, command, _response) = commands.recv().await.unwrap();
+        assert_eq!(opcode, Opcode::new(OpcodeGroup::HciControl, 0x0001));
+        assert_eq!(command.slice(3..), mask_parameters(EventMask::core()));
+        assert_eq!(hci.event_mask.lock().current, EventMask::core());
+    }
+
+    fn mask_parameters(mask: EventMask) -> Bytes {
+        let mut parameters = BytesMut::new();
+        parameters.write_le(mask);
+        parameters.freeze()
+    }
+
+    #[test]
+    fn batches_are_queued_together() {
+        let (sender, mut pdus) = AclSender::detached(8);
+        sender
+            .send_batch(0x0001, [Bytes::from_static(&[1; 12]), Bytes::from_static(&[2; 4])])
+            .unwrap();
+        let pdu = pdus.try_recv().unwrap();
+        assert!(pdus.try_recv().is_err());
+        // Fragmented at the ACL size, only the first fragment of each SDU starts a new L2CAP PDU
+        let lengths: Vec<usize> = pdu.iter().map(|(data, _)| data.len() - 4).collect();
+        assert_eq!(lengths, [8, 4, 4]);
+        let boundaries: Vec<u8> = pdu.iter().map(|(data, _)| data[1] >> 4).collect();
+        assert_eq!(boundaries, [0b00, 0b01, 0b00]);
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use instructor::{Buffer, BufferMut, Exstruct, Instruct};
+use parking_lot::Mutex;
+
+#[cfg(feature = "avdtp")]
+use crate::avdtp::capabilities::AudioCodec;
+use crate::hci::consts::{BdAddr, CompanyId, CoreVersion};
+use crate::hci::Error;
+use crate::sdp::Uuid;
+use crate::storage::{device_key, migrate_file, parent_dir, parse_device_key, persist, run_blocking, FileStorage, Storage};
+
+/// The namespace of the [RemoteDeviceInfo] in the [Storage], one entry per bonded device.
+pub const REMOTE_INFO_NAMESPACE: &str = "remote-info";
+
+/// `HCI_Read_Remote_Version_Information_Complete` event parameters
+/// ([Vol 4] Part E, Section 7.7.12).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[instructor(endian = "little")]
+pub struct RemoteVersion {
+    pub version: CoreVersion,
+    pub company_id: CompanyId,
+    pub subversion: u16
+}
+
+/// The LMP features page 0 of a remote controller ([Vol 2] Part C, Section 3.3).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Exstruct, Instruct)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[instructor(endian = "little")]
+pub struct LmpFeatures(pub u64);
+
+impl LmpFeatures {
+    pub const ENCRYPTION: u8 = 2;
+    pub const ROLE_SWITCH: u8 = 5;
+    pub const SNIFF_MODE: u8 = 7;
+    pub const EDR_ACL_2MBPS: u8 = 25;
+    pub const EDR_ACL_3MBPS: u8 = 26;
+    pub const SECURE_SIMPLE_PAIRING: u8 = 51;
+    pub const EXTENDED_FEATURES: u8 = 63;
+
+    pub fn supports(self, bit: u8) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+}
+
+/// Information extracted from a service record of the remote device.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteProfile {
+    pub version: Option<u16>,
+    pub supported_features: Option<u16>,
+    /// The PSM of an additional protocol (e.g. AVRCP browsing).
+    pub additional_psm: Option<u16>
+}
+
+/// User preferences for a remote device that are restored when it reconnects.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceSettings {
+    /// The last absolute volume (`0..=0x7F`) ([AVRCP] Section 6.13.1).
+    pub absolute_volume: Option<u8>,
+    #[cfg(feature = "avdtp")]
+    pub preferred_codec: Option<AudioCodec>,
+    /// Whether the device may connect without confirmation.
+    pub trusted: bool
+}
+
+impl DeviceSettings {
+    const MAX_VOLUME: u8 = 0x7F;
+
+    /// The last volume in the range `0.0..=1.0`.
+    pub fn volume(&self) -> Option<f32> {
+        self.absolute_volume
+            .map(|volume| volume.min(Self::MAX_VOLUME) as f32 / Self::MAX_VOLUME as f32)
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.absolute_volume = Some((volume.clamp(0.0, 1.0) * Self::MAX_VOLUME as f32).round() as u8);
+    }
+}
+
+/// Everything we learned about a remote device that is unlikely to change between connections.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteDeviceInfo {
+    pub version: Option<RemoteVersion>,
+    pub lmp_features: Option<LmpFeatures>,
+    /// Keyed by the service class of the record.
+    pub profiles: BTreeMap<Uuid, RemoteProfile>,
+    pub settings: DeviceSettings
+}
+
+impl RemoteDeviceInfo {
+    pub fn has_controller_info(&self) -> bool {
+        self.version.is_some() && self.lmp_features.is_some()
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    devices: BTreeMap<BdAddr, RemoteDeviceInfo>,
+    bonded: Vec<BdAddr>
+}
+
+/// Caches [RemoteDeviceInfo] per device and persists the entries of bonded devices,
+/// so reconnecting devices don't have to be queried again.
+#[derive(Clone, Default)]
+pub struct RemoteInfoCache {
+    state: Arc<Mutex<CacheState>>,
+    storage: Option<Storage>
+}
+
+impl RemoteInfoCache {
+    /// Creates a cache that forgets everything when the program exits.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Loads the cache from a [FileStorage] in the directory of `path` after moving the entries of the file at `path`,
+    /// which earlier versions used, into it.
+    #[deprecated(note = "use load with a Storage")]
+    pub async fn load_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let storage = Storage::new(FileStorage::new(parent_dir(path.as_ref())));
+        Self::migrate_file(&storage, path).await?;
+        Self::load(storage).await
+    }
+
+    /// Moves the entries of the single file that earlier versions kept the cache in into `storage` and deletes the file.
+    /// Does nothing if there is no such file.
+    pub async fn migrate_file<P: AsRef<Path>>(storage: &Storage, path: P) -> Result<(), Error> {
+        migrate_file::<Error, _>(storage, REMOTE_INFO_NAMESPACE, path.as_ref(), |mut data| {
+            let mut entries = Vec::new();
+            while !data.is_empty() {
+                let addr: BdAddr = data.read_le()?;
+                let mut entry = BytesMut::new();
+                write_device_info(&mut entry, &read_device_info(&mut data)?);
+                entries.push((addr, entry.to_vec()));
+            }
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// Loads the cache from `storage` and writes every change back to it.
+    pub async fn load(storage: Storage) -> Result<Self, Error> {
+        let entries = run_blocking(&storage, |storage| {
+            let mut result = Vec::new();
+            for key in storage.keys(REMOTE_INFO_NAMESPACE)? {
+                if let (Some(addr), Some(data)) = (parse_device_key(&key), storage.read(REMOTE_INFO_NAMESPACE, &key)?) {
+                    result.push((addr, data));
+                }
+            }
+            Ok(result)
+        })
+        .await?;
+        let mut state = CacheState::default();
+        for (addr, data) in entries {
+            let info = read_device_info(&mut data.as_slice())?;
+            state.devices.insert(addr, info);
+            state.bonded.push(addr);
+        }
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+            storage: Some(storage)
+        })
+    }
+
+    pub fn get(&self, addr: BdAddr) -> Option<RemoteDeviceInfo> {
+        self.state.lock().devices.get(&addr).cloned()
+    }
+
+    pub fn update<F: FnOnce(&mut RemoteDeviceInfo)>(&self, addr: BdAddr, func: F) {
+        let bonded = {
+            let mut state = self.state.lock();
+            func(state.devices.entry(addr).or_default());
+            state.bonded.contains(&addr)
+        };
+        if bonded {
+            self.save(addr);
+        }
+    }
+
+    /// The settings of the device, the defaults if there are none yet.
+    pub fn settings(&self, addr: BdAddr) -> DeviceSettings {
+        self.state
+            .lock()
+            .devices
+            .get(&addr)
+            .map(|info| info.settings)
+            .unwrap_or_default()
+    }
+
+    /// Changes the settings of the device. Like all other information, they are only persisted for bonded devices.
+    pub fn update_settings<F: FnOnce(&mut DeviceSettings)>(&self, addr: BdAddr, func: F) {
+        self.update(addr, |info| func(&mut info.settings));
+    }
+
+    /// Marks the device as bonded, which causes its information to be persisted.
+    pub fn set_bonded(&self, addr: BdAddr) {
+        let changed = {
+            let mut state = self.state.lock();
+            let changed = !state.bonded.contains(&addr);
+            if changed {
+                state.bonded.push(addr);
+            }
+            changed && state.devices.contains_key(&addr)
+        };
+        if changed {
+            self.save(addr);
+        }
+    }
+
+    /// The devices that were marked as bonded, the ones loaded from the storage first.
+    pub fn bonded(&self) -> Vec<BdAddr> {
+        self.state.lock().bonded.clone()
+    }
+
+    pub fn remove(&self, addr: BdAddr) {
+        let mut state = self.state.lock();
+        state.devices.remove(&addr);
+        let persisted = state.bonded.contains(&addr);
+        state.bonded.retain(|bonded| *bonded != addr);
+        if let (true, Some(storage)) = (persisted, &self.storage) {
+            persist(storage, REMOTE_INFO_NAMESPACE, device_key(addr), None);
+        }
+    }
+
+    /// Queues the write while holding the lock, so concurrent saves of a device are written in the order of
+    /// their snapshots and the entry ends up with the latest one.
+    fn save(&self, addr: BdAddr) {
+        let Some(storage) = &self.storage else { return };
+        let state = self.state.lock();
+        if let Some(info) = state.devices.get(&addr) {
+            let mut data = BytesMut::new();
+            write_device_info(&mut data, info);
+            persist(storage, REMOTE_INFO_NAMESPACE, device_key(addr), Some(data.to_vec()));
+        }
+    }
+}
+
+const HAS_VERSION: u8 = 1 << 0;
+const HAS_FEATURES: u8 = 1 << 1;
+const HAS_PSM: u8 = 1 << 2;
+// Appended after the profiles, so files written before settings existed can still be read
+const HAS_SETTINGS: u8 = 1 << 3;
+// Followed by the format version, entries without it are version 1
+const HAS_FORMAT_VERSION: u8 = 1 << 7;
+
+/// The layout of the entries, later changes bump it instead of adding flags.
+const FORMAT_VERSION: u8 = 2;
+
+const SETTING_VOLUME: u8 = 1 << 0;
+const SETTING_CODEC: u8 = 1 << 1;
+const SETTING_TRUSTED: u8 = 1 << 2;
+
+fn write_device_info(buffer: &mut BytesMut, info: &RemoteDeviceInfo) {
+    buffer.write_le(info.version.map_or(0, |_| HAS_VERSION) | info.lmp_features.map_or(0, |_| HAS_FEATURES) | HAS_SETTINGS | HAS_FORMAT_VERSION);
+    buffer.write_le(FORMAT_VERSION);
+    if let Some(version) = info.version {
+        buffer.write_le(version);
+    }
+    if let Some(features) = info.lmp_features {
+        buffer.write_le(features);
+    }
+    buffer.write_le(info.profiles.len() as u8);
+    for (uuid, profile) in info.profiles.iter().take(u8::MAX as usize) {
+        buffer.write_le(uuid.as_u128());
+        buffer.write_le(
+            profile.version.map_or(0, |_| HAS_VERSION)
+                | profile.supported_features.map_or(0, |_| HAS_FEATURES)
+                | profile.additional_psm.map_or(0, |_| HAS_PSM)
+        );
+        buffer.write_le(profile.version.unwrap_or_default());
+        buffer.write_le(profile.supported_features.unwrap_or_default());
+        buffer.write_le(profile.additional_psm.unwrap_or_default());
+    }
+    let settings = &info.settings;
+    // The codec is kept in the format, so builds without AVDTP read the same entries
+    #[cfg(feature = "avdtp")]
+    let codec = settings.preferred_codec.map(|codec| codec as u8);
+    #[cfg(not(feature = "avdtp"))]
+    let codec: Option<u8> = None;
+    buffer.write_le(
+        settings.absolute_volume.map_or(0, |_| SETTING_VOLUME)
+            | codec.map_or(0, |_| SETTING_CODEC)
+            | if settings.trusted { SETTING_TRUSTED } else { 0 }
+    );
+    buffer.write_le(settings.absolute_volume.unwrap_or_default());
+    buffer.write_le(codec.unwrap_or_default());
+}
+
+fn read_device_info(data: &mut &[u8]) -> Result<RemoteDeviceInfo, instructor::Error> {
+    let flags: u8 = data.read_le()?;
+    let version: u8 = match flags & HAS_FORMAT_VERSION != 0 {
+        true => data.read_le()?,
+        false => 1
+    };
+    // Written by a newer version of the stack
+    if version > FORMAT_VERSION {
+        return Err(instructor::Error::InvalidValue);
+    }
+    let mut info = RemoteDeviceInfo::default();
+    if flags & HAS_VERSION != 0 {
+        info.version = Some(data.read_le()?);
+    }
+    if flags & HAS_FEATURES != 0 {
+        info.lmp_features = Some(data.read_le()?);
+    }
+    let count: u8 = data.read_le()?;
+    for _ in 0..count {
+        let uuid = Uuid::from_u128(data.read_le()?);
+        let flags: u8 = data.read_le()?;
+        let version: u16 = data.read_le()?;
+        let supported_features: u16 = data.read_le()?;
+        let additional_psm: u16 = data.read_le()?;
+        info.profiles.insert(uuid, RemoteProfile {
+            version: (flags & HAS_VERSION != 0).then_some(version),
+            supported_features: (flags & HAS_FEATURES != 0).then_some(supported_features),
+            additional_psm: (flags & HAS_PSM != 0).then_some(additional_psm)
+        });
+    }
+    if flags & HAS_SETTINGS != 0 {
+        let settings: u8 = data.read_le()?;
+        let volume: u8 = data.read_le()?;
+        #[cfg(feature = "avdtp")]
+        let codec: AudioCodec = data.read_le()?;
+        #[cfg(not(feature = "avdtp"))]
+        let _codec: u8 = data.read_le()?;
+        info.settings = DeviceSettings {
+            absolute_volume: (settings & SETTING_VOLUME != 0).then_some(volume),
+            #[cfg(feature = "avdtp")]
+            preferred_codec: (settings & SETTING_CODEC != 0).then_some(codec),
+            trusted: settings & SETTING_TRUSTED != 0
+        };
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use instructor::BufferMut;
+
+    #[cfg(feature = "avdtp")]
+    use crate::avdtp::capabilities::AudioCodec;
+    use crate::hci::consts::BdAddr;
+    use crate::hci::remote_info::{read_device_info, write_device_info, DeviceSettings, RemoteDeviceInfo, RemoteInfoCache};
+    use crate::storage::{MemoryStorage, Storage};
+
+    #[test]
+    fn settings_roundtrip() {
+        let info = RemoteDeviceInfo {
+            settings: DeviceSettings {
+                absolute_volume: Some(0x40),
+                #[cfg(feature = "avdtp")]
+                preferred_codec: Some(AudioCodec::Mpeg24Acc),
+                trusted: true
+            },
+            ..Default::default()
+        };
+        let mut buffer = BytesMut::new();
+        write_device_info(&mut buffer, &info);
+        assert_eq!(read_device_info(&mut buffer.as_ref()).unwrap(), info);
+
+        // Written before settings existed: no flags and no profiles
+        let old: &[u8] = &[0x00, 0x00];
+        assert_eq!(read_device_info(&mut &old[..]).unwrap().settings, DeviceSettings::default());
+
+        // Written by a newer version
+        let mut newer = buffer.to_vec();
+        newer[1] += 1;
+        assert!(read_device_info(&mut newer.as_slice()).is_err());
+    }
+
+    #[tokio::test]
+    async fn migrate_file() {
+        let addr = BdAddr::new([1, 2, 3, 4, 5, 6]);
+        let info = RemoteDeviceInfo {
+            settings: DeviceSettings {
+                trusted: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut file = BytesMut::new();
+        file.write_le_ref(&addr);
+        write_device_info(&mut file, &info);
+        let path = std::env::temp_dir().join(format!("bluefang-remote-info-{}.dat", std::process::id()));
+        std::fs::write(&path, &file).unwrap();
+
+        let storage = Storage::new(MemoryStorage::new());
+        RemoteInfoCache::migrate_file(&storage, &path).await.unwrap();
+        assert!(!path.exists());
+        let cache = RemoteInfoCache::load(storage.clone()).await.unwrap();
+        assert_eq!(cache.bonded(), [addr]);
+        assert_eq!(cache.get(addr), Some(info));
+        // Nothing left to migrate
+        RemoteInfoCache::migrate_file(&storage, &path).await.unwrap();
+    }
+}
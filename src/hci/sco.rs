@@ -0,0 +1,35 @@
+/// Where the controller sends the audio of SCO and eSCO connections, see [Hci::set_sco_routing](crate::hci::Hci::set_sco_routing).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum ScoRouting {
+    /// As HCI synchronous data packets, the default of every controller.
+    #[default]
+    Hci,
+    /// Over the PCM interface of the controller, e.g. to a codec on the same board.
+    Pcm(PcmConfig),
+    /// Over the I2S interface of the controller.
+    I2s(PcmConfig)
+}
+
+/// The bit clock of the PCM or I2S interface.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PcmClockRate {
+    Khz128 = 0x00,
+    #[default]
+    Khz256 = 0x01,
+    Khz512 = 0x02,
+    Khz1024 = 0x03,
+    Khz2048 = 0x04
+}
+
+/// The wiring of the PCM or I2S interface.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PcmConfig {
+    pub clock_rate: PcmClockRate,
+    /// Whether the controller drives the bit clock and the frame sync, otherwise the codec does.
+    pub master: bool,
+    /// A frame sync that lasts the whole first sample instead of a single bit clock.
+    pub long_frame_sync: bool,
+    /// 16 kHz samples for wideband speech (mSBC) instead of 8 kHz.
+    pub wideband: bool
+}
@@ -0,0 +1,124 @@
+//! A quick health check of the controller, meant to catch broken dongles or cabling when a product boots.
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::hci::consts::Status;
+use crate::hci::{BufferSizes, Error, Hci, LocalVersion, LoopbackMode};
+use crate::utils::clock::{now, timeout};
+
+/// How many commands are timed for the latency measurement.
+const LATENCY_SAMPLES: usize = 8;
+/// Commands that take longer than this point to a congested or flaky connection to the controller.
+const SLOW_COMMAND: Duration = Duration::from_millis(250);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The round-trip times of commands that completed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CommandLatency {
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    /// Commands that were not answered within the timeout.
+    pub timeouts: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub version: LocalVersion,
+    pub buffer_sizes: BufferSizes,
+    pub command_latency: CommandLatency,
+    /// Whether the controller entered and left local loopback mode, `None` if it doesn't support it.
+    pub loopback: Option<bool>,
+    /// Everything that looked wrong, empty for a healthy controller.
+    pub issues: Vec<String>
+}
+
+impl SelfTestReport {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl Hci {
+    /// Checks that the controller answers commands in time, reports sane buffer sizes and can switch loopback modes.
+    /// Entering loopback mode creates a loopback connection that is reported through the regular connection events,
+    /// so this should run before the other subsystems are set up. Only fails if the controller stops responding.
+    pub async fn self_test(&self) -> Result<SelfTestReport, Error> {
+        let mut issues = Vec::new();
+
+        let (version, command_latency) = self.measure_command_latency().await?;
+        if command_latency.timeouts > 0 {
+            issues.push(format!("{} of {} commands timed out", command_latency.timeouts, LATENCY_SAMPLES));
+        }
+        if command_latency.max > SLOW_COMMAND {
+            issues.push(format!("Slow command round trip of {:?}", command_latency.max));
+        }
+
+        let buffer_sizes = self.read_buffer_size().await?;
+        if buffer_sizes.acl_data_packet_length == 0 || buffer_sizes.total_num_acl_data_packets == 0 {
+            issues.push(format!("Controller reports no ACL buffers: {:?}", buffer_sizes));
+        }
+        if buffer_sizes.acl_data_packet_length as usize != self.acl_size {
+            issues.push(format!(
+                "ACL packet length changed from {} to {} since startup",
+                self.acl_size, buffer_sizes.acl_data_packet_length
+            ));
+        }
+
+        let loopback = match self.check_loopback().await {
+            Ok(true) => Some(true),
+            Ok(false) => {
+                issues.push(String::from("Controller did not report the selected loopback mode"));
+                Some(false)
+            }
+            Err(Error::Controller(Status::UnknownCommand)) => None,
+            Err(err) => {
+                issues.push(format!("Loopback mode failed: {}", err));
+                Some(false)
+            }
+        };
+
+        for issue in &issues {
+            warn!("Self test: {}", issue);
+        }
+        debug!("Self test finished with {} issue(s), command latency {:?}", issues.len(), command_latency);
+        Ok(SelfTestReport {
+            version,
+            buffer_sizes,
+            command_latency,
+            loopback,
+            issues
+        })
+    }
+
+    async fn measure_command_latency(&self) -> Result<(LocalVersion, CommandLatency), Error> {
+        let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+        let mut latency = CommandLatency::default();
+        let mut version = None;
+        for _ in 0..LATENCY_SAMPLES {
+            let start = now();
+            match timeout(COMMAND_TIMEOUT, self.read_local_version()).await {
+                Ok(result) => {
+                    version = Some(result?);
+                    samples.push(now() - start);
+                }
+                Err(_) => latency.timeouts += 1
+            }
+        }
+        let version = version.ok_or(Error::Generic("Controller does not answer commands"))?;
+        latency.min = samples.iter().copied().min().unwrap_or_default();
+        latency.max = samples.iter().copied().max().unwrap_or_default();
+        latency.mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+        Ok((version, latency))
+    }
+
+    /// Enters local loopback mode and leaves it again, checking that the controller reports both modes.
+    async fn check_loopback(&self) -> Result<bool, Error> {
+        self.write_loopback_mode(LoopbackMode::Local).await?;
+        let entered = self.read_loopback_mode().await;
+        self.write_loopback_mode(LoopbackMode::Disabled).await?;
+        let left = self.read_loopback_mode().await?;
+        Ok(entered? == LoopbackMode::Local && left == LoopbackMode::Disabled)
+    }
+}
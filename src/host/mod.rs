@@ -1 +1,48 @@
 pub mod usb;
+#[cfg(target_os = "linux")]
+pub mod user_channel;
+
+use crate::host::usb::UsbHost;
+#[cfg(target_os = "linux")]
+use crate::host::user_channel::UserChannel;
+
+/// The connection to the controller, see [Hci::new](crate::hci::Hci::new).
+pub enum Transport {
+    /// A USB device that was detached from its kernel driver.
+    Usb(UsbHost),
+    /// A controller that stays attached to the kernel driver, which is handy if the driver is built in.
+    #[cfg(target_os = "linux")]
+    UserChannel(UserChannel)
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Usb(_) => "USB",
+            #[cfg(target_os = "linux")]
+            Transport::UserChannel(_) => "HCI user channel"
+        }
+    }
+
+    /// The largest ACL packet the host accepts from the controller.
+    pub fn max_acl_packet_length(&self) -> u16 {
+        match self {
+            Transport::Usb(host) => host.max_acl_packet_length(),
+            #[cfg(target_os = "linux")]
+            Transport::UserChannel(channel) => channel.max_acl_packet_length()
+        }
+    }
+}
+
+impl From<UsbHost> for Transport {
+    fn from(value: UsbHost) -> Self {
+        Transport::Usb(value)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<UserChannel> for Transport {
+    fn from(value: UserChannel) -> Self {
+        Transport::UserChannel(value)
+    }
+}
@@ -1,7 +1,9 @@
 use nusb::descriptors::InterfaceAltSetting;
 use nusb::transfer::Direction::{In, Out};
-use nusb::transfer::EndpointType::{Bulk, Interrupt};
+use nusb::transfer::EndpointType::{Bulk, Interrupt, Isochronous};
 use nusb::{Device, DeviceInfo, Error, Interface};
+use std::io::ErrorKind;
+use std::mem::size_of;
 use tracing::{debug, warn};
 
 use crate::ensure;
@@ -32,15 +34,56 @@ impl UsbController {
         Ok(UsbHost {
             device: self.device,
             endpoints: self.endpoints,
-            interface
+            interface,
+            transfers: TransferConfig::default()
         })
     }
 }
 
+/// The smallest transfer buffer, which holds a full event packet ([Vol 4] Part E, Section 5.4.4).
+const MIN_TRANSFER_BUFFER_SIZE: usize = 260;
+
+/// The size and number of the transfers that are kept queued to receive events and ACL data.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TransferConfig {
+    /// The size of each transfer buffer, which also limits the ACL packets the host accepts.
+    pub buffer_size: usize,
+    /// How many transfers are submitted in advance per endpoint.
+    pub queue_depth: usize
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 4096,
+            queue_depth: 4
+        }
+    }
+}
+
 pub struct UsbHost {
     pub device: Device,
     pub endpoints: Endpoints,
-    pub interface: Interface
+    pub interface: Interface,
+    pub transfers: TransferConfig
+}
+
+impl UsbHost {
+    /// Larger and more transfers allow higher throughput, e.g. for controllers with large ACL buffers.
+    /// Fails with [ErrorKind::InvalidInput] if a buffer can't hold a full event packet or no transfers are queued.
+    pub fn with_transfer_config(mut self, transfers: TransferConfig) -> Result<Self, Error> {
+        ensure!(
+            transfers.buffer_size >= MIN_TRANSFER_BUFFER_SIZE && transfers.queue_depth > 0,
+            Error::new(ErrorKind::InvalidInput, format!("Invalid transfer config: {:?}", transfers))
+        );
+        self.transfers = transfers;
+        Ok(self)
+    }
+
+    /// The largest ACL packet that fits into a transfer buffer.
+    pub fn max_acl_packet_length(&self) -> u16 {
+        (self.transfers.buffer_size - size_of::<u32>()).min(u16::MAX as usize) as u16
+    }
 }
 
 /// USB addresses for Bluetooth interfaces and endpoints ([Vol 4] Part B, Section 2.1.1).
@@ -49,7 +92,17 @@ pub struct Endpoints {
     pub main_iface: u8,
     pub event: u8,
     pub acl_out: u8,
-    pub acl_in: u8
+    pub acl_in: u8,
+    /// The isochronous interface for SCO data, if the controller has one.
+    //TODO Transfer SCO data over it, this needs isochronous transfers which nusb does not support yet
+    pub sco: Option<ScoInterface>
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScoInterface {
+    pub iface: u8,
+    /// The alternate settings the interface offers.
+    pub alt_settings: Vec<u8>
 }
 
 impl Endpoints {
@@ -66,7 +119,8 @@ impl Endpoints {
                     main_iface: ifas.interface_number(),
                     event: 0,
                     acl_out: 0,
-                    acl_in: 0
+                    acl_in: 0,
+                    sco: None
                 };
                 for epd in ifas.endpoints() {
                     match (epd.transfer_type(), epd.direction()) {
@@ -82,6 +136,10 @@ impl Endpoints {
                 Some(r)
             })
             .next()
+            .map(|endpoints| Endpoints {
+                sco: ScoInterface::discover(dev),
+                ..endpoints
+            })
     }
 
     fn is_bluetooth(ifas: &InterfaceAltSetting) -> bool {
@@ -89,3 +147,24 @@ impl Endpoints {
         ifas.class() == 0xE0 && ifas.subclass() == 0x01 && ifas.protocol() == 0x01
     }
 }
+
+impl ScoInterface {
+    /// The Bluetooth interface with isochronous endpoints, usually interface 1.
+    fn discover(dev: &Device) -> Option<Self> {
+        dev.active_configuration()
+            .ok()?
+            .interfaces()
+            .find_map(|ifg| {
+                let alt_settings: Vec<InterfaceAltSetting> = ifg.alt_settings().collect();
+                ensure!(alt_settings.iter().any(|ifas| Endpoints::is_bluetooth(ifas)
+                    && ifas.endpoints().any(|epd| epd.transfer_type() == Isochronous)));
+                Some(ScoInterface {
+                    iface: ifg.interface_number(),
+                    alt_settings: alt_settings
+                        .iter()
+                        .map(|ifas| ifas.alternate_setting())
+                        .collect()
+                })
+            })
+    }
+}
@@ -0,0 +1,114 @@
+//! Access to a controller through the HCI user channel of the Linux kernel, which hands the controller over
+//! to a single process while the kernel driver stays attached to the device.
+use std::ffi::c_int;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::task::{ready, Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use libc::{bind, sockaddr, socket, socklen_t, AF_BLUETOOTH, EBUSY, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_RAW};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tracing::debug;
+
+const BTPROTO_HCI: c_int = 1;
+const HCI_CHANNEL_USER: u16 = 1;
+
+/// Packet indicators of the UART transport, which the kernel uses to frame the packets ([Vol 4] Part A, Section 2).
+pub const HCI_COMMAND_PKT: u8 = 0x01;
+pub const HCI_ACLDATA_PKT: u8 = 0x02;
+pub const HCI_EVENT_PKT: u8 = 0x04;
+
+/// The kernel reads whole packets, so this only bounds the memory held per packet.
+const MAX_ACL_PACKET_LENGTH: u16 = 4092;
+/// The packet indicator and the ACL header in front of the largest ACL packet, events are always smaller.
+const RECEIVE_BUFFER_SIZE: usize = 1 + 4 + MAX_ACL_PACKET_LENGTH as usize;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: u16,
+    hci_dev: u16,
+    hci_channel: u16
+}
+
+/// An exclusive HCI socket to a controller known to the kernel, e.g. `hci0`.
+pub struct UserChannel {
+    socket: AsyncFd<File>,
+    buffer: BytesMut
+}
+
+impl UserChannel {
+    /// Takes over the controller with the index `dev_id` (`0` for `hci0`).
+    /// The controller has to be down (`hciconfig hci0 down`) and not be managed by bluetoothd,
+    /// opening the channel requires the `CAP_NET_ADMIN` capability.
+    pub fn open(dev_id: u16) -> Result<Self> {
+        // SAFETY: plain system calls, the returned descriptor is owned by `fd` right away
+        let fd = unsafe {
+            let fd = socket(AF_BLUETOOTH, SOCK_RAW | SOCK_NONBLOCK | SOCK_CLOEXEC, BTPROTO_HCI);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        let addr = SockaddrHci {
+            hci_family: AF_BLUETOOTH as u16,
+            hci_dev: dev_id,
+            hci_channel: HCI_CHANNEL_USER
+        };
+        // SAFETY: `addr` outlives the call and has the layout of `struct sockaddr_hci`
+        let result = unsafe { bind(fd.as_raw_fd(), &addr as *const SockaddrHci as *const sockaddr, size_of::<SockaddrHci>() as socklen_t) };
+        if result < 0 {
+            let err = Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(EBUSY) => Error::new(ErrorKind::Other, "The controller is still up or in use by another process"),
+                _ => err
+            });
+        }
+        debug!("Opened HCI user channel for hci{}", dev_id);
+        Ok(Self {
+            socket: AsyncFd::new(File::from(fd))?,
+            buffer: BytesMut::new()
+        })
+    }
+
+    /// The largest ACL packet the host accepts from the controller.
+    pub fn max_acl_packet_length(&self) -> u16 {
+        MAX_ACL_PACKET_LENGTH
+    }
+
+    /// Receives the next packet together with its packet indicator.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<(u8, Bytes)>> {
+        loop {
+            let mut guard = ready!(self.socket.poll_read_ready(cx))?;
+            self.buffer.resize(RECEIVE_BUFFER_SIZE, 0);
+            let buffer = &mut self.buffer;
+            match guard.try_io(|socket| socket.get_ref().read(buffer)) {
+                Ok(Ok(0)) => return Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+                Ok(Ok(len)) => {
+                    self.buffer.truncate(len);
+                    let mut packet = self.buffer.split().freeze();
+                    let indicator = packet.split_to(1)[0];
+                    return Poll::Ready(Ok((indicator, packet)));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue
+            }
+        }
+    }
+
+    /// Sends `data` prefixed by the packet indicator.
+    pub async fn send(&self, indicator: u8, data: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(1 + data.len());
+        packet.push(indicator);
+        packet.extend_from_slice(data);
+        self.socket
+            .async_io(Interest::WRITABLE, |mut socket| socket.write(&packet))
+            .await
+            .and_then(|written| match written == packet.len() {
+                true => Ok(()),
+                false => Err(ErrorKind::WriteZero.into())
+            })
+    }
+}
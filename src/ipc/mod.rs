@@ -0,0 +1,520 @@
+//! A JSON-RPC 2.0 facade that mirrors the adapter, device, media control and volume objects of BlueZ,
+//! so tools written against BlueZ can drive a bluefang based stack with little changes.
+//!
+//! Requests and responses are newline delimited JSON objects. Method names use the BlueZ interface
+//! as prefix (e.g. `Adapter.SetDiscoverable`, `MediaControl.Play`) and take named parameters.
+//! With the `pts` feature, the `Pts` methods drive the [PtsHooks](crate::pts::PtsHooks) for qualification testing.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[cfg(feature = "pts")]
+use instructor::Buffer;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::{debug, trace, warn};
+
+use crate::avc::PassThroughOp;
+#[cfg(feature = "pts")]
+use crate::avdtp::SignalIdentifier;
+use crate::avrcp::AvrcpController;
+use crate::hci::connection::{ConnectionEvent, ConnectionEventReceiver};
+use crate::hci::consts::{BdAddr, Lap, Status};
+use crate::hci::remote_info::RemoteInfoCache;
+use crate::hci::{Error, Hci};
+#[cfg(feature = "pts")]
+use crate::pts::PtsHooks;
+use crate::utils::spawn_supervised;
+
+/// The AVRCP sessions that can be controlled over IPC, keyed by the address of the peer.
+/// Register the session in the AVRCP session handler using [MediaPlayers::register].
+#[derive(Clone, Default)]
+pub struct MediaPlayers(Arc<Mutex<BTreeMap<BdAddr, AvrcpController>>>);
+
+impl MediaPlayers {
+    pub fn register(&self, controller: AvrcpController) {
+        self.0.lock().insert(controller.remote_addr(), controller);
+    }
+
+    pub fn get(&self, addr: BdAddr) -> Option<AvrcpController> {
+        let mut players = self.0.lock();
+        players.retain(|_, controller| !controller.is_closed());
+        players.get(&addr).cloned()
+    }
+
+    pub fn addresses(&self) -> Vec<BdAddr> {
+        let mut players = self.0.lock();
+        players.retain(|_, controller| !controller.is_closed());
+        players.keys().copied().collect()
+    }
+}
+
+/// The properties of the `Adapter` object.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AdapterProperties {
+    address: Option<BdAddr>,
+    name: Option<String>,
+    discoverable: Option<bool>
+}
+
+/// The properties of a `Device` object.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceProperties {
+    address: BdAddr,
+    name: Option<String>,
+    connected: bool,
+    #[serde(skip)]
+    handle: Option<u16>
+}
+
+impl DeviceProperties {
+    fn new(address: BdAddr) -> Self {
+        Self {
+            address,
+            name: None,
+            connected: false,
+            handle: None
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    adapter: AdapterProperties,
+    devices: BTreeMap<BdAddr, DeviceProperties>
+}
+
+/// Serves the JSON-RPC interface, see the [module documentation](self).
+#[derive(Clone)]
+pub struct IpcServer {
+    hci: Arc<Hci>,
+    players: MediaPlayers,
+    remote_info: Option<RemoteInfoCache>,
+    state: Arc<Mutex<State>>,
+    #[cfg(feature = "pts")]
+    pts: PtsHooks
+}
+
+impl IpcServer {
+    /// Starts tracking the connected devices.
+    pub fn new(hci: Arc<Hci>) -> Result<Self, Error> {
+        let server = Self {
+            hci,
+            players: MediaPlayers::default(),
+            remote_info: None,
+            state: Arc::new(Mutex::new(State::default())),
+            #[cfg(feature = "pts")]
+            pts: PtsHooks::default()
+        };
+        let events = ConnectionEventReceiver::new(&server.hci)?;
+        spawn_supervised("ipc-devices", "IPC", track_devices(server.state.clone(), events));
+        Ok(server)
+    }
+
+    pub fn with_media_players(mut self, players: MediaPlayers) -> Self {
+        self.players = players;
+        self
+    }
+
+    /// Includes the cached information about a device in `Device.GetProperties`.
+    pub fn with_remote_info_cache(mut self, cache: RemoteInfoCache) -> Self {
+        self.remote_info = Some(cache);
+        self
+    }
+
+    /// Exposes `hooks` as the `Pts` methods, register the same hooks with the profiles under test.
+    #[cfg(feature = "pts")]
+    pub fn with_pts_hooks(mut self, hooks: PtsHooks) -> Self {
+        self.pts = hooks;
+        self
+    }
+
+    pub async fn listen_tcp<A: ToSocketAddrs>(self, addr: A) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("New IPC client: {}", peer);
+            spawn_supervised("ipc-client", peer, self.clone().serve(stream));
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn listen_unix<P: AsRef<std::path::Path>>(self, path: P) -> std::io::Result<()> {
+        let listener = tokio::net::UnixListener::bind(path)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            debug!("New IPC client");
+            spawn_supervised("ipc-client", "unix socket", self.clone().serve(stream));
+        }
+    }
+
+    /// Answers the requests of a single client until it disconnects.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(self, stream: S) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Failed to read IPC request: {:?}", err);
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = serde_json::to_vec(&self.process(&line).await).expect("Failed to serialize response");
+            response.push(b'\n');
+            if let Err(err) = writer.write_all(&response).await {
+                warn!("Failed to write IPC response: {:?}", err);
+                break;
+            }
+        }
+        trace!("IPC client disconnected");
+    }
+
+    async fn process(&self, line: &str) -> Value {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => return RpcError::new(PARSE_ERROR, err).into_response(Value::Null)
+        };
+        // The parameters carry device addresses and names, which must not end up in the logs
+        trace!("IPC request: {}", request.method);
+        match self.dispatch(&request.method, request.params).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+            Err(err) => err.into_response(request.id)
+        }
+    }
+
+    async fn dispatch(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "Adapter.GetProperties" => {
+                let address = self.hci.read_bd_addr().await.map_err(RpcError::failed)?;
+                let mut state = self.state.lock();
+                state.adapter.address = Some(address);
+                to_value(&state.adapter)
+            }
+            "Adapter.SetName" => {
+                let NameParams { name } = parse(params)?;
+                match self.hci.identity().await {
+                    Some(_) => self.hci.update_identity(|identity| identity.name = name.clone()).await,
+                    None => self.hci.write_local_name(&name).await
+                }
+                .map_err(RpcError::failed)?;
+                self.state.lock().adapter.name = Some(name);
+                Ok(Value::Null)
+            }
+            "Adapter.SetDiscoverable" => {
+                let DiscoverableParams { discoverable } = parse(params)?;
+                self.hci
+                    .set_scan_enabled(true, discoverable)
+                    .await
+                    .map_err(RpcError::failed)?;
+                self.state.lock().adapter.discoverable = Some(discoverable);
+                Ok(Value::Null)
+            }
+            "Adapter.StartDiscovery" => {
+                let DiscoveryParams { duration } = parse(params)?;
+                let results = self
+                    .hci
+                    .inquiry(Lap::General, duration.clamp(1, 30), 0)
+                    .await
+                    .map_err(RpcError::failed)?;
+                let mut state = self.state.lock();
+                for result in &results {
+                    state
+                        .devices
+                        .entry(result.addr)
+                        .or_insert_with(|| DeviceProperties::new(result.addr));
+                }
+                to_value(&results)
+            }
+            "Adapter.GetDevices" => {
+                let state = self.state.lock();
+                to_value(&state.devices.values().collect::<Vec<_>>())
+            }
+            "Device.GetProperties" => {
+                let AddressParams { address } = parse(params)?;
+                let device = self.device(address)?;
+                let mut value = to_value(&device)?;
+                if let Some(info) = self.remote_info.as_ref().and_then(|cache| cache.get(address)) {
+                    value["Info"] = to_value(&info)?;
+                }
+                value["MediaPlayer"] = Value::Bool(self.players.get(address).is_some());
+                Ok(value)
+            }
+            "Device.Connect" => {
+                let AddressParams { address } = parse(params)?;
+                self.hci
+                    .create_connection(address, true)
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(Value::Null)
+            }
+            "Device.Disconnect" => {
+                let AddressParams { address } = parse(params)?;
+                let handle = self
+                    .device(address)?
+                    .handle
+                    .ok_or_else(|| RpcError::new(FAILED, "Device is not connected"))?;
+                self.hci
+                    .disconnect(handle, Status::RemoteUserTerminatedConnection)
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(Value::Null)
+            }
+            "MediaControl.Play" => self.media_action(params, PassThroughOp::Play).await,
+            "MediaControl.Pause" => self.media_action(params, PassThroughOp::Pause).await,
+            "MediaControl.Stop" => self.media_action(params, PassThroughOp::Stop).await,
+            "MediaControl.Next" => self.media_action(params, PassThroughOp::Forward).await,
+            "MediaControl.Previous" => self.media_action(params, PassThroughOp::Backward).await,
+            "MediaPlayer.GetTrack" => {
+                let AddressParams { address } = parse(params)?;
+                let attributes = self
+                    .player(address)?
+                    .get_current_media_attributes(None)
+                    .await
+                    .map_err(RpcError::failed)?;
+                to_value(&attributes)
+            }
+            "MediaTransport.SetVolume" => {
+                let VolumeParams { address, volume } = parse(params)?;
+                let volume = self
+                    .player(address)?
+                    .set_absolute_volume(volume)
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(json!(volume))
+            }
+            #[cfg(feature = "pts")]
+            "Pts.RejectAvrcp" => {
+                let RejectParams { pdu, error } = parse(params)?;
+                let status = [error]
+                    .as_slice()
+                    .read_be()
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "Unknown AVRCP status"))?;
+                self.pts.reject_avrcp(pdu, status);
+                Ok(Value::Null)
+            }
+            #[cfg(feature = "pts")]
+            "Pts.RejectAvdtp" => {
+                let RejectParams { pdu, error } = parse(params)?;
+                let signal = [pdu]
+                    .as_slice()
+                    .read_be()
+                    .ok()
+                    .filter(|signal| *signal != SignalIdentifier::Unknown)
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Unknown AVDTP signal"))?;
+                self.pts.reject_avdtp(signal, error);
+                Ok(Value::Null)
+            }
+            #[cfg(feature = "pts")]
+            "Pts.ClearRejects" => {
+                self.pts.clear();
+                Ok(Value::Null)
+            }
+            #[cfg(feature = "pts")]
+            "Pts.SendAvrcp" => {
+                let SendParams { address, ctype, pdu, parameters } = parse(params)?;
+                let ctype = [ctype]
+                    .as_slice()
+                    .read_be()
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "Unknown command type"))?;
+                let parameters = from_hex(&parameters).ok_or_else(|| RpcError::new(INVALID_PARAMS, "Invalid hex parameters"))?;
+                let response = self
+                    .player(address)?
+                    .send_raw_command(ctype, pdu, parameters.into())
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(json!(to_hex(&response)))
+            }
+            #[cfg(feature = "pts")]
+            "Pts.NotifyVolume" => {
+                let VolumeParams { address, volume } = parse(params)?;
+                self.player(address)?
+                    .notify_local_volume_change(volume)
+                    .await
+                    .map_err(RpcError::failed)?;
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+        }
+    }
+
+    fn device(&self, address: BdAddr) -> Result<DeviceProperties, RpcError> {
+        self.state
+            .lock()
+            .devices
+            .get(&address)
+            .cloned()
+            .ok_or_else(|| RpcError::new(FAILED, "Unknown device"))
+    }
+
+    fn player(&self, address: BdAddr) -> Result<AvrcpController, RpcError> {
+        self.players
+            .get(address)
+            .ok_or_else(|| RpcError::new(FAILED, "Device has no media player"))
+    }
+
+    async fn media_action(&self, params: Value, op: PassThroughOp) -> Result<Value, RpcError> {
+        let AddressParams { address } = parse(params)?;
+        self.player(address)?
+            .action(op)
+            .await
+            .map_err(RpcError::failed)?;
+        Ok(Value::Null)
+    }
+}
+
+async fn track_devices(state: Arc<Mutex<State>>, mut events: ConnectionEventReceiver) {
+    while let Some(event) = events.recv().await {
+        let mut state = state.lock();
+        match event {
+            ConnectionEvent::ConnectionComplete { status, handle, addr, .. } if status.is_ok() => {
+                let device = state
+                    .devices
+                    .entry(addr)
+                    .or_insert_with(|| DeviceProperties::new(addr));
+                device.connected = true;
+                device.handle = Some(handle);
+            }
+            ConnectionEvent::DisconnectionComplete { status, handle, .. } if status.is_ok() => {
+                if let Some(device) = state.devices.values_mut().find(|device| device.handle == Some(handle)) {
+                    device.connected = false;
+                    device.handle = None;
+                }
+            }
+            ConnectionEvent::RemoteNameRequestComplete { status, addr, name } if status.is_ok() => {
+                state
+                    .devices
+                    .entry(addr)
+                    .or_insert_with(|| DeviceProperties::new(addr))
+                    .name = Some(name);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value
+}
+
+#[derive(Deserialize)]
+struct AddressParams {
+    address: BdAddr
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String
+}
+
+#[derive(Deserialize)]
+struct DiscoverableParams {
+    discoverable: bool
+}
+
+#[derive(Deserialize)]
+struct DiscoveryParams {
+    /// In units of 1.28 seconds.
+    #[serde(default = "default_discovery_duration")]
+    duration: u8
+}
+
+fn default_discovery_duration() -> u8 {
+    8
+}
+
+#[derive(Deserialize)]
+struct VolumeParams {
+    address: BdAddr,
+    volume: f32
+}
+
+/// `pdu` is the AVRCP PDU id or the AVDTP signal identifier.
+#[cfg(feature = "pts")]
+#[derive(Deserialize)]
+struct RejectParams {
+    pdu: u8,
+    error: u8
+}
+
+#[cfg(feature = "pts")]
+#[derive(Deserialize)]
+struct SendParams {
+    address: BdAddr,
+    /// The AV/C command type, e.g. 0x00 for control and 0x01 for status.
+    ctype: u8,
+    pdu: u8,
+    /// The parameters of the PDU as hex string.
+    #[serde(default)]
+    parameters: String
+}
+
+#[cfg(feature = "pts")]
+fn from_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    (value.len() % 2 == 0).then_some(())?;
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(feature = "pts")]
+fn to_hex(value: &[u8]) -> String {
+    value.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ([JSON-RPC 2.0] Section 5.1).
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// Implementation defined server error for commands that failed.
+const FAILED: i32 = -32000;
+
+struct RpcError {
+    code: i32,
+    message: String
+}
+
+impl RpcError {
+    fn new<M: ToString>(code: i32, message: M) -> Self {
+        Self {
+            code,
+            message: message.to_string()
+        }
+    }
+
+    fn failed<E: std::fmt::Debug>(err: E) -> Self {
+        Self::new(FAILED, format!("{:?}", err))
+    }
+
+    fn into_response(self, id: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "error": { "code": self.code, "message": self.message } })
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    // Omitted parameters are treated like an empty parameter object
+    let params = match params {
+        Value::Null => json!({}),
+        params => params
+    };
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(RpcError::failed)
+}
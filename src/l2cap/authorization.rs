@@ -0,0 +1,143 @@
+//! Lets the application confirm inbound connections of bonded devices that are not trusted yet,
+//! e.g. to implement a "trust this device?" prompt.
+use std::time::Duration;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot::{channel as oneshot_channel, Receiver as OneshotReceiver, Sender as OneshotSender};
+use tracing::{debug, warn};
+
+use crate::hci::consts::BdAddr;
+use crate::hci::remote_info::RemoteInfoCache;
+use crate::l2cap::{AVCTP_PSM, AVDTP_PSM, SDP_PSM};
+use crate::utils::clock::timeout;
+use crate::utils::redact::redacted;
+use crate::sdp::ids::protocols;
+use crate::sdp::Uuid;
+
+/// An inbound connection that waits for the decision of the application.
+/// Dropping the request without answering rejects the connection.
+#[derive(Debug)]
+pub struct AuthorizationRequest {
+    pub addr: BdAddr,
+    pub psm: u64,
+    responder: OneshotSender<Authorization>
+}
+
+impl AuthorizationRequest {
+    /// The protocol the device wants to use, if the PSM is a well known one.
+    pub fn protocol(&self) -> Option<Uuid> {
+        match u16::try_from(self.psm).ok()? {
+            SDP_PSM => Some(protocols::SDP),
+            AVCTP_PSM => Some(protocols::AVCTP),
+            AVDTP_PSM => Some(protocols::AVDTP),
+            _ => None
+        }
+    }
+
+    /// Whether the connection was already decided, e.g. because the request timed out.
+    pub fn is_answered(&self) -> bool {
+        self.responder.is_closed()
+    }
+
+    pub fn accept(self) {
+        let _ = self.responder.send(Authorization::Accept);
+    }
+
+    /// Accepts the connection and marks the device as trusted, so it isn't asked about again.
+    pub fn trust(self) {
+        let _ = self.responder.send(Authorization::Trust);
+    }
+
+    pub fn reject(self) {
+        let _ = self.responder.send(Authorization::Reject);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Authorization {
+    Accept,
+    Trust,
+    Reject
+}
+
+/// The pending authorization requests, see [ConnectionAuthorizer::new].
+pub struct AuthorizationRequests(UnboundedReceiver<AuthorizationRequest>);
+
+impl AuthorizationRequests {
+    pub async fn recv(&mut self) -> Option<AuthorizationRequest> {
+        self.0.recv().await
+    }
+}
+
+/// Decides which inbound connections need the confirmation of the application.
+/// Only bonded devices that are not marked as [trusted](crate::hci::remote_info::DeviceSettings::trusted) are asked about,
+/// SDP connections are always accepted.
+#[derive(Clone)]
+pub struct ConnectionAuthorizer {
+    remote_info: RemoteInfoCache,
+    requests: UnboundedSender<AuthorizationRequest>,
+    timeout: Duration
+}
+
+impl ConnectionAuthorizer {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(remote_info: RemoteInfoCache) -> (Self, AuthorizationRequests) {
+        let (tx, rx) = unbounded_channel();
+        let authorizer = Self {
+            remote_info,
+            requests: tx,
+            timeout: Self::DEFAULT_TIMEOUT
+        };
+        (authorizer, AuthorizationRequests(rx))
+    }
+
+    /// How long to wait for the decision of the application before the connection is rejected.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the pending decision or `None` if the connection can be accepted right away.
+    pub(crate) fn authorize(&self, addr: BdAddr, psm: u64) -> Option<PendingAuthorization> {
+        if psm == SDP_PSM as u64 || !self.remote_info.bonded().contains(&addr) || self.remote_info.settings(addr).trusted {
+            return None;
+        }
+        let (tx, rx) = oneshot_channel();
+        let request = AuthorizationRequest { addr, psm, responder: tx };
+        if self.requests.send(request).is_err() {
+            warn!("Nobody handles authorization requests, rejecting the connection of {}", redacted(&addr));
+        }
+        Some(PendingAuthorization {
+            addr,
+            decision: rx,
+            remote_info: self.remote_info.clone(),
+            timeout: self.timeout
+        })
+    }
+}
+
+pub(crate) struct PendingAuthorization {
+    addr: BdAddr,
+    decision: OneshotReceiver<Authorization>,
+    remote_info: RemoteInfoCache,
+    timeout: Duration
+}
+
+impl PendingAuthorization {
+    /// Waits for the decision of the application, rejecting the connection when it takes too long.
+    pub async fn granted(self) -> bool {
+        let decision = match timeout(self.timeout, self.decision).await {
+            Ok(Ok(decision)) => decision,
+            Ok(Err(_)) => Authorization::Reject,
+            Err(_) => {
+                debug!("Authorization of {} timed out", redacted(&self.addr));
+                Authorization::Reject
+            }
+        };
+        if decision == Authorization::Trust {
+            self.remote_info.update_settings(self.addr, |settings| settings.trusted = true);
+        }
+        decision != Authorization::Reject
+    }
+}
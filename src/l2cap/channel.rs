@@ -1,22 +1,32 @@
+use std::collections::VecDeque;
 use std::future::{poll_fn, Future};
-use std::task::{Context, Poll};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
-use futures_lite::FutureExt;
+use futures_lite::{FutureExt, Stream};
+use futures_sink::Sink;
 use instructor::utils::Length;
 use instructor::{BufferMut, Instruct, LittleEndian};
 use tokio::sync::mpsc::UnboundedReceiver as MpscReceiver;
-use tokio::time::sleep;
+use tokio::time::Instant;
 use tracing::{debug, info_span, instrument, trace, warn, Span, error};
 use tracing::field::Empty;
 use crate::ensure;
 
-use crate::hci::{AclSendError, AclSender};
-use crate::l2cap::configuration::{ConfigurationParameter, FlushTimeout, Mtu};
+use crate::hci::consts::BdAddr;
+use crate::hci::devices::ProfileFailure;
+use crate::hci::{AclPriority, AclSendError, AclSender, Flushed};
+use crate::l2cap::configuration::{ChannelParameters, ConfigurationParameter, ConfigurationPolicy, Fcs, FlushTimeout, Mode, Mtu};
 use crate::l2cap::signaling::{Psm, RejectReason, SignalingCode, SignalingContext};
-use crate::l2cap::{ChannelEvent, CID_ID_NONE, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, SignalingIds};
+use crate::l2cap::stats::{ChannelStats, StatsRecorder};
+use crate::l2cap::streaming::{StreamingReceiver, StreamingTransmitter};
+use crate::l2cap::{ChannelEvent, ChannelOpener, CID_ID_NONE, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, LinkEvent, SignalingIds};
+use crate::utils::clock::sleep;
 use crate::utils::{now_or_never, Loggable, IgnoreableResult};
+#[cfg(feature = "fault-injection")]
+use crate::utils::fault::FaultInjector;
 
 macro_rules! event {
     ($evt: expr) => {
@@ -26,10 +36,14 @@ macro_rules! event {
     };
 }
 
-const DEFAULT_MTU: Mtu = Mtu(1691);
+/// How often a configuration request is revised after the peer found its options unacceptable.
+const MAX_CONFIGURATION_ATTEMPTS: u8 = 3;
+
+/// How many SDUs sent through the [Sink] may wait for the controller before the channel stops being ready.
+const MAX_UNFLUSHED_SDUS: usize = 4;
 
 enum Event {
-    DataReceived(Bytes),
+    DataReceived(Bytes, Instant),
     ConnectionComplete,
     ConfigurationCompete,
     DisconnectComplete
@@ -45,6 +59,8 @@ pub enum Error {
     Timeout,
     #[error("The channel has been disconnected")]
     Disconnected,
+    #[error("The channel was disconnected because the configuration was rejected")]
+    ConfigurationRejected,
     #[error("The underlying transport has been closed. Is the event loop still running?")]
     ChannelClosed
 }
@@ -53,17 +69,58 @@ impl From<AclSendError> for Error {
     fn from(value: AclSendError) -> Self {
         match value {
             AclSendError::EventLoopClosed => Self::ChannelClosed,
+            AclSendError::Discarded => Self::Disconnected,
             AclSendError::InvalidData(e) => Self::InvalidData(e)
         }
     }
 }
 
+impl Error {
+    /// Whether repeating the operation might succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+}
+
+impl From<Error> for ProfileFailure {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::ConfigurationRejected => ProfileFailure::Rejected,
+            Error::Timeout => ProfileFailure::Timeout,
+            _ => ProfileFailure::Disconnected
+        }
+    }
+}
+
 impl Loggable for Error {
     fn should_log(&self) -> bool {
         !matches!(self, Error::Disconnected | Error::ChannelClosed)
     }
 }
 
+/// How often a configuration request that the peer doesn't answer is repeated before giving up.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The total number of attempts, including the first one.
+    pub attempts: u8,
+    /// How long to wait for the configuration to complete in each attempt.
+    pub timeout: Duration,
+    /// The delay before the first retry, doubled for every further retry.
+    pub backoff: Duration,
+    pub max_backoff: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            timeout: Duration::from_secs(2),
+            backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(2)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum State {
     Closed(ClosedState),
@@ -94,36 +151,90 @@ enum ConfigState {
 
 pub struct Channel {
     connection_handle: u16,
+    remote_addr: BdAddr,
     state: State,
     remote_cid: u16,
     local_cid: u16,
     receiver: MpscReceiver<ChannelEvent>,
+    link_events: MpscReceiver<LinkEvent>,
     sender: AclSender,
     next_signaling_id: SignalingIds,
+    opener: ChannelOpener,
     local_mtu: Mtu,
-    remote_mtu: Mtu,
-    flush_timeout: FlushTimeout,
-    span: Span
+    /// The configuration of the direction from us to the peer, as requested by the peer.
+    outgoing: ChannelParameters,
+    policy: ConfigurationPolicy,
+    configuration_request: Vec<ConfigurationParameter>,
+    configuration_attempts: u8,
+    configuration_rejected: bool,
+    retry_policy: RetryPolicy,
+    transmitter: Option<StreamingTransmitter>,
+    streaming_receiver: Option<StreamingReceiver>,
+    /// PDUs that arrived while waiting for the configuration to complete, returned by the next reads.
+    early_data: VecDeque<(Bytes, Instant)>,
+    /// SDUs sent through the [Sink] that the controller hasn't transmitted yet, oldest first.
+    unflushed: VecDeque<Flushed>,
+    last_received: Option<Instant>,
+    stats: StatsRecorder,
+    span: Span,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<FaultInjector>
 }
 
 impl Channel {
 
-    pub fn new(connection_handle: u16, local_cid: u16, receiver: MpscReceiver<ChannelEvent>, sender: AclSender, next_signaling_id: SignalingIds) -> Self {
+    pub fn new(
+        connection_handle: u16, remote_addr: BdAddr, local_cid: u16, receiver: MpscReceiver<ChannelEvent>, link_events: MpscReceiver<LinkEvent>,
+        sender: AclSender, next_signaling_id: SignalingIds, opener: ChannelOpener
+    ) -> Self {
         Self {
             connection_handle,
+            remote_addr,
             state: State::Closed(ClosedState::Idle),
             remote_cid: CID_ID_NONE,
             local_cid,
             receiver,
+            link_events,
             sender,
             next_signaling_id,
+            opener,
             local_mtu: Mtu::MINIMUM_ACL_U,
-            remote_mtu: Mtu::MINIMUM_ACL_U,
-            flush_timeout: FlushTimeout::default(),
-            span: info_span!(parent: None, "l2cap_channel", remote_cid = Empty, local_cid = format_args!("{:#X}", local_cid))
+            outgoing: ChannelParameters::default(),
+            policy: ConfigurationPolicy::default(),
+            configuration_request: Vec::new(),
+            configuration_attempts: 0,
+            configuration_rejected: false,
+            retry_policy: RetryPolicy::default(),
+            transmitter: None,
+            streaming_receiver: None,
+            early_data: VecDeque::new(),
+            unflushed: VecDeque::new(),
+            last_received: None,
+            stats: StatsRecorder::new(Default::default(), Default::default()),
+            span: info_span!(parent: None, "l2cap_channel", remote_cid = Empty, local_cid = format_args!("{:#X}", local_cid)),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: None
         }
     }
 
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    pub(crate) fn set_stats_recorder(&mut self, stats: StatsRecorder) {
+        self.stats = stats;
+    }
+
+    /// Adds the traffic of this channel to the counters of `psm` from now on.
+    pub(crate) fn set_psm(&mut self, psm: u64) {
+        self.stats.set_psm(psm);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn set_fault_injector(&mut self, injector: Option<FaultInjector>) {
+        self.fault_injector = injector;
+    }
+
     pub fn is_response_pending(&self) -> bool {
         matches!(self.state, State::Closed(ClosedState::WaitingForResponse(_)))
     }
@@ -137,6 +248,7 @@ impl Channel {
     #[instrument(parent = &self.span, skip(self))]
     pub async fn connect(&mut self, psm: u64) -> Result<(), Error> {
         ensure!(self.state == State::Closed(ClosedState::Idle), Error::BadState);
+        self.set_psm(psm);
         self.send_signaling(None, SignalingCode::ConnectionRequest, (Psm(psm), self.local_cid))?;
         self.set_state(State::WaitConnectRsp);
         self.wait_for_connection().await?;
@@ -158,13 +270,17 @@ impl Channel {
         }
     }
 
-    #[instrument(parent = &self.span, skip(self))]
     pub fn reject_connection(&mut self) -> Result<(), Error> {
+        self.refuse_connection(ConnectionResult::RefusedNoResources)
+    }
+
+    #[instrument(parent = &self.span, skip(self))]
+    pub(crate) fn refuse_connection(&mut self, result: ConnectionResult) -> Result<(), Error> {
         if let State::Closed(ClosedState::WaitingForResponse(transaction_id)) = self.state {
             self.send_signaling(Some(transaction_id), SignalingCode::ConnectionResponse, (
                 self.local_cid,
                 self.remote_cid,
-                ConnectionResult::RefusedNoResources,
+                result,
                 ConnectionStatus::NoFurtherInformation))?;
             self.set_state(State::Closed(ClosedState::Disconnected));
             Ok(())
@@ -182,8 +298,76 @@ impl Channel {
         self.connection_handle
     }
 
+    pub fn remote_addr(&self) -> BdAddr {
+        self.remote_addr
+    }
+
+    pub fn local_cid(&self) -> u16 {
+        self.local_cid
+    }
+
+    /// Can be used to open further channels on the same connection.
+    pub fn channel_opener(&self) -> ChannelOpener {
+        self.opener.clone()
+    }
+
+    /// Data of high priority channels is sent before queued data of normal priority channels.
+    /// Signaling packets always use normal priority.
+    pub fn set_priority(&mut self, priority: AclPriority) {
+        self.sender = self.sender.with_priority(priority);
+    }
+
+    pub fn priority(&self) -> AclPriority {
+        self.sender.priority()
+    }
+
     pub fn remote_mtu(&self) -> u16 {
-        self.remote_mtu.0
+        self.outgoing.mtu.0
+    }
+
+    /// The configuration the peer requested for the data we send.
+    pub fn outgoing_parameters(&self) -> &ChannelParameters {
+        &self.outgoing
+    }
+
+    /// Replaces the limits used to negotiate the configuration, has to be called before [Channel::configure].
+    /// With a flush timeout the data is sent as automatically flushable packets, the timeout of the controller
+    /// has to be set with [Hci::write_automatic_flush_timeout](crate::hci::Hci::write_automatic_flush_timeout).
+    pub fn set_configuration_policy(&mut self, policy: ConfigurationPolicy) {
+        self.sender = self.sender.with_flushable(policy.flush_timeout != FlushTimeout::Reliable);
+        self.policy = policy;
+    }
+
+    /// The mode of the data we receive and the data we send.
+    pub fn modes(&self) -> (Mode, Mode) {
+        (self.incoming_mode(), self.outgoing.rfc.mode)
+    }
+
+    fn incoming_mode(&self) -> Mode {
+        self.configuration_request
+            .iter()
+            .find_map(|option| match option {
+                ConfigurationParameter::RetransmissionAndFlowControl(rfc) => Some(rfc.mode),
+                _ => None
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sets up the framing of the negotiated modes once the configuration is complete.
+    fn setup_framing(&mut self) {
+        // ([Vol 3] Part A, Section 5.5) the FCS is only omitted if both sides asked for it
+        let fcs = self.outgoing.fcs == Fcs::Fcs16 || !self.configuration_request.contains(&Fcs::NoFcs.into());
+        self.transmitter = (self.outgoing.rfc.mode == Mode::Streaming).then(|| {
+            let mps = match self.outgoing.rfc.mps {
+                0 => self.outgoing.mtu.0,
+                mps => mps.min(self.outgoing.mtu.0)
+            };
+            StreamingTransmitter::new(mps, fcs)
+        });
+        self.streaming_receiver = (self.incoming_mode() == Mode::Streaming).then(|| StreamingReceiver::new(fcs));
+        if self.transmitter.is_some() || self.streaming_receiver.is_some() {
+            debug!("Using streaming mode: {:?}", self.modes());
+        }
     }
 
     fn set_state(&mut self, state: State) -> Option<Event> {
@@ -192,7 +376,10 @@ impl Channel {
         self.state = state;
         match self.state {
             State::Closed(ClosedState::Disconnected) => Some(Event::DisconnectComplete),
-            State::Open => Some(Event::ConfigurationCompete),
+            State::Open => {
+                self.setup_framing();
+                Some(Event::ConfigurationCompete)
+            }
             State::Config(ConfigState::Config) => Some(Event::ConnectionComplete),
             _ => None
         }
@@ -202,22 +389,116 @@ impl Channel {
         poll_fn(move |cx| self.poll_data(cx))
     }
 
+    /// When the L2CAP server received the (last fragment of the) most recent SDU returned by [Channel::read],
+    /// before it waited in the queue of this channel.
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received
+    }
+
+    /// The traffic counters of this channel.
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.snapshot()
+    }
+
     #[instrument(parent = &self.span, skip(self, data))]
     pub async fn write(&mut self, data: Bytes) -> Result<(), Error> {
+        self.write_batch([data]).await
+    }
+
+    /// Like [`Channel::write`] for several SDUs, which are queued together so their packets are sent back to back
+    /// (see [AclSender::send_batch]).
+    pub async fn write_batch<I: IntoIterator<Item = Bytes>>(&mut self, sdus: I) -> Result<(), Error> {
+        let mut packets = Vec::new();
+        for sdu in sdus {
+            packets.extend(self.frame(sdu).await?);
+        }
+        self.send_packets(packets)
+    }
+
+    fn send_packets(&self, packets: Vec<Bytes>) -> Result<(), Error> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(injector) = &self.fault_injector {
+            for packet in packets {
+                let sender = self.sender.clone();
+                let handle = self.connection_handle;
+                injector.apply(packet).deliver(move |packet| {
+                    let _ = sender.send(handle, packet);
+                });
+            }
+            return Ok(());
+        }
+        self.sender.send_batch(self.connection_handle, packets)?;
+        Ok(())
+    }
+
+    /// Like [Channel::send_packets], but tracks when the controller has transmitted the last packet.
+    fn send_packets_flushed(&self, mut packets: Vec<Bytes>) -> Result<Option<Flushed>, Error> {
+        #[cfg(feature = "fault-injection")]
+        if self.fault_injector.is_some() {
+            // Delayed and dropped packets can't be tracked
+            self.send_packets(packets)?;
+            return Ok(None);
+        }
+        let Some(last) = packets.pop() else {
+            return Ok(None);
+        };
+        for packet in packets {
+            self.sender.send(self.connection_handle, packet)?;
+        }
+        Ok(Some(self.sender.send_flushed(self.connection_handle, last)?))
+    }
+
+    /// Waits until at most `limit` SDUs sent through the [Sink] are still waiting for the controller.
+    fn poll_unflushed(&mut self, cx: &mut Context<'_>, limit: usize) -> Poll<Result<(), Error>> {
+        while self.unflushed.len() > limit {
+            let oldest = self.unflushed.front_mut().expect("unflushed SDUs should not be empty");
+            // Packets of a connection complete in order, so only the oldest SDU needs to be polled
+            let result = ready!(oldest.poll(cx));
+            self.unflushed.pop_front();
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Like [`Channel::write`], but returns a future that resolves once the controller has actually transmitted the data.
+    #[instrument(parent = &self.span, skip(self, data))]
+    pub async fn write_flushed(&mut self, data: Bytes) -> Result<Flushed, Error> {
+        let mut packets = self.frame(data).await?;
+        let last = packets.pop().ok_or(Error::BadState)?;
+        for packet in packets {
+            self.sender.send(self.connection_handle, packet)?;
+        }
+        Ok(self.sender.send_flushed(self.connection_handle, last)?)
+    }
+
+    /// Waits until the configuration is complete, e.g. because the MTU of the peer is needed.
+    pub async fn wait_until_open(&mut self) -> Result<(), Error> {
         if self.state != State::Open {
             trace!("Channel not yet open, waiting for configuration");
             self.wait_for_configuration_complete()
                 .or(timeout(Duration::from_secs(2)))
                 .await?;
         }
+        Ok(())
+    }
+
+    async fn frame(&mut self, data: Bytes) -> Result<Vec<Bytes>, Error> {
+        self.wait_until_open().await?;
+        self.frame_open(data)
+    }
+
+    fn frame_open(&mut self, data: Bytes) -> Result<Vec<Bytes>, Error> {
+        self.stats.sent(data.len());
+        if let Some(transmitter) = &mut self.transmitter {
+            return Ok(transmitter.frame(self.remote_cid, data)?);
+        }
         let mut buffer = BytesMut::new();
         buffer.write_le(L2capHeader {
             len: Length::new(data.len())?,
             cid: self.remote_cid
         });
         buffer.put(data);
-        self.sender.send(self.connection_handle, buffer.freeze())?;
-        Ok(())
+        Ok(vec![buffer.freeze()])
     }
 
     #[instrument(parent = &self.span, skip(self))]
@@ -227,6 +508,8 @@ impl Channel {
         self.wait_for_disconnect().await
     }
 
+    /// Negotiates the channel parameters with the peer and waits until both directions are configured.
+    /// Unanswered requests are repeated according to the [RetryPolicy], a rejection by the peer fails immediately.
     #[instrument(parent = &self.span, skip(self))]
     pub async fn configure(&mut self) -> Result<(), Error> {
         match self.state {
@@ -236,10 +519,38 @@ impl Channel {
             _ => return Err(Error::BadState)
         };
         // Send ConfigReq
-        self.send_configuration_request(vec![DEFAULT_MTU.into()])?;
-        self.local_mtu = DEFAULT_MTU;
+        self.configuration_request = self.policy.request();
+        self.configuration_attempts = 1;
+        self.send_configuration_request(self.configuration_request.clone())?;
+        self.local_mtu = self.policy.mtu;
+
+        let policy = self.retry_policy;
+        let mut backoff = policy.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.wait_for_configuration_complete().or(timeout(policy.timeout)).await {
+                Err(err) if err.is_transient() && attempt < policy.attempts => {
+                    debug!("Configuration attempt {} timed out, retrying in {:?}", attempt, backoff);
+                    sleep(backoff).await;
+                    // The peer might have answered while we were waiting
+                    if let Some(result) = now_or_never(self.wait_for_configuration_complete()) {
+                        return result;
+                    }
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                    attempt += 1;
+                    self.stats.configuration_retry();
+                    self.retransmit_configuration_request()?;
+                }
+                result => return result
+            }
+        }
+    }
 
-        //self.wait_for_configuration_complete().await?;
+    /// Sends our configuration request again if the peer hasn't answered it yet.
+    fn retransmit_configuration_request(&mut self) -> Result<(), Error> {
+        if let State::Config(ConfigState::ConfigReqRsp | ConfigState::ConfigRsp) = self.state {
+            self.send_configuration_request(self.configuration_request.clone())?;
+        }
         Ok(())
     }
 
@@ -264,6 +575,7 @@ impl Channel {
                             event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                         }
                     }
+                    DataReceived(..) => self.stats.dropped(),
                     _ => { /* Ignore */ }
                 },
                 // ([Vol 3] Part A, Section 6.1.2)
@@ -282,7 +594,8 @@ impl Channel {
                         /* Send CommandReject (with reason Invalid CID) */
                         self.send_invalid_cid(id)?;
                     }
-                    DataReceived(_) | ConfigurationResponse { .. } | DisconnectRequest { .. } | DisconnectResponse { .. } => { /* Ignore */  }
+                    DataReceived(..) => self.stats.dropped(),
+                    ConfigurationResponse { .. } | DisconnectRequest { .. } | DisconnectResponse { .. } => { /* Ignore */  }
                 }
                 // ([Vol 3] Part A, Section 6.1.4)
                 State::Config(cs) => match data {
@@ -313,7 +626,7 @@ impl Channel {
                         event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                     }
                     DisconnectResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
-                    DataReceived(data) => return Poll::Ready(Ok(Event::DataReceived(data)))
+                    DataReceived(data, received) => return Poll::Ready(Ok(Event::DataReceived(data, received)))
                 },
                 // ([Vol 3] Part A, Section 6.1.5)
                 State::Open => match data {
@@ -325,7 +638,7 @@ impl Channel {
                         self.send_disconnect_response(id)?;
                         event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                     }
-                    DataReceived(data) => return Poll::Ready(Ok(Event::DataReceived(data))),
+                    DataReceived(data, received) => return Poll::Ready(Ok(Event::DataReceived(data, received))),
                     DisconnectResponse { .. } | ConfigurationResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
                 },
                 // ([Vol 3] Part A, Section 6.1.6)
@@ -342,18 +655,37 @@ impl Channel {
                     DisconnectResponse { .. } => {
                         event!(self.set_state(State::Closed(ClosedState::Disconnected)));
                     }
-                    DataReceived(_) | ConfigurationResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
+                    DataReceived(..) => self.stats.dropped(),
+                    ConfigurationResponse { .. } | ConnectionResponse { .. } => { /* Ignore */ }
                 }
             }
         }
         Poll::Pending
     }
 
+    /// Waits for the next change of the underlying ACL link (sniff mode, role switch, re-keying, ...).
+    pub fn link_event(&mut self) -> impl Future<Output = Option<LinkEvent>> + '_ {
+        poll_fn(move |cx| self.poll_link_event(cx))
+    }
+
+    pub fn poll_link_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<LinkEvent>> {
+        self.link_events.poll_recv(cx)
+    }
+
     pub fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        while let Some((data, received)) = self.early_data.pop_front() {
+            if let Some(sdu) = self.receive(data, received) {
+                return Poll::Ready(Some(sdu));
+            }
+        }
         while let Poll::Ready(event) = self.poll_events(cx) {
             match event {
-                Ok(Event::DataReceived(data)) => return Poll::Ready(Some(data)),
-                Ok(Event::DisconnectComplete) | Err(Error::Disconnected | Error::ChannelClosed | Error::Timeout) => return Poll::Ready(None),
+                Ok(Event::DataReceived(data, received)) => {
+                    if let Some(sdu) = self.receive(data, received) {
+                        return Poll::Ready(Some(sdu));
+                    }
+                }
+                Ok(Event::DisconnectComplete) | Err(Error::Disconnected | Error::ConfigurationRejected | Error::ChannelClosed | Error::Timeout) => return Poll::Ready(None),
                 Ok(Event::ConnectionComplete | Event::ConfigurationCompete) => {}
                 Err(e) => panic!("{}", e)
             }
@@ -361,26 +693,25 @@ impl Channel {
         Poll::Pending
     }
 
-    fn handle_config_req(&mut self, id: u8, mut options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
-        let updated = false;
-        for option in options.iter_mut() {
-            match option {
-                ConfigurationParameter::Mtu(mtu) => self.remote_mtu = *mtu,
-                //TODO How to actually handle a flush timeout?
-                ConfigurationParameter::FlushTimeout(timeout) => self.flush_timeout = *timeout,
-                _ => {
-                    warn!("Unsupported configuration parameter: {:?}", option);
-                    self.send_configuration_response(id, ConfigureResult::Rejected, Vec::new())?;
-                    return Ok(None);
-                }
-            }
+    /// Returns the SDU that `data` completes, if any.
+    fn receive(&mut self, data: Bytes, received: Instant) -> Option<Bytes> {
+        self.last_received = Some(received);
+        self.stats.received(data.len());
+        match &mut self.streaming_receiver {
+            Some(receiver) => receiver.receive(self.local_cid, data),
+            None => Some(data)
         }
-        if updated {
-            self.send_configuration_response(id, ConfigureResult::UnacceptableParameters, options)?;
-            Ok(None)
-        } else {
-            self.send_configuration_response(id, ConfigureResult::Success, options)?;
-            Ok(self.set_state(success))
+    }
+
+    fn handle_config_req(&mut self, id: u8, options: Vec<ConfigurationParameter>, success: State) -> Result<Option<Event>, Error> {
+        let (result, options) = self.policy.process_request(&options, &mut self.outgoing);
+        self.send_configuration_response(id, result, options)?;
+        match result {
+            ConfigureResult::Success => Ok(self.set_state(success)),
+            other => {
+                debug!("Configuration request not accepted: {:?}", other);
+                Ok(None)
+            }
         }
     }
 
@@ -388,20 +719,45 @@ impl Channel {
         match result {
             ConfigureResult::Success => {
                 for option in options {
-                    match option {
-                        ConfigurationParameter::Mtu(mtu) => self.local_mtu = mtu,
-                        _ => warn!("Unexpected configuration parameter: {:?}", option)
+                    if let ConfigurationParameter::Mtu(mtu) = option {
+                        self.local_mtu = mtu;
                     }
                 }
                 Ok(self.set_state(success))
             }
+            ConfigureResult::UnacceptableParameters if self.configuration_attempts < MAX_CONFIGURATION_ATTEMPTS => {
+                match self.policy.revise_request(&self.configuration_request, &options) {
+                    Some(request) => {
+                        // ([Vol 3] Part A, Section 7.1.3) the response to the new request completes this step
+                        debug!("Revising configuration request: {:?}", request);
+                        self.configuration_attempts += 1;
+                        self.stats.configuration_retry();
+                        for option in &request {
+                            if let ConfigurationParameter::Mtu(mtu) = option {
+                                self.local_mtu = *mtu;
+                            }
+                        }
+                        self.configuration_request = request;
+                        self.send_configuration_request(self.configuration_request.clone())?;
+                        Ok(None)
+                    }
+                    None => self.abort_configuration()
+                }
+            }
             other => {
-                // Send ConfigReq (new options)
-                unimplemented!("Configuration failed: {:?}", other)
+                warn!("Configuration failed: {:?}", other);
+                self.abort_configuration()
             }
         }
     }
 
+    /// Disconnects a channel that can't be configured ([Vol 3] Part A, Section 7.1.3).
+    fn abort_configuration(&mut self) -> Result<Option<Event>, Error> {
+        self.configuration_rejected = true;
+        self.send_signaling(None, SignalingCode::DisconnectionRequest, (self.remote_cid, self.local_cid))?;
+        Ok(self.set_state(State::WaitDisconnect))
+    }
+
     fn wait_for_connection(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
         poll_fn(|cx| {
             if let State::Closed(ClosedState::Disconnected) = self.state {
@@ -419,20 +775,32 @@ impl Channel {
     }
 
     fn wait_for_configuration_complete(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
-        poll_fn(|cx| {
-            if let State::Closed(ClosedState::Disconnected) = self.state {
-                return Poll::Ready(Err(Error::Disconnected));
-            }
-            while let Poll::Ready(event) = self.poll_events(cx) {
-                match event? {
-                    Event::ConfigurationCompete => return Poll::Ready(Ok(())),
-                    Event::DisconnectComplete => return Poll::Ready(Err(Error::Disconnected)),
-                    Event::DataReceived(_) => warn!("Received data while still configuring"),
-                    Event::ConnectionComplete => {}
+        poll_fn(|cx| self.poll_configuration_complete(cx))
+    }
+
+    fn poll_configuration_complete(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let State::Closed(ClosedState::Disconnected) = self.state {
+            return Poll::Ready(Err(self.configuration_error()));
+        }
+        while let Poll::Ready(event) = self.poll_events(cx) {
+            match event? {
+                Event::ConfigurationCompete => return Poll::Ready(Ok(())),
+                Event::DisconnectComplete => return Poll::Ready(Err(self.configuration_error())),
+                Event::DataReceived(data, received) => {
+                    trace!("Received data while still configuring");
+                    self.early_data.push_back((data, received));
                 }
+                Event::ConnectionComplete => {}
             }
-            Poll::Pending
-        })
+        }
+        Poll::Pending
+    }
+
+    fn configuration_error(&self) -> Error {
+        match self.configuration_rejected {
+            true => Error::ConfigurationRejected,
+            false => Error::Disconnected
+        }
     }
 
     fn wait_for_disconnect(&mut self) -> impl Future<Output = Result<(), Error>> + '_ {
@@ -469,8 +837,8 @@ impl Channel {
             Some(id),
             SignalingCode::CommandReject,
             RejectReason::InvalidCid {
-                scid: self.local_cid,
-                dcid: self.remote_cid
+                local_cid: self.local_cid,
+                remote_cid: self.remote_cid
             }
         )
     }
@@ -484,6 +852,49 @@ impl Channel {
     }
 }
 
+/// The SDUs of the peer, like [Channel::read]. Ends once the channel is closed.
+impl Stream for Channel {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.get_mut().poll_data(cx)
+    }
+}
+
+/// Sends SDUs like [Channel::write]. The channel is ready once it is configured and fewer than [MAX_UNFLUSHED_SDUS]
+/// sent SDUs are waiting for the controller, flushing waits until the controller has transmitted all of them.
+/// Data that arrives while waiting for the configuration is kept for the [Stream].
+/// Closing doesn't disconnect, dropping the channel does.
+impl Sink<Bytes> for Channel {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.state != State::Open {
+            ready!(this.poll_configuration_complete(cx))?;
+        }
+        this.poll_unflushed(cx, MAX_UNFLUSHED_SDUS - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Error> {
+        let this = self.get_mut();
+        ensure!(this.state == State::Open, Error::BadState);
+        let packets = this.frame_open(item)?;
+        if let Some(flushed) = this.send_packets_flushed(packets)? {
+            this.unflushed.push_back(flushed);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_unflushed(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+}
+
 impl Drop for Channel {
     fn drop(&mut self) {
         if !matches!(self.state, State::Closed(_)) {
@@ -498,4 +909,135 @@ impl Drop for Channel {
 async fn timeout(duration: Duration) -> Result<(), Error> {
     sleep(duration).await;
     Err(Error::Timeout)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures_lite::StreamExt;
+    use futures_sink::Sink;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use tokio::time::Instant;
+
+    use crate::hci::consts::BdAddr;
+    use crate::hci::AclSender;
+    use crate::l2cap::channel::{Channel, ConfigState, Error, RetryPolicy, State, MAX_UNFLUSHED_SDUS};
+    use crate::l2cap::signaling::SignalingCode;
+    use crate::l2cap::{ChannelEvent, ChannelOpener, SignalingIds};
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+    use crate::utils::now_or_never;
+
+    type Pdus = UnboundedReceiver<Vec<(Bytes, Option<tokio::sync::oneshot::Sender<()>>)>>;
+
+    fn channel(state: State) -> (Channel, UnboundedSender<ChannelEvent>, Pdus) {
+        let (sender, pdus) = AclSender::detached(1021);
+        let (events, receiver) = unbounded_channel();
+        let (_, link_events) = unbounded_channel();
+        let (opener, _) = unbounded_channel();
+        let mut channel = Channel::new(
+            0x0001,
+            BdAddr::new([1, 2, 3, 4, 5, 6]),
+            0x0040,
+            receiver,
+            link_events,
+            sender,
+            SignalingIds::default(),
+            ChannelOpener(opener)
+        );
+        channel.set_remote_cid(0x0041);
+        channel.state = state;
+        (channel, events, pdus)
+    }
+
+    fn poll_ready(channel: &mut Channel) -> Option<Result<(), Error>> {
+        now_or_never(poll_fn(|cx| Pin::new(&mut *channel).poll_ready(cx)))
+    }
+
+    fn poll_flush(channel: &mut Channel) -> Option<Result<(), Error>> {
+        now_or_never(poll_fn(|cx| Pin::new(&mut *channel).poll_flush(cx)))
+    }
+
+    #[test]
+    fn sink_waits_for_the_controller() {
+        let (mut channel, _events, mut pdus) = channel(State::Open);
+        for i in 0..MAX_UNFLUSHED_SDUS {
+            assert_eq!(poll_ready(&mut channel), Some(Ok(())));
+            Pin::new(&mut channel).start_send(Bytes::from(vec![i as u8; 8])).unwrap();
+        }
+        assert_eq!(poll_ready(&mut channel), None);
+        assert_eq!(poll_flush(&mut channel), None);
+
+        let mut notifiers = Vec::new();
+        while let Ok(mut pdu) = pdus.try_recv() {
+            assert_eq!(pdu.len(), 1);
+            notifiers.push(pdu.pop().unwrap().1.unwrap());
+        }
+        assert_eq!(notifiers.len(), MAX_UNFLUSHED_SDUS);
+
+        let mut notifiers = notifiers.into_iter();
+        notifiers.next().unwrap().send(()).unwrap();
+        assert_eq!(poll_ready(&mut channel), Some(Ok(())));
+        assert_eq!(poll_flush(&mut channel), None);
+        for notifier in notifiers {
+            notifier.send(()).unwrap();
+        }
+        assert_eq!(poll_flush(&mut channel), Some(Ok(())));
+
+        // SDUs that are discarded before the controller transmitted them fail the flush
+        Pin::new(&mut channel).start_send(Bytes::from_static(b"lost")).unwrap();
+        drop(pdus.try_recv().unwrap());
+        assert_eq!(poll_flush(&mut channel), Some(Err(Error::Disconnected)));
+    }
+
+    #[test]
+    fn data_received_while_configuring_is_kept() {
+        let (mut channel, events, _pdus) = channel(State::Config(ConfigState::ConfigRsp));
+        events.send(ChannelEvent::DataReceived(Bytes::from_static(b"early"), Instant::now())).unwrap();
+        assert_eq!(poll_ready(&mut channel), None);
+
+        channel.state = State::Open;
+        events.send(ChannelEvent::DataReceived(Bytes::from_static(b"later"), Instant::now())).unwrap();
+        assert_eq!(now_or_never(channel.next()), Some(Some(Bytes::from_static(b"early"))));
+        assert_eq!(now_or_never(channel.next()), Some(Some(Bytes::from_static(b"later"))));
+        assert_eq!(now_or_never(channel.next()), None);
+
+        drop(events);
+        assert_eq!(now_or_never(channel.next()), Some(None));
+    }
+
+    #[test]
+    fn unanswered_configuration_is_retried() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let (mut channel, _events, mut pdus) = channel(State::Config(ConfigState::Config));
+        channel.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            timeout: Duration::from_secs(2),
+            backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(2)
+        });
+        let mut configure = Box::pin(channel.configure());
+        // Each attempt times out, followed by a backoff that doubles
+        for step in [2000, 250, 2000, 500] {
+            assert_eq!(now_or_never(configure.as_mut()), None);
+            clock.advance(Duration::from_millis(step));
+        }
+        assert_eq!(now_or_never(configure.as_mut()), None);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(now_or_never(configure.as_mut()), Some(Err(Error::Timeout)));
+        drop(configure);
+
+        let mut requests = 0;
+        while let Ok(pdu) = pdus.try_recv() {
+            // The signaling code follows the ACL and L2CAP headers
+            assert_eq!(pdu[0].0[8], SignalingCode::ConfigureRequest as u8);
+            requests += 1;
+        }
+        assert_eq!(requests, 3);
+        assert_eq!(channel.stats().configuration_retries, 2);
+    }
+}
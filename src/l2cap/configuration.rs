@@ -1,15 +1,38 @@
 use instructor::{Buffer, BufferMut, Error, Exstruct, Instruct, LittleEndian};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::ensure;
+use crate::l2cap::ConfigureResult;
 
 trait ConfigurationOption: Default + Instruct<LittleEndian> + Exstruct<LittleEndian> + Into<ConfigurationParameter> {
     const TYPE: u8;
     const LENGTH: u8;
 }
 
+/// How a value proposed by the peer is answered ([Vol 3] Part A, Section 7.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Negotiation<T> {
+    Accept,
+    /// The value is unacceptable, the response suggests this one instead.
+    CounterProposal(T),
+    Reject
+}
+
+/// An option with its own rules for accepting the values of the peer.
+trait NegotiableOption: ConfigurationOption + Copy {
+    /// Checks a value of a configuration request of the peer.
+    fn negotiate(&self, policy: &ConfigurationPolicy) -> Negotiation<Self>;
+
+    /// Checks a value the peer suggested in response to our configuration request.
+    fn accept_counter_proposal(&self, policy: &ConfigurationPolicy) -> bool {
+        matches!(self.negotiate(policy), Negotiation::Accept)
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters);
+}
+
 // ([Vol 3] Part A, Section 5.1)
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Instruct, Exstruct)]
 #[instructor(endian = "little")]
 pub struct Mtu(pub u16);
 
@@ -28,6 +51,19 @@ impl ConfigurationOption for Mtu {
     const LENGTH: u8 = 2;
 }
 
+impl NegotiableOption for Mtu {
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        match *self >= Self::MINIMUM_ACL_U {
+            true => Negotiation::Accept,
+            false => Negotiation::CounterProposal(Self::MINIMUM_ACL_U)
+        }
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.mtu = self;
+    }
+}
+
 // ([Vol 3] Part A, Section 5.2)
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FlushTimeout {
@@ -42,6 +78,16 @@ impl ConfigurationOption for FlushTimeout {
     const LENGTH: u8 = 2;
 }
 
+impl NegotiableOption for FlushTimeout {
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        Negotiation::Accept
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.flush_timeout = self;
+    }
+}
+
 impl Instruct<LittleEndian> for FlushTimeout {
     fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
         let value = match *self {
@@ -110,6 +156,23 @@ impl ConfigurationOption for QualityOfService {
     const LENGTH: u8 = 22;
 }
 
+impl NegotiableOption for QualityOfService {
+    /// Guaranteed traffic can't be provided, it is countered with best effort.
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        match self.service_type {
+            ServiceType::NoTraffic | ServiceType::BestEffort => Negotiation::Accept,
+            ServiceType::Guaranteed => Negotiation::CounterProposal(Self {
+                service_type: ServiceType::BestEffort,
+                ..*self
+            })
+        }
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.qos = self;
+    }
+}
+
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
 #[repr(u8)]
 pub enum Mode {
@@ -139,6 +202,23 @@ impl ConfigurationOption for RetransmissionAndFlowControl {
     const LENGTH: u8 = 9;
 }
 
+impl NegotiableOption for RetransmissionAndFlowControl {
+    /// Modes outside of the policy are countered with the preferred mode.
+    fn negotiate(&self, policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        match policy.modes.contains(&self.mode) {
+            true => Negotiation::Accept,
+            false => Negotiation::CounterProposal(Self {
+                mode: policy.preferred_mode(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.rfc = self;
+    }
+}
+
 // ([Vol 3] Part A, Section 5.5)
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
 #[repr(u8)]
@@ -153,6 +233,16 @@ impl ConfigurationOption for Fcs {
     const LENGTH: u8 = 1;
 }
 
+impl NegotiableOption for Fcs {
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        Negotiation::Accept
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.fcs = self;
+    }
+}
+
 // ([Vol 3] Part A, Section 5.6)
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
 #[instructor(endian = "little")]
@@ -183,6 +273,23 @@ impl ConfigurationOption for ExtendedFlowSpecification {
     const LENGTH: u8 = 16;
 }
 
+impl NegotiableOption for ExtendedFlowSpecification {
+    /// Like [QualityOfService], guaranteed traffic is countered with best effort.
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        match self.service_type {
+            ServiceType::NoTraffic | ServiceType::BestEffort => Negotiation::Accept,
+            ServiceType::Guaranteed => Negotiation::CounterProposal(Self {
+                service_type: ServiceType::BestEffort,
+                ..*self
+            })
+        }
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.flow_spec = Some(self);
+    }
+}
+
 // ([Vol 3] Part A, Section 5.7)
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ExtendedWindowSize {
@@ -220,6 +327,161 @@ impl ConfigurationOption for ExtendedWindowSize {
     const LENGTH: u8 = 2;
 }
 
+impl NegotiableOption for ExtendedWindowSize {
+    fn negotiate(&self, _policy: &ConfigurationPolicy) -> Negotiation<Self> {
+        Negotiation::Accept
+    }
+
+    fn apply(self, parameters: &mut ChannelParameters) {
+        parameters.window_size = Some(self);
+    }
+}
+
+/// The configuration of one direction of a channel, the options a side didn't request keep their defaults.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ChannelParameters {
+    pub mtu: Mtu,
+    pub flush_timeout: FlushTimeout,
+    pub qos: QualityOfService,
+    pub rfc: RetransmissionAndFlowControl,
+    pub fcs: Fcs,
+    pub flow_spec: Option<ExtendedFlowSpecification>,
+    pub window_size: Option<ExtendedWindowSize>
+}
+
+/// The local side of the configuration process ([Vol 3] Part A, Section 7.1).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigurationPolicy {
+    /// The largest packet we can receive.
+    pub mtu: Mtu,
+    /// The supported modes, in the order of preference.
+    pub modes: Vec<Mode>,
+    /// How long we try to deliver the data we send, announced to the peer if it isn't reliable.
+    pub flush_timeout: FlushTimeout
+}
+
+impl Default for ConfigurationPolicy {
+    fn default() -> Self {
+        Self {
+            mtu: Mtu(1691),
+            modes: vec![Mode::Basic],
+            flush_timeout: FlushTimeout::Reliable
+        }
+    }
+}
+
+impl ConfigurationPolicy {
+    /// A policy for time sensitive data like media packets, which are better dropped than delivered late.
+    /// Streaming mode is preferred, peers that don't support it can counter with basic mode.
+    /// `flush_timeout` is in milliseconds.
+    pub fn streaming(flush_timeout: u16) -> Self {
+        Self {
+            modes: vec![Mode::Streaming, Mode::Basic],
+            flush_timeout: match flush_timeout {
+                0 | 1 => FlushTimeout::NoRetransmission,
+                u16::MAX => FlushTimeout::Reliable,
+                timeout => FlushTimeout::Timeout(timeout)
+            },
+            ..Default::default()
+        }
+    }
+
+    fn preferred_mode(&self) -> Mode {
+        self.modes.first().copied().unwrap_or_default()
+    }
+
+    /// The options of our configuration request.
+    pub fn request(&self) -> Vec<ConfigurationParameter> {
+        let mut options = vec![self.mtu.into()];
+        if self.flush_timeout != FlushTimeout::Reliable {
+            options.push(self.flush_timeout.into());
+        }
+        if self.preferred_mode() != Mode::Basic {
+            // ([Vol 3] Part A, Section 5.4) the timeouts and the window are unused in streaming mode
+            options.push(
+                RetransmissionAndFlowControl {
+                    mode: self.preferred_mode(),
+                    mps: self.mtu.0,
+                    ..Default::default()
+                }
+                .into()
+            );
+        }
+        options
+    }
+
+    /// Answers a configuration request of the peer. The accepted values are only applied to `parameters` on success.
+    pub fn process_request(&self, options: &[ConfigurationParameter], parameters: &mut ChannelParameters) -> (ConfigureResult, Vec<ConfigurationParameter>) {
+        fn check<T: NegotiableOption>(value: T, policy: &ConfigurationPolicy, accepted: &mut ChannelParameters) -> Negotiation<ConfigurationParameter> {
+            match value.negotiate(policy) {
+                Negotiation::Accept => {
+                    value.apply(accepted);
+                    Negotiation::Accept
+                }
+                Negotiation::CounterProposal(counter) => Negotiation::CounterProposal(counter.into()),
+                Negotiation::Reject => Negotiation::Reject
+            }
+        }
+
+        let mut accepted = *parameters;
+        let mut counter_proposals = Vec::new();
+        let mut rejected = false;
+        for &option in options {
+            let negotiation = match option {
+                ConfigurationParameter::Mtu(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::FlushTimeout(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::QualityOfService(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::RetransmissionAndFlowControl(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::Fcs(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::ExtendedFlowSpecification(value) => check(value, self, &mut accepted),
+                ConfigurationParameter::ExtendedWindowSize(value) => check(value, self, &mut accepted),
+                // Only hints are parsed as unknown options, they can be ignored ([Vol 3] Part A, Section 5)
+                ConfigurationParameter::Unknown(_) => continue
+            };
+            match negotiation {
+                Negotiation::Accept => {}
+                Negotiation::CounterProposal(counter) => counter_proposals.push(counter),
+                Negotiation::Reject => rejected = true
+            }
+        }
+        if rejected {
+            (ConfigureResult::Rejected, Vec::new())
+        } else if !counter_proposals.is_empty() {
+            (ConfigureResult::UnacceptableParameters, counter_proposals)
+        } else {
+            *parameters = accepted;
+            (ConfigureResult::Success, options.to_vec())
+        }
+    }
+
+    /// Builds a new configuration request from the counter proposals of the peer,
+    /// `None` if one of them is unacceptable.
+    pub fn revise_request(&self, request: &[ConfigurationParameter], counter_proposals: &[ConfigurationParameter]) -> Option<Vec<ConfigurationParameter>> {
+        let mut revised = request.to_vec();
+        for &proposal in counter_proposals {
+            let acceptable = match proposal {
+                ConfigurationParameter::Mtu(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::FlushTimeout(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::QualityOfService(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::RetransmissionAndFlowControl(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::Fcs(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::ExtendedFlowSpecification(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::ExtendedWindowSize(value) => value.accept_counter_proposal(self),
+                ConfigurationParameter::Unknown(_) => continue
+            };
+            if !acceptable {
+                warn!("Unacceptable counter proposal: {:?}", proposal);
+                return None;
+            }
+            match revised.iter_mut().find(|option| option.option_type() == proposal.option_type()) {
+                Some(option) => *option = proposal,
+                None => revised.push(proposal)
+            }
+        }
+        Some(revised)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ConfigurationParameter {
     Mtu(Mtu),
@@ -232,6 +494,21 @@ pub enum ConfigurationParameter {
     Unknown(u8)
 }
 
+impl ConfigurationParameter {
+    fn option_type(&self) -> u8 {
+        match self {
+            ConfigurationParameter::Mtu(_) => Mtu::TYPE,
+            ConfigurationParameter::FlushTimeout(_) => FlushTimeout::TYPE,
+            ConfigurationParameter::QualityOfService(_) => QualityOfService::TYPE,
+            ConfigurationParameter::RetransmissionAndFlowControl(_) => RetransmissionAndFlowControl::TYPE,
+            ConfigurationParameter::Fcs(_) => Fcs::TYPE,
+            ConfigurationParameter::ExtendedFlowSpecification(_) => ExtendedFlowSpecification::TYPE,
+            ConfigurationParameter::ExtendedWindowSize(_) => ExtendedWindowSize::TYPE,
+            ConfigurationParameter::Unknown(ty) => *ty
+        }
+    }
+}
+
 impl Instruct<LittleEndian> for ConfigurationParameter {
     fn write_to_buffer<B: BufferMut>(&self, buffer: &mut B) {
         match self {
@@ -366,8 +643,10 @@ mod tests {
     use instructor::BufferMut;
 
     use crate::l2cap::configuration::{
-        ConfigurationOption, ExtendedFlowSpecification, ExtendedWindowSize, Fcs, FlushTimeout, Mtu, QualityOfService, RetransmissionAndFlowControl
+        ChannelParameters, ConfigurationOption, ConfigurationParameter, ConfigurationPolicy, ExtendedFlowSpecification, ExtendedWindowSize, Fcs,
+        FlushTimeout, Mode, Mtu, QualityOfService, RetransmissionAndFlowControl, ServiceType
     };
+    use crate::l2cap::ConfigureResult;
 
     #[test]
     fn check_sizes() {
@@ -385,4 +664,35 @@ mod tests {
         check_size::<ExtendedFlowSpecification>();
         check_size::<ExtendedWindowSize>();
     }
+
+    #[test]
+    fn negotiation() {
+        let policy = ConfigurationPolicy::default();
+        let mut parameters = ChannelParameters::default();
+        let ertm = RetransmissionAndFlowControl {
+            mode: Mode::EnhancedRetransmission,
+            ..Default::default()
+        };
+        let (result, options) = policy.process_request(&[Mtu(672).into(), ertm.into()], &mut parameters);
+        assert_eq!(result, ConfigureResult::UnacceptableParameters);
+        assert_eq!(options, vec![ConfigurationParameter::RetransmissionAndFlowControl(Default::default())]);
+        assert_eq!(parameters.mtu, Mtu::MINIMUM_ACL_U);
+
+        let (result, _) = policy.process_request(&[Mtu(672).into()], &mut parameters);
+        assert_eq!(result, ConfigureResult::Success);
+        assert_eq!(parameters.mtu, Mtu(672));
+
+        let guaranteed = QualityOfService {
+            service_type: ServiceType::Guaranteed,
+            ..Default::default()
+        };
+        assert_eq!(policy.revise_request(&policy.request(), &[Mtu(1024).into()]), Some(vec![Mtu(1024).into()]));
+        assert_eq!(policy.revise_request(&policy.request(), &[guaranteed.into()]), None);
+
+        // Peers without streaming mode counter with basic mode
+        let streaming = ConfigurationPolicy::streaming(20);
+        let basic = RetransmissionAndFlowControl::default();
+        let revised = streaming.revise_request(&streaming.request(), &[basic.into()]).unwrap();
+        assert_eq!(revised[1..], [FlushTimeout::Timeout(20).into(), basic.into()]);
+    }
 }
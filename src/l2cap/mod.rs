@@ -1,6 +1,9 @@
+pub mod authorization;
 pub mod channel;
 pub mod configuration;
 pub mod signaling;
+pub mod stats;
+pub mod streaming;
 
 use std::collections::BTreeMap;
 use std::future::Future;
@@ -9,22 +12,35 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::Bytes;
 use instructor::utils::Length;
 use instructor::{Buffer, Exstruct, Instruct};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender as MpscSender};
+use tokio::sync::oneshot::{channel as oneshot_channel, Sender as OneshotSender};
+use tokio::time::Instant;
 use tracing::{debug, warn};
 
 use crate::hci::acl::{AclDataAssembler, AclHeader};
-use crate::hci::consts::{ConnectionMode, EventCode, LinkType, RemoteAddr, Status};
+use crate::hci::consts::{BdAddr, ConnectionMode, EncryptionMode, EventCode, LinkType, Role, Status, BASE_BAND_SLOT};
 use crate::hci::{AclSender, Error, Hci};
-use crate::l2cap::channel::Channel;
+use crate::l2cap::authorization::ConnectionAuthorizer;
+use crate::l2cap::channel::{Channel, Error as ChannelError, RetryPolicy};
 use crate::l2cap::configuration::ConfigurationParameter;
+use crate::l2cap::stats::{ChannelStats, Counters, PsmCounters, StatsRecorder};
+use crate::utils::clock::now;
+use crate::utils::redact::redacted;
+use crate::utils::DispatchExt;
+#[cfg(feature = "fault-injection")]
+use crate::utils::fault::FaultInjector;
 
 pub const SDP_PSM: u16 = 0x0001;
 pub const AVCTP_PSM: u16 = 0x0017;
 pub const AVDTP_PSM: u16 = 0x0019;
+pub const AVCTP_BROWSING_PSM: u16 = 0x001B;
+/// A PSM of the dynamic range for the AVRCP cover art service, peers find it in the service record of the target.
+pub const AVRCP_COVER_ART_PSM: u16 = 0x1001;
 
 const CID_ID_NONE: u16 = 0x0000;
 const CID_ID_SIGNALING: u16 = 0x0001;
@@ -32,7 +48,11 @@ const CID_RANGE_DYNAMIC: Range<u16> = 0x0040..0xFFFF;
 
 #[derive(Default)]
 pub struct L2capServerBuilder {
-    handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>
+    handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>,
+    authorizer: Option<ConnectionAuthorizer>,
+    retry_policy: RetryPolicy,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<FaultInjector>
 }
 
 impl L2capServerBuilder {
@@ -43,6 +63,25 @@ impl L2capServerBuilder {
         self
     }
 
+    /// Holds back inbound connections of untrusted devices until `authorizer` grants them.
+    pub fn with_authorizer(mut self, authorizer: ConnectionAuthorizer) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Sets how often the channels repeat configuration requests that peers don't answer.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Applies the faults of `injector` to the data of all dynamic channels in both directions.
+    #[cfg(feature = "fault-injection")]
+    pub fn with_fault_injector(mut self, injector: FaultInjector) -> Self {
+        self.fault_injector = Some(injector);
+        self
+    }
+
     pub fn run(self, hci: &Hci) -> Result<L2capServer, Error> {
         let data = {
             let (tx, rx) = unbounded_channel();
@@ -52,20 +91,41 @@ impl L2capServerBuilder {
         let events = {
             let (tx, rx) = unbounded_channel();
             hci.register_event_handler(
-                [EventCode::ConnectionComplete, EventCode::DisconnectionComplete, EventCode::MaxSlotsChange, EventCode::ModeChange],
+                [
+                    EventCode::ConnectionComplete,
+                    EventCode::DisconnectionComplete,
+                    EventCode::MaxSlotsChange,
+                    EventCode::ModeChange,
+                    EventCode::RoleChange,
+                    EventCode::EncryptionChange,
+                    EventCode::EncryptionKeyRefreshComplete
+                ],
                 tx
             )?;
             rx
         };
         let sender = hci.get_acl_sender();
+        let (open_tx, open_rx) = unbounded_channel();
+        let (snapshot_tx, snapshot_rx) = unbounded_channel();
+        let psm_counters = PsmCounters::default();
         Ok(L2capServer {
             data,
             events,
+            open_requests: open_rx,
+            opener: ChannelOpener(open_tx),
+            snapshot_requests: snapshot_rx,
+            inspector: L2capInspector(snapshot_tx, psm_counters.clone()),
             sender,
             connections: Default::default(),
             handlers: self.handlers,
+            authorizer: self.authorizer,
+            retry_policy: self.retry_policy,
             channels: Default::default(),
+            channel_counters: Default::default(),
+            psm_counters,
             next_signaling_id: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: self.fault_injector
         })
     }
 
@@ -77,20 +137,56 @@ struct PhysicalConnection {
     handle: u16,
     max_slots: u8,
     mode: ConnectionMode,
-    addr: RemoteAddr,
-    assembler: AclDataAssembler
+    addr: BdAddr,
+    assembler: AclDataAssembler,
+    link_listeners: Vec<MpscSender<LinkEvent>>,
+    local_cids: Vec<u16>
+}
+
+/// The state of an ACL connection and its channels for debugging.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionSnapshot {
+    pub handle: u16,
+    pub addr: BdAddr,
+    pub mode: String,
+    pub max_slots: u8,
+    /// The local CIDs of the channels that are still in use.
+    pub channels: Vec<u16>,
+    /// The traffic counters of the channels, by local CID.
+    pub channel_stats: BTreeMap<u16, ChannelStats>
+}
+
+/// Changes of an ACL link that profiles using it might want to react to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LinkEvent {
+    /// The link entered sniff / hold mode or went back to active mode.
+    ModeChanged { mode: ConnectionMode, interval: Duration },
+    RoleChanged(Role),
+    EncryptionChanged(EncryptionMode),
+    EncryptionKeyRefreshed
 }
 
 #[must_use = "Futures do nothing unless you `.await` or poll them"]
 pub struct L2capServer {
     data: UnboundedReceiver<Bytes>,
     events: UnboundedReceiver<(EventCode, Bytes)>,
+    open_requests: UnboundedReceiver<ChannelRequest>,
+    opener: ChannelOpener,
+    snapshot_requests: UnboundedReceiver<SnapshotRequest>,
+    inspector: L2capInspector,
 
     sender: AclSender,
     connections: BTreeMap<u16, PhysicalConnection>,
     handlers: BTreeMap<u64, Arc<dyn ProtocolHandler>>,
+    authorizer: Option<ConnectionAuthorizer>,
+    retry_policy: RetryPolicy,
     channels: BTreeMap<u16, MpscSender<ChannelEvent>>,
-    next_signaling_id: SignalingIds
+    channel_counters: BTreeMap<u16, Arc<Counters>>,
+    psm_counters: PsmCounters,
+    next_signaling_id: SignalingIds,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<FaultInjector>
 }
 
 impl Future for L2capServer {
@@ -107,6 +203,16 @@ impl Future for L2capServer {
            self.handle_event(event)
                .unwrap_or_else(|err| warn!("Error handling event: {:?}", err));
         }
+        while let Poll::Ready(Some((handle, tx))) = self.open_requests.poll_recv(cx) {
+            let channel = match self.connections.contains_key(&handle) {
+                true => self.new_channel(handle),
+                false => None
+            };
+            let _ = tx.send(channel);
+        }
+        while let Poll::Ready(Some(tx)) = self.snapshot_requests.poll_recv(cx) {
+            let _ = tx.send(self.snapshot());
+        }
         Poll::Pending
     }
 }
@@ -130,13 +236,30 @@ impl L2capServer {
         Ok(())
     }
 
+    fn send_channel_data(&mut self, cid: u16, data: Bytes) -> Result<(), Error> {
+        let received = now();
+        #[cfg(feature = "fault-injection")]
+        if let Some(injector) = &self.fault_injector {
+            let channel = self
+                .channels
+                .get(&cid)
+                .ok_or(Error::UnknownChannelId(cid))?
+                .clone();
+            injector.apply(data).deliver(move |data| {
+                let _ = channel.send(ChannelEvent::DataReceived(data, received));
+            });
+            return Ok(());
+        }
+        self.send_channel_msg(cid, ChannelEvent::DataReceived(data, received))
+    }
+
     fn handle_event(&mut self, (code, mut data): (EventCode, Bytes)) -> Result<(), Error> {
         match code {
             EventCode::ConnectionComplete => {
                 // ([Vol 4] Part E, Section 7.7.3).
                 let status: Status = data.read_le()?;
                 let handle: u16 = data.read_le()?;
-                let addr: RemoteAddr = data.read_le()?;
+                let addr: BdAddr = data.read_le()?;
                 let link_type: LinkType = data.read_le()?;
                 let _encryption_enabled = data.read_le::<u8>().map(|b| b == 0x01)?;
                 data.finish()?;
@@ -152,12 +275,14 @@ impl L2capServer {
                                     max_slots: 0x01,
                                     mode: ConnectionMode::default(),
                                     addr,
-                                    assembler: AclDataAssembler::default()
+                                    assembler: AclDataAssembler::default(),
+                                    link_listeners: Vec::new(),
+                                    local_cids: Vec::new()
                                 }
                             )
                             .is_none()
                     );
-                    debug!("Connection complete: 0x{:04X} {}", handle, addr);
+                    debug!("Connection complete: 0x{:04X} {}", handle, redacted(&addr));
                 } else {
                     warn!("Connection failed: {:?}", status);
                 }
@@ -189,11 +314,51 @@ impl L2capServer {
                 let _status: Status = data.read_le()?;
                 let handle: u16 = data.read_le()?;
                 let current_mode: ConnectionMode = data.read_le()?;
-                let _interval: u16 = data.read_le()?;
+                let interval: u16 = data.read_le()?;
                 data.finish()?;
-                self.get_connection(handle)?.mode = current_mode;
+                let connection = self.get_connection(handle)?;
+                connection.mode = current_mode;
+                connection.link_listeners.dispatch(LinkEvent::ModeChanged {
+                    mode: current_mode,
+                    interval: BASE_BAND_SLOT * interval as u32
+                });
                 debug!("Mode change for {:#04x}: {:?}", handle, current_mode);
             }
+            EventCode::RoleChange => {
+                // ([Vol 4] Part E, Section 7.7.18).
+                let status: Status = data.read_le()?;
+                let addr: BdAddr = data.read_le()?;
+                let role: Role = data.read_le()?;
+                data.finish()?;
+                if status.is_ok() {
+                    if let Some(connection) = self.connections.values_mut().find(|c| c.addr == addr) {
+                        connection.link_listeners.dispatch(LinkEvent::RoleChanged(role));
+                    }
+                }
+            }
+            EventCode::EncryptionChange => {
+                // ([Vol 4] Part E, Section 7.7.8).
+                let status: Status = data.read_le()?;
+                let handle: u16 = data.read_le()?;
+                let mode: EncryptionMode = data.read_le()?;
+                data.finish()?;
+                if status.is_ok() {
+                    self.get_connection(handle)?
+                        .link_listeners
+                        .dispatch(LinkEvent::EncryptionChanged(mode));
+                }
+            }
+            EventCode::EncryptionKeyRefreshComplete => {
+                // ([Vol 4] Part E, Section 7.7.39).
+                let status: Status = data.read_le()?;
+                let handle: u16 = data.read_le()?;
+                data.finish()?;
+                if status.is_ok() {
+                    self.get_connection(handle)?
+                        .link_listeners
+                        .dispatch(LinkEvent::EncryptionKeyRefreshed);
+                }
+            }
             _ => unreachable!()
         }
         Ok(())
@@ -213,6 +378,7 @@ impl L2capServer {
     }
 
     // ([Vol 3] Part A, Section 3.1).
+    #[cfg_attr(feature = "flamegraph", inline(never))]
     fn handle_l2cap_packet(&mut self, handle: u16, mut data: Bytes) -> Result<(), Error> {
         let L2capHeader { cid, .. } = data.read()?;
 
@@ -221,7 +387,7 @@ impl L2capServer {
         match cid {
             CID_ID_NONE => Err(Error::BadPacket(instructor::Error::InvalidValue)),
             CID_ID_SIGNALING => self.handle_l2cap_signaling(handle, data),
-            cid if CID_RANGE_DYNAMIC.contains(&cid) => self.send_channel_msg(cid, ChannelEvent::DataReceived(data)),
+            cid if CID_RANGE_DYNAMIC.contains(&cid) => self.send_channel_data(cid, data),
             _ => {
                 warn!("Unhandled L2CAP CID: {:04X}", cid);
                 Ok(())
@@ -232,20 +398,112 @@ impl L2capServer {
     pub fn new_channel(&mut self, handle: u16) -> Option<Channel> {
         assert!(self.connections.contains_key(&handle));
         self.channels.retain(|_, tx| !tx.is_closed());
+        let channels = &self.channels;
+        self.channel_counters.retain(|cid, _| channels.contains_key(cid));
         let scid = CID_RANGE_DYNAMIC
             .clone()
             .find(|&cid| !self.channels.contains_key(&cid))?;
         let (tx, rx) = unbounded_channel();
         self.channels.insert(scid, tx);
-        let channel = Channel::new(
+        let counters = Arc::new(Counters::default());
+        self.channel_counters.insert(scid, counters.clone());
+        let (link_tx, link_rx) = unbounded_channel();
+        let connection = self.connections.get_mut(&handle)?;
+        connection.link_listeners.push(link_tx);
+        connection.local_cids.push(scid);
+        let mut channel = Channel::new(
             handle,
+            connection.addr,
             scid,
             rx,
+            link_rx,
             self.sender.clone(),
-            self.next_signaling_id.clone()
+            self.next_signaling_id.clone(),
+            self.opener.clone()
         );
+        channel.set_retry_policy(self.retry_policy);
+        channel.set_stats_recorder(StatsRecorder::new(counters, self.psm_counters.clone()));
+        #[cfg(feature = "fault-injection")]
+        channel.set_fault_injector(self.fault_injector.clone());
         Some(channel)
     }
+
+    pub fn channel_opener(&self) -> ChannelOpener {
+        self.opener.clone()
+    }
+
+    pub fn inspector(&self) -> L2capInspector {
+        self.inspector.clone()
+    }
+
+    fn snapshot(&mut self) -> Vec<ConnectionSnapshot> {
+        self.channels.retain(|_, tx| !tx.is_closed());
+        let channels = &self.channels;
+        self.channel_counters.retain(|cid, _| channels.contains_key(cid));
+        let counters = &self.channel_counters;
+        self.connections
+            .values_mut()
+            .map(|connection| {
+                connection.local_cids.retain(|cid| channels.contains_key(cid));
+                ConnectionSnapshot {
+                    handle: connection.handle,
+                    addr: connection.addr,
+                    mode: format!("{:?}", connection.mode),
+                    max_slots: connection.max_slots,
+                    channels: connection.local_cids.clone(),
+                    channel_stats: connection
+                        .local_cids
+                        .iter()
+                        .filter_map(|cid| counters.get(cid).map(|c| (*cid, c.snapshot())))
+                        .collect()
+                }
+            })
+            .collect()
+    }
+}
+
+type SnapshotRequest = OneshotSender<Vec<ConnectionSnapshot>>;
+
+/// Retrieves the state of the [L2capServer] after it has been moved into its own task.
+#[derive(Clone)]
+pub struct L2capInspector(MpscSender<SnapshotRequest>, PsmCounters);
+
+impl L2capInspector {
+    pub async fn connections(&self) -> Option<Vec<ConnectionSnapshot>> {
+        let (tx, rx) = oneshot_channel();
+        self.0.send(tx).ok()?;
+        rx.await.ok()
+    }
+
+    /// The traffic counters summed over all channels of each PSM, including closed ones.
+    pub fn psm_stats(&self) -> BTreeMap<u64, ChannelStats> {
+        self.1.snapshot()
+    }
+}
+
+type ChannelRequest = (u16, OneshotSender<Option<Channel>>);
+
+/// Allows opening outgoing channels after the [L2capServer] has been moved into its own task.
+#[derive(Clone)]
+pub struct ChannelOpener(MpscSender<ChannelRequest>);
+
+impl ChannelOpener {
+    pub async fn new_channel(&self, handle: u16) -> Option<Channel> {
+        let (tx, rx) = oneshot_channel();
+        self.0.send((handle, tx)).ok()?;
+        rx.await.ok().flatten()
+    }
+
+    /// Connects and configures a new channel to `psm` on the given connection.
+    pub async fn open(&self, handle: u16, psm: u64) -> Result<Channel, ChannelError> {
+        let mut channel = self
+            .new_channel(handle)
+            .await
+            .ok_or(ChannelError::Disconnected)?;
+        channel.connect(psm).await?;
+        channel.configure().await?;
+        Ok(channel)
+    }
 }
 
 // ([Vol 3] Part A, Section 3.1).
@@ -316,7 +574,8 @@ impl SignalingIds {
 }
 
 pub enum ChannelEvent {
-    DataReceived(Bytes),
+    /// A PDU and the time it was received from the controller.
+    DataReceived(Bytes, Instant),
     ConnectionResponse {
         id: u8,
         remote_cid: u16,
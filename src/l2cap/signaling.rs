@@ -5,12 +5,16 @@ use instructor::utils::Length;
 use instructor::{Buffer, BufferMut, Exstruct, Instruct, LittleEndian};
 use tracing::{debug, error, instrument, trace, warn, Span};
 
-use crate::hci::{AclSendError, AclSender, Error};
+use crate::hci::{AclPriority, AclSendError, AclSender, Error};
 use crate::l2cap::configuration::ConfigurationParameter;
 use crate::l2cap::{ChannelEvent, ConfigureResult, ConnectionResult, ConnectionStatus, L2capHeader, L2capServer, CID_ID_SIGNALING, CID_RANGE_DYNAMIC};
-use crate::utils::{catch_error, IgnoreableResult};
+use crate::utils::{catch_error, spawn_supervised, IgnoreableResult};
 use crate::{ensure, log_assert};
 
+/// The largest C-frame accepted on the ACL-U signaling channel ([Vol 3] Part A, Section 4),
+/// the spec only requires 48 bytes.
+pub const SIGNALING_MTU: usize = 672;
+
 #[derive(Debug, Copy, Clone)]
 pub struct SignalingContext {
     pub handle: u16,
@@ -19,24 +23,30 @@ pub struct SignalingContext {
 
 impl AclSender {
     pub fn send_signaling<P: Instruct<LittleEndian>>(&self, ctx: SignalingContext, code: SignalingCode, parameters: P) -> Result<(), AclSendError> {
-        let mut data = BytesMut::new();
-        data.write(parameters);
-        let parameters = data.split().freeze();
-        data.write(L2capHeader {
-            len: Length::new(parameters.len() + 4)?,
-            cid: CID_ID_SIGNALING
-        });
-        data.write(SignalingHeader {
-            code,
-            id: ctx.id,
-            length: u16::try_from(parameters.len()).expect("Length overflow")
-        });
-        data.write_le(parameters);
+        let packet = signaling_packet(ctx.id, code, parameters)?;
         trace!(?code, id = ctx.id, "Sending signaling command");
-        self.send(ctx.handle, data.freeze())
+        self.send_with_priority(ctx.handle, packet, AclPriority::Normal)
     }
 }
 
+// ([Vol 3] Part A, Section 4).
+pub fn signaling_packet<P: Instruct<LittleEndian>>(id: u8, code: SignalingCode, parameters: P) -> Result<Bytes, instructor::Error> {
+    let mut data = BytesMut::new();
+    data.write(parameters);
+    let parameters = data.split().freeze();
+    data.write(L2capHeader {
+        len: Length::new(parameters.len() + 4)?,
+        cid: CID_ID_SIGNALING
+    });
+    data.write(SignalingHeader {
+        code,
+        id,
+        length: u16::try_from(parameters.len()).expect("Length overflow")
+    });
+    data.write_le(parameters);
+    Ok(data.freeze())
+}
+
 impl L2capServer {
     //fn send_response<F: FnOnce(&mut BytesMut)>(&self, ctx: SignalingContext, code: SignalingCode, writer: F) -> Result<(), Error> {
     //    let mut data = BytesMut::new();
@@ -57,15 +67,31 @@ impl L2capServer {
     // ([Vol 3] Part A, Section 4).
     #[instrument(skip(self, data))]
     pub fn handle_l2cap_signaling(&mut self, handle: u16, mut data: Bytes) -> Result<(), Error> {
-        // TODO: Send reject response when cid is unknown
+        if data.len() > SIGNALING_MTU {
+            warn!("Signaling packet exceeds the MTU ({} bytes)", data.len());
+            // ([Vol 3] Part A, Section 4.1) the reject uses the identifier of the first command
+            let SignalingHeader { id, .. } = data.read()?;
+            let reason = RejectReason::SignalingMtuExceeded { actual_mtu: SIGNALING_MTU as u16 };
+            self.sender
+                .send_signaling(SignalingContext { handle, id }, SignalingCode::CommandReject, reason)
+                .ignore();
+            return Ok(());
+        }
         while !data.is_empty() {
             let SignalingHeader { code, id, length } = data.read()?;
             Span::current()
                 .record("code", format_args!("{:?}", code))
                 .record("id", id);
+            let ctx = SignalingContext { handle, id };
+            if data.len() < length as usize {
+                warn!("Signaling command is longer than the packet");
+                self.sender
+                    .send_signaling(ctx, SignalingCode::CommandReject, RejectReason::CommandNotUnderstood)
+                    .ignore();
+                break;
+            }
             let mut data = data.split_to(length as usize);
 
-            let ctx = SignalingContext { handle, id };
             let result = catch_error(|| match code {
                 SignalingCode::CommandReject => {
                     let reason: RejectReason = data.read()?;
@@ -81,6 +107,11 @@ impl L2capServer {
                 SignalingCode::DisconnectionResponse => self.handle_disconnect_response(ctx, data),
                 SignalingCode::EchoRequest => self.handle_echo_request(ctx, data),
                 SignalingCode::InformationRequest => self.handle_information_request(ctx, data),
+                // Responses to requests this implementation never sends are dropped, only commands are rejected
+                SignalingCode::EchoResponse | SignalingCode::InformationResponse => {
+                    debug!("Ignoring unsolicited {:?}", code);
+                    Ok(())
+                }
                 _ => {
                     warn!("Command Unsupported");
                     Err(RejectReason::CommandNotUnderstood)
@@ -112,7 +143,25 @@ impl L2capServer {
             let mut channel = self.new_channel(ctx.handle)
                 .ok_or(ConnectionResult::RefusedNoResources)?;
             channel.connection_request_received(scid, ctx.id);
-            server.handle(channel);
+            channel.set_psm(psm);
+            match self.authorizer.as_ref().and_then(|auth| auth.authorize(channel.remote_addr(), psm)) {
+                Some(pending) => {
+                    // ([Vol 3] Part A, Section 4.3) the final response follows once the application decided
+                    self.sender
+                        .send_signaling(ctx, SignalingCode::ConnectionResponse, (
+                            channel.local_cid(), scid, ConnectionResult::Pending, ConnectionStatus::AuthorizationPending))
+                        .ignore();
+                    spawn_supervised("l2cap-authorization", format!("handle 0x{:04x}", ctx.handle), async move {
+                        match pending.granted().await {
+                            true => server.handle(channel),
+                            false => channel
+                                .refuse_connection(ConnectionResult::RefusedSecurityBlock)
+                                .unwrap_or_else(|err| warn!("Failed to reject connection: {:?}", err))
+                        }
+                    });
+                }
+                None => server.handle(channel)
+            }
             Ok(())
         });
         if let Err(result) = result {
@@ -137,7 +186,7 @@ impl L2capServer {
             remote_cid: dcid,
             result,
             status,
-        }).map_err(|_| RejectReason::InvalidCid { scid, dcid })
+        }).map_err(|_| RejectReason::InvalidCid { local_cid: scid, remote_cid: dcid })
     }
 
     // ([Vol 3] Part A, Section 4.4).
@@ -151,7 +200,7 @@ impl L2capServer {
         debug!("Configuration request: DCID={:04X}", dcid);
 
         self.send_channel_msg(dcid, ChannelEvent::ConfigurationRequest{ id: ctx.id, options })
-            .map_err(|_| RejectReason::InvalidCid { scid: 0, dcid })
+            .map_err(|_| RejectReason::InvalidCid { local_cid: dcid, remote_cid: 0 })
     }
 
     // ([Vol 3] Part A, Section 4.5).
@@ -166,7 +215,7 @@ impl L2capServer {
         debug!("Configuration response: SCID={:04X}", scid);
 
         self.send_channel_msg(scid, ChannelEvent::ConfigurationResponse{ id: ctx.id, result, options })
-            .map_err(|_| RejectReason::InvalidCid { scid, dcid: 0 })
+            .map_err(|_| RejectReason::InvalidCid { local_cid: scid, remote_cid: 0 })
     }
 
     // ([Vol 3] Part A, Section 4.6).
@@ -180,7 +229,7 @@ impl L2capServer {
                 let _ = channel.send(ChannelEvent::DisconnectRequest { id: ctx.id });
                 Ok(())
             }
-            None => Err(RejectReason::InvalidCid { scid, dcid })
+            None => Err(RejectReason::InvalidCid { local_cid: dcid, remote_cid: scid })
         }
     }
 
@@ -335,12 +384,12 @@ impl Instruct<LittleEndian> for Psm {
 }
 
 // ([Vol 3] Part A, Section 4.1).
-#[allow(dead_code)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RejectReason {
     CommandNotUnderstood,
     SignalingMtuExceeded { actual_mtu: u16 },
-    InvalidCid { scid: u16, dcid: u16 }
+    /// The endpoints are seen from the sender of the reject, `local_cid` is the destination CID of the rejected command.
+    InvalidCid { local_cid: u16, remote_cid: u16 }
 }
 
 impl Instruct<LittleEndian> for RejectReason {
@@ -353,10 +402,10 @@ impl Instruct<LittleEndian> for RejectReason {
                 buffer.write_le(0x0001u16);
                 buffer.write_le(actual_mtu);
             }
-            RejectReason::InvalidCid { scid, dcid } => {
+            RejectReason::InvalidCid { local_cid, remote_cid } => {
                 buffer.write_le(0x0002u16);
-                buffer.write_le(scid);
-                buffer.write_le(dcid);
+                buffer.write_le(local_cid);
+                buffer.write_le(remote_cid);
             }
         }
     }
@@ -372,9 +421,9 @@ impl Exstruct<LittleEndian> for RejectReason {
                 Ok(RejectReason::SignalingMtuExceeded { actual_mtu })
             }
             0x0002 => {
-                let scid: u16 = buffer.read_le()?;
-                let dcid: u16 = buffer.read_le()?;
-                Ok(RejectReason::InvalidCid { scid, dcid })
+                let local_cid: u16 = buffer.read_le()?;
+                let remote_cid: u16 = buffer.read_le()?;
+                Ok(RejectReason::InvalidCid { local_cid, remote_cid })
             }
             _ => Err(instructor::Error::InvalidValue)
         }
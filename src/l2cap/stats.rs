@@ -0,0 +1,155 @@
+//! Traffic counters of the channels and of all channels of a PSM.
+//! With the `metrics` feature the counters are also reported to the [metrics](https://docs.rs/metrics) facade,
+//! labeled with the PSM of the channel.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+/// A snapshot of the counters of a channel, or the sum over all channels of a PSM.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelStats {
+    /// The payload bytes of the received PDUs.
+    pub bytes_in: u64,
+    pub packets_in: u64,
+    /// The bytes of the SDUs written to the channel.
+    pub bytes_out: u64,
+    pub packets_out: u64,
+    /// Received PDUs that were discarded, e.g. because the channel was not open.
+    pub dropped: u64,
+    /// Retransmitted I-frames, stays zero until the enhanced retransmission mode is supported.
+    pub retransmissions: u64,
+    /// Configuration requests that were repeated or revised.
+    pub configuration_retries: u64
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    bytes_in: AtomicU64,
+    packets_in: AtomicU64,
+    bytes_out: AtomicU64,
+    packets_out: AtomicU64,
+    dropped: AtomicU64,
+    retransmissions: AtomicU64,
+    configuration_retries: AtomicU64
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            retransmissions: self.retransmissions.load(Ordering::Relaxed),
+            configuration_retries: self.configuration_retries.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// The per PSM counters of an [L2capServer](crate::l2cap::L2capServer), shared with its channels.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PsmCounters(Arc<Mutex<BTreeMap<u64, Arc<Counters>>>>);
+
+impl PsmCounters {
+    fn get(&self, psm: u64) -> Arc<Counters> {
+        self.0.lock().entry(psm).or_default().clone()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<u64, ChannelStats> {
+        self.0
+            .lock()
+            .iter()
+            .map(|(psm, counters)| (*psm, counters.snapshot()))
+            .collect()
+    }
+}
+
+/// Updates the counters of a channel and, once its PSM is known, those of the PSM.
+#[derive(Debug)]
+pub(crate) struct StatsRecorder {
+    channel: Arc<Counters>,
+    registry: PsmCounters,
+    psm: Option<(u64, Arc<Counters>)>
+}
+
+impl StatsRecorder {
+    pub fn new(channel: Arc<Counters>, registry: PsmCounters) -> Self {
+        Self {
+            channel,
+            registry,
+            psm: None
+        }
+    }
+
+    pub fn set_psm(&mut self, psm: u64) {
+        if self.psm.as_ref().map(|(current, _)| *current) != Some(psm) {
+            self.psm = Some((psm, self.registry.get(psm)));
+        }
+    }
+
+    pub fn snapshot(&self) -> ChannelStats {
+        self.channel.snapshot()
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.add(|c| &c.bytes_in, "bluefang_l2cap_received_bytes", bytes as u64);
+        self.add(|c| &c.packets_in, "bluefang_l2cap_received_packets", 1);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.add(|c| &c.bytes_out, "bluefang_l2cap_sent_bytes", bytes as u64);
+        self.add(|c| &c.packets_out, "bluefang_l2cap_sent_packets", 1);
+    }
+
+    pub fn dropped(&self) {
+        self.add(|c| &c.dropped, "bluefang_l2cap_dropped_packets", 1);
+    }
+
+    pub fn configuration_retry(&self) {
+        self.add(|c| &c.configuration_retries, "bluefang_l2cap_configuration_retries", 1);
+    }
+
+    fn add<F: Fn(&Counters) -> &AtomicU64>(&self, counter: F, _metric: &'static str, value: u64) {
+        counter(&self.channel).fetch_add(value, Ordering::Relaxed);
+        if let Some((_psm, counters)) = &self.psm {
+            counter(counters).fetch_add(value, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            metrics::counter!(_metric, "psm" => format!("0x{:04X}", _psm)).increment(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::l2cap::stats::{PsmCounters, StatsRecorder};
+    use crate::l2cap::AVDTP_PSM;
+
+    #[test]
+    fn psm_aggregates() {
+        let registry = PsmCounters::default();
+        let mut first = StatsRecorder::new(Arc::default(), registry.clone());
+        let mut second = StatsRecorder::new(Arc::default(), registry.clone());
+
+        // Traffic before the PSM is known only counts for the channel
+        first.dropped();
+        first.set_psm(AVDTP_PSM as u64);
+        second.set_psm(AVDTP_PSM as u64);
+        first.received(100);
+        second.received(20);
+        second.sent(8);
+        second.configuration_retry();
+
+        assert_eq!(first.snapshot().dropped, 1);
+        assert_eq!(first.snapshot().bytes_in, 100);
+        let total = registry.snapshot()[&(AVDTP_PSM as u64)];
+        assert_eq!((total.bytes_in, total.packets_in), (120, 2));
+        assert_eq!((total.bytes_out, total.packets_out), (8, 1));
+        assert_eq!((total.dropped, total.configuration_retries), (0, 1));
+    }
+}
@@ -0,0 +1,229 @@
+//! Framing of L2CAP streaming mode ([Vol 3] Part A, Section 8.6), which drops lost or late data instead of retransmitting it.
+use bytes::{BufMut, Bytes, BytesMut};
+use instructor::utils::Length;
+use instructor::{Buffer, BufferMut};
+use tracing::{debug, trace};
+
+use crate::l2cap::L2capHeader;
+
+const SEQUENCE_MODULUS: u8 = 64;
+
+// ([Vol 3] Part A, Section 3.3.2)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Sar {
+    Unsegmented = 0b00,
+    Start = 0b01,
+    End = 0b10,
+    Continuation = 0b11
+}
+
+impl Sar {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 0b11 {
+            0b00 => Sar::Unsegmented,
+            0b01 => Sar::Start,
+            0b10 => Sar::End,
+            _ => Sar::Continuation
+        }
+    }
+}
+
+/// The frame check sequence of an I-frame ([Vol 3] Part A, Section 3.3.5).
+pub fn fcs(data: &[u8]) -> u16 {
+    // CRC-16 with the polynomial x^16 + x^15 + x^2 + 1, processed lsb first
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xA001,
+                _ => crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+/// Splits outgoing SDUs into I-frames with the enhanced control field ([Vol 3] Part A, Section 3.3.2).
+#[derive(Debug)]
+pub struct StreamingTransmitter {
+    next_seq: u8,
+    mps: usize,
+    fcs: bool
+}
+
+impl StreamingTransmitter {
+    /// `mps` is the largest information payload the peer accepts.
+    pub fn new(mps: u16, fcs: bool) -> Self {
+        Self {
+            next_seq: 0,
+            mps: (mps as usize).max(1),
+            fcs
+        }
+    }
+
+    /// Returns the complete L2CAP PDUs that carry `sdu` to `remote_cid`.
+    pub fn frame(&mut self, remote_cid: u16, sdu: Bytes) -> Result<Vec<Bytes>, instructor::Error> {
+        if sdu.len() <= self.mps {
+            return Ok(vec![self.i_frame(remote_cid, Sar::Unsegmented, None, &sdu)?]);
+        }
+        let sdu_length = u16::try_from(sdu.len()).map_err(|_| instructor::Error::TooLong)?;
+        // The SDU length field of the start frame counts towards the payload
+        let mut frames = Vec::new();
+        let (first, mut rest) = sdu.split_at((self.mps - 2).max(1));
+        frames.push(self.i_frame(remote_cid, Sar::Start, Some(sdu_length), first)?);
+        while !rest.is_empty() {
+            let (segment, remaining) = rest.split_at(rest.len().min(self.mps));
+            let sar = match remaining.is_empty() {
+                true => Sar::End,
+                false => Sar::Continuation
+            };
+            frames.push(self.i_frame(remote_cid, sar, None, segment)?);
+            rest = remaining;
+        }
+        Ok(frames)
+    }
+
+    fn i_frame(&mut self, remote_cid: u16, sar: Sar, sdu_length: Option<u16>, payload: &[u8]) -> Result<Bytes, instructor::Error> {
+        let length = 2 + sdu_length.map_or(0, |_| 2) + payload.len() + if self.fcs { 2 } else { 0 };
+        let mut buffer = BytesMut::with_capacity(4 + length);
+        buffer.write_le(L2capHeader {
+            len: Length::new(length)?,
+            cid: remote_cid
+        });
+        // ReqSeq and the final bit are always zero in streaming mode
+        let control = (self.next_seq as u16) << 1 | (sar as u16) << 14;
+        self.next_seq = (self.next_seq + 1) % SEQUENCE_MODULUS;
+        buffer.write_le(control);
+        if let Some(sdu_length) = sdu_length {
+            buffer.write_le(sdu_length);
+        }
+        buffer.put_slice(payload);
+        if self.fcs {
+            let fcs = fcs(&buffer);
+            buffer.write_le(fcs);
+        }
+        Ok(buffer.freeze())
+    }
+}
+
+/// Reassembles incoming I-frames, dropping the SDUs that lost a frame ([Vol 3] Part A, Section 8.6.2).
+#[derive(Debug)]
+pub struct StreamingReceiver {
+    expected_seq: Option<u8>,
+    fcs: bool,
+    partial: Option<(usize, BytesMut)>
+}
+
+impl StreamingReceiver {
+    pub fn new(fcs: bool) -> Self {
+        Self {
+            expected_seq: None,
+            fcs,
+            partial: None
+        }
+    }
+
+    /// Processes the payload of a PDU received on `local_cid` and returns the SDU it completes.
+    pub fn receive(&mut self, local_cid: u16, mut frame: Bytes) -> Option<Bytes> {
+        if self.fcs {
+            if frame.len() < 4 {
+                debug!("Dropping truncated I-frame");
+                return None;
+            }
+            let expected = u16::from_le_bytes([frame[frame.len() - 2], frame[frame.len() - 1]]);
+            // The FCS covers the basic header, which was already removed
+            let mut covered = BytesMut::with_capacity(4 + frame.len());
+            covered.write_le(frame.len() as u16);
+            covered.write_le(local_cid);
+            covered.put_slice(&frame[..frame.len() - 2]);
+            if fcs(&covered) != expected {
+                debug!("Dropping I-frame with invalid FCS");
+                return None;
+            }
+            frame.truncate(frame.len() - 2);
+        }
+        let control: u16 = frame.read_le().ok()?;
+        if control & 0x0001 != 0 {
+            trace!("Ignoring S-frame in streaming mode");
+            return None;
+        }
+        let seq = ((control >> 1) & 0x3F) as u8;
+        if self.expected_seq.is_some_and(|expected| expected != seq) {
+            debug!("Missing I-frames before {}, dropping the partial SDU", seq);
+            self.partial = None;
+        }
+        self.expected_seq = Some((seq + 1) % SEQUENCE_MODULUS);
+        match Sar::from_bits(control >> 14) {
+            Sar::Unsegmented => {
+                self.partial = None;
+                Some(frame)
+            }
+            Sar::Start => {
+                let length: u16 = frame.read_le().ok()?;
+                let mut sdu = BytesMut::with_capacity(length as usize);
+                sdu.put(frame);
+                self.partial = Some((length as usize, sdu));
+                None
+            }
+            Sar::Continuation => {
+                let (_, sdu) = self.partial.as_mut()?;
+                sdu.put(frame);
+                None
+            }
+            Sar::End => {
+                let (length, mut sdu) = self.partial.take()?;
+                sdu.put(frame);
+                match sdu.len() == length {
+                    true => Some(sdu.freeze()),
+                    false => {
+                        debug!("Dropping SDU with invalid length");
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, Bytes};
+
+    use crate::l2cap::streaming::{fcs, StreamingReceiver, StreamingTransmitter};
+
+    #[test]
+    fn frame_check_sequence() {
+        // Example of an I-frame from ([Vol 3] Part A, Section 3.3.5)
+        let frame = [0x0E, 0x00, 0x40, 0x00, 0x02, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
+        assert_eq!(fcs(&frame), 0x6138);
+    }
+
+    #[test]
+    fn segmentation() {
+        let mut transmitter = StreamingTransmitter::new(4, true);
+        let mut receiver = StreamingReceiver::new(true);
+        let sdu = Bytes::from_static(b"streaming mode");
+        let frames = transmitter.frame(0x0040, sdu.clone()).unwrap();
+        assert_eq!(frames.len(), 4);
+        let strip_header = |mut frame: Bytes| {
+            frame.advance(4);
+            frame
+        };
+        let mut results: Vec<_> = frames
+            .iter()
+            .cloned()
+            .filter_map(|frame| receiver.receive(0x0040, strip_header(frame)))
+            .collect();
+        assert_eq!(results.pop(), Some(sdu.clone()));
+
+        // A lost continuation drops the whole SDU, the next one is still delivered
+        let mut frames = transmitter.frame(0x0040, sdu.clone()).unwrap();
+        frames.remove(1);
+        frames.extend(transmitter.frame(0x0040, Bytes::from_static(b"ok")).unwrap());
+        let results: Vec<_> = frames
+            .into_iter()
+            .filter_map(|frame| receiver.receive(0x0040, strip_header(frame)))
+            .collect();
+        assert_eq!(results, vec![Bytes::from_static(b"ok")]);
+    }
+}
@@ -1,12 +1,32 @@
 //TODO make private
+#[cfg(feature = "avdtp")]
 pub mod a2dp;
+pub mod adv;
+pub mod at;
+#[cfg(feature = "avrcp")]
 pub mod avc;
+#[cfg(feature = "avrcp")]
 pub mod avctp;
+#[cfg(feature = "avdtp")]
 pub mod avdtp;
+#[cfg(feature = "avrcp")]
 pub mod avrcp;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
 pub mod firmware;
 pub mod hci;
 pub mod host;
+#[cfg(feature = "ipc")]
+pub mod ipc;
 pub mod l2cap;
+#[cfg(feature = "mpris")]
+pub mod mpris;
+#[cfg(feature = "avrcp")]
+pub mod obex;
+pub mod profile;
+#[cfg(feature = "pts")]
+pub mod pts;
 pub mod sdp;
+pub mod storage;
 pub mod utils;
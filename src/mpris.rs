@@ -0,0 +1,332 @@
+//! Publishes an AVRCP session as MPRIS media player on the D-Bus session bus, so desktop environments show
+//! the track of the connected phone and their media keys control its playback.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+use zbus::fdo;
+use zbus::object_server::InterfaceRef;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::interface;
+
+use crate::avc::PassThroughOp;
+use crate::avrcp::notifications::{CurrentTrack, PlaybackStatus};
+use crate::avrcp::{AvrcpController, AvrcpSession, Event, EventId, MediaAttributeId};
+use crate::utils::redact::redacted;
+
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+#[derive(Default)]
+struct PlayerState {
+    status: PlaybackStatus,
+    track: CurrentTrack,
+    attributes: BTreeMap<MediaAttributeId, String>,
+    volume: f32
+}
+
+/// Serves the MPRIS interfaces for `session` until the session is closed.
+/// `identity` is the player name shown by the desktop environment.
+pub async fn serve(mut session: AvrcpSession, identity: &str) -> zbus::Result<()> {
+    let controller = session.controller();
+    let state = Arc::new(Mutex::new(PlayerState {
+        volume: 1.0,
+        ..Default::default()
+    }));
+    // Bus name elements may only contain alphanumerics and underscores
+    let bus_name = format!("org.mpris.MediaPlayer2.bluefang.dev_{}", controller.remote_addr().to_string().replace(':', "_"));
+    let connection = zbus::connection::Builder::session()?
+        .name(bus_name)?
+        .serve_at(OBJECT_PATH, Root { identity: identity.to_string() })?
+        .serve_at(OBJECT_PATH, Player {
+            controller: controller.clone(),
+            state: state.clone()
+        })?
+        .build()
+        .await?;
+    let player: InterfaceRef<Player> = connection
+        .object_server()
+        .interface(OBJECT_PATH)
+        .await?;
+    debug!("Publishing {} as MPRIS player", redacted(&controller.remote_addr()));
+
+    let supported_events = session.get_supported_events().await.unwrap_or_default();
+    if supported_events.contains(&EventId::TrackChanged) {
+        update_track(&controller, &state, &player).await;
+    }
+    if supported_events.contains(&EventId::PlaybackStatusChanged) {
+        update_status(&controller, &state, &player).await;
+    }
+    while let Some(event) = session.next_event().await {
+        match event {
+            Event::TrackChanged(_) => update_track(&controller, &state, &player).await,
+            Event::PlaybackStatusChanged(_) => update_status(&controller, &state, &player).await,
+            Event::VolumeChanged(volume, _) => {
+                state.lock().volume = volume;
+                player
+                    .get()
+                    .await
+                    .volume_changed(player.signal_context())
+                    .await
+                    .unwrap_or_else(|err| warn!("Failed to signal volume change: {:?}", err));
+            }
+            _ => {}
+        }
+    }
+    debug!("AVRCP session closed, removing MPRIS player");
+    drop(connection);
+    Ok(())
+}
+
+/// Re-registers the track notification and fetches the metadata of the new track.
+async fn update_track(controller: &AvrcpController, state: &Mutex<PlayerState>, player: &InterfaceRef<Player>) {
+    let track: CurrentTrack = match controller.register_notification(None).await {
+        Ok(track) => track,
+        Err(err) => return warn!("Failed to register for track changes: {:?}", err)
+    };
+    let attributes = match track {
+        CurrentTrack::NotSelected => BTreeMap::new(),
+        _ => controller
+            .get_current_media_attributes(None)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Failed to retrieve track metadata: {:?}", err);
+                BTreeMap::new()
+            })
+    };
+    {
+        let mut state = state.lock();
+        state.track = track;
+        state.attributes = attributes;
+    }
+    player
+        .get()
+        .await
+        .metadata_changed(player.signal_context())
+        .await
+        .unwrap_or_else(|err| warn!("Failed to signal metadata change: {:?}", err));
+}
+
+/// Re-registers the playback status notification and publishes the new status.
+async fn update_status(controller: &AvrcpController, state: &Mutex<PlayerState>, player: &InterfaceRef<Player>) {
+    match controller.register_notification::<PlaybackStatus>(None).await {
+        Ok(status) => state.lock().status = status,
+        Err(err) => return warn!("Failed to register for playback status changes: {:?}", err)
+    }
+    player
+        .get()
+        .await
+        .playback_status_changed(player.signal_context())
+        .await
+        .unwrap_or_else(|err| warn!("Failed to signal playback status change: {:?}", err));
+}
+
+struct Root {
+    identity: String
+}
+
+// (MPRIS org.mpris.MediaPlayer2).
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        self.identity.clone()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct Player {
+    controller: AvrcpController,
+    state: Arc<Mutex<PlayerState>>
+}
+
+impl Player {
+    async fn action(&self, op: PassThroughOp) -> fdo::Result<()> {
+        self.controller
+            .action(op)
+            .await
+            .map_err(|err| fdo::Error::Failed(format!("{:?}", err)))
+    }
+}
+
+// (MPRIS org.mpris.MediaPlayer2.Player).
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    async fn play(&self) -> fdo::Result<()> {
+        self.action(PassThroughOp::Play).await
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.action(PassThroughOp::Pause).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        let playing = self.state.lock().status == PlaybackStatus::Playing;
+        self.action(if playing { PassThroughOp::Pause } else { PassThroughOp::Play })
+            .await
+    }
+
+    async fn stop(&self) -> fdo::Result<()> {
+        self.action(PassThroughOp::Stop).await
+    }
+
+    async fn next(&self) -> fdo::Result<()> {
+        self.action(PassThroughOp::Forward).await
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.action(PassThroughOp::Backward).await
+    }
+
+    fn seek(&self, _offset: i64) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(String::from("Seeking is not supported")))
+    }
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(String::from("Seeking is not supported")))
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        match self.state.lock().status {
+            PlaybackStatus::Playing | PlaybackStatus::FwdSeek | PlaybackStatus::RevSeek => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped | PlaybackStatus::Error => "Stopped"
+        }
+        .to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let state = self.state.lock();
+        let mut metadata = HashMap::new();
+        let track_id = match state.track {
+            CurrentTrack::Id(id) => format!("{}/track/{}", OBJECT_PATH, id),
+            CurrentTrack::Selected => format!("{}/track/current", OBJECT_PATH),
+            CurrentTrack::NotSelected => String::from("/org/mpris/MediaPlayer2/TrackList/NoTrack")
+        };
+        insert(&mut metadata, "mpris:trackid", ObjectPath::try_from(track_id).expect("Invalid track path"));
+        for (id, value) in &state.attributes {
+            match id {
+                MediaAttributeId::Title => insert(&mut metadata, "xesam:title", value.as_str()),
+                MediaAttributeId::ArtistName => insert(&mut metadata, "xesam:artist", vec![value.as_str()]),
+                MediaAttributeId::AlbumName => insert(&mut metadata, "xesam:album", value.as_str()),
+                MediaAttributeId::Genre => insert(&mut metadata, "xesam:genre", vec![value.as_str()]),
+                MediaAttributeId::TrackNumber => {
+                    if let Ok(number) = value.parse::<i32>() {
+                        insert(&mut metadata, "xesam:trackNumber", number);
+                    }
+                }
+                // ([AVRCP] Section 26), the playing time is in milliseconds
+                MediaAttributeId::PlayingTime => {
+                    if let Ok(millis) = value.parse::<u64>() {
+                        insert(&mut metadata, "mpris:length", Duration::from_millis(millis).as_micros() as i64);
+                    }
+                }
+                _ => {}
+            }
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.state.lock().volume as f64
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&mut self, volume: f64) -> zbus::Result<()> {
+        let volume = self
+            .controller
+            .set_absolute_volume(volume as f32)
+            .await
+            .map_err(|err| zbus::Error::Failure(format!("{:?}", err)))?;
+        self.state.lock().volume = volume;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+fn insert<'a, V: Into<Value<'a>>>(metadata: &mut HashMap<String, OwnedValue>, key: &str, value: V) {
+    // Only values containing file descriptors can fail to convert
+    let value = OwnedValue::try_from(value.into()).expect("Failed to convert metadata value");
+    metadata.insert(key.to_string(), value);
+}
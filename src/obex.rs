@@ -0,0 +1,397 @@
+//! A minimal OBEX client over L2CAP ([GOEP] Section 5, [OBEX] Section 3), as far as the cover art of AVRCP needs it.
+//! Every OBEX packet is carried in its own L2CAP SDU. Single response mode is not used, so every part of a response
+//! is requested individually.
+use bytes::{BufMut, Bytes, BytesMut};
+use instructor::{Buffer, BufferMut, Exstruct, Instruct};
+use thiserror::Error;
+use tracing::{trace, warn};
+
+use crate::ensure;
+use crate::l2cap::channel::{Channel, Error as L2capError};
+
+/// OBEX version 1.0 ([OBEX] Section 3.4.1).
+pub const VERSION: u8 = 0x10;
+/// The smallest maximum packet length a device may announce ([OBEX] Section 3.4.1).
+pub const MIN_PACKET_LENGTH: u16 = 255;
+/// The largest packet we accept, every packet has to fit into one SDU of the default L2CAP MTU.
+pub const MAX_PACKET_LENGTH: u16 = 1691;
+
+// ([OBEX] Section 3.4).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[repr(u8)]
+pub enum Opcode {
+    Connect = 0x80,
+    Disconnect = 0x81,
+    Put = 0x02,
+    PutFinal = 0x82,
+    Get = 0x03,
+    GetFinal = 0x83,
+    SetPath = 0x85,
+    Abort = 0xFF
+}
+
+// ([OBEX] Section 3.2.1), response codes always have the final bit set.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[repr(u8)]
+pub enum ResponseCode {
+    Continue = 0x90,
+    Success = 0xA0,
+    BadRequest = 0xC0,
+    Unauthorized = 0xC1,
+    Forbidden = 0xC3,
+    NotFound = 0xC4,
+    MethodNotAllowed = 0xC5,
+    NotAcceptable = 0xC6,
+    PreconditionFailed = 0xCC,
+    /// Also used for codes that are not listed here.
+    #[default]
+    #[instructor(default)]
+    InternalServerError = 0xD0,
+    NotImplemented = 0xD1,
+    ServiceUnavailable = 0xD3
+}
+
+/// Header identifiers, the upper two bits encode the type of the value ([OBEX] Section 2.1).
+pub mod header_ids {
+    pub const NAME: u8 = 0x01;
+    pub const TYPE: u8 = 0x42;
+    pub const LENGTH: u8 = 0xC3;
+    pub const TARGET: u8 = 0x46;
+    pub const BODY: u8 = 0x48;
+    pub const END_OF_BODY: u8 = 0x49;
+    pub const WHO: u8 = 0x4A;
+    pub const CONNECTION_ID: u8 = 0xCB;
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HeaderValue {
+    /// Null terminated UTF-16 on the wire.
+    Text(String),
+    Bytes(Bytes),
+    U8(u8),
+    U32(u32)
+}
+
+impl HeaderValue {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            HeaderValue::Text(text) => Some(text),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match self {
+            HeaderValue::Bytes(bytes) => Some(bytes),
+            _ => None
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            HeaderValue::U32(value) => Some(*value),
+            _ => None
+        }
+    }
+}
+
+// ([OBEX] Section 2.1).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Header {
+    pub id: u8,
+    pub value: HeaderValue
+}
+
+impl Header {
+    pub fn text(id: u8, text: &str) -> Self {
+        debug_assert_eq!(id >> 6, 0b00, "Header {:#04x} is not a text header", id);
+        Self { id, value: HeaderValue::Text(text.to_string()) }
+    }
+
+    pub fn bytes<B: Into<Bytes>>(id: u8, bytes: B) -> Self {
+        debug_assert_eq!(id >> 6, 0b01, "Header {:#04x} is not a byte sequence header", id);
+        Self { id, value: HeaderValue::Bytes(bytes.into()) }
+    }
+
+    pub fn u32(id: u8, value: u32) -> Self {
+        debug_assert_eq!(id >> 6, 0b11, "Header {:#04x} is not a four byte header", id);
+        Self { id, value: HeaderValue::U32(value) }
+    }
+
+    /// The type header, a null terminated ASCII string like `text/plain` ([OBEX] Section 2.2.3).
+    pub fn object_type(mime_type: &str) -> Self {
+        let mut value = BytesMut::with_capacity(mime_type.len() + 1);
+        value.put_slice(mime_type.as_bytes());
+        value.put_u8(0);
+        Self::bytes(header_ids::TYPE, value)
+    }
+
+    fn write(&self, buffer: &mut BytesMut) -> Result<(), instructor::Error> {
+        buffer.write_be(self.id);
+        match &self.value {
+            HeaderValue::Text(text) => {
+                // An empty text is sent without terminator
+                let units: Vec<u16> = text.encode_utf16().chain((!text.is_empty()).then_some(0)).collect();
+                buffer.write_be(header_length(2 * units.len())?);
+                units.into_iter().for_each(|unit| buffer.write_be(unit));
+            }
+            HeaderValue::Bytes(bytes) => {
+                buffer.write_be(header_length(bytes.len())?);
+                buffer.put_slice(bytes);
+            }
+            HeaderValue::U8(value) => buffer.write_be(*value),
+            HeaderValue::U32(value) => buffer.write_be(*value)
+        }
+        Ok(())
+    }
+
+    fn read(data: &mut Bytes) -> Result<Self, instructor::Error> {
+        let id: u8 = data.read_be()?;
+        let value = match id >> 6 {
+            0b00 | 0b01 => {
+                let length: u16 = data.read_be()?;
+                let length = (length as usize)
+                    .checked_sub(3)
+                    .ok_or(instructor::Error::InvalidValue)?;
+                ensure!(data.len() >= length, instructor::Error::TooShort);
+                let mut value = data.split_to(length);
+                match id >> 6 {
+                    0b00 => {
+                        ensure!(length % 2 == 0, instructor::Error::InvalidValue);
+                        let mut units = Vec::with_capacity(length / 2);
+                        while !value.is_empty() {
+                            units.push(value.read_be::<u16>()?);
+                        }
+                        if units.last() == Some(&0) {
+                            units.pop();
+                        }
+                        HeaderValue::Text(String::from_utf16(&units).map_err(|_| instructor::Error::InvalidValue)?)
+                    }
+                    _ => HeaderValue::Bytes(value)
+                }
+            }
+            0b10 => HeaderValue::U8(data.read_be()?),
+            _ => HeaderValue::U32(data.read_be()?)
+        };
+        Ok(Self { id, value })
+    }
+}
+
+fn header_length(value_length: usize) -> Result<u16, instructor::Error> {
+    u16::try_from(value_length + 3).map_err(|_| instructor::Error::TooLong)
+}
+
+/// The fields of connect requests and responses that precede the headers ([OBEX] Section 3.4.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Instruct, Exstruct)]
+#[instructor(endian = "big")]
+pub struct ConnectParameters {
+    pub version: u8,
+    pub flags: u8,
+    pub max_packet_length: u16
+}
+
+// ([OBEX] Section 3.1).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Packet {
+    /// The opcode of a request or the code of a response, both including the final bit.
+    pub code: u8,
+    pub connect: Option<ConnectParameters>,
+    pub headers: Vec<Header>
+}
+
+impl Packet {
+    pub fn request(opcode: Opcode) -> Self {
+        Self {
+            code: opcode as u8,
+            connect: None,
+            headers: Vec::new()
+        }
+    }
+
+    pub fn response(code: ResponseCode) -> Self {
+        Self {
+            code: code as u8,
+            connect: None,
+            headers: Vec::new()
+        }
+    }
+
+    pub fn with_connect(mut self, parameters: ConnectParameters) -> Self {
+        self.connect = Some(parameters);
+        self
+    }
+
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// The value of the first header with `id`.
+    pub fn header(&self, id: u8) -> Option<&HeaderValue> {
+        self.headers
+            .iter()
+            .find(|header| header.id == id)
+            .map(|header| &header.value)
+    }
+
+    pub fn response_code(&self) -> ResponseCode {
+        [self.code].as_slice().read_be().unwrap_or_default()
+    }
+
+    pub fn encode(&self) -> Result<Bytes, instructor::Error> {
+        let mut buffer = BytesMut::new();
+        buffer.write_be(self.code);
+        buffer.write_be(0u16);
+        if let Some(connect) = self.connect {
+            buffer.write(connect);
+        }
+        for header in &self.headers {
+            header.write(&mut buffer)?;
+        }
+        let length = u16::try_from(buffer.len()).map_err(|_| instructor::Error::TooLong)?;
+        buffer[1..3].copy_from_slice(&length.to_be_bytes());
+        Ok(buffer.freeze())
+    }
+
+    /// `connect` tells if the packet is a connect request or the response to one, which carry [ConnectParameters].
+    pub fn decode(mut data: Bytes, connect: bool) -> Result<Self, instructor::Error> {
+        let code: u8 = data.read_be()?;
+        let length: u16 = data.read_be()?;
+        ensure!(length as usize == data.len() + 3, instructor::Error::InvalidValue);
+        let connect = match connect {
+            true => Some(data.read()?),
+            false => None
+        };
+        let mut headers = Vec::new();
+        while !data.is_empty() {
+            headers.push(Header::read(&mut data)?);
+        }
+        Ok(Self { code, connect, headers })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Channel(#[from] L2capError),
+    #[error("Invalid OBEX packet: {0}")]
+    InvalidPacket(#[from] instructor::Error),
+    #[error("The OBEX server answered with {0:?}.")]
+    Response(ResponseCode),
+    #[error("The object exceeds the size limit.")]
+    TooLarge
+}
+
+/// An OBEX connection to a server on an L2CAP channel.
+pub struct ObexClient {
+    channel: Channel,
+    connection_id: Option<u32>,
+    /// The largest packet the server accepts.
+    max_packet_length: u16
+}
+
+impl ObexClient {
+    /// Connects to the service `target` of the server, or to its default service without target ([OBEX] Section 3.4.1).
+    pub async fn connect(mut channel: Channel, target: Option<Bytes>) -> Result<Self, Error> {
+        let mut request = Packet::request(Opcode::Connect).with_connect(ConnectParameters {
+            version: VERSION,
+            flags: 0,
+            max_packet_length: MAX_PACKET_LENGTH
+        });
+        if let Some(target) = target {
+            request = request.with_header(Header::bytes(header_ids::TARGET, target));
+        }
+        let response = transact(&mut channel, &request, MIN_PACKET_LENGTH).await?;
+        ensure!(response.response_code() == ResponseCode::Success, Error::Response(response.response_code()));
+        let max_packet_length = response
+            .connect
+            .map_or(MIN_PACKET_LENGTH, |connect| connect.max_packet_length.max(MIN_PACKET_LENGTH))
+            .min(channel.remote_mtu());
+        let connection_id = response
+            .header(header_ids::CONNECTION_ID)
+            .and_then(HeaderValue::as_u32);
+        trace!("OBEX connection established (id: {:?}, max packet length: {})", connection_id, max_packet_length);
+        Ok(Self {
+            channel,
+            connection_id,
+            max_packet_length
+        })
+    }
+
+    /// Fetches the object described by `headers` and returns its body ([OBEX] Section 3.4.5).
+    /// Bodies larger than `max_size` abort the operation.
+    pub async fn get(&mut self, headers: Vec<Header>, max_size: usize) -> Result<Bytes, Error> {
+        let mut request = self.request(Opcode::GetFinal);
+        request.headers.extend(headers);
+        let mut body = BytesMut::new();
+        loop {
+            let response = transact(&mut self.channel, &request, self.max_packet_length).await?;
+            for header in &response.headers {
+                if let (header_ids::BODY | header_ids::END_OF_BODY, HeaderValue::Bytes(data)) = (header.id, &header.value) {
+                    body.extend_from_slice(data);
+                }
+            }
+            if body.len() > max_size {
+                let abort = self.request(Opcode::Abort);
+                if let Err(err) = transact(&mut self.channel, &abort, self.max_packet_length).await {
+                    warn!("Failed to abort OBEX operation: {:?}", err);
+                }
+                return Err(Error::TooLarge);
+            }
+            match response.response_code() {
+                ResponseCode::Continue => request = self.request(Opcode::GetFinal),
+                ResponseCode::Success => return Ok(body.freeze()),
+                code => return Err(Error::Response(code))
+            }
+        }
+    }
+
+    /// Ends the OBEX connection and closes the channel ([OBEX] Section 3.4.2).
+    pub async fn disconnect(mut self) -> Result<(), Error> {
+        let request = self.request(Opcode::Disconnect);
+        transact(&mut self.channel, &request, self.max_packet_length).await?;
+        self.channel.disconnect().await?;
+        Ok(())
+    }
+
+    /// A request that starts with the connection id header, which has to be the first header ([OBEX] Section 2.2.11).
+    fn request(&self, opcode: Opcode) -> Packet {
+        let request = Packet::request(opcode);
+        match self.connection_id {
+            Some(id) => request.with_header(Header::u32(header_ids::CONNECTION_ID, id)),
+            None => request
+        }
+    }
+}
+
+async fn transact(channel: &mut Channel, request: &Packet, max_packet_length: u16) -> Result<Packet, Error> {
+    let data = request.encode()?;
+    ensure!(data.len() <= max_packet_length as usize, instructor::Error::TooLong);
+    channel.write(data).await?;
+    let response = channel.read().await.ok_or(L2capError::Disconnected)?;
+    Ok(Packet::decode(response, request.code == Opcode::Connect as u8)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::obex::{header_ids, Header, HeaderValue, Packet, ResponseCode};
+
+    #[test]
+    fn packets() {
+        let response = Packet::response(ResponseCode::Success)
+            .with_header(Header::u32(header_ids::CONNECTION_ID, 7))
+            .with_header(Header::text(header_ids::NAME, "Cover ♫"))
+            .with_header(Header::bytes(header_ids::END_OF_BODY, Bytes::from_static(&[1, 2, 3])));
+        let decoded = Packet::decode(response.encode().unwrap(), false).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(decoded.header(header_ids::NAME).and_then(HeaderValue::as_text), Some("Cover ♫"));
+        assert_eq!(decoded.response_code(), ResponseCode::Success);
+
+        // Unknown response codes are read as internal server error, truncated headers are rejected
+        let unknown = Packet::decode(Bytes::from_static(&[0xC7, 0x00, 0x03]), false).unwrap();
+        assert_eq!(unknown.response_code(), ResponseCode::InternalServerError);
+        assert!(Packet::decode(Bytes::from_static(&[0xA0, 0x00, 0x06, 0x48, 0x00, 0x05]), false).is_err());
+    }
+}
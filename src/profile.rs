@@ -0,0 +1,353 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use bitflags::bitflags;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+#[cfg(feature = "avdtp")]
+use crate::avdtp::AvdtpSessionSnapshot;
+#[cfg(feature = "avrcp")]
+use crate::avrcp::AvrcpSessionSnapshot;
+use crate::hci::consts::{ClassOfDevice, DeviceClass, MajorServiceClasses};
+#[cfg(feature = "sdp-server")]
+use crate::hci::DeviceId;
+use crate::hci::{Error, Hci};
+use crate::l2cap::authorization::ConnectionAuthorizer;
+use crate::l2cap::{ChannelOpener, ConnectionSnapshot, L2capInspector, L2capServerBuilder, ProtocolHandler, ProtocolHandlerProvider};
+use crate::sdp::ServiceRecord;
+#[cfg(feature = "sdp-server")]
+use crate::sdp::{DeviceIdServiceRecord, SdpBuilder};
+#[cfg(feature = "fault-injection")]
+use crate::utils::fault::FaultInjector;
+use crate::utils::spawn_supervised;
+
+/// A Bluetooth profile consisting of SDP records, L2CAP protocol handlers and an optional lifecycle.
+pub trait Profile: ProtocolHandlerProvider + Send + Sync {
+    /// Short name used in the manifest and in log messages.
+    fn name(&self) -> &'static str;
+
+    /// The service records advertising this profile.
+    /// `handles` hands out service record handles that are not used by any other profile.
+    fn service_records(&self, handles: &mut RecordHandles) -> Vec<Box<dyn ServiceRecord>>;
+
+    /// The major service classes a device offering this profile should advertise ([Assigned Numbers] Section 2.8.1).
+    fn service_classes(&self) -> MajorServiceClasses {
+        MajorServiceClasses::empty()
+    }
+
+    /// The roles this profile takes, used to check that the registered profiles fit together.
+    fn roles(&self) -> ProfileRoles {
+        ProfileRoles::empty()
+    }
+
+    /// Called after the L2CAP server is running.
+    fn start(&self, _hci: &Arc<Hci>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when the stack shuts down.
+    fn stop(&self) {}
+
+    /// The current state of the sessions of this profile, see [ProfileStack::debug_snapshot].
+    fn debug_snapshot(&self) -> Option<ProfileSnapshot> {
+        None
+    }
+}
+
+bitflags! {
+    /// The roles of the audio profiles, see [ProfileRegistry::validate].
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct ProfileRoles: u8 {
+        const A2DP_SINK = 1 << 0;
+        const A2DP_SOURCE = 1 << 1;
+        const AVRCP_CONTROLLER = 1 << 2;
+        const AVRCP_TARGET = 1 << 3;
+    }
+}
+
+/// The state of the sessions of a profile for debugging.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ProfileSnapshot {
+    #[cfg(feature = "avdtp")]
+    Avdtp(Vec<AvdtpSessionSnapshot>),
+    #[cfg(feature = "avrcp")]
+    Avrcp(Vec<AvrcpSessionSnapshot>)
+}
+
+/// The state of the whole stack for debugging, see [ProfileStack::debug_snapshot].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StackSnapshot {
+    pub connections: Vec<ConnectionSnapshot>,
+    pub profiles: Vec<ProfileSnapshot>
+}
+
+/// Allocates service record handles outside the reserved range ([Vol 3] Part B, Section 2.2).
+#[derive(Debug)]
+pub struct RecordHandles {
+    next: u32
+}
+
+impl Default for RecordHandles {
+    fn default() -> Self {
+        Self { next: 0x00010001 }
+    }
+}
+
+impl RecordHandles {
+    pub fn allocate(&mut self) -> u32 {
+        let handle = self.next;
+        self.next = self.next.checked_add(1).expect("Ran out of service record handles");
+        handle
+    }
+}
+
+/// Describes a registered profile.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProfileInfo {
+    pub name: &'static str,
+    pub psms: Vec<u64>,
+    pub record_handles: Vec<u32>,
+    pub service_classes: MajorServiceClasses,
+    pub roles: ProfileRoles
+}
+
+impl Display for ProfileInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (PSMs: {:04X?}, records: {:08X?})", self.name, self.psms, self.record_handles)
+    }
+}
+
+/// Collects profiles and starts them together with the SDP server.
+/// Without the `sdp-server` feature the service records only reserve their handles and are not published.
+#[derive(Default)]
+pub struct ProfileRegistry {
+    profiles: Vec<Arc<dyn Profile>>,
+    manifest: Vec<ProfileInfo>,
+    handles: RecordHandles,
+    #[cfg(feature = "sdp-server")]
+    sdp: SdpBuilder,
+    l2cap: L2capServerBuilder
+}
+
+impl ProfileRegistry {
+    pub fn with_profile<P: Profile + 'static>(mut self, profile: P) -> Self {
+        let records = profile.service_records(&mut self.handles);
+        let record_handles = records.iter().map(|record| record.handle()).collect();
+        #[cfg(feature = "sdp-server")]
+        for record in records {
+            self.sdp = self.sdp.with_record(record);
+        }
+        let handlers = profile.protocol_handlers();
+        let psms = handlers.iter().map(|handler| handler.psm()).collect();
+        self.l2cap = self.l2cap.with_protocol(HandlerList(handlers));
+        self.manifest.push(ProfileInfo {
+            name: profile.name(),
+            psms,
+            record_handles,
+            service_classes: profile.service_classes(),
+            roles: profile.roles()
+        });
+        self.profiles.push(Arc::new(profile));
+        self
+    }
+
+    /// Adds a service record that does not belong to any registered profile.
+    #[cfg(feature = "sdp-server")]
+    pub fn with_record<T: ServiceRecord>(mut self, record: T) -> Self {
+        self.sdp = self.sdp.with_record(record);
+        self
+    }
+
+    /// Lets `authorizer` hold back the connections of untrusted devices to all profiles, see [L2capServerBuilder::with_authorizer].
+    pub fn with_authorizer(mut self, authorizer: ConnectionAuthorizer) -> Self {
+        self.l2cap = self.l2cap.with_authorizer(authorizer);
+        self
+    }
+
+    /// Applies the faults of `injector` to the channels of all profiles, see [L2capServerBuilder::with_fault_injector].
+    #[cfg(feature = "fault-injection")]
+    pub fn with_fault_injector(mut self, injector: FaultInjector) -> Self {
+        self.l2cap = self.l2cap.with_fault_injector(injector);
+        self
+    }
+
+    /// Publishes the Device ID record for `device_id`.
+    #[cfg(feature = "sdp-server")]
+    pub fn with_device_id(mut self, device_id: DeviceId) -> Self {
+        let handle = self.allocate_record_handle();
+        self.with_record(DeviceIdServiceRecord::new(handle, device_id))
+    }
+
+    /// Returns a handle that is guaranteed to not clash with the records of the registered profiles.
+    pub fn allocate_record_handle(&mut self) -> u32 {
+        self.handles.allocate()
+    }
+
+    pub fn manifest(&self) -> &[ProfileInfo] {
+        &self.manifest
+    }
+
+    /// The union of the service classes of all registered profiles.
+    pub fn service_classes(&self) -> MajorServiceClasses {
+        self.manifest
+            .iter()
+            .fold(MajorServiceClasses::empty(), |classes, info| classes | info.service_classes)
+    }
+
+    /// Combines `device_class` with the service classes of the registered profiles.
+    pub fn class_of_device(&self, device_class: DeviceClass) -> ClassOfDevice {
+        let service_classes = self.service_classes();
+        if service_classes.contains(MajorServiceClasses::Audio) && !matches!(device_class, DeviceClass::AudioVideo(_)) {
+            warn!("Audio profiles are registered, but the device class is {:?}", device_class);
+        }
+        ClassOfDevice {
+            service_classes,
+            device_class
+        }
+    }
+
+    /// Returns a description of every registration that is most likely a mistake.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.manifest.is_empty() {
+            problems.push(String::from("No profiles are registered"));
+        }
+        for info in &self.manifest {
+            if info.record_handles.is_empty() {
+                problems.push(format!("{} does not advertise any service record", info.name));
+            }
+            if info.psms.is_empty() {
+                problems.push(format!("{} does not handle any PSM", info.name));
+            }
+        }
+        // The usual pairs are an A2DP sink with an AVRCP controller and an A2DP source with an AVRCP target
+        let roles = self
+            .manifest
+            .iter()
+            .fold(ProfileRoles::empty(), |roles, info| roles | info.roles);
+        let avrcp = roles.intersects(ProfileRoles::AVRCP_CONTROLLER | ProfileRoles::AVRCP_TARGET);
+        if avrcp && roles.contains(ProfileRoles::A2DP_SINK) && !roles.contains(ProfileRoles::AVRCP_CONTROLLER) {
+            problems.push(String::from("The A2DP sink cannot control the playback of the source without the AVRCP controller role"));
+        }
+        if avrcp && roles.contains(ProfileRoles::A2DP_SOURCE) && !roles.contains(ProfileRoles::AVRCP_TARGET) {
+            problems.push(String::from("The playback of the A2DP source cannot be controlled without the AVRCP target role"));
+        }
+        problems
+    }
+
+    pub fn start(self, hci: &Arc<Hci>) -> Result<ProfileStack, Error> {
+        for problem in self.validate() {
+            warn!("Profile configuration: {}", problem);
+        }
+        #[cfg(feature = "sdp-server")]
+        let server = self.l2cap.with_protocol(self.sdp.build()).run(hci)?;
+        #[cfg(not(feature = "sdp-server"))]
+        let server = self.l2cap.run(hci)?;
+        let opener = server.channel_opener();
+        let inspector = server.inspector();
+        let server = spawn_supervised("l2cap-server", "HCI", server);
+        for profile in &self.profiles {
+            debug!("Starting profile {}", profile.name());
+            profile.start(hci)?;
+        }
+        Ok(ProfileStack {
+            profiles: self.profiles,
+            manifest: self.manifest,
+            opener,
+            inspector,
+            server
+        })
+    }
+}
+
+/// The running set of profiles.
+pub struct ProfileStack {
+    profiles: Vec<Arc<dyn Profile>>,
+    manifest: Vec<ProfileInfo>,
+    opener: ChannelOpener,
+    inspector: L2capInspector,
+    server: JoinHandle<Option<()>>
+}
+
+impl ProfileStack {
+    pub fn manifest(&self) -> &[ProfileInfo] {
+        &self.manifest
+    }
+
+    /// Opens outgoing channels, e.g. to connect profiles that act as initiator.
+    pub fn channel_opener(&self) -> ChannelOpener {
+        self.opener.clone()
+    }
+
+    /// Collects the state of all connections, channels and profile sessions.
+    /// Cheap enough to be called from a signal handler or a debug RPC while the stack is running.
+    pub async fn debug_snapshot(&self) -> StackSnapshot {
+        StackSnapshot {
+            connections: self.inspector.connections().await.unwrap_or_default(),
+            profiles: self
+                .profiles
+                .iter()
+                .filter_map(|profile| profile.debug_snapshot())
+                .collect()
+        }
+    }
+
+    pub fn stop(self) {
+        for profile in self.profiles.iter().rev() {
+            debug!("Stopping profile {}", profile.name());
+            profile.stop();
+        }
+        self.server.abort();
+    }
+}
+
+struct HandlerList(Vec<Arc<dyn ProtocolHandler>>);
+
+impl ProtocolHandlerProvider for HandlerList {
+    fn protocol_handlers(&self) -> Vec<Arc<dyn ProtocolHandler>> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hci::consts::MajorServiceClasses;
+    use crate::profile::{ProfileInfo, ProfileRegistry, ProfileRoles};
+
+    fn registry(profiles: &[(&'static str, ProfileRoles)]) -> ProfileRegistry {
+        ProfileRegistry {
+            manifest: profiles
+                .iter()
+                .map(|(name, roles)| ProfileInfo {
+                    name,
+                    psms: vec![0x0019],
+                    record_handles: vec![0x00010001],
+                    service_classes: MajorServiceClasses::empty(),
+                    roles: *roles
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn role_combinations() {
+        let speaker = registry(&[
+            ("A2DP", ProfileRoles::A2DP_SINK),
+            ("AVRCP", ProfileRoles::AVRCP_CONTROLLER | ProfileRoles::AVRCP_TARGET)
+        ]);
+        assert!(speaker.validate().is_empty());
+        let phone = registry(&[("A2DP", ProfileRoles::A2DP_SOURCE), ("AVRCP", ProfileRoles::AVRCP_TARGET)]);
+        assert!(phone.validate().is_empty());
+        // A sink without AVRCP is limited, but not wrong
+        assert!(registry(&[("A2DP", ProfileRoles::A2DP_SINK)]).validate().is_empty());
+
+        let sink_with_target = registry(&[("A2DP", ProfileRoles::A2DP_SINK), ("AVRCP", ProfileRoles::AVRCP_TARGET)]);
+        assert_eq!(sink_with_target.validate().len(), 1);
+        let source_with_controller = registry(&[("A2DP", ProfileRoles::A2DP_SOURCE), ("AVRCP", ProfileRoles::AVRCP_CONTROLLER)]);
+        assert_eq!(source_with_controller.validate().len(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Hooks for running the qualification test cases of the Bluetooth SIG Profile Tuning Suite (PTS) against a product.
+//! Many test cases expect the implementation under test to reject a specific command or to send a specific PDU,
+//! which a product never does on its own. The hooks trigger these behaviors at runtime, so a single build passes
+//! all test cases. The [IpcServer](crate::ipc::IpcServer) exposes them as the `Pts.*` methods.
+//!
+//! Never enable the `pts` feature in production builds, anyone with access to the IPC server can break the profiles.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::avdtp::SignalIdentifier;
+use crate::avrcp::ErrorCode;
+
+#[derive(Debug, Default)]
+struct State {
+    /// AVRCP PDU ids and the status to reject them with.
+    avrcp_rejects: BTreeMap<u8, ErrorCode>,
+    /// AVDTP signal identifiers and the error code to reject them with.
+    avdtp_rejects: BTreeMap<u8, u8>
+}
+
+/// The behaviors forced by the tester, shared by all sessions of the profiles it is registered with.
+#[derive(Debug, Clone, Default)]
+pub struct PtsHooks(Arc<Mutex<State>>);
+
+impl PtsHooks {
+    /// Rejects every AVRCP command `pdu_id` of the peer with `status` until [PtsHooks::clear] is called.
+    /// Applies to the control and the browsing channel.
+    pub fn reject_avrcp(&self, pdu_id: u8, status: ErrorCode) {
+        self.0.lock().avrcp_rejects.insert(pdu_id, status);
+    }
+
+    /// Rejects every AVDTP `signal` of the peer with the error code `error` ([AVDTP] Section 8.20.6).
+    pub fn reject_avdtp(&self, signal: SignalIdentifier, error: u8) {
+        self.0.lock().avdtp_rejects.insert(signal as u8, error);
+    }
+
+    /// Returns to the regular behavior.
+    pub fn clear(&self) {
+        let mut state = self.0.lock();
+        state.avrcp_rejects.clear();
+        state.avdtp_rejects.clear();
+    }
+
+    pub(crate) fn avrcp_reject(&self, pdu_id: u8) -> Option<ErrorCode> {
+        let status = self.0.lock().avrcp_rejects.get(&pdu_id).copied();
+        if let Some(status) = status {
+            debug!("Rejecting AVRCP pdu {:#04x} with {:?} on request of the tester", pdu_id, status);
+        }
+        status
+    }
+
+    pub(crate) fn avdtp_reject(&self, signal: SignalIdentifier) -> Option<u8> {
+        let error = self.0.lock().avdtp_rejects.get(&(signal as u8)).copied();
+        if let Some(error) = error {
+            debug!("Rejecting AVDTP signal {:?} with {:#04x} on request of the tester", signal, error);
+        }
+        error
+    }
+}
@@ -0,0 +1,167 @@
+use std::ops::RangeInclusive;
+
+use bytes::{Bytes, BytesMut};
+use instructor::utils::Length;
+use instructor::{Buffer, BufferMut};
+use tracing::trace;
+
+use crate::ensure;
+use crate::l2cap::channel::{Channel, Error as L2capError};
+use crate::l2cap::{ChannelOpener, SDP_PSM};
+use crate::sdp::error::{Error, SdpErrorCodes};
+use crate::sdp::{DataElement, PduId, SdpHeader, ServiceAttribute, Uuid};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Channel(L2capError),
+    ErrorResponse(SdpErrorCodes),
+    InvalidResponse
+}
+
+impl From<L2capError> for ClientError {
+    fn from(value: L2capError) -> Self {
+        Self::Channel(value)
+    }
+}
+
+impl From<instructor::Error> for ClientError {
+    fn from(_: instructor::Error) -> Self {
+        Self::InvalidResponse
+    }
+}
+
+impl From<Error> for ClientError {
+    fn from(_: Error) -> Self {
+        Self::InvalidResponse
+    }
+}
+
+/// Minimal SDP client for querying the service records of a remote device.
+pub struct SdpClient {
+    channel: Channel,
+    transaction_id: u16
+}
+
+impl SdpClient {
+    const MAX_ATTRIBUTE_BYTE_COUNT: u16 = 0xFFFF;
+
+    pub fn new(channel: Channel) -> Self {
+        Self { channel, transaction_id: 0 }
+    }
+
+    pub async fn connect(opener: &ChannelOpener, handle: u16) -> Result<Self, ClientError> {
+        let channel = opener.open(handle, SDP_PSM as u64).await?;
+        Ok(Self::new(channel))
+    }
+
+    // ([Vol 3] Part B, Section 4.7).
+    pub async fn service_search_attribute<U: Into<Uuid> + Copy>(
+        &mut self, patterns: &[U], attributes: &[RangeInclusive<u16>]
+    ) -> Result<Vec<Vec<ServiceAttribute>>, ClientError> {
+        let patterns = patterns.iter().map(|uuid| Into::<Uuid>::into(*uuid)).collect::<DataElement>();
+        let attributes = attributes
+            .iter()
+            .map(|range| match range.start() == range.end() {
+                true => DataElement::U16(*range.start()),
+                false => DataElement::U32((*range.start() as u32) << 16 | *range.end() as u32)
+            })
+            .collect::<DataElement>();
+
+        let mut attribute_lists = BytesMut::new();
+        let mut continuation_state = Bytes::new();
+        loop {
+            let parameters = search_attribute_parameters(&patterns, Self::MAX_ATTRIBUTE_BYTE_COUNT, &attributes, &continuation_state)?;
+            let mut response = self
+                .transact(PduId::SearchAttributeRequest, parameters)
+                .await?;
+            // ([Vol 3] Part B, Section 4.7.2).
+            let byte_count: u16 = response.read_be()?;
+            ensure!(response.len() > byte_count as usize, ClientError::InvalidResponse);
+            attribute_lists.extend_from_slice(&response.split_to(byte_count as usize));
+            let continuation_length: u8 = response.read_be()?;
+            ensure!(response.len() == continuation_length as usize, ClientError::InvalidResponse);
+            if continuation_length == 0 {
+                break;
+            }
+            trace!("SDP response continues ({} bytes so far)", attribute_lists.len());
+            continuation_state = response;
+        }
+
+        let mut attribute_lists = attribute_lists.freeze();
+        let records: DataElement = attribute_lists.read()?;
+        attribute_lists.finish()?;
+        records
+            .as_sequence()?
+            .iter()
+            .map(parse_attribute_list)
+            .collect()
+    }
+
+    async fn transact(&mut self, pdu: PduId, parameters: Bytes) -> Result<Bytes, ClientError> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        self.channel
+            .write(encode_request(pdu, self.transaction_id, &parameters)?)
+            .await?;
+
+        let mut response = self.channel.read().await.ok_or(L2capError::Disconnected)?;
+        let header: SdpHeader = response.read()?;
+        ensure!(header.transaction_id == self.transaction_id, ClientError::InvalidResponse);
+        match header.pdu {
+            PduId::ErrorResponse => Err(ClientError::ErrorResponse(response.read_be()?)),
+            PduId::SearchAttributeResponse if pdu == PduId::SearchAttributeRequest => Ok(response),
+            _ => Err(ClientError::InvalidResponse)
+        }
+    }
+}
+
+// ([Vol 3] Part B, Section 4.7.1).
+fn search_attribute_parameters(
+    patterns: &DataElement, max_byte_count: u16, attributes: &DataElement, continuation_state: &[u8]
+) -> Result<Bytes, ClientError> {
+    let mut parameters = BytesMut::new();
+    parameters.write_ref(patterns);
+    parameters.write_be(max_byte_count);
+    parameters.write_ref(attributes);
+    parameters.write_be(u8::try_from(continuation_state.len()).map_err(|_| ClientError::InvalidResponse)?);
+    parameters.extend_from_slice(continuation_state);
+    Ok(parameters.freeze())
+}
+
+// ([Vol 3] Part B, Section 4.2).
+fn encode_request(pdu: PduId, transaction_id: u16, parameters: &[u8]) -> Result<Bytes, instructor::Error> {
+    let mut packet = BytesMut::new();
+    packet.write(SdpHeader {
+        pdu,
+        transaction_id,
+        parameter_length: Length::new(parameters.len())?
+    });
+    packet.extend_from_slice(parameters);
+    Ok(packet.freeze())
+}
+
+fn parse_attribute_list(list: &DataElement) -> Result<Vec<ServiceAttribute>, ClientError> {
+    let list = list.as_sequence()?;
+    ensure!(list.len() % 2 == 0, ClientError::InvalidResponse);
+    list.chunks_exact(2)
+        .map(|pair| -> Result<_, ClientError> { Ok(ServiceAttribute::new(pair[0].as_u16()?, pair[1].clone())) })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sdp::client::{encode_request, search_attribute_parameters};
+    use crate::sdp::ids::protocols::L2CAP;
+    use crate::sdp::{DataElement, PduId};
+
+    #[test]
+    fn encode_requests() {
+        let patterns = DataElement::from_iter([L2CAP]);
+        let attributes = DataElement::from_iter([DataElement::U32(0x0000FFFF)]);
+        let parameters = search_attribute_parameters(&patterns, 0x03F0, &attributes, &[]).unwrap();
+        let request = encode_request(PduId::SearchAttributeRequest, 0x0000, &parameters).unwrap();
+        assert_eq!(
+            request.as_ref(),
+            &[0x06, 0x00, 0x00, 0x00, 0x0F, 0x35, 0x03, 0x19, 0x01, 0x00, 0x03, 0xF0, 0x35, 0x05, 0x0A, 0x00, 0x00, 0xFF, 0xFF, 0x00]
+        );
+    }
+}
@@ -2,7 +2,7 @@ mod uuid;
 
 use instructor::utils::Limit;
 use instructor::{BigEndian, Buffer, BufferMut, Error as InstructorError, Exstruct, Instruct};
-pub use uuid::Uuid;
+pub use uuid::{Uuid, Uuid128, Uuid16, Uuid32};
 
 use crate::ensure;
 use crate::sdp::error::Error;
@@ -78,6 +78,7 @@ impl Exstruct<BigEndian> for FullDataElementHeader {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataElement {
     Nil,
     U8(u8),
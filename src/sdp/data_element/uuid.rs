@@ -1,4 +1,5 @@
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 use instructor::Instruct;
 
@@ -24,6 +25,11 @@ impl Uuid {
         Self(value)
     }
 
+    #[inline]
+    pub const fn as_u128(self) -> u128 {
+        self.0
+    }
+
     #[inline]
     fn remove_base(self) -> Option<u32> {
         ((self.0 & ((1u128 << 96) - 1)) == Self::BASE).then_some((self.0 >> 96) as u32)
@@ -107,3 +113,159 @@ impl Display for Uuid {
         )
     }
 }
+
+impl FromStr for Uuid {
+    type Err = instructor::Error;
+
+    /// Parses the format produced by [Display], hyphens are optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|c| *c != '-').collect();
+        if digits.len() != 32 {
+            return Err(instructor::Error::InvalidValue);
+        }
+        u128::from_str_radix(&digits, 16)
+            .map(Self)
+            .map_err(|_| instructor::Error::InvalidValue)
+    }
+}
+
+/// A UUID from the Bluetooth base range in its 16 bit short form, e.g. `0x110B` for the audio sink.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct Uuid16(pub u16);
+
+/// A UUID from the Bluetooth base range in its 32 bit short form.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct Uuid32(pub u32);
+
+/// A full 128 bit UUID that may or may not be part of the Bluetooth base range.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct Uuid128(pub u128);
+
+macro_rules! impl_short_uuid {
+    ($name:ident, $ty:ty, $width:literal) => {
+        impl From<$name> for Uuid {
+            #[inline]
+            fn from(value: $name) -> Self {
+                Self::from(value.0)
+            }
+        }
+
+        impl TryFrom<Uuid> for $name {
+            type Error = instructor::Error;
+
+            /// Fails if the UUID is not part of the Bluetooth base range or does not fit into the short form.
+            fn try_from(value: Uuid) -> Result<Self, Self::Error> {
+                value
+                    .remove_base()
+                    .and_then(|short| <$ty>::try_from(short).ok())
+                    .map(Self)
+                    .ok_or(instructor::Error::InvalidValue)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write!(f, concat!("0x{:0", $width, "X}"), self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = instructor::Error;
+
+            /// Parses the short form with or without `0x` prefix as well as the full form of a base range UUID.
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+                match hex.len() <= $width {
+                    true => <$ty>::from_str_radix(hex, 16)
+                        .map(Self)
+                        .map_err(|_| instructor::Error::InvalidValue),
+                    false => Self::try_from(Uuid::from_str(s)?)
+                }
+            }
+        }
+    };
+}
+
+impl_short_uuid!(Uuid16, u16, 4);
+impl_short_uuid!(Uuid32, u32, 8);
+
+impl From<Uuid128> for Uuid {
+    #[inline]
+    fn from(value: Uuid128) -> Self {
+        Self(value.0)
+    }
+}
+
+impl From<Uuid> for Uuid128 {
+    #[inline]
+    fn from(value: Uuid) -> Self {
+        Self(value.0)
+    }
+}
+
+impl Display for Uuid128 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&Uuid(self.0), f)
+    }
+}
+
+impl FromStr for Uuid128 {
+    type Err = instructor::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(Self::from)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Uuid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Uuid {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        struct UuidVisitor;
+
+        impl serde::de::Visitor<'_> for UuidVisitor {
+            type Value = Uuid;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a string in the format XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> where E: serde::de::Error {
+                value.parse().map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_str(UuidVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sdp::data_element::uuid::{Uuid, Uuid128, Uuid16, Uuid32};
+
+    #[test]
+    fn short_forms() {
+        let sink = Uuid::from(Uuid16(0x110B));
+        assert_eq!(sink.to_string(), "0000110B-0000-1000-8000-00805F9B34FB");
+        assert_eq!(Uuid16::try_from(sink).ok(), Some(Uuid16(0x110B)));
+        assert_eq!(Uuid32::try_from(sink).ok(), Some(Uuid32(0x110B)));
+        assert!(Uuid16::try_from(Uuid::from(0x0001_0000u32)).is_err());
+        assert!(Uuid16::try_from(Uuid::from(0x1234u128)).is_err());
+
+        assert_eq!(Uuid16(0x110B).to_string(), "0x110B");
+        assert_eq!("0x110B".parse().ok(), Some(Uuid16(0x110B)));
+        assert_eq!("110b".parse().ok(), Some(Uuid16(0x110B)));
+        assert_eq!("0000110B-0000-1000-8000-00805F9B34FB".parse().ok(), Some(Uuid16(0x110B)));
+        assert_eq!(Uuid32(0x110B).to_string(), "0x0000110B");
+        assert_eq!("0000110B-0000-1000-8000-00805F9B34FB".parse::<Uuid128>().ok().map(Uuid::from), Some(sink));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::hci::DeviceId;
+use crate::sdp::ids::attributes::*;
+use crate::sdp::ids::browse_groups::PUBLIC_BROWSE_ROOT;
+use crate::sdp::ids::service_classes::PN_P_INFORMATION;
+use crate::sdp::{DataElement, ServiceAttribute, ServiceRecord};
+
+// ([DID] Section 5).
+const SPECIFICATION_ID_ID: u16 = 0x0200;
+const VENDOR_ID_ID: u16 = 0x0201;
+const PRODUCT_ID_ID: u16 = 0x0202;
+const VERSION_ID: u16 = 0x0203;
+const PRIMARY_RECORD_ID: u16 = 0x0204;
+const VENDOR_ID_SOURCE_ID: u16 = 0x0205;
+
+/// The Device ID record, which lets remote devices identify the product ([DID] Section 5).
+#[derive(Debug)]
+pub struct DeviceIdServiceRecord {
+    handle: u32,
+    device_id: DeviceId
+}
+
+impl DeviceIdServiceRecord {
+    pub fn new(handle: u32, device_id: DeviceId) -> Self {
+        Self { handle, device_id }
+    }
+}
+
+impl ServiceRecord for DeviceIdServiceRecord {
+    fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    fn attributes(&self) -> Vec<ServiceAttribute> {
+        let did_version = 1u16 << 8 | 3u16;
+        vec![
+            ServiceAttribute::new(SERVICE_RECORD_HANDLE_ID, self.handle),
+            ServiceAttribute::new(BROWSE_GROUP_LIST_ID, DataElement::from_iter([PUBLIC_BROWSE_ROOT])),
+            ServiceAttribute::new(SERVICE_CLASS_ID_LIST_ID, DataElement::from_iter([PN_P_INFORMATION])),
+            ServiceAttribute::new(
+                BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID,
+                DataElement::from_iter([(PN_P_INFORMATION, did_version)])
+            ),
+            ServiceAttribute::new(SPECIFICATION_ID_ID, did_version),
+            ServiceAttribute::new(VENDOR_ID_ID, self.device_id.vendor_id),
+            ServiceAttribute::new(PRODUCT_ID_ID, self.device_id.product_id),
+            ServiceAttribute::new(VERSION_ID, self.device_id.version),
+            ServiceAttribute::new(PRIMARY_RECORD_ID, true),
+            ServiceAttribute::new(VENDOR_ID_SOURCE_ID, self.device_id.vendor_id_source as u16),
+        ]
+    }
+}
@@ -1,13 +1,15 @@
 use instructor::{Exstruct, Instruct};
 
 #[derive(Debug)]
+#[cfg_attr(not(feature = "sdp-server"), allow(dead_code))]
 pub enum Error {
     InvalidContinuationState,
     InvalidRequest,
     UnexpectedDataType,
     UnknownServiceRecordHandle(u32),
     MalformedPacketContent,
-    UnexpectedPacketLength
+    UnexpectedPacketLength,
+    InsufficientResources
 }
 
 impl From<Error> for SdpErrorCodes {
@@ -18,7 +20,8 @@ impl From<Error> for SdpErrorCodes {
             Error::UnexpectedDataType => Self::InvalidRequestSyntax,
             Error::MalformedPacketContent => Self::InvalidSdpVersion,
             Error::UnexpectedPacketLength => Self::InvalidPduSize,
-            Error::UnknownServiceRecordHandle(_) => Self::InvalidServiceRecordHandle
+            Error::UnknownServiceRecordHandle(_) => Self::InvalidServiceRecordHandle,
+            Error::InsufficientResources => Self::InsufficientResources
         }
     }
 }
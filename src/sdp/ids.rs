@@ -15,7 +15,7 @@ pub mod attributes {
     pub const PROTOCOL_DESCRIPTOR_LIST_ID: u16 = 0x0004;
 
     // ([Vol 3] Part B, Section 5.1.6).
-    pub const ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID: u16 = 0x0005;
+    pub const ADDITIONAL_PROTOCOL_DESCRIPTOR_LIST_ID: u16 = 0x000D;
 
     // ([Vol 3] Part B, Section 5.1.7).
     pub const BROWSE_GROUP_LIST_ID: u16 = 0x0005;
@@ -31,6 +31,14 @@ pub mod attributes {
 
     // ([Vol 3] Part B, Section 5.1.11).
     pub const BLUETOOTH_PROFILE_DESCRIPTOR_LIST_ID: u16 = 0x0009;
+
+    // The base of the primary language in the language base list ([Vol 3] Part B, Section 5.1.8).
+    pub const PRIMARY_LANGUAGE_BASE_ID: u16 = 0x0100;
+
+    // Offsets from the base of a language ([Vol 3] Part B, Section 5.1.15 to 5.1.17).
+    pub const SERVICE_NAME_OFFSET: u16 = 0x0000;
+    pub const SERVICE_DESCRIPTION_OFFSET: u16 = 0x0001;
+    pub const PROVIDER_NAME_OFFSET: u16 = 0x0002;
 }
 
 // ([Assigned Numbers] Section 3.1).
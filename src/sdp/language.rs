@@ -0,0 +1,131 @@
+use crate::sdp::ids::attributes::*;
+use crate::sdp::{DataElement, ServiceAttribute, ServiceRecord};
+
+/// The IANA MIBenum of UTF-8, the encoding of all strings we provide.
+const UTF8_ENCODING: u16 = 106;
+/// The space between the base attribute ids of the languages.
+/// Keeps all languages within the universal attribute range of 0x0100 to 0x01FF ([Vol 3] Part B, Section 5.1).
+const LANGUAGE_STRIDE: u16 = 0x0010;
+const MAX_LANGUAGES: usize = 16;
+
+/// The human-readable strings of a service record in one language ([Vol 3] Part B, Section 5.1.15 to 5.1.17).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ServiceStrings {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub provider: Option<String>
+}
+
+impl ServiceStrings {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_provider(mut self, provider: impl Into<String>) -> Self {
+        self.provider = Some(provider.into());
+        self
+    }
+}
+
+/// The strings of a service record in several languages, described by the LanguageBaseAttributeIDList
+/// ([Vol 3] Part B, Section 5.1.8). The first language is the primary language with the base id 0x0100.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LocalizedStrings {
+    languages: Vec<([u8; 2], ServiceStrings)>
+}
+
+impl LocalizedStrings {
+    /// `language` is a two letter ISO 639:1988 code like `en`.
+    pub fn with_language(mut self, language: &str, strings: ServiceStrings) -> Self {
+        let code: [u8; 2] = language
+            .as_bytes()
+            .try_into()
+            .ok()
+            .filter(|code: &[u8; 2]| code.iter().all(u8::is_ascii_lowercase))
+            .unwrap_or_else(|| panic!("Invalid language code: {}", language));
+        assert!(!self.languages.iter().any(|(other, _)| *other == code), "Duplicate language: {}", language);
+        assert!(self.languages.len() < MAX_LANGUAGES, "Too many languages");
+        self.languages.push((code, strings));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.languages.is_empty()
+    }
+
+    /// The language base list followed by the strings at their offsets from the base of their language.
+    pub fn attributes(&self) -> Vec<ServiceAttribute> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let bases = || (0..).map(|i| PRIMARY_LANGUAGE_BASE_ID + i * LANGUAGE_STRIDE);
+        let base_list = self
+            .languages
+            .iter()
+            .zip(bases())
+            .flat_map(|(([a, b], _), base)| [u16::from_be_bytes([*a, *b]), UTF8_ENCODING, base]);
+        let mut attributes = vec![ServiceAttribute::new(LANGUAGE_BASE__ID_LIST_ID, DataElement::from_iter(base_list))];
+        for ((_, strings), base) in self.languages.iter().zip(bases()) {
+            let entries = [
+                (SERVICE_NAME_OFFSET, &strings.name),
+                (SERVICE_DESCRIPTION_OFFSET, &strings.description),
+                (PROVIDER_NAME_OFFSET, &strings.provider)
+            ];
+            attributes.extend(
+                entries
+                    .into_iter()
+                    .filter_map(|(offset, value)| Some(ServiceAttribute::new(base + offset, value.as_deref()?)))
+            );
+        }
+        attributes
+    }
+}
+
+/// A record with its strings in several languages, see [SdpBuilder::with_localized_record](crate::sdp::SdpBuilder::with_localized_record).
+pub struct Localized<T> {
+    pub record: T,
+    pub strings: LocalizedStrings
+}
+
+impl<T: ServiceRecord> ServiceRecord for Localized<T> {
+    fn handle(&self) -> u32 {
+        self.record.handle()
+    }
+
+    fn attributes(&self) -> Vec<ServiceAttribute> {
+        let mut attributes = self.record.attributes();
+        attributes.extend(self.strings.attributes());
+        attributes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sdp::ids::attributes::*;
+    use crate::sdp::language::{LocalizedStrings, ServiceStrings};
+    use crate::sdp::DataElement;
+
+    #[test]
+    fn language_bases() {
+        let strings = LocalizedStrings::default()
+            .with_language("en", ServiceStrings::new("Speaker").with_provider("bluefang"))
+            .with_language("de", ServiceStrings::new("Lautsprecher"));
+        let attributes = strings.attributes();
+        assert_eq!(attributes[0].id, LANGUAGE_BASE__ID_LIST_ID);
+        assert_eq!(
+            attributes[0].value,
+            DataElement::from_iter([0x656Eu16, 106, 0x0100, 0x6465, 106, 0x0110])
+        );
+        let ids: Vec<u16> = attributes[1..].iter().map(|a| a.id).collect();
+        assert_eq!(ids, [0x0100, 0x0102, 0x0110]);
+        assert_eq!(attributes[3].value, DataElement::from("Lautsprecher"));
+    }
+}
@@ -0,0 +1,204 @@
+//! Protections of the SDP server, which every device in range can reach before authentication.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::hci::consts::BdAddr;
+use crate::utils::clock::now;
+
+/// Bounds the resources a single device can take up on the SDP server.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SdpLimits {
+    /// How many SDP channels a device may have open at the same time, further channels are refused.
+    pub max_channels_per_device: usize,
+    /// How many requests per second a device may send on average, excess requests are answered with an error.
+    pub requests_per_second: u32,
+    /// How many requests a device may send at once before the rate applies.
+    pub request_burst: u32,
+    /// The largest attribute list a single request may produce, including all continuations.
+    pub max_response_size: usize
+}
+
+impl Default for SdpLimits {
+    fn default() -> Self {
+        Self {
+            max_channels_per_device: 2,
+            requests_per_second: 20,
+            request_burst: 20,
+            max_response_size: 16 * 1024
+        }
+    }
+}
+
+/// What the limits of the SDP server prevented since it was started.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SdpStats {
+    pub requests: u64,
+    pub rejected_channels: u64,
+    pub rate_limited_requests: u64,
+    pub oversized_responses: u64
+}
+
+#[derive(Debug)]
+struct DeviceState {
+    channels: usize,
+    /// The remaining requests of the token bucket and when it was last refilled.
+    tokens: f64,
+    refilled: Instant
+}
+
+impl DeviceState {
+    fn refill(&mut self, limits: &SdpLimits) {
+        let time = now();
+        let elapsed = time.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.requests_per_second as f64).min(limits.request_burst as f64);
+        self.refilled = time;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: BTreeMap<BdAddr, DeviceState>,
+    stats: SdpStats
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Limiter {
+    limits: SdpLimits,
+    state: Arc<Mutex<State>>
+}
+
+impl Limiter {
+    pub fn new(limits: SdpLimits) -> Self {
+        Self {
+            limits,
+            state: Arc::default()
+        }
+    }
+
+    pub fn stats(&self) -> SdpStats {
+        self.state.lock().stats
+    }
+
+    /// Registers a new channel of `addr`, `None` if the device already has too many.
+    pub fn open_channel(&self, addr: BdAddr) -> Option<ChannelPermit> {
+        let state = &mut *self.state.lock();
+        let device = state.devices.entry(addr).or_insert_with(|| DeviceState {
+            channels: 0,
+            tokens: self.limits.request_burst as f64,
+            refilled: now()
+        });
+        if device.channels >= self.limits.max_channels_per_device {
+            state.stats.rejected_channels += 1;
+            return None;
+        }
+        device.channels += 1;
+        Some(ChannelPermit {
+            addr,
+            limits: self.limits,
+            state: self.state.clone()
+        })
+    }
+
+    /// Takes a request of `addr` from its token bucket, `false` if the device exceeded its rate.
+    pub fn allow_request(&self, addr: BdAddr) -> bool {
+        let state = &mut *self.state.lock();
+        state.stats.requests += 1;
+        let Some(device) = state.devices.get_mut(&addr) else {
+            return false;
+        };
+        device.refill(&self.limits);
+        match device.tokens >= 1.0 {
+            true => {
+                device.tokens -= 1.0;
+                true
+            }
+            false => {
+                state.stats.rate_limited_requests += 1;
+                false
+            }
+        }
+    }
+
+    /// Checks the size of a complete attribute list before the first part of it is sent.
+    pub fn allow_response(&self, size: usize) -> bool {
+        let allowed = size <= self.limits.max_response_size;
+        if !allowed {
+            self.state.lock().stats.oversized_responses += 1;
+        }
+        allowed
+    }
+}
+
+/// Counts towards the channels of a device until it is dropped.
+#[derive(Debug)]
+pub(crate) struct ChannelPermit {
+    addr: BdAddr,
+    limits: SdpLimits,
+    state: Arc<Mutex<State>>
+}
+
+impl Drop for ChannelPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if let Some(device) = state.devices.get_mut(&self.addr) {
+            device.channels -= 1;
+            device.refill(&self.limits);
+            // The bucket is kept while it is not full, so reconnecting doesn't reset the rate
+            if device.channels == 0 && device.tokens >= self.limits.request_burst as f64 {
+                state.devices.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::hci::consts::BdAddr;
+    use crate::sdp::limits::{Limiter, SdpLimits};
+    use crate::utils::clock::{set_thread_clock, SimulatedClock};
+
+    #[test]
+    fn per_device_limits() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let limiter = Limiter::new(SdpLimits {
+            max_channels_per_device: 1,
+            requests_per_second: 10,
+            request_burst: 2,
+            max_response_size: 100
+        });
+        let addr: BdAddr = "00:11:22:33:44:55".parse().unwrap();
+        let other: BdAddr = "00:11:22:33:44:66".parse().unwrap();
+
+        let permit = limiter.open_channel(addr).unwrap();
+        assert!(limiter.open_channel(addr).is_none());
+        let _other = limiter.open_channel(other).unwrap();
+
+        assert!(limiter.allow_request(addr));
+        assert!(limiter.allow_request(addr));
+        assert!(!limiter.allow_request(addr));
+        assert!(limiter.allow_request(other));
+        clock.advance(Duration::from_millis(150));
+        assert!(limiter.allow_request(addr));
+
+        drop(permit);
+        // The drained bucket survives the reconnect
+        let _permit = limiter.open_channel(addr).unwrap();
+        assert!(!limiter.allow_request(addr));
+
+        assert!(limiter.allow_response(100));
+        assert!(!limiter.allow_response(101));
+        let stats = limiter.stats();
+        assert_eq!(stats.requests, 6);
+        assert_eq!(stats.rejected_channels, 1);
+        assert_eq!(stats.rate_limited_requests, 2);
+        assert_eq!(stats.oversized_responses, 1);
+    }
+}
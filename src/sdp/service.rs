@@ -1,10 +1,13 @@
 use std::fmt::Debug;
+#[cfg(feature = "sdp-server")]
 use std::ops::RangeInclusive;
+#[cfg(feature = "sdp-server")]
 use std::sync::Arc;
 
 use crate::sdp::data_element::{DataElement, Uuid};
 
 #[derive(Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServiceAttribute {
     pub id: u16,
     pub value: DataElement
@@ -15,7 +18,7 @@ impl ServiceAttribute {
         Self { id, value: value.into() }
     }
 
-    pub fn contains(&self, uuid: Uuid) -> bool {
+    pub fn contains(&self, uuid: impl Into<Uuid>) -> bool {
         fn contains(v: &DataElement, uuid: Uuid) -> bool {
             match v {
                 DataElement::Uuid(value) => *value == uuid,
@@ -23,7 +26,7 @@ impl ServiceAttribute {
                 _ => false
             }
         }
-        contains(&self.value, uuid)
+        contains(&self.value, uuid.into())
     }
 }
 
@@ -46,12 +49,15 @@ impl Debug for ServiceAttribute {
 }
 
 //#[derive(Clone)]
+#[cfg(feature = "sdp-server")]
 pub struct Service {
     attributes: Arc<Vec<ServiceAttribute>>
 }
 
+#[cfg(feature = "sdp-server")]
 impl Service {
-    pub fn contains(&self, uuid: Uuid) -> bool {
+    pub fn contains(&self, uuid: impl Into<Uuid>) -> bool {
+        let uuid = uuid.into();
         self.attributes.iter().any(|a| a.contains(uuid))
     }
 
@@ -62,12 +68,14 @@ impl Service {
     }
 }
 
+#[cfg(feature = "sdp-server")]
 impl AsRef<[ServiceAttribute]> for Service {
     fn as_ref(&self) -> &[ServiceAttribute] {
         &self.attributes
     }
 }
 
+#[cfg(feature = "sdp-server")]
 impl From<Vec<ServiceAttribute>> for Service {
     fn from(mut attributes: Vec<ServiceAttribute>) -> Self {
         attributes.sort_by_key(|a| a.id);
@@ -77,6 +85,7 @@ impl From<Vec<ServiceAttribute>> for Service {
     }
 }
 
+#[cfg(feature = "sdp-server")]
 impl Debug for Service {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_list().entries(self.attributes.iter()).finish()
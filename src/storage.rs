@@ -0,0 +1,367 @@
+//! Persistence of the state the stack keeps across restarts: link keys, device settings and the remote device info cache.
+//! Everything goes through a [StorageBackend], a small key-value store with namespaces,
+//! so embedded users can put it into their own flash or KV store instead of files.
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Result, Write};
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::task::spawn_blocking;
+use tracing::{debug, warn};
+
+use crate::hci::consts::BdAddr;
+use crate::utils::redact::redacted;
+
+/// A key-value store with namespaces. Keys consist of ASCII letters, digits, `-` and `_`.
+///
+/// The calls may block, the stack only invokes them from blocking tasks.
+pub trait StorageBackend: Send + Sync {
+    /// Returns `None` if the key does not exist.
+    fn read(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn write(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Removing a key that does not exist is not an error.
+    fn remove(&self, namespace: &str, key: &str) -> Result<()>;
+
+    /// All keys of the namespace in ascending order.
+    fn keys(&self, namespace: &str) -> Result<Vec<String>>;
+}
+
+/// A [StorageBackend] shared by the parts of the stack, together with the background writes that are still pending.
+/// Clones refer to the same backend.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    writes: Arc<Mutex<BTreeMap<(&'static str, String), Arc<EntryWrites>>>>
+}
+
+impl Storage {
+    pub fn new<B: StorageBackend + 'static>(backend: B) -> Self {
+        Self::from(Arc::new(backend) as Arc<dyn StorageBackend>)
+    }
+}
+
+impl From<Arc<dyn StorageBackend>> for Storage {
+    fn from(backend: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            backend,
+            writes: Default::default()
+        }
+    }
+}
+
+impl Deref for Storage {
+    type Target = dyn StorageBackend;
+
+    fn deref(&self) -> &Self::Target {
+        self.backend.as_ref()
+    }
+}
+
+/// Runs `func` on a blocking task, as the backend may block.
+pub(crate) async fn run_blocking<T, F>(storage: &Storage, func: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn StorageBackend) -> Result<T> + Send + 'static
+{
+    let backend = storage.backend.clone();
+    spawn_blocking(move || func(backend.as_ref()))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// The background writes of an entry.
+#[derive(Default)]
+struct EntryWrites {
+    /// The number of the latest write of the entry.
+    latest: AtomicU64,
+    /// Held while writing the entry.
+    lock: Mutex<()>
+}
+
+/// Writes or, if `value` is `None`, removes an entry in the background.
+/// The writes of an entry are applied one at a time and a write that was overtaken by a later one is skipped,
+/// so the entry always ends up with the latest value.
+pub(crate) fn persist(storage: &Storage, namespace: &'static str, key: String, value: Option<Vec<u8>>) {
+    let storage = storage.clone();
+    let (writes, sequence) = {
+        // The sequence is taken under the lock, so the last write can tell that nothing is queued after it
+        let mut pending = storage.writes.lock();
+        let writes = pending.entry((namespace, key.clone())).or_default().clone();
+        let sequence = writes.latest.fetch_add(1, Ordering::SeqCst) + 1;
+        (writes, sequence)
+    };
+    spawn_blocking(move || {
+        let _guard = writes.lock.lock();
+        if writes.latest.load(Ordering::SeqCst) != sequence {
+            return;
+        }
+        match value {
+            Some(value) => storage.write(namespace, &key, &value),
+            None => storage.remove(namespace, &key)
+        }
+        .unwrap_or_else(|err| match parse_device_key(&key) {
+            Some(addr) => warn!("Failed to persist {}/{}: {:?}", namespace, redacted(&addr), err),
+            None => warn!("Failed to persist {}/{}: {:?}", namespace, key, err)
+        });
+        let mut pending = storage.writes.lock();
+        if writes.latest.load(Ordering::SeqCst) == sequence {
+            pending.remove(&(namespace, key));
+        }
+    });
+}
+
+/// Moves the entries of a file of an earlier version, which kept a whole namespace in a single file, into `namespace`
+/// and deletes the file. `parse` splits the file into the entries of the devices. Entries that already exist win.
+pub(crate) async fn migrate_file<E, F>(storage: &Storage, namespace: &'static str, path: &Path, parse: F) -> std::result::Result<(), E>
+where
+    E: From<std::io::Error>,
+    F: FnOnce(&[u8]) -> std::result::Result<Vec<(BdAddr, Vec<u8>)>, E>
+{
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into())
+    };
+    let entries = parse(&data)?;
+    let count = entries.len();
+    let file = path.to_path_buf();
+    run_blocking(storage, move |storage| {
+        for (addr, value) in entries {
+            let key = device_key(addr);
+            if storage.read(namespace, &key)?.is_none() {
+                storage.write(namespace, &key, &value)?;
+            }
+        }
+        std::fs::remove_file(file)
+    })
+    .await?;
+    debug!("Moved {} entries of {} to {}", count, path.display(), namespace);
+    Ok(())
+}
+
+/// The directory of the file at `path`, which earlier versions took instead of a [Storage].
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+/// The key of the entries that belong to a remote device.
+pub fn device_key(addr: BdAddr) -> String {
+    addr.to_be_bytes().iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// The inverse of [device_key].
+pub fn parse_device_key(key: &str) -> Option<BdAddr> {
+    if key.len() != 12 || !key.is_ascii() {
+        return None;
+    }
+    let mut addr = [0; 6];
+    for (i, byte) in addr.iter_mut().rev().enumerate() {
+        *byte = u8::from_str_radix(&key[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(BdAddr::new(addr))
+}
+
+/// Keeps everything in memory, e.g. for tests or devices that should forget their pairings on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<String, BTreeMap<String, Vec<u8>>>>
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn read(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .entries
+            .lock()
+            .get(namespace)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn write(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.entries
+            .lock()
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<()> {
+        if let Some(entries) = self.entries.lock().get_mut(namespace) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+        Ok(self
+            .entries
+            .lock()
+            .get(namespace)
+            .map(|entries| entries.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+/// Stores every namespace as a directory below `root` and every entry as a file in it.
+/// On Unix only the owner may read the entries, as they include the link keys of bonded devices.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    root: PathBuf
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> Result<PathBuf> {
+        let valid = |name: &str| !name.is_empty() && name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+        match valid(namespace) && valid(key) {
+            true => Ok(self.root.join(namespace).join(key)),
+            false => Err(ErrorKind::InvalidInput.into())
+        }
+    }
+}
+
+impl StorageBackend for FileStorage {
+    fn read(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(namespace, key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn write(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+        let path = self.path(namespace, key)?;
+        let mut dir = std::fs::DirBuilder::new();
+        dir.recursive(true);
+        #[cfg(unix)]
+        dir.mode(0o700);
+        dir.create(self.root.join(namespace))?;
+        // Replace the file in one step, so a crash never leaves a truncated entry behind.
+        // The name of the temporary file is no valid key, so it never shows up in `keys`.
+        let temp = self.root.join(namespace).join(format!(
+            ".{}.{}-{}.tmp",
+            key,
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let result = options
+            .open(&temp)
+            .and_then(|mut file| file.write_all(value).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&temp, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<()> {
+        match std::fs::remove_file(self.path(namespace, key)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(self.root.join(namespace)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err)
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if entry.file_type()?.is_file() && self.path(namespace, name).is_ok() {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::yield_now;
+
+    use crate::hci::consts::BdAddr;
+    use crate::storage::{device_key, parse_device_key, persist, FileStorage, MemoryStorage, Storage, StorageBackend};
+
+    #[test]
+    fn memory_storage() {
+        let addr: BdAddr = "00:11:22:33:AA:FF".parse().unwrap();
+        assert_eq!(device_key(addr), "00112233AAFF");
+        assert_eq!(parse_device_key(&device_key(addr)), Some(addr));
+        assert_eq!(parse_device_key("00:11:22:33"), None);
+
+        let storage = MemoryStorage::new();
+        storage.write("link-keys", &device_key(addr), &[1, 2, 3]).unwrap();
+        storage.write("link-keys", "000000000001", &[4]).unwrap();
+        assert_eq!(storage.read("link-keys", &device_key(addr)).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(storage.read("remote-info", &device_key(addr)).unwrap(), None);
+        assert_eq!(storage.keys("link-keys").unwrap(), ["000000000001", "00112233AAFF"]);
+        storage.remove("link-keys", "000000000001").unwrap();
+        storage.remove("link-keys", "000000000001").unwrap();
+        assert_eq!(storage.keys("link-keys").unwrap(), ["00112233AAFF"]);
+    }
+
+    #[test]
+    fn file_storage() {
+        let root = std::env::temp_dir().join(format!("bluefang-storage-{}", std::process::id()));
+        let storage = FileStorage::new(&root);
+        storage.write("link-keys", "00112233AAFF", &[1, 2, 3]).unwrap();
+        storage.write("link-keys", "00112233AAFF", &[4, 5]).unwrap();
+        assert_eq!(storage.read("link-keys", "00112233AAFF").unwrap(), Some(vec![4, 5]));
+        // No temporary files are left behind
+        assert_eq!(std::fs::read_dir(root.join("link-keys")).unwrap().count(), 1);
+        assert_eq!(storage.keys("link-keys").unwrap(), ["00112233AAFF"]);
+        assert!(storage.write("link-keys", "../escape", &[0]).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(root.join("link-keys").join("00112233AAFF")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        storage.remove("link-keys", "00112233AAFF").unwrap();
+        assert_eq!(storage.read("link-keys", "00112233AAFF").unwrap(), None);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn persist_keeps_the_latest_value() {
+        let storage = Storage::new(MemoryStorage::new());
+        for value in 0..16u8 {
+            persist(&storage, "link-keys", "00112233AAFF".to_string(), Some(vec![value]));
+        }
+        while !storage.writes.lock().is_empty() {
+            yield_now().await;
+        }
+        assert_eq!(storage.read("link-keys", "00112233AAFF").unwrap(), Some(vec![15]));
+    }
+}
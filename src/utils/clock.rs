@@ -0,0 +1,228 @@
+//! The time source behind the timeouts and timers of the stack.
+//! Defaults to tokio time, tests and simulations can install a [SimulatedClock] that only moves when told to.
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::utils::{select2, Either2};
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// The clock of the tokio runtime, also honors `tokio::time::pause`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+static GLOBAL_CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+
+thread_local! {
+    static LOCAL_CLOCK: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+/// Replaces the clock of the whole process. Only works once and before the first use of the clock.
+pub fn set_global_clock(clock: Arc<dyn Clock>) -> bool {
+    GLOBAL_CLOCK.set(clock).is_ok()
+}
+
+/// Replaces the clock of the current thread until the guard is dropped, meant for tests on a current thread runtime.
+pub fn set_thread_clock(clock: Arc<dyn Clock>) -> ClockGuard {
+    let previous = LOCAL_CLOCK.with(|local| local.replace(Some(clock)));
+    ClockGuard { previous }
+}
+
+#[must_use = "the clock is reset when the guard is dropped"]
+pub struct ClockGuard {
+    previous: Option<Arc<dyn Clock>>
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        LOCAL_CLOCK.with(|local| local.replace(previous));
+    }
+}
+
+pub fn clock() -> Arc<dyn Clock> {
+    LOCAL_CLOCK
+        .with(|local| local.borrow().clone())
+        .unwrap_or_else(|| GLOBAL_CLOCK.get_or_init(|| Arc::new(TokioClock)).clone())
+}
+
+pub fn now() -> Instant {
+    clock().now()
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    let clock = clock();
+    clock.sleep_until(clock.now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    clock().sleep_until(deadline)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Elapsed;
+
+/// Like `tokio::time::timeout`, but measured by the current [Clock].
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    match select2(future, sleep(duration)).await {
+        Either2::A(output) => Ok(output),
+        Either2::B(()) => Err(Elapsed)
+    }
+}
+
+/// A value and the time the stack received it, taken before it was queued for the application.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub received: Instant
+}
+
+impl<T> Timestamped<T> {
+    /// Stamps `value` with the current time of the [Clock].
+    pub fn now(value: T) -> Self {
+        Self { value, received: now() }
+    }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Timestamped<U> {
+        Timestamped {
+            value: f(self.value),
+            received: self.received
+        }
+    }
+
+    /// How long ago the value was received, e.g. the time it spent in queues.
+    pub fn age(&self) -> Duration {
+        now().saturating_duration_since(self.received)
+    }
+}
+
+/// A clock that stands still until it is advanced manually.
+#[derive(Clone)]
+pub struct SimulatedClock {
+    inner: Arc<Mutex<SimulatedState>>
+}
+
+struct SimulatedState {
+    now: Instant,
+    sleepers: Vec<Waker>
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SimulatedState {
+                now: Instant::now(),
+                sleepers: Vec::new()
+            }))
+        }
+    }
+}
+
+impl SimulatedClock {
+    /// Moves the time forward and wakes the timers that expired.
+    pub fn advance(&self, duration: Duration) {
+        let sleepers = {
+            let mut state = self.inner.lock();
+            state.now += duration;
+            std::mem::take(&mut state.sleepers)
+        };
+        // Timers that are still pending register themselves again
+        sleepers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(SimulatedSleep {
+            inner: self.inner.clone(),
+            deadline
+        })
+    }
+}
+
+struct SimulatedSleep {
+    inner: Arc<Mutex<SimulatedState>>,
+    deadline: Instant
+}
+
+impl Future for SimulatedSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.inner.lock();
+        if state.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        if !state.sleepers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.sleepers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::utils::clock::{now, set_thread_clock, sleep, timeout, Elapsed, SimulatedClock, Timestamped};
+    use crate::utils::now_or_never;
+
+    #[test]
+    fn simulated_time() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let start = now();
+
+        let mut timer = sleep(Duration::from_secs(2));
+        assert_eq!(now_or_never(timer.as_mut()), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(now_or_never(timer.as_mut()), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(now_or_never(timer.as_mut()), Some(()));
+        assert_eq!(now() - start, Duration::from_secs(2));
+
+        let mut pending = Box::pin(timeout(Duration::from_millis(500), std::future::pending::<()>()));
+        assert_eq!(now_or_never(pending.as_mut()), None);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(now_or_never(pending.as_mut()), Some(Err(Elapsed)));
+    }
+
+    #[test]
+    fn timestamps() {
+        let clock = SimulatedClock::default();
+        let _guard = set_thread_clock(Arc::new(clock.clone()));
+        let event = Timestamped::now(1u8);
+        clock.advance(Duration::from_millis(30));
+        let event = event.map(|value| value + 1);
+        assert_eq!(event.value, 2);
+        assert_eq!(event.age(), Duration::from_millis(30));
+    }
+}
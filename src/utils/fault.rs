@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::spawn;
+
+use crate::utils::clock::sleep;
+
+/// The probability (`0.0..=1.0`) of each fault per packet. Each fault is rolled independently.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub drop: f32,
+    pub duplicate: f32,
+    /// Cuts the packet off at a random length.
+    pub truncate: f32,
+    /// Delays the packet by a random duration of up to `max_delay`, which can reorder packets.
+    pub delay: f32,
+    pub max_delay: Duration
+}
+
+/// How many packets were affected by each fault.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FaultStats {
+    pub packets: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub truncated: u64,
+    pub delayed: u64
+}
+
+struct State {
+    config: FaultConfig,
+    stats: FaultStats,
+    rng: u64
+}
+
+impl State {
+    // xorshift64*, good enough to roll faults and reproducible from the seed
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn roll(&mut self, probability: f32) -> bool {
+        probability > 0.0 && ((self.next() >> 40) as f32 / (1u64 << 24) as f32) < probability
+    }
+}
+
+/// Randomly drops, duplicates, truncates and delays packets to soak test the upper layers on an unreliable link.
+/// The configuration can be changed at any time and applies to all clones.
+#[derive(Clone)]
+pub struct FaultInjector(Arc<Mutex<State>>);
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Self::with_seed(config, seed)
+    }
+
+    /// Uses a fixed seed, so that a failing run can be reproduced.
+    pub fn with_seed(config: FaultConfig, seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(State {
+            config,
+            stats: FaultStats::default(),
+            // xorshift must not be seeded with zero
+            rng: seed.max(1)
+        })))
+    }
+
+    pub fn config(&self) -> FaultConfig {
+        self.0.lock().config
+    }
+
+    pub fn set_config(&self, config: FaultConfig) {
+        self.0.lock().config = config;
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        self.0.lock().stats
+    }
+
+    pub(crate) fn apply(&self, packet: Bytes) -> Faulted {
+        let mut state = self.0.lock();
+        let config = state.config;
+        state.stats.packets += 1;
+        if state.roll(config.drop) {
+            state.stats.dropped += 1;
+            return Faulted::default();
+        }
+        let mut packet = packet;
+        if !packet.is_empty() && state.roll(config.truncate) {
+            state.stats.truncated += 1;
+            packet.truncate(state.next() as usize % packet.len());
+        }
+        let mut packets = vec![packet];
+        if state.roll(config.duplicate) {
+            state.stats.duplicated += 1;
+            packets.push(packets[0].clone());
+        }
+        let mut delay = None;
+        if !config.max_delay.is_zero() && state.roll(config.delay) {
+            state.stats.delayed += 1;
+            delay = Some(config.max_delay.mul_f64((state.next() >> 11) as f64 / (1u64 << 53) as f64));
+        }
+        Faulted { packets, delay }
+    }
+}
+
+/// The packets that are left after applying the faults.
+#[derive(Default)]
+pub(crate) struct Faulted {
+    packets: Vec<Bytes>,
+    delay: Option<Duration>
+}
+
+impl Faulted {
+    /// Passes the remaining packets to `deliver`, after the delay if there is one.
+    pub fn deliver<F: FnMut(Bytes) + Send + 'static>(self, deliver: F) {
+        match self.delay {
+            None => self.packets.into_iter().for_each(deliver),
+            Some(delay) => {
+                spawn(async move {
+                    sleep(delay).await;
+                    self.packets.into_iter().for_each(deliver);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::utils::fault::{FaultConfig, FaultInjector};
+
+    #[test]
+    fn faults() {
+        let packet = Bytes::from_static(&[0x01, 0x02, 0x03, 0x04]);
+
+        let passthrough = FaultInjector::with_seed(FaultConfig::default(), 1);
+        assert_eq!(passthrough.apply(packet.clone()).packets, vec![packet.clone()]);
+
+        let drop = FaultInjector::with_seed(FaultConfig { drop: 1.0, ..Default::default() }, 1);
+        assert!(drop.apply(packet.clone()).packets.is_empty());
+
+        let duplicate = FaultInjector::with_seed(FaultConfig { duplicate: 1.0, ..Default::default() }, 1);
+        assert_eq!(duplicate.apply(packet.clone()).packets, vec![packet.clone(), packet.clone()]);
+
+        let truncate = FaultInjector::with_seed(FaultConfig { truncate: 1.0, ..Default::default() }, 1);
+        let truncated = truncate.apply(packet.clone()).packets;
+        assert_eq!(truncated.len(), 1);
+        assert!(truncated[0].len() < packet.len());
+
+        let stats = truncate.stats();
+        assert_eq!((stats.packets, stats.truncated), (1, 1));
+    }
+}
@@ -8,64 +8,6 @@ use tokio::pin;
 
 use crate::log_assert;
 
-#[derive(Debug)]
-#[must_use = "futures do nothing unless you `.await` or poll them"]
-pub struct SelectAll<F> {
-    inner: Vec<F>
-}
-
-impl<F: Unpin> Unpin for SelectAll<F> {}
-
-impl<F: Future + Unpin> Future for SelectAll<F> {
-    type Output = (usize, F::Output);
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        for (i, future) in self.inner.iter_mut().enumerate() {
-            if let Poll::Ready(output) = Pin::new(future).poll(cx) {
-                return Poll::Ready((i, output));
-            }
-        }
-        Poll::Pending
-    }
-}
-
-impl<F: Future + Unpin> FromIterator<F> for SelectAll<F> {
-    fn from_iter<T: IntoIterator<Item = F>>(iter: T) -> Self {
-        select_all(iter)
-    }
-}
-
-pub fn select_all<I>(iter: I) -> SelectAll<I::Item>
-where
-    I: IntoIterator,
-    I::Item: Future + Unpin
-{
-    SelectAll {
-        inner: iter.into_iter().collect()
-    }
-}
-
-//pub struct SelectAll<'a, T> {
-//    futures: &'a mut [T]
-//}
-//
-//impl<'a, T: Future + Unpin> Future for SelectAll<'a, T> {
-//    type Output = (usize, T::Output);
-//
-//    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-//        for (i, future) in self.futures.iter_mut().enumerate() {
-//            if let Poll::Ready(output) = Pin::new(future).poll(cx) {
-//                return Poll::Ready((i, output));
-//            }
-//        }
-//        Poll::Pending
-//    }
-//}
-//
-//pub fn select_all<T: Future + Unpin>(futures: &mut [T]) -> SelectAll<T> {
-//    SelectAll { futures }
-//}
-
 pin_project! {
     #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
     #[project = OptionFutureProj]
@@ -168,6 +110,59 @@ where
     Select2 { future1, future2 }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Either3<A, B, C> {
+    A(A),
+    B(B),
+    C(C)
+}
+
+pin_project! {
+    #[derive(Debug)]
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub struct Select3<F1, F2, F3> {
+        #[pin]
+        future1: F1,
+        #[pin]
+        future2: F2,
+        #[pin]
+        future3: F3,
+    }
+}
+
+impl<F1, F2, F3> Future for Select3<F1, F2, F3>
+where
+    F1: Future,
+    F2: Future,
+    F3: Future
+{
+    type Output = Either3<F1::Output, F2::Output, F3::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(t) = this.future1.poll(cx) {
+            return Poll::Ready(Either3::A(t));
+        }
+        if let Poll::Ready(t) = this.future2.poll(cx) {
+            return Poll::Ready(Either3::B(t));
+        }
+        if let Poll::Ready(t) = this.future3.poll(cx) {
+            return Poll::Ready(Either3::C(t));
+        }
+        Poll::Pending
+    }
+}
+
+pub fn select3<F1, F2, F3>(future1: F1, future2: F2, future3: F3) -> Select3<F1, F2, F3>
+where
+    F1: Future,
+    F2: Future,
+    F3: Future
+{
+    Select3 { future1, future2, future3 }
+}
+
 struct NoopWaker;
 impl Wake for NoopWaker {
     fn wake(self: Arc<Self>) {}
@@ -0,0 +1,64 @@
+//! Hooks that see the PDUs of a protocol before they are processed or sent,
+//! e.g. for logging, emulating the quirks of other devices or filtering commands.
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    Pass,
+    /// Drops the PDU. Incoming commands are not answered, so the peer runs into its timeout.
+    Veto
+}
+
+pub type Interceptor<M> = Arc<dyn Fn(Direction, &M) -> Verdict + Send + Sync>;
+
+/// The interceptors of a protocol, invoked in registration order until the first veto.
+pub struct Interceptors<M> {
+    hooks: Vec<Interceptor<M>>
+}
+
+impl<M> Interceptors<M> {
+    pub fn push<F>(&mut self, interceptor: F)
+    where
+        F: Fn(Direction, &M) -> Verdict + Send + Sync + 'static
+    {
+        self.hooks.push(Arc::new(interceptor));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Whether `message` may pass.
+    pub fn check(&self, direction: Direction, message: &M) -> bool {
+        self.hooks
+            .iter()
+            .all(|hook| hook(direction, message) == Verdict::Pass)
+    }
+}
+
+impl<M> Default for Interceptors<M> {
+    fn default() -> Self {
+        Self { hooks: Vec::new() }
+    }
+}
+
+impl<M> Clone for Interceptors<M> {
+    fn clone(&self) -> Self {
+        Self { hooks: self.hooks.clone() }
+    }
+}
+
+impl<M> Debug for Interceptors<M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Interceptors")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
@@ -1,7 +1,14 @@
 mod bytes;
+pub mod clock;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
 mod futures;
+pub mod interceptor;
 mod iter;
 mod mutex_cell;
+mod poll_set;
+pub mod redact;
+mod supervisor;
 
 use std::fmt::{Debug, Display, Formatter};
 
@@ -9,6 +16,8 @@ pub use bytes::{FromStruct, SliceExt};
 pub use futures::*;
 pub use iter::IteratorExt;
 pub use mutex_cell::MutexCell;
+pub use poll_set::PollSet;
+pub use supervisor::{spawn_supervised, supervise, Supervisor, TaskStats};
 use tokio::sync::mpsc::UnboundedSender;
 use tracing::warn;
 
@@ -0,0 +1,187 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::{poll_fn, Future};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use parking_lot::Mutex;
+
+/// A keyed collection of long-lived pollable items, e.g. the streams of a session.
+/// Like `FuturesUnordered`, every item gets its own waker and only the woken items are polled again,
+/// so a busy item doesn't cause all others to be polled as well.
+/// Items stay in the set after they completed until they are removed.
+pub struct PollSet<K, T> {
+    entries: BTreeMap<K, (T, Waker)>,
+    ready: Arc<ReadyQueue<K>>
+}
+
+struct ReadyQueue<K> {
+    keys: Mutex<BTreeSet<K>>,
+    waker: Mutex<Option<Waker>>
+}
+
+impl<K: Ord> ReadyQueue<K> {
+    fn push(&self, key: K) {
+        self.keys.lock().insert(key);
+        if let Some(waker) = self.waker.lock().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+struct EntryWaker<K> {
+    key: K,
+    queue: Arc<ReadyQueue<K>>
+}
+
+impl<K: Ord + Copy + Send + Sync + 'static> Wake for EntryWaker<K> {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.push(self.key);
+    }
+}
+
+impl<K, T> Default for PollSet<K, T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            ready: Arc::new(ReadyQueue {
+                keys: Mutex::new(BTreeSet::new()),
+                waker: Mutex::new(None)
+            })
+        }
+    }
+}
+
+impl<K: Ord + Copy + Send + Sync + 'static, T> PollSet<K, T> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Adds an item that is polled on the next poll of the set, replacing the previous item with the same key.
+    pub fn insert(&mut self, key: K, item: T) -> Option<T> {
+        self.ready.push(key);
+        match self.entries.entry(key) {
+            Entry::Occupied(mut entry) => Some(std::mem::replace(&mut entry.get_mut().0, item)),
+            Entry::Vacant(entry) => {
+                let waker = Waker::from(Arc::new(EntryWaker {
+                    key,
+                    queue: self.ready.clone()
+                }));
+                entry.insert((item, waker));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<T> {
+        self.entries.remove(key).map(|(item, _)| item)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&T> {
+        self.entries.get(key).map(|(item, _)| item)
+    }
+
+    /// The item is polled again, as the caller might have changed what it waits for.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut T> {
+        let (item, _) = self.entries.get_mut(key)?;
+        self.ready.push(*key);
+        Some(item)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.entries.values().map(|(item, _)| item)
+    }
+
+    /// Every item the iterator yields is polled again, see [PollSet::get_mut].
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let ready = &self.ready;
+        self.entries.iter_mut().map(move |(key, (item, _))| {
+            ready.push(*key);
+            item
+        })
+    }
+
+    /// Polls the woken items with `poll` until one of them is ready.
+    pub fn poll_next<R, F>(&mut self, cx: &mut Context<'_>, mut poll: F) -> Poll<(K, R)>
+    where
+        F: FnMut(&mut T, &mut Context<'_>) -> Poll<R>
+    {
+        *self.ready.waker.lock() = Some(cx.waker().clone());
+        // Items that wake themselves while being polled are only polled again on the next call
+        let mut keys = std::mem::take(&mut *self.ready.keys.lock()).into_iter();
+        let ready = keys.by_ref().find_map(|key| {
+            let (item, waker) = self.entries.get_mut(&key)?;
+            match poll(item, &mut Context::from_waker(waker)) {
+                Poll::Ready(output) => Some((key, output)),
+                Poll::Pending => None
+            }
+        });
+        self.ready.keys.lock().extend(keys);
+        ready.map_or(Poll::Pending, Poll::Ready)
+    }
+
+    pub fn next<'a, R, F>(&'a mut self, mut poll: F) -> impl Future<Output = (K, R)> + 'a
+    where
+        F: FnMut(&mut T, &mut Context<'_>) -> Poll<R> + 'a,
+        R: 'a
+    {
+        poll_fn(move |cx| self.poll_next(cx, &mut poll))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+
+    use tokio::sync::oneshot::{channel, Receiver};
+
+    use crate::utils::{now_or_never, PollSet};
+
+    #[test]
+    fn polls_woken_items() {
+        let mut set = PollSet::default();
+        let mut senders = Vec::new();
+        for key in 0..4u8 {
+            let (sender, receiver) = channel::<u8>();
+            set.insert(key, receiver);
+            senders.push(sender);
+        }
+        let polled = Cell::new(0);
+        let next = |set: &mut PollSet<u8, Receiver<u8>>| {
+            polled.set(0);
+            now_or_never(poll_fn(|cx| {
+                set.poll_next(cx, |receiver, cx| {
+                    polled.set(polled.get() + 1);
+                    Pin::new(receiver).poll(cx)
+                })
+            }))
+        };
+        // The first poll registers the wakers of all items
+        assert_eq!(next(&mut set), None);
+        assert_eq!(polled.get(), 4);
+
+        senders.remove(2).send(42).unwrap();
+        assert_eq!(next(&mut set), Some((2, Ok(42))));
+        assert_eq!(polled.get(), 1);
+
+        // Completed items are kept until removed
+        assert!(set.remove(&2).is_some());
+        assert_eq!(set.len(), 3);
+        assert_eq!(next(&mut set), None);
+        assert_eq!(polled.get(), 0);
+    }
+}
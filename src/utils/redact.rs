@@ -0,0 +1,247 @@
+//! Formatting of personal data in log messages: device addresses, device names and track metadata.
+//! With the `redact-logs` feature the values are replaced by a short keyed hash, so the messages of one device
+//! can still be correlated without logging the data itself. Connection handles, CIDs and transaction labels are
+//! not personal and are always logged as they are.
+#[cfg(feature = "avrcp")]
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "redact-logs")]
+use std::hash::{BuildHasher, Hasher, RandomState};
+#[cfg(feature = "redact-logs")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "avrcp")]
+use crate::avrcp::MediaAttributeId;
+use crate::hci::consts::BdAddr;
+
+#[cfg(feature = "redact-logs")]
+static REDACTION_KEY: OnceLock<u64> = OnceLock::new();
+
+/// Sets the key of the hashes that replace redacted values, which is random per process otherwise.
+/// A fixed key allows correlating devices across restarts. Returns `false` if the key is already in use.
+#[cfg(feature = "redact-logs")]
+pub fn set_redaction_key(key: u64) -> bool {
+    REDACTION_KEY.set(key).is_ok()
+}
+
+/// A value that must not appear in the logs when the `redact-logs` feature is enabled.
+pub trait Redact {
+    /// Prefixed to the hash to tell the kinds of values apart.
+    const KIND: &'static str;
+
+    fn fmt_plain(&self, f: &mut Formatter<'_>) -> std::fmt::Result;
+
+    /// Feeds the value to `state` as bytes of a fixed layout. Unlike the [Hash](std::hash::Hash) implementations
+    /// of the standard library, the layout doesn't change between Rust releases.
+    #[cfg(feature = "redact-logs")]
+    fn hash_value<H: Hasher>(&self, state: &mut H);
+}
+
+impl Redact for BdAddr {
+    const KIND: &'static str = "addr";
+
+    fn fmt_plain(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+
+    #[cfg(feature = "redact-logs")]
+    fn hash_value<H: Hasher>(&self, state: &mut H) {
+        state.write(&self.to_be_bytes());
+    }
+}
+
+impl Redact for str {
+    const KIND: &'static str = "text";
+
+    fn fmt_plain(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+
+    #[cfg(feature = "redact-logs")]
+    fn hash_value<H: Hasher>(&self, state: &mut H) {
+        state.write(self.as_bytes());
+        // Terminates the string, so that consecutive strings can't be shifted into each other
+        state.write(&[0xFF]);
+    }
+}
+
+impl Redact for String {
+    const KIND: &'static str = "text";
+
+    fn fmt_plain(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt_plain(f)
+    }
+
+    #[cfg(feature = "redact-logs")]
+    fn hash_value<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash_value(state);
+    }
+}
+
+/// The attributes of a track, e.g. its title and artist.
+#[cfg(feature = "avrcp")]
+impl Redact for BTreeMap<MediaAttributeId, String> {
+    const KIND: &'static str = "metadata";
+
+    fn fmt_plain(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+
+    #[cfg(feature = "redact-logs")]
+    fn hash_value<H: Hasher>(&self, state: &mut H) {
+        for (&id, value) in self {
+            state.write(&(id as u32).to_be_bytes());
+            value.hash_value(state);
+        }
+    }
+}
+
+/// Formats the wrapped value for a log message, see [redacted].
+pub struct Redacted<'a, T: Redact + ?Sized>(&'a T);
+
+/// Wraps `value` so that it is only logged as a hash with the `redact-logs` feature.
+pub fn redacted<T: Redact + ?Sized>(value: &T) -> Redacted<'_, T> {
+    Redacted(value)
+}
+
+impl<T: Redact + ?Sized> Display for Redacted<'_, T> {
+    #[cfg(not(feature = "redact-logs"))]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt_plain(f)
+    }
+
+    #[cfg(feature = "redact-logs")]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let key = REDACTION_KEY.get_or_init(|| RandomState::new().hash_one(0u8));
+        let mut hasher = SipHasher24::new(*key, SIP_KEY_HIGH);
+        T::KIND.hash_value(&mut hasher);
+        self.0.hash_value(&mut hasher);
+        write!(f, "<{} {:08x}>", T::KIND, hasher.finish() as u32)
+    }
+}
+
+impl<T: Redact + ?Sized> Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// The upper half of the SipHash key, the lower half is the redaction key.
+#[cfg(feature = "redact-logs")]
+const SIP_KEY_HIGH: u64 = u64::from_le_bytes(*b"bluefang");
+
+/// SipHash-2-4, a keyed hash with a fixed output. The [DefaultHasher](std::hash::DefaultHasher) of the standard
+/// library may change its algorithm in any release, which would break the correlation of devices across restarts.
+#[cfg(feature = "redact-logs")]
+#[derive(Clone)]
+struct SipHasher24 {
+    v: [u64; 4],
+    /// The bytes of the incomplete last word.
+    tail: u64,
+    length: usize
+}
+
+#[cfg(feature = "redact-logs")]
+impl SipHasher24 {
+    fn new(k0: u64, k1: u64) -> Self {
+        Self {
+            v: [
+                k0 ^ 0x736f6d6570736575,
+                k1 ^ 0x646f72616e646f6d,
+                k0 ^ 0x6c7967656e657261,
+                k1 ^ 0x7465646279746573
+            ],
+            tail: 0,
+            length: 0
+        }
+    }
+
+    fn rounds(&mut self, n: usize) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        for _ in 0..n {
+            *v0 = v0.wrapping_add(*v1);
+            *v1 = v1.rotate_left(13) ^ *v0;
+            *v0 = v0.rotate_left(32);
+            *v2 = v2.wrapping_add(*v3);
+            *v3 = v3.rotate_left(16) ^ *v2;
+            *v0 = v0.wrapping_add(*v3);
+            *v3 = v3.rotate_left(21) ^ *v0;
+            *v2 = v2.wrapping_add(*v1);
+            *v1 = v1.rotate_left(17) ^ *v2;
+            *v2 = v2.rotate_left(32);
+        }
+    }
+
+    fn compress(&mut self, word: u64) {
+        self.v[3] ^= word;
+        self.rounds(2);
+        self.v[0] ^= word;
+    }
+}
+
+#[cfg(feature = "redact-logs")]
+impl Hasher for SipHasher24 {
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        state.compress(((self.length as u64) << 56) | self.tail);
+        state.v[2] ^= 0xFF;
+        state.rounds(4);
+        state.v.iter().fold(0, |hash, v| hash ^ v)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.tail |= (byte as u64) << (8 * (self.length % 8));
+            self.length += 1;
+            if self.length % 8 == 0 {
+                self.compress(self.tail);
+                self.tail = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hci::consts::BdAddr;
+    use crate::utils::redact::redacted;
+
+    #[test]
+    fn redaction() {
+        let addr = BdAddr::new([1, 2, 3, 4, 5, 6]);
+        let name = String::from("Alice's Headphones");
+        #[cfg(not(feature = "redact-logs"))]
+        {
+            assert_eq!(redacted(&addr).to_string(), "06:05:04:03:02:01");
+            assert_eq!(redacted(&name).to_string(), "\"Alice's Headphones\"");
+        }
+        #[cfg(feature = "redact-logs")]
+        {
+            let logged = redacted(&addr).to_string();
+            assert!(logged.starts_with("<addr ") && !logged.contains("06:05"));
+            // The same value always maps to the same hash
+            assert_eq!(logged, redacted(&BdAddr::new([1, 2, 3, 4, 5, 6])).to_string());
+            assert!(!redacted(&name).to_string().contains("Alice"));
+        }
+    }
+
+    #[cfg(feature = "redact-logs")]
+    #[test]
+    fn sip_hash() {
+        use std::hash::Hasher;
+
+        use crate::utils::redact::SipHasher24;
+
+        // The test vectors of the reference implementation
+        let key: Vec<u8> = (0..16).collect();
+        let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+        assert_eq!(SipHasher24::new(k0, k1).finish(), 0x726fdb47dd0e0e31);
+        let mut hasher = SipHasher24::new(k0, k1);
+        hasher.write(&[0x00]);
+        assert_eq!(hasher.finish(), 0x74f839c593dc67fd);
+        let mut hasher = SipHasher24::new(k0, k1);
+        hasher.write(&(0..15).collect::<Vec<u8>>());
+        assert_eq!(hasher.finish(), 0xa129ca6149be45e5);
+    }
+}
@@ -0,0 +1,103 @@
+//! Runs the long lived tasks of the stack, so a panic only ends the affected task instead of silently killing it.
+//! The owner of a task can clean up after a panic, e.g. to close the connection the task was responsible for.
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::OnceLock;
+
+use futures_lite::FutureExt;
+use parking_lot::Mutex;
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+/// The health of all tasks with the same name.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TaskStats {
+    pub spawned: u64,
+    pub running: u64,
+    pub panicked: u64
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Mutex<BTreeMap<&'static str, TaskStats>>
+}
+
+impl Supervisor {
+    /// The supervisor of all tasks of the stack.
+    pub fn global() -> &'static Supervisor {
+        static SUPERVISOR: OnceLock<Supervisor> = OnceLock::new();
+        SUPERVISOR.get_or_init(Supervisor::default)
+    }
+
+    pub fn stats(&self) -> BTreeMap<&'static str, TaskStats> {
+        self.tasks.lock().clone()
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut TaskStats)) {
+        f(self.tasks.lock().entry(name).or_default());
+    }
+}
+
+/// Decrements the running tasks even if the task is cancelled.
+struct RunningGuard(&'static str);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        Supervisor::global().update(self.0, |stats| stats.running -= 1);
+    }
+}
+
+/// Runs `task` and returns its output, or `None` if it panicked.
+/// `context` identifies the instance of the task in the log, e.g. the connection handle.
+pub async fn supervise<F: Future>(name: &'static str, context: impl Display, task: F) -> Option<F::Output> {
+    Supervisor::global().update(name, |stats| {
+        stats.spawned += 1;
+        stats.running += 1;
+    });
+    let _guard = RunningGuard(name);
+    match AssertUnwindSafe(task).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(payload) => {
+            Supervisor::global().update(name, |stats| stats.panicked += 1);
+            error!("Task {} ({}) panicked: {}", name, context, panic_message(payload.as_ref()));
+            None
+        }
+    }
+}
+
+/// Spawns `task` on the runtime under the supervision of [supervise].
+pub fn spawn_supervised<F>(name: &'static str, context: impl Display + Send + 'static, task: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static
+{
+    spawn(supervise(name, context, task))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::{supervise, Supervisor};
+
+    #[tokio::test]
+    async fn panic_isolation() {
+        assert_eq!(supervise("test-ok", 1, async { 42 }).await, Some(42));
+        let result = supervise("test-panic", 2, async { panic!("session failed") }).await;
+        assert_eq!(result, None::<()>);
+        let stats = Supervisor::global().stats();
+        assert_eq!((stats["test-panic"].spawned, stats["test-panic"].running, stats["test-panic"].panicked), (1, 0, 1));
+        assert_eq!(stats["test-ok"].panicked, 0);
+    }
+}
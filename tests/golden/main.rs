@@ -0,0 +1,136 @@
+//! Compares the encoders of the public packet types byte-for-byte against the fixtures in `tests/golden/*.hex`.
+//!
+//! The fixtures are encoded by hand from the specifications, not captured from real devices, so they catch
+//! regressions of the encoders, but not misreadings of the specification that a fixture shares. The framing of
+//! the internal AVCTP, AVDTP, AVRCP and SDP encoders is checked by the unit tests next to them.
+use std::fs::read_to_string;
+use std::path::Path;
+
+use bytes::{Bytes, BytesMut};
+use instructor::BufferMut;
+
+/// Loads the fixture `name` from `tests/golden/<file>.hex`.
+///
+/// Every non-empty line that doesn't start with `#` has the form `name: 0a 1b 2c ...`.
+fn fixture(file: &str, name: &str) -> Bytes {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.hex", file));
+    let content = read_to_string(&path).unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, hex)| {
+            hex.split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16).unwrap_or_else(|_| panic!("invalid hex byte {:?} in {}", byte, name)))
+                .collect::<Vec<u8>>()
+                .into()
+        })
+        .unwrap_or_else(|| panic!("no fixture named {} in {}", name, path.display()))
+}
+
+/// Compares an encoded packet against the fixture `name` from `tests/golden/<file>.hex`.
+#[track_caller]
+fn assert_golden(file: &str, name: &str, actual: &[u8]) {
+    let expected = fixture(file, name);
+    assert_eq!(
+        actual,
+        expected.as_ref(),
+        "encoding of {}/{} differs from the golden file\n  actual:   {:02x?}\n  expected: {:02x?}",
+        file,
+        name,
+        actual,
+        expected.as_ref()
+    );
+}
+
+#[test]
+fn l2cap_signaling() {
+    use bluefang::l2cap::configuration::{ConfigurationParameter, Mtu};
+    use bluefang::l2cap::signaling::{signaling_packet, Psm, RejectReason, SignalingCode};
+
+    let packet = signaling_packet(0x01, SignalingCode::ConnectionRequest, (Psm(0x0019), 0x0040u16)).unwrap();
+    assert_golden("l2cap", "connection_request", &packet);
+
+    let packet = signaling_packet(0x03, SignalingCode::DisconnectionRequest, (0x0041u16, 0x0040u16)).unwrap();
+    assert_golden("l2cap", "disconnection_request", &packet);
+
+    let reason = RejectReason::InvalidCid { local_cid: 0x0041, remote_cid: 0x0040 };
+    let packet = signaling_packet(0x07, SignalingCode::CommandReject, reason).unwrap();
+    assert_golden("l2cap", "command_reject_invalid_cid", &packet);
+
+    let mut buffer = BytesMut::new();
+    buffer.write_le(ConfigurationParameter::Mtu(Mtu(1691)));
+    assert_golden("l2cap", "mtu_option", &buffer);
+}
+
+#[test]
+#[cfg(feature = "avrcp")]
+fn sdp_data_elements() {
+    use bluefang::avrcp::sdp::AvrcpControllerServiceRecord;
+    use bluefang::sdp::ids::attributes::PROTOCOL_DESCRIPTOR_LIST_ID;
+    use bluefang::sdp::ServiceRecord;
+
+    let protocols = AvrcpControllerServiceRecord::new(0x00010002)
+        .attributes()
+        .into_iter()
+        .find(|attribute| attribute.id == PROTOCOL_DESCRIPTOR_LIST_ID)
+        .unwrap();
+    let mut buffer = BytesMut::new();
+    buffer.write_ref(&protocols.value);
+    assert_golden("sdp", "avrcp_protocol_descriptor_list", &buffer);
+}
+
+#[test]
+#[cfg(feature = "avdtp")]
+fn avdtp_capabilities() {
+    use bluefang::avdtp::capabilities::Capability;
+    use instructor::Buffer;
+
+    // Decode the capabilities of the fixture and encode them again
+    let mut capabilities = fixture("avdtp", "sbc_capabilities");
+    let capabilities: Vec<Capability> = capabilities.read().unwrap();
+    let mut buffer = BytesMut::new();
+    buffer.write_ref(&capabilities);
+    assert_golden("avdtp", "sbc_capabilities", &buffer);
+}
+
+#[test]
+#[cfg(feature = "avrcp")]
+fn avc_pass_through() {
+    use bluefang::avc::{CommandCode, Frame, Opcode, PassThroughFrame, PassThroughOp, PassThroughState, Subunit, SubunitType};
+
+    let mut buffer = BytesMut::new();
+    buffer.write_be(Frame {
+        ctype: CommandCode::Control,
+        subunit: Subunit {
+            ty: SubunitType::Panel,
+            id: 0
+        },
+        opcode: Opcode::PassThrough
+    });
+    buffer.write_be(PassThroughFrame {
+        state: PassThroughState::Pressed,
+        op: PassThroughOp::Play,
+        data_len: 0
+    });
+    assert_golden("avrcp", "pass_through_play_pressed", &buffer);
+}
+
+#[test]
+#[cfg(feature = "avrcp")]
+fn obex_connect() {
+    use bluefang::obex::{header_ids, ConnectParameters, Header, Opcode, Packet, VERSION};
+
+    let request = Packet::request(Opcode::Connect)
+        .with_connect(ConnectParameters {
+            version: VERSION,
+            flags: 0,
+            max_packet_length: 1691
+        })
+        .with_header(Header::bytes(header_ids::TARGET, Bytes::from_static(b"0123456789abcdef")));
+    assert_golden("obex", "connect_request", &request.encode().unwrap());
+}